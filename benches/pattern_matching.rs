@@ -19,6 +19,16 @@ fn bench_pattern_matching(c: &mut Criterion) {
         })
     });
 
+    c.bench_function("should_exclude_recursive_braces", |b| {
+        let patterns = vec!["var/**/cache/*.{log,tmp}".to_string()];
+        b.iter(|| {
+            should_exclude(
+                black_box(Path::new("/srv/rootfs/var/lib/apt/cache/pkg.tmp")),
+                black_box(&patterns),
+            )
+        })
+    });
+
     c.bench_function("should_exclude_no_match", |b| {
         let patterns = vec!["*.log".to_string()];
         b.iter(|| should_exclude(black_box(Path::new("src/main.rs")), black_box(&patterns)))
@@ -0,0 +1,107 @@
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use rust_utils::commands::copy::{CopyArgs, CopyCommand, ReflinkMode};
+use rust_utils::commands::fingerprint::{FingerprintArgs, FingerprintCommand};
+use rust_utils::commands::remap::{RemapArgs, RemapCommand};
+use rust_utils::idmap::IdMapping;
+use tempfile::TempDir;
+
+#[path = "../tests/common/mod.rs"]
+mod common;
+
+use common::Rootfs;
+
+/// Files added to the fixture so that per-entry costs dominate.
+const FILES: usize = 2000;
+
+fn rootfs(dir: &TempDir) -> Rootfs {
+    Rootfs::build(&dir.path().join("rootfs"), 0)
+        .and_then(|rootfs| rootfs.with_files(FILES))
+        .expect("build fixture")
+}
+
+fn remap_args(rootfs: &Rootfs, from_base: u32, to_base: u32) -> RemapArgs {
+    RemapArgs {
+        base_directory: rootfs.root().to_path_buf(),
+        from_base,
+        to_base,
+        ..Default::default()
+    }
+}
+
+fn bench_remap(c: &mut Criterion) {
+    let dir = TempDir::new().unwrap();
+    let rootfs = rootfs(&dir);
+
+    c.bench_function("remap_dry_run_rootfs", |b| {
+        b.iter(|| {
+            let args = RemapArgs {
+                dry_run: true,
+                ..remap_args(&rootfs, 0, 100000)
+            };
+            RemapCommand::new(args).execute().unwrap()
+        })
+    });
+
+    // Every iteration moves the tree to the other range and back on the next
+    let mut shifted = false;
+    c.bench_function("remap_rootfs", |b| {
+        b.iter(|| {
+            let (from, to) = if shifted { (100000, 0) } else { (0, 100000) };
+            shifted = !shifted;
+            RemapCommand::new(remap_args(&rootfs, from, to))
+                .execute()
+                .unwrap()
+        })
+    });
+}
+
+fn bench_fingerprint(c: &mut Criterion) {
+    let dir = TempDir::new().unwrap();
+    let rootfs = rootfs(&dir);
+
+    c.bench_function("fingerprint_rootfs", |b| {
+        b.iter(|| {
+            let command = FingerprintCommand::new(FingerprintArgs {
+                directory: rootfs.root().to_path_buf(),
+                exclude: Vec::new(),
+                depth: 0,
+                metadata_cache: false,
+            });
+            command.compute(&mut Vec::new()).unwrap()
+        })
+    });
+}
+
+fn bench_copy(c: &mut Criterion) {
+    let dir = TempDir::new().unwrap();
+    let rootfs = rootfs(&dir);
+
+    c.bench_function("copy_rootfs", |b| {
+        b.iter_batched(
+            || TempDir::new().unwrap(),
+            |destination| {
+                let args = CopyArgs {
+                    source: rootfs.root().to_path_buf(),
+                    destination: destination.path().join("copy"),
+                    map: vec![IdMapping {
+                        from: 0,
+                        to: 100000,
+                        count: 65536,
+                    }],
+                    lxc: Default::default(),
+                    dry_run: false,
+                    verbose: false,
+                    exclude: Vec::new(),
+                    reflink: ReflinkMode::Auto,
+                    progress: Default::default(),
+                };
+                CopyCommand::new(args).execute().unwrap();
+                destination
+            },
+            BatchSize::PerIteration,
+        )
+    });
+}
+
+criterion_group!(benches, bench_remap, bench_fingerprint, bench_copy);
+criterion_main!(benches);
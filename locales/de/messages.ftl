@@ -0,0 +1,19 @@
+# German messages.
+
+dry-run = PROBELAUF - Es werden keine Änderungen vorgenommen
+
+remap-starting = Starte UID/GID-Neuzuordnung
+remap-base-directory = Basisverzeichnis: { $path }
+remap-from-range = Quellbereich: { $first }-{ $last }
+remap-to-range = Zielbereich: { $first }-{ $last }
+remap-heartbeat = { $processed } Dateien verarbeitet, { $remapped } neu zugeordnet
+remap-completed = Neuzuordnung abgeschlossen
+remap-files-processed = Verarbeitete Dateien: { $count }
+remap-files-remapped = Neu zugeordnete Dateien: { $count }
+remap-nested-archives = Verschachtelte Archive: { $count }
+remap-filesystem = Dateisystem { $device } ({ $fstype } auf { $mountpoint }): { $entries ->
+        [one] 1 Eintrag
+       *[other] { $entries } Einträge
+    }, { $changed } neu zugeordnet, { $failed } fehlgeschlagen
+unknown-fstype = unbekannt
+unknown-mount = unbekannter Einhängepunkt
@@ -0,0 +1,17 @@
+# English messages, also used for any message missing from another locale.
+# Message IDs are logged as the `code` field and must not change.
+
+dry-run = DRY RUN MODE - No changes will be made
+
+remap-starting = Starting UID/GID remapping
+remap-base-directory = Base directory: { $path }
+remap-from-range = From range: { $first }-{ $last }
+remap-to-range = To range: { $first }-{ $last }
+remap-heartbeat = Processed { $processed } files, remapped { $remapped }
+remap-completed = Remapping completed
+remap-files-processed = Files processed: { $count }
+remap-files-remapped = Files remapped: { $count }
+remap-nested-archives = Nested archives: { $count }
+remap-filesystem = Filesystem { $device } ({ $fstype } on { $mountpoint }): { $entries } entries, { $changed } remapped, { $failed } failed
+unknown-fstype = unknown
+unknown-mount = unknown mount
@@ -0,0 +1,220 @@
+//! Ownership changes made through directory file descriptors, for `remap`.
+//!
+//! A change by path resolves every component again when it is made, so a directory of the
+//! tree swapped for a symlink between the walk and the chown redirects the change outside
+//! the tree. An [`Anchor`] holds the tree's root open and reaches each entry's directory
+//! with `openat2(RESOLVE_BENEATH | RESOLVE_NO_SYMLINKS)`, which refuses symlinks and any
+//! path leaving the root, then changes the entry with `fchownat(AT_SYMLINK_NOFOLLOW)`
+//! relative to that descriptor.
+
+use std::ffi::{CString, OsString};
+use std::fs::{self, File, Metadata, Permissions};
+use std::io;
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use nix::libc;
+use rustix::fs::{chownat, openat, AtFlags, Gid, Mode, OFlags, ResolveFlags, Uid, CWD};
+
+use crate::iostats::{self, Syscall};
+
+/// The root of a tree, with the directory of the entry last changed kept open.
+pub struct Anchor {
+    root: PathBuf,
+    fd: OwnedFd,
+    parent: Mutex<Option<(PathBuf, Arc<OwnedFd>)>>,
+}
+
+/// An entry of a tree, named relative to its directory's descriptor.
+pub struct Entry {
+    dir: Arc<OwnedFd>,
+    /// Name in the directory, or `.` for the root itself
+    name: CString,
+}
+
+impl Anchor {
+    /// Open `root` to change entries beneath it.
+    ///
+    /// # Errors
+    ///
+    /// Fails like `openat2`: with `ENOSYS` before Linux 5.6.
+    pub fn open(root: &Path) -> io::Result<Self> {
+        let fd = openat(
+            CWD,
+            root,
+            OFlags::PATH | OFlags::DIRECTORY | OFlags::CLOEXEC,
+            Mode::empty(),
+        )?;
+        // Kernels without openat2 are found out before any entry depends on it
+        let parent = openat2(&fd, Path::new("."))?;
+        Ok(Self {
+            root: root.to_path_buf(),
+            fd,
+            parent: Mutex::new(Some((PathBuf::new(), Arc::new(parent)))),
+        })
+    }
+
+    /// Open the directory of `path`, which must be below the root, without following a
+    /// symlink on the way. `None` for paths outside the root.
+    pub fn entry(&self, path: &Path) -> Option<io::Result<Entry>> {
+        let relative = path.strip_prefix(&self.root).ok()?;
+        let (dir, name) = match (relative.parent(), relative.file_name()) {
+            (Some(dir), Some(name)) => (dir, name.to_os_string()),
+            _ => (Path::new(""), OsString::from(".")),
+        };
+        Some(self.dir(dir).and_then(|dir| {
+            Ok(Entry {
+                dir,
+                name: CString::new(name.as_bytes())?,
+            })
+        }))
+    }
+
+    fn dir(&self, relative: &Path) -> io::Result<Arc<OwnedFd>> {
+        let mut parent = self.parent.lock().map_err(|_| {
+            io::Error::other(format!(
+                "directories below {} are unusable after a failed change",
+                self.root.display()
+            ))
+        })?;
+        if let Some((path, fd)) = parent.as_ref() {
+            if path == relative {
+                return Ok(Arc::clone(fd));
+            }
+        }
+        let path = if relative.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            relative
+        };
+        let fd = Arc::new(openat2(&self.fd, path)?);
+        *parent = Some((relative.to_path_buf(), Arc::clone(&fd)));
+        Ok(fd)
+    }
+}
+
+impl Entry {
+    /// Status of the entry itself, not of what it links to.
+    pub fn metadata(&self) -> io::Result<Metadata> {
+        let fd = openat(
+            &*self.dir,
+            &*self.name,
+            OFlags::PATH | OFlags::NOFOLLOW | OFlags::CLOEXEC,
+            Mode::empty(),
+        )?;
+        iostats::timed(Syscall::Statx, || File::from(fd).metadata())
+    }
+
+    /// Change the owner of the entry itself, as `lchown`. `-1` leaves an ID as it is.
+    pub fn chown(&self, uid: Option<u32>, gid: Option<u32>) -> io::Result<()> {
+        Ok(iostats::timed(Syscall::Fchownat, || {
+            chownat(
+                &*self.dir,
+                &*self.name,
+                uid.map(Uid::from_raw),
+                gid.map(Gid::from_raw),
+                AtFlags::SYMLINK_NOFOLLOW,
+            )
+        })?)
+    }
+
+    /// Set the permission bits of the entry, refusing a symlink with `ELOOP` rather than
+    /// changing what it links to.
+    pub fn set_mode(&self, mode: u32) -> io::Result<()> {
+        let fd = openat(
+            &*self.dir,
+            &*self.name,
+            OFlags::PATH | OFlags::NOFOLLOW | OFlags::CLOEXEC,
+            Mode::empty(),
+        )?;
+        // chmod cannot take an O_PATH descriptor, but follows its link in /proc
+        let proc = PathBuf::from(format!("/proc/self/fd/{}", fd.as_raw_fd()));
+        if fs::metadata(&proc)?.file_type().is_symlink() {
+            return Err(io::Error::from_raw_os_error(libc::ELOOP));
+        }
+        fs::set_permissions(&proc, Permissions::from_mode(mode))
+    }
+
+    /// A path to the entry through its directory's descriptor, for calls that take paths
+    /// and do not follow a symlink at the end, such as `lsetxattr`.
+    pub fn path(&self) -> PathBuf {
+        let name = std::ffi::OsStr::from_bytes(self.name.as_bytes());
+        PathBuf::from(format!("/proc/self/fd/{}", self.dir.as_raw_fd())).join(name)
+    }
+}
+
+/// Open `path` below `dir` as a directory, refusing symlinks and paths leaving `dir`.
+fn openat2(dir: &OwnedFd, path: &Path) -> io::Result<OwnedFd> {
+    Ok(rustix::fs::openat2(
+        dir,
+        path,
+        OFlags::PATH | OFlags::DIRECTORY | OFlags::CLOEXEC,
+        Mode::empty(),
+        ResolveFlags::BENEATH | ResolveFlags::NO_SYMLINKS,
+    )?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::{symlink, MetadataExt};
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_anchor() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = TempDir::new()?;
+        let root = temp_dir.path().join("tree");
+        let outside = temp_dir.path().join("outside");
+        fs::create_dir_all(root.join("dir"))?;
+        fs::create_dir(&outside)?;
+        fs::write(root.join("dir/file"), "")?;
+        fs::write(outside.join("file"), "")?;
+        symlink(&outside, root.join("link"))?;
+
+        let anchor = Anchor::open(&root)?;
+        let entry = anchor.entry(&root.join("dir/file")).unwrap()?;
+        assert_eq!(
+            entry.metadata()?.ino(),
+            fs::metadata(root.join("dir/file"))?.ino()
+        );
+        assert!(entry.path().ends_with("file"));
+        let root_entry = anchor.entry(&root).unwrap()?;
+        assert_eq!(root_entry.metadata()?.ino(), fs::metadata(&root)?.ino());
+        assert!(anchor.entry(&outside.join("file")).is_none());
+
+        // A directory swapped for a symlink is refused, not followed
+        let error = anchor
+            .entry(&root.join("link/file"))
+            .unwrap()
+            .err()
+            .unwrap();
+        assert_eq!(error.raw_os_error(), Some(libc::ELOOP));
+        anchor.entry(&root.join("dir/file")).unwrap()?;
+        fs::rename(root.join("dir"), temp_dir.path().join("moved"))?;
+        symlink(&outside, root.join("dir"))?;
+        // While open, the directory is the one moved away, wherever it went
+        let entry = anchor.entry(&root.join("dir/file")).unwrap()?;
+        assert_eq!(
+            entry.metadata()?.ino(),
+            fs::metadata(temp_dir.path().join("moved/file"))?.ino()
+        );
+        anchor.entry(&root.join("file")).unwrap()?;
+        let error = anchor.entry(&root.join("dir/file")).unwrap().err().unwrap();
+        assert_eq!(error.raw_os_error(), Some(libc::ELOOP));
+
+        let file = anchor.entry(&root.join("link")).unwrap()?;
+        assert!(file.metadata()?.file_type().is_symlink());
+        file.chown(Some(4242), None)?;
+        assert_eq!(fs::symlink_metadata(root.join("link"))?.uid(), 4242);
+        assert_eq!(fs::metadata(&outside)?.uid(), 0);
+        assert_eq!(
+            file.set_mode(0o755).unwrap_err().raw_os_error(),
+            Some(libc::ELOOP)
+        );
+        Ok(())
+    }
+}
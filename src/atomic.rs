@@ -0,0 +1,268 @@
+//! Directories remapped on a clone and swapped into place, for `remap --atomic-dirs`.
+//!
+//! An [`AtomicDir`] copies a directory to a hidden sibling, sharing file data through
+//! reflinks where the filesystem allows it, so the remap can change the copy while the
+//! original stays as it was. [`AtomicDir::swap`] then exchanges the two with
+//! `renameat2(RENAME_EXCHANGE)`: readers see either the old directory or the fully remapped
+//! one, never a mix. Dropping an `AtomicDir` that was not swapped removes the copy.
+
+use std::collections::HashMap;
+use std::fs::{self, Metadata, Permissions};
+use std::os::unix::fs::{lchown, symlink, DirBuilderExt, FileTypeExt, MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+use nix::fcntl::{renameat2, RenameFlags};
+use nix::sys::stat::{mknod, Mode, SFlag};
+use tracing::{debug, warn};
+use walkdir::WalkDir;
+
+use crate::commands::copy::{copy_file_data, set_times, ReflinkMode};
+use crate::error::{Result, RustUtilsError};
+use crate::xattrs::Snapshot;
+
+/// Marks the name of a copy, followed by the process ID.
+const COPY_SUFFIX: &str = ".rust-utils-atomic-";
+
+/// Whether `path` is named like the copy of an [`AtomicDir`], which walks of the tree leave
+/// out, whether it belongs to this run or is left over from one that was killed.
+pub fn is_copy(path: &Path) -> bool {
+    path.file_name()
+        .map(|name| name.to_string_lossy())
+        .is_some_and(|name| name.starts_with('.') && name.contains(COPY_SUFFIX))
+}
+
+/// A directory with a copy of it to change and swap in.
+#[derive(Debug)]
+pub struct AtomicDir {
+    original: PathBuf,
+    clone: PathBuf,
+    /// The parent as it was before the copy was made next to it
+    parent: Option<(PathBuf, Metadata)>,
+    /// Whether `clone` now holds the original directory, to be removed
+    swapped: bool,
+}
+
+impl AtomicDir {
+    /// Copy `dir` to a new hidden directory next to it. Fails if a mount point, one of
+    /// `mountpoints` or an entry of another filesystem, is part of it, as a mount cannot
+    /// move with the swap.
+    pub fn create(dir: &Path, mountpoints: &[PathBuf]) -> Result<Self> {
+        let (Some(parent), Some(name)) = (dir.parent(), dir.file_name()) else {
+            return Err(RustUtilsError::InvalidArguments(format!(
+                "{} has no parent to swap it in",
+                dir.display()
+            )));
+        };
+        let mut clone_name = std::ffi::OsString::from(".");
+        clone_name.push(name);
+        clone_name.push(format!("{COPY_SUFFIX}{}", std::process::id()));
+        let atomic = Self {
+            original: dir.to_path_buf(),
+            clone: parent.join(clone_name),
+            parent: fs::symlink_metadata(parent)
+                .ok()
+                .map(|metadata| (parent.to_path_buf(), metadata)),
+            swapped: false,
+        };
+        fs::DirBuilder::new().mode(0o700).create(&atomic.clone)?;
+        atomic.copy_tree(mountpoints)?;
+        Ok(atomic)
+    }
+
+    fn copy_tree(&self, mountpoints: &[PathBuf]) -> Result<()> {
+        let device = fs::symlink_metadata(&self.original)?.dev();
+        let mut links: HashMap<(u64, u64), PathBuf> = HashMap::new();
+        // Directories get their owner, mode and times once their contents are in place
+        let mut directories = Vec::new();
+        for entry in WalkDir::new(&self.original).follow_links(false) {
+            let entry = entry.map_err(|e| RustUtilsError::Io(e.into()))?;
+            let source = entry.path();
+            let metadata = entry.metadata().map_err(|e| RustUtilsError::Io(e.into()))?;
+            if metadata.dev() != device || mountpoints.iter().any(|m| m == source) {
+                return Err(RustUtilsError::OperationFailed(format!(
+                    "cannot swap {} atomically: {} is a mount point",
+                    self.original.display(),
+                    source.display()
+                )));
+            }
+            let target = self
+                .clone_path(source)
+                .expect("entries are below the original");
+            let file_type = metadata.file_type();
+            if file_type.is_dir() {
+                if entry.depth() > 0 {
+                    fs::DirBuilder::new().mode(0o700).create(&target)?;
+                }
+                directories.push((source.to_path_buf(), target, metadata));
+                continue;
+            }
+            if metadata.nlink() > 1 {
+                let key = (metadata.dev(), metadata.ino());
+                if let Some(first) = links.get(&key) {
+                    fs::hard_link(first, &target)?;
+                    continue;
+                }
+                links.insert(key, target.clone());
+            }
+            if file_type.is_file() {
+                copy_file_data(source, &target, ReflinkMode::Auto)?;
+            } else if file_type.is_symlink() {
+                symlink(fs::read_link(source)?, &target)?;
+            } else if file_type.is_fifo()
+                || file_type.is_char_device()
+                || file_type.is_block_device()
+                || file_type.is_socket()
+            {
+                let kind = SFlag::from_bits_truncate(metadata.mode() & SFlag::S_IFMT.bits());
+                mknod(
+                    &target,
+                    kind,
+                    Mode::from_bits_truncate(0o600),
+                    metadata.rdev(),
+                )?;
+            } else {
+                return Err(RustUtilsError::OperationFailed(format!(
+                    "cannot copy {} of unknown type",
+                    source.display()
+                )));
+            }
+            copy_attributes(source, &target, &metadata)?;
+        }
+        for (source, target, metadata) in directories.iter().rev() {
+            copy_attributes(source, target, metadata)?;
+        }
+        debug!(
+            "Copied {} to {}",
+            self.original.display(),
+            self.clone.display()
+        );
+        Ok(())
+    }
+
+    pub fn original(&self) -> &Path {
+        &self.original
+    }
+
+    /// Where `path` below the original directory is in the copy, or `None` if it is not
+    /// below it.
+    pub fn clone_path(&self, path: &Path) -> Option<PathBuf> {
+        let relative = path.strip_prefix(&self.original).ok()?;
+        Some(if relative.as_os_str().is_empty() {
+            self.clone.clone()
+        } else {
+            self.clone.join(relative)
+        })
+    }
+
+    /// Exchange the copy with the original directory in one step, then remove the
+    /// original.
+    pub fn swap(mut self) -> Result<()> {
+        renameat2(
+            None,
+            &self.clone,
+            None,
+            &self.original,
+            RenameFlags::RENAME_EXCHANGE,
+        )
+        .map_err(|e| {
+            RustUtilsError::OperationFailed(format!(
+                "cannot swap the copy of {} into place: {e}",
+                self.original.display()
+            ))
+        })?;
+        self.swapped = true;
+        self.remove()
+    }
+
+    fn remove(&mut self) -> Result<()> {
+        fs::remove_dir_all(&self.clone)?;
+        // Neither the copy nor its removal is a change of the parent's own
+        if let Some((parent, metadata)) = self.parent.take() {
+            set_times(&parent, &metadata)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for AtomicDir {
+    fn drop(&mut self) {
+        if self.swapped || !self.clone.exists() {
+            return;
+        }
+        if let Err(e) = self.remove() {
+            warn!("Cannot remove {}: {}", self.clone.display(), e);
+        }
+    }
+}
+
+/// Give `target` the owner, mode, extended attributes and times of `source`.
+fn copy_attributes(source: &Path, target: &Path, metadata: &Metadata) -> Result<()> {
+    lchown(target, Some(metadata.uid()), Some(metadata.gid()))?;
+    // After the chown, which clears set-ID bits; symlinks have no mode of their own
+    if !metadata.file_type().is_symlink() {
+        fs::set_permissions(target, Permissions::from_mode(metadata.mode() & 0o7777))?;
+    }
+    Snapshot::capture(source)?.restore(target)?;
+    set_times(target, metadata)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_atomic_dir() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = TempDir::new()?;
+        let dir = temp_dir.path().join("etc");
+        fs::create_dir_all(dir.join("ssl"))?;
+        fs::write(dir.join("hosts"), "127.0.0.1 localhost")?;
+        fs::hard_link(dir.join("hosts"), dir.join("ssl/hosts"))?;
+        symlink("../hosts", dir.join("ssl/link"))?;
+        fs::set_permissions(dir.join("ssl"), Permissions::from_mode(0o750))?;
+        lchown(dir.join("hosts"), Some(100000), Some(100001))?;
+
+        let atomic = AtomicDir::create(&dir, &[])?;
+        let clone = atomic.clone_path(&dir).unwrap();
+        assert!(is_copy(&clone));
+        assert!(!is_copy(&dir));
+        assert_eq!(atomic.clone_path(temp_dir.path()), None);
+        let copied = fs::symlink_metadata(clone.join("hosts"))?;
+        assert_eq!((copied.uid(), copied.gid()), (100000, 100001));
+        assert_eq!(copied.nlink(), 2);
+        assert_eq!(
+            fs::read_to_string(clone.join("ssl/hosts"))?,
+            "127.0.0.1 localhost"
+        );
+        assert_eq!(
+            fs::read_link(clone.join("ssl/link"))?,
+            Path::new("../hosts")
+        );
+        assert_eq!(
+            fs::symlink_metadata(clone.join("ssl"))?.mode() & 0o7777,
+            0o750
+        );
+
+        // The changed copy takes the place of the original, which goes away
+        lchown(clone.join("hosts"), Some(200000), Some(200001))?;
+        atomic.swap()?;
+        assert_eq!(fs::symlink_metadata(dir.join("ssl/hosts"))?.uid(), 200000);
+        assert_eq!(fs::read_dir(temp_dir.path())?.count(), 1);
+
+        // Dropped without a swap, the copy is removed and the original left alone
+        let atomic = AtomicDir::create(&dir, &[])?;
+        lchown(
+            atomic.clone_path(&dir.join("hosts")).unwrap(),
+            Some(0),
+            None,
+        )?;
+        drop(atomic);
+        assert_eq!(fs::symlink_metadata(dir.join("hosts"))?.uid(), 200000);
+        assert_eq!(fs::read_dir(temp_dir.path())?.count(), 1);
+
+        let error = AtomicDir::create(&dir, &[dir.join("ssl")]).unwrap_err();
+        assert!(error.to_string().contains("is a mount point"), "{error}");
+        assert_eq!(fs::read_dir(temp_dir.path())?.count(), 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,138 @@
+//! Linux capabilities of the running process, for `with-caps`.
+//!
+//! A binary given file capabilities with `setcap` starts every command with all of them.
+//! [`keep_only`] narrows the process to the ones a command needs: they stay permitted,
+//! effective, inheritable and ambient, and the rest are dropped. Ambient capabilities carry
+//! the set across an exec of a binary without file capabilities, as when the process was
+//! started with systemd's `AmbientCapabilities=`; `no_new_privs` stops an exec of one with
+//! file capabilities, such as this binary, from gaining the dropped ones back.
+
+use std::fmt;
+use std::io;
+
+use rustix::thread::{self, CapabilitySet, CapabilitySets};
+
+/// The capabilities commands may need, numbered as in `linux/capability.h`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Capability {
+    Chown = 0,
+    DacOverride = 1,
+    DacReadSearch = 2,
+    Fowner = 3,
+    Fsetid = 4,
+    Mknod = 27,
+    Setfcap = 31,
+}
+
+impl fmt::Display for Capability {
+    /// The name `setcap` and `capsh` use.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Capability::Chown => "cap_chown",
+            Capability::DacOverride => "cap_dac_override",
+            Capability::DacReadSearch => "cap_dac_read_search",
+            Capability::Fowner => "cap_fowner",
+            Capability::Fsetid => "cap_fsetid",
+            Capability::Mknod => "cap_mknod",
+            Capability::Setfcap => "cap_setfcap",
+        })
+    }
+}
+
+/// A set of capabilities as a bit mask.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CapSet(u64);
+
+impl CapSet {
+    pub fn contains(self, capability: Capability) -> bool {
+        self.0 & (1 << capability as u64) != 0
+    }
+}
+
+impl FromIterator<Capability> for CapSet {
+    fn from_iter<I: IntoIterator<Item = Capability>>(iter: I) -> Self {
+        Self(iter.into_iter().fold(0, |mask, cap| mask | 1 << cap as u64))
+    }
+}
+
+impl From<CapabilitySet> for CapSet {
+    fn from(set: CapabilitySet) -> Self {
+        Self(set.bits())
+    }
+}
+
+/// The capability sets of the calling thread.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Caps {
+    pub effective: CapSet,
+    pub permitted: CapSet,
+    pub inheritable: CapSet,
+}
+
+/// Read the capability sets of the calling thread.
+pub fn current() -> io::Result<Caps> {
+    let sets = thread::capabilities(None)?;
+    Ok(Caps {
+        effective: sets.effective.into(),
+        permitted: sets.permitted.into(),
+        inheritable: sets.inheritable.into(),
+    })
+}
+
+/// Narrow the process to `keep`: no other capability stays in any set, these are raised
+/// as ambient capabilities, and `no_new_privs` is set. All of `keep` must be permitted.
+pub fn keep_only(keep: &[Capability]) -> io::Result<()> {
+    let set: CapSet = keep.iter().copied().collect();
+    let set = CapabilitySet::from_bits_retain(set.0);
+    thread::set_capabilities(
+        None,
+        CapabilitySets {
+            effective: set,
+            permitted: set,
+            inheritable: set,
+        },
+    )?;
+
+    thread::clear_ambient_capability_set()?;
+    for &capability in keep {
+        let capability = CapabilitySet::from_bits_retain(1 << capability as u64);
+        thread::configure_capability_in_ambient_set(capability, true)?;
+    }
+    Ok(thread::set_no_new_privs(true)?)
+}
+
+/// Comma-separated names of `capabilities`, as `setcap` takes them.
+pub fn names(capabilities: &[Capability]) -> String {
+    capabilities
+        .iter()
+        .map(Capability::to_string)
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cap_set() {
+        let set: CapSet = [Capability::Chown, Capability::Setfcap]
+            .into_iter()
+            .collect();
+        assert!(set.contains(Capability::Chown));
+        assert!(set.contains(Capability::Setfcap));
+        assert!(!set.contains(Capability::Fowner));
+        assert_eq!(
+            names(&[Capability::Chown, Capability::DacReadSearch]),
+            "cap_chown,cap_dac_read_search"
+        );
+    }
+
+    #[test]
+    fn test_current() -> io::Result<()> {
+        // Whatever the tests run as, effective capabilities are always permitted
+        let caps = current()?;
+        assert_eq!(caps.effective.0 & !caps.permitted.0, 0);
+        Ok(())
+    }
+}
@@ -0,0 +1,546 @@
+//! Resumable archive output: `OUTPUT.partial` plus a log of verified checkpoints.
+//!
+//! At each checkpoint the compressed stream is ended at a frame boundary (a gzip member, xz
+//! stream or zstd frame) and synced, then its length, SHA-256 digest and the number of
+//! input entries it covers are appended to the job's log in the state directory. Resuming verifies the
+//! partial file against that log, truncates it to the last checkpoint that still matches
+//! and continues with the next input entry. Decoders read the concatenated frames as one
+//! stream. The partial file is renamed to `OUTPUT` only once the archive is complete.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+use tracing::{debug, warn};
+
+use crate::compress::{Compression, Encoder};
+use crate::error::{Result, RustUtilsError};
+use crate::util::hex;
+
+const LOG_MAGIC: &str = "rust-utils-checkpoint";
+
+/// Version of the checkpoint log format written by this build; logs of this or any earlier
+/// version are resumed.
+pub const LOG_FORMAT_VERSION: u32 = 1;
+
+/// A checkpointed run: where its checkpoints are logged and what it is.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Job {
+    /// Checkpoint log, see [`StateDir::checkpoint_log`](crate::state::StateDir::checkpoint_log)
+    pub log: PathBuf,
+    /// Identifies the input and settings; a log written for a different key is never resumed
+    pub key: String,
+}
+
+/// One line of the checkpoint log.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Checkpoint {
+    /// Input entries fully written before this point
+    entries: u64,
+    /// Length of the partial file at this point
+    offset: u64,
+    /// Hex SHA-256 of the first `offset` bytes
+    digest: String,
+}
+
+/// Counts and hashes everything written to the partial file.
+struct HashingWriter {
+    file: BufWriter<File>,
+    hasher: Sha256,
+    len: u64,
+}
+
+impl Write for HashingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.file.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.len += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Writes a compressed archive to `OUTPUT.partial`, taking a checkpoint whenever at least
+/// `interval` uncompressed bytes were written since the last one.
+pub struct CheckpointWriter {
+    output: PathBuf,
+    partial: PathBuf,
+    log_path: PathBuf,
+    log: File,
+    encoder: Option<Encoder<HashingWriter>>,
+    compression: Compression,
+    level: Option<u32>,
+    threads: usize,
+    interval: u64,
+    pending: u64,
+}
+
+impl CheckpointWriter {
+    /// Start writing `output`, returning the number of input entries already covered.
+    ///
+    /// Checkpoints are logged to `job.log`. Without `resume`, any earlier partial output is
+    /// discarded. Compression uses `threads` workers as in [`Encoder::with_threads`].
+    pub fn create(
+        output: &Path,
+        job: &Job,
+        compression: Compression,
+        level: Option<u32>,
+        threads: usize,
+        interval: u64,
+        resume: bool,
+    ) -> Result<(Self, u64)> {
+        let partial = suffixed(output, ".partial");
+        let log_path = job.log.clone();
+        // Earlier versions kept the log next to the output
+        let legacy_log = suffixed(output, ".partial.ckpt");
+
+        let resumed = if resume {
+            let log = if !log_path.exists() && legacy_log.exists() {
+                &legacy_log
+            } else {
+                &log_path
+            };
+            resume_point(&partial, log, &job.key)?
+        } else {
+            if partial.exists() {
+                warn!(
+                    "Discarding interrupted output {} (use --resume to continue it)",
+                    partial.display()
+                );
+            }
+            None
+        };
+
+        let (file, hasher, checkpoints) = match resumed {
+            Some((hasher, checkpoints)) => {
+                let offset = checkpoints.last().map_or(0, |c| c.offset);
+                let mut file = OpenOptions::new().write(true).open(&partial)?;
+                file.set_len(offset)?;
+                file.seek(SeekFrom::End(0))?;
+                (file, hasher, checkpoints)
+            }
+            None => (File::create(&partial)?, Sha256::new(), Vec::new()),
+        };
+
+        // Rewrite the log so it only lists checkpoints the partial file still matches
+        let mut log = File::create(&log_path)?;
+        writeln!(log, "{LOG_MAGIC} {LOG_FORMAT_VERSION} {}", job.key)?;
+        for c in &checkpoints {
+            writeln!(log, "{} {} {}", c.entries, c.offset, c.digest)?;
+        }
+        log.sync_data()?;
+        if legacy_log.exists() {
+            fs::remove_file(&legacy_log)?;
+        }
+
+        let (entries, len) = checkpoints.last().map_or((0, 0), |c| (c.entries, c.offset));
+        let inner = HashingWriter {
+            file: BufWriter::new(file),
+            hasher,
+            len,
+        };
+        let writer = Self {
+            output: output.to_path_buf(),
+            partial,
+            log_path,
+            log,
+            encoder: Some(Encoder::with_threads(inner, compression, level, threads)?),
+            compression,
+            level,
+            threads,
+            interval,
+            pending: 0,
+        };
+        Ok((writer, entries))
+    }
+
+    /// Take a checkpoint after `entries` input entries if enough data has been written.
+    pub fn checkpoint_if_due(&mut self, entries: u64) -> io::Result<()> {
+        if self.pending < self.interval {
+            return Ok(());
+        }
+
+        let inner = self.take_encoder()?.finish()?;
+        inner.file.get_ref().sync_data()?;
+        let digest = hex(&inner.hasher.clone().finalize());
+        writeln!(self.log, "{} {} {}", entries, inner.len, digest)?;
+        self.log.sync_data()?;
+        debug!("Checkpoint after {} entries at byte {}", entries, inner.len);
+
+        self.encoder = Some(
+            Encoder::with_threads(inner, self.compression, self.level, self.threads)
+                .map_err(|e| io::Error::other(e.to_string()))?,
+        );
+        self.pending = 0;
+        Ok(())
+    }
+
+    /// Complete the archive and move it into place.
+    pub fn finish(mut self) -> Result<()> {
+        let inner = self.take_encoder()?.finish()?;
+        let file = inner
+            .file
+            .into_inner()
+            .map_err(io::IntoInnerError::into_error)?;
+        file.sync_all()?;
+        fs::rename(&self.partial, &self.output)?;
+        fs::remove_file(&self.log_path)?;
+        Ok(())
+    }
+
+    fn take_encoder(&mut self) -> io::Result<Encoder<HashingWriter>> {
+        self.encoder
+            .take()
+            .ok_or_else(|| io::Error::other("checkpoint writer used after a failed checkpoint"))
+    }
+}
+
+impl Write for CheckpointWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let encoder = self
+            .encoder
+            .as_mut()
+            .ok_or_else(|| io::Error::other("checkpoint writer used after a failed checkpoint"))?;
+        let n = encoder.write(buf)?;
+        self.pending += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.encoder {
+            Some(encoder) => encoder.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Verify an interrupted run's partial file against its log, returning the hasher state and
+/// checkpoints up to the last one that still matches. `None` means starting over.
+fn resume_point(
+    partial: &Path,
+    log_path: &Path,
+    job: &str,
+) -> Result<Option<(Sha256, Vec<Checkpoint>)>> {
+    if !partial.exists() || !log_path.exists() {
+        warn!("Nothing to resume for {}; starting over", partial.display());
+        return Ok(None);
+    }
+
+    let checkpoints = read_log(log_path, job)?;
+    let mut file = BufReader::new(File::open(partial)?);
+    let mut hasher = Sha256::new();
+    let mut position = 0u64;
+    let mut verified = Vec::new();
+    let mut verified_hasher = None;
+    let mut buf = vec![0u8; 1 << 20];
+
+    for checkpoint in checkpoints {
+        while position < checkpoint.offset {
+            let want = (checkpoint.offset - position).min(buf.len() as u64) as usize;
+            let n = file.read(&mut buf[..want])?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+            position += n as u64;
+        }
+        if position != checkpoint.offset || hex(&hasher.clone().finalize()) != checkpoint.digest {
+            warn!(
+                "{} does not match checkpoint at byte {}; resuming from the previous one",
+                partial.display(),
+                checkpoint.offset
+            );
+            break;
+        }
+        verified.push(checkpoint);
+        verified_hasher = Some(hasher.clone());
+    }
+
+    match verified_hasher {
+        Some(hasher) => Ok(Some((hasher, verified))),
+        None => {
+            warn!(
+                "No usable checkpoint in {}; starting over",
+                log_path.display()
+            );
+            Ok(None)
+        }
+    }
+}
+
+fn read_log(path: &Path, job: &str) -> Result<Vec<Checkpoint>> {
+    let invalid =
+        |detail: &str| RustUtilsError::InvalidArguments(format!("{}: {}", path.display(), detail));
+
+    let mut lines = BufReader::new(File::open(path)?).lines();
+    let header = lines.next().transpose()?.unwrap_or_default();
+    let mut fields = header.split_whitespace();
+    let (Some(LOG_MAGIC), Some(Ok(version)), Some(found)) = (
+        fields.next(),
+        fields.next().map(str::parse::<u32>),
+        fields.next(),
+    ) else {
+        return Err(invalid("not a checkpoint log"));
+    };
+    if version > LOG_FORMAT_VERSION {
+        return Err(RustUtilsError::UnsupportedFormat(format!(
+            "{}: checkpoint log version {} is newer than the supported version {}",
+            path.display(),
+            version,
+            LOG_FORMAT_VERSION
+        )));
+    }
+    if found != job {
+        return Err(invalid(
+            "checkpoint belongs to a different input or settings; remove it or drop --resume",
+        ));
+    }
+
+    let mut checkpoints = Vec::new();
+    for line in lines {
+        let line = line?;
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        // A torn final line from a crash mid-append is ignored
+        let [entries, offset, digest] = fields[..] else {
+            break;
+        };
+        let (Ok(entries), Ok(offset)) = (entries.parse(), offset.parse()) else {
+            break;
+        };
+        checkpoints.push(Checkpoint {
+            entries,
+            offset,
+            digest: digest.to_string(),
+        });
+    }
+    Ok(checkpoints)
+}
+
+fn suffixed(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compress;
+    use tempfile::TempDir;
+
+    fn job(dir: &TempDir, key: &str) -> Job {
+        Job {
+            log: dir.path().join("out.ckpt"),
+            key: key.to_string(),
+        }
+    }
+
+    fn write_records(writer: &mut CheckpointWriter, range: std::ops::Range<u64>) {
+        for i in range {
+            writeln!(writer, "record {i}").unwrap();
+            writer.checkpoint_if_due(i + 1).unwrap();
+        }
+    }
+
+    fn decode(path: &Path) -> String {
+        let (_, mut reader) = compress::decoder(BufReader::new(File::open(path).unwrap())).unwrap();
+        let mut text = String::new();
+        reader.read_to_string(&mut text).unwrap();
+        text
+    }
+
+    #[test]
+    fn test_resume_after_interruption() {
+        let dir = TempDir::new().unwrap();
+        let output = dir.path().join("out.gz");
+
+        let (mut writer, skip) = CheckpointWriter::create(
+            &output,
+            &job(&dir, "job"),
+            Compression::Gzip,
+            None,
+            1,
+            20,
+            false,
+        )
+        .unwrap();
+        assert_eq!(skip, 0);
+        write_records(&mut writer, 0..10);
+        // Simulate a crash: the partial file also holds data past the last checkpoint
+        drop(writer);
+        assert!(!output.exists());
+
+        let (mut writer, skip) = CheckpointWriter::create(
+            &output,
+            &job(&dir, "job"),
+            Compression::Gzip,
+            None,
+            1,
+            20,
+            true,
+        )
+        .unwrap();
+        // Records are 9 bytes, so checkpoints fell after every third one
+        assert_eq!(skip, 9);
+        write_records(&mut writer, skip..15);
+        writer.finish().unwrap();
+
+        let expected: String = (0..15).map(|i| format!("record {i}\n")).collect();
+        assert_eq!(decode(&output), expected);
+        assert!(!suffixed(&output, ".partial").exists());
+        assert!(!job(&dir, "job").log.exists());
+    }
+
+    #[test]
+    fn test_resume_skips_corrupt_checkpoints() {
+        let dir = TempDir::new().unwrap();
+        let output = dir.path().join("out");
+
+        let (mut writer, _) = CheckpointWriter::create(
+            &output,
+            &job(&dir, "job"),
+            Compression::None,
+            None,
+            1,
+            1,
+            false,
+        )
+        .unwrap();
+        write_records(&mut writer, 0..4);
+        drop(writer);
+
+        // Damage the data covered by the third checkpoint onwards
+        let partial = suffixed(&output, ".partial");
+        let mut data = fs::read(&partial).unwrap();
+        data[20] = b'X';
+        fs::write(&partial, &data).unwrap();
+
+        let (mut writer, skip) = CheckpointWriter::create(
+            &output,
+            &job(&dir, "job"),
+            Compression::None,
+            None,
+            1,
+            1,
+            true,
+        )
+        .unwrap();
+        assert_eq!(skip, 2);
+        write_records(&mut writer, skip..4);
+        writer.finish().unwrap();
+        assert_eq!(
+            fs::read_to_string(&output).unwrap(),
+            "record 0\nrecord 1\nrecord 2\nrecord 3\n"
+        );
+    }
+
+    #[test]
+    fn test_resume_rejects_other_job() {
+        let dir = TempDir::new().unwrap();
+        let output = dir.path().join("out");
+
+        let (mut writer, _) = CheckpointWriter::create(
+            &output,
+            &job(&dir, "first"),
+            Compression::None,
+            None,
+            1,
+            1,
+            false,
+        )
+        .unwrap();
+        write_records(&mut writer, 0..2);
+        drop(writer);
+
+        let result = CheckpointWriter::create(
+            &output,
+            &job(&dir, "second"),
+            Compression::None,
+            None,
+            1,
+            1,
+            true,
+        );
+        assert!(matches!(result, Err(RustUtilsError::InvalidArguments(_))));
+    }
+
+    #[test]
+    fn test_resume_rejects_newer_log() {
+        let dir = TempDir::new().unwrap();
+        let output = dir.path().join("out");
+        let (mut writer, _) = CheckpointWriter::create(
+            &output,
+            &job(&dir, "job"),
+            Compression::None,
+            None,
+            1,
+            1,
+            false,
+        )
+        .unwrap();
+        write_records(&mut writer, 0..2);
+        drop(writer);
+
+        let log_path = job(&dir, "job").log;
+        let log = fs::read_to_string(&log_path).unwrap();
+        assert!(log.starts_with(&format!("{LOG_MAGIC} {LOG_FORMAT_VERSION} job\n")));
+        let newer = log.replacen(
+            &format!(" {LOG_FORMAT_VERSION} "),
+            &format!(" {} ", LOG_FORMAT_VERSION + 1),
+            1,
+        );
+        fs::write(&log_path, newer).unwrap();
+
+        let result = CheckpointWriter::create(
+            &output,
+            &job(&dir, "job"),
+            Compression::None,
+            None,
+            1,
+            1,
+            true,
+        );
+        assert!(matches!(result, Err(RustUtilsError::UnsupportedFormat(_))));
+    }
+
+    #[test]
+    fn test_resume_from_legacy_log() {
+        let dir = TempDir::new().unwrap();
+        let output = dir.path().join("out");
+        let (mut writer, _) = CheckpointWriter::create(
+            &output,
+            &job(&dir, "job"),
+            Compression::None,
+            None,
+            1,
+            1,
+            false,
+        )
+        .unwrap();
+        write_records(&mut writer, 0..3);
+        drop(writer);
+
+        // Earlier versions wrote the log next to the output
+        let legacy = suffixed(&output, ".partial.ckpt");
+        fs::rename(job(&dir, "job").log, &legacy).unwrap();
+
+        let (writer, skip) = CheckpointWriter::create(
+            &output,
+            &job(&dir, "job"),
+            Compression::None,
+            None,
+            1,
+            1,
+            true,
+        )
+        .unwrap();
+        assert_eq!(skip, 3);
+        assert!(!legacy.exists());
+        assert!(job(&dir, "job").log.exists());
+        writer.finish().unwrap();
+    }
+}
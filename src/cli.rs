@@ -1,6 +1,34 @@
-use clap::{Parser, Subcommand};
+use std::fs;
+use std::io::Write;
+use std::num::NonZeroUsize;
+use std::os::fd::RawFd;
+use std::path::{Path, PathBuf};
 
-use crate::commands::remap::RemapArgs;
+use clap::error::ErrorKind;
+use clap::{Args, CommandFactory, Parser, Subcommand};
+use serde::Deserialize;
+
+use crate::caps::Capability;
+use crate::commands::archive::{ArchiveArgs, ArchiveCommands};
+use crate::commands::chown_helper::ChownHelperArgs;
+use crate::commands::copy::CopyArgs;
+use crate::commands::fingerprint::FingerprintArgs;
+use crate::commands::homes::RemapHomesArgs;
+use crate::commands::idmap::{IdmapArgs, IdmapCommands};
+use crate::commands::journal::{JournalArgs, JournalCommands};
+use crate::commands::normalize::NormalizeArgs;
+use crate::commands::remap::{RemapCliArgs, RemapCommands};
+use crate::commands::report::{ReportArgs, ReportCommands};
+use crate::commands::schema::SchemaArgs;
+use crate::commands::send_stream::SendStreamArgs;
+use crate::commands::template::{TemplateArgs, TemplateCommands};
+use crate::commands::with_caps::WithCapsArgs;
+use crate::error::{Result as RustUtilsResult, RustUtilsError};
+use crate::help::HelpTree;
+use crate::i18n::Lang;
+use crate::logfile::LogFileArgs;
+use crate::profile;
+use crate::report::OutputFormat;
 
 #[derive(Parser)]
 #[command(name = "rust-utils")]
@@ -11,19 +39,421 @@ use crate::commands::remap::RemapArgs;
 pub struct Cli {
     #[command(subcommand)]
     pub command: Commands,
+
+    /// Write a JSON run report to FILE when the command finishes
+    #[arg(long, global = true, value_name = "FILE")]
+    pub report: Option<PathBuf>,
+
+    /// Time the statx, fchownat and getdents calls of the run and write their latencies to
+    /// FILE as a profile in folded-stack format when the command finishes
+    #[arg(long, global = true, value_name = "FILE")]
+    pub profile_io: Option<PathBuf>,
+
+    /// Directory for state kept between runs, such as checkpoint logs
+    /// [default: $RUST_UTILS_STATE_DIR, /var/lib/rust-utils for root, else
+    /// $XDG_DATA_HOME/rust-utils]
+    #[arg(long, global = true, value_name = "DIR")]
+    pub state_dir: Option<PathBuf>,
+
+    /// Language of user-facing messages [default: from LC_ALL, LC_MESSAGES or LANG]
+    #[arg(long, global = true, value_enum)]
+    pub lang: Option<Lang>,
+
+    #[command(flatten)]
+    pub log: LogFileArgs,
+
+    #[command(flatten)]
+    pub globals: GlobalArgs,
+
+    /// Print every command and option as JSON and exit
+    #[arg(long, global = true)]
+    pub help_json: bool,
+}
+
+/// Options shared by the whole command family, accepted before or after the command name.
+#[derive(Args, Clone, Debug, Default)]
+pub struct GlobalArgs {
+    /// Show what would change without changing anything (remap, remap undo and copy)
+    #[arg(long, global = true)]
+    pub dry_run: bool,
+
+    /// Log at info level unless RUST_LOG says otherwise, and show details per entry where
+    /// the command has them
+    #[arg(short, long, global = true, conflicts_with = "quiet")]
+    pub verbose: bool,
+
+    /// Log only errors, whatever RUST_LOG says
+    #[arg(short, long, global = true)]
+    pub quiet: bool,
+
+    /// How the summary at the end of every command is printed [default: text]; with json,
+    /// log output goes to stderr so that stdout carries only the report
+    #[arg(long, global = true, value_enum, value_name = "FORMAT")]
+    pub output_format: Option<OutputFormat>,
+
+    /// Read defaults for the global options from this JSON file
+    #[arg(long, global = true, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+
+    /// Worker threads for commands that can use several (remap, archive remap, remap-homes)
+    #[arg(long, global = true, value_name = "N", visible_alias = "jobs")]
+    pub threads: Option<NonZeroUsize>,
+}
+
+/// Defaults for the global options read from `--config`. Options given on the command line
+/// take precedence.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct ConfigFile {
+    dry_run: bool,
+    verbose: bool,
+    quiet: bool,
+    output_format: Option<OutputFormat>,
+    state_dir: Option<PathBuf>,
+    threads: Option<NonZeroUsize>,
+}
+
+impl ConfigFile {
+    fn read(path: &Path) -> RustUtilsResult<Self> {
+        let text = fs::read_to_string(path)?;
+        let config: Self = serde_json::from_str(&text).map_err(|e| {
+            RustUtilsError::InvalidArguments(format!("invalid config {}: {}", path.display(), e))
+        })?;
+        if config.verbose && config.quiet {
+            return Err(RustUtilsError::InvalidArguments(format!(
+                "config {} sets both verbose and quiet",
+                path.display()
+            )));
+        }
+        Ok(config)
+    }
 }
 
 #[derive(Subcommand)]
 pub enum Commands {
     /// Remap UID/GID ranges in LXC filesystem
-    Remap(RemapArgs),
+    #[command(visible_alias = "shift")]
+    Remap(RemapCliArgs),
+    /// Give every entry of each user's home directory the user's own new ID, the homes in
+    /// parallel
+    RemapHomes(RemapHomesArgs),
+    /// Shift a tree's ownership down to a canonical 0-based range, from its detected base
+    Normalize(NormalizeArgs),
+    /// Print a comparable digest of a tree's ownership structure
+    Fingerprint(FingerprintArgs),
+    /// Copy a tree to a new location, remapping UIDs/GIDs on the fly
+    Copy(CopyArgs),
+    /// Rewrite ownership in a `btrfs send` stream from stdin to stdout
+    SendStream(SendStreamArgs),
+    /// Build and import portable container templates
+    Template(TemplateArgs),
+    /// Rewrite ownership inside tar archives
+    Archive(ArchiveArgs),
+    /// Print the JSON Schema of a machine-readable output format
+    Schema(SchemaArgs),
+    /// Work with run reports written by --report
+    Report(ReportArgs),
+    /// Compact, encrypt and prune undo journals, snapshot manifests and partition journals
+    Journal(JournalArgs),
+    /// Plan host ID ranges for containers
+    Idmap(IdmapArgs),
+    /// Run a command with only the capabilities it needs, out of those the binary was
+    /// given with setcap
+    WithCaps(WithCapsArgs),
+    /// Change owners on behalf of unprivileged `remap --chown-helper` runs, as far as a
+    /// policy allows
+    ChownHelper(ChownHelperArgs),
+}
+
+/// Capabilities to change the ownership and modes of entries of a tree, and put back file
+/// capabilities the kernel drops with an owner.
+const CHANGE_TREE: &[Capability] = &[
+    Capability::Chown,
+    Capability::DacOverride,
+    Capability::DacReadSearch,
+    Capability::Fowner,
+    Capability::Fsetid,
+    Capability::Setfcap,
+];
+/// Capabilities to create a tree of entries owned by others, device nodes included.
+const CREATE_TREE: &[Capability] = &[
+    Capability::Chown,
+    Capability::DacOverride,
+    Capability::DacReadSearch,
+    Capability::Fowner,
+    Capability::Fsetid,
+    Capability::Mknod,
+    Capability::Setfcap,
+];
+/// Capabilities to read a whole tree whatever its permissions.
+const READ_TREE: &[Capability] = &[Capability::DacReadSearch];
+
+impl Cli {
+    /// Parse the command line like [`Parser::parse`], also checking the constraints between
+    /// arguments that value parsers cannot see, and exit with usage output if any fails.
+    ///
+    /// With `--help-json` anywhere before a `--`, print [`Cli::help_json`] and exit instead,
+    /// whatever else the command line holds.
+    pub fn parse_checked() -> Self {
+        let args: Vec<_> = std::env::args_os().collect();
+        if args
+            .iter()
+            .skip(1)
+            .take_while(|arg| *arg != "--")
+            .any(|arg| arg == "--help-json")
+        {
+            // Like --help, ignore a reader that went away
+            let _ = writeln!(std::io::stdout(), "{}", Self::help_json());
+            std::process::exit(0);
+        }
+        Self::try_parse_checked_from(args).unwrap_or_else(|e| e.exit())
+    }
+
+    /// The commands and options of the whole command line as pretty-printed JSON.
+    pub fn help_json() -> String {
+        serde_json::to_string_pretty(&HelpTree::new(&Self::command()))
+            .expect("help always serializes")
+    }
+
+    /// Like [`parse_checked`](Self::parse_checked), returning the error instead of exiting.
+    pub fn try_parse_checked_from<I, T>(args: I) -> Result<Self, clap::Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<std::ffi::OsString> + Clone,
+    {
+        let mut cli = Self::try_parse_from(args)?;
+        if let Commands::WithCaps(args) = &cli.command {
+            // Checked now, with its usage on error, although it runs as a command line of its own
+            Self::try_parse_checked_from(args.command_line())?;
+        }
+        // Before anything is read or written as root on the caller's behalf
+        if profile::pkexec_uid().is_some() {
+            cli.check_elevated()
+                .map_err(|e| Self::error(&[], ErrorKind::ArgumentConflict, e))?;
+        }
+        if let Some(path) = &cli.globals.config {
+            let config = ConfigFile::read(path).map_err(|e| Self::error(&[], ErrorKind::Io, e))?;
+            if !cli.globals.verbose && !cli.globals.quiet {
+                cli.globals.verbose = config.verbose;
+                cli.globals.quiet = config.quiet;
+            }
+            cli.globals.dry_run |= config.dry_run;
+            cli.globals.output_format = cli.globals.output_format.or(config.output_format);
+            cli.globals.threads = cli.globals.threads.or(config.threads);
+            cli.state_dir = cli.state_dir.or(config.state_dir);
+        }
+        if let Commands::Remap(RemapCliArgs::Run(args)) = &cli.command {
+            cli.globals.output_format = args.output.or(cli.globals.output_format);
+        }
+        let has_dry_run = cli.command.apply_globals(&cli.globals);
+        if cli.globals.dry_run && !has_dry_run {
+            return Err(Self::error(
+                &[],
+                ErrorKind::ArgumentConflict,
+                format!(
+                    "{} has no dry run (only {} do)",
+                    cli.command.name(),
+                    DRY_RUN_COMMANDS.join(", ")
+                ),
+            ));
+        }
+
+        let (path, checked): (&[&str], _) = match &cli.command {
+            Commands::Remap(RemapCliArgs::Run(args)) => (&["remap"], args.check_ranges()),
+            Commands::Idmap(args) => match &args.command {
+                IdmapCommands::Free(args) => (&["idmap", "free"], args.check_ranges()),
+                _ => return Ok(cli),
+            },
+            _ => return Ok(cli),
+        };
+        if let Err(e) = checked {
+            return Err(Self::error(path, ErrorKind::ValueValidation, e));
+        }
+        Ok(cli)
+    }
+
+    /// Check a command line run through pkexec, which may only run a profile from the
+    /// profile directory, and without options naming files root would read or write.
+    pub fn check_elevated(&self) -> RustUtilsResult<()> {
+        let profile = matches!(
+            &self.command,
+            Commands::Remap(RemapCliArgs::Command(RemapCommands::Profile(args)))
+                if args.profile_dir == Path::new(profile::PROFILE_DIR)
+        );
+        let files = self.report.is_some()
+            || self.profile_io.is_some()
+            || self.state_dir.is_some()
+            || self.log.log_file.is_some()
+            || self.globals.config.is_some();
+        if !profile || files {
+            return Err(RustUtilsError::Permission(
+                "through pkexec only `remap profile NAME` runs, without options naming files"
+                    .to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// A usage error of the (sub)command at `path`, printed with its usage line.
+    fn error(path: &[&str], kind: ErrorKind, message: impl std::fmt::Display) -> clap::Error {
+        let mut command = Self::command();
+        command.build();
+        let mut subcommand = &mut command;
+        for name in path {
+            subcommand = subcommand
+                .find_subcommand_mut(name)
+                .expect("checked subcommands exist");
+        }
+        subcommand.error(kind, message)
+    }
+}
+
+/// Commands with a dry run, by [`Commands::name`]: those given `--dry-run` by
+/// [`Commands::apply_globals`], which refuses it for the rest.
+const DRY_RUN_COMMANDS: &[&str] = &[
+    "remap",
+    "remap-undo",
+    "remap-homes",
+    "normalize",
+    "copy",
+    "journal-compact",
+    "journal-encrypt",
+];
+
+impl Commands {
+    /// Hand the global options to the arguments of the command, returning whether it has
+    /// a dry run.
+    fn apply_globals(&mut self, globals: &GlobalArgs) -> bool {
+        match self {
+            Commands::Remap(RemapCliArgs::Run(args)) => {
+                args.dry_run = globals.dry_run;
+                args.verbose = globals.verbose;
+                args.jobs = globals.threads.unwrap_or(NonZeroUsize::MIN);
+            }
+            Commands::Remap(RemapCliArgs::Command(RemapCommands::Undo(args))) => {
+                args.dry_run = globals.dry_run;
+                args.verbose = globals.verbose;
+            }
+            Commands::Remap(RemapCliArgs::Command(RemapCommands::Profile(args))) => {
+                args.dry_run = globals.dry_run;
+                args.verbose = globals.verbose;
+            }
+            Commands::Remap(RemapCliArgs::Command(RemapCommands::Verify(args))) => {
+                args.verbose = globals.verbose;
+            }
+            Commands::Copy(args) => {
+                args.dry_run = globals.dry_run;
+                args.verbose = globals.verbose;
+            }
+            Commands::RemapHomes(args) => {
+                args.dry_run = globals.dry_run;
+                args.verbose = globals.verbose;
+                args.threads = globals.threads;
+            }
+            Commands::Normalize(args) => {
+                args.dry_run = globals.dry_run;
+                args.verbose = globals.verbose;
+            }
+            Commands::Archive(args) => {
+                let ArchiveCommands::Remap(args) = &mut args.command;
+                args.threads = globals.threads;
+            }
+            Commands::Journal(args) => match &mut args.command {
+                JournalCommands::Compact(args) => args.dry_run = globals.dry_run,
+                JournalCommands::Encrypt(args) => args.compact.dry_run = globals.dry_run,
+            },
+            _ => {}
+        }
+        DRY_RUN_COMMANDS.contains(&self.name())
+    }
+
+    /// Name of the operation as it appears in run reports, e.g. `template-pack`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Commands::Remap(args) => match args {
+                RemapCliArgs::Run(_) => "remap",
+                RemapCliArgs::Command(RemapCommands::Undo(_)) => "remap-undo",
+                RemapCliArgs::Command(RemapCommands::Verify(_)) => "remap-verify",
+                // The profile's remap reports as any other
+                RemapCliArgs::Command(RemapCommands::Profile(_)) => "remap",
+            },
+            Commands::RemapHomes(_) => "remap-homes",
+            Commands::Normalize(_) => "normalize",
+            Commands::Fingerprint(_) => "fingerprint",
+            Commands::Copy(_) => "copy",
+            Commands::SendStream(_) => "send-stream",
+            Commands::Template(args) => match args.command {
+                TemplateCommands::Pack(_) => "template-pack",
+                TemplateCommands::Import(_) => "template-import",
+            },
+            Commands::Archive(args) => match args.command {
+                ArchiveCommands::Remap(_) => "archive-remap",
+            },
+            Commands::Schema(_) => "schema",
+            Commands::Report(args) => match args.command {
+                ReportCommands::Merge(_) => "report-merge",
+            },
+            Commands::Journal(args) => match args.command {
+                JournalCommands::Compact(_) => "journal-compact",
+                JournalCommands::Encrypt(_) => "journal-encrypt",
+            },
+            Commands::Idmap(args) => match args.command {
+                IdmapCommands::Free(_) => "idmap-free",
+                IdmapCommands::Config(_) => "idmap-config",
+                IdmapCommands::Show(_) => "idmap-show",
+            },
+            Commands::WithCaps(_) => "with-caps",
+            Commands::ChownHelper(_) => "chown-helper",
+        }
+    }
+
+    /// Capabilities the command needs to run as a user other than root, or `None` for
+    /// commands `with-caps` does not run: itself, and profiles, which elevate through
+    /// pkexec instead.
+    pub fn capabilities(&self) -> Option<&'static [Capability]> {
+        Some(match self {
+            Commands::Remap(RemapCliArgs::Command(RemapCommands::Profile(_)))
+            | Commands::WithCaps(_) => return None,
+            Commands::Remap(RemapCliArgs::Command(RemapCommands::Verify(_))) => READ_TREE,
+            Commands::Remap(_)
+            | Commands::RemapHomes(_)
+            | Commands::Normalize(_)
+            | Commands::ChownHelper(_) => CHANGE_TREE,
+            Commands::Copy(_) => CREATE_TREE,
+            Commands::Template(args) => match args.command {
+                TemplateCommands::Pack(_) => READ_TREE,
+                TemplateCommands::Import(_) => CREATE_TREE,
+            },
+            Commands::Fingerprint(_) => READ_TREE,
+            Commands::SendStream(_)
+            | Commands::Archive(_)
+            | Commands::Schema(_)
+            | Commands::Report(_)
+            | Commands::Journal(_)
+            | Commands::Idmap(_) => &[],
+        })
+    }
+
+    /// Descriptor given to `--progress-fd`, for commands that report progress.
+    pub fn progress_fd(&self) -> Option<RawFd> {
+        match self {
+            Commands::Remap(RemapCliArgs::Run(args)) => args.progress.progress_fd,
+            Commands::Copy(args) => args.progress.progress_fd,
+            Commands::Archive(args) => match &args.command {
+                ArchiveCommands::Remap(args) => args.progress.progress_fd,
+            },
+            _ => None,
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::commands::copy::ReflinkMode;
+    use crate::compress::Compression;
+    use crate::report::View;
     use clap::Parser;
-    use std::path::PathBuf;
 
     #[test]
     fn test_cli_parsing_remap_basic() {
@@ -37,10 +467,10 @@ mod tests {
             "50000000",
         ];
 
-        let cli = Cli::try_parse_from(args).unwrap();
+        let cli = Cli::try_parse_checked_from(args).unwrap();
 
         match cli.command {
-            Commands::Remap(remap_args) => {
+            Commands::Remap(RemapCliArgs::Run(remap_args)) => {
                 assert_eq!(remap_args.base_directory, PathBuf::from("/test/path"));
                 assert_eq!(remap_args.from_base, 100000);
                 assert_eq!(remap_args.to_base, 50000000);
@@ -50,7 +480,9 @@ mod tests {
                 assert!(!remap_args.uid_only);
                 assert!(!remap_args.gid_only);
                 assert!(remap_args.exclude.is_empty());
+                assert_eq!(remap_args.view, View::Host);
             }
+            _ => panic!("Expected remap command"),
         }
     }
 
@@ -75,10 +507,10 @@ mod tests {
             "tmp/*",
         ];
 
-        let cli = Cli::try_parse_from(args).unwrap();
+        let cli = Cli::try_parse_checked_from(args).unwrap();
 
         match cli.command {
-            Commands::Remap(remap_args) => {
+            Commands::Remap(RemapCliArgs::Run(remap_args)) => {
                 assert_eq!(remap_args.base_directory, PathBuf::from("/test/path"));
                 assert_eq!(remap_args.from_base, 100000);
                 assert_eq!(remap_args.to_base, 50000000);
@@ -89,9 +521,500 @@ mod tests {
                 assert!(!remap_args.gid_only);
                 assert_eq!(remap_args.exclude, vec!["*.log", "tmp/*"]);
             }
+            _ => panic!("Expected remap command"),
+        }
+    }
+
+    #[test]
+    fn test_cli_parsing_remap_container_view() {
+        let args = vec![
+            "rust-utils",
+            "remap",
+            "/test/path",
+            "--from-base",
+            "100000",
+            "--to-base",
+            "50000000",
+            "--view",
+            "container",
+        ];
+
+        let cli = Cli::try_parse_from(args).unwrap();
+
+        match cli.command {
+            Commands::Remap(RemapCliArgs::Run(remap_args)) => {
+                assert_eq!(remap_args.view, View::Container)
+            }
+            _ => panic!("Expected remap command"),
+        }
+    }
+
+    #[test]
+    fn test_cli_parsing_fingerprint() {
+        let args = vec!["rust-utils", "fingerprint", "/srv/rootfs", "--depth", "2"];
+
+        let cli = Cli::try_parse_from(args).unwrap();
+
+        match cli.command {
+            Commands::Fingerprint(fingerprint_args) => {
+                assert_eq!(fingerprint_args.directory, PathBuf::from("/srv/rootfs"));
+                assert_eq!(fingerprint_args.depth, 2);
+                assert!(fingerprint_args.exclude.is_empty());
+            }
+            _ => panic!("Expected fingerprint command"),
+        }
+    }
+
+    #[test]
+    fn test_cli_parsing_copy() {
+        let args = vec![
+            "rust-utils",
+            "copy",
+            "/srv/old",
+            "/srv/new",
+            "--map",
+            "0:100000:65536",
+            "--map",
+            "65536:5000:1",
+        ];
+
+        let cli = Cli::try_parse_from(args).unwrap();
+
+        match cli.command {
+            Commands::Copy(copy_args) => {
+                assert_eq!(copy_args.source, PathBuf::from("/srv/old"));
+                assert_eq!(copy_args.destination, PathBuf::from("/srv/new"));
+                assert_eq!(copy_args.map.len(), 2);
+                assert_eq!(copy_args.map[1].to, 5000);
+                assert_eq!(copy_args.reflink, ReflinkMode::Auto);
+            }
+            _ => panic!("Expected copy command"),
+        }
+
+        // A mapping is required and must be well-formed
+        assert!(Cli::try_parse_from(["rust-utils", "copy", "/a", "/b"]).is_err());
+        assert!(Cli::try_parse_from(["rust-utils", "copy", "/a", "/b", "--map", "0:1"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_template_pack() {
+        let args = vec![
+            "rust-utils",
+            "template",
+            "pack",
+            "/srv/rootfs",
+            "-o",
+            "web.tar",
+            "--normalize-ids",
+        ];
+
+        let cli = Cli::try_parse_from(args).unwrap();
+
+        match cli.command {
+            Commands::Template(template_args) => match template_args.command {
+                TemplateCommands::Pack(pack_args) => {
+                    assert_eq!(pack_args.rootfs, PathBuf::from("/srv/rootfs"));
+                    assert_eq!(pack_args.output, PathBuf::from("web.tar"));
+                    assert!(pack_args.normalize_ids);
+                    assert_eq!(pack_args.from_base, None);
+                    assert_eq!(pack_args.range_size, 65536);
+                }
+                _ => panic!("Expected pack command"),
+            },
+            _ => panic!("Expected template command"),
+        }
+
+        // --from-base only makes sense when normalizing
+        let args = [
+            "rust-utils",
+            "template",
+            "pack",
+            "/r",
+            "-o",
+            "t.tar",
+            "--from-base",
+            "1",
+        ];
+        assert!(Cli::try_parse_from(args).is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_template_import() {
+        let args = vec![
+            "rust-utils",
+            "template",
+            "import",
+            "web.tar",
+            "--target",
+            "/var/lib/lxc/web/rootfs",
+            "--subid-user",
+            "lxc",
+        ];
+
+        let cli = Cli::try_parse_from(args).unwrap();
+
+        match cli.command {
+            Commands::Template(template_args) => match template_args.command {
+                TemplateCommands::Import(import_args) => {
+                    assert_eq!(import_args.archive, PathBuf::from("web.tar"));
+                    assert_eq!(import_args.subid_user.as_deref(), Some("lxc"));
+                    assert_eq!(import_args.subuid_file, PathBuf::from("/etc/subuid"));
+                    assert!(import_args.map.is_empty());
+                }
+                _ => panic!("Expected import command"),
+            },
+            _ => panic!("Expected template command"),
+        }
+
+        // Exactly one source of mappings is required
+        let base = [
+            "rust-utils",
+            "template",
+            "import",
+            "t.tar",
+            "--target",
+            "/t",
+        ];
+        assert!(Cli::try_parse_from(base).is_err());
+        let both = [&base[..], &["--subid-user", "lxc", "--map", "0:1:1"]].concat();
+        assert!(Cli::try_parse_from(both).is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_archive_remap() {
+        let args = vec![
+            "rust-utils",
+            "archive",
+            "remap",
+            "base.tar.zst",
+            "-",
+            "--map",
+            "0:100000:65536",
+            "--compress",
+            "gzip",
+            "--level",
+            "9",
+            "--threads",
+            "8",
+        ];
+
+        let cli = Cli::try_parse_checked_from(args).unwrap();
+
+        match cli.command {
+            Commands::Archive(archive_args) => {
+                assert!(archive_args.writes_stdout());
+                let ArchiveCommands::Remap(remap_args) = archive_args.command;
+                assert_eq!(remap_args.input, PathBuf::from("base.tar.zst"));
+                assert_eq!(remap_args.map.len(), 1);
+                assert_eq!(remap_args.compress, Some(Compression::Gzip));
+                assert_eq!(remap_args.level, Some(9));
+                assert_eq!(remap_args.threads.map(|n| n.get()), Some(8));
+            }
+            _ => panic!("Expected archive command"),
+        }
+    }
+
+    #[test]
+    fn test_cli_parsing_global_report() {
+        let args = vec![
+            "rust-utils",
+            "template",
+            "pack",
+            "/srv/rootfs",
+            "--output",
+            "base.tar",
+            "--report",
+            "run.json",
+        ];
+
+        let cli = Cli::try_parse_from(args).unwrap();
+        assert_eq!(cli.report, Some(PathBuf::from("run.json")));
+        assert_eq!(cli.state_dir, None);
+        assert_eq!(cli.command.name(), "template-pack");
+    }
+
+    #[test]
+    fn test_cli_parsing_remap_profile() {
+        let cli =
+            Cli::try_parse_checked_from(["rust-utils", "--dry-run", "remap", "profile", "web"])
+                .unwrap();
+        assert_eq!(cli.command.name(), "remap");
+        match &cli.command {
+            Commands::Remap(RemapCliArgs::Command(RemapCommands::Profile(args))) => {
+                assert_eq!(args.name, "web");
+                assert_eq!(args.profile_dir, Path::new(profile::PROFILE_DIR));
+                assert!(args.dry_run);
+            }
+            _ => panic!("Expected remap profile command"),
+        }
+        cli.check_elevated().unwrap();
+
+        // Through pkexec nothing else runs, and no file can be named
+        for args in [
+            &[
+                "rust-utils",
+                "remap",
+                "profile",
+                "web",
+                "--profile-dir",
+                "/tmp",
+            ][..],
+            &[
+                "rust-utils",
+                "remap",
+                "profile",
+                "web",
+                "--report",
+                "/etc/passwd",
+            ],
+            &[
+                "rust-utils",
+                "--log-file",
+                "/etc/shadow",
+                "remap",
+                "profile",
+                "web",
+            ],
+            &["rust-utils", "remap", "undo", "undo.journal"],
+        ] {
+            let cli = Cli::try_parse_checked_from(args).unwrap();
+            assert!(cli.check_elevated().is_err(), "{args:?}");
+        }
+    }
+
+    #[test]
+    fn test_cli_parsing_with_caps() {
+        let cli = Cli::try_parse_checked_from([
+            "rust-utils",
+            "with-caps",
+            "--",
+            "remap",
+            "/srv/rootfs",
+            "--from-base",
+            "0",
+            "--to-base",
+            "100000",
+        ])
+        .unwrap();
+        let Commands::WithCaps(args) = &cli.command else {
+            panic!("Expected with-caps command");
+        };
+        let inner = Cli::try_parse_from(args.command_line()).unwrap();
+        assert_eq!(inner.command.name(), "remap");
+        assert!(inner
+            .command
+            .capabilities()
+            .unwrap()
+            .contains(&Capability::Chown));
+        assert_eq!(cli.command.capabilities(), None);
+
+        // The command line run is checked up front
+        let error = Cli::try_parse_checked_from(["rust-utils", "with-caps", "--", "copy", "/a"])
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), ErrorKind::MissingRequiredArgument);
+    }
+
+    #[test]
+    fn test_cli_parsing_remap_undo() {
+        let cli = Cli::try_parse_checked_from([
+            "rust-utils",
+            "remap",
+            "undo",
+            "undo.journal",
+            "--dry-run",
+        ])
+        .unwrap();
+        assert_eq!(cli.command.name(), "remap-undo");
+        match cli.command {
+            Commands::Remap(RemapCliArgs::Command(RemapCommands::Undo(undo_args))) => {
+                assert_eq!(undo_args.journal, PathBuf::from("undo.journal"));
+                assert!(undo_args.dry_run);
+            }
+            _ => panic!("Expected remap undo command"),
+        }
+
+        // Remap options do not apply to undo or profiles
+        assert!(Cli::try_parse_checked_from([
+            "rust-utils",
+            "remap",
+            "profile",
+            "web",
+            "--to-base",
+            "0"
+        ])
+        .is_err());
+        assert!(Cli::try_parse_checked_from([
+            "rust-utils",
+            "remap",
+            "undo",
+            "undo.journal",
+            "--from-base",
+            "0"
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_journal() {
+        let cli = Cli::try_parse_checked_from([
+            "rust-utils",
+            "--dry-run",
+            "journal",
+            "encrypt",
+            "/srv/undo",
+            "-R",
+            "/etc/rust-utils/recipients",
+            "--keep-last",
+            "5",
+        ])
+        .unwrap();
+        assert_eq!(cli.command.name(), "journal-encrypt");
+        match &cli.command {
+            Commands::Journal(JournalArgs {
+                command: JournalCommands::Encrypt(args),
+            }) => {
+                assert_eq!(args.compact.paths, vec![PathBuf::from("/srv/undo")]);
+                assert_eq!(args.compact.keep_last, Some(5));
+                assert!(args.compact.dry_run);
+                assert!(args.recipient.is_empty());
+            }
+            _ => panic!("Expected journal encrypt command"),
+        }
+        // Encrypting needs someone to encrypt to
+        assert!(
+            Cli::try_parse_checked_from(["rust-utils", "journal", "encrypt", "/srv/undo"]).is_err()
+        );
+    }
+
+    #[test]
+    fn test_cli_parsing_remap_verify() {
+        let cli = Cli::try_parse_checked_from([
+            "rust-utils",
+            "--verbose",
+            "remap",
+            "verify",
+            "/srv/rootfs",
+            "--from-base",
+            "100000",
+            "--to-base",
+            "200000",
+        ])
+        .unwrap();
+        assert_eq!(cli.command.name(), "remap-verify");
+        assert_eq!(cli.command.capabilities(), Some(READ_TREE));
+        match &cli.command {
+            Commands::Remap(RemapCliArgs::Command(RemapCommands::Verify(args))) => {
+                assert_eq!(
+                    args.base_directory.as_deref(),
+                    Some(Path::new("/srv/rootfs"))
+                );
+                assert_eq!((args.from_base, args.to_base), (Some(100000), Some(200000)));
+                assert_eq!(args.range_size, 65536);
+                assert!(args.verbose);
+            }
+            _ => panic!("Expected remap verify command"),
+        }
+        assert!(Cli::try_parse_checked_from([
+            "rust-utils",
+            "remap",
+            "verify",
+            "--manifest",
+            "owners.manifest"
+        ])
+        .is_ok());
+
+        // A mapping or a manifest is checked against, not both, and nothing is changed
+        for args in [
+            &["rust-utils", "remap", "verify", "/srv/rootfs"][..],
+            &[
+                "rust-utils",
+                "remap",
+                "verify",
+                "/srv/rootfs",
+                "--from-base",
+                "0",
+            ],
+            &[
+                "rust-utils",
+                "remap",
+                "verify",
+                "/srv/rootfs",
+                "--manifest",
+                "owners.manifest",
+            ],
+            &[
+                "rust-utils",
+                "--dry-run",
+                "remap",
+                "verify",
+                "--manifest",
+                "owners.manifest",
+            ],
+        ] {
+            assert!(Cli::try_parse_checked_from(args).is_err(), "{args:?}");
+        }
+    }
+
+    #[test]
+    fn test_cli_parsing_remap_subid_user() {
+        let cli = Cli::try_parse_checked_from([
+            "rust-utils",
+            "remap",
+            "/srv/rootfs",
+            "--from-base",
+            "0",
+            "--subid-user",
+            "lxc",
+        ])
+        .unwrap();
+        match cli.command {
+            Commands::Remap(RemapCliArgs::Run(remap_args)) => {
+                assert_eq!(remap_args.subid_user.as_deref(), Some("lxc"));
+                assert_eq!(remap_args.subuid_file, PathBuf::from("/etc/subuid"));
+            }
+            _ => panic!("Expected remap command"),
+        }
+
+        // Exactly one of --to-base and --subid-user gives the target
+        for target in [&[][..], &["--to-base", "100000", "--subid-user", "lxc"]] {
+            let error = Cli::try_parse_checked_from(
+                ["rust-utils", "remap", "/srv/rootfs", "--from-base", "0"]
+                    .iter()
+                    .chain(target),
+            )
+            .err()
+            .unwrap();
+            assert!(matches!(
+                error.kind(),
+                ErrorKind::MissingRequiredArgument | ErrorKind::ArgumentConflict
+            ));
         }
     }
 
+    #[test]
+    fn test_command_aliases() {
+        let cli = Cli::try_parse_from([
+            "rust-utils",
+            "shift",
+            "/srv/rootfs",
+            "--from-base",
+            "0",
+            "--to-base",
+            "100000",
+        ])
+        .unwrap();
+        assert_eq!(cli.command.name(), "remap");
+
+        let error = Cli::try_parse_from(["rust-utils", "fingreprint", "/srv/rootfs"])
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), ErrorKind::InvalidSubcommand);
+        assert!(error
+            .to_string()
+            .contains("a similar subcommand exists: 'fingerprint'"));
+    }
+
     #[test]
     fn test_cli_parsing_missing_required_args() {
         let args = vec![
@@ -146,4 +1069,139 @@ mod tests {
         // Should fail with help message, not an error
         assert!(result.is_err());
     }
+
+    #[test]
+    fn test_parse_checked() {
+        let remap = |extra: &[&str]| {
+            let args = [
+                "rust-utils",
+                "remap",
+                "/r",
+                "--from-base",
+                "100000",
+                "--to-base",
+            ];
+            Cli::try_parse_checked_from(args.iter().chain(extra))
+        };
+        assert!(remap(&["200000"]).is_ok());
+
+        let overflow = remap(&["4294967000"]).err().unwrap();
+        assert_eq!(overflow.kind(), ErrorKind::ValueValidation);
+        assert!(overflow
+            .to_string()
+            .contains("to_base + range_size would overflow"));
+        assert!(overflow
+            .render()
+            .to_string()
+            .contains("Usage: rust-utils remap"));
+
+        let conflict = remap(&["200000", "--uid-only", "--gid-only"])
+            .err()
+            .unwrap();
+        assert_eq!(conflict.kind(), ErrorKind::ArgumentConflict);
+        assert!(remap(&["200000", "--resume"]).is_ok());
+        assert!(remap(&["200000", "--resume", "--subtree", "home"]).is_ok());
+        assert!(remap(&["200000", "--range-size", "0"]).is_err());
+
+        let free = [
+            "rust-utils",
+            "idmap",
+            "free",
+            "--first-id",
+            "10",
+            "--last-id",
+            "5",
+        ];
+        assert!(Cli::try_parse_checked_from(free).is_err());
+        // Plain parsing leaves the constraints between arguments to the command
+        assert!(Cli::try_parse_from(free).is_ok());
+    }
+
+    #[test]
+    fn test_global_options() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        // Global options go before or after the command name
+        let cli = Cli::try_parse_checked_from([
+            "rust-utils",
+            "--dry-run",
+            "remap",
+            "/r",
+            "--from-base",
+            "1",
+            "--to-base",
+            "2",
+            "--jobs",
+            "4",
+            "-v",
+        ])?;
+        let Commands::Remap(RemapCliArgs::Run(args)) = cli.command else {
+            panic!("Expected remap command");
+        };
+        assert!(args.dry_run && args.verbose);
+        assert_eq!(args.jobs.get(), 4);
+        assert_eq!(cli.globals.output_format, None);
+
+        let cli = Cli::try_parse_checked_from([
+            "rust-utils",
+            "remap",
+            "/r",
+            "--from-base",
+            "1",
+            "--to-base",
+            "2",
+            "--output",
+            "json",
+        ])?;
+        assert_eq!(cli.globals.output_format, Some(OutputFormat::Json));
+
+        let no_dry_run = Cli::try_parse_checked_from(["rust-utils", "idmap", "free", "--dry-run"])
+            .err()
+            .unwrap();
+        assert_eq!(no_dry_run.kind(), ErrorKind::ArgumentConflict);
+        assert!(no_dry_run
+            .to_string()
+            .contains("idmap-free has no dry run (only remap, remap-undo, remap-homes, normalize, copy, journal-compact, journal-encrypt do)"));
+        assert!(
+            Cli::try_parse_checked_from(["rust-utils", "-v", "-q", "schema", "report"]).is_err()
+        );
+
+        let dir = tempfile::TempDir::new()?;
+        let config = dir.path().join("config.json");
+        fs::write(
+            &config,
+            r#"{"dry-run": true, "verbose": true, "output-format": "json", "threads": 2}"#,
+        )?;
+        let config = config.to_str().unwrap();
+        let cli = Cli::try_parse_checked_from([
+            "rust-utils",
+            "--config",
+            config,
+            "-q",
+            "copy",
+            "/a",
+            "/b",
+            "--map",
+            "0:1:1",
+        ])?;
+        // The command line wins over the file
+        assert!(cli.globals.quiet && !cli.globals.verbose);
+        assert_eq!(cli.globals.output_format, Some(OutputFormat::Json));
+        assert_eq!(cli.globals.threads, NonZeroUsize::new(2));
+        let Commands::Copy(args) = cli.command else {
+            panic!("Expected copy command");
+        };
+        assert!(args.dry_run);
+
+        fs::write(dir.path().join("bad.json"), r#"{"threads": 0}"#)?;
+        let bad = dir.path().join("bad.json");
+        let bad = Cli::try_parse_checked_from([
+            "rust-utils",
+            "--config",
+            bad.to_str().unwrap(),
+            "schema",
+            "report",
+        ]);
+        assert_eq!(bad.err().unwrap().kind(), ErrorKind::Io);
+
+        Ok(())
+    }
 }
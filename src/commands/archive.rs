@@ -0,0 +1,897 @@
+use std::fs;
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::num::NonZeroUsize;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::thread;
+
+use anyhow::Result;
+use clap::{Args, Subcommand};
+use sha2::{Digest, Sha256};
+use tar::{Archive, Builder, EntryType, Header};
+use tracing::{debug, info, warn};
+
+use crate::checkpoint::{CheckpointWriter, Job};
+use crate::compress::{self, Compression, Encoder};
+use crate::error::{Result as RustUtilsResult, RustUtilsError};
+use crate::idmap::{self, IdMap, IdMapping};
+use crate::lxc::LxcIdMapArgs;
+use crate::nested::{self, NestedPolicy};
+use crate::progress::{Counters, Progress, ProgressArgs};
+use crate::remote;
+use crate::report::RunReport;
+use crate::state::StateDir;
+use crate::stream::{self, is_stdio, ByteSize};
+use crate::util::hex;
+
+/// PAX keys regenerated from the rewritten header instead of being copied through.
+const REWRITTEN_PAX_KEYS: &[&[u8]] = &[b"path", b"linkpath", b"size", b"uid", b"gid"];
+
+/// Access control data carried in PAX records. It is copied through untouched, but any IDs
+/// inside it are not remapped, so entries carrying it are counted and reported.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum SecurityRecord {
+    /// POSIX.1e or NFSv4 ACLs (`SCHILY.acl.*` or ACL xattrs)
+    Acl,
+    /// Windows/NTFS security descriptors (`MSWINDOWS.*`, Samba and ntfs-3g xattrs)
+    WindowsDescriptor,
+}
+
+impl SecurityRecord {
+    fn from_key(key: &[u8]) -> Option<Self> {
+        let key = std::str::from_utf8(key).ok()?;
+        if key.starts_with("SCHILY.acl.") {
+            return Some(SecurityRecord::Acl);
+        }
+        if key.starts_with("MSWINDOWS.") {
+            return Some(SecurityRecord::WindowsDescriptor);
+        }
+        let xattr = key
+            .strip_prefix("SCHILY.xattr.")
+            .or_else(|| key.strip_prefix("LIBARCHIVE.xattr."))?;
+        match xattr {
+            "system.posix_acl_access" | "system.posix_acl_default" | "system.nfs4_acl" => {
+                Some(SecurityRecord::Acl)
+            }
+            "security.NTACL" | "system.ntfs_acl" => Some(SecurityRecord::WindowsDescriptor),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Args)]
+pub struct ArchiveArgs {
+    #[command(subcommand)]
+    pub command: ArchiveCommands,
+}
+
+#[derive(Subcommand)]
+pub enum ArchiveCommands {
+    /// Rewrite the ownership of every entry in a tar archive without extracting it
+    Remap(ArchiveRemapArgs),
+}
+
+#[derive(Args)]
+pub struct ArchiveRemapArgs {
+    /// Tar archive to read, optionally gzip/xz/zstd compressed or split into INPUT.000, ...
+    /// parts ("-" reads from stdin; may be an s3:// or http(s):// URL)
+    pub input: PathBuf,
+
+    /// Tar archive to write ("-" writes to stdout; may be an s3:// or http(s):// URL)
+    pub output: PathBuf,
+
+    /// ID mapping applied to both UIDs and GIDs (repeatable)
+    #[arg(
+        long = "map",
+        value_name = "FROM:TO:COUNT",
+        required_unless_present_any = ["idmap", "idmap_file"]
+    )]
+    pub map: Vec<IdMapping>,
+
+    #[command(flatten)]
+    pub lxc: LxcIdMapArgs,
+
+    /// Compression for the output (defaults to the output file's extension, then the input's)
+    #[arg(long, value_enum)]
+    pub compress: Option<Compression>,
+
+    /// Compression level (gzip/xz 0-9, zstd 1-22)
+    #[arg(long)]
+    pub level: Option<u32>,
+
+    /// Split the output into OUTPUT.000, OUTPUT.001, ... parts of at most this size (e.g. 4G)
+    #[arg(long, value_name = "SIZE")]
+    pub split_size: Option<ByteSize>,
+
+    /// Continue an interrupted run from the last verified checkpoint in OUTPUT.partial
+    #[arg(long, conflicts_with = "split_size")]
+    pub resume: bool,
+
+    /// Uncompressed bytes written between checkpoints of a file output (e.g. 1G)
+    #[arg(long, value_name = "SIZE", default_value = "1G")]
+    pub checkpoint_every: ByteSize,
+
+    /// Threads for decompression and compression (the global --threads; defaults to the
+    /// number of CPUs, 1 keeps everything on one thread)
+    #[arg(skip)]
+    pub threads: Option<NonZeroUsize>,
+
+    /// What to do with tar archives stored as entries of the archive
+    #[arg(long, value_enum, default_value_t = NestedPolicy::Warn)]
+    pub nested: NestedPolicy,
+
+    #[command(flatten)]
+    pub progress: ProgressArgs,
+}
+
+impl ArchiveArgs {
+    /// Whether the archive is written to stdout, which must then stay free of log output.
+    pub fn writes_stdout(&self) -> bool {
+        match &self.command {
+            ArchiveCommands::Remap(args) => is_stdio(&args.output),
+        }
+    }
+}
+
+/// Counters reported after rewriting an archive.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ArchiveStats {
+    pub entries: u64,
+    /// Entries whose UID or GID changed
+    pub remapped: u64,
+    /// File data bytes of the entries rewritten
+    pub bytes: u64,
+    /// Entries already written by the interrupted run being resumed
+    pub resumed: u64,
+    /// Nested archives whose entries were rewritten as well
+    pub nested: u64,
+    /// Entries carrying ACL records, copied unchanged
+    pub acls: u64,
+    /// Entries carrying Windows security descriptors, copied unchanged
+    pub security_descriptors: u64,
+}
+
+impl ArchiveStats {
+    fn counters(&self) -> Counters {
+        Counters {
+            entries: self.entries,
+            changed: self.remapped,
+            bytes: self.bytes,
+        }
+    }
+}
+
+/// What [`transform`] tells its `after_entry` callback about each entry it wrote.
+struct EntryDone<'a> {
+    /// Input entries handled so far, including skipped ones
+    index: u64,
+    path: &'a Path,
+    stats: &'a ArchiveStats,
+}
+
+/// How entries are rewritten: the ID maps and what to do with archives nested inside.
+#[derive(Clone, Copy, Debug)]
+pub struct RemapRules<'a> {
+    pub uid_map: &'a IdMap,
+    pub gid_map: &'a IdMap,
+    pub nested: NestedPolicy,
+    /// How many archives deep the entries being rewritten are
+    pub depth: usize,
+}
+
+impl<'a> RemapRules<'a> {
+    /// Translate both UIDs and GIDs through `idmap`, leaving nested archives alone.
+    pub fn new(idmap: &'a IdMap) -> Self {
+        Self::by_kind(idmap, idmap)
+    }
+
+    /// Translate UIDs through `uid_map` and GIDs through `gid_map`, leaving nested archives
+    /// alone.
+    pub fn by_kind(uid_map: &'a IdMap, gid_map: &'a IdMap) -> Self {
+        Self {
+            uid_map,
+            gid_map,
+            nested: NestedPolicy::Skip,
+            depth: 0,
+        }
+    }
+
+    pub fn with_nested(self, nested: NestedPolicy) -> Self {
+        Self { nested, ..self }
+    }
+}
+
+pub struct ArchiveCommand {
+    args: ArchiveArgs,
+    state_dir: Option<PathBuf>,
+}
+
+impl ArchiveCommand {
+    pub fn new(args: ArchiveArgs) -> Self {
+        Self {
+            args,
+            state_dir: None,
+        }
+    }
+
+    /// Keep checkpoint logs in `state_dir` instead of the default state directory.
+    pub fn with_state_dir(mut self, state_dir: Option<PathBuf>) -> Self {
+        self.state_dir = state_dir;
+        self
+    }
+
+    pub fn execute(self) -> Result<RunReport> {
+        let mut report = RunReport::new("archive-remap");
+        match self.args.command {
+            ArchiveCommands::Remap(args) => {
+                let state = StateDir::resolve(self.state_dir.as_deref())?;
+                let stats = remap(&args, &state)?;
+                if stats.resumed > 0 {
+                    info!("Resumed after {} entries", stats.resumed);
+                }
+                info!(
+                    "Rewrote {} entries of {}",
+                    stats.entries,
+                    args.input.display()
+                );
+                info!("Entries remapped: {}", stats.remapped);
+                if stats.nested > 0 {
+                    info!("Nested archives rewritten: {}", stats.nested);
+                }
+                if stats.acls > 0 {
+                    warn!(
+                        "{} entries carry ACLs, copied unchanged; IDs inside them were not remapped",
+                        stats.acls
+                    );
+                }
+                if stats.security_descriptors > 0 {
+                    info!(
+                        "Windows security descriptors passed through: {}",
+                        stats.security_descriptors
+                    );
+                }
+                report
+                    .count("entries", stats.entries)
+                    .count("remapped", stats.remapped)
+                    .count("bytes", stats.bytes)
+                    .count("resumed", stats.resumed)
+                    .count("nested_archives", stats.nested)
+                    .count("acls", stats.acls)
+                    .count("security_descriptors", stats.security_descriptors)
+                    .artifact("archive", &args.output);
+            }
+        }
+        Ok(report)
+    }
+}
+
+/// Stream `args.input` to `args.output`, decompressing and recompressing on the fly.
+/// Checkpoint logs of local file outputs are kept in `state`.
+pub fn remap(args: &ArchiveRemapArgs, state: &StateDir) -> RustUtilsResult<ArchiveStats> {
+    let (uid_map, gid_map) = args.lxc.id_maps(&args.map)?;
+    idmap::log_mappings(&uid_map, &gid_map);
+    let rules = RemapRules::by_kind(&uid_map, &gid_map).with_nested(args.nested);
+    let mut progress = Progress::open(&args.progress, "archive-remap")?;
+
+    let threads = args.threads.map_or_else(
+        || thread::available_parallelism().map_or(1, NonZeroUsize::get),
+        NonZeroUsize::get,
+    );
+    debug!("Using {} threads", threads);
+
+    let input = BufReader::new(stream::open_input(&args.input)?);
+    let (input_compression, input) = if threads > 1 {
+        compress::threaded_decoder(input)?
+    } else {
+        compress::decoder(input)?
+    };
+    let output_compression =
+        Compression::for_output(args.compress, &args.output, input_compression);
+    debug!(
+        "Compression: {:?} -> {:?}",
+        input_compression, output_compression
+    );
+
+    // Plain local files are written through checkpoints so an interrupted run can resume
+    let checkpointed =
+        !is_stdio(&args.output) && remote::url(&args.output).is_none() && args.split_size.is_none();
+    if !checkpointed {
+        if args.resume {
+            return Err(RustUtilsError::InvalidArguments(
+                "--resume needs a local output file".to_string(),
+            ));
+        }
+        let output = stream::create_output(&args.output, args.split_size)?;
+        let encoder = Encoder::with_threads(
+            BufWriter::new(output),
+            output_compression,
+            args.level,
+            threads,
+        )?;
+        let (encoder, stats) = transform(input, encoder, &rules, 0, |_, done| {
+            progress.update(done.path, done.stats.counters());
+            Ok(())
+        })?;
+        stream::finish_output(encoder.finish()?)?;
+        progress.finish(stats.counters());
+        return Ok(stats);
+    }
+
+    let job = Job {
+        log: state.checkpoint_log(&args.output)?,
+        key: job_key(args, output_compression),
+    };
+    let (writer, resumed) = CheckpointWriter::create(
+        &args.output,
+        &job,
+        output_compression,
+        args.level,
+        threads,
+        args.checkpoint_every.0,
+        args.resume,
+    )?;
+    let (writer, stats) = transform(input, writer, &rules, resumed, |writer, done| {
+        progress.update(done.path, done.stats.counters());
+        writer.checkpoint_if_due(done.index)
+    })?;
+    writer.finish()?;
+    progress.finish(stats.counters());
+    Ok(stats)
+}
+
+/// Identity of a run, so checkpoints are only resumed for the same input, mappings and
+/// output format.
+fn job_key(args: &ArchiveRemapArgs, compression: Compression) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(args.input.as_os_str().as_bytes());
+    // A local input must also be unchanged since the interrupted run
+    let metadata =
+        fs::metadata(&args.input).or_else(|_| fs::metadata(stream::part_path(&args.input, 0)));
+    if let Ok(metadata) = metadata {
+        hasher.update(format!("\0{}\0{}", metadata.len(), metadata.mtime()));
+    }
+    for mapping in &args.map {
+        hasher.update(format!("\0{mapping}"));
+    }
+    hasher.update(format!(
+        "\0{:?}\0{:?}\0{:?}",
+        compression, args.level, args.nested
+    ));
+    hex(&hasher.finalize())
+}
+
+/// Copy the tar stream `input` to `output` entry by entry, translating each owner through
+/// `rules`.
+///
+/// File data, modes, times, link targets and remaining PAX records (xattrs, ACLs, high
+/// resolution times) pass through unchanged. User and group names are dropped from entries
+/// whose ID changed so extracting tools cannot resolve them back to the old owner.
+pub fn remap_tar<R: Read, W: Write>(
+    input: R,
+    output: W,
+    rules: &RemapRules,
+) -> RustUtilsResult<(W, ArchiveStats)> {
+    transform(input, output, rules, 0, |_, _| Ok(()))
+}
+
+/// [`remap_tar`] skipping the first `skip` input entries, which an earlier run already
+/// wrote, and calling `after_entry` after writing each entry.
+fn transform<R, W, F>(
+    input: R,
+    output: W,
+    rules: &RemapRules,
+    skip: u64,
+    mut after_entry: F,
+) -> RustUtilsResult<(W, ArchiveStats)>
+where
+    R: Read,
+    W: Write,
+    F: FnMut(&mut W, &EntryDone) -> io::Result<()>,
+{
+    let mut archive = Archive::new(input);
+    let mut builder = Builder::new(output);
+    let mut stats = ArchiveStats::default();
+
+    for (index, entry) in (1..).zip(archive.entries()?) {
+        let mut entry = entry?;
+        if index <= skip {
+            stats.resumed += 1;
+            continue;
+        }
+        let mut header = entry.header().clone();
+        let entry_type = header.entry_type();
+
+        // Global headers apply to the whole archive and carry no owner
+        if entry_type.is_pax_global_extensions() {
+            builder.append(&header, &mut entry)?;
+            let done = EntryDone {
+                index,
+                path: Path::new(""),
+                stats: &stats,
+            };
+            after_entry(builder.get_mut(), &done)?;
+            continue;
+        }
+
+        let path = entry.path()?.into_owned();
+        let link_name = entry.link_name()?.map(|link| link.into_owned());
+
+        let (uid, gid) = (header.uid()?, header.gid()?);
+        let (new_uid, new_gid) = (map_id(rules.uid_map, uid), map_id(rules.gid_map, gid));
+        let mut pax = Vec::new();
+        let mut security = Vec::new();
+        if let Some(extensions) = entry.pax_extensions()? {
+            for extension in extensions {
+                let extension = extension?;
+                let key = extension.key_bytes();
+                let renamed =
+                    (key == b"uname" && new_uid != uid) || (key == b"gname" && new_gid != gid);
+                if REWRITTEN_PAX_KEYS.contains(&key) || renamed {
+                    continue;
+                }
+                if let Some(record) = SecurityRecord::from_key(key) {
+                    if !security.contains(&record) {
+                        security.push(record);
+                    }
+                }
+                pax.push((key.to_vec(), extension.value_bytes().to_vec()));
+            }
+        }
+        for record in &security {
+            debug!("{}: {:?} copied unchanged", path.display(), record);
+            match record {
+                SecurityRecord::Acl => stats.acls += 1,
+                SecurityRecord::WindowsDescriptor => stats.security_descriptors += 1,
+            }
+        }
+
+        header.set_uid(new_uid);
+        header.set_gid(new_gid);
+        if header.username_bytes().is_some() {
+            if new_uid != uid {
+                header.set_username("")?;
+            }
+            if new_gid != gid {
+                header.set_groupname("")?;
+            }
+        }
+        if entry_type == EntryType::GNUSparse {
+            // The reader expands sparse entries, so write them back as plain files
+            header.set_entry_type(EntryType::Regular);
+            header.set_size(entry.size());
+        }
+
+        append_pax_records(&mut builder, &pax)?;
+        match link_name {
+            Some(target) if matches!(entry_type, EntryType::Symlink | EntryType::Link) => {
+                builder.append_link(&mut header, &path, target)?
+            }
+            _ if rules.nested != NestedPolicy::Skip
+                && entry_type.is_file()
+                && nested::has_archive_name(&path) =>
+            {
+                let mut head = Vec::new();
+                (&mut entry)
+                    .take(nested::SNIFF_LEN)
+                    .read_to_end(&mut head)?;
+                let data = io::Cursor::new(head).chain(&mut entry);
+                match nested::sniff(data.get_ref().0.get_ref()) {
+                    Some(compression) if rules.nested == NestedPolicy::Recurse => {
+                        if rules.depth + 1 >= nested::MAX_DEPTH {
+                            warn!(
+                                "Nested archive {} is too deeply nested; not remapped",
+                                path.display()
+                            );
+                            builder.append_data(&mut header, &path, data)?;
+                        } else {
+                            let (mut spool, nested_stats) =
+                                remap_nested(Box::new(data), compression, rules)?;
+                            debug!("Nested archive {}: {:?}", path.display(), nested_stats);
+                            header.set_size(spool.stream_position()?);
+                            spool.seek(SeekFrom::Start(0))?;
+                            builder.append_data(&mut header, &path, spool)?;
+                            stats.nested += 1 + nested_stats.nested;
+                        }
+                    }
+                    Some(_) => {
+                        warn!(
+                            "Nested archive {} not remapped (use --nested recurse)",
+                            path.display()
+                        );
+                        builder.append_data(&mut header, &path, data)?;
+                    }
+                    None => builder.append_data(&mut header, &path, data)?,
+                }
+            }
+            _ => builder.append_data(&mut header, &path, &mut entry)?,
+        }
+
+        stats.entries += 1;
+        stats.bytes += entry.size();
+        if (new_uid, new_gid) != (uid, gid) {
+            stats.remapped += 1;
+        }
+        let done = EntryDone {
+            index,
+            path: &path,
+            stats: &stats,
+        };
+        after_entry(builder.get_mut(), &done)?;
+    }
+
+    let output = builder.into_inner()?;
+    debug!("Archive stats: {:?}", stats);
+    Ok((output, stats))
+}
+
+/// Write `records` as a PAX extended header for the next entry. Unlike
+/// [`Builder::append_pax_extensions`] keys are raw bytes, so records with keys that are not
+/// valid UTF-8 survive the rewrite too.
+fn append_pax_records<W: Write>(
+    builder: &mut Builder<W>,
+    records: &[(Vec<u8>, Vec<u8>)],
+) -> io::Result<()> {
+    if records.is_empty() {
+        return Ok(());
+    }
+
+    let mut data = Vec::new();
+    for (key, value) in records {
+        // Each record is "LEN KEY=VALUE\n", where LEN counts its own digits too
+        let rest = key.len() + value.len() + 3;
+        let mut len = rest + 1;
+        while rest + len.to_string().len() != len {
+            len = rest + len.to_string().len();
+        }
+        data.extend_from_slice(format!("{len} ").as_bytes());
+        data.extend_from_slice(key);
+        data.push(b'=');
+        data.extend_from_slice(value);
+        data.push(b'\n');
+    }
+
+    let mut header = Header::new_ustar();
+    header.set_size(data.len() as u64);
+    header.set_entry_type(EntryType::XHeader);
+    header.set_cksum();
+    builder.append(&header, data.as_slice())
+}
+
+/// Rewrite the archive entry `data` into a temporary file with the same compression,
+/// returning the file positioned at its end. Not generic, so the recursion through
+/// [`transform`] stays finite.
+fn remap_nested(
+    data: Box<dyn Read + '_>,
+    compression: Compression,
+    rules: &RemapRules,
+) -> RustUtilsResult<(fs::File, ArchiveStats)> {
+    let (_, input) = compress::decoder(BufReader::new(data))?;
+    let rules = RemapRules {
+        depth: rules.depth + 1,
+        ..*rules
+    };
+    let encoder = Encoder::new(BufWriter::new(nested::spool_file()?), compression, None)?;
+    let (encoder, stats) = remap_tar(input, encoder, &rules)?;
+    let spool = encoder
+        .finish()?
+        .into_inner()
+        .map_err(io::IntoInnerError::into_error)?;
+    Ok((spool, stats))
+}
+
+/// Translate a header ID; values beyond `u32` cannot be mapped and pass through.
+fn map_id(idmap: &IdMap, id: u64) -> u64 {
+    match u32::try_from(id) {
+        Ok(id) => u64::from(idmap.map(id)),
+        Err(_) => id,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::path::Path;
+
+    fn remap_bytes(input: &[u8], map: &str) -> (Vec<u8>, ArchiveStats) {
+        let idmap = IdMap::new(vec![map.parse().unwrap()]).unwrap();
+        remap_tar(input, Vec::new(), &RemapRules::new(&idmap)).unwrap()
+    }
+
+    fn sample_archive() -> Vec<u8> {
+        let mut builder = Builder::new(Vec::new());
+
+        let mut header = Header::new_ustar();
+        header.set_entry_type(EntryType::Regular);
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_username("root").unwrap();
+        header.set_groupname("root").unwrap();
+        header.set_mode(0o644);
+        header.set_size(5);
+        builder
+            .append_pax_extensions([("SCHILY.xattr.user.note", &b"kept"[..])])
+            .unwrap();
+        builder
+            .append_data(&mut header, "etc/hostname", &b"host\n"[..])
+            .unwrap();
+
+        let mut header = Header::new_ustar();
+        header.set_entry_type(EntryType::Symlink);
+        header.set_uid(70000);
+        header.set_gid(70000);
+        header.set_username("nobody").unwrap();
+        header.set_mode(0o777);
+        header.set_size(0);
+        builder
+            .append_link(
+                &mut header,
+                "etc/long-".to_string() + &"x".repeat(120),
+                "hostname",
+            )
+            .unwrap();
+
+        builder.into_inner().unwrap()
+    }
+
+    #[test]
+    fn test_remap_tar() {
+        let (output, stats) = remap_bytes(&sample_archive(), "0:100000:65536");
+        assert_eq!(
+            stats,
+            ArchiveStats {
+                entries: 2,
+                remapped: 1,
+                bytes: 5,
+                resumed: 0,
+                nested: 0,
+                acls: 0,
+                security_descriptors: 0
+            }
+        );
+
+        let mut archive = Archive::new(&output[..]);
+        let mut entries = archive.entries().unwrap();
+
+        let mut file = entries.next().unwrap().unwrap();
+        assert_eq!(file.header().uid().unwrap(), 100000);
+        assert_eq!(file.header().gid().unwrap(), 100000);
+        assert_eq!(file.header().username().unwrap(), Some(""));
+        let xattr: Vec<_> = file
+            .pax_extensions()
+            .unwrap()
+            .unwrap()
+            .map(|e| e.unwrap().key().unwrap().to_string())
+            .collect();
+        assert_eq!(xattr, ["SCHILY.xattr.user.note"]);
+        let mut data = String::new();
+        file.read_to_string(&mut data).unwrap();
+        assert_eq!(data, "host\n");
+
+        // Unmapped IDs and their names pass through, as do long names
+        let link = entries.next().unwrap().unwrap();
+        assert_eq!(link.header().uid().unwrap(), 70000);
+        assert_eq!(link.header().username().unwrap(), Some("nobody"));
+        assert_eq!(link.path().unwrap().to_str().unwrap().len(), 129);
+        assert_eq!(link.link_name().unwrap().unwrap(), Path::new("hostname"));
+    }
+
+    #[test]
+    fn test_resume_interrupted_remap() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::TempDir::new()?;
+        let output = dir.path().join("out.tar.zst");
+        let idmap = IdMap::new(vec!["0:100000:65536".parse()?])?;
+        let rules = RemapRules::new(&idmap);
+        let job = Job {
+            log: dir.path().join("out.ckpt"),
+            key: "job".to_string(),
+        };
+        let create =
+            |resume| CheckpointWriter::create(&output, &job, Compression::Zstd, None, 1, 1, resume);
+
+        // Checkpoint after every entry, then fail as if killed during the second
+        let (writer, skip) = create(false)?;
+        let result = transform(
+            &sample_archive()[..],
+            writer,
+            &rules,
+            skip,
+            |writer, done| {
+                writer.checkpoint_if_due(done.index)?;
+                if done.index == 1 {
+                    return Err(io::Error::other("interrupted"));
+                }
+                Ok(())
+            },
+        );
+        assert!(result.is_err());
+        assert!(!output.exists());
+
+        let (writer, skip) = create(true)?;
+        assert_eq!(skip, 1);
+        let (writer, stats) = transform(&sample_archive()[..], writer, &rules, skip, |w, done| {
+            w.checkpoint_if_due(done.index)
+        })?;
+        writer.finish()?;
+        assert_eq!((stats.resumed, stats.entries), (1, 1));
+
+        let (_, reader) = compress::decoder(BufReader::new(File::open(&output)?))?;
+        let mut archive = Archive::new(reader);
+        let uids: Vec<u64> = archive
+            .entries()?
+            .map(|e| e.unwrap().header().uid().unwrap())
+            .collect();
+        assert_eq!(uids, [100000, 70000]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_remap_compressed_file() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::TempDir::new()?;
+        let input = dir.path().join("in.tar.xz");
+        let mut encoder = Encoder::new(File::create(&input)?, Compression::Xz, None)?;
+        encoder.write_all(&sample_archive())?;
+        encoder.finish()?;
+
+        // No extension on the output, so the input's compression is kept
+        let output = dir.path().join("out");
+        let args = ArchiveRemapArgs {
+            input,
+            output: output.clone(),
+            map: vec!["0:100000:65536".parse()?],
+            lxc: Default::default(),
+            compress: None,
+            level: None,
+            split_size: None,
+            resume: false,
+            checkpoint_every: ByteSize(1 << 30),
+            threads: None,
+            nested: NestedPolicy::Warn,
+            progress: ProgressArgs::default(),
+        };
+        let state = StateDir::new(dir.path().join("state"));
+        assert_eq!(remap(&args, &state)?.remapped, 1);
+
+        let (compression, reader) = compress::decoder(BufReader::new(File::open(&output)?))?;
+        assert_eq!(compression, Compression::Xz);
+        let mut archive = Archive::new(reader);
+        assert_eq!(archive.entries()?.count(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parallel_remap() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::TempDir::new()?;
+        let input = dir.path().join("in.tar.gz");
+        let mut encoder = Encoder::new(File::create(&input)?, Compression::Gzip, None)?;
+        encoder.write_all(&sample_archive())?;
+        encoder.finish()?;
+
+        // Threaded decoding and block compression, checkpointing after every entry
+        let output = dir.path().join("out.tar.zst");
+        let args = ArchiveRemapArgs {
+            input,
+            output: output.clone(),
+            map: vec!["0:100000:65536".parse()?],
+            lxc: Default::default(),
+            compress: None,
+            level: None,
+            split_size: None,
+            resume: false,
+            checkpoint_every: ByteSize(1),
+            threads: NonZeroUsize::new(4),
+            nested: NestedPolicy::Warn,
+            progress: ProgressArgs::default(),
+        };
+        let state = StateDir::new(dir.path().join("state"));
+        assert_eq!(remap(&args, &state)?.entries, 2);
+
+        let (compression, reader) = compress::decoder(BufReader::new(File::open(&output)?))?;
+        assert_eq!(compression, Compression::Zstd);
+        let mut archive = Archive::new(reader);
+        let uids: Vec<u64> = archive
+            .entries()?
+            .map(|e| e.unwrap().header().uid().unwrap())
+            .collect();
+        assert_eq!(uids, [100000, 70000]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_remap_nested_archive() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let mut inner = Encoder::new(Vec::new(), Compression::Gzip, None)?;
+        inner.write_all(&sample_archive())?;
+        let inner = inner.finish()?;
+
+        let mut builder = Builder::new(Vec::new());
+        let mut header = Header::new_gnu();
+        header.set_entry_type(EntryType::Regular);
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_mode(0o644);
+        header.set_size(inner.len() as u64);
+        builder.append_data(&mut header, "images/base.tar.gz", &inner[..])?;
+        let outer = builder.into_inner()?;
+
+        let idmap = IdMap::new(vec!["0:100000:65536".parse()?])?;
+        let nested_uids = |policy| -> std::result::Result<_, Box<dyn std::error::Error>> {
+            let rules = RemapRules::new(&idmap).with_nested(policy);
+            let (output, stats) = remap_tar(&outer[..], Vec::new(), &rules)?;
+            let mut archive = Archive::new(&output[..]);
+            let entry = archive.entries()?.next().unwrap()?;
+            assert_eq!(entry.path()?, Path::new("images/base.tar.gz"));
+            let (compression, reader) = compress::decoder(BufReader::new(entry))?;
+            assert_eq!(compression, Compression::Gzip);
+            let uids: Vec<u64> = Archive::new(reader)
+                .entries()?
+                .map(|e| e.unwrap().header().uid().unwrap())
+                .collect();
+            Ok((uids, stats.nested))
+        };
+
+        assert_eq!(
+            nested_uids(NestedPolicy::Recurse)?,
+            (vec![100000, 70000], 1)
+        );
+        assert_eq!(nested_uids(NestedPolicy::Warn)?, (vec![0, 70000], 0));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_security_records_pass_through() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let acl = &b"user::rw-,user:builder:r--:1000,group::r--,mask::r--,other::---"[..];
+        let descriptor = &b"\x01\x00\x04\x80\x14\x00\x00\x00"[..];
+        let mut builder = Builder::new(Vec::new());
+        let mut header = Header::new_ustar();
+        header.set_entry_type(EntryType::Regular);
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_mode(0o640);
+        header.set_size(0);
+        builder.append_pax_extensions([
+            ("SCHILY.acl.access", acl),
+            ("MSWINDOWS.rawsd", descriptor),
+            ("SCHILY.xattr.user.note", &b"kept"[..]),
+        ])?;
+        builder.append_data(&mut header, "srv/share/report.doc", io::empty())?;
+        append_pax_records(&mut builder, &[(b"VENDOR.\xff".to_vec(), b"raw".to_vec())])?;
+        builder.append_data(&mut header, "srv/share/other.doc", io::empty())?;
+        let (output, stats) = remap_bytes(&builder.into_inner()?, "0:100000:65536");
+        assert_eq!((stats.acls, stats.security_descriptors), (1, 1));
+
+        let mut archive = Archive::new(&output[..]);
+        let records: Vec<Vec<(Vec<u8>, Vec<u8>)>> = archive
+            .entries()?
+            .map(|entry| {
+                let mut entry = entry.unwrap();
+                entry
+                    .pax_extensions()
+                    .unwrap()
+                    .unwrap()
+                    .map(|e| {
+                        let e = e.unwrap();
+                        (e.key_bytes().to_vec(), e.value_bytes().to_vec())
+                    })
+                    .collect()
+            })
+            .collect();
+        assert_eq!(
+            records,
+            [
+                vec![
+                    (b"SCHILY.acl.access".to_vec(), acl.to_vec()),
+                    (b"MSWINDOWS.rawsd".to_vec(), descriptor.to_vec()),
+                    (b"SCHILY.xattr.user.note".to_vec(), b"kept".to_vec()),
+                ],
+                vec![(b"VENDOR.\xff".to_vec(), b"raw".to_vec())],
+            ]
+        );
+
+        Ok(())
+    }
+}
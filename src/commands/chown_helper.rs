@@ -0,0 +1,88 @@
+use std::fs::{self, Permissions};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::UnixListener;
+use std::path::PathBuf;
+
+use anyhow::Result;
+use clap::Args;
+use tracing::info;
+
+use crate::error::RustUtilsError;
+use crate::helper::{self, Policy};
+use crate::idmap::{IdMap, IdMapping};
+use crate::report::RunReport;
+
+#[derive(Args, Debug)]
+pub struct ChownHelperArgs {
+    /// Unix socket to listen on, open to every user: --allow-client decides who is served
+    #[arg(long, value_name = "PATH")]
+    pub socket: PathBuf,
+
+    /// Directory whose entries clients may have changed, symlinks resolved (repeatable)
+    #[arg(long = "allow-dir", value_name = "DIR", required = true)]
+    pub allow_dirs: Vec<PathBuf>,
+
+    /// UID of a user whose requests are served (repeatable)
+    #[arg(long = "allow-client", value_name = "UID", required = true)]
+    pub allow_clients: Vec<u32>,
+
+    /// UIDs FROM to FROM+COUNT-1 may be changed to the same offset from TO (repeatable)
+    #[arg(long = "allow-uid-map", value_name = "FROM:TO:COUNT")]
+    pub allow_uid_maps: Vec<IdMapping>,
+
+    /// Like --allow-uid-map, for GIDs
+    #[arg(long = "allow-gid-map", value_name = "FROM:TO:COUNT")]
+    pub allow_gid_maps: Vec<IdMapping>,
+}
+
+/// Serves `remap --chown-helper` clients until killed.
+pub struct ChownHelperCommand {
+    args: ChownHelperArgs,
+}
+
+impl ChownHelperCommand {
+    pub fn new(args: ChownHelperArgs) -> Self {
+        Self { args }
+    }
+
+    pub fn execute(self) -> Result<RunReport> {
+        let dirs = self
+            .args
+            .allow_dirs
+            .iter()
+            .map(|dir| {
+                dir.canonicalize().map_err(|e| {
+                    RustUtilsError::DirectoryNotFound(format!("{}: {e}", dir.display()))
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let policy = Policy {
+            dirs,
+            clients: self.args.allow_clients.clone(),
+            uid: IdMap::new(self.args.allow_uid_maps.clone())?,
+            gid: IdMap::new(self.args.allow_gid_maps.clone())?,
+        };
+
+        // A socket left behind by an earlier helper is replaced, anything else kept
+        match fs::symlink_metadata(&self.args.socket) {
+            Ok(metadata) if metadata.file_type().is_socket() => fs::remove_file(&self.args.socket)?,
+            Ok(_) => {
+                return Err(RustUtilsError::InvalidArguments(format!(
+                    "{} exists and is not a socket",
+                    self.args.socket.display()
+                ))
+                .into())
+            }
+            Err(_) => {}
+        }
+        let listener = UnixListener::bind(&self.args.socket)?;
+        fs::set_permissions(&self.args.socket, Permissions::from_mode(0o666))?;
+        info!(
+            "Serving chown requests on {} for {} director(ies)",
+            self.args.socket.display(),
+            policy.dirs.len()
+        );
+        helper::serve(listener, policy)?;
+        Ok(RunReport::new("chown-helper"))
+    }
+}
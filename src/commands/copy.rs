@@ -0,0 +1,639 @@
+use std::collections::HashMap;
+use std::fs::{self, File, Metadata, OpenOptions};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::{lchown, symlink, FileExt, FileTypeExt, MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use clap::{Args, ValueEnum};
+use nix::errno::Errno;
+use nix::sys::stat::{mknod, utimensat, Mode, SFlag, UtimensatFlags};
+use nix::sys::time::TimeSpec;
+use nix::unistd::{lseek, Whence};
+use rustix::fs::{copy_file_range, ioctl_ficlone};
+use tracing::{debug, info, warn};
+use walkdir::WalkDir;
+
+use crate::error::{Result as RustUtilsResult, RustUtilsError};
+use crate::fs::{ensure_empty_destination, should_exclude};
+use crate::idmap::{self, IdMap, IdMapping};
+use crate::lxc::LxcIdMapArgs;
+use crate::progress::{Counters, Progress, ProgressArgs};
+use crate::report::RunReport;
+
+/// Block size used when copying file data through userspace.
+const BLOCK_SIZE: usize = 64 * 1024;
+
+/// Whether file data may be shared with the source via reflinks (FICLONE).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum ReflinkMode {
+    /// Clone where the filesystem supports it, otherwise copy
+    #[default]
+    Auto,
+    /// Fail on files that cannot be cloned
+    Always,
+    /// Always copy the data
+    Never,
+}
+
+#[derive(Args)]
+pub struct CopyArgs {
+    /// Directory tree to copy
+    pub source: PathBuf,
+
+    /// Destination directory (must not exist or be empty)
+    pub destination: PathBuf,
+
+    /// ID mapping applied to both UIDs and GIDs (repeatable)
+    #[arg(
+        long = "map",
+        value_name = "FROM:TO:COUNT",
+        required_unless_present_any = ["idmap", "idmap_file"]
+    )]
+    pub map: Vec<IdMapping>,
+
+    #[command(flatten)]
+    pub lxc: LxcIdMapArgs,
+
+    /// Show what would be copied without writing anything (the global --dry-run)
+    #[arg(skip)]
+    pub dry_run: bool,
+
+    /// Log every entry whose ownership changes (the global --verbose)
+    #[arg(skip)]
+    pub verbose: bool,
+
+    /// Exclude paths matching pattern (can be used multiple times)
+    #[arg(long)]
+    pub exclude: Vec<String>,
+
+    /// Share data blocks with the source on reflink-capable filesystems (btrfs, XFS)
+    #[arg(long, value_enum, default_value_t = ReflinkMode::Auto)]
+    pub reflink: ReflinkMode,
+
+    #[command(flatten)]
+    pub progress: ProgressArgs,
+}
+
+/// Counters reported at the end of a copy.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct CopyStats {
+    pub files: u64,
+    pub directories: u64,
+    pub symlinks: u64,
+    pub special: u64,
+    /// Additional paths recreated as hard links instead of copies
+    pub hard_links: u64,
+    /// Files whose data blocks are shared with the source
+    pub reflinked: u64,
+    /// Entries whose UID or GID changed
+    pub remapped: u64,
+    pub bytes: u64,
+}
+
+impl CopyStats {
+    fn counters(&self) -> Counters {
+        Counters {
+            entries: self.files + self.directories + self.symlinks + self.special + self.hard_links,
+            changed: self.remapped,
+            bytes: self.bytes,
+        }
+    }
+}
+
+pub struct CopyCommand {
+    args: CopyArgs,
+    uid_map: IdMap,
+    gid_map: IdMap,
+    /// Destination path of the first copy of every multiply-linked inode
+    links: HashMap<(u64, u64), PathBuf>,
+    stats: CopyStats,
+}
+
+impl CopyCommand {
+    pub fn new(args: CopyArgs) -> Self {
+        Self {
+            args,
+            uid_map: IdMap::default(),
+            gid_map: IdMap::default(),
+            links: HashMap::new(),
+            stats: CopyStats::default(),
+        }
+    }
+
+    pub fn execute(mut self) -> Result<RunReport> {
+        let stats = self.run()?;
+
+        info!(
+            "Copied {} files, {} directories, {} symlinks, {} special files ({} bytes)",
+            stats.files, stats.directories, stats.symlinks, stats.special, stats.bytes
+        );
+        info!("Hard links preserved: {}", stats.hard_links);
+        info!("Files reflinked: {}", stats.reflinked);
+        info!("Entries remapped: {}", stats.remapped);
+
+        let mut report = RunReport::new("copy");
+        report
+            .count("files", stats.files)
+            .count("directories", stats.directories)
+            .count("symlinks", stats.symlinks)
+            .count("special", stats.special)
+            .count("hard_links", stats.hard_links)
+            .count("reflinked", stats.reflinked)
+            .count("remapped", stats.remapped)
+            .count("bytes", stats.bytes);
+        if !self.args.dry_run {
+            report.artifact("tree", &self.args.destination);
+        }
+        Ok(report)
+    }
+
+    /// Copy the tree and return the counters.
+    pub fn run(&mut self) -> RustUtilsResult<&CopyStats> {
+        self.validate_args()?;
+        let mut progress = Progress::open(&self.args.progress, "copy")?;
+
+        if self.args.dry_run {
+            info!("DRY RUN MODE - No changes will be made");
+        }
+        info!(
+            "Copying {} to {}",
+            self.args.source.display(),
+            self.args.destination.display()
+        );
+        idmap::log_mappings(&self.uid_map, &self.gid_map);
+
+        let source = self.args.source.clone();
+        let exclude = self.args.exclude.clone();
+        // Directory mode and timestamps are applied last so read-only directories can still
+        // be populated and child creation does not disturb their mtime
+        let mut directories = Vec::new();
+
+        for entry in WalkDir::new(&source)
+            .follow_links(false)
+            .sort_by_file_name()
+            .into_iter()
+            .filter_entry(|e| !should_exclude(e.path(), &exclude))
+        {
+            let entry = entry.map_err(|e| RustUtilsError::Io(e.into()))?;
+            let relative = entry.path().strip_prefix(&source).unwrap_or(entry.path());
+            let target = self.args.destination.join(relative);
+            let metadata = entry.path().symlink_metadata()?;
+
+            self.copy_entry(entry.path(), &target, &metadata)?;
+            progress.update(relative, self.stats.counters());
+            if metadata.is_dir() && !self.args.dry_run {
+                directories.push((target, metadata));
+            }
+        }
+
+        for (target, metadata) in directories.iter().rev() {
+            fs::set_permissions(target, fs::Permissions::from_mode(metadata.mode() & 0o7777))?;
+            set_times(target, metadata)?;
+        }
+
+        progress.finish(self.stats.counters());
+        Ok(&self.stats)
+    }
+
+    fn validate_args(&mut self) -> RustUtilsResult<()> {
+        (self.uid_map, self.gid_map) = self.args.lxc.id_maps(&self.args.map)?;
+
+        if !self.args.source.is_dir() {
+            return Err(RustUtilsError::DirectoryNotFound(
+                self.args.source.display().to_string(),
+            ));
+        }
+
+        let destination = &self.args.destination;
+        ensure_empty_destination(destination)?;
+
+        // Copying into the source would make the walk pick up its own output
+        let source = self.args.source.canonicalize()?;
+        let absolute = std::env::current_dir()?.join(destination);
+        let mut ancestor = Some(absolute.as_path());
+        while let Some(path) = ancestor {
+            if let Ok(canonical) = path.canonicalize() {
+                if canonical.starts_with(&source) {
+                    return Err(RustUtilsError::InvalidArguments(format!(
+                        "destination {} is inside the source tree",
+                        destination.display()
+                    )));
+                }
+                break;
+            }
+            ancestor = path.parent();
+        }
+
+        Ok(())
+    }
+
+    fn copy_entry(
+        &mut self,
+        source: &Path,
+        target: &Path,
+        metadata: &Metadata,
+    ) -> RustUtilsResult<()> {
+        // Later paths of an inode share its already-remapped copy
+        if !metadata.is_dir() && metadata.nlink() > 1 {
+            let key = (metadata.dev(), metadata.ino());
+            if let Some(first) = self.links.get(&key) {
+                debug!("Hard link: {} -> {}", target.display(), first.display());
+                if !self.args.dry_run {
+                    fs::hard_link(first, target)?;
+                }
+                self.stats.hard_links += 1;
+                return Ok(());
+            }
+            self.links.insert(key, target.to_path_buf());
+        }
+
+        let (uid, gid) = (metadata.uid(), metadata.gid());
+        let (new_uid, new_gid) = (self.uid_map.map(uid), self.gid_map.map(gid));
+
+        if new_uid != uid || new_gid != gid {
+            self.stats.remapped += 1;
+            if self.args.verbose || self.args.dry_run {
+                info!(
+                    "{}: {}:{} -> {}:{}{}",
+                    source.display(),
+                    uid,
+                    gid,
+                    new_uid,
+                    new_gid,
+                    if self.args.dry_run { " (dry run)" } else { "" }
+                );
+            }
+        }
+
+        let file_type = metadata.file_type();
+        if file_type.is_dir() {
+            self.stats.directories += 1;
+        } else if file_type.is_file() {
+            self.stats.files += 1;
+            self.stats.bytes += metadata.len();
+        } else if file_type.is_symlink() {
+            self.stats.symlinks += 1;
+        } else {
+            self.stats.special += 1;
+        }
+
+        if self.args.dry_run {
+            return Ok(());
+        }
+
+        if file_type.is_dir() {
+            // The destination root may already exist as an empty directory
+            if !target.is_dir() {
+                fs::create_dir(target)?;
+            }
+        } else if file_type.is_file() {
+            if copy_file_data(source, target, self.args.reflink)? == DataCopy::Cloned {
+                self.stats.reflinked += 1;
+            }
+        } else if file_type.is_symlink() {
+            symlink(fs::read_link(source)?, target)?;
+        } else if file_type.is_fifo()
+            || file_type.is_char_device()
+            || file_type.is_block_device()
+            || file_type.is_socket()
+        {
+            let kind = SFlag::from_bits_truncate(metadata.mode() & SFlag::S_IFMT.bits());
+            mknod(
+                target,
+                kind,
+                Mode::from_bits_truncate(0o600),
+                metadata.rdev(),
+            )?;
+        } else {
+            warn!("Skipping unsupported file type: {}", source.display());
+            return Ok(());
+        }
+
+        lchown(target, Some(new_uid), Some(new_gid))?;
+
+        if !file_type.is_dir() {
+            if !file_type.is_symlink() {
+                // After chown, which clears set-ID bits
+                fs::set_permissions(target, fs::Permissions::from_mode(metadata.mode() & 0o7777))?;
+            }
+            set_times(target, metadata)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// How file data ended up in the copy.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum DataCopy {
+    /// Blocks are shared with the source via FICLONE
+    Cloned,
+    Copied,
+}
+
+/// Copy file contents, sharing data blocks with the source where the filesystem allows it.
+pub(crate) fn copy_file_data(
+    source: &Path,
+    target: &Path,
+    reflink: ReflinkMode,
+) -> RustUtilsResult<DataCopy> {
+    let input = File::open(source)?;
+    let output = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(target)?;
+
+    if reflink != ReflinkMode::Never {
+        match ioctl_ficlone(&output, &input) {
+            Ok(_) => return Ok(DataCopy::Cloned),
+            Err(errno) if reflink == ReflinkMode::Always => {
+                return Err(RustUtilsError::OperationFailed(format!(
+                    "cannot reflink {}: {errno}",
+                    source.display()
+                )));
+            }
+            Err(errno) => debug!("Reflink unavailable for {}: {}", source.display(), errno),
+        }
+    }
+
+    copy_extents(&input, &output)?;
+    Ok(DataCopy::Copied)
+}
+
+/// Copy only the data extents of `input`, leaving holes where the source has them.
+fn copy_extents(input: &File, output: &File) -> RustUtilsResult<()> {
+    let len = input.metadata()?.len() as i64;
+    let fd = input.as_raw_fd();
+
+    let mut offset = 0;
+    while offset < len {
+        let start = match lseek(fd, offset, Whence::SeekData) {
+            Ok(start) => start,
+            // Only a trailing hole is left
+            Err(Errno::ENXIO) => break,
+            Err(e) => return Err(e.into()),
+        };
+        let end = lseek(fd, start, Whence::SeekHole)?.min(len);
+        copy_range(input, output, start, end)?;
+        offset = end;
+    }
+    // Extends the file over a trailing hole
+    output.set_len(len as u64)?;
+
+    Ok(())
+}
+
+/// Copy `start..end` in-kernel, which lets btrfs/XFS/NFS share or offload the blocks.
+fn copy_range(input: &File, output: &File, mut start: i64, end: i64) -> RustUtilsResult<()> {
+    while start < end {
+        let (mut off_in, mut off_out) = (start as u64, start as u64);
+        let len = (end - start) as usize;
+        match copy_file_range(input, Some(&mut off_in), output, Some(&mut off_out), len) {
+            // The source shrank while being copied
+            Ok(0) => break,
+            Ok(copied) => start += copied as i64,
+            // Not supported for this pair of files; copy through userspace instead
+            Err(
+                rustix::io::Errno::NOSYS
+                | rustix::io::Errno::XDEV
+                | rustix::io::Errno::OPNOTSUPP
+                | rustix::io::Errno::INVAL,
+            ) => {
+                return copy_range_buffered(input, output, start, end);
+            }
+            Err(e) => return Err(std::io::Error::from(e).into()),
+        }
+    }
+    Ok(())
+}
+
+/// Copy `start..end` through a buffer, skipping all-zero blocks so they stay holes.
+fn copy_range_buffered(
+    input: &File,
+    output: &File,
+    mut start: i64,
+    end: i64,
+) -> RustUtilsResult<()> {
+    let mut buffer = vec![0u8; BLOCK_SIZE];
+    while start < end {
+        let want = BLOCK_SIZE.min((end - start) as usize);
+        let read = input.read_at(&mut buffer[..want], start as u64)?;
+        if read == 0 {
+            break;
+        }
+        let block = &buffer[..read];
+        if block.iter().any(|&b| b != 0) {
+            output.write_all_at(block, start as u64)?;
+        }
+        start += read as i64;
+    }
+    Ok(())
+}
+
+pub(crate) fn set_times(path: &Path, metadata: &Metadata) -> RustUtilsResult<()> {
+    let atime = TimeSpec::new(metadata.atime(), metadata.atime_nsec());
+    let mtime = TimeSpec::new(metadata.mtime(), metadata.mtime_nsec());
+    utimensat(None, path, &atime, &mtime, UtimensatFlags::NoFollowSymlink)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::unistd::{geteuid, getgid, getuid};
+    use tempfile::TempDir;
+
+    fn copy_args(source: &Path, destination: &Path, map: &str) -> CopyArgs {
+        CopyArgs {
+            source: source.to_path_buf(),
+            destination: destination.to_path_buf(),
+            map: vec![map.parse().unwrap()],
+            lxc: Default::default(),
+            dry_run: false,
+            verbose: false,
+            exclude: vec![],
+            reflink: ReflinkMode::Auto,
+            progress: ProgressArgs::default(),
+        }
+    }
+
+    #[test]
+    fn test_copy_tree_structure() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let source = TempDir::new()?;
+        let destination = TempDir::new()?;
+        let target = destination.path().join("copy");
+
+        fs::create_dir(source.path().join("etc"))?;
+        fs::write(source.path().join("etc/hosts"), "127.0.0.1 localhost\n")?;
+        fs::set_permissions(
+            source.path().join("etc/hosts"),
+            fs::Permissions::from_mode(0o640),
+        )?;
+        fs::hard_link(
+            source.path().join("etc/hosts"),
+            source.path().join("hosts.link"),
+        )?;
+        symlink("etc/hosts", source.path().join("hosts.sym"))?;
+
+        let uid = getuid().as_raw();
+        let mut command =
+            CopyCommand::new(copy_args(source.path(), &target, &format!("{uid}:{uid}:1")));
+        let stats = command.run()?;
+        assert_eq!(stats.files, 1);
+        assert_eq!(stats.directories, 2);
+        assert_eq!(stats.symlinks, 1);
+        assert_eq!(stats.hard_links, 1);
+
+        assert_eq!(
+            fs::read_to_string(target.join("etc/hosts"))?,
+            "127.0.0.1 localhost\n"
+        );
+        let hosts = fs::metadata(target.join("etc/hosts"))?;
+        assert_eq!(hosts.mode() & 0o7777, 0o640);
+        assert_eq!(hosts.nlink(), 2);
+        assert_eq!(hosts.ino(), fs::metadata(target.join("hosts.link"))?.ino());
+        assert_eq!(
+            fs::read_link(target.join("hosts.sym"))?,
+            PathBuf::from("etc/hosts")
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_sparse_file() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let source = TempDir::new()?;
+        let destination = TempDir::new()?;
+
+        let sparse = source.path().join("disk.img");
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&sparse)?;
+        file.set_len(16 * 1024 * 1024)?;
+        file.write_all_at(b"header", 0)?;
+        drop(file);
+
+        let target = destination.path().join("disk.img");
+        let method = copy_file_data(&sparse, &target, ReflinkMode::Never)?;
+        assert_eq!(method, DataCopy::Copied);
+
+        let copied = fs::metadata(&target)?;
+        assert_eq!(copied.len(), 16 * 1024 * 1024);
+        // st_blocks counts 512-byte units; a dense copy would need 32768
+        assert!(copied.blocks() < 1024);
+        assert_eq!(&fs::read(&target)?[..6], b"header");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_range_buffered_skips_zero_blocks(
+    ) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = TempDir::new()?;
+        let mut data = vec![0u8; 4 * BLOCK_SIZE];
+        data.extend_from_slice(b"tail");
+        fs::write(temp_dir.path().join("dense"), &data)?;
+
+        let input = File::open(temp_dir.path().join("dense"))?;
+        let output = File::create(temp_dir.path().join("copy"))?;
+        copy_range_buffered(&input, &output, 0, data.len() as i64)?;
+        drop(output);
+
+        assert_eq!(fs::read(temp_dir.path().join("copy"))?, data);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_reflink_modes() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let source = TempDir::new()?;
+        let destination = TempDir::new()?;
+        fs::write(source.path().join("data"), "payload")?;
+
+        let auto = destination.path().join("auto");
+        copy_file_data(&source.path().join("data"), &auto, ReflinkMode::Auto)?;
+        assert_eq!(fs::read_to_string(&auto)?, "payload");
+
+        // Depends on the filesystem backing the temp directory
+        let always = destination.path().join("always");
+        match copy_file_data(&source.path().join("data"), &always, ReflinkMode::Always) {
+            Ok(method) => {
+                assert_eq!(method, DataCopy::Cloned);
+                assert_eq!(fs::read_to_string(&always)?, "payload");
+            }
+            Err(e) => assert!(matches!(e, RustUtilsError::OperationFailed(_))),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_applies_mapping() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        if !geteuid().is_root() {
+            return Ok(());
+        }
+
+        let source = TempDir::new()?;
+        let destination = TempDir::new()?;
+        let target = destination.path().join("copy");
+        fs::write(source.path().join("data"), "payload")?;
+
+        let uid = getuid().as_raw();
+        let gid = getgid().as_raw();
+        let mut args = copy_args(source.path(), &target, &format!("{uid}:200000:1"));
+        if gid != uid {
+            args.map.push(format!("{gid}:300000:1").parse()?);
+        }
+        let mut command = CopyCommand::new(args);
+        assert_eq!(command.run()?.remapped, 2);
+
+        let data = fs::metadata(target.join("data"))?;
+        assert_eq!(data.uid(), 200000);
+        assert_eq!(data.gid(), if gid == uid { 200000 } else { 300000 });
+        // The source is untouched
+        assert_eq!(fs::metadata(source.path().join("data"))?.uid(), uid);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_dry_run_writes_nothing() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let source = TempDir::new()?;
+        let destination = TempDir::new()?;
+        let target = destination.path().join("copy");
+        fs::write(source.path().join("data"), "payload")?;
+
+        let mut args = copy_args(source.path(), &target, "0:100000:65536");
+        args.dry_run = true;
+        let mut command = CopyCommand::new(args);
+        assert_eq!(command.run()?.files, 1);
+        assert!(!target.exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_rejects_bad_destination() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let source = TempDir::new()?;
+        fs::write(source.path().join("data"), "payload")?;
+
+        // Non-empty destination
+        let mut command = CopyCommand::new(copy_args(source.path(), source.path(), "0:1:1"));
+        assert!(matches!(
+            command.run(),
+            Err(RustUtilsError::InvalidArguments(_))
+        ));
+
+        // Destination inside the source
+        let nested = source.path().join("sub/copy");
+        let mut command = CopyCommand::new(copy_args(source.path(), &nested, "0:1:1"));
+        assert!(matches!(
+            command.run(),
+            Err(RustUtilsError::InvalidArguments(_))
+        ));
+
+        Ok(())
+    }
+}
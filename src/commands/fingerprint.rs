@@ -0,0 +1,402 @@
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use clap::Args;
+use sha2::{Digest, Sha256};
+use tracing::{debug, info};
+
+use crate::error::{Result as RustUtilsResult, RustUtilsError};
+use crate::fs::{get_file_metadata, should_exclude};
+use crate::iostats::{self, Syscall, Timed};
+use crate::metacache::{type_tag, CachedEntry, DirKey, MetadataCache, DIR_TAG};
+use crate::report::RunReport;
+use crate::state::StateDir;
+use crate::util::hex;
+
+#[derive(Args)]
+pub struct FingerprintArgs {
+    /// Directory whose ownership structure should be fingerprinted
+    pub directory: PathBuf,
+
+    /// Exclude paths matching pattern (can be used multiple times)
+    #[arg(long)]
+    pub exclude: Vec<String>,
+
+    /// Also print the digest of every directory down to this depth
+    #[arg(long, default_value = "0")]
+    pub depth: usize,
+
+    /// Keep the owners of each directory's entries in the state directory and take them
+    /// from there while the directory's ctime is unchanged, instead of statting them
+    #[arg(long)]
+    pub metadata_cache: bool,
+}
+
+/// Ownership digest of a tree together with its ID histograms.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Fingerprint {
+    /// SHA-256 Merkle rollup over names, file types and owners
+    pub digest: [u8; 32],
+    /// Number of entries covered, including the root
+    pub entries: u64,
+    pub uids: BTreeMap<u32, u64>,
+    pub gids: BTreeMap<u32, u64>,
+}
+
+impl Fingerprint {
+    /// Digest as a lowercase hex string.
+    pub fn hex(&self) -> String {
+        hex(&self.digest)
+    }
+
+    fn count(&mut self, uid: u32, gid: u32) {
+        self.entries += 1;
+        *self.uids.entry(uid).or_default() += 1;
+        *self.gids.entry(gid).or_default() += 1;
+    }
+}
+
+pub struct FingerprintCommand {
+    args: FingerprintArgs,
+    state_dir: Option<PathBuf>,
+    /// Owners of directory entries under `--metadata-cache`
+    cache: RefCell<Option<MetadataCache>>,
+    /// Directories whose entries were taken from the cache, and those read
+    dirs_cached: Cell<u64>,
+    dirs_read: Cell<u64>,
+}
+
+impl FingerprintCommand {
+    pub fn new(args: FingerprintArgs) -> Self {
+        Self {
+            args,
+            state_dir: None,
+            cache: RefCell::new(None),
+            dirs_cached: Cell::new(0),
+            dirs_read: Cell::new(0),
+        }
+    }
+
+    /// Keep the metadata cache in `state_dir` instead of the default state directory.
+    pub fn with_state_dir(mut self, state_dir: Option<PathBuf>) -> Self {
+        self.state_dir = state_dir;
+        self
+    }
+
+    pub fn execute(self) -> Result<RunReport> {
+        if !self.args.directory.is_dir() {
+            return Err(RustUtilsError::DirectoryNotFound(
+                self.args.directory.display().to_string(),
+            )
+            .into());
+        }
+
+        info!("Fingerprinting {}", self.args.directory.display());
+
+        let mut subtrees = Vec::new();
+        let fingerprint = self.compute(&mut subtrees)?;
+
+        println!("fingerprint: sha256:{}", fingerprint.hex());
+        println!("entries: {}", fingerprint.entries);
+        println!("uids: {}", format_histogram(&fingerprint.uids));
+        println!("gids: {}", format_histogram(&fingerprint.gids));
+        for (path, digest) in subtrees {
+            println!("{}  {}", hex(&digest), path.display());
+        }
+
+        let mut report = RunReport::new("fingerprint");
+        report
+            .count("entries", fingerprint.entries)
+            .count("uids", fingerprint.uids.len() as u64)
+            .count("gids", fingerprint.gids.len() as u64);
+        if self.args.metadata_cache {
+            info!(
+                "Metadata cache: {} directories unchanged, {} read",
+                self.dirs_cached.get(),
+                self.dirs_read.get()
+            );
+            report
+                .count("dirs_cached", self.dirs_cached.get())
+                .count("dirs_read", self.dirs_read.get());
+        }
+        Ok(report)
+    }
+
+    /// Compute the fingerprint, collecting per-directory digests up to `--depth`.
+    pub fn compute(&self, subtrees: &mut Vec<(PathBuf, [u8; 32])>) -> RustUtilsResult<Fingerprint> {
+        let root = &self.args.directory;
+        let metadata = get_file_metadata(root)?;
+        let cache_path = if self.args.metadata_cache {
+            let path = StateDir::resolve(self.state_dir.as_deref())?.metadata_cache(root)?;
+            debug!("Metadata cache: {}", path.display());
+            *self.cache.borrow_mut() = Some(MetadataCache::load(&path));
+            Some(path)
+        } else {
+            None
+        };
+
+        let mut fingerprint = Fingerprint::default();
+        fingerprint.count(metadata.uid(), metadata.gid());
+
+        let children = self.digest_dir(root, &metadata, 1, &mut fingerprint, subtrees)?;
+
+        let mut hasher = Sha256::new();
+        hasher.update(entry_record(&entry_of(OsString::new(), &metadata)));
+        hasher.update(children);
+        fingerprint.digest = hasher.finalize().into();
+
+        if let (Some(path), Some(cache)) = (cache_path, self.cache.take()) {
+            cache.save(&path)?;
+        }
+        Ok(fingerprint)
+    }
+
+    fn digest_dir(
+        &self,
+        dir: &Path,
+        metadata: &fs::Metadata,
+        depth: usize,
+        fingerprint: &mut Fingerprint,
+        subtrees: &mut Vec<(PathBuf, [u8; 32])>,
+    ) -> RustUtilsResult<[u8; 32]> {
+        let mut hasher = Sha256::new();
+        for mut entry in self.entries(dir, metadata)? {
+            let path = dir.join(&entry.name);
+            if should_exclude(&path, &self.args.exclude) {
+                debug!("Excluded from fingerprint: {}", path.display());
+                continue;
+            }
+
+            // Directories are statted all the same, as their ctimes decide what is cached
+            let metadata = if entry.tag == DIR_TAG {
+                let metadata = get_file_metadata(&path)?;
+                entry = entry_of(entry.name, &metadata);
+                Some(metadata)
+            } else {
+                None
+            };
+            fingerprint.count(entry.uid, entry.gid);
+            hasher.update(entry_record(&entry));
+
+            if let Some(metadata) = metadata.filter(|metadata| metadata.is_dir()) {
+                let digest = self.digest_dir(&path, &metadata, depth + 1, fingerprint, subtrees)?;
+                if depth <= self.args.depth {
+                    subtrees.push((path, digest));
+                }
+                hasher.update(digest);
+            }
+        }
+
+        Ok(hasher.finalize().into())
+    }
+
+    /// The entries of the directory `dir`, from the metadata cache while its ctime is
+    /// unchanged, in byte order, which keeps the digest independent of locale and readdir
+    /// order.
+    fn entries(&self, dir: &Path, metadata: &fs::Metadata) -> RustUtilsResult<Vec<CachedEntry>> {
+        let key = DirKey::of(metadata);
+        if let Some(entries) = self
+            .cache
+            .borrow_mut()
+            .as_mut()
+            .and_then(|cache| cache.get(key))
+        {
+            self.dirs_cached.set(self.dirs_cached.get() + 1);
+            return Ok(entries);
+        }
+
+        let mut entries = Vec::new();
+        let listing = iostats::timed(Syscall::Getdents, || fs::read_dir(dir))?;
+        for entry in Timed::new(Syscall::Getdents, listing) {
+            let name = entry?.file_name();
+            entries.push(entry_of(name.clone(), &get_file_metadata(&dir.join(name))?));
+        }
+        entries.sort_by(|a, b| a.name.as_bytes().cmp(b.name.as_bytes()));
+        self.dirs_read.set(self.dirs_read.get() + 1);
+        if let Some(cache) = self.cache.borrow_mut().as_mut() {
+            cache.insert(key, entries.clone());
+        }
+        Ok(entries)
+    }
+}
+
+fn entry_of(name: OsString, metadata: &fs::Metadata) -> CachedEntry {
+    CachedEntry {
+        name,
+        tag: type_tag(metadata),
+        uid: metadata.uid(),
+        gid: metadata.gid(),
+    }
+}
+
+/// Length-prefixed record of one entry so distinct trees cannot produce the same byte stream.
+fn entry_record(entry: &CachedEntry) -> Vec<u8> {
+    let name = entry.name.as_bytes();
+    let mut record = Vec::with_capacity(name.len() + 17);
+    record.extend_from_slice(&(name.len() as u64).to_le_bytes());
+    record.extend_from_slice(name);
+    record.push(entry.tag);
+    record.extend_from_slice(&entry.uid.to_le_bytes());
+    record.extend_from_slice(&entry.gid.to_le_bytes());
+    record
+}
+
+fn format_histogram(histogram: &BTreeMap<u32, u64>) -> String {
+    histogram
+        .iter()
+        .map(|(id, count)| format!("{id}={count}"))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use tempfile::TempDir;
+
+    fn fingerprint(dir: &Path, exclude: Vec<String>) -> Fingerprint {
+        let command = FingerprintCommand::new(FingerprintArgs {
+            directory: dir.to_path_buf(),
+            exclude,
+            depth: 0,
+            metadata_cache: false,
+        });
+        command.compute(&mut Vec::new()).unwrap()
+    }
+
+    #[test]
+    fn test_fingerprint_identical_trees() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let first = TempDir::new()?;
+        let second = TempDir::new()?;
+
+        // Same structure, created in a different order
+        for (dir, order) in [(&first, ["a", "b"]), (&second, ["b", "a"])] {
+            for name in order {
+                fs::create_dir(dir.path().join(name))?;
+                File::create(dir.path().join(name).join("file"))?;
+            }
+        }
+
+        let a = fingerprint(first.path(), vec![]);
+        let b = fingerprint(second.path(), vec![]);
+        assert_eq!(a, b);
+        assert_eq!(a.entries, 5);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fingerprint_detects_structure_change(
+    ) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = TempDir::new()?;
+        fs::create_dir(temp_dir.path().join("etc"))?;
+        File::create(temp_dir.path().join("etc/passwd"))?;
+
+        let before = fingerprint(temp_dir.path(), vec![]);
+
+        fs::rename(
+            temp_dir.path().join("etc/passwd"),
+            temp_dir.path().join("etc/group"),
+        )?;
+        let after = fingerprint(temp_dir.path(), vec![]);
+        assert_ne!(before.digest, after.digest);
+        assert_eq!(before.uids, after.uids);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fingerprint_histogram_and_exclude(
+    ) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = TempDir::new()?;
+        File::create(temp_dir.path().join("keep.txt"))?;
+        File::create(temp_dir.path().join("skip.log"))?;
+
+        let uid = nix::unistd::getuid().as_raw();
+        let full = fingerprint(temp_dir.path(), vec![]);
+        assert_eq!(full.uids.get(&uid), Some(&3));
+
+        let filtered = fingerprint(temp_dir.path(), vec!["*.log".to_string()]);
+        assert_eq!(filtered.entries, 2);
+        assert_ne!(full.digest, filtered.digest);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fingerprint_subtree_depth() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = TempDir::new()?;
+        fs::create_dir_all(temp_dir.path().join("var/lib"))?;
+
+        let command = FingerprintCommand::new(FingerprintArgs {
+            directory: temp_dir.path().to_path_buf(),
+            exclude: vec![],
+            depth: 1,
+            metadata_cache: false,
+        });
+        let mut subtrees = Vec::new();
+        command.compute(&mut subtrees)?;
+
+        let paths: Vec<_> = subtrees.into_iter().map(|(path, _)| path).collect();
+        assert_eq!(paths, vec![temp_dir.path().join("var")]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fingerprint_metadata_cache() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = TempDir::new()?;
+        let tree = temp_dir.path().join("tree");
+        fs::create_dir_all(tree.join("etc/ssl"))?;
+        File::create(tree.join("etc/passwd"))?;
+        let run = || -> RustUtilsResult<(Fingerprint, u64, u64)> {
+            let command = FingerprintCommand::new(FingerprintArgs {
+                directory: tree.clone(),
+                exclude: vec![],
+                depth: 0,
+                metadata_cache: true,
+            })
+            .with_state_dir(Some(temp_dir.path().join("state")));
+            let fingerprint = command.compute(&mut Vec::new())?;
+            Ok((
+                fingerprint,
+                command.dirs_cached.get(),
+                command.dirs_read.get(),
+            ))
+        };
+
+        let (first, cached, read) = run()?;
+        assert_eq!((cached, read), (0, 3));
+        assert_eq!(first, fingerprint(&tree, vec![]));
+        let (second, cached, read) = run()?;
+        assert_eq!((cached, read), (3, 0));
+        assert_eq!(second, first);
+
+        // Only the directory that changed is read again
+        File::create(tree.join("etc/group"))?;
+        let (third, cached, read) = run()?;
+        assert_eq!((cached, read), (2, 1));
+        assert_eq!(third, fingerprint(&tree, vec![]));
+        assert_ne!(third.digest, first.digest);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fingerprint_nonexistent_directory() {
+        let command = FingerprintCommand::new(FingerprintArgs {
+            directory: PathBuf::from("/nonexistent/fingerprint/dir"),
+            exclude: vec![],
+            depth: 0,
+            metadata_cache: false,
+        });
+        assert!(command.execute().is_err());
+    }
+}
@@ -0,0 +1,417 @@
+//! `remap-homes`: give every entry of each user's home directory that user's own new ID, as
+//! when the users of several servers are consolidated on one, with the homes remapped in
+//! parallel.
+//!
+//! Unlike `remap`, which shifts a range of IDs to another range, each home collapses onto a
+//! single UID and GID, whatever its entries were owned by before.
+
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, Permissions};
+use std::io;
+use std::num::NonZeroUsize;
+use std::os::unix::fs::{lchown, MetadataExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+use anyhow::Result;
+use clap::Args;
+use nix::errno::Errno;
+use tracing::{info, warn};
+use walkdir::{DirEntry, WalkDir};
+
+use crate::diagnose::Failures;
+use crate::error::{Result as RustUtilsResult, RustUtilsError};
+use crate::lock::RunLock;
+use crate::logfile::ENTRY_TARGET;
+use crate::passwd::NamedMapping;
+use crate::report::RunReport;
+use crate::signals;
+use crate::state::StateDir;
+use crate::undo::Owner;
+
+#[derive(Args, Debug)]
+pub struct RemapHomesArgs {
+    /// Directory holding the home directories, each named after its user
+    #[arg(default_value = "/home")]
+    pub homes_directory: PathBuf,
+
+    /// Give every entry of the home directory NAME the UID and GID TO, e.g. alice:100000
+    #[arg(long, value_name = "NAME:TO", num_args = 1.., required = true)]
+    pub map_users: Vec<NamedMapping>,
+
+    /// Leave the GIDs of the entries as they are, e.g. for groups shared between users
+    #[arg(long)]
+    pub keep_groups: bool,
+
+    /// Show what would be changed without making modifications (the global --dry-run)
+    #[arg(skip)]
+    pub dry_run: bool,
+
+    /// Log every entry whose ownership changes (the global --verbose)
+    #[arg(skip)]
+    pub verbose: bool,
+
+    /// Homes remapped at once (the global --threads, by default the number of CPUs)
+    #[arg(skip)]
+    pub threads: Option<NonZeroUsize>,
+}
+
+/// What became of one home directory.
+#[derive(Debug, Default)]
+struct HomeOutcome {
+    entries: u64,
+    changed: u64,
+    errors: Vec<(PathBuf, String)>,
+}
+
+/// Remaps each user's home directory to the user's single new ID.
+pub struct RemapHomesCommand {
+    args: RemapHomesArgs,
+    state_dir: Option<PathBuf>,
+    failures: Mutex<Failures>,
+}
+
+impl RemapHomesCommand {
+    pub fn new(args: RemapHomesArgs) -> Self {
+        Self {
+            args,
+            state_dir: None,
+            failures: Mutex::default(),
+        }
+    }
+
+    /// Keep the locks of the homes in `state_dir` instead of the default state directory.
+    pub fn with_state_dir(mut self, state_dir: Option<PathBuf>) -> Self {
+        self.state_dir = state_dir;
+        self
+    }
+
+    pub fn execute(self) -> Result<RunReport> {
+        let homes = self.homes()?;
+        let _locks = if self.args.dry_run {
+            Vec::new()
+        } else {
+            self.lock(&homes)?
+        };
+        let threads = self
+            .args
+            .threads
+            .map_or_else(
+                || thread::available_parallelism().map_or(1, NonZeroUsize::get),
+                NonZeroUsize::get,
+            )
+            .min(homes.len());
+        info!(
+            "Remapping {} home director(ies) in {}, {} at a time",
+            homes.len(),
+            self.args.homes_directory.display(),
+            threads
+        );
+
+        // Each worker takes the next home not yet taken until none are left
+        let next = AtomicUsize::new(0);
+        let mut outcomes: Vec<(usize, HomeOutcome)> = thread::scope(|scope| {
+            let workers: Vec<_> = (0..threads)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut done = Vec::new();
+                        loop {
+                            let i = next.fetch_add(1, Ordering::Relaxed);
+                            let Some((home, mapping)) = homes.get(i) else {
+                                break;
+                            };
+                            done.push((i, self.remap_home(home, mapping.to)));
+                        }
+                        done
+                    })
+                })
+                .collect();
+            workers
+                .into_iter()
+                .flat_map(|worker| worker.join().expect("home worker panicked"))
+                .collect()
+        });
+        outcomes.sort_by_key(|(i, _)| *i);
+
+        let mut report = RunReport::new("remap-homes");
+        let (mut entries, mut changed) = (0, 0);
+        for (i, outcome) in &outcomes {
+            let (home, mapping) = &homes[*i];
+            info!(
+                "{}: {} of {} entries given {}, {} failed",
+                home.display(),
+                outcome.changed,
+                outcome.entries,
+                mapping.to,
+                outcome.errors.len()
+            );
+            for (path, message) in &outcome.errors {
+                report.error(Some(path), message);
+            }
+            entries += outcome.entries;
+            changed += outcome.changed;
+        }
+        report
+            .count("homes", homes.len() as u64)
+            .count("entries", entries)
+            .count("remapped", changed);
+        let failures = self.failures.into_inner().unwrap_or_default();
+        failures.log();
+        failures.summarize(&mut report);
+        if let Some(signal) = signals::received() {
+            warn!(
+                "Stopped by {} after {} entries, {} of them changed; run again to finish",
+                signal, entries, changed
+            );
+            report.success = false;
+            report.interrupted = Some(signal.to_string());
+        }
+        Ok(report)
+    }
+
+    /// The home directory of each user mapped, checked to be directories mapped to IDs
+    /// of their own.
+    fn homes(&self) -> RustUtilsResult<Vec<(PathBuf, &NamedMapping)>> {
+        let mut names = HashSet::new();
+        let mut ids = HashMap::new();
+        let mut homes = Vec::new();
+        for mapping in &self.args.map_users {
+            let name = &mapping.name;
+            let mut components = Path::new(name).components();
+            if !matches!(
+                (components.next(), components.next()),
+                (Some(Component::Normal(_)), None)
+            ) {
+                return Err(RustUtilsError::InvalidArguments(format!(
+                    "'{name}' is not a user name"
+                )));
+            }
+            if !names.insert(name) {
+                return Err(RustUtilsError::InvalidArguments(format!(
+                    "user {name} is mapped more than once"
+                )));
+            }
+            if let Some(other) = ids.insert(mapping.to, name) {
+                return Err(RustUtilsError::InvalidArguments(format!(
+                    "{} and {} are both mapped to {}; each home needs an ID of its own",
+                    other, name, mapping.to
+                )));
+            }
+            let home = self.args.homes_directory.join(name);
+            if !fs::symlink_metadata(&home).is_ok_and(|metadata| metadata.is_dir()) {
+                return Err(RustUtilsError::DirectoryNotFound(format!(
+                    "home directory of {} ({})",
+                    name,
+                    home.display()
+                )));
+            }
+            homes.push((home, mapping));
+        }
+        Ok(homes)
+    }
+
+    /// Lock every home against other runs, as `remap` locks its tree.
+    fn lock(&self, homes: &[(PathBuf, &NamedMapping)]) -> RustUtilsResult<Vec<RunLock>> {
+        let locks = StateDir::resolve(self.state_dir.as_deref()).and_then(|state| {
+            homes
+                .iter()
+                .map(|(home, _)| {
+                    let home = home.canonicalize()?;
+                    RunLock::acquire(&state.tree_lock(&home)?, true, &home)
+                })
+                .collect()
+        });
+        match locks {
+            Err(e @ RustUtilsError::InUse(_)) => Err(e),
+            Err(e) => {
+                warn!("Cannot lock the homes against other runs: {}", e);
+                Ok(Vec::new())
+            }
+            locks => locks,
+        }
+    }
+
+    /// Give every entry of `home` the owner `to`, until a signal asks to stop.
+    fn remap_home(&self, home: &Path, to: u32) -> HomeOutcome {
+        let mut outcome = HomeOutcome::default();
+        for entry in WalkDir::new(home) {
+            if signals::received().is_some() {
+                break;
+            }
+            let result = entry
+                .map_err(|e| {
+                    let path = e.path().unwrap_or(home).to_path_buf();
+                    (path, io::Error::from(e))
+                })
+                .and_then(|entry| {
+                    outcome.entries += 1;
+                    self.remap_entry(&entry, to)
+                        .map_err(|e| (entry.into_path(), e))
+                });
+            match result {
+                Ok(true) => outcome.changed += 1,
+                Ok(false) => {}
+                Err((path, e)) => {
+                    warn!("Failed to process {}: {}", path.display(), e);
+                    let errno = e.raw_os_error().map(Errno::from_i32);
+                    let class = self
+                        .failures
+                        .lock()
+                        .map(|mut failures| failures.record(errno, Some(&path)));
+                    warn!(
+                        target: ENTRY_TARGET,
+                        event = "failed",
+                        path = %path.display(),
+                        error = %e,
+                        category = class.map_or("other", |class| class.name())
+                    );
+                    outcome.errors.push((path, e.to_string()));
+                }
+            }
+        }
+        outcome
+    }
+
+    /// Give the entry the owner `to`, returning whether it had another.
+    fn remap_entry(&self, entry: &DirEntry, to: u32) -> io::Result<bool> {
+        let metadata = entry.metadata()?;
+        let old = Owner {
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+        };
+        let new = Owner {
+            uid: to,
+            gid: if self.args.keep_groups { old.gid } else { to },
+        };
+        if old == new {
+            return Ok(false);
+        }
+        if self.args.verbose {
+            info!("{}: {} -> {}", entry.path().display(), old, new);
+        }
+        if !self.args.dry_run {
+            lchown(entry.path(), Some(new.uid), Some(new.gid))?;
+            // A new owner clears the set-user-ID and set-group-ID bits of files
+            let mode = metadata.mode();
+            if metadata.is_file() && mode & 0o6000 != 0 {
+                fs::set_permissions(entry.path(), Permissions::from_mode(mode & 0o7777))?;
+            }
+        }
+        info!(
+            target: ENTRY_TARGET,
+            event = "remapped",
+            path = %entry.path().display(),
+            old_uid = old.uid,
+            old_gid = old.gid,
+            uid = new.uid,
+            gid = new.gid,
+            dry_run = self.args.dry_run
+        );
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn args(homes: &Path, map_users: &[&str]) -> RemapHomesArgs {
+        RemapHomesArgs {
+            homes_directory: homes.to_path_buf(),
+            map_users: map_users.iter().map(|m| m.parse().unwrap()).collect(),
+            keep_groups: false,
+            dry_run: false,
+            verbose: false,
+            threads: NonZeroUsize::new(2),
+        }
+    }
+
+    fn owner(path: &Path) -> io::Result<(u32, u32)> {
+        let metadata = fs::symlink_metadata(path)?;
+        Ok((metadata.uid(), metadata.gid()))
+    }
+
+    #[test]
+    fn test_execute_remap_homes() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = TempDir::new()?;
+        let homes = temp_dir.path();
+        for (user, id) in [("alice", 1000), ("bob", 1001), ("carol", 1002)] {
+            fs::create_dir_all(homes.join(user).join(".config"))?;
+            fs::write(homes.join(user).join(".config/settings"), "")?;
+            for path in ["", ".config", ".config/settings"] {
+                lchown(homes.join(user).join(path), Some(id), Some(id))?;
+            }
+        }
+        // Everything in a home becomes the user's, whoever owned it
+        lchown(homes.join("bob/.config"), Some(0), Some(100))?;
+        std::os::unix::fs::symlink("settings", homes.join("alice/.config/link"))?;
+        fs::set_permissions(
+            homes.join("alice/.config/settings"),
+            Permissions::from_mode(0o4755),
+        )?;
+        let state = temp_dir.path().join("state");
+
+        let mut dry_run = args(homes, &["alice:100000", "bob:100001"]);
+        dry_run.dry_run = true;
+        let report = RemapHomesCommand::new(dry_run).execute()?;
+        assert_eq!(report.counts["remapped"], 7);
+        assert_eq!(owner(&homes.join("alice"))?, (1000, 1000));
+
+        let report = RemapHomesCommand::new(args(homes, &["alice:100000", "bob:100001"]))
+            .with_state_dir(Some(state.clone()))
+            .execute()?;
+        assert_eq!(report.command, "remap-homes");
+        assert_eq!(report.counts["homes"], 2);
+        assert_eq!(report.counts["entries"], 7);
+        assert_eq!(report.counts["remapped"], 7);
+        assert!(report.errors.is_empty());
+        for path in ["alice", "alice/.config/settings", "alice/.config/link"] {
+            assert_eq!(owner(&homes.join(path))?, (100000, 100000), "{path}");
+        }
+        assert_eq!(owner(&homes.join("bob/.config"))?, (100001, 100001));
+        assert_eq!(owner(&homes.join("carol/.config"))?, (1002, 1002));
+        assert_eq!(
+            fs::symlink_metadata(homes.join("alice/.config/settings"))?.mode() & 0o7777,
+            0o4755
+        );
+
+        let mut keep_groups = args(homes, &["carol:100002"]);
+        keep_groups.keep_groups = true;
+        let report = RemapHomesCommand::new(keep_groups)
+            .with_state_dir(Some(state))
+            .execute()?;
+        assert_eq!(report.counts["remapped"], 3);
+        assert_eq!(
+            owner(&homes.join("carol/.config/settings"))?,
+            (100002, 1002)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_remap_homes_refused() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = TempDir::new()?;
+        let homes = temp_dir.path();
+        fs::create_dir(homes.join("alice"))?;
+        fs::create_dir(homes.join("bob"))?;
+
+        for (map_users, expected) in [
+            (
+                &["alice:100000", "bob:100000"][..],
+                "alice and bob are both mapped to 100000",
+            ),
+            (&["alice:100000", "alice:100001"], "mapped more than once"),
+            (&["../etc:100000"], "is not a user name"),
+            (&["dave:100003"], "home directory of dave"),
+        ] {
+            let error = RemapHomesCommand::new(args(homes, map_users))
+                .execute()
+                .unwrap_err();
+            assert!(error.to_string().contains(expected), "{error}");
+        }
+        Ok(())
+    }
+}
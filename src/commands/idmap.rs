@@ -0,0 +1,517 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use clap::{Args, Subcommand, ValueEnum};
+use tracing::{info, warn};
+
+use crate::error::{Result as RustUtilsResult, RustUtilsError};
+use crate::idmap::{id_span, IdKind, IdMap, IdMapping};
+use crate::idspace::{self, UsedRange};
+use crate::lxc::{self, LxcIdMap, LxcIdMapArgs};
+use crate::report::RunReport;
+use crate::subid::{self, LockedSubIdFile, SUBGID_FILE, SUBUID_FILE};
+
+#[derive(Args)]
+pub struct IdmapArgs {
+    #[command(subcommand)]
+    pub command: IdmapCommands,
+}
+
+#[derive(Subcommand)]
+pub enum IdmapCommands {
+    /// Propose host ID ranges no subid file, LXC container or running namespace uses yet
+    Free(FreeArgs),
+    /// Print lxc.idmap or raw.idmap configuration for a mapping, checked against the subid
+    /// files
+    Config(ConfigArgs),
+    /// Show a mapping as a table and a diagram of its container and host ranges
+    Show(ShowArgs),
+}
+
+#[derive(Args)]
+pub struct FreeArgs {
+    /// Number of IDs the new container needs
+    #[arg(long, value_name = "N", default_value_t = 65536, value_parser = clap::value_parser!(u32).range(1..))]
+    pub min_size: u32,
+
+    /// Number of ranges to propose
+    #[arg(long, value_name = "N", default_value_t = 1)]
+    pub count: usize,
+
+    /// Lowest host ID to hand out (SUB_UID_MIN in login.defs)
+    #[arg(long, value_name = "ID", default_value_t = 100000)]
+    pub first_id: u32,
+
+    /// Highest host ID to hand out (SUB_UID_MAX in login.defs)
+    #[arg(long, value_name = "ID", default_value_t = 600100000)]
+    pub last_id: u32,
+
+    /// Subordinate UID file
+    #[arg(long, value_name = "FILE", default_value = SUBUID_FILE)]
+    pub subuid: PathBuf,
+
+    /// Subordinate GID file
+    #[arg(long, value_name = "FILE", default_value = SUBGID_FILE)]
+    pub subgid: PathBuf,
+
+    /// Directory holding LXC containers, each with a `config` (can be used multiple times)
+    #[arg(long, value_name = "DIR", default_value = "/var/lib/lxc")]
+    pub lxc_path: Vec<PathBuf>,
+
+    /// Do not look at the user namespaces of running processes
+    #[arg(long)]
+    pub ignore_running: bool,
+
+    /// Delegate the proposed range to USER in the subuid and subgid files
+    #[arg(long, value_name = "USER", conflicts_with = "count")]
+    pub allocate: Option<String>,
+}
+
+/// A container's UID and GID mappings.
+#[derive(Args)]
+pub struct MapArgs {
+    /// Map container IDs FROM.. to host IDs TO.., for both UIDs and GIDs (can be used
+    /// multiple times)
+    #[arg(long, value_name = "FROM:TO:COUNT")]
+    pub map: Vec<IdMapping>,
+
+    /// Like --map, for UIDs only
+    #[arg(long, value_name = "FROM:TO:COUNT")]
+    pub uid_map: Vec<IdMapping>,
+
+    /// Like --map, for GIDs only
+    #[arg(long, value_name = "FROM:TO:COUNT")]
+    pub gid_map: Vec<IdMapping>,
+
+    #[command(flatten)]
+    pub lxc: LxcIdMapArgs,
+}
+
+impl MapArgs {
+    /// The UID mappings followed by the GID mappings: those of --map and --uid-map or
+    /// --gid-map, then those of --idmap and --idmap-file, each in the order given.
+    fn by_kind(&self) -> RustUtilsResult<[(IdKind, Vec<IdMapping>); 2]> {
+        let (lxc_uids, lxc_gids) = self.lxc.by_kind()?;
+        Ok([
+            (
+                IdKind::Uid,
+                [&self.map[..], &self.uid_map, &lxc_uids].concat(),
+            ),
+            (
+                IdKind::Gid,
+                [&self.map[..], &self.gid_map, &lxc_gids].concat(),
+            ),
+        ])
+    }
+}
+
+impl FreeArgs {
+    /// Check the arguments that constrain each other.
+    pub fn check_ranges(&self) -> RustUtilsResult<()> {
+        if self.min_size == 0 || self.first_id > self.last_id {
+            return Err(RustUtilsError::InvalidRange(
+                "--min-size must be positive and --first-id at most --last-id".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Args)]
+pub struct ConfigArgs {
+    #[command(flatten)]
+    pub maps: MapArgs,
+
+    /// Configuration syntax to print
+    #[arg(long, value_enum, default_value_t = ConfigFormat::Lxc)]
+    pub format: ConfigFormat,
+
+    /// User the host IDs must be delegated to: root for LXD, Incus and privileged LXC, the
+    /// container's owner for unprivileged LXC
+    #[arg(long, default_value = "root")]
+    pub user: String,
+
+    /// Subordinate UID file
+    #[arg(long, value_name = "FILE", default_value = SUBUID_FILE)]
+    pub subuid: PathBuf,
+
+    /// Subordinate GID file
+    #[arg(long, value_name = "FILE", default_value = SUBGID_FILE)]
+    pub subgid: PathBuf,
+}
+
+#[derive(Args)]
+pub struct ShowArgs {
+    #[command(flatten)]
+    pub maps: MapArgs,
+}
+
+/// Container manager configuration syntaxes for ID mappings.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ConfigFormat {
+    /// `lxc.idmap` lines for an LXC container config
+    Lxc,
+    /// The value of the LXD/Incus `raw.idmap` option
+    RawIdmap,
+}
+
+pub struct IdmapCommand {
+    args: IdmapArgs,
+}
+
+impl IdmapCommand {
+    pub fn new(args: IdmapArgs) -> Self {
+        Self { args }
+    }
+
+    pub fn execute(self) -> Result<RunReport> {
+        match self.args.command {
+            IdmapCommands::Free(args) => free(&args),
+            IdmapCommands::Config(args) => config(&args),
+            IdmapCommands::Show(args) => show(&args),
+        }
+    }
+}
+
+fn free(args: &FreeArgs) -> Result<RunReport> {
+    args.check_ranges()?;
+    if let Some(user) = &args.allocate {
+        subid::check_owner(user)?;
+    }
+
+    // Held until the allocation is written, so concurrent allocations cannot pick the same
+    // range
+    let locked = match &args.allocate {
+        Some(_) => Some((
+            LockedSubIdFile::lock(&args.subuid)?,
+            LockedSubIdFile::lock(&args.subgid)?,
+        )),
+        None => None,
+    };
+
+    let used = used_ranges(args)?;
+    for range in &used {
+        println!("used: {range}");
+    }
+
+    // UIDs and GIDs are usually delegated together, so a range must be free in both
+    let free: Vec<_> = idspace::free_ranges(&used, args.first_id, args.last_id)
+        .into_iter()
+        .filter(|range| range.count >= args.min_size)
+        .collect();
+    for range in &free {
+        println!(
+            "free: {}-{} ({} IDs)",
+            range.start,
+            u64::from(range.start) + u64::from(range.count) - 1,
+            range.count
+        );
+    }
+
+    let proposed = idspace::propose(&free, args.min_size, args.first_id, args.count);
+    if proposed.is_empty() {
+        return Err(RustUtilsError::OperationFailed(format!(
+            "no free range of {} IDs between {} and {}",
+            args.min_size, args.first_id, args.last_id
+        ))
+        .into());
+    }
+    if proposed.len() < args.count {
+        warn!(
+            "Only {} of {} ranges of {} IDs are free",
+            proposed.len(),
+            args.count,
+            args.min_size
+        );
+    }
+    for range in &proposed {
+        println!("proposed: {}:{}", range.start, range.count);
+    }
+
+    let mut report = RunReport::new("idmap-free");
+    report
+        .count("used_ranges", used.len() as u64)
+        .count("free_ranges", free.len() as u64)
+        .count("proposed", proposed.len() as u64);
+
+    if let (Some(user), Some((subuid, subgid))) = (&args.allocate, &locked) {
+        let range = proposed[0];
+        for file in [subuid, subgid] {
+            if file.entries()?.iter().any(|entry| &entry.owner == user) {
+                warn!("{} already has subordinate IDs; adding another range", user);
+                break;
+            }
+        }
+        // Both files are checked before either is written, so that they stay in step
+        subuid.check_append(user, range)?;
+        subgid.check_append(user, range)?;
+        subuid.append(user, range)?;
+        if let Err(e) = subgid.append(user, range) {
+            subuid.restore_backup()?;
+            return Err(e.into());
+        }
+        info!(
+            "Delegated {}:{} to {} in {} and {}",
+            range.start,
+            range.count,
+            user,
+            args.subuid.display(),
+            args.subgid.display()
+        );
+        println!("allocated: {}:{}:{}", user, range.start, range.count);
+        report.count("allocated", 1);
+    }
+    Ok(report)
+}
+
+fn config(args: &ConfigArgs) -> Result<RunReport> {
+    let [(_, uid_maps), (_, gid_maps)] = args.maps.by_kind()?;
+    for (kind, maps, file) in [
+        (IdKind::Uid, &uid_maps, &args.subuid),
+        (IdKind::Gid, &gid_maps, &args.subgid),
+    ] {
+        if maps.is_empty() {
+            return Err(RustUtilsError::InvalidArguments(format!(
+                "no {kind} mapping given (use --map, --{kind}-map or --idmap)"
+            ))
+            .into());
+        }
+        // Rejects container ranges mapped twice
+        IdMap::new(maps.clone())?;
+        check_delegated(kind, maps, file, &args.user)?;
+    }
+
+    let lines = match args.format {
+        ConfigFormat::Lxc => {
+            let uids = uid_maps.iter().map(|&mapping| LxcIdMap {
+                kind: IdKind::Uid,
+                mapping,
+            });
+            let gids = gid_maps.iter().map(|&mapping| LxcIdMap {
+                kind: IdKind::Gid,
+                mapping,
+            });
+            uids.chain(gids)
+                .map(|idmap| format!("lxc.idmap = {idmap}"))
+                .collect()
+        }
+        ConfigFormat::RawIdmap => lxc::raw_idmap(&uid_maps, &gid_maps),
+    };
+    for line in &lines {
+        println!("{line}");
+    }
+
+    let mut report = RunReport::new("idmap-config");
+    report.count("lines", lines.len() as u64);
+    Ok(report)
+}
+
+fn show(args: &ShowArgs) -> Result<RunReport> {
+    let kinds: Vec<_> = args
+        .maps
+        .by_kind()?
+        .into_iter()
+        .filter(|(_, maps)| !maps.is_empty())
+        .collect();
+    if kinds.is_empty() {
+        return Err(RustUtilsError::InvalidArguments(
+            "no mapping given (use --map, --uid-map, --gid-map or --idmap)".to_string(),
+        )
+        .into());
+    }
+    for (_, maps) in &kinds {
+        // Rejects container ranges mapped twice
+        IdMap::new(maps.clone())?;
+    }
+
+    for line in table(&kinds) {
+        println!("{line}");
+    }
+    for (kind, maps) in &kinds {
+        println!();
+        for line in diagram(*kind, maps) {
+            println!("{line}");
+        }
+    }
+
+    let mut report = RunReport::new("idmap-show");
+    report.count(
+        "mappings",
+        kinds.iter().map(|(_, maps)| maps.len() as u64).sum(),
+    );
+    Ok(report)
+}
+
+/// Width of the bars of [`diagram`], in characters.
+const DIAGRAM_WIDTH: usize = 60;
+
+/// The letter marking the `index`th mapping of a kind in the table and diagram.
+fn label(index: usize) -> char {
+    match index {
+        0..=25 => char::from(b'A' + index as u8),
+        26..=51 => char::from(b'a' + (index - 26) as u8),
+        _ => '#',
+    }
+}
+
+/// One aligned row per mapping with its container and host ranges as inclusive
+/// `FIRST-LAST` spans.
+fn table(kinds: &[(IdKind, Vec<IdMapping>)]) -> Vec<String> {
+    let mut rows = vec![["kind", "map", "container", "host", "count"].map(String::from)];
+    for (kind, maps) in kinds {
+        for (index, mapping) in maps.iter().enumerate() {
+            rows.push([
+                kind.to_string(),
+                label(index).to_string(),
+                id_span(mapping.from, mapping.count),
+                id_span(mapping.to, mapping.count),
+                mapping.count.to_string(),
+            ]);
+        }
+    }
+
+    let mut widths = [0; 5];
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    rows.iter()
+        .map(|[kind, map, container, host, count]| {
+            format!(
+                "{:<w0$}  {:<w1$}  {:<w2$}  {:<w3$}  {:>w4$}",
+                kind,
+                map,
+                container,
+                host,
+                count,
+                w0 = widths[0],
+                w1 = widths[1],
+                w2 = widths[2],
+                w3 = widths[3],
+                w4 = widths[4]
+            )
+        })
+        .collect()
+}
+
+/// Bars of the container and the host side of a kind's mappings, each scaled to the IDs
+/// between its lowest and highest mapped ID. Every mapping takes at least one character, so
+/// single IDs stay visible next to large ranges; unmapped IDs in between show as dots, at
+/// least one however few they are.
+fn diagram(kind: IdKind, maps: &[IdMapping]) -> Vec<String> {
+    let bar = |side: fn(&IdMapping) -> u32| {
+        let low = maps.iter().map(|m| u64::from(side(m))).min().unwrap_or(0);
+        let high = maps
+            .iter()
+            .map(|m| u64::from(side(m)) + u64::from(m.count))
+            .max()
+            .unwrap_or(0);
+        let scale = |id: u64| ((id - low) * DIAGRAM_WIDTH as u64 / (high - low)) as usize;
+
+        let mut order: Vec<usize> = (0..maps.len()).collect();
+        order.sort_by_key(|&index| side(&maps[index]));
+        let mut cells = vec!['.'; DIAGRAM_WIDTH];
+        let (mut next, mut end) = (0, low);
+        for index in order {
+            let start = u64::from(side(&maps[index]));
+            // Keep a dot for even a single unmapped ID between two ranges
+            let gap = usize::from(start > end);
+            let first = scale(start).max(next + gap).min(DIAGRAM_WIDTH - 1);
+            end = start + u64::from(maps[index].count);
+            next = scale(end).max(first + 1).min(DIAGRAM_WIDTH);
+            cells[first..next].fill(label(index));
+        }
+        let span = id_span(low as u32, (high - low) as u32);
+        format!("|{}|  {}", cells.into_iter().collect::<String>(), span)
+    };
+    vec![
+        format!("{kind}  container  {}", bar(|m| m.from)),
+        format!("     host       {}", bar(|m| m.to)),
+    ]
+}
+
+/// Fail unless every host ID of `maps` is delegated to `user` in `file`.
+fn check_delegated(kind: IdKind, maps: &[IdMapping], file: &Path, user: &str) -> Result<()> {
+    let delegated = subid::ranges_for(file, user)?;
+    for mapping in maps {
+        if let Some((first, last)) = subid::uncovered(&delegated, mapping.to, mapping.count) {
+            return Err(RustUtilsError::InvalidRange(format!(
+                "host {}s {}-{} of mapping {} are not delegated to {} in {}",
+                kind,
+                first,
+                last,
+                mapping,
+                user,
+                file.display()
+            ))
+            .into());
+        }
+    }
+    Ok(())
+}
+
+fn used_ranges(args: &FreeArgs) -> Result<Vec<UsedRange>> {
+    let mut used = idspace::from_subid_file(&args.subuid, IdKind::Uid)?;
+    used.extend(idspace::from_subid_file(&args.subgid, IdKind::Gid)?);
+    for dir in &args.lxc_path {
+        used.extend(idspace::from_lxc_configs(dir)?);
+    }
+    if !args.ignore_running {
+        used.extend(idspace::from_namespaces()?);
+    }
+    info!("Host ID ranges in use: {}", used.len());
+    Ok(used)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mapping(from: u32, to: u32, count: u32) -> IdMapping {
+        IdMapping { from, to, count }
+    }
+
+    #[test]
+    fn test_table() {
+        let kinds = [
+            (
+                IdKind::Uid,
+                vec![mapping(0, 100000, 1000), mapping(1000, 1000, 1)],
+            ),
+            (IdKind::Gid, vec![mapping(0, 100000, 65536)]),
+        ];
+        assert_eq!(
+            table(&kinds),
+            [
+                "kind  map  container  host           count",
+                "uid   A    0-999      100000-100999   1000",
+                "uid   B    1000       1000               1",
+                "gid   A    0-65535    100000-165535  65536",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_diagram() {
+        let maps = [
+            mapping(0, 100000, 1000),
+            mapping(1000, 1000, 1),
+            mapping(1001, 101001, 64535),
+        ];
+        let lines = diagram(IdKind::Uid, &maps);
+        let container = &lines[0];
+        let host = &lines[1];
+        assert!(container.starts_with("uid  container  |A"));
+        assert!(container.ends_with("C|  0-65535"));
+        // The single ID mapped to itself still shows, far below the rest on the host
+        assert!(host.starts_with("     host       |B."));
+        assert!(host.ends_with("C|  1000-165535"));
+        assert_eq!(container.matches('B').count(), 1);
+
+        // A single unmapped ID between two ranges still shows
+        let lines = diagram(IdKind::Gid, &[maps[0], maps[2]]);
+        assert!(lines[0].starts_with("gid  container  |A.B"));
+        assert_eq!(label(30), 'e');
+    }
+}
@@ -0,0 +1,360 @@
+//! Upkeep of the journals runs leave behind: undo journals, snapshot manifests and partition
+//! journals. They list every path a remap changed, so the ones kept are compacted, can be
+//! encrypted with `age`, and old ones can be removed.
+
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader};
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::SystemTime;
+
+use anyhow::Result;
+use clap::{Args, Subcommand};
+use nix::errno::Errno;
+use nix::fcntl::{flock, FlockArg};
+use tracing::{info, warn};
+
+use crate::error::RustUtilsError;
+use crate::partition::{self, JOURNAL_MAGIC};
+use crate::report::RunReport;
+use crate::undo::{self, UNDO_MAGIC};
+
+/// First line of files encrypted by `age`.
+const AGE_HEADER: &str = "age-encryption.org/v1";
+
+#[derive(Args)]
+pub struct JournalArgs {
+    #[command(subcommand)]
+    pub command: JournalCommands,
+}
+
+#[derive(Subcommand)]
+pub enum JournalCommands {
+    /// Merge repeated entries of undo journals, snapshot manifests and partition journals
+    Compact(CompactArgs),
+    /// Compact journals, then encrypt them with `age` and remove the plain text
+    Encrypt(EncryptArgs),
+}
+
+#[derive(Args, Clone, Debug, Default)]
+pub struct CompactArgs {
+    /// Journals, or directories to take the journals in, such as the state directory's
+    /// partitions/
+    #[arg(required = true)]
+    pub paths: Vec<PathBuf>,
+
+    /// Remove all but the N most recently modified journals among those named, encrypted
+    /// ones included
+    #[arg(long, value_name = "N")]
+    pub keep_last: Option<usize>,
+
+    /// Dry run mode (set from the global option)
+    #[arg(skip)]
+    pub dry_run: bool,
+}
+
+#[derive(Args, Clone, Debug, Default)]
+pub struct EncryptArgs {
+    #[command(flatten)]
+    pub compact: CompactArgs,
+
+    /// age recipient to encrypt to, such as an `age1...` or SSH public key (repeatable)
+    #[arg(long, short = 'r', required_unless_present = "recipients_file")]
+    pub recipient: Vec<String>,
+
+    /// File of age recipients, one per line (repeatable)
+    #[arg(long, short = 'R', value_name = "FILE")]
+    pub recipients_file: Vec<PathBuf>,
+}
+
+/// Format of a journal file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Kind {
+    /// Undo journal or snapshot manifest
+    Undo,
+    Partition,
+    /// Encrypted by an earlier `journal encrypt`
+    Encrypted,
+}
+
+struct Found {
+    path: PathBuf,
+    kind: Kind,
+    modified: SystemTime,
+}
+
+pub struct JournalCommand {
+    args: JournalArgs,
+}
+
+impl JournalCommand {
+    pub fn new(args: JournalArgs) -> Self {
+        Self { args }
+    }
+
+    pub fn execute(self) -> Result<RunReport> {
+        let (name, compact, encrypt) = match &self.args.command {
+            JournalCommands::Compact(args) => ("journal-compact", args, None),
+            JournalCommands::Encrypt(args) => ("journal-encrypt", &args.compact, Some(args)),
+        };
+        let mut report = RunReport::new(name);
+        let mut journals = find_journals(&compact.paths)?;
+        report.count("journals", journals.len() as u64);
+
+        if let Some(keep) = compact.keep_last {
+            journals.sort_by_key(|journal| std::cmp::Reverse(journal.modified));
+            let mut removed = 0;
+            for journal in journals.split_off(keep.min(journals.len())) {
+                let Some(_lock) = lock(&journal)? else {
+                    continue;
+                };
+                if compact.dry_run {
+                    info!("Would remove {} (dry run)", journal.path.display());
+                } else {
+                    fs::remove_file(&journal.path)?;
+                    info!("Removed {}", journal.path.display());
+                }
+                removed += 1;
+            }
+            report.count("removed", removed);
+        }
+
+        let mut dropped = 0;
+        let mut encrypted = 0;
+        for journal in journals
+            .iter()
+            .filter(|journal| journal.kind != Kind::Encrypted)
+        {
+            let Some(_lock) = lock(journal)? else {
+                continue;
+            };
+            if compact.dry_run {
+                info!("Would compact {} (dry run)", journal.path.display());
+                continue;
+            }
+            let lines = compact_journal(journal)?;
+            if lines > 0 {
+                info!(
+                    "Dropped {} repeated line(s) of {}",
+                    lines,
+                    journal.path.display()
+                );
+            }
+            dropped += lines as u64;
+            if let Some(args) = encrypt {
+                let output = encrypt_journal(&journal.path, args)?;
+                info!(
+                    "Encrypted {} to {}",
+                    journal.path.display(),
+                    output.display()
+                );
+                encrypted += 1;
+            }
+        }
+        report.count("lines_dropped", dropped);
+        if encrypt.is_some() {
+            report.count("encrypted", encrypted);
+        }
+        Ok(report)
+    }
+}
+
+/// The journals among `paths`, and in those that are directories. Named files must be
+/// journals; other files in directories are passed over.
+fn find_journals(paths: &[PathBuf]) -> Result<Vec<Found>> {
+    let mut journals = Vec::new();
+    for path in paths {
+        if path.is_dir() {
+            let mut entries = fs::read_dir(path)?
+                .map(|entry| entry.map(|entry| entry.path()))
+                .collect::<io::Result<Vec<_>>>()?;
+            entries.sort();
+            for entry in entries.into_iter().filter(|entry| entry.is_file()) {
+                if let Some(kind) = kind_of(&entry)? {
+                    journals.push(found(entry, kind)?);
+                }
+            }
+        } else {
+            let kind = kind_of(path)?.ok_or_else(|| {
+                RustUtilsError::InvalidArguments(format!(
+                    "{}: not a journal, snapshot manifest or encrypted journal",
+                    path.display()
+                ))
+            })?;
+            journals.push(found(path.clone(), kind)?);
+        }
+    }
+    Ok(journals)
+}
+
+fn found(path: PathBuf, kind: Kind) -> Result<Found> {
+    let modified = fs::metadata(&path)?.modified()?;
+    Ok(Found {
+        path,
+        kind,
+        modified,
+    })
+}
+
+fn kind_of(path: &Path) -> Result<Option<Kind>> {
+    let mut first = Vec::new();
+    BufReader::new(File::open(path)?).read_until(b'\n', &mut first)?;
+    let first = String::from_utf8_lossy(&first);
+    Ok(match first.split_whitespace().next() {
+        Some(UNDO_MAGIC) => Some(Kind::Undo),
+        Some(JOURNAL_MAGIC) => Some(Kind::Partition),
+        Some(AGE_HEADER) => Some(Kind::Encrypted),
+        _ => None,
+    })
+}
+
+/// Take the lock runs hold on their journals, or `None` with a warning if a run holds it.
+fn lock(journal: &Found) -> Result<Option<File>> {
+    let file = File::open(&journal.path)?;
+    match flock(file.as_raw_fd(), FlockArg::LockExclusiveNonblock) {
+        Ok(()) => Ok(Some(file)),
+        Err(Errno::EWOULDBLOCK) => {
+            warn!(
+                "{} is in use by a running job; left as it is",
+                journal.path.display()
+            );
+            Ok(None)
+        }
+        Err(e) => Err(RustUtilsError::from(e).into()),
+    }
+}
+
+/// Compact `journal` in place, returning the number of lines dropped.
+fn compact_journal(journal: &Found) -> Result<usize> {
+    Ok(match journal.kind {
+        Kind::Undo => {
+            let entries = undo::read_journal(&journal.path)?;
+            let compacted = undo::compact(&entries);
+            if compacted.len() < entries.len() {
+                undo::write_journal(&journal.path, &compacted)?;
+            }
+            entries.len() - compacted.len()
+        }
+        Kind::Partition => partition::compact_journal(&journal.path)?,
+        Kind::Encrypted => 0,
+    })
+}
+
+/// Encrypt the journal at `path` to `PATH.age` with the `age` command, then remove it.
+fn encrypt_journal(path: &Path, args: &EncryptArgs) -> Result<PathBuf> {
+    let mut output = path.as_os_str().to_owned();
+    output.push(".age");
+    let output = PathBuf::from(output);
+    if output.exists() {
+        return Err(RustUtilsError::InvalidArguments(format!(
+            "{} already exists; remove it or move it away first",
+            output.display()
+        ))
+        .into());
+    }
+    let mut partial = output.as_os_str().to_owned();
+    partial.push(format!(".{}", std::process::id()));
+    let partial = PathBuf::from(partial);
+
+    let mut command = Command::new("age");
+    command.arg("--encrypt");
+    for recipient in &args.recipient {
+        command.arg("--recipient").arg(recipient);
+    }
+    for file in &args.recipients_file {
+        command.arg("--recipients-file").arg(file);
+    }
+    let status = command
+        .arg("--output")
+        .arg(&partial)
+        .arg(path)
+        .status()
+        .map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => RustUtilsError::OperationFailed(
+                "age is not installed; install it to encrypt journals".to_string(),
+            ),
+            _ => e.into(),
+        })?;
+    if !status.success() {
+        let _ = fs::remove_file(&partial);
+        return Err(RustUtilsError::OperationFailed(format!(
+            "age failed to encrypt {}: {}",
+            path.display(),
+            status
+        ))
+        .into());
+    }
+    File::open(&partial)?.sync_all()?;
+    fs::rename(&partial, &output)?;
+    fs::remove_file(path)?;
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::undo::{Owner, UndoJournal};
+    use std::time::Duration;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_compact_and_keep_last() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let dir = TempDir::new()?;
+        let owner = |id| Owner { uid: id, gid: id };
+        let mut modified = SystemTime::now() - Duration::from_secs(60);
+        for name in ["old", "new"] {
+            let path = dir.path().join(format!("{name}.journal"));
+            let journal = UndoJournal::create(&path)?;
+            journal.record(Path::new("/srv/a"), owner(0), owner(1000))?;
+            journal.record(Path::new("/srv/a"), owner(1000), owner(2000))?;
+            journal.finish()?;
+            File::options()
+                .write(true)
+                .open(&path)?
+                .set_modified(modified)?;
+            modified += Duration::from_secs(30);
+        }
+        fs::write(dir.path().join("notes.txt"), "not a journal")?;
+        // A journal of a run still going is left alone
+        let running = UndoJournal::create(&dir.path().join("running.journal"))?;
+        running.record(Path::new("/srv/a"), owner(0), owner(1000))?;
+        running.record(Path::new("/srv/a"), owner(1000), owner(2000))?;
+
+        let args = CompactArgs {
+            paths: vec![dir.path().to_path_buf()],
+            keep_last: Some(2),
+            dry_run: false,
+        };
+        let report = JournalCommand::new(JournalArgs {
+            command: JournalCommands::Compact(args),
+        })
+        .execute()?;
+        assert_eq!(report.counts["journals"], 3);
+        // The running journal is the newest, so the oldest goes
+        assert_eq!(report.counts["removed"], 1);
+        assert!(!dir.path().join("old.journal").exists());
+        assert_eq!(report.counts["lines_dropped"], 1);
+        assert_eq!(
+            undo::read_journal(&dir.path().join("new.journal"))?.len(),
+            1
+        );
+        assert_eq!(
+            undo::read_journal(&dir.path().join("running.journal"))?.len(),
+            2
+        );
+        assert!(dir.path().join("notes.txt").exists());
+
+        let args = CompactArgs {
+            paths: vec![dir.path().join("notes.txt")],
+            ..Default::default()
+        };
+        let error = JournalCommand::new(JournalArgs {
+            command: JournalCommands::Compact(args),
+        })
+        .execute()
+        .unwrap_err();
+        assert!(error.to_string().contains("not a journal"));
+        Ok(())
+    }
+}
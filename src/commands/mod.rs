@@ -1 +1,14 @@
+pub mod archive;
+pub mod chown_helper;
+pub mod copy;
+pub mod fingerprint;
+pub mod homes;
+pub mod idmap;
+pub mod journal;
+pub mod normalize;
 pub mod remap;
+pub mod report;
+pub mod schema;
+pub mod send_stream;
+pub mod template;
+pub mod with_caps;
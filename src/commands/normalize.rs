@@ -0,0 +1,249 @@
+use std::collections::BTreeMap;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use clap::Args;
+use tracing::{info, warn};
+use walkdir::WalkDir;
+
+use crate::commands::remap::{RemapArgs, RemapCommand};
+use crate::error::{Result as RustUtilsResult, RustUtilsError};
+use crate::idmap::{id_span, IdKind};
+use crate::report::RunReport;
+
+#[derive(Args, Debug)]
+pub struct NormalizeArgs {
+    /// Root of the tree to shift down to a 0-based range
+    pub base_directory: PathBuf,
+
+    /// Host ID that becomes 0, for UIDs and GIDs alike, instead of the detected bases
+    #[arg(long)]
+    pub from_base: Option<u32>,
+
+    /// Size of the container ID range
+    #[arg(long, default_value = "65536", value_parser = clap::value_parser!(u32).range(1..))]
+    pub range_size: u32,
+
+    /// Show what would be changed without making modifications (the global --dry-run)
+    #[arg(skip)]
+    pub dry_run: bool,
+
+    /// Log every entry whose ownership changes (the global --verbose)
+    #[arg(skip)]
+    pub verbose: bool,
+}
+
+/// How many entries of a tree have each UID and each GID.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct IdCounts {
+    pub uid: BTreeMap<u32, u64>,
+    pub gid: BTreeMap<u32, u64>,
+    pub entries: u64,
+}
+
+impl IdCounts {
+    /// Count the IDs of every entry of the tree at `root`, without following symlinks.
+    pub fn of_tree(root: &Path) -> RustUtilsResult<Self> {
+        let mut counts = Self::default();
+        for entry in WalkDir::new(root) {
+            let entry = entry.map_err(|e| RustUtilsError::Io(e.into()))?;
+            let metadata = entry.metadata().map_err(|e| RustUtilsError::Io(e.into()))?;
+            *counts.uid.entry(metadata.uid()).or_default() += 1;
+            *counts.gid.entry(metadata.gid()).or_default() += 1;
+            counts.entries += 1;
+        }
+        Ok(counts)
+    }
+
+    fn of_kind(&self, kind: IdKind) -> &BTreeMap<u32, u64> {
+        match kind {
+            IdKind::Uid => &self.uid,
+            IdKind::Gid => &self.gid,
+        }
+    }
+}
+
+/// The base of the `range_size` IDs holding the most entries of `counts`, and how many
+/// entries that is. Bases are IDs met in the tree; among equally good ones `preferred`
+/// wins, the owner of the tree's root, and then the lowest.
+pub fn dominant_base(counts: &BTreeMap<u32, u64>, range_size: u32, preferred: u32) -> (u32, u64) {
+    let ids: Vec<(u32, u64)> = counts.iter().map(|(&id, &count)| (id, count)).collect();
+    let mut below = vec![0];
+    for (_, count) in &ids {
+        below.push(below.last().copied().unwrap_or(0) + count);
+    }
+    let mut best = (0, 0);
+    for (i, &(id, _)) in ids.iter().enumerate() {
+        let end = u64::from(id) + u64::from(range_size);
+        let past = ids.partition_point(|&(other, _)| u64::from(other) < end);
+        let covered = below[past] - below[i];
+        if covered > best.1 || (covered == best.1 && id == preferred) {
+            best = (id, covered);
+        }
+    }
+    best
+}
+
+/// Shifts a tree's ownership down to a canonical 0-based range.
+pub struct NormalizeCommand {
+    args: NormalizeArgs,
+}
+
+impl NormalizeCommand {
+    pub fn new(args: NormalizeArgs) -> Self {
+        Self { args }
+    }
+
+    pub fn execute(self) -> Result<RunReport> {
+        let base = &self.args.base_directory;
+        let root = std::fs::symlink_metadata(base)
+            .map_err(|e| RustUtilsError::DirectoryNotFound(format!("{}: {e}", base.display())))?;
+        let counts = IdCounts::of_tree(base)?;
+
+        let mut bases = Vec::new();
+        let mut outside = Vec::new();
+        for (kind, owner) in [(IdKind::Uid, root.uid()), (IdKind::Gid, root.gid())] {
+            let ids = counts.of_kind(kind);
+            let (first, covered) = match self.args.from_base {
+                Some(first) => {
+                    let end = u64::from(first) + u64::from(self.args.range_size);
+                    let covered = ids
+                        .iter()
+                        .filter(|(&id, _)| id >= first && u64::from(id) < end)
+                        .map(|(_, count)| count)
+                        .sum();
+                    (first, covered)
+                }
+                None => dominant_base(ids, self.args.range_size, owner),
+            };
+            info!(
+                "{} base {}: {} of {} entries in {}",
+                kind,
+                first,
+                covered,
+                counts.entries,
+                id_span(first, self.args.range_size)
+            );
+            if covered < counts.entries {
+                warn!(
+                    "{} entry(ies) have {}s outside {}, which they keep",
+                    counts.entries - covered,
+                    kind,
+                    id_span(first, self.args.range_size)
+                );
+            }
+            outside.push(counts.entries - covered);
+            bases.push(first);
+        }
+        let (uid_base, gid_base) = (bases[0], bases[1]);
+
+        // One remap where UIDs and GIDs share a base, else one for each
+        let runs: Vec<(u32, bool, bool)> = if uid_base == gid_base {
+            vec![(uid_base, false, false)]
+        } else {
+            vec![(uid_base, true, false), (gid_base, false, true)]
+        };
+        let mut reports = Vec::new();
+        for (from_base, uid_only, gid_only) in runs {
+            if from_base == 0 {
+                continue;
+            }
+            let args = RemapArgs {
+                base_directory: base.clone(),
+                from_base,
+                to_base: 0,
+                range_size: self.args.range_size,
+                uid_only,
+                gid_only,
+                dry_run: self.args.dry_run,
+                verbose: self.args.verbose,
+                ..Default::default()
+            };
+            reports.push(RemapCommand::new(args).execute()?);
+        }
+
+        let mut report = if reports.is_empty() {
+            info!("{} is already in canonical form", base.display());
+            let mut report = RunReport::new("normalize");
+            report.count("remapped", 0);
+            report
+        } else {
+            RunReport::merge(&reports)?
+        };
+        report.command = "normalize".to_string();
+        report
+            .count("entries", counts.entries)
+            .count("uid_base", u64::from(uid_base))
+            .count("gid_base", u64::from(gid_base))
+            .count("uids_outside", outside[0])
+            .count("gids_outside", outside[1]);
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::lchown;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_dominant_base() {
+        let counts = BTreeMap::from([(0, 2), (100000, 50), (100033, 10), (165534, 1)]);
+        assert_eq!(dominant_base(&counts, 65536, 100000), (100000, 61));
+        // A base that leaves out what lies below it holds fewer
+        assert_eq!(dominant_base(&counts, 65536, 100033), (100000, 61));
+        let counts = BTreeMap::from([(100033, 1), (100034, 1)]);
+        assert_eq!(dominant_base(&counts, 65536, 0), (100033, 2));
+        assert_eq!(dominant_base(&BTreeMap::new(), 65536, 0), (0, 0));
+    }
+
+    #[test]
+    fn test_execute_normalize() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = TempDir::new()?;
+        let root = temp_dir.path();
+        fs::create_dir(root.join("etc"))?;
+        fs::write(root.join("www"), "")?;
+        fs::write(root.join("leaked"), "")?;
+        for (path, id) in [
+            ("", 100000),
+            ("etc", 100000),
+            ("www", 100033),
+            ("leaked", 0),
+        ] {
+            lchown(root.join(path), Some(id), Some(id))?;
+        }
+
+        let args = |dry_run| NormalizeArgs {
+            base_directory: root.to_path_buf(),
+            from_base: None,
+            range_size: 65536,
+            dry_run,
+            verbose: false,
+        };
+        let report = NormalizeCommand::new(args(true)).execute()?;
+        assert_eq!(report.command, "normalize");
+        assert_eq!(report.counts["uid_base"], 100000);
+        assert_eq!(report.counts["uids_outside"], 1);
+        assert_eq!(report.counts["gids_outside"], 1);
+        assert_eq!(fs::symlink_metadata(root.join("www"))?.uid(), 100033);
+
+        let report = NormalizeCommand::new(args(false)).execute()?;
+        assert_eq!(report.counts["remapped"], 3);
+        let owner = |path: &str| -> std::io::Result<(u32, u32)> {
+            let metadata = fs::symlink_metadata(root.join(path))?;
+            Ok((metadata.uid(), metadata.gid()))
+        };
+        assert_eq!(owner("")?, (0, 0));
+        assert_eq!(owner("www")?, (33, 33));
+        assert_eq!(owner("leaked")?, (0, 0));
+
+        // Nothing left to do the second time
+        let report = NormalizeCommand::new(args(false)).execute()?;
+        assert_eq!(report.counts["remapped"], 0);
+        assert_eq!(report.counts["uid_base"], 0);
+        Ok(())
+    }
+}
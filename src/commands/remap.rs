@@ -1,17 +1,250 @@
-use std::collections::HashMap;
-use std::fs::Metadata;
-use std::os::unix::fs::{lchown, MetadataExt};
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::env;
+use std::fmt;
+use std::fs::{self, Metadata, Permissions};
+use std::io;
+use std::num::NonZeroUsize;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{lchown, MetadataExt, PermissionsExt};
+use std::os::unix::process::CommandExt;
 use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use anyhow::Result;
-use clap::Args;
+use clap::{ArgGroup, ArgMatches, Args, FromArgMatches, Subcommand, ValueEnum};
+use nix::errno::Errno;
+use nix::libc;
+use nix::sys::stat::{major, minor};
+use regex::RegexSet;
+use tracing::subscriber::NoSubscriber;
 use tracing::{debug, info, warn};
-use walkdir::WalkDir;
+use walkdir::{DirEntry, DirEntryExt};
 
+use crate::anchor::{self, Anchor};
+use crate::atomic::AtomicDir;
+use crate::commands::archive::RemapRules;
+use crate::diagnose::Failures;
 use crate::error::{Result as RustUtilsResult, RustUtilsError};
-use crate::fs::{get_file_metadata, should_exclude};
+use crate::fakeroot::{self, FakerootDb, Session};
+use crate::freezer::{self, FrozenCgroup};
+use crate::fs::{
+    get_file_metadata, resolve_in_root, resolve_subdirectory, should_include, EntryType,
+};
+use crate::glob;
+use crate::helper::{self, Request};
+use crate::idmap::{self, id_span, IdKind, IdMap, IdMapping};
+use crate::iostats::{self, Syscall};
+use crate::ipc::{self, IpcMounts};
+use crate::live;
+use crate::lock::RunLock;
+use crate::logfile::ENTRY_TARGET;
+use crate::lxc::{LxcIdMap, LxcIdMapArgs};
+use crate::mapfile;
+use crate::marker::{OpenDir, OpenDirs, ResumeMarker, RESUME_XATTR};
+use crate::mounts::{self, FilesystemStats, FilesystemSummary};
+use crate::nested::{self, NestedPolicy};
+use crate::overlay::PreviewOverlay;
+use crate::partition::{self, Claim, Coordinator, Journal, Partition};
+use crate::passwd::{self, NamedMapping};
+use crate::pipeline::{Pipeline, TreeVisitor, VisitEvent, VisitorRegistry};
+use crate::plan::{self, PlanHash, PlannedOwners};
+use crate::plugin::{PluginDecision, WasmPlugin};
+use crate::probe::{self, Probe};
+use crate::profile::{self, Profile};
+use crate::progress::{Counters, Heartbeat, Progress, ProgressArgs, ProgressInterval};
+use crate::project::{self, ProjectIdMode};
+use crate::report::{
+    EntryTypeStats, EntryTypeSummary, IdView, OutputFormat, OwnerView, RunReport, View,
+};
+use crate::safety::{inspect, Finding};
+use crate::shadow::{self, BeneathMounts, ReadOnlyView};
+use crate::signals;
+use crate::state::StateDir;
+use crate::subid::{self, SUBGID_FILE, SUBUID_FILE};
+use crate::throttle::{self, Limiter, Throttle};
+use crate::undo::{self, Owner, UndoEntry, UndoJournal};
+use crate::walk::{Aliases, TreeWalk, WalkFilter};
+use crate::xattrs::Snapshot;
+use crate::{log_message, tr};
 
-#[derive(Args)]
+/// How additional paths to an already-seen inode are handled.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum HardLinkPolicy {
+    /// Remap the first path found and skip every other link to the same inode
+    #[default]
+    First,
+    /// Explicitly chown every link path using the ownership recorded at the first path
+    All,
+    /// Abort when a link to the same inode turns up in a different directory
+    Fail,
+}
+
+/// Arguments of `remap`: a remap of a tree, or one of the subcommands working with what
+/// earlier remaps left behind.
+///
+/// Implemented by hand because the derive cannot tell whether an optional flattened
+/// [`RemapArgs`] is present, as it flattens [`ProgressArgs`] in turn.
+pub enum RemapCliArgs {
+    Run(Box<RemapArgs>),
+    Command(RemapCommands),
+}
+
+impl FromArgMatches for RemapCliArgs {
+    fn from_arg_matches_mut(matches: &mut ArgMatches) -> std::result::Result<Self, clap::Error> {
+        if matches.subcommand_name().is_some() {
+            RemapCommands::from_arg_matches_mut(matches).map(Self::Command)
+        } else {
+            RemapArgs::from_arg_matches_mut(matches).map(|args| Self::Run(Box::new(args)))
+        }
+    }
+
+    fn from_arg_matches(matches: &ArgMatches) -> std::result::Result<Self, clap::Error> {
+        Self::from_arg_matches_mut(&mut matches.clone())
+    }
+
+    fn update_from_arg_matches(
+        &mut self,
+        matches: &ArgMatches,
+    ) -> std::result::Result<(), clap::Error> {
+        *self = Self::from_arg_matches(matches)?;
+        Ok(())
+    }
+}
+
+impl Args for RemapCliArgs {
+    fn augment_args(command: clap::Command) -> clap::Command {
+        RemapCommands::augment_subcommands(RemapArgs::augment_args(command))
+            .args_conflicts_with_subcommands(true)
+            .subcommand_negates_reqs(true)
+    }
+
+    fn augment_args_for_update(command: clap::Command) -> clap::Command {
+        Self::augment_args(command)
+    }
+}
+
+#[derive(Subcommand)]
+pub enum RemapCommands {
+    /// Restore the owners recorded by a remap run with --undo-journal or --snapshot-manifest
+    Undo(UndoArgs),
+    /// Check that the owners of a tree are those a remap leaves, failing for every entry
+    /// that does not have one
+    Verify(VerifyArgs),
+    /// Run a remap profile set up by the administrator, through pkexec unless run as root
+    Profile(ProfileArgs),
+}
+
+#[derive(Args, Clone, Debug, Default)]
+pub struct UndoArgs {
+    /// Journal written by `remap --undo-journal` or manifest by `remap --snapshot-manifest`
+    pub journal: PathBuf,
+
+    /// Show what would be restored without changing anything (the global --dry-run)
+    #[arg(skip)]
+    pub dry_run: bool,
+
+    /// Log every entry restored (the global --verbose)
+    #[arg(skip)]
+    pub verbose: bool,
+
+    /// Check that every entry still has the owner the remap gave it before restoring
+    /// any, and stop at the first that no longer does
+    #[arg(long)]
+    pub expect_clean: bool,
+}
+
+#[derive(Args, Clone, Debug)]
+#[command(group(ArgGroup::new("expected").args(["from_base", "manifest"]).required(true)))]
+pub struct VerifyArgs {
+    /// Tree to check against the mapping
+    #[arg(required_unless_present = "manifest")]
+    pub base_directory: Option<PathBuf>,
+
+    /// Source UID/GID base range of the remap the tree went through
+    #[arg(long, requires = "to_base")]
+    pub from_base: Option<u32>,
+
+    /// Target UID/GID base range of the remap
+    #[arg(long, requires = "from_base")]
+    pub to_base: Option<u32>,
+
+    /// Size of the ID range remapped
+    #[arg(long, default_value = "65536", value_parser = clap::value_parser!(u32).range(1..))]
+    pub range_size: u32,
+
+    /// The single UID FROM was mapped to TO instead of through the range (repeatable)
+    #[arg(long, value_name = "FROM:TO", value_parser = idmap::parse_point)]
+    pub map_uid: Vec<IdMapping>,
+
+    /// Like --map-uid, for GIDs
+    #[arg(long, value_name = "FROM:TO", value_parser = idmap::parse_point)]
+    pub map_gid: Vec<IdMapping>,
+
+    /// Also fail for entries with a UID or GID in neither the source nor the target range
+    #[arg(long, requires = "from_base")]
+    pub strict: bool,
+
+    /// Instead of a mapping, check that every entry of this undo journal or snapshot
+    /// manifest has the owner recorded as its new one
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["base_directory", "from_base"])]
+    pub manifest: Option<PathBuf>,
+
+    /// Leave out paths matching PATTERN (repeatable)
+    #[arg(long, value_name = "PATTERN")]
+    pub exclude: Vec<String>,
+
+    /// Do not descend into other filesystems below the tree
+    #[arg(long)]
+    pub one_file_system: bool,
+
+    /// Log every entry checked (the global --verbose)
+    #[arg(skip)]
+    pub verbose: bool,
+}
+
+impl Default for VerifyArgs {
+    fn default() -> Self {
+        Self {
+            base_directory: None,
+            from_base: None,
+            to_base: None,
+            range_size: 65536,
+            map_uid: Vec::new(),
+            map_gid: Vec::new(),
+            strict: false,
+            manifest: None,
+            exclude: Vec::new(),
+            one_file_system: false,
+            verbose: false,
+        }
+    }
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct ProfileArgs {
+    /// Name of the profile, read from NAME.json in the profile directory
+    pub name: String,
+
+    /// Directory holding the profiles; refused when run through pkexec
+    #[arg(long, value_name = "DIR", default_value = profile::PROFILE_DIR, hide = true)]
+    pub profile_dir: PathBuf,
+
+    /// Show what would change without changing anything (the global --dry-run)
+    #[arg(skip)]
+    pub dry_run: bool,
+
+    /// Log every entry changed (the global --verbose)
+    #[arg(skip)]
+    pub verbose: bool,
+}
+
+#[derive(Args, Clone)]
+#[command(group(ArgGroup::new("target").args(["to_base", "subid_user"]).required(true)))]
 pub struct RemapArgs {
     /// Base directory path to remap (e.g., /var/lib/lxc/container/rootfs)
     pub base_directory: PathBuf,
@@ -21,546 +254,6695 @@ pub struct RemapArgs {
     pub from_base: u32,
 
     /// Target UID/GID base range (e.g., 50000000)
-    #[arg(long)]
+    #[arg(long, default_value_t = 0, hide_default_value = true)]
     pub to_base: u32,
 
-    /// Size of the ID range to remap
-    #[arg(long, default_value = "65536")]
+    /// Take the target base from the subordinate IDs delegated to this user in the subid
+    /// files, instead of --to-base
+    #[arg(long, value_name = "USER")]
+    pub subid_user: Option<String>,
+
+    /// Subordinate UID file consulted for --subid-user
+    #[arg(long, value_name = "FILE", default_value = SUBUID_FILE)]
+    pub subuid_file: PathBuf,
+
+    /// Subordinate GID file consulted for --subid-user
+    #[arg(long, value_name = "FILE", default_value = SUBGID_FILE)]
+    pub subgid_file: PathBuf,
+
+    /// Size of the ID range to remap; both ranges must end below 4294967295
+    #[arg(long, default_value = "65536", value_parser = clap::value_parser!(u32).range(1..))]
     pub range_size: u32,
 
-    /// Show what would be changed without making modifications
-    #[arg(long)]
+    /// Map the single UID FROM to TO instead of through the range, e.g. 33:3333 for a
+    /// service account that must land on a given host ID (repeatable)
+    #[arg(long, value_name = "FROM:TO", value_parser = idmap::parse_point, conflicts_with = "gid_only")]
+    pub map_uid: Vec<IdMapping>,
+
+    /// Like --map-uid, for GIDs
+    #[arg(long, value_name = "FROM:TO", value_parser = idmap::parse_point, conflicts_with = "uid_only")]
+    pub map_gid: Vec<IdMapping>,
+
+    /// Also map the UID and GID translations in FILE, CSV lines of KIND,FROM,TO[,COUNT] or
+    /// a JSON array, checked for overlaps and duplicates before the walk
+    #[arg(long, value_name = "FILE")]
+    pub mapping_file: Option<PathBuf>,
+
+    /// Map the UID the tree's own etc/passwd gives user NAME to TO, e.g. postgres:64000;
+    /// the UID is taken as the container sees it, counted from --from-base (repeatable)
+    #[arg(long, value_name = "NAME:TO", conflicts_with = "gid_only")]
+    pub map_user: Vec<NamedMapping>,
+
+    /// Like --map-user, for groups of the tree's etc/group
+    #[arg(long, value_name = "NAME:TO", conflicts_with = "uid_only")]
+    pub map_group: Vec<NamedMapping>,
+
+    /// Also map the container IDs of lxc.idmap entries, counted from --from-base, to the
+    /// host IDs they give
+    #[command(flatten)]
+    pub lxc: LxcIdMapArgs,
+
+    /// Show what would be changed without making modifications (the global --dry-run)
+    #[arg(skip)]
     pub dry_run: bool,
 
-    /// Show detailed output for each file processed
-    #[arg(long)]
+    /// Show detailed output for each file processed (the global --verbose)
+    #[arg(skip)]
     pub verbose: bool,
 
+    /// With --verbose, log a progress line every N entries or every DURATION (e.g. 30s, 5m)
+    #[arg(long, value_name = "N|DURATION", default_value_t = ProgressInterval::default())]
+    pub progress_interval: ProgressInterval,
+
     /// Exclude paths matching pattern (can be used multiple times)
     #[arg(long)]
     pub exclude: Vec<String>,
 
-    /// Only remap UIDs, leave GIDs unchanged
+    /// Exclude paths whose path relative to the base directory matches a regular expression
+    /// (can be used multiple times)
+    #[arg(long, value_name = "REGEX")]
+    pub exclude_regex: Vec<String>,
+
+    /// Only remap paths matching pattern, relative to the base directory, and everything
+    /// below them (can be used multiple times); --exclude takes precedence
+    #[arg(long)]
+    pub include: Vec<String>,
+
+    /// Treat a directory as a mount boundary and skip it entirely (can be used multiple times)
+    #[arg(long, value_name = "PATH")]
+    pub exclude_mountpoint: Vec<PathBuf>,
+
+    /// Do not descend into other mounts below the base directory, such as bind mounts,
+    /// volumes or tmpfs
     #[arg(long)]
+    pub one_file_system: bool,
+
+    /// Remap the tree's own filesystem with what mounts inside it hide, seen without the
+    /// mounts through a bind mount in a private mount namespace; the mounts are left alone
+    #[arg(long, conflicts_with_all = ["preview_overlay", "undo_journal", "snapshot_manifest"])]
+    pub beneath_mounts: bool,
+
+    /// Remap a throwaway overlay of the tree held in memory instead of the tree itself,
+    /// left to inspect until Enter is pressed and then discarded
+    #[arg(
+        long,
+        conflicts_with_all = ["partition", "subtree", "coordinate", "undo_journal", "snapshot_manifest", "freeze_cgroup"]
+    )]
+    pub preview_overlay: bool,
+
+    /// Mark each finished directory with a user extended attribute and skip marked ones, so
+    /// an interrupted run resumes without a state directory; the run that completes the walk
+    /// removes the markers
+    #[arg(long, conflicts_with_all = ["preview_overlay", "coordinate"])]
+    pub resume_by_xattr: bool,
+
+    /// Remap directories matching GLOB, such as etc, on a copy swapped into place once
+    /// done, so readers never see them half remapped (repeatable)
+    #[arg(long, value_name = "GLOB", conflicts_with = "resume_by_xattr")]
+    pub atomic_dirs: Vec<String>,
+
+    /// Remap the owners recorded in a fakeroot state file (as saved by fakeroot -s and
+    /// loaded by fakeroot -i) instead of those on disk, so no privileges are needed
+    #[arg(
+        long,
+        value_name = "FILE",
+        conflicts_with_all = ["undo_journal", "snapshot_manifest", "preview_overlay", "atomic_dirs", "partition", "subtree", "coordinate", "probe"]
+    )]
+    pub fakeroot_db: Option<PathBuf>,
+
+    /// Have the chown helper listening on SOCKET change the owners (see chown-helper), so
+    /// the run itself needs no privileges
+    #[arg(long, value_name = "SOCKET", conflicts_with = "fakeroot_db")]
+    pub chown_helper: Option<PathBuf>,
+
+    /// Only remap UIDs, leave GIDs unchanged
+    #[arg(long, conflicts_with = "gid_only")]
     pub uid_only: bool,
 
     /// Only remap GIDs, leave UIDs unchanged
     #[arg(long)]
     pub gid_only: bool,
-}
 
-pub struct RemapCommand {
-    args: RemapArgs,
-    seen_inodes: HashMap<(u64, u64), PathBuf>, // (device, inode) -> first path
+    /// Only remap the GIDs in the range that the tree's own etc/group names a group for,
+    /// leaving orphaned ones as they are, and report the groups remapped
+    #[arg(long, conflicts_with = "uid_only")]
+    pub known_groups_only: bool,
+
+    /// How to treat additional hard links to an inode that was already processed
+    #[arg(long, value_enum, default_value_t = HardLinkPolicy::First)]
+    pub hardlinks: HardLinkPolicy,
+
+    /// Process every path of a hard-linked inode, like --hardlinks all, so the dry run,
+    /// the undo journal and the plan list each one
+    #[arg(long, conflicts_with = "hardlinks")]
+    pub all_hardlinks: bool,
+
+    /// Abort before changing anything if an inode has hard links outside the walked tree
+    #[arg(long)]
+    pub fail_on_external_links: bool,
+
+    /// Abort before changing anything if an entry already has its owner in the target
+    /// range, as in a tree remapped before
+    #[arg(long)]
+    pub assert_unmapped: bool,
+
+    /// Change nothing and report the entries with a UID or GID in neither the source nor
+    /// the target range, grouped by owner
+    #[arg(long)]
+    pub audit: bool,
+
+    /// Stop at the first entry that fails instead of going on with the rest of the tree
+    #[arg(long, conflicts_with = "max_errors")]
+    pub fail_fast: bool,
+
+    /// Stop once N entries have failed instead of going on with the rest of the tree
+    #[arg(long, value_name = "N")]
+    pub max_errors: Option<NonZeroUsize>,
+
+    /// Do not put back extended attributes, such as file capabilities, that an ownership
+    /// change dropped or altered
+    #[arg(long)]
+    pub no_preserve_xattrs: bool,
+
+    /// Do not put back setuid, setgid and sticky bits that an ownership change cleared
+    #[arg(long)]
+    pub no_restore_mode: bool,
+
+    /// Scan for setuid-root files, root-owned world-writable directories and stray device
+    /// nodes before making changes
+    #[arg(long)]
+    pub safety_scan: bool,
+
+    /// Once the remap is done, warn about symlinks left with an owner in the source range
+    /// whose target has one in the target range, or the other way round
+    #[arg(long)]
+    pub audit_symlinks: bool,
+
+    /// Once the remap is done, walk the tree again and fail for every entry still carrying
+    /// an owner the remap maps
+    #[arg(long, conflicts_with = "fakeroot_db")]
+    pub verify: bool,
+
+    /// Walk the tree for --verify on a private read-only bind of it and its mounts, which
+    /// the pass cannot change and no mount comes or goes from while it reads
+    #[arg(long, requires = "verify")]
+    pub verify_readonly: bool,
+
+    /// Once the remap is done, stat this percentage of the changed entries again and fail
+    /// for those whose owner is not the one given, as on filesystems that accept ownership
+    /// changes without keeping them
+    #[arg(
+        long,
+        value_name = "PERCENT",
+        value_parser = clap::value_parser!(u8).range(1..=100),
+        conflicts_with = "fakeroot_db"
+    )]
+    pub spot_check: Option<u8>,
+
+    /// With --dry-run, check for CAP_CHOWN and try a reversible chown on one entry per
+    /// filesystem to predict permission failures
+    #[arg(long, requires = "dry_run")]
+    pub probe: bool,
+
+    /// Remap even if processes are using the tree, such as a container still running on it
+    #[arg(long)]
+    pub allow_in_use: bool,
+
+    /// Only remap the top-level entries (or --subtree units) that hash to partition INDEX of
+    /// COUNT, e.g. 2/8, as one resumable job of a larger migration
+    #[arg(long, value_name = "INDEX/COUNT")]
+    pub partition: Option<Partition>,
+
+    /// Remap only this directory below the base directory, as a unit of a resumable job (can
+    /// be used multiple times)
+    #[arg(long, value_name = "PATH")]
+    pub subtree: Vec<PathBuf>,
+
+    /// Skip what an interrupted or failed run of the same job completed: the units of a
+    /// --partition/--subtree job, or the top-level directories of a whole tree
+    #[arg(long)]
+    pub resume: bool,
+
+    /// Share the units with jobs on other hosts coordinating through this directory on
+    /// shared storage, so that each unit is remapped by exactly one of them
+    #[arg(long, value_name = "DIR", conflicts_with_all = ["partition", "resume"])]
+    pub coordinate: Option<PathBuf>,
+
+    /// Freeze this cgroup v2 directory (e.g. /sys/fs/cgroup/lxc.payload.web) while remapping
+    /// and thaw it afterwards, instead of stopping the container
+    #[arg(long, value_name = "DIR")]
+    pub freeze_cgroup: Option<PathBuf>,
+
+    /// Refused: a filesystem frozen with FIFREEZE blocks the remap's own ownership changes,
+    /// so --freeze-cgroup keeps writers still instead
+    #[arg(long, hide = true)]
+    pub freeze_fs: bool,
+
+    /// Record every ownership change in this new file before making it, so that
+    /// `remap undo FILE` can restore the previous owners
+    #[arg(long, value_name = "FILE")]
+    pub undo_journal: Option<PathBuf>,
+
+    /// Before changing anything, write the current owner of every entry the run will change
+    /// to this new file, in the format of an undo journal, so that `remap undo FILE` can
+    /// restore them even if the run was given the wrong ranges
+    #[arg(long, value_name = "FILE")]
+    pub snapshot_manifest: Option<PathBuf>,
+
+    /// Only make changes if they hash to this plan, as printed by an approved --dry-run;
+    /// with --dry-run, check the plan without changing anything
+    #[arg(long, value_name = "HASH", value_parser = plan::parse_plan_hash)]
+    pub plan_hash: Option<String>,
+
+    /// With --plan-hash, stop at the first entry whose owner is no longer the one the
+    /// approved plan changes it from
+    #[arg(long, requires = "plan_hash")]
+    pub expect_clean: bool,
+
+    /// Walk the tree and apply ownership changes on N threads (the global --threads)
+    #[arg(skip = NonZeroUsize::MIN)]
+    pub jobs: NonZeroUsize,
+
+    /// Limit reads of entries, stats and owner changes to N a second, or to a share of the
+    /// time such as 25%, to leave IOPS to others on shared storage
+    #[arg(long, value_name = "N|PERCENT")]
+    pub throttle: Option<Throttle>,
+
+    /// Run additional analyzers in the same pass over the tree (comma-separated:
+    /// owners, perms, checksum)
+    #[arg(long, value_name = "TASK", value_delimiter = ',')]
+    pub with: Vec<String>,
+
+    /// WebAssembly plugin deciding which entries to process and how IDs map
+    /// (requires the wasm-plugins feature)
+    #[arg(long, value_name = "FILE")]
+    pub plugin: Option<PathBuf>,
+
+    /// Present IDs in output as seen from the host or from inside the container
+    #[arg(long, value_enum, default_value_t = View::Host)]
+    pub view: View,
+
+    /// Print the final summary in this format, as the global --output-format does
+    #[arg(long, value_enum, value_name = "FORMAT")]
+    pub output: Option<OutputFormat>,
+
+    /// What to do with tar archives found in the tree, such as images shipped in a rootfs
+    #[arg(long, value_enum, default_value_t = NestedPolicy::Warn)]
+    pub nested: NestedPolicy,
+
+    /// Report the project quota IDs of files and directories, or remap those in the source
+    /// range like UIDs
+    #[arg(long, value_enum, default_value_t = ProjectIdMode::Ignore)]
+    pub project_ids: ProjectIdMode,
+
+    #[command(flatten)]
+    pub progress: ProgressArgs,
 }
 
-impl RemapCommand {
-    pub fn new(args: RemapArgs) -> Self {
+impl Default for RemapArgs {
+    fn default() -> Self {
         Self {
-            args,
-            seen_inodes: HashMap::new(),
+            base_directory: PathBuf::new(),
+            from_base: 0,
+            to_base: 0,
+            subid_user: None,
+            subuid_file: PathBuf::from(SUBUID_FILE),
+            subgid_file: PathBuf::from(SUBGID_FILE),
+            range_size: 65536,
+            map_uid: Vec::new(),
+            map_gid: Vec::new(),
+            mapping_file: None,
+            map_user: Vec::new(),
+            map_group: Vec::new(),
+            lxc: LxcIdMapArgs::default(),
+            dry_run: false,
+            verbose: false,
+            progress_interval: ProgressInterval::default(),
+            exclude: Vec::new(),
+            exclude_regex: Vec::new(),
+            include: Vec::new(),
+            exclude_mountpoint: Vec::new(),
+            one_file_system: false,
+            beneath_mounts: false,
+            preview_overlay: false,
+            resume_by_xattr: false,
+            atomic_dirs: Vec::new(),
+            fakeroot_db: None,
+            chown_helper: None,
+            uid_only: false,
+            gid_only: false,
+            known_groups_only: false,
+            hardlinks: HardLinkPolicy::First,
+            all_hardlinks: false,
+            fail_on_external_links: false,
+            assert_unmapped: false,
+            audit: false,
+            fail_fast: false,
+            max_errors: None,
+            no_preserve_xattrs: false,
+            no_restore_mode: false,
+            safety_scan: false,
+            audit_symlinks: false,
+            verify: false,
+            verify_readonly: false,
+            spot_check: None,
+            probe: false,
+            allow_in_use: false,
+            partition: None,
+            subtree: Vec::new(),
+            resume: false,
+            coordinate: None,
+            freeze_cgroup: None,
+            freeze_fs: false,
+            undo_journal: None,
+            snapshot_manifest: None,
+            plan_hash: None,
+            expect_clean: false,
+            jobs: NonZeroUsize::MIN,
+            throttle: None,
+            with: Vec::new(),
+            plugin: None,
+            view: View::Host,
+            output: None,
+            nested: NestedPolicy::Warn,
+            project_ids: ProjectIdMode::Ignore,
+            progress: ProgressArgs::default(),
         }
     }
+}
 
-    pub fn execute(mut self) -> Result<()> {
-        self.validate_args()?;
+impl RemapArgs {
+    /// Check that both ranges fit in the ID space, which depends on more than one argument
+    /// and so is beyond clap's value parsers.
+    pub fn check_ranges(&self) -> RustUtilsResult<()> {
+        if self.from_base >= u32::MAX - self.range_size {
+            return Err(RustUtilsError::InvalidRange(
+                "from_base + range_size would overflow".to_string(),
+            ));
+        }
 
-        if !self.args.base_directory.exists() {
-            return Err(RustUtilsError::DirectoryNotFound(
-                self.args.base_directory.display().to_string(),
+        if self.to_base >= u32::MAX - self.range_size {
+            return Err(RustUtilsError::InvalidRange(
+                "to_base + range_size would overflow".to_string(),
+            ));
+        }
+
+        let mut args = self.clone();
+        args.load_overrides()?;
+        args.id_map(IdKind::Uid)?;
+        args.id_map(IdKind::Gid)?;
+        Ok(())
+    }
+
+    /// Add the mappings of `--mapping-file`, `--idmap` and `--idmap-file`, and of
+    /// `--map-user` and `--map-group` resolved in the tree's own databases, to those of
+    /// `--map-uid` and `--map-gid`, once: nothing is read again for the same arguments.
+    pub fn load_overrides(&mut self) -> RustUtilsResult<()> {
+        self.load_mapping_file()?;
+        self.load_idmaps()?;
+        for kind in [IdKind::Uid, IdKind::Gid] {
+            let named = std::mem::take(match kind {
+                IdKind::Uid => &mut self.map_user,
+                IdKind::Gid => &mut self.map_group,
+            });
+            if named.is_empty() {
+                continue;
+            }
+            let database = passwd::Database::read(&self.base_directory, kind)?;
+            for mapping in named {
+                let id = database.id(&mapping.name)?;
+                if id >= self.range_size {
+                    return Err(RustUtilsError::InvalidRange(format!(
+                        "{} of '{}' in {} is {id}, beyond the {} IDs remapped",
+                        kind,
+                        mapping.name,
+                        database.path().display(),
+                        self.range_size
+                    )));
+                }
+                let mapping = IdMapping {
+                    from: self.from_base + id,
+                    to: mapping.to,
+                    count: 1,
+                };
+                match kind {
+                    IdKind::Uid => self.map_uid.push(mapping),
+                    IdKind::Gid => self.map_gid.push(mapping),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn load_idmaps(&mut self) -> RustUtilsResult<()> {
+        let lxc = std::mem::take(&mut self.lxc);
+        if lxc.is_empty() {
+            return Ok(());
+        }
+        let (uids, gids) = lxc.by_kind()?;
+        for (kind, entries) in [(IdKind::Uid, uids), (IdKind::Gid, gids)] {
+            let unchanged = match kind {
+                IdKind::Uid => self.gid_only,
+                IdKind::Gid => self.uid_only,
+            };
+            for mapping in entries {
+                let entry = LxcIdMap { kind, mapping };
+                if unchanged {
+                    return Err(RustUtilsError::InvalidArguments(format!(
+                        "lxc.idmap '{entry}' maps IDs that --uid-only or --gid-only leaves \
+                         unchanged"
+                    )));
+                }
+                if u64::from(mapping.from) + u64::from(mapping.count) > u64::from(self.range_size) {
+                    return Err(RustUtilsError::InvalidRange(format!(
+                        "lxc.idmap '{entry}' reaches beyond the {} IDs remapped",
+                        self.range_size
+                    )));
+                }
+                let mapping = IdMapping {
+                    from: self.from_base + mapping.from,
+                    ..mapping
+                };
+                match kind {
+                    IdKind::Uid => self.map_uid.push(mapping),
+                    IdKind::Gid => self.map_gid.push(mapping),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn load_mapping_file(&mut self) -> RustUtilsResult<()> {
+        let Some(path) = self.mapping_file.take() else {
+            return Ok(());
+        };
+        let file = mapfile::read(&path)?;
+        let ignored = if self.uid_only {
+            (!file.gid.is_empty()).then_some("GID")
+        } else if self.gid_only {
+            (!file.uid.is_empty()).then_some("UID")
+        } else {
+            None
+        };
+        if let Some(kind) = ignored {
+            return Err(RustUtilsError::InvalidArguments(format!(
+                "{} maps {kind}s, which --uid-only or --gid-only leaves unchanged",
+                path.display()
+            )));
+        }
+        self.map_uid.extend(file.uid);
+        self.map_gid.extend(file.gid);
+        Ok(())
+    }
+
+    /// The mapping of UIDs or GIDs: the range with the mappings that override it, or no
+    /// mapping at all for the IDs `--uid-only` or `--gid-only` leaves unchanged.
+    pub fn id_map(&self, kind: IdKind) -> RustUtilsResult<IdMap> {
+        let (unchanged, overrides) = match kind {
+            IdKind::Uid => (self.gid_only, &self.map_uid),
+            IdKind::Gid => (self.uid_only, &self.map_gid),
+        };
+        if unchanged {
+            return Ok(IdMap::default());
+        }
+        let range = IdMapping {
+            from: self.from_base,
+            to: self.to_base,
+            count: self.range_size,
+        };
+        IdMap::with_overrides(range, overrides)
+    }
+
+    /// How `--view` shows the UIDs and GIDs of the run's reports.
+    pub fn owner_view(&self) -> RustUtilsResult<OwnerView> {
+        let view = |kind| {
+            IdView::new(
+                self.view,
+                self.from_base,
+                self.range_size,
+                &self.id_map(kind)?,
             )
-            .into());
+        };
+        Ok(OwnerView {
+            uid: view(IdKind::Uid)?,
+            gid: view(IdKind::Gid)?,
+        })
+    }
+
+    /// The mappings of `--map-uid` or `--map-gid` and `--mapping-file` that override the
+    /// range.
+    fn overrides(&self, kind: IdKind) -> &[IdMapping] {
+        match kind {
+            IdKind::Uid => &self.map_uid,
+            IdKind::Gid => &self.map_gid,
         }
+    }
 
-        if !self.args.base_directory.is_dir() {
-            return Err(RustUtilsError::DirectoryNotFound(format!(
-                "{} is not a directory",
-                self.args.base_directory.display()
-            ))
-            .into());
+    /// Whether the UID or GID map changes some ID to one it also changes, as when the
+    /// source and target ranges overlap, so the run has to take two phases.
+    pub fn is_chained(&self) -> RustUtilsResult<bool> {
+        Ok(self.id_map(IdKind::Uid)?.is_chained() || self.id_map(IdKind::Gid)?.is_chained())
+    }
+
+    /// Start of the IDs a chained run passes through between its two phases, or `None` if
+    /// it needs only one: the range moved, followed by room for the overrides outside it.
+    /// They are at the top of the ID space if no mapping uses it, else below every mapping.
+    pub fn temporary_base(&self) -> RustUtilsResult<Option<u32>> {
+        if !self.is_chained()? {
+            return Ok(None);
+        }
+        let mut used = Vec::new();
+        for kind in [IdKind::Uid, IdKind::Gid] {
+            for mapping in self.id_map(kind)?.mappings() {
+                used.push((u64::from(mapping.from), u64::from(mapping.count)));
+                used.push((u64::from(mapping.to), u64::from(mapping.count)));
+            }
+        }
+        let needed = u64::from(self.range_size) + u64::from(self.outside_count());
+        // (uid_t)-1 leaves an ID unchanged, so the last ID used is the one below it
+        let top = u64::from(u32::MAX - 1).checked_sub(needed);
+        let bottom = used
+            .iter()
+            .map(|&(first, _)| first)
+            .min()
+            .and_then(|lowest| lowest.checked_sub(needed));
+        let free = |first: u64| {
+            used.iter()
+                .all(|&(from, count)| from + count <= first || first + needed <= from)
+        };
+        match [top, bottom]
+            .into_iter()
+            .flatten()
+            .find(|&first| free(first))
+        {
+            Some(first) => Ok(Some(first as u32)),
+            None => Err(RustUtilsError::InvalidRange(format!(
+                "no room for the {needed} IDs overlapping ranges pass through"
+            ))),
         }
+    }
 
-        if self.args.dry_run {
-            info!("DRY RUN MODE - No changes will be made");
+    /// How many IDs outside the range the UID or GID overrides map, whichever is more.
+    fn outside_count(&self) -> u32 {
+        [IdKind::Uid, IdKind::Gid]
+            .into_iter()
+            .map(|kind| {
+                self.overrides(kind)
+                    .iter()
+                    .flat_map(|mapping| self.split_override(mapping).1)
+                    .map(|piece| piece.count)
+                    .sum::<u32>()
+            })
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// The part of an override inside the range and those outside it.
+    fn split_override(&self, mapping: &IdMapping) -> (Option<IdMapping>, Vec<IdMapping>) {
+        let piece = |from: u32, end: u32| {
+            (from < end).then(|| IdMapping {
+                from,
+                to: mapping.to + (from - mapping.from),
+                count: end - from,
+            })
+        };
+        let mapping_end = mapping.from + mapping.count;
+        let range_end = self.from_base + self.range_size;
+        let inside = piece(mapping.from.max(self.from_base), mapping_end.min(range_end));
+        let outside = [
+            piece(mapping.from, mapping_end.min(self.from_base)),
+            piece(mapping.from.max(range_end), mapping_end),
+        ];
+        (inside, outside.into_iter().flatten().collect())
+    }
+
+    /// The runs of a chained remap through the IDs from `temporary`: the first moves every
+    /// ID that changes there, out of the way of the others, and the second on to its target.
+    pub fn phases(&self, temporary: u32) -> [RemapArgs; 2] {
+        let mut first = RemapArgs {
+            to_base: temporary,
+            map_uid: Vec::new(),
+            map_gid: Vec::new(),
+            subid_user: None,
+            plan_hash: None,
+            snapshot_manifest: None,
+            freeze_cgroup: None,
+            audit_symlinks: false,
+            // Only the entries the second phase leaves behind are verified
+            verify: false,
+            // Checked against the final target before the first phase
+            assert_unmapped: false,
+            ..self.clone()
+        };
+        // What the first phase saw and checked needs no second look
+        let mut second = RemapArgs {
+            from_base: temporary,
+            map_uid: Vec::new(),
+            map_gid: Vec::new(),
+            subid_user: None,
+            plan_hash: None,
+            snapshot_manifest: None,
+            freeze_cgroup: None,
+            safety_scan: false,
+            fail_on_external_links: false,
+            assert_unmapped: false,
+            probe: false,
+            with: Vec::new(),
+            plugin: None,
+            ..self.clone()
+        };
+        for kind in [IdKind::Uid, IdKind::Gid] {
+            let mut next = temporary + self.range_size;
+            for mapping in self.overrides(kind) {
+                let (inside, outside) = self.split_override(mapping);
+                let (first, second) = match kind {
+                    IdKind::Uid => (&mut first.map_uid, &mut second.map_uid),
+                    IdKind::Gid => (&mut first.map_gid, &mut second.map_gid),
+                };
+                // Inside the range, an override moves with it
+                if let Some(inside) = inside {
+                    second.push(IdMapping {
+                        from: temporary + (inside.from - self.from_base),
+                        ..inside
+                    });
+                }
+                for piece in outside {
+                    first.push(IdMapping { to: next, ..piece });
+                    second.push(IdMapping {
+                        from: next,
+                        ..piece
+                    });
+                    next += piece.count;
+                }
+            }
         }
+        [first, second]
+    }
 
-        info!("Starting UID/GID remapping");
-        info!("Base directory: {}", self.args.base_directory.display());
-        info!(
-            "From range: {}-{}",
-            self.args.from_base,
-            self.args.from_base + self.args.range_size - 1
-        );
+    /// Set `to_base` to the start of the first range delegated to `--subid-user` that holds
+    /// `range_size` IDs, in the subordinate UID file, the GID file or both as remapped.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`RustUtilsError::InvalidArguments`] if the user has no such range, or if the
+    /// ranges found for UIDs and GIDs start at different IDs, which one remap cannot target.
+    pub fn apply_subid_user(&mut self) -> RustUtilsResult<()> {
+        let Some(user) = &self.subid_user else {
+            return Ok(());
+        };
+        let mut files = Vec::new();
+        if !self.gid_only {
+            files.push((IdKind::Uid, &self.subuid_file));
+        }
+        if !self.uid_only {
+            files.push((IdKind::Gid, &self.subgid_file));
+        }
+
+        let mut starts = Vec::new();
+        for (kind, file) in files {
+            let range = subid::ranges_for(file, user)?
+                .into_iter()
+                .find(|range| range.count >= self.range_size)
+                .ok_or_else(|| {
+                    RustUtilsError::InvalidArguments(format!(
+                        "no range of {} subordinate {}s for '{}' in {}",
+                        self.range_size,
+                        kind,
+                        user,
+                        file.display()
+                    ))
+                })?;
+            starts.push((kind, range.start));
+        }
+        if let [(_, uid_start), (_, gid_start)] = starts[..] {
+            if uid_start != gid_start {
+                return Err(RustUtilsError::InvalidArguments(format!(
+                    "subordinate uids of '{user}' start at {uid_start} but gids at {gid_start}; \
+                     remap them separately with --uid-only and --gid-only"
+                )));
+            }
+        }
+        if let Some(&(_, start)) = starts.first() {
+            self.to_base = start;
+        }
+        Ok(())
+    }
+}
+
+/// A part of the tree walked on its own and journaled once complete.
+struct Unit {
+    /// Path relative to the base directory (`.` for the base directory entry itself), or
+    /// `None` when the whole tree is one walk
+    name: Option<PathBuf>,
+    root: PathBuf,
+    max_depth: usize,
+}
+
+/// First sighting of a multiply-linked inode.
+struct LinkRecord {
+    first_path: PathBuf,
+    metadata: Metadata,
+    paths_seen: u64,
+}
+
+/// How many entries with only one ID in the source range are listed individually.
+const MAX_LISTED_ASYMMETRIC: usize = 20;
+
+/// Permission bits of a mode, including the special bits.
+const MODE_BITS: u32 = 0o7777;
+/// Setuid, setgid and sticky bits, which a chown may clear.
+const SPECIAL_MODE_BITS: u32 = 0o7000;
+
+/// Entry with only one of its IDs in the source range. Remaps change both IDs together, so
+/// these usually mean an earlier remap was interrupted or limited to UIDs or GIDs.
+#[derive(Debug, PartialEq, Eq)]
+struct AsymmetricEntry {
+    path: PathBuf,
+    uid: u32,
+    gid: u32,
+    /// `true` if the UID is the one in the source range, `false` for the GID
+    uid_in_range: bool,
+    /// Whether the other ID is already in the target range
+    other_mapped: bool,
+}
+
+/// Entries with only one ID in the source range found during a walk.
+#[derive(Default)]
+struct AsymmetricEntries {
+    listed: Vec<AsymmetricEntry>,
+    uid: u64,
+    gid: u64,
+    other_mapped: u64,
+    /// Sockets and FIFOs, counted apart from the rest as they are recreated anyway
+    ephemeral: u64,
+}
+
+/// Entries with an ID in neither the source nor the target range, found by `--audit`:
+/// stray host-owned files or IDs leaked from another container.
+#[derive(Default)]
+struct UnexpectedOwners {
+    paths: BTreeMap<(u32, u32), Vec<PathBuf>>,
+}
+
+impl UnexpectedOwners {
+    fn record(&mut self, owner: (u32, u32), path: &Path) {
+        self.paths
+            .entry(owner)
+            .or_default()
+            .push(path.to_path_buf());
+    }
+
+    fn entries(&self) -> u64 {
+        self.paths.values().map(|paths| paths.len() as u64).sum()
+    }
+
+    fn log(&self, owners: &OwnerView) {
+        for (owner, paths) in &self.paths {
+            warn!(
+                "{} entry(ies) owned by {}, in neither range:",
+                paths.len(),
+                owners.current(*owner)
+            );
+            for path in paths {
+                warn!("  {}", path.display());
+            }
+        }
         info!(
-            "To range: {}-{}",
-            self.args.to_base,
-            self.args.to_base + self.args.range_size - 1
+            "Audit: {} entries with an ID in neither range, {} owners",
+            self.entries(),
+            self.paths.len()
         );
+    }
+}
 
-        let mut files_processed = 0;
-        let mut files_remapped = 0;
+/// Entries counted by group with `--known-groups-only`.
+#[derive(Default)]
+struct KnownGroups {
+    /// Entries given a new GID, by the name of their group in the tree
+    remapped: BTreeMap<String, u64>,
+    /// Entries left alone, by their GID no group of the tree has
+    orphaned: BTreeMap<u32, u64>,
+}
 
-        // Collect paths first to avoid borrowing issues
-        let entries: Result<Vec<_>, _> = WalkDir::new(&self.args.base_directory)
-            .follow_links(false)
-            .into_iter()
-            .filter_entry(|e| !should_exclude(e.path(), &self.args.exclude))
-            .collect();
+impl KnownGroups {
+    fn log(&self, owners: &OwnerView) {
+        for (name, entries) in &self.remapped {
+            info!("Group {}: {} entry(ies) remapped", name, entries);
+        }
+        if !self.orphaned.is_empty() {
+            let gids: Vec<String> = self
+                .orphaned
+                .keys()
+                .map(|gid| owners.gid.current(*gid).to_string())
+                .collect();
+            warn!(
+                "{} entry(ies) kept GIDs no group of the tree has: {}",
+                self.orphaned.values().sum::<u64>(),
+                gids.join(", ")
+            );
+        }
+    }
+
+    /// Count the entries of each group as `group_<name>` in `report`, and those left alone
+    /// as `orphaned_gid_entries`.
+    fn summarize(&self, report: &mut RunReport) {
+        for (name, entries) in &self.remapped {
+            report.count(&format!("group_{name}"), *entries);
+        }
+        report.count("orphaned_gid_entries", self.orphaned.values().sum());
+    }
+}
+
+/// Changes sampled by `--spot-check` to stat again once the run is done.
+#[derive(Default)]
+struct SpotCheck {
+    /// Changes made so far
+    changes: u64,
+    /// Path, device and new owner of the changes sampled
+    sampled: Vec<(PathBuf, u64, Owner)>,
+}
+
+impl SpotCheck {
+    /// Count a change of `path` on `device` to `owner`, sampling it if it is due for
+    /// `percent` of the changes to be sampled, the first of them included.
+    fn offer(&mut self, percent: u8, path: &Path, device: u64, owner: Owner) {
+        let due = |changes: u64| (changes * u64::from(percent)).div_ceil(100);
+        self.changes += 1;
+        if due(self.changes) > due(self.changes - 1) {
+            self.sampled.push((path.to_path_buf(), device, owner));
+        }
+    }
+}
+
+/// Project IDs seen with `--project-ids`.
+#[derive(Default)]
+struct ProjectIds {
+    /// Entries with a project ID other than 0
+    assigned: u64,
+    in_range: u64,
+    remapped: u64,
+}
+
+impl ProjectIds {
+    fn log(&self, mode: ProjectIdMode) {
+        if mode == ProjectIdMode::Ignore {
+            return;
+        }
+        info!(
+            "Project IDs: {} entries with one, {} in the source range, {} remapped",
+            self.assigned, self.in_range, self.remapped
+        );
+        if mode == ProjectIdMode::Report && self.in_range > 0 {
+            warn!(
+                "{} entries have a project ID in the source range; --project-ids remap maps them",
+                self.in_range
+            );
+        }
+    }
+}
+
+/// What a remap run counts as it walks the tree, for its report.
+#[derive(Default)]
+struct RunTotals {
+    counters: Counters,
+    skipped: u64,
+    atomic_dirs: u64,
+    visitor_events: u64,
+    nested_archives: u64,
+    ipc_objects: u64,
+    asymmetric: AsymmetricEntries,
+    project_ids: ProjectIds,
+    filesystems: FilesystemStats,
+    entry_types: EntryTypeStats,
+    dirs_marked: u64,
+    dirs_resumed: u64,
+    markers_removed: u64,
+    /// Inodes with links outside the tree
+    external_links: u64,
+    /// Mounts hiding entries below them, if that was checked
+    shadowed_mounts: Option<u64>,
+}
+
+impl AsymmetricEntries {
+    fn record(&mut self, entry: AsymmetricEntry, kind: EntryType) {
+        if kind.is_ephemeral() {
+            self.ephemeral += 1;
+            return;
+        }
+        if entry.uid_in_range {
+            self.uid += 1;
+        } else {
+            self.gid += 1;
+        }
+        if entry.other_mapped {
+            self.other_mapped += 1;
+        }
+        if self.listed.len() < MAX_LISTED_ASYMMETRIC {
+            self.listed.push(entry);
+        }
+    }
+
+    fn total(&self) -> u64 {
+        self.uid + self.gid
+    }
+
+    fn log(&self, owners: &OwnerView) {
+        for entry in &self.listed {
+            let (id, other, other_id) = if entry.uid_in_range {
+                ("UID", "GID", owners.gid.current(entry.gid))
+            } else {
+                ("GID", "UID", owners.uid.current(entry.uid))
+            };
+            warn!(
+                "{} has only its {} in the source range ({}){}",
+                entry.path.display(),
+                id,
+                owners.current((entry.uid, entry.gid)),
+                if entry.other_mapped {
+                    format!(", its {other} {other_id} is already in the target range")
+                } else {
+                    String::new()
+                }
+            );
+        }
+        if self.total() > self.listed.len() as u64 {
+            warn!(
+                "... and {} more entries with only one ID in the source range",
+                self.total() - self.listed.len() as u64
+            );
+        }
+        if self.total() > 0 {
+            warn!(
+                "Entries with only one ID in the source range: {} by UID, {} by GID, {} with the other ID already in the target range; an earlier remap may have been interrupted",
+                self.uid, self.gid, self.other_mapped
+            );
+        }
+        if self.ephemeral > 0 {
+            info!(
+                "Sockets and FIFOs with only one ID in the source range: {} (recreated when the container starts)",
+                self.ephemeral
+            );
+        }
+    }
+}
+
+/// Symlink whose owner and its target's are on either side of the remap.
+#[derive(Debug, PartialEq, Eq)]
+struct MixedSymlink {
+    path: PathBuf,
+    target: PathBuf,
+    /// UID and GID of the link itself
+    owner: (u32, u32),
+    target_owner: (u32, u32),
+}
+
+/// Symlinks checked by `--audit-symlinks`.
+#[derive(Default)]
+struct SymlinkAudit {
+    listed: Vec<MixedSymlink>,
+    audited: u64,
+    mixed: u64,
+    dangling: u64,
+}
+
+impl SymlinkAudit {
+    fn record(&mut self, link: MixedSymlink) {
+        self.mixed += 1;
+        if self.listed.len() < MAX_LISTED_ASYMMETRIC {
+            self.listed.push(link);
+        }
+    }
+
+    fn log(&self, owners: &OwnerView) {
+        for link in &self.listed {
+            warn!(
+                "{} is owned by {}, its target {} by {}",
+                link.path.display(),
+                owners.current(link.owner),
+                link.target.display(),
+                owners.current(link.target_owner)
+            );
+        }
+        if self.mixed > self.listed.len() as u64 {
+            warn!(
+                "... and {} more symlinks owned on the other side of the remap from their targets",
+                self.mixed - self.listed.len() as u64
+            );
+        }
+        info!(
+            "Symlink audit: {} symlinks, {} owned on the other side of the remap from their targets, {} dangling",
+            self.audited, self.mixed, self.dangling
+        );
+    }
+}
+
+/// Inode whose link count exceeds the number of its paths found inside the tree.
+#[derive(Debug, PartialEq, Eq)]
+struct ExternalLink {
+    path: PathBuf,
+    nlink: u64,
+    paths_seen: u64,
+}
+
+impl LinkRecord {
+    fn external(&self) -> Option<ExternalLink> {
+        (self.metadata.nlink() > self.paths_seen).then(|| ExternalLink {
+            path: self.first_path.clone(),
+            nlink: self.metadata.nlink(),
+            paths_seen: self.paths_seen,
+        })
+    }
+}
+
+/// Ownership change decided for an entry.
+struct Chown {
+    uid: Option<u32>,
+    gid: Option<u32>,
+    /// Permission bits to put back afterwards if the chown cleared setuid, setgid or
+    /// sticky bits among them, unless `--no-restore-mode`
+    mode: Option<u32>,
+    /// Extended attributes to put back afterwards, unless `--no-preserve-xattrs`
+    xattrs: Option<Snapshot>,
+    /// Helper to have the change made by, which puts back what it drops itself
+    helper: Option<Arc<helper::Client>>,
+    /// Root to reach entries below by descriptor, without following symlinks
+    anchor: Option<Arc<Anchor>>,
+}
+
+/// What had to be put back after ownership changes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct Restored {
+    /// Entries whose setuid, setgid or sticky bits were restored
+    modes: u64,
+    xattrs: u64,
+}
+
+impl std::ops::AddAssign for Restored {
+    fn add_assign(&mut self, other: Self) {
+        self.modes += other.modes;
+        self.xattrs += other.xattrs;
+    }
+}
+
+impl Chown {
+    /// Change the owner of `path` and put back the special mode bits and extended
+    /// attributes the change dropped.
+    fn apply(&self, path: &Path) -> RustUtilsResult<Restored> {
+        if let Some(helper) = &self.helper {
+            let request = Request {
+                path: std::path::absolute(path)?,
+                uid: self.uid,
+                gid: self.gid,
+                restore_mode: self.mode.is_some(),
+                preserve_xattrs: self.xattrs.is_some(),
+            };
+            let response = throttle::paced(|| helper.chown(&request))?;
+            return Ok(Restored {
+                modes: response.mode_restored as u64,
+                xattrs: response.xattrs_restored,
+            });
+        }
+        let entry = match self.anchor.as_ref().and_then(|anchor| anchor.entry(path)) {
+            Some(entry) => Some(entry.map_err(|e| chown_failed(path, e))?),
+            None => None,
+        };
+        match &entry {
+            Some(entry) => entry
+                .chown(self.uid, self.gid)
+                .map_err(|e| chown_failed(path, e))?,
+            None => chown(path, self.uid, self.gid)?,
+        }
+        let mut restored = Restored::default();
+        if let Some(mode) = self.mode {
+            let current = match &entry {
+                Some(entry) => entry.metadata()?.mode() & MODE_BITS,
+                None => get_file_metadata(path)?.mode() & MODE_BITS,
+            };
+            if current != mode {
+                match &entry {
+                    Some(entry) => entry.set_mode(mode),
+                    None => fs::set_permissions(path, Permissions::from_mode(mode)),
+                }
+                .map_err(|e| {
+                    RustUtilsError::RemapFailed(format!(
+                        "Failed to restore mode {:o} of {}: {}",
+                        mode,
+                        path.display(),
+                        e
+                    ))
+                })?;
+                debug!(
+                    "Restored mode {:o} on {} (was {:o})",
+                    mode,
+                    path.display(),
+                    current
+                );
+                restored.modes += 1;
+            }
+        }
+        if let Some(xattrs) = &self.xattrs {
+            let on_disk = entry.as_ref().map(|entry| entry.path());
+            let names = xattrs
+                .restore(on_disk.as_deref().unwrap_or(path))
+                .map_err(|e| {
+                    RustUtilsError::RemapFailed(format!(
+                        "Failed to restore xattrs of {}: {}",
+                        path.display(),
+                        e
+                    ))
+                })?;
+            for name in &names {
+                debug!("Restored {} on {}", name.to_string_lossy(), path.display());
+            }
+            restored.xattrs = names.len() as u64;
+        }
+        Ok(restored)
+    }
+}
+
+/// An entry handed to an [`ApplyPool`] worker, with the ownership change decided for it.
+struct Apply {
+    path: PathBuf,
+    device: Option<u64>,
+    kind: EntryType,
+    chown: Option<Chown>,
+    /// The owners the entry changes from and to, in a dry run too
+    change: Option<(Owner, Owner)>,
+}
+
+/// An entry once its ownership change, if any, has been applied.
+struct Applied {
+    path: PathBuf,
+    device: Option<u64>,
+    kind: EntryType,
+    /// The entry's metadata afterwards, or why the change failed
+    result: RustUtilsResult<Metadata>,
+    change: Option<(Owner, Owner)>,
+    /// What had to be put back after the change
+    restored: Restored,
+}
+
+/// Worker threads applying the ownership changes the walk decides on, for `--jobs`.
+///
+/// Entries of one inode always go to the same worker, so that the changes to hard links and
+/// the checks after them happen in walk order.
+struct ApplyPool {
+    queues: Vec<mpsc::Sender<Apply>>,
+    applied: mpsc::Receiver<Applied>,
+    workers: Vec<thread::JoinHandle<()>>,
+    pending: usize,
+}
+
+impl ApplyPool {
+    fn new(jobs: usize) -> Self {
+        let (done, applied) = mpsc::channel();
+        let mut queues = Vec::with_capacity(jobs);
+        let mut workers = Vec::with_capacity(jobs);
+        for _ in 0..jobs {
+            let (queue, work) = mpsc::channel::<Apply>();
+            let done = done.clone();
+            let limiter = throttle::current();
+            workers.push(thread::spawn(move || {
+                let _throttle = throttle::install(limiter);
+                for apply in work {
+                    let mut restored = Restored::default();
+                    let result = match &apply.chown {
+                        Some(chown) => chown.apply(&apply.path),
+                        None => Ok(Restored::default()),
+                    }
+                    .and_then(|applied| {
+                        restored = applied;
+                        get_file_metadata(&apply.path)
+                    });
+                    let applied = Applied {
+                        path: apply.path,
+                        device: apply.device,
+                        kind: apply.kind,
+                        result,
+                        change: apply.change,
+                        restored,
+                    };
+                    if done.send(applied).is_err() {
+                        break;
+                    }
+                }
+            }));
+            queues.push(queue);
+        }
+        Self {
+            queues,
+            applied,
+            workers,
+            pending: 0,
+        }
+    }
+
+    fn submit(&mut self, inode: u64, apply: Apply) {
+        let queue = &self.queues[(inode % self.queues.len() as u64) as usize];
+        queue
+            .send(apply)
+            .expect("workers run until the pool is dropped");
+        self.pending += 1;
+    }
+
+    /// Entries applied so far, without waiting.
+    fn finished(&mut self) -> Vec<Applied> {
+        let applied: Vec<_> = self.applied.try_iter().collect();
+        self.pending -= applied.len();
+        applied
+    }
+
+    /// Wait until every submitted entry is applied.
+    fn wait(&mut self) -> Vec<Applied> {
+        let applied: Vec<_> = self.applied.iter().take(self.pending).collect();
+        self.pending = 0;
+        applied
+    }
+}
+
+impl Drop for ApplyPool {
+    fn drop(&mut self) {
+        // Closing the queues lets the workers finish what they have and exit
+        self.queues.clear();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+pub struct RemapCommand {
+    args: RemapArgs,
+    seen_inodes: HashMap<(u64, u64), LinkRecord>, // (device, inode) -> first sighting
+    /// Paths of inodes met before at another path, and how many of them were skipped
+    hardlink_paths: u64,
+    hardlink_paths_skipped: u64,
+    /// Entries left alone with their owner in the target range already
+    already_mapped: u64,
+    /// Entries found by `--audit`
+    unexpected: UnexpectedOwners,
+    /// The tree's groups with `--known-groups-only`, and the entries counted by group
+    groups: Option<passwd::Database>,
+    known_groups: RefCell<KnownGroups>,
+    /// Entries that failed, by what would fix them
+    failures: RefCell<Failures>,
+    /// Changes to stat again under `--spot-check`
+    spot_check: RefCell<SpotCheck>,
+    /// Whether the caller holds the tree's lock for this run, as for the phases of a
+    /// two-phase remap
+    lock_held: bool,
+    /// Top-level directories of a whole-tree run journaled as finished by an earlier run,
+    /// skipped under `--resume`
+    completed: HashSet<PathBuf>,
+    registry: VisitorRegistry,
+    extra_visitors: Vec<Box<dyn TreeVisitor>>,
+    pipeline: Pipeline,
+    plugin: Option<WasmPlugin>,
+    probes: HashMap<u64, Probe>,          // device -> probe result
+    changes_by_device: HashMap<u64, u64>, // device -> entries whose owner would change
+    state_dir: Option<PathBuf>,
+    /// Ownership change left to the `--jobs` workers by the last `remap_file`
+    deferred_chown: Cell<Option<Chown>>,
+    /// Mode bits and extended attributes put back after ownership changes
+    restored: Cell<Restored>,
+    /// Ownership changes made, or planned in a dry run
+    plan: RefCell<PlanHash>,
+    /// Owners the planned changes start from under `--expect-clean`, recorded by the dry
+    /// run that checks the plan and checked off by the run applying it
+    planned: Rc<RefCell<PlannedOwners>>,
+    /// Whether the last `remap_file` gave the entry a new owner, or would in a dry run
+    owner_change: Cell<Option<(Owner, Owner)>>,
+    undo: Option<UndoJournal>,
+    /// Manifest of `--snapshot-manifest` the planning dry run writes the changes to
+    snapshot: Option<UndoJournal>,
+    /// IPC filesystems mounted below the base directory
+    ipc: IpcMounts,
+    /// Device of the base directory, the walk's boundary under `--one-file-system` when
+    /// the mount table cannot be read
+    device: Option<u64>,
+    /// `--exclude-regex` expressions, compiled once per run
+    exclude_regex: Option<RegexSet>,
+    /// Marker of finished directories under `--resume-by-xattr`
+    resume_marker: Option<ResumeMarker>,
+    /// Directory of `--atomic-dirs` the walk is in, remapped on a copy
+    atomic: Option<AtomicDir>,
+    /// Owners of `--fakeroot-db`, changed in place of those on disk
+    fakeroot: Option<RefCell<FakerootDb>>,
+    /// Connection to the `--chown-helper` changing owners for the run
+    helper: Option<Arc<helper::Client>>,
+    /// Inodes of the mounts the walk descends into, walked at one path only
+    aliases: Option<Arc<Aliases>>,
+    /// The tree's root held open, to change owners below it by descriptor
+    anchor: Option<Arc<Anchor>>,
+    /// How `--view` shows the UIDs and GIDs of the run's reports
+    owners: OwnerView,
+}
+
+impl RemapCommand {
+    pub fn new(args: RemapArgs) -> Self {
+        Self {
+            args,
+            seen_inodes: HashMap::new(),
+            hardlink_paths: 0,
+            hardlink_paths_skipped: 0,
+            already_mapped: 0,
+            unexpected: UnexpectedOwners::default(),
+            groups: None,
+            known_groups: RefCell::default(),
+            failures: RefCell::default(),
+            spot_check: RefCell::default(),
+            lock_held: false,
+            completed: HashSet::new(),
+            registry: VisitorRegistry::with_builtins(),
+            extra_visitors: Vec::new(),
+            pipeline: Pipeline::default(),
+            plugin: None,
+            probes: HashMap::new(),
+            changes_by_device: HashMap::new(),
+            state_dir: None,
+            deferred_chown: Cell::new(None),
+            restored: Cell::new(Restored::default()),
+            plan: RefCell::new(PlanHash::default()),
+            planned: Rc::default(),
+            owner_change: Cell::new(None),
+            undo: None,
+            snapshot: None,
+            ipc: IpcMounts::default(),
+            device: None,
+            exclude_regex: None,
+            resume_marker: None,
+            atomic: None,
+            fakeroot: None,
+            helper: None,
+            aliases: None,
+            anchor: None,
+            owners: OwnerView::default(),
+        }
+    }
+
+    /// Keep partition journals in `state_dir` instead of the default state directory.
+    pub fn with_state_dir(mut self, state_dir: Option<PathBuf>) -> Self {
+        self.state_dir = state_dir;
+        self
+    }
+
+    /// Resolve `--with` names against `registry` instead of the built-in visitors.
+    pub fn with_registry(mut self, registry: VisitorRegistry) -> Self {
+        self.registry = registry;
+        self
+    }
+
+    /// Run `visitor` on every entry of the walk in addition to any `--with` selections.
+    pub fn with_visitor(mut self, visitor: Box<dyn TreeVisitor>) -> Self {
+        self.extra_visitors.push(visitor);
+        self
+    }
+
+    pub fn execute(mut self) -> Result<RunReport> {
+        if let Some(user) = &self.args.subid_user {
+            let user = user.clone();
+            self.args.apply_subid_user()?;
+            info!("Target base {} delegated to {}", self.args.to_base, user);
+        }
+        if let Some(path) = &self.args.mapping_file {
+            info!("Reading mappings from {}", path.display());
+        }
+        self.args.load_overrides()?;
+        if self.args.all_hardlinks {
+            self.args.hardlinks = HardLinkPolicy::All;
+        }
+        self.validate_args()?;
+        self.owners = self.args.owner_view()?;
+        if self.args.audit {
+            info!("Auditing owners; nothing is changed");
+            self.args.dry_run = true;
+        }
+        if !self.args.exclude_regex.is_empty() {
+            self.exclude_regex = Some(RegexSet::new(&self.args.exclude_regex).map_err(|e| {
+                RustUtilsError::InvalidArguments(format!("invalid --exclude-regex: {e}"))
+            })?);
+        }
+        if self.args.resume_by_xattr {
+            self.resume_marker = Some(ResumeMarker::new(&self.resume_key()));
+        }
+
+        if !self.args.base_directory.exists() {
+            return Err(RustUtilsError::DirectoryNotFound(
+                self.args.base_directory.display().to_string(),
+            )
+            .into());
+        }
+
+        if !self.args.base_directory.is_dir() {
+            return Err(RustUtilsError::DirectoryNotFound(format!(
+                "{} is not a directory",
+                self.args.base_directory.display()
+            ))
+            .into());
+        }
+
+        if self.args.known_groups_only {
+            let groups = passwd::Database::read(&self.args.base_directory, IdKind::Gid)?;
+            info!(
+                "Remapping only GIDs of groups in {}",
+                groups.path().display()
+            );
+            self.groups = Some(groups);
+        }
+
+        // Dry runs change nothing, so they neither take the lock nor wait for it
+        let _locks = if self.args.dry_run || self.lock_held {
+            Vec::new()
+        } else {
+            self.lock_tree()?
+        };
+        // The limit is shared by every thread of the run, the workers applying changes too
+        let _throttle = throttle::install(self.args.throttle.map(|limit| {
+            info!("Throttling the run to {}", limit);
+            Limiter::new(limit)
+        }));
+
+        if let (Some(expected), false) = (&self.args.plan_hash, self.args.dry_run) {
+            self.check_plan(expected)?;
+        }
+        if let (Some(path), false) = (&self.args.snapshot_manifest, self.args.dry_run) {
+            self.write_snapshot(path)?;
+        }
+
+        // A dry run changes nothing, so one pass shows where every entry ends up
+        if let Some(temporary) = self.args.temporary_base()? {
+            if !self.args.dry_run {
+                return self.execute_two_phase(temporary);
+            }
+            info!(
+                "Ranges overlap; would remap in two phases through {}",
+                id_span(temporary, self.args.range_size)
+            );
+        }
+
+        let preview = if self.args.preview_overlay {
+            let overlay = PreviewOverlay::mount(&self.args.base_directory, &env::temp_dir())?;
+            info!(
+                "Remapping an overlay of {} at {}",
+                self.args.base_directory.display(),
+                overlay.merged().display()
+            );
+            self.args.base_directory = overlay.merged().to_path_buf();
+            Some(overlay)
+        } else {
+            None
+        };
+
+        if let Some(path) = &self.args.plugin {
+            info!("Loading plugin: {}", path.display());
+            self.plugin = Some(WasmPlugin::load(path)?);
+        }
+
+        self.pipeline = Pipeline::from_names(&self.registry, &self.args.with)?;
+        for visitor in self.extra_visitors.drain(..) {
+            self.pipeline.push(visitor);
+        }
+
+        let mut mountpoints = self
+            .args
+            .exclude_mountpoint
+            .iter()
+            .map(|path| resolve_subdirectory(&self.args.base_directory, path))
+            .collect::<RustUtilsResult<Vec<_>>>()?;
+
+        let mounts = mounts::read_mounts().unwrap_or_else(|e| {
+            warn!("Cannot read the mount table: {}", e);
+            Vec::new()
+        });
+        let canonical_base = self.args.base_directory.canonicalize()?;
+        let shadowed = self.scan_mounts(&canonical_base, &mounts, &mut mountpoints)?;
+
+        if self.args.dry_run {
+            log_message!(INFO, "dry-run");
+        }
+        match (&self.args.fakeroot_db, fakeroot::session()) {
+            (Some(path), _) => {
+                info!("Remapping the owners recorded in {}", path.display());
+                self.fakeroot = Some(RefCell::new(FakerootDb::open(path)?));
+            }
+            (None, Some(Session::Fakeroot)) => {
+                info!("Running under fakeroot; ownership changes are recorded by faked, not made on disk")
+            }
+            (None, Some(Session::Fakechroot)) => {
+                info!("Running under fakechroot; paths are translated, ownership changes are real")
+            }
+            (None, None) => {}
+        }
+        if let (Some(socket), false) = (&self.args.chown_helper, self.args.dry_run) {
+            info!(
+                "Changing owners through the chown helper at {}",
+                socket.display()
+            );
+            self.helper = Some(Arc::new(helper::Client::connect(socket)?));
+        }
+        if self.args.probe && fakeroot::session() == Some(Session::Fakeroot) {
+            info!("Probe: running under fakeroot, where every ownership change succeeds");
+        } else if self.args.probe {
+            match probe::has_cap_chown() {
+                Ok(true) => info!("Probe: CAP_CHOWN is effective"),
+                Ok(false) => warn!(
+                    "Probe: CAP_CHOWN is not effective; only changes to groups of the caller can succeed"
+                ),
+                Err(e) => warn!("Probe: cannot read capabilities: {}", e),
+            }
+        }
+
+        let mut frozen = match &self.args.freeze_cgroup {
+            Some(dir) if self.args.dry_run => {
+                info!("Would freeze cgroup {}", dir.display());
+                None
+            }
+            Some(dir) => Some(FrozenCgroup::freeze(dir)?),
+            None => None,
+        };
+        self.check_in_use()?;
+
+        // Only now, as the checks above are of the tree as others see it
+        let beneath = self.mount_beneath(&canonical_base, &mut mountpoints)?;
+
+        for mountpoint in &mountpoints {
+            info!("Excluding mount point: {}", mountpoint.display());
+        }
+        self.open_anchor();
+
+        log_message!(INFO, "remap-starting");
+        log_message!(
+            INFO,
+            "remap-base-directory",
+            path = self.args.base_directory.display().to_string()
+        );
+        log_message!(
+            INFO,
+            "remap-from-range",
+            first = self.args.from_base,
+            last = self.args.from_base + self.args.range_size - 1
+        );
+        log_message!(
+            INFO,
+            "remap-to-range",
+            first = self.args.to_base,
+            last = self.args.to_base + self.args.range_size - 1
+        );
+        for kind in [IdKind::Uid, IdKind::Gid] {
+            for mapping in self.args.overrides(kind) {
+                info!(
+                    "Mapping {} {} to {}",
+                    kind,
+                    id_span(mapping.from, mapping.count),
+                    id_span(mapping.to, mapping.count)
+                );
+            }
+        }
+
+        let mut totals = RunTotals {
+            shadowed_mounts: shadowed,
+            ..Default::default()
+        };
+        let mut progress = Progress::open(&self.args.progress, "remap")?;
+
+        // Project IDs, neither UIDs nor GIDs, map through the range alone
+        let range = IdMap::new(vec![IdMapping {
+            from: self.args.from_base,
+            to: self.args.to_base,
+            count: self.args.range_size,
+        }])?;
+        // Nested archives get the same mapping, limited by --uid-only/--gid-only
+        let uid_map = self.args.id_map(IdKind::Uid)?;
+        let gid_map = self.args.id_map(IdKind::Gid)?;
+        let rules = RemapRules {
+            uid_map: &uid_map,
+            gid_map: &gid_map,
+            nested: self.args.nested,
+            depth: 0,
+        };
+
+        let mut report = RunReport::new("remap");
+
+        let units = self.units()?;
+        let (mut journal, pending_units) = self.pending_units(&units)?;
+        let mut coordinator = match &self.args.coordinate {
+            Some(dir) if !self.args.dry_run => {
+                let key = partition::job_key(&[&self.mapping_key()]);
+                Some(Coordinator::open(dir, &key)?)
+            }
+            _ => None,
+        };
+        let mut units_resumed = (units.len() - pending_units.len()) as u64;
+
+        // Coordinated jobs claim one unit at a time so that all of them share the work;
+        // otherwise all units are walked together
+        let batches: Vec<Vec<&Unit>> = if coordinator.is_some() {
+            pending_units.iter().map(|unit| vec![*unit]).collect()
+        } else {
+            vec![pending_units]
+        };
+        let mut heartbeat = Heartbeat::new(self.args.progress_interval);
+        let mut scan_time = Duration::ZERO;
+        let mut apply_time = Duration::ZERO;
+        let mut units_elsewhere = 0;
+        let mut pool = (self.args.jobs.get() > 1).then(|| ApplyPool::new(self.args.jobs.get()));
+        match &self.args.undo_journal {
+            Some(path) if !self.args.dry_run => {
+                self.undo = Some(UndoJournal::create(path)?);
+                report.artifact("undo-journal", path);
+            }
+            _ => {}
+        }
+        if let (Some(path), false) = (&self.args.snapshot_manifest, self.args.dry_run) {
+            report.artifact("snapshot-manifest", path);
+        }
+        // Under --resume-by-xattr, directories the walk is inside of until they are finished
+        let marking = self.resume_marker.clone().filter(|_| !self.args.dry_run);
+        let mut open_dirs = OpenDirs::default();
+        let mut interrupted = None;
+        for batch in batches {
+            if let Some(coordinator) = &mut coordinator {
+                let name = batch[0].name.as_deref().ok_or_else(|| {
+                    RustUtilsError::InvalidArguments(
+                        "--coordinate needs the tree split into named units".to_string(),
+                    )
+                })?;
+                match coordinator.claim(name)? {
+                    Claim::Claimed => debug!("Claimed unit {}", name.display()),
+                    Claim::Done => {
+                        debug!("Skipping completed unit {}", name.display());
+                        units_resumed += 1;
+                        continue;
+                    }
+                    Claim::Taken(owner) => {
+                        debug!("Skipping unit {} claimed by {}", name.display(), owner);
+                        units_elsewhere += 1;
+                        continue;
+                    }
+                }
+            }
+            let scan_started = Instant::now();
+
+            // Checks of the whole batch walk it once more before anything changes, since
+            // entries are not kept
+            if self.args.safety_scan {
+                self.safety_scan(self.walk_paths(&batch, &mountpoints))?;
+            }
+
+            if self.args.assert_unmapped {
+                self.check_unmapped(self.walk_paths(&batch, &mountpoints))?;
+            }
+
+            if self.args.fail_on_external_links {
+                let external = find_external_links(self.walk_paths(&batch, &mountpoints))?;
+                if !external.is_empty() {
+                    report_external_links(&external);
+                    return Err(RustUtilsError::UnexpectedHardLink(format!(
+                        "{} inode(s) have links outside {}",
+                        external.len(),
+                        self.args.base_directory.display()
+                    ))
+                    .into());
+                }
+            }
+
+            scan_time += scan_started.elapsed();
+            let apply_started = Instant::now();
+
+            let resumed_before = self.resume_marker.as_ref().map_or(0, ResumeMarker::resumed);
+            for unit in &batch {
+                let errors_before = report.errors.len();
+                // Top-level directory of a whole-tree walk being journaled
+                let mut subtree: Option<(PathBuf, usize)> = None;
+                for entry in self.walk(unit, &mountpoints) {
+                    interrupted = signals::received();
+                    if interrupted.is_some() {
+                        break;
+                    }
+                    self.check_errors(&report)?;
+                    let entry = match entry {
+                        Ok(entry) => entry,
+                        Err(e) => {
+                            let path = e.path().map(Path::to_path_buf);
+                            warn!(
+                                "Cannot walk {}: {}",
+                                path.as_deref().unwrap_or(&unit.root).display(),
+                                e
+                            );
+                            report.error(path.as_deref(), format!("walk: {e}"));
+                            let errno = e
+                                .io_error()
+                                .and_then(io::Error::raw_os_error)
+                                .map(Errno::from_i32);
+                            self.failed(errno, path.as_deref(), &e);
+                            continue;
+                        }
+                    };
+                    let path = entry.path();
+                    if self
+                        .atomic
+                        .as_ref()
+                        .is_some_and(|atomic| !path.starts_with(atomic.original()))
+                    {
+                        totals.skipped += self.drain_pool(
+                            &mut pool,
+                            &mut totals.counters,
+                            &mut totals.filesystems,
+                            &mut totals.entry_types,
+                            &mut report,
+                            &mut progress,
+                        );
+                        totals.atomic_dirs += self.swap_atomic()?;
+                    }
+                    if let (Some(journal), None) = (&mut journal, &unit.name) {
+                        if path.parent() == Some(unit.root.as_path()) {
+                            if let Some((name, errors)) = subtree.take() {
+                                totals.skipped += self.drain_pool(
+                                    &mut pool,
+                                    &mut totals.counters,
+                                    &mut totals.filesystems,
+                                    &mut totals.entry_types,
+                                    &mut report,
+                                    &mut progress,
+                                );
+                                if report.errors.len() == errors {
+                                    journal.complete(&name)?;
+                                }
+                            }
+                            if entry.file_type().is_dir() {
+                                subtree = Some((entry.file_name().into(), report.errors.len()));
+                            }
+                        }
+                    }
+                    if self.atomic.is_none()
+                        && !self.args.dry_run
+                        && entry.file_type().is_dir()
+                        && self.is_atomic(path)
+                    {
+                        info!("Remapping {} on a copy to swap in", path.display());
+                        self.atomic = Some(AtomicDir::create(path, &mountpoints)?);
+                    }
+                    if let Some(marker) = &marking {
+                        let left = open_dirs.leave_for(path);
+                        if !left.is_empty() {
+                            // Everything below them is applied before they are marked
+                            totals.skipped += self.drain_pool(
+                                &mut pool,
+                                &mut totals.counters,
+                                &mut totals.filesystems,
+                                &mut totals.entry_types,
+                                &mut report,
+                                &mut progress,
+                            );
+                            totals.dirs_marked +=
+                                self.mark_finished(marker, left, report.errors.len())?;
+                        }
+                        if entry.file_type().is_dir() {
+                            open_dirs.open(path, report.errors.len());
+                        }
+                    }
+                    let device = entry.metadata().ok().map(|metadata| metadata.dev());
+                    let ipc = entry
+                        .metadata()
+                        .ok()
+                        .and_then(|metadata| self.ipc.classify(path, &metadata));
+                    if let Some(kind) = ipc {
+                        debug!("{}: {}", path.display(), kind);
+                        totals.ipc_objects += 1;
+                    }
+
+                    let kind = EntryType::of(entry.file_type());
+
+                    totals.counters.entries += 1;
+                    if let Some(device) = device {
+                        totals.filesystems.entry(device, path);
+                    }
+                    totals.entry_types.entry(kind);
+                    if let Some(found) = entry
+                        .metadata()
+                        .ok()
+                        .and_then(|metadata| self.asymmetry(path, &metadata))
+                    {
+                        totals.asymmetric.record(found, kind);
+                    }
+
+                    // Tasks observe each entry as found, before any ownership change
+                    if !self.pipeline.is_empty() {
+                        let relative = path.strip_prefix(&self.args.base_directory).unwrap_or(path);
+                        let dry_run = self.args.dry_run;
+                        if let Err(e) = get_file_metadata(path).and_then(|metadata| {
+                            self.pipeline.visit(path, relative, &metadata, dry_run)
+                        }) {
+                            warn!("Task failed on {}: {}", path.display(), e);
+                            report.error(Some(path), format!("task failed: {e}"));
+                        }
+                        for event in self.pipeline.drain_events() {
+                            totals.visitor_events += 1;
+                            log_visit_event(&event);
+                        }
+                    }
+
+                    // Rewritten archives are renamed into place before their own owner changes;
+                    // IPC objects are never opened
+                    if self.args.nested != NestedPolicy::Skip && ipc.is_none() {
+                        match self.process_nested(&self.on_disk(path), &rules) {
+                            Ok(true) => totals.nested_archives += 1,
+                            Ok(false) => {}
+                            Err(e) => {
+                                warn!("Failed to remap nested archive {}: {}", path.display(), e);
+                                report.error(Some(path), format!("nested archive: {e}"));
+                                if let Some(device) = device {
+                                    totals.filesystems.failed(device);
+                                }
+                            }
+                        }
+                    }
+
+                    if self.args.project_ids != ProjectIdMode::Ignore && ipc.is_none() {
+                        if let Err(e) = entry.metadata().map_err(Into::into).and_then(|metadata| {
+                            self.process_project_id(
+                                path,
+                                &metadata,
+                                &range,
+                                &mut totals.project_ids,
+                            )
+                        }) {
+                            warn!("Failed to remap project ID of {}: {}", path.display(), e);
+                            report.error(Some(path), format!("project ID: {e}"));
+                            if let Some(device) = device {
+                                totals.filesystems.failed(device);
+                            }
+                        }
+                    }
+
+                    let processed = self.process_file(path);
+                    let chown = self.deferred_chown.take();
+                    let change = self.owner_change.take();
+                    if let Err(e) = processed {
+                        if let RustUtilsError::UnexpectedHardLink(_)
+                        | RustUtilsError::Probe(_)
+                        | RustUtilsError::UnexpectedChange(_) = e
+                        {
+                            return Err(e.into());
+                        }
+                        warn!("Failed to process {}: {}", path.display(), e);
+                        report.error(Some(path), &e);
+                        self.failed(e.errno(), Some(path), &e);
+                        if let Some(device) = device {
+                            totals.filesystems.failed(device);
+                        }
+                        totals.entry_types.failed(kind);
+                        continue;
+                    }
+
+                    let applied = match &mut pool {
+                        Some(pool) => {
+                            let apply = Apply {
+                                path: self.on_disk(path).into_owned(),
+                                device,
+                                kind,
+                                chown,
+                                change,
+                            };
+                            pool.submit(entry.ino(), apply);
+                            pool.finished()
+                        }
+                        None => vec![Applied {
+                            path: path.to_path_buf(),
+                            device,
+                            kind,
+                            result: Ok(get_file_metadata(&self.on_disk(path))?),
+                            change,
+                            restored: Restored::default(),
+                        }],
+                    };
+                    for applied in applied {
+                        if self.record_applied(
+                            applied,
+                            &mut totals.counters,
+                            &mut totals.filesystems,
+                            &mut totals.entry_types,
+                            &mut report,
+                            &mut progress,
+                        ) {
+                            totals.skipped += 1;
+                        }
+                    }
+
+                    if self.args.verbose && heartbeat.is_due(totals.counters.entries) {
+                        log_message!(
+                            INFO,
+                            "remap-heartbeat",
+                            processed = totals.counters.entries,
+                            remapped = totals.counters.changed
+                        );
+                    }
+                }
+                totals.skipped += self.drain_pool(
+                    &mut pool,
+                    &mut totals.counters,
+                    &mut totals.filesystems,
+                    &mut totals.entry_types,
+                    &mut report,
+                    &mut progress,
+                );
+                // What was left unfinished is neither swapped in nor marked as done
+                if interrupted.is_some() {
+                    if let Some(atomic) = self.atomic.take() {
+                        info!(
+                            "Discarding the unfinished copy of {}",
+                            atomic.original().display()
+                        );
+                    }
+                    break;
+                }
+                totals.atomic_dirs += self.swap_atomic()?;
+                self.check_errors(&report)?;
+                if let Some(marker) = &marking {
+                    totals.dirs_marked +=
+                        self.mark_finished(marker, open_dirs.finish(), report.errors.len())?;
+                }
+                if let (Some(journal), Some((name, errors))) = (&mut journal, subtree) {
+                    if report.errors.len() == errors {
+                        journal.complete(&name)?;
+                    }
+                }
+                let failed = report.errors.len() > errors_before;
+                self.complete_unit(&mut journal, &mut coordinator, unit, failed)?;
+            }
+            totals.dirs_resumed +=
+                self.resume_marker.as_ref().map_or(0, ResumeMarker::resumed) - resumed_before;
+            apply_time += apply_started.elapsed();
+            if interrupted.is_some() {
+                break;
+            }
+        }
+        // The walk is complete, so markers have served their purpose
+        if let (Some(marker), None) = (&marking, interrupted) {
+            totals.markers_removed = self.clear_markers(marker, &units, &mountpoints)?;
+            info!(
+                "Resume markers: {} directories marked, {} totals.skipped as finished, {} markers removed",
+                totals.dirs_marked, totals.dirs_resumed, totals.markers_removed
+            );
+        }
+        report
+            .duration("scan", scan_time)
+            .duration("apply", apply_time);
+        if self.is_split() {
+            info!(
+                "Units: {} selected, {} already completed, {} claimed by other jobs",
+                units.len(),
+                units_resumed,
+                units_elsewhere
+            );
+            report
+                .count("units", units.len() as u64)
+                .count("units_resumed", units_resumed)
+                .count("units_claimed_elsewhere", units_elsewhere);
+        } else if self.args.resume {
+            report.count("subtrees_resumed", self.completed.len() as u64);
+        }
+
+        self.log_findings(&mut totals, &mut report);
+        // Still frozen, so nothing in the cgroup writes while the tree is verified
+        if self.args.verify && !self.args.dry_run && interrupted.is_none() {
+            self.verify(&units, &mountpoints, &mut report)?;
+        }
+        if self.args.spot_check.is_some() && !self.args.dry_run && interrupted.is_none() {
+            self.spot_check(&mounts, &mut report);
+        }
+        if let Some(frozen) = &mut frozen {
+            report.duration("frozen", frozen.frozen_for());
+            frozen.thaw()?;
+        }
+        if self.args.probe {
+            let failures = self.report_probes(&mut report);
+            report
+                .count("probed_filesystems", self.probes.len() as u64)
+                .count("predicted_failures", failures);
+        }
+
+        if self.args.audit_symlinks && interrupted.is_none() {
+            let audit = self.audit_symlinks(&units, &mountpoints)?;
+            audit.log(&self.owners);
+            report
+                .count("symlinks_audited", audit.audited)
+                .count("symlinks_mixed", audit.mixed)
+                .count("symlinks_dangling", audit.dangling);
+        }
+
+        self.summarize(
+            &totals,
+            &mut progress,
+            &mut report,
+            &mounts,
+            &canonical_base,
+            interrupted.is_some(),
+        )?;
+        self.finish_journal(journal, interrupted.is_some(), &report)?;
+        if let Some(undo) = self.undo.take() {
+            undo.finish()?;
+        }
+        if let Some(snapshot) = self.snapshot.take() {
+            snapshot.finish()?;
+        }
+        if let Some(signal) = interrupted {
+            warn!(
+                "Stopped by {} after {} entries, {} of them changed; run the remap again to finish",
+                signal, totals.counters.entries, totals.counters.changed
+            );
+            report.success = false;
+            report.interrupted = Some(signal.to_string());
+        }
+        if let Some(overlay) = preview {
+            overlay.wait_for_inspection()?;
+        }
+        drop(beneath);
+        Ok(report)
+    }
+
+    /// Find the mounts below the base directory: those left alone are added to
+    /// `mountpoints`, and those remapped with the tree are watched for paths walked twice.
+    /// Returns how many mounts hide entries, if that was checked.
+    fn scan_mounts(
+        &mut self,
+        canonical_base: &Path,
+        mounts: &[mounts::Mount],
+        mountpoints: &mut Vec<PathBuf>,
+    ) -> RustUtilsResult<Option<u64>> {
+        for mountpoint in ipc::host_ipc_mounts(canonical_base, mounts) {
+            warn!(
+                "{} is this system's own IPC filesystem; leaving the objects of running processes alone",
+                mountpoint.display()
+            );
+            mountpoints.push(resolve_subdirectory(
+                &self.args.base_directory,
+                &mountpoint,
+            )?);
+        }
+        self.ipc = IpcMounts::below(canonical_base, mounts);
+        let below = mounts::mounts_below(canonical_base, mounts);
+        let shadowed = self.find_shadowed(canonical_base, &below);
+        let mut descended = Vec::new();
+        for mount in below {
+            let Ok(relative) = mount.mountpoint.strip_prefix(canonical_base) else {
+                continue;
+            };
+            let mountpoint = self.args.base_directory.join(relative);
+            if mountpoints.contains(&mountpoint) {
+                continue;
+            }
+            if self.args.beneath_mounts {
+                info!(
+                    "Leaving {} ({} from {}) alone and remapping what it hides",
+                    mountpoint.display(),
+                    mount.fstype,
+                    mount.source
+                );
+            } else if self.args.one_file_system {
+                info!(
+                    "Not descending into {} ({} from {})",
+                    mountpoint.display(),
+                    mount.fstype,
+                    mount.source
+                );
+                mountpoints.push(mountpoint);
+            } else {
+                warn!(
+                    "{} is a separate {} mount from {} and is remapped with the tree; use --one-file-system to leave it alone",
+                    mountpoint.display(),
+                    mount.fstype,
+                    mount.source
+                );
+                descended.push(mountpoint);
+            }
+        }
+        if !descended.is_empty() {
+            self.aliases = Some(Arc::new(Aliases::watch(&descended)));
+        }
+        if self.args.one_file_system && mounts.is_empty() {
+            self.device = Some(fs::metadata(&self.args.base_directory)?.dev());
+        }
+        Ok(shadowed)
+    }
+
+    /// Under `--beneath-mounts`, mount the tree without its mounts and walk that instead,
+    /// moving `mountpoints` along.
+    fn mount_beneath(
+        &mut self,
+        canonical_base: &Path,
+        mountpoints: &mut Vec<PathBuf>,
+    ) -> Result<Option<BeneathMounts>> {
+        if !self.args.beneath_mounts {
+            return Ok(None);
+        }
+        let beneath = BeneathMounts::mount(canonical_base, &env::temp_dir())?;
+        info!(
+            "Remapping {} without its mounts at {}",
+            self.args.base_directory.display(),
+            beneath.path().display()
+        );
+        // Mounts are out of view; paths given to --exclude-mountpoint are still left out
+        mountpoints.truncate(self.args.exclude_mountpoint.len());
+        mountpoints.retain_mut(|mountpoint| {
+            match mountpoint.strip_prefix(&self.args.base_directory) {
+                Ok(relative) => {
+                    *mountpoint = beneath.path().join(relative);
+                    true
+                }
+                Err(_) => {
+                    warn!(
+                        "{} is not below {}; not excluded beneath its mounts",
+                        mountpoint.display(),
+                        self.args.base_directory.display()
+                    );
+                    false
+                }
+            }
+        });
+        self.ipc = IpcMounts::default();
+        self.args.base_directory = beneath.path().to_path_buf();
+        Ok(Some(beneath))
+    }
+
+    /// Hold the base directory open to change owners below it by descriptor.
+    fn open_anchor(&mut self) {
+        // Helpers and faking sessions change owners by path themselves
+        if !self.args.dry_run
+            && self.fakeroot.is_none()
+            && self.helper.is_none()
+            && fakeroot::session().is_none()
+        {
+            match Anchor::open(&self.args.base_directory) {
+                Ok(anchor) => self.anchor = Some(Arc::new(anchor)),
+                Err(e) => warn!(
+                    "Cannot change owners below {} by descriptor, changing them by path: {}",
+                    self.args.base_directory.display(),
+                    e
+                ),
+            }
+        }
+    }
+
+    /// Open the journal of completed parts of the tree, returning it with the units it
+    /// does not record as done.
+    fn pending_units<'a>(
+        &mut self,
+        units: &'a [Unit],
+    ) -> RustUtilsResult<(Option<Journal>, Vec<&'a Unit>)> {
+        let journal = self.open_journal()?;
+        if let (Some(journal), false) = (&journal, self.is_split()) {
+            let base = &self.args.base_directory;
+            self.completed = journal.done().map(|name| base.join(name)).collect();
+            if !self.completed.is_empty() {
+                info!(
+                    "Skipping {} top-level director(ies) completed by an earlier run",
+                    self.completed.len()
+                );
+            }
+        }
+        let mut pending_units = Vec::new();
+        for unit in units {
+            match (&unit.name, &journal) {
+                (Some(name), Some(journal)) if journal.is_done(name) => {
+                    debug!("Skipping completed unit {}", name.display());
+                }
+                _ => pending_units.push(unit),
+            }
+        }
+        Ok((journal, pending_units))
+    }
+
+    /// Finish the journal of a run, unless it is needed to resume the run.
+    fn finish_journal(
+        &self,
+        journal: Option<Journal>,
+        interrupted: bool,
+        report: &RunReport,
+    ) -> RustUtilsResult<()> {
+        // An interrupted job, or one with failed entries, keeps its journal for --resume
+        match journal {
+            Some(journal) if !interrupted && report.errors.is_empty() => {
+                journal.finish()?;
+            }
+            Some(journal) if !interrupted => info!(
+                "{} part(s) of the tree completed are journaled; once the failed entries are \
+                 fixed, run again with --resume to skip them",
+                journal.done_count()
+            ),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Log what the walk found beyond the changes it made.
+    fn log_findings(&mut self, totals: &mut RunTotals, report: &mut RunReport) {
+        let mut external: Vec<_> = self
+            .seen_inodes
+            .values()
+            .filter_map(LinkRecord::external)
+            .collect();
+        external.sort_by(|a, b| a.path.cmp(&b.path));
+        report_external_links(&external);
+        totals.external_links = external.len() as u64;
+        totals.asymmetric.log(&self.owners);
+        let failures = self.failures.get_mut();
+        failures.log();
+        failures.summarize(report);
+        if let Some(limiter) = throttle::current() {
+            report.duration("throttled", limiter.waited());
+        }
+        if let Some(aliases) = &self.aliases {
+            let found = aliases.found();
+            for alias in found.iter().take(MAX_LISTED_ASYMMETRIC) {
+                warn!(
+                    "{} is {} again, through a bind mount; walked once",
+                    alias.path.display(),
+                    alias.original.display()
+                );
+            }
+            if found.len() > MAX_LISTED_ASYMMETRIC {
+                warn!(
+                    "... and {} more paths to entries walked elsewhere",
+                    found.len() - MAX_LISTED_ASYMMETRIC
+                );
+            }
+            report.count("aliases", found.len() as u64);
+        }
+        if self.groups.is_some() {
+            let known_groups = self.known_groups.get_mut();
+            known_groups.log(&self.owners);
+            known_groups.summarize(report);
+        }
+        if self.args.audit {
+            self.unexpected.log(&self.owners);
+            report
+                .count("unexpected_entries", self.unexpected.entries())
+                .count("unexpected_owners", self.unexpected.paths.len() as u64);
+        }
+    }
+
+    /// Log the totals of a run and add them to its report.
+    fn summarize(
+        &mut self,
+        totals: &RunTotals,
+        progress: &mut Progress,
+        report: &mut RunReport,
+        mounts: &[mounts::Mount],
+        canonical_base: &Path,
+        interrupted: bool,
+    ) -> Result<()> {
+        for (task, line) in self.pipeline.finish() {
+            info!("[{}] {}", task, line);
+        }
+        if totals.visitor_events > 0 {
+            info!("Visitor events: {}", totals.visitor_events);
+        }
+
+        log_message!(INFO, "remap-completed");
+        log_message!(
+            INFO,
+            "remap-files-processed",
+            count = totals.counters.entries
+        );
+        log_message!(
+            INFO,
+            "remap-files-remapped",
+            count = totals.counters.changed
+        );
+        if totals.nested_archives > 0 {
+            log_message!(
+                INFO,
+                "remap-nested-archives",
+                count = totals.nested_archives
+            );
+        }
+        let restored = self.restored.get();
+        if restored.modes > 0 {
+            info!(
+                "Setuid, setgid and sticky bits restored after ownership changes: {}",
+                restored.modes
+            );
+        }
+        if restored.xattrs > 0 {
+            info!(
+                "Extended attributes restored after ownership changes: {}",
+                restored.xattrs
+            );
+        }
+        if self.already_mapped > 0 {
+            info!(
+                "Entries already owned in the target range, left alone: {}",
+                self.already_mapped
+            );
+        }
+        if self.hardlink_paths_skipped > 0 {
+            info!(
+                "Further paths of hard-linked inodes totals.skipped: {} (changed through their first \
+                 path; --all-hardlinks processes every path)",
+                self.hardlink_paths_skipped
+            );
+        }
+        report.filesystems = totals.filesystems.summarize(mounts);
+        log_filesystems(&report.filesystems);
+        report.entry_types = totals.entry_types.summarize();
+        log_entry_types(&report.entry_types);
+        let plan_hash = self.plan.borrow().finish(canonical_base);
+        info!(
+            "Plan hash: {} ({} changes)",
+            plan_hash,
+            self.plan.borrow().changes()
+        );
+        match &self.args.plan_hash {
+            Some(expected) if self.args.dry_run && !interrupted && *expected != plan_hash => {
+                return Err(RustUtilsError::PlanMismatch(format!(
+                    "the changes hash to {plan_hash}, not the approved {expected}"
+                ))
+                .into());
+            }
+            _ => {}
+        }
+        if self.args.expect_clean && !self.args.dry_run && !interrupted {
+            self.planned.borrow().finish()?;
+        }
+        report.plan_hash = Some(plan_hash);
+        totals.project_ids.log(self.args.project_ids);
+        progress.finish(totals.counters);
+
+        report
+            .count("entries", totals.counters.entries)
+            .count("remapped", totals.counters.changed)
+            .count("skipped", totals.skipped)
+            .count("already_mapped", self.already_mapped)
+            .count("bytes", totals.counters.bytes)
+            .count("external_links", totals.external_links)
+            .count("hardlink_paths", self.hardlink_paths)
+            .count("hardlink_paths_skipped", self.hardlink_paths_skipped)
+            .count("nested_archives", totals.nested_archives)
+            .count("ipc_objects", totals.ipc_objects)
+            .count("modes_restored", restored.modes)
+            .count("xattrs_restored", restored.xattrs)
+            .count("visitor_events", totals.visitor_events)
+            .count("asymmetric_uid", totals.asymmetric.uid)
+            .count("asymmetric_gid", totals.asymmetric.gid)
+            .count("asymmetric_ephemeral", totals.asymmetric.ephemeral);
+        if !self.args.atomic_dirs.is_empty() {
+            report.count("atomic_dirs", totals.atomic_dirs);
+        }
+        if let Some(shadowed) = totals.shadowed_mounts {
+            report.count("shadowed_mounts", shadowed);
+        }
+        if let Some(db) = &self.fakeroot {
+            let db = db.borrow();
+            if !self.args.dry_run {
+                db.save()?;
+            }
+            report.count("fakeroot_owners", db.changed());
+        }
+        if self.args.resume_by_xattr {
+            report
+                .count("directories_marked", totals.dirs_marked)
+                .count("directories_resumed", totals.dirs_resumed)
+                .count("markers_removed", totals.markers_removed);
+        }
+        if self.args.project_ids != ProjectIdMode::Ignore {
+            report
+                .count("project_ids", totals.project_ids.assigned)
+                .count("project_ids_in_range", totals.project_ids.in_range)
+                .count("project_ids_remapped", totals.project_ids.remapped);
+        }
+        let (from, to, size) = (self.args.from_base, self.args.to_base, self.args.range_size);
+        for (kind, unchanged, view) in [
+            (IdKind::Uid, self.args.gid_only, &self.owners.uid),
+            (IdKind::Gid, self.args.uid_only, &self.owners.gid),
+        ] {
+            if unchanged {
+                continue;
+            }
+            report.range(kind, view.current(from), view.mapped(to), size);
+            for mapping in self.args.overrides(kind) {
+                report.range(
+                    kind,
+                    view.current(mapping.from),
+                    view.mapped(mapping.to),
+                    mapping.count,
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// The arguments of a quiet dry run of this run: only what decides the changes, and
+    /// nothing the dry run would write or hold.
+    fn planning_args(&self) -> RemapArgs {
+        RemapArgs {
+            dry_run: true,
+            verbose: false,
+            subid_user: None,
+            plan_hash: None,
+            undo_journal: None,
+            snapshot_manifest: None,
+            freeze_cgroup: None,
+            coordinate: None,
+            verify: false,
+            probe: false,
+            safety_scan: false,
+            with: Vec::new(),
+            output: None,
+            progress: ProgressArgs::default(),
+            ..self.args.clone()
+        }
+    }
+
+    /// Remap in the two phases of [`RemapArgs::phases`], so that no entry is moved twice
+    /// however often it is met. A marker in the state directory records the end of the
+    /// first phase, which a run interrupted during the second then skips when started again.
+    fn execute_two_phase(mut self, temporary: u32) -> Result<RunReport> {
+        let marker =
+            StateDir::resolve(self.state_dir.as_deref())?.two_phase_marker(&self.job_key()?)?;
+        let [first, second] = self.args.phases(temporary);
+        info!(
+            "Ranges overlap; remapping in two phases through {}",
+            id_span(temporary, self.args.range_size)
+        );
+        let mut frozen = match &self.args.freeze_cgroup {
+            Some(dir) => Some(FrozenCgroup::freeze(dir)?),
+            None => None,
+        };
+
+        if marker.exists() {
+            info!("Phase 1 was finished by an earlier run; resuming with phase 2");
+        } else {
+            if self.args.assert_unmapped {
+                // The phases see other targets, so a dry run of the whole remap checks
+                let planner =
+                    RemapCommand::new(self.planning_args()).with_state_dir(self.state_dir.clone());
+                tracing::subscriber::with_default(NoSubscriber::default(), || planner.execute())?;
+                check_not_interrupted("before phase 1")?;
+            }
+            info!(
+                "Phase 1: {}",
+                id_span(self.args.from_base, self.args.range_size)
+            );
+            let mut phase = RemapCommand::new(first)
+                .with_state_dir(self.state_dir.clone())
+                .with_registry(std::mem::take(&mut self.registry));
+            phase.extra_visitors = std::mem::take(&mut self.extra_visitors);
+            phase.lock_held = true;
+            let report = phase.execute()?;
+            if report.interrupted.is_some() {
+                return Ok(report);
+            }
+            // Moving on would leave the failed entries behind for good
+            if !report.errors.is_empty() {
+                warn!(
+                    "{} entry(ies) could not be moved to {}; phase 2 is left for a run after they are fixed",
+                    report.errors.len(),
+                    id_span(temporary, self.args.range_size)
+                );
+                return Ok(report);
+            }
+            fs::File::create(&marker)?;
+        }
+
+        info!(
+            "Phase 2: {}",
+            id_span(self.args.to_base, self.args.range_size)
+        );
+        let mut phase = RemapCommand::new(second).with_state_dir(self.state_dir.clone());
+        phase.lock_held = true;
+        let mut report = phase.execute()?;
+        if report.errors.is_empty() && report.interrupted.is_none() {
+            fs::remove_file(&marker)?;
+        }
+        if let Some(frozen) = &mut frozen {
+            report.duration("frozen", frozen.frozen_for());
+            frozen.thaw()?;
+        }
+        report.count("temporary_base", u64::from(temporary));
+        if let Some(path) = &self.args.snapshot_manifest {
+            report.artifact("snapshot-manifest", path);
+        }
+        Ok(report)
+    }
+
+    /// Plan the run again as a quiet dry run before changing anything, and fail unless it
+    /// comes to the `--plan-hash` approved.
+    fn check_plan(&self, expected: &str) -> RustUtilsResult<()> {
+        let args = RemapArgs {
+            assert_unmapped: false,
+            ..self.planning_args()
+        };
+        let mut planner = RemapCommand::new(args).with_state_dir(self.state_dir.clone());
+        planner.planned = Rc::clone(&self.planned);
+        let report =
+            tracing::subscriber::with_default(NoSubscriber::default(), || planner.execute())
+                .map_err(|e| RustUtilsError::PlanMismatch(format!("cannot plan the run: {e}")))?;
+        check_not_interrupted("before the plan was checked")?;
+        let planned = report.plan_hash.unwrap_or_default();
+        if planned != expected {
+            return Err(RustUtilsError::PlanMismatch(format!(
+                "the changes would now hash to {planned}, not the approved {expected}; run the dry run again and review it"
+            )));
+        }
+        info!("Plan {} matches the approved plan", planned);
+        Ok(())
+    }
+
+    /// Plan the run as a quiet dry run before changing anything, writing the current and
+    /// planned owner of each entry it changes to the `--snapshot-manifest` at `path`.
+    fn write_snapshot(&self, path: &Path) -> Result<()> {
+        let args = RemapArgs {
+            assert_unmapped: false,
+            ..self.planning_args()
+        };
+        let mut planner = RemapCommand::new(args).with_state_dir(self.state_dir.clone());
+        planner.snapshot = Some(UndoJournal::create(path)?);
+        let planned =
+            tracing::subscriber::with_default(NoSubscriber::default(), || planner.execute())
+                .and_then(|report| {
+                    check_not_interrupted("before the snapshot manifest was written")?;
+                    Ok(report)
+                });
+        // A partial manifest would pass for a complete one
+        let report = match planned {
+            Ok(report) => report,
+            Err(e) => {
+                fs::remove_file(path)?;
+                return Err(e);
+            }
+        };
+        info!(
+            "Snapshot of {} owner(s) written to {}",
+            report.counts.get("remapped").copied().unwrap_or(0),
+            path.display()
+        );
+        Ok(())
+    }
+
+    /// Stream the entries of a unit covered by `--include`, walked on `--jobs` threads.
+    /// Walk errors are all passed on, as they may hide included entries.
+    fn walk(
+        &self,
+        unit: &Unit,
+        mountpoints: &[PathBuf],
+    ) -> impl Iterator<Item = walkdir::Result<DirEntry>> {
+        let base = self.args.base_directory.clone();
+        let include = self.args.include.clone();
+        self.tree_walk(unit, self.walk_filter(mountpoints))
+            .filter(move |entry| match entry {
+                Ok(entry) => {
+                    let path = entry.path();
+                    let relative = path.strip_prefix(&base).unwrap_or(path);
+                    should_include(relative, &include)
+                }
+                Err(_) => true,
+            })
+    }
+
+    /// What walks of the tree leave out: `--exclude` matches, mount points and directories
+    /// finished by an earlier run.
+    fn walk_filter(&self, mountpoints: &[PathBuf]) -> WalkFilter {
+        WalkFilter {
+            exclude: self.args.exclude.clone(),
+            exclude_regex: self.exclude_regex.clone(),
+            base: self.args.base_directory.clone(),
+            mountpoints: mountpoints.to_vec(),
+            device: self.device,
+            resume: self.resume_marker.clone(),
+            completed: self.completed.clone(),
+            aliases: self.aliases.clone(),
+        }
+    }
+
+    fn tree_walk(&self, unit: &Unit, filter: WalkFilter) -> TreeWalk {
+        TreeWalk::new(
+            unit.root.clone(),
+            // Top-level symlinks are entries of the tree, not roots to descend into
+            unit.root == self.args.base_directory,
+            unit.max_depth,
+            filter,
+            self.args.jobs.get(),
+        )
+    }
+
+    /// Mark the finished `dirs` in which no error was reported, `errors` being the number
+    /// reported now, returning how many were marked.
+    fn mark_finished(
+        &self,
+        marker: &ResumeMarker,
+        dirs: Vec<OpenDir>,
+        errors: usize,
+    ) -> RustUtilsResult<u64> {
+        let mut marked = 0;
+        for dir in dirs {
+            if dir.errors != errors {
+                debug!("Not marking {}: errors below it", dir.path.display());
+                continue;
+            }
+            marker.mark(&dir.path).map_err(|e| match e.raw_os_error() {
+                Some(libc::ENOTSUP) => RustUtilsError::InvalidArguments(format!(
+                    "--resume-by-xattr needs user extended attributes, which the filesystem of {} does not support",
+                    dir.path.display()
+                )),
+                _ => RustUtilsError::OperationFailed(format!(
+                    "cannot mark {}: {e}",
+                    dir.path.display()
+                )),
+            })?;
+            marked += 1;
+        }
+        Ok(marked)
+    }
+
+    /// Walk the symlinks of `units` again to compare the owner of each with that of its
+    /// target, resolved inside the base directory as the container sees it. A dry run
+    /// compares the owners the remap would leave them with.
+    fn audit_symlinks(
+        &self,
+        units: &[Unit],
+        mountpoints: &[PathBuf],
+    ) -> RustUtilsResult<SymlinkAudit> {
+        let filter = self.walk_filter(mountpoints);
+        let mut audit = SymlinkAudit::default();
+        for unit in units {
+            for entry in self.walk(unit, mountpoints) {
+                let Ok(entry) = entry else { continue };
+                if !entry.file_type().is_symlink() {
+                    continue;
+                }
+                audit.audited += 1;
+                let path = entry.path();
+                let Some(target) = resolve_in_root(&self.args.base_directory, path)? else {
+                    audit.dangling += 1;
+                    continue;
+                };
+                let link = get_file_metadata(path)?;
+                let target_metadata = get_file_metadata(&target)?;
+                let (owner, target_owner) = if self.args.dry_run {
+                    (
+                        self.mapped_ids(&link)?,
+                        if self.is_walked(&filter, &target, &target_metadata) {
+                            self.mapped_ids(&target_metadata)?
+                        } else {
+                            self.owner(&target_metadata)
+                        },
+                    )
+                } else {
+                    (self.owner(&link), self.owner(&target_metadata))
+                };
+                if self.is_mixed(owner, target_owner) {
+                    audit.record(MixedSymlink {
+                        path: path.to_path_buf(),
+                        target,
+                        owner,
+                        target_owner,
+                    });
+                }
+            }
+        }
+        Ok(audit)
+    }
+
+    /// Walk `units` again for `--verify`, on a read-only view of the tree under
+    /// `--verify-readonly`, and report every entry whose owner the remap would still change.
+    fn verify(
+        &self,
+        units: &[Unit],
+        mountpoints: &[PathBuf],
+        report: &mut RunReport,
+    ) -> RustUtilsResult<()> {
+        let base = &self.args.base_directory;
+        let view = if self.args.verify_readonly {
+            let view = ReadOnlyView::open(base).map_err(|e| {
+                RustUtilsError::OperationFailed(format!(
+                    "cannot make a read-only bind of {} to verify: {e}",
+                    base.display()
+                ))
+            })?;
+            info!(
+                "Verifying {} on a read-only bind at {}",
+                base.display(),
+                view.path().display()
+            );
+            Some(view)
+        } else {
+            None
+        };
+        let root = view.as_ref().map_or(base.as_path(), ReadOnlyView::path);
+        let rebase = |path: &Path| match path.strip_prefix(base) {
+            Ok(relative) if !relative.as_os_str().is_empty() => root.join(relative),
+            _ => root.to_path_buf(),
+        };
+        let filter = WalkFilter {
+            base: root.to_path_buf(),
+            mountpoints: mountpoints.iter().map(|path| rebase(path)).collect(),
+            // What earlier runs finished is verified with the rest
+            resume: None,
+            completed: HashSet::new(),
+            aliases: self.aliases.as_ref().map(|aliases| {
+                let mountpoints: Vec<_> = aliases.mountpoints().map(rebase).collect();
+                Arc::new(Aliases::watch(&mountpoints))
+            }),
+            ..self.walk_filter(&[])
+        };
+
+        let (mut entries, mut mismatched) = (0, 0);
+        for unit in units {
+            let walk = TreeWalk::new(
+                rebase(&unit.root),
+                unit.root == *base,
+                unit.max_depth,
+                filter.clone(),
+                self.args.jobs.get(),
+            );
+            for entry in walk {
+                let entry = match entry {
+                    Ok(entry) => entry,
+                    Err(e) => {
+                        let path = e
+                            .path()
+                            .map(|path| base.join(path.strip_prefix(root).unwrap_or(path)));
+                        report.error(path.as_deref(), format!("verify: {e}"));
+                        continue;
+                    }
+                };
+                let relative = entry.path().strip_prefix(root).unwrap_or(entry.path());
+                if !should_include(relative, &self.args.include) {
+                    continue;
+                }
+                entries += 1;
+                let path = base.join(relative);
+                let metadata = match get_file_metadata(entry.path()) {
+                    Ok(metadata) => metadata,
+                    Err(e) => {
+                        report.error(Some(&path), format!("verify: {e}"));
+                        continue;
+                    }
+                };
+                let (uid, gid) = self.owner(&metadata);
+                let mapped = self.mapped_ids(&metadata)?;
+                if mapped != (uid, gid) {
+                    mismatched += 1;
+                    let (owner, mapped) =
+                        (self.owners.current((uid, gid)), self.owners.mapped(mapped));
+                    if mismatched <= MAX_LISTED_ASYMMETRIC as u64 {
+                        warn!(
+                            "{} is still owned by {}, which the remap maps to {}",
+                            path.display(),
+                            owner,
+                            mapped
+                        );
+                    }
+                    report.error(
+                        Some(&path),
+                        format!("verify: still owned by {owner}, mapped to {mapped}"),
+                    );
+                }
+            }
+        }
+        if mismatched > MAX_LISTED_ASYMMETRIC as u64 {
+            warn!(
+                "... and {} more entries still owned by IDs the remap maps",
+                mismatched - MAX_LISTED_ASYMMETRIC as u64
+            );
+        }
+        info!(
+            "Verify: {} entries, {} still owned by IDs the remap maps",
+            entries, mismatched
+        );
+        report
+            .count("verified_entries", entries)
+            .count("verify_mismatches", mismatched);
+        Ok(())
+    }
+
+    /// Stat the changes sampled by `--spot-check` again and report those whose owner is not
+    /// the one given, naming each filesystem that did not keep its changes.
+    fn spot_check(&self, mounts: &[mounts::Mount], report: &mut RunReport) {
+        let sampled = std::mem::take(&mut self.spot_check.borrow_mut().sampled);
+        // Changes checked and not kept by device, with the first path not kept
+        let mut devices: BTreeMap<u64, (u64, u64, Option<&Path>)> = BTreeMap::new();
+        for (path, device, owner) in &sampled {
+            let metadata = match get_file_metadata(path) {
+                Ok(metadata) => metadata,
+                // Removed since, which says nothing about the filesystem
+                Err(e) if e.errno() == Some(Errno::ENOENT) => continue,
+                Err(e) => {
+                    report.error(Some(path), format!("spot check: {e}"));
+                    continue;
+                }
+            };
+            let (checked, lost, example) = devices.entry(*device).or_default();
+            *checked += 1;
+            let (uid, gid) = self.owner(&metadata);
+            if (uid, gid) != (owner.uid, owner.gid) {
+                *lost += 1;
+                example.get_or_insert(path);
+                report.error(
+                    Some(path),
+                    format!(
+                        "spot check: owned by {} after the change to {}",
+                        self.owners.current((uid, gid)),
+                        self.owners.mapped((owner.uid, owner.gid))
+                    ),
+                );
+            }
+        }
+
+        let (mut checked_total, mut lost_total) = (0, 0);
+        for (device, (checked, lost, example)) in devices {
+            checked_total += checked;
+            lost_total += lost;
+            let Some(example) = example else { continue };
+            let filesystem = match mounts::find_mount(mounts, device, example) {
+                Some(mount) => format!(
+                    "{} ({} from {})",
+                    mount.mountpoint.display(),
+                    mount.fstype,
+                    mount.source
+                ),
+                None => format!("device {}:{}", major(device), minor(device)),
+            };
+            warn!(
+                "{} of {} changes checked on {} were not kept, e.g. {}; the filesystem may accept ownership changes without storing them, as some FUSE and network filesystems do",
+                lost,
+                checked,
+                filesystem,
+                example.display()
+            );
+        }
+        info!(
+            "Spot check: {} changed entries stat again, {} without their new owner",
+            checked_total, lost_total
+        );
+        report
+            .count("spot_checked", checked_total)
+            .count("spot_check_mismatches", lost_total);
+    }
+
+    /// Whether a walk with `filter` and the include patterns comes to `path` below the
+    /// base directory.
+    fn is_walked(&self, filter: &WalkFilter, path: &Path, metadata: &Metadata) -> bool {
+        let Ok(relative) = path.strip_prefix(&self.args.base_directory) else {
+            return false;
+        };
+        should_include(relative, &self.args.include)
+            && !relative
+                .ancestors()
+                .filter(|ancestor| !ancestor.as_os_str().is_empty())
+                .any(|ancestor| filter.excludes(&self.args.base_directory.join(ancestor)))
+            && self.device.is_none_or(|device| metadata.dev() == device)
+    }
+
+    /// Whether one of two owners has a remapped ID still in the source range where the
+    /// other has it in the target range.
+    fn is_mixed(&self, a: (u32, u32), b: (u32, u32)) -> bool {
+        let split = |kind, a: u32, b: u32| {
+            let in_source = |id| self.map_id(kind, id).is_some();
+            let in_target = |id| self.is_target(kind, id);
+            (in_source(a) && in_target(b)) || (in_target(a) && in_source(b))
+        };
+        (!self.args.gid_only && split(IdKind::Uid, a.0, b.0))
+            || (!self.args.uid_only && split(IdKind::Gid, a.1, b.1))
+    }
+
+    /// Remove the markers of `--resume-by-xattr` from the directories of `units`, then walk
+    /// them again to make sure none is left, returning how many were removed.
+    fn clear_markers(
+        &self,
+        marker: &ResumeMarker,
+        units: &[Unit],
+        mountpoints: &[PathBuf],
+    ) -> RustUtilsResult<u64> {
+        let filter = WalkFilter {
+            resume: None,
+            completed: HashSet::new(),
+            ..self.walk_filter(mountpoints)
+        };
+        let dirs = || {
+            units
+                .iter()
+                .flat_map(|unit| self.tree_walk(unit, filter.clone()))
+                .filter_map(|entry| entry.ok())
+                .filter(|entry| entry.file_type().is_dir())
+        };
+        let mut removed = 0;
+        for entry in dirs() {
+            if marker.clear(entry.path())? {
+                removed += 1;
+            }
+        }
+        let left: Vec<DirEntry> = dirs()
+            .filter(|entry| marker.is_marked(entry.path()))
+            .collect();
+        if let Some(first) = left.first() {
+            return Err(RustUtilsError::OperationFailed(format!(
+                "{} directories still carry {} after removing the markers, such as {}",
+                left.len(),
+                RESUME_XATTR,
+                first.path().display()
+            )));
+        }
+        Ok(removed)
+    }
+
+    /// Stream the paths of a batch of units, leaving out what cannot be walked.
+    fn walk_paths<'a>(
+        &'a self,
+        batch: &'a [&Unit],
+        mountpoints: &'a [PathBuf],
+    ) -> impl Iterator<Item = PathBuf> + 'a {
+        batch
+            .iter()
+            .flat_map(move |unit| self.walk(unit, mountpoints))
+            .filter_map(|entry| entry.ok())
+            .map(DirEntry::into_path)
+    }
+
+    /// Read the project ID of a file or directory and, with `--project-ids remap`, move it
+    /// from the source range to the target range. Dry runs only count it.
+    fn process_project_id(
+        &self,
+        path: &Path,
+        metadata: &Metadata,
+        range: &IdMap,
+        ids: &mut ProjectIds,
+    ) -> std::io::Result<()> {
+        let Some(id) = project::project_id(path, metadata)? else {
+            return Ok(());
+        };
+        if id != 0 {
+            ids.assigned += 1;
+        }
+        let Some(new_id) = range.get(id) else {
+            return Ok(());
+        };
+        ids.in_range += 1;
+        if self.args.project_ids != ProjectIdMode::Remap {
+            debug!("{}: project ID {} in the source range", path.display(), id);
+            return Ok(());
+        }
+        if self.args.dry_run {
+            debug!(
+                "Would change project ID of {}: {} -> {}",
+                path.display(),
+                id,
+                new_id
+            );
+        } else {
+            project::set_project_id(path, metadata, new_id)?;
+            debug!(
+                "Changed project ID of {}: {} -> {}",
+                path.display(),
+                id,
+                new_id
+            );
+        }
+        ids.remapped += 1;
+        Ok(())
+    }
+
+    /// Wait for the changes handed to `pool` and record them, returning how many entries
+    /// were left as they were.
+    fn drain_pool(
+        &self,
+        pool: &mut Option<ApplyPool>,
+        counters: &mut Counters,
+        filesystems: &mut FilesystemStats,
+        entry_types: &mut EntryTypeStats,
+        report: &mut RunReport,
+        progress: &mut Progress,
+    ) -> u64 {
+        let mut skipped = 0;
+        for applied in pool.as_mut().map(ApplyPool::wait).unwrap_or_default() {
+            if self.record_applied(
+                applied,
+                counters,
+                filesystems,
+                entry_types,
+                report,
+                progress,
+            ) {
+                skipped += 1;
+            }
+        }
+        skipped
+    }
+
+    /// Whether directory `path` matches an `--atomic-dirs` pattern.
+    fn is_atomic(&self, path: &Path) -> bool {
+        let relative = path.strip_prefix(&self.args.base_directory).unwrap_or(path);
+        let relative = relative.to_string_lossy();
+        !relative.is_empty()
+            && self
+                .args
+                .atomic_dirs
+                .iter()
+                .any(|pattern| glob::matches_path(pattern, &relative))
+    }
+
+    /// Swap in the copy of the `--atomic-dirs` directory the walk has finished, once every
+    /// change to it is applied, returning how many were swapped.
+    fn swap_atomic(&mut self) -> RustUtilsResult<u64> {
+        let Some(atomic) = self.atomic.take() else {
+            return Ok(0);
+        };
+        debug!(
+            "Swapping in the remapped copy of {}",
+            atomic.original().display()
+        );
+        atomic.swap()?;
+        Ok(1)
+    }
+
+    /// Where the entry at `path` is changed: in the copy of an `--atomic-dirs` directory
+    /// the walk is in, else in place.
+    fn on_disk<'a>(&self, path: &'a Path) -> Cow<'a, Path> {
+        match self
+            .atomic
+            .as_ref()
+            .and_then(|atomic| atomic.clone_path(path))
+        {
+            Some(clone) => Cow::Owned(clone),
+            None => Cow::Borrowed(path),
+        }
+    }
+
+    /// Count an entry once its ownership change, if any, has been applied, and return
+    /// whether it was left as it was.
+    fn record_applied(
+        &self,
+        applied: Applied,
+        counters: &mut Counters,
+        filesystems: &mut FilesystemStats,
+        entry_types: &mut EntryTypeStats,
+        report: &mut RunReport,
+        progress: &mut Progress,
+    ) -> bool {
+        let path = &applied.path;
+        let metadata = match applied.result {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                warn!("Failed to process {}: {}", path.display(), e);
+                report.error(Some(path), &e);
+                self.failed(e.errno(), Some(path), &e);
+                if let Some(device) = applied.device {
+                    filesystems.failed(device);
+                }
+                entry_types.failed(applied.kind);
+                return false;
+            }
+        };
+
+        let mut restored = self.restored.get();
+        restored += applied.restored;
+        self.restored.set(restored);
+        if let Some((from, to)) = applied.change {
+            info!(
+                target: ENTRY_TARGET,
+                event = "remapped",
+                path = %path.display(),
+                old_uid = from.uid,
+                old_gid = from.gid,
+                uid = to.uid,
+                gid = to.gid,
+                dry_run = self.args.dry_run
+            );
+            counters.changed += 1;
+            if let Some(device) = applied.device {
+                filesystems.changed(device);
+            }
+            if let (Some(percent), false) = (self.args.spot_check, self.args.dry_run) {
+                self.spot_check
+                    .borrow_mut()
+                    .offer(percent, path, metadata.dev(), to);
+            }
+            entry_types.changed(applied.kind);
+        } else {
+            entry_types.skipped(applied.kind);
+        }
+
+        if progress.is_enabled() {
+            if metadata.is_file() {
+                counters.bytes += metadata.len();
+            }
+            let relative = path.strip_prefix(&self.args.base_directory).unwrap_or(path);
+            progress.update(relative, *counters);
+        }
+        applied.change.is_none()
+    }
+
+    /// Check the arguments again for API users, who bypass the command line's checks.
+    fn validate_args(&self) -> RustUtilsResult<()> {
+        self.args.check_ranges()?;
+
+        if self.args.freeze_fs {
+            return Err(RustUtilsError::InvalidArguments(
+                "--freeze-fs is not supported: a frozen filesystem blocks the remap's own ownership changes until it is thawed; use --freeze-cgroup to keep the container from writing"
+                    .to_string(),
+            ));
+        }
+
+        if self.args.preview_overlay && self.args.dry_run {
+            return Err(RustUtilsError::InvalidArguments(
+                "--preview-overlay changes an overlay, not the tree, and has no dry run"
+                    .to_string(),
+            ));
+        }
+
+        if !self.args.atomic_dirs.is_empty() && self.args.project_ids == ProjectIdMode::Remap {
+            return Err(RustUtilsError::InvalidArguments(
+                "--atomic-dirs copies carry no project IDs, so cannot be combined with --project-ids remap"
+                    .to_string(),
+            ));
+        }
+
+        if self.args.uid_only && self.args.gid_only {
+            return Err(RustUtilsError::InvalidRange(
+                "Cannot specify both --uid-only and --gid-only".to_string(),
+            ));
+        }
+
+        if self.args.is_chained()? {
+            for (given, option) in [
+                (self.args.preview_overlay, "--preview-overlay"),
+                (self.args.undo_journal.is_some(), "--undo-journal"),
+                (self.args.expect_clean, "--expect-clean"),
+                (self.args.coordinate.is_some(), "--coordinate"),
+                (self.args.chown_helper.is_some(), "--chown-helper"),
+            ] {
+                if given {
+                    return Err(RustUtilsError::InvalidArguments(format!(
+                        "{option} cannot be combined with overlapping ranges, which are remapped in two phases"
+                    )));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Classify a failure with `errno` at `path` and record it in the entry log.
+    fn failed(&self, errno: Option<Errno>, path: Option<&Path>, error: &dyn fmt::Display) {
+        let class = self.failures.borrow_mut().record(errno, path);
+        warn!(
+            target: ENTRY_TARGET,
+            event = "failed",
+            path = %path.unwrap_or(&self.args.base_directory).display(),
+            error = %error,
+            category = class.name()
+        );
+    }
+
+    /// Stop the run once the errors recorded in `report` reach `--max-errors`, or with
+    /// `--fail-fast` the first.
+    fn check_errors(&self, report: &RunReport) -> RustUtilsResult<()> {
+        let limit = if self.args.fail_fast {
+            NonZeroUsize::MIN
+        } else {
+            match self.args.max_errors {
+                Some(limit) => limit,
+                None => return Ok(()),
+            }
+        };
+        if report.errors.len() < limit.get() {
+            return Ok(());
+        }
+        let last = report.errors.last().expect("at least one error");
+        Err(RustUtilsError::TooManyErrors(format!(
+            "stopping after {} failed {}, the last {}: {}",
+            report.errors.len(),
+            if report.errors.len() == 1 {
+                "entry"
+            } else {
+                "entries"
+            },
+            last.path.as_deref().unwrap_or("-"),
+            last.message
+        )))
+    }
+
+    /// Whether the run is split into units, as a `--partition`, `--subtree` or `--coordinate`
+    /// job.
+    fn is_split(&self) -> bool {
+        self.args.partition.is_some()
+            || !self.args.subtree.is_empty()
+            || self.args.coordinate.is_some()
+    }
+
+    /// The parts of the tree this run covers: the whole tree, or the `--subtree` directories
+    /// or top-level entries, limited to those of the `--partition`.
+    fn units(&self) -> RustUtilsResult<Vec<Unit>> {
+        let base = &self.args.base_directory;
+        if !self.is_split() {
+            return Ok(vec![Unit {
+                name: None,
+                root: base.clone(),
+                max_depth: usize::MAX,
+            }]);
+        }
+
+        let mut units = Vec::new();
+        if self.args.subtree.is_empty() {
+            units.push(Unit {
+                name: Some(PathBuf::from(".")),
+                root: base.clone(),
+                max_depth: 0,
+            });
+            let mut names: Vec<_> = std::fs::read_dir(base)?
+                .map(|entry| entry.map(|entry| entry.file_name()))
+                .collect::<Result<_, _>>()?;
+            names.sort();
+            units.extend(names.into_iter().map(|name| Unit {
+                root: base.join(&name),
+                name: Some(PathBuf::from(name)),
+                max_depth: usize::MAX,
+            }));
+        } else {
+            // Subtrees by inode, as names in a case-insensitive directory or a bind mount
+            // may lead to the same one
+            let mut seen: HashMap<_, &PathBuf> = HashMap::new();
+            for subtree in &self.args.subtree {
+                let root = resolve_subdirectory(base, subtree)?;
+                let metadata = fs::metadata(&root)?;
+                if let Some(first) = seen.get(&(metadata.dev(), metadata.ino())) {
+                    warn!(
+                        "--subtree {} is {} again; remapping it once",
+                        subtree.display(),
+                        first.display()
+                    );
+                    continue;
+                }
+                seen.insert((metadata.dev(), metadata.ino()), subtree);
+                let name = root.strip_prefix(base).unwrap_or(&root).to_path_buf();
+                units.push(Unit {
+                    name: Some(name),
+                    root,
+                    max_depth: usize::MAX,
+                });
+            }
+        }
+        if let Some(partition) = &self.args.partition {
+            units.retain(|unit| {
+                unit.name
+                    .as_deref()
+                    .is_some_and(|name| partition.contains(name))
+            });
+        }
+        Ok(units)
+    }
+
+    /// Lock the tree against other runs changing it: exclusively for a run over the whole
+    /// tree, shared with the other jobs of a split run and exclusively for this job. Without
+    /// a usable state directory the run goes on unlocked, with a warning.
+    fn lock_tree(&self) -> RustUtilsResult<Vec<RunLock>> {
+        let base = self.args.base_directory.canonicalize()?;
+        let split = self.is_split();
+        let locks = StateDir::resolve(self.state_dir.as_deref()).and_then(|state| {
+            let mut locks = vec![RunLock::acquire(&state.tree_lock(&base)?, !split, &base)?];
+            if split {
+                let job = state.job_lock(&self.job_key()?)?;
+                locks.push(RunLock::acquire(&job, true, &base)?);
+            }
+            Ok(locks)
+        });
+        match locks {
+            Err(e @ RustUtilsError::InUse(_)) => Err(e),
+            Err(e) => {
+                warn!("Cannot lock {} against other runs: {}", base.display(), e);
+                Ok(Vec::new())
+            }
+            locks => locks,
+        }
+    }
+
+    /// Journal of the units completed, or of the top-level directories for a whole-tree run,
+    /// resumed with `--resume`. Dry runs, coordinated jobs and runs that change no owners on
+    /// disk keep no journal, and a whole-tree run without a usable state directory goes on
+    /// without one.
+    fn open_journal(&self) -> RustUtilsResult<Option<Journal>> {
+        if self.args.dry_run
+            || self.args.coordinate.is_some()
+            || self.args.fakeroot_db.is_some()
+            || self.args.preview_overlay
+        {
+            return Ok(None);
+        }
+        let open = || {
+            let key = self.job_key()?;
+            let state = StateDir::resolve(self.state_dir.as_deref())?;
+            let path = state.partition_journal(&key)?;
+            let journal = Journal::open(&path, &key, self.args.resume)?;
+            debug!("Partition journal: {}", path.display());
+            Ok(journal)
+        };
+        match open() {
+            Ok(journal) => Ok(Some(journal)),
+            Err(e) if !self.is_split() && !self.args.resume => {
+                warn!("Cannot journal the directories completed: {}", e);
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// The tree, the mapping and the part of the tree this job covers, hashed.
+    fn job_key(&self) -> RustUtilsResult<String> {
+        let base = self.args.base_directory.canonicalize()?;
+        let partition = self
+            .args
+            .partition
+            .map(|p| p.to_string())
+            .unwrap_or_default();
+        let subtrees: Vec<u8> = self
+            .args
+            .subtree
+            .iter()
+            .flat_map(|subtree| subtree.as_os_str().as_bytes().iter().chain(b"\0"))
+            .copied()
+            .collect();
+        Ok(partition::job_key(&[
+            base.as_os_str().as_bytes(),
+            &self.mapping_key(),
+            partition.as_bytes(),
+            &subtrees,
+        ]))
+    }
+
+    /// The mapping as the value of `--resume-by-xattr` markers, such as `0:100000:65536`.
+    fn resume_key(&self) -> String {
+        let ids = if self.args.uid_only {
+            ":uid"
+        } else if self.args.gid_only {
+            ":gid"
+        } else {
+            ""
+        };
+        let mut key = format!(
+            "{}:{}:{}{}",
+            self.args.from_base, self.args.to_base, self.args.range_size, ids
+        );
+        for (prefix, overrides) in [("u", &self.args.map_uid), ("g", &self.args.map_gid)] {
+            for mapping in overrides {
+                key.push_str(&format!(",{prefix}{mapping}"));
+            }
+        }
+        key
+    }
+
+    /// The options deciding how entries change, which all jobs of one migration share. The
+    /// base directory is left out as hosts may mount shared storage in different places.
+    fn mapping_key(&self) -> Vec<u8> {
+        [
+            &self.args.from_base.to_be_bytes()[..],
+            &self.args.to_base.to_be_bytes(),
+            &self.args.range_size.to_be_bytes(),
+            &[u8::from(self.args.uid_only), u8::from(self.args.gid_only)],
+            &overrides_key(b'u', &self.args.map_uid),
+            &overrides_key(b'g', &self.args.map_gid),
+        ]
+        .concat()
+    }
+
+    /// Record `unit` as done, in the journal only if none of its entries `failed`, so that
+    /// `--resume` walks it again once the failures are fixed.
+    fn complete_unit(
+        &self,
+        journal: &mut Option<Journal>,
+        coordinator: &mut Option<Coordinator>,
+        unit: &Unit,
+        failed: bool,
+    ) -> RustUtilsResult<()> {
+        if let (Some(journal), Some(name), false) = (journal, &unit.name, failed) {
+            journal.complete(name)?;
+        }
+        if let (Some(coordinator), Some(name)) = (coordinator, &unit.name) {
+            coordinator.complete(name)?;
+        }
+        Ok(())
+    }
+
+    /// Warn about mounts below the tree that hide entries of its own filesystem, which keep
+    /// their owners unless `--beneath-mounts` is given. Returns how many mounts do, or
+    /// `None` if there are none below the tree or nothing can be seen beneath them.
+    fn find_shadowed(&self, canonical_base: &Path, below: &[&mounts::Mount]) -> Option<u64> {
+        let mut mountpoints: Vec<PathBuf> = below.iter().map(|m| m.mountpoint.clone()).collect();
+        mountpoints.sort();
+        mountpoints.dedup();
+        if mountpoints.is_empty() {
+            return None;
+        }
+        let shadowed = match shadow::find_shadowed(canonical_base, &mountpoints) {
+            Ok(shadowed) => shadowed,
+            Err(e) => {
+                debug!("Cannot look beneath the mounts below the tree: {}", e);
+                return None;
+            }
+        };
+        for hidden in &shadowed {
+            let Ok(relative) = hidden.mountpoint.strip_prefix(canonical_base) else {
+                debug!(
+                    "{} is not below {}; passed over",
+                    hidden.mountpoint.display(),
+                    canonical_base.display()
+                );
+                continue;
+            };
+            let mountpoint = self.args.base_directory.join(relative);
+            if self.args.beneath_mounts {
+                info!(
+                    "Remapping {} entry(ies) hidden under {}",
+                    hidden.entries,
+                    mountpoint.display()
+                );
+            } else {
+                warn!(
+                    "{} hides {} entry(ies) of the directory it is mounted on, which keep their owners; use --beneath-mounts to remap them",
+                    mountpoint.display(),
+                    hidden.entries
+                );
+            }
+        }
+        Some(shadowed.len() as u64)
+    }
+
+    /// Refuse to remap a tree processes are using, since changing ownership under a running
+    /// container corrupts its runtime state. Dry runs and `--allow-in-use` only warn, and
+    /// processes of the `--freeze-cgroup` cgroup are expected.
+    fn check_in_use(&self) -> RustUtilsResult<()> {
+        let users = match live::find_users(&self.args.base_directory) {
+            Ok(users) => users,
+            Err(e) => {
+                warn!("Cannot check for processes using the tree: {}", e);
+                return Ok(());
+            }
+        };
+        let frozen_pids = match &self.args.freeze_cgroup {
+            Some(dir) => freezer::cgroup_pids(dir)?,
+            None => Vec::new(),
+        };
+        let (frozen, users): (Vec<_>, Vec<_>) = users
+            .into_iter()
+            .partition(|user| frozen_pids.contains(&user.pid));
+        for user in &frozen {
+            debug!("In use, frozen: {}", user);
+        }
+        for user in &users {
+            warn!("In use: {}", user);
+        }
+        if users.is_empty() || self.args.dry_run || self.args.allow_in_use {
+            return Ok(());
+        }
+        Err(RustUtilsError::InUse(format!(
+            "{} process(es) are using {}; stop the container first or pass --allow-in-use",
+            users.len(),
+            self.args.base_directory.display()
+        )))
+    }
+
+    /// Report content that would become a privilege-escalation risk once remapped.
+    fn safety_scan(&self, paths: impl Iterator<Item = impl AsRef<Path>>) -> RustUtilsResult<()> {
+        let mut findings = Vec::new();
+
+        for path in paths {
+            let path = path.as_ref();
+            let metadata = get_file_metadata(path)?;
+            let (new_uid, _) = self.mapped_ids(&metadata)?;
+            let relative = path.strip_prefix(&self.args.base_directory).unwrap_or(path);
+
+            let root_uid = self
+                .map_id(IdKind::Uid, self.args.from_base)
+                .unwrap_or(self.args.to_base);
+            for kind in inspect(relative, &metadata, new_uid, root_uid) {
+                findings.push(Finding {
+                    path: path.to_path_buf(),
+                    kind,
+                });
+            }
+        }
+
+        for finding in &findings {
+            warn!("Safety scan: {}: {}", finding.path.display(), finding.kind);
+        }
+        info!("Safety scan findings: {}", findings.len());
+
+        Ok(())
+    }
+
+    /// Apply `--nested` to `path` if it is a tar archive, returning whether it was one.
+    fn process_nested(&self, path: &Path, rules: &RemapRules) -> RustUtilsResult<bool> {
+        let metadata = get_file_metadata(path)?;
+        if !metadata.is_file()
+            || !nested::has_archive_name(path)
+            || nested::sniff_file(path)?.is_none()
+        {
+            return Ok(false);
+        }
+
+        if self.args.nested == NestedPolicy::Warn {
+            warn!(
+                "Nested archive {} not remapped (use --nested recurse)",
+                path.display()
+            );
+            return Ok(true);
+        }
+
+        let stats = nested::rewrite_file(path, rules, self.args.dry_run)?;
+        if self.args.verbose || self.args.dry_run {
+            info!(
+                "{}: {} of {} nested archive entries remapped{}",
+                path.display(),
+                stats.remapped,
+                stats.entries,
+                if self.args.dry_run { " (dry run)" } else { "" }
+            );
+        }
+        Ok(true)
+    }
+
+    fn process_file(&mut self, path: &Path) -> RustUtilsResult<()> {
+        let metadata = self.entry_metadata(path)?;
+
+        if let Some(plugin) = &self.plugin {
+            let relative = path.strip_prefix(&self.args.base_directory).unwrap_or(path);
+            let (uid, gid) = self.owner(&metadata);
+            let decision = plugin.decide(relative, uid, gid, metadata.mode())?;
+            if decision == PluginDecision::Skip {
+                debug!("Skipped by plugin: {}", path.display());
+                return Ok(());
+            }
+        }
+
+        // Check for hard links
+        if metadata.nlink() > 1 {
+            let key = (metadata.dev(), metadata.ino());
+            if let Some(record) = self.seen_inodes.get_mut(&key) {
+                record.paths_seen += 1;
+            }
+            if let Some(record) = self.seen_inodes.get(&key) {
+                self.hardlink_paths += 1;
+                match self.args.hardlinks {
+                    HardLinkPolicy::First => {
+                        self.skip_hardlink(path, &record.first_path);
+                    }
+                    HardLinkPolicy::All => {
+                        debug!(
+                            "Processing hard link: {} -> {}",
+                            path.display(),
+                            record.first_path.display()
+                        );
+                        // The inode may already carry the new owner, so decide from the
+                        // ownership seen at the first path
+                        if self.metadata_in_range(&record.metadata) {
+                            self.remap_file(path, &record.metadata)?;
+                        }
+                    }
+                    HardLinkPolicy::Fail => {
+                        if path.parent() != record.first_path.parent() {
+                            return Err(RustUtilsError::UnexpectedHardLink(format!(
+                                "{} is linked to {}",
+                                path.display(),
+                                record.first_path.display()
+                            )));
+                        }
+                        self.skip_hardlink(path, &record.first_path);
+                    }
+                }
+                if self.args.hardlinks != HardLinkPolicy::All {
+                    self.hardlink_paths_skipped += 1;
+                }
+                return Ok(());
+            }
+            self.seen_inodes.insert(
+                key,
+                LinkRecord {
+                    first_path: path.to_path_buf(),
+                    metadata: metadata.clone(),
+                    paths_seen: 1,
+                },
+            );
+        }
+
+        if self.metadata_in_range(&metadata) {
+            if self.args.probe {
+                self.probe(path, &metadata)?;
+            }
+            self.remap_file(path, &metadata)?;
+        } else if self.is_already_mapped(&metadata) {
+            self.already_mapped += 1;
+        }
+        if self.args.audit && self.is_unexpected(&metadata) {
+            let owner = self.owner(&metadata);
+            self.unexpected.record(owner, path);
+        }
+
+        Ok(())
+    }
+
+    /// Count a pending change against the entry's filesystem, probing that filesystem with
+    /// the first entry that can be probed without side effects.
+    fn probe(&mut self, path: &Path, metadata: &Metadata) -> RustUtilsResult<()> {
+        let (new_uid, new_gid) = self.mapped_ids(metadata)?;
+        if (new_uid, new_gid) == self.owner(metadata) {
+            return Ok(());
+        }
+        *self.changes_by_device.entry(metadata.dev()).or_default() += 1;
+        if self.probes.contains_key(&metadata.dev()) || !probe::is_probeable(path, metadata) {
+            return Ok(());
+        }
+
+        let result = probe::probe_chown(path, metadata, new_uid, new_gid)?;
+        debug!("Probed {}: {:?}", path.display(), result.error);
+        self.probes.insert(metadata.dev(), result);
+        Ok(())
+    }
+
+    /// Log the predicted outcome for every filesystem with pending changes, returning the
+    /// number of changes expected to fail.
+    fn report_probes(&self, report: &mut RunReport) -> u64 {
+        let mut devices: Vec<_> = self.changes_by_device.iter().collect();
+        devices.sort();
+
+        let mut failures = 0;
+        for (&device, &changes) in devices {
+            let device_name = format!("{}:{}", major(device), minor(device));
+            match self.probes.get(&device) {
+                Some(Probe { path, error: None }) => info!(
+                    "Probe: chown permitted on device {} ({}); {} changes expected to succeed",
+                    device_name,
+                    path.display(),
+                    changes
+                ),
+                Some(Probe {
+                    path,
+                    error: Some(errno),
+                }) => {
+                    failures += changes;
+                    warn!(
+                        "Probe: chown fails with {} on device {} ({}); {} changes would fail",
+                        errno,
+                        device_name,
+                        path.display(),
+                        changes
+                    );
+                    report.error(
+                        Some(path),
+                        format!("probe: chown fails with {errno}; {changes} changes would fail"),
+                    );
+                }
+                None => warn!(
+                    "Probe: no entry on device {} could be probed safely; {} changes unverified",
+                    device_name, changes
+                ),
+            }
+        }
+        failures
+    }
+
+    /// The entry at `path`, a path as changes are made to it, reached through the anchor if
+    /// the run holds one.
+    fn anchored(&self, path: &Path) -> RustUtilsResult<Option<anchor::Entry>> {
+        self.anchor
+            .as_ref()
+            .and_then(|anchor| anchor.entry(path))
+            .transpose()
+            .map_err(RustUtilsError::Io)
+    }
+
+    /// Status of the entry at `path`, taken from the entry its ownership change is made to,
+    /// so that the change is decided for the entry it is made to.
+    fn entry_metadata(&self, path: &Path) -> RustUtilsResult<Metadata> {
+        let on_disk = self.on_disk(path);
+        match self.anchored(&on_disk)? {
+            Some(entry) => entry.metadata().map_err(RustUtilsError::Io),
+            None => get_file_metadata(&on_disk),
+        }
+    }
+
+    /// Whether the owner of an entry the run leaves alone is in the target range already,
+    /// for both IDs or the one `--uid-only` or `--gid-only` remaps.
+    fn is_already_mapped(&self, metadata: &Metadata) -> bool {
+        let (uid, gid) = self.owner(metadata);
+        (self.args.gid_only || self.is_target(IdKind::Uid, uid))
+            && (self.args.uid_only || self.is_target(IdKind::Gid, gid))
+    }
+
+    /// Whether one of the entry's IDs is in neither the source nor the target range, for
+    /// `--audit`.
+    fn is_unexpected(&self, metadata: &Metadata) -> bool {
+        let (uid, gid) = self.owner(metadata);
+        let outside = |kind, id| self.map_id(kind, id).is_none() && !self.is_target(kind, id);
+        (!self.args.gid_only && outside(IdKind::Uid, uid))
+            || (!self.args.uid_only && outside(IdKind::Gid, gid))
+    }
+
+    /// Fail at the first entry outside the source range with its owner in the target range,
+    /// for `--assert-unmapped`.
+    fn check_unmapped(&self, paths: impl Iterator<Item = impl AsRef<Path>>) -> RustUtilsResult<()> {
+        for path in paths {
+            let path = path.as_ref();
+            let metadata = self.entry_metadata(path)?;
+            if !self.metadata_in_range(&metadata) && self.is_already_mapped(&metadata) {
+                let (uid, gid) = self.owner(&metadata);
+                return Err(RustUtilsError::AlreadyMapped(format!(
+                    "{} is owned by {}:{}, in the target range; the tree may have been remapped before",
+                    path.display(),
+                    uid,
+                    gid
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    fn metadata_in_range(&self, metadata: &Metadata) -> bool {
+        let (uid, gid) = self.owner(metadata);
+
+        let uid_in_range = self.map_id(IdKind::Uid, uid).is_some();
+        let gid_in_range = self.map_id(IdKind::Gid, gid).is_some();
+
+        match (self.args.uid_only, self.args.gid_only) {
+            (true, false) => uid_in_range,
+            (false, true) => gid_in_range,
+            (false, false) => uid_in_range || gid_in_range,
+            (true, true) => unreachable!(), // Validated in validate_args
+        }
+    }
+
+    /// Check whether only one of the entry's IDs is in the source range, which is expected
+    /// and not reported with `--uid-only` or `--gid-only`.
+    fn asymmetry(&self, path: &Path, metadata: &Metadata) -> Option<AsymmetricEntry> {
+        if self.args.uid_only || self.args.gid_only {
+            return None;
+        }
+        let (uid, gid) = self.owner(metadata);
+        let uid_in_range = match (
+            self.map_id(IdKind::Uid, uid).is_some(),
+            self.map_id(IdKind::Gid, gid).is_some(),
+        ) {
+            (true, false) => true,
+            (false, true) => false,
+            _ => return None,
+        };
+        Some(AsymmetricEntry {
+            path: path.to_path_buf(),
+            uid,
+            gid,
+            uid_in_range,
+            other_mapped: if uid_in_range {
+                self.is_target(IdKind::Gid, gid)
+            } else {
+                self.is_target(IdKind::Uid, uid)
+            },
+        })
+    }
+
+    /// The owner of an entry: as recorded in `--fakeroot-db`, or on disk.
+    fn owner(&self, metadata: &Metadata) -> (u32, u32) {
+        match &self.fakeroot {
+            Some(db) => db.borrow().owner(metadata),
+            None => (metadata.uid(), metadata.gid()),
+        }
+    }
+
+    /// Where `id` goes through a mapping overriding the range or the range itself, or
+    /// `None` if neither covers it.
+    fn map_id(&self, kind: IdKind, id: u32) -> Option<u32> {
+        let overrides = self.args.overrides(kind);
+        match overrides.iter().find_map(|mapping| mapping.map(id)) {
+            Some(mapped) => Some(mapped),
+            None => (id.wrapping_sub(self.args.from_base) < self.args.range_size)
+                .then(|| self.args.to_base + (id - self.args.from_base)),
+        }
+    }
+
+    /// The GID of the tree's group database that `gid` stands for, if `--known-groups-only`
+    /// is given and `gid` is in the range and not mapped by an override.
+    fn group_id(&self, gid: u32) -> Option<u32> {
+        let id = gid.wrapping_sub(self.args.from_base);
+        let overridden = self
+            .args
+            .overrides(IdKind::Gid)
+            .iter()
+            .any(|mapping| mapping.map(gid).is_some());
+        (self.groups.is_some() && id < self.args.range_size && !overridden).then_some(id)
+    }
+
+    /// Whether `--known-groups-only` leaves `gid` as it is, as no group of the tree has it.
+    fn is_orphaned_gid(&self, gid: u32) -> bool {
+        match (&self.groups, self.group_id(gid)) {
+            (Some(groups), Some(id)) => groups.name(id).is_none(),
+            _ => false,
+        }
+    }
+
+    /// Whether some ID is mapped to `id`, through an override or the range.
+    fn is_target(&self, kind: IdKind, id: u32) -> bool {
+        let overrides = self.args.overrides(kind);
+        if overrides
+            .iter()
+            .any(|mapping| id.wrapping_sub(mapping.to) < mapping.count)
+        {
+            return true;
+        }
+        let source = id.wrapping_sub(self.args.to_base);
+        source < self.args.range_size
+            && !overrides
+                .iter()
+                .any(|mapping| mapping.map(self.args.from_base + source).is_some())
+    }
+
+    /// Compute the (uid, gid) an entry with the given ownership ends up with.
+    fn mapped_ids(&self, metadata: &Metadata) -> RustUtilsResult<(u32, u32)> {
+        let (current_uid, current_gid) = self.owner(metadata);
+
+        let new_uid = match self.map_id(IdKind::Uid, current_uid) {
+            Some(mapped) if !self.args.gid_only => {
+                let plugin_uid = match &self.plugin {
+                    Some(plugin) => plugin.map_uid(current_uid)?,
+                    None => None,
+                };
+                plugin_uid.unwrap_or(mapped)
+            }
+            _ => current_uid,
+        };
+
+        let new_gid = match self.map_id(IdKind::Gid, current_gid) {
+            Some(mapped) if !self.args.uid_only && !self.is_orphaned_gid(current_gid) => {
+                let plugin_gid = match &self.plugin {
+                    Some(plugin) => plugin.map_gid(current_gid)?,
+                    None => None,
+                };
+                plugin_gid.unwrap_or(mapped)
+            }
+            _ => current_gid,
+        };
+
+        Ok((new_uid, new_gid))
+    }
+
+    /// Leave `path` alone as a further link to the inode of `first_path`, saying so in the
+    /// dry run and verbose output, where it would otherwise look forgotten.
+    fn skip_hardlink(&self, path: &Path, first_path: &Path) {
+        if self.args.verbose || self.args.dry_run {
+            info!(
+                "{}: hard link to {}, skipped{}",
+                path.display(),
+                first_path.display(),
+                if self.args.dry_run { " (dry run)" } else { "" }
+            );
+        } else {
+            debug!(
+                "Skipping hard link: {} -> {}",
+                path.display(),
+                first_path.display()
+            );
+        }
+    }
+
+    fn remap_file(&self, path: &Path, metadata: &Metadata) -> RustUtilsResult<()> {
+        let (current_uid, current_gid) = self.owner(metadata);
+        let (new_uid, new_gid) = self.mapped_ids(metadata)?;
+        if let (Some(groups), Some(id)) = (&self.groups, self.group_id(current_gid)) {
+            let mut known_groups = self.known_groups.borrow_mut();
+            match groups.name(id) {
+                Some(name) if new_gid != current_gid => {
+                    *known_groups.remapped.entry(name.to_string()).or_default() += 1;
+                }
+                Some(_) => {}
+                None => *known_groups.orphaned.entry(current_gid).or_default() += 1,
+            }
+        }
+
+        if (self.args.verbose || self.args.dry_run)
+            && (new_uid != current_uid || new_gid != current_gid)
+        {
+            info!(
+                "{}: {} -> {}{}",
+                path.display(),
+                self.owners.current((current_uid, current_gid)),
+                self.owners.mapped((new_uid, new_gid)),
+                if self.args.dry_run { " (dry run)" } else { "" }
+            );
+        }
+
+        if new_uid != current_uid || new_gid != current_gid {
+            let relative = path.strip_prefix(&self.args.base_directory).unwrap_or(path);
+            let old = Owner {
+                uid: current_uid,
+                gid: current_gid,
+            };
+            let new = Owner {
+                uid: new_uid,
+                gid: new_gid,
+            };
+            self.owner_change.set(Some((old, new)));
+            self.plan.borrow_mut().add(relative, old, new);
+            if let Some(snapshot) = &self.snapshot {
+                snapshot.record(path, old, new)?;
+            }
+            if self.args.expect_clean {
+                if self.args.dry_run {
+                    self.planned.borrow_mut().record(path, old);
+                } else {
+                    self.planned.borrow_mut().check(path, old)?;
+                }
+            }
+        }
+        if !self.args.dry_run && (new_uid != current_uid || new_gid != current_gid) {
+            if let Some(db) = &self.fakeroot {
+                db.borrow_mut().set_owner(metadata, new_uid, new_gid);
+                return Ok(());
+            }
+            if let Some(undo) = &self.undo {
+                let old = Owner {
+                    uid: current_uid,
+                    gid: current_gid,
+                };
+                let new = Owner {
+                    uid: new_uid,
+                    gid: new_gid,
+                };
+                undo.record(path, old, new)?;
+            }
+
+            let uid = if new_uid != current_uid {
+                Some(new_uid)
+            } else {
+                None
+            };
+
+            let gid = if new_gid != current_gid {
+                Some(new_gid)
+            } else {
+                None
+            };
+
+            let xattrs = if self.args.no_preserve_xattrs {
+                None
+            } else {
+                let on_disk = self.on_disk(path);
+                let entry = self.anchored(&on_disk)?;
+                let source = entry.as_ref().map(anchor::Entry::path);
+                let mut xattrs =
+                    Snapshot::capture(source.as_deref().unwrap_or(&on_disk)).map_err(|e| {
+                        RustUtilsError::RemapFailed(format!(
+                            "Failed to read xattrs of {}: {}",
+                            path.display(),
+                            e
+                        ))
+                    })?;
+                // Namespaced file capabilities are bound to the UID of the container's root
+                if !self.args.gid_only {
+                    xattrs.map_capability_rootid(|id| self.map_id(IdKind::Uid, id).unwrap_or(id));
+                }
+                (!xattrs.is_empty()).then_some(xattrs)
+            };
+            // Symlinks have no mode of their own, and only special bits get cleared
+            let mode = metadata.mode() & MODE_BITS;
+            let mode = (!self.args.no_restore_mode
+                && !metadata.file_type().is_symlink()
+                && mode & SPECIAL_MODE_BITS != 0)
+                .then_some(mode);
+            let chown = Chown {
+                uid,
+                gid,
+                mode,
+                xattrs,
+                helper: self.helper.clone(),
+                anchor: self.anchor.clone(),
+            };
+            if self.args.jobs.get() > 1 {
+                self.deferred_chown.set(Some(chown));
+            } else {
+                let mut restored = self.restored.get();
+                restored += chown.apply(&self.on_disk(path))?;
+                self.restored.set(restored);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Puts back the owners recorded in an undo journal.
+pub struct UndoCommand {
+    args: UndoArgs,
+}
+
+impl UndoCommand {
+    pub fn new(args: UndoArgs) -> Self {
+        Self { args }
+    }
+
+    pub fn execute(self) -> Result<RunReport> {
+        let entries = undo::read_journal(&self.args.journal)?;
+        if self.args.dry_run {
+            log_message!(INFO, "dry-run");
+        }
+        info!(
+            "Restoring {} ownership change(s) from {}",
+            entries.len(),
+            self.args.journal.display()
+        );
+        if self.args.expect_clean {
+            check_clean(&entries)?;
+        }
+
+        let mut report = RunReport::new("remap-undo");
+        let mut restored = 0;
+        let mut already_restored = 0;
+        // Latest changes first, the reverse of the remap
+        for entry in entries.iter().rev() {
+            let path = &entry.path;
+            let metadata = match get_file_metadata(path) {
+                Ok(metadata) => metadata,
+                Err(e) if self.args.expect_clean => {
+                    return Err(RustUtilsError::UnexpectedChange(format!(
+                        "{}: {e}",
+                        path.display()
+                    ))
+                    .into());
+                }
+                Err(e) => {
+                    warn!("Cannot restore {}: {}", path.display(), e);
+                    report.error(Some(path), &e);
+                    continue;
+                }
+            };
+            let current = Owner {
+                uid: metadata.uid(),
+                gid: metadata.gid(),
+            };
+            if current == entry.old {
+                // Another link to the inode, or an earlier undo, restored it already
+                already_restored += 1;
+                continue;
+            }
+            if current != entry.new {
+                if self.args.expect_clean {
+                    return Err(RustUtilsError::UnexpectedChange(format!(
+                        "{} is owned by {current}, not {} as remapped",
+                        path.display(),
+                        entry.new
+                    ))
+                    .into());
+                }
+                warn!(
+                    "Not restoring {}: its owner changed to {} after the remap",
+                    path.display(),
+                    current
+                );
+                report.error(
+                    Some(path),
+                    format!("owner is {current}, not {} as remapped", entry.new),
+                );
+                continue;
+            }
+
+            if self.args.verbose || self.args.dry_run {
+                info!(
+                    "{}: {} -> {}{}",
+                    path.display(),
+                    entry.new,
+                    entry.old,
+                    if self.args.dry_run { " (dry run)" } else { "" }
+                );
+            }
+            if !self.args.dry_run {
+                let uid = (entry.old.uid != current.uid).then_some(entry.old.uid);
+                let gid = (entry.old.gid != current.gid).then_some(entry.old.gid);
+                // The special mode bits come back as the remap kept them
+                let mode = metadata.mode() & MODE_BITS;
+                let chown = Chown {
+                    uid,
+                    gid,
+                    mode: (!metadata.file_type().is_symlink() && mode & SPECIAL_MODE_BITS != 0)
+                        .then_some(mode),
+                    xattrs: None,
+                    helper: None,
+                    anchor: None,
+                };
+                if let Err(e) = chown.apply(path) {
+                    warn!("{}", e);
+                    report.error(Some(path), &e);
+                    continue;
+                }
+            }
+            restored += 1;
+        }
+
+        info!(
+            "Restored {} entries, {} already restored, {} failed",
+            restored,
+            already_restored,
+            report.errors.len()
+        );
+        report
+            .count("entries", entries.len() as u64)
+            // The entries changed, as `remapped` is for a remap
+            .count("remapped", restored)
+            .count("already_restored", already_restored);
+        Ok(report)
+    }
+}
+
+/// Checks the owners of a tree against a mapping or a manifest, after a migration.
+pub struct VerifyCommand {
+    args: VerifyArgs,
+    /// Entries found without the owner expected
+    mismatches: u64,
+}
+
+impl VerifyCommand {
+    pub fn new(args: VerifyArgs) -> Self {
+        Self {
+            args,
+            mismatches: 0,
+        }
+    }
+
+    pub fn execute(mut self) -> Result<RunReport> {
+        let mut report = RunReport::new("remap-verify");
+        let entries = match self.args.manifest.clone() {
+            Some(manifest) => self.verify_manifest(&manifest, &mut report)?,
+            None => self.verify_mapping(&mut report)?,
+        };
+        if self.mismatches > MAX_LISTED_ASYMMETRIC as u64 {
+            warn!(
+                "... and {} more entries without the owner expected",
+                self.mismatches - MAX_LISTED_ASYMMETRIC as u64
+            );
+        }
+        info!(
+            "Verified {} entries, {} without the owner expected",
+            entries, self.mismatches
+        );
+        report
+            .count("entries", entries)
+            .count("mismatches", self.mismatches);
+        Ok(report)
+    }
+
+    /// Check every entry of the tree against the mapping, returning how many were checked.
+    fn verify_mapping(&mut self, report: &mut RunReport) -> Result<u64> {
+        let base = self
+            .args
+            .base_directory
+            .clone()
+            .expect("the tree is required without a manifest");
+        if !base.is_dir() {
+            return Err(RustUtilsError::DirectoryNotFound(format!(
+                "{} is not a directory",
+                base.display()
+            ))
+            .into());
+        }
+        // The checks of the remap's own arguments apply to the mapping
+        let remap = RemapArgs {
+            base_directory: base.clone(),
+            from_base: self.args.from_base.expect("required with --to-base"),
+            to_base: self.args.to_base.expect("required with --from-base"),
+            range_size: self.args.range_size,
+            map_uid: self.args.map_uid.clone(),
+            map_gid: self.args.map_gid.clone(),
+            ..Default::default()
+        };
+        remap.check_ranges()?;
+        let maps = [remap.id_map(IdKind::Uid)?, remap.id_map(IdKind::Gid)?];
+        info!(
+            "Verifying {} against {} -> {}",
+            base.display(),
+            id_span(remap.from_base, remap.range_size),
+            id_span(remap.to_base, remap.range_size)
+        );
+
+        let filter = WalkFilter {
+            exclude: self.args.exclude.clone(),
+            base: base.clone(),
+            device: if self.args.one_file_system {
+                Some(fs::metadata(&base)?.dev())
+            } else {
+                None
+            },
+            ..WalkFilter::default()
+        };
+        let mut entries = 0;
+        for entry in TreeWalk::new(base, true, usize::MAX, filter, 1) {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    warn!("{}", e);
+                    report.error(e.path(), format!("walk: {e}"));
+                    continue;
+                }
+            };
+            let path = entry.path();
+            let metadata = match get_file_metadata(path) {
+                Ok(metadata) => metadata,
+                Err(e) => {
+                    report.error(Some(path), &e);
+                    continue;
+                }
+            };
+            entries += 1;
+            if self.args.verbose {
+                info!("{}: {}:{}", path.display(), metadata.uid(), metadata.gid());
+            }
+            let ids = [(IdKind::Uid, metadata.uid()), (IdKind::Gid, metadata.gid())];
+            let problems: Vec<String> = ids
+                .into_iter()
+                .zip(&maps)
+                .filter_map(|((kind, id), map)| match map.get(id) {
+                    // IDs both mapped from and to may be either
+                    Some(mapped) if !map.is_target(id) => {
+                        Some(format!("{kind} {id} is still unmapped, to become {mapped}"))
+                    }
+                    None if self.args.strict && !map.is_target(id) => {
+                        Some(format!("{kind} {id} is in neither range"))
+                    }
+                    _ => None,
+                })
+                .collect();
+            if !problems.is_empty() {
+                self.mismatch(report, path, &problems.join(", "));
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Check every entry of an undo journal or snapshot manifest for the new owner it
+    /// records, returning how many were checked.
+    fn verify_manifest(&mut self, manifest: &Path, report: &mut RunReport) -> Result<u64> {
+        let entries = undo::read_journal(manifest)?;
+        info!(
+            "Verifying {} entries of {}",
+            entries.len(),
+            manifest.display()
+        );
+        for entry in &entries {
+            let metadata = match get_file_metadata(&entry.path) {
+                Ok(metadata) => metadata,
+                Err(e) => {
+                    self.mismatch(report, &entry.path, &e.to_string());
+                    continue;
+                }
+            };
+            let current = Owner {
+                uid: metadata.uid(),
+                gid: metadata.gid(),
+            };
+            if self.args.verbose {
+                info!("{}: {}", entry.path.display(), current);
+            }
+            if current != entry.new {
+                self.mismatch(
+                    report,
+                    &entry.path,
+                    &format!("owned by {current}, not {} as remapped", entry.new),
+                );
+            }
+        }
+        Ok(entries.len() as u64)
+    }
+
+    /// Report `path` as not having the owner expected, logging the first few.
+    fn mismatch(&mut self, report: &mut RunReport, path: &Path, problem: &str) {
+        self.mismatches += 1;
+        if self.mismatches <= MAX_LISTED_ASYMMETRIC as u64 {
+            warn!("{}: {}", path.display(), problem);
+        }
+        report.error(Some(path), problem);
+    }
+}
+
+/// Runs a remap profile, as root or through pkexec.
+pub struct ProfileCommand {
+    args: ProfileArgs,
+}
+
+impl ProfileCommand {
+    pub fn new(args: ProfileArgs) -> Self {
+        Self { args }
+    }
+
+    pub fn execute(self) -> Result<RunReport> {
+        if !nix::unistd::geteuid().is_root() {
+            // Only returns if pkexec could not be started; the elevated run reports itself
+            let exe = std::env::current_exe()?;
+            info!("Running profile {} through pkexec", self.args.name);
+            let error = profile::pkexec_command(
+                &exe,
+                &self.args.name,
+                self.args.dry_run,
+                self.args.verbose,
+            )
+            .exec();
+            return Err(
+                RustUtilsError::OperationFailed(format!("cannot run pkexec: {error}")).into(),
+            );
+        }
+
+        let profile = Profile::load(&self.args.profile_dir, &self.args.name)?;
+        info!(
+            "Profile {}: {} from {} to {}",
+            self.args.name,
+            profile.base_directory.display(),
+            profile.from_base,
+            profile.to_base
+        );
+        let args = RemapArgs {
+            dry_run: self.args.dry_run,
+            verbose: self.args.verbose,
+            ..profile.remap_args()
+        };
+        args.check_ranges()?;
+        RemapCommand::new(args).execute()
+    }
+}
+
+/// Fail unless every entry of an undo journal has the owner the remap gave it, or the one
+/// it had before, naming the first that has neither.
+fn check_clean(entries: &[UndoEntry]) -> RustUtilsResult<()> {
+    let unclean: Vec<String> = entries
+        .iter()
+        .filter_map(|entry| match get_file_metadata(&entry.path) {
+            Ok(metadata) => {
+                let current = Owner {
+                    uid: metadata.uid(),
+                    gid: metadata.gid(),
+                };
+                (current != entry.new && current != entry.old).then(|| {
+                    format!(
+                        "{} is owned by {current}, not {} as remapped",
+                        entry.path.display(),
+                        entry.new
+                    )
+                })
+            }
+            Err(e) => Some(format!("{}: {e}", entry.path.display())),
+        })
+        .collect();
+    match unclean.split_first() {
+        Some((first, [])) => Err(RustUtilsError::UnexpectedChange(first.clone())),
+        Some((first, rest)) => Err(RustUtilsError::UnexpectedChange(format!(
+            "{first}, and {} more entries changed since the remap",
+            rest.len()
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Mappings overriding the range, each marked with `kind`, for mapping keys; nothing
+/// without any, so keys of runs without them stay as they were.
+fn overrides_key(kind: u8, overrides: &[IdMapping]) -> Vec<u8> {
+    overrides
+        .iter()
+        .flat_map(|mapping| {
+            [
+                &[kind][..],
+                &mapping.from.to_be_bytes(),
+                &mapping.to.to_be_bytes(),
+                &mapping.count.to_be_bytes(),
+            ]
+            .concat()
+        })
+        .collect()
+}
+
+/// Fail if a signal asked the run to stop during a check made `when`, as nothing has been
+/// changed yet that a summary would need to cover.
+fn check_not_interrupted(when: &str) -> RustUtilsResult<()> {
+    match signals::received() {
+        Some(signal) => Err(RustUtilsError::Interrupted(format!("{signal} {when}"))),
+        None => Ok(()),
+    }
+}
+
+fn chown(path: &Path, uid: Option<u32>, gid: Option<u32>) -> RustUtilsResult<()> {
+    iostats::timed(Syscall::Fchownat, || lchown(path, uid, gid)).map_err(|e| chown_failed(path, e))
+}
+
+fn chown_failed(path: &Path, e: io::Error) -> RustUtilsError {
+    let hint = if e.raw_os_error() == Some(libc::EPERM)
+        && !nix::unistd::geteuid().is_root()
+        && fakeroot::session().is_none()
+    {
+        "; without root, run under fakeroot, record owners with --fakeroot-db or have a \
+         helper change them with --chown-helper"
+    } else {
+        ""
+    };
+    RustUtilsError::ChownFailed {
+        message: format!("Failed to chown {}: {}{}", path.display(), e, hint),
+        source: e,
+    }
+}
+
+/// Count the paths of every multiply-linked inode among `paths` and return the inodes that
+/// have links elsewhere, i.e. where a change would leak outside the given set of paths.
+fn find_external_links(
+    paths: impl Iterator<Item = impl AsRef<Path>>,
+) -> RustUtilsResult<Vec<ExternalLink>> {
+    let mut records: HashMap<(u64, u64), LinkRecord> = HashMap::new();
+
+    for path in paths {
+        let path = path.as_ref();
+        let metadata = get_file_metadata(path)?;
+        if metadata.nlink() <= 1 {
+            continue;
+        }
+        records
+            .entry((metadata.dev(), metadata.ino()))
+            .and_modify(|record| record.paths_seen += 1)
+            .or_insert_with(|| LinkRecord {
+                first_path: path.to_path_buf(),
+                metadata,
+                paths_seen: 1,
+            });
+    }
+
+    let mut external: Vec<_> = records.values().filter_map(LinkRecord::external).collect();
+    external.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(external)
+}
+
+fn log_visit_event(event: &VisitEvent) {
+    match event {
+        VisitEvent::Change {
+            visitor,
+            path,
+            description,
+        } => info!("[{}] changed {}: {}", visitor, path.display(), description),
+        VisitEvent::Finding {
+            visitor,
+            path,
+            description,
+        } => info!("[{}] {}: {}", visitor, path.display(), description),
+    }
+}
+
+/// One line per filesystem, so failures confined to one mount stand out.
+fn log_filesystems(filesystems: &[FilesystemSummary]) {
+    for fs in filesystems {
+        let line = tr!(
+            "remap-filesystem",
+            device = fs.device.as_str(),
+            fstype = fs.fstype.clone().unwrap_or_else(|| tr!("unknown-fstype")),
+            mountpoint = fs
+                .mountpoint
+                .clone()
+                .unwrap_or_else(|| tr!("unknown-mount")),
+            entries = fs.entries,
+            changed = fs.changed,
+            failed = fs.failed
+        );
+        if fs.failed > 0 {
+            warn!(code = "remap-filesystem", "{}", line);
+        } else {
+            info!(code = "remap-filesystem", "{}", line);
+        }
+    }
+}
+
+fn log_entry_types(entry_types: &[EntryTypeSummary]) {
+    for summary in entry_types {
+        info!(
+            "Entry type {}{}: {} entries, {} remapped, {} left as they were, {} failed",
+            summary.kind,
+            if summary.ephemeral {
+                " (ephemeral)"
+            } else {
+                ""
+            },
+            summary.entries,
+            summary.changed,
+            summary.skipped,
+            summary.failed
+        );
+    }
+    let (skipped, failed) = entry_types
+        .iter()
+        .filter(|summary| summary.ephemeral)
+        .fold((0, 0), |(skipped, failed), summary| {
+            (skipped + summary.skipped, failed + summary.failed)
+        });
+    if skipped + failed > 0 {
+        info!(
+            "{} sockets and FIFOs kept their owners and {} failed; programs recreate them when the container starts",
+            skipped, failed
+        );
+    }
+}
+
+fn report_external_links(external: &[ExternalLink]) {
+    for link in external {
+        warn!(
+            "{} has {} hard links but only {} inside the tree; ownership changes will also affect the others",
+            link.path.display(),
+            link.nlink,
+            link.paths_seen
+        );
+    }
+    if !external.is_empty() {
+        warn!(
+            "Inodes with hard links outside the tree: {}",
+            external.len()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::hex;
+    use nix::unistd::{geteuid, getgid, getuid};
+    use std::fs::{self, File};
+    use std::os::unix::fs::{symlink, MetadataExt};
+    use tempfile::TempDir;
+    use walkdir::WalkDir;
+
+    use crate::xattrs::CAPABILITY_XATTR;
+
+    /// Test argument validation logic - no filesystem operations needed
+    #[test]
+    fn test_remap_args_validation() {
+        let args = RemapArgs {
+            base_directory: PathBuf::from("/tmp"),
+            from_base: 100000,
+            to_base: 50000000,
+            range_size: 65536,
+            dry_run: false,
+            verbose: false,
+            exclude: vec![],
+            uid_only: false,
+            gid_only: false,
+            ..Default::default()
+        };
+
+        let command = RemapCommand::new(args);
+        assert!(command.validate_args().is_ok());
+    }
+
+    /// Test overflow detection in validation
+    #[test]
+    fn test_remap_args_validation_from_base_overflow() {
+        let args = RemapArgs {
+            base_directory: PathBuf::from("/tmp"),
+            from_base: u32::MAX - 1000,
+            to_base: 50000000,
+            range_size: 65536, // This would overflow from_base + range_size
+            dry_run: false,
+            verbose: false,
+            exclude: vec![],
+            uid_only: false,
+            gid_only: false,
+            ..Default::default()
+        };
+
+        let command = RemapCommand::new(args);
+        let result = command.validate_args();
+        assert!(result.is_err());
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .contains("from_base + range_size would overflow"));
+    }
+
+    /// Test to_base overflow detection
+    #[test]
+    fn test_remap_args_validation_to_base_overflow() {
+        let args = RemapArgs {
+            base_directory: PathBuf::from("/tmp"),
+            from_base: 100000,
+            to_base: u32::MAX - 1000,
+            range_size: 65536, // This would overflow to_base + range_size
+            dry_run: false,
+            verbose: false,
+            exclude: vec![],
+            uid_only: false,
+            gid_only: false,
+            ..Default::default()
+        };
+
+        let command = RemapCommand::new(args);
+        let result = command.validate_args();
+        assert!(result.is_err());
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .contains("to_base + range_size would overflow"));
+    }
+
+    /// Test conflicting flags validation
+    #[test]
+    fn test_remap_args_validation_both_uid_gid_only() {
+        let args = RemapArgs {
+            base_directory: PathBuf::from("/tmp"),
+            from_base: 100000,
+            to_base: 50000000,
+            range_size: 65536,
+            dry_run: false,
+            verbose: false,
+            exclude: vec![],
+            uid_only: true,
+            gid_only: true, // Both flags set - should error
+            ..Default::default()
+        };
+
+        let command = RemapCommand::new(args);
+        let result = command.validate_args();
+        assert!(result.is_err());
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .contains("Cannot specify both --uid-only and --gid-only"));
+    }
+
+    /// Test decision logic for files with current user ownership - NO DRY RUN
+    #[test]
+    fn test_should_remap_file_with_current_user_ownership(
+    ) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = TempDir::new()?;
+        let file_path = temp_dir.path().join("test_file.txt");
+        File::create(&file_path)?;
+
+        let current_uid = getuid().as_raw();
+
+        // Test with current user's UID in the remap range
+        let args = RemapArgs {
+            base_directory: temp_dir.path().to_path_buf(),
+            from_base: current_uid,
+            to_base: current_uid + 1000,
+            range_size: 1,  // Exactly matches current_uid
+            dry_run: false, // NOT dry run - testing decision logic
+            verbose: false,
+            exclude: vec![],
+            uid_only: false,
+            gid_only: false,
+            ..Default::default()
+        };
+
+        let command = RemapCommand::new(args);
+        let should_remap = command.metadata_in_range(&command.entry_metadata(&file_path)?);
+        assert!(
+            should_remap,
+            "File with UID {current_uid} should be identified for remapping"
+        );
+
+        Ok(())
+    }
+
+    /// Test UID-only flag decision logic - NO DRY RUN  
+    #[test]
+    fn test_should_remap_file_uid_only_flag() -> std::result::Result<(), Box<dyn std::error::Error>>
+    {
+        let temp_dir = TempDir::new()?;
+        let file_path = temp_dir.path().join("test_file.txt");
+        File::create(&file_path)?;
+
+        let current_uid = getuid().as_raw();
+
+        let args = RemapArgs {
+            base_directory: temp_dir.path().to_path_buf(),
+            from_base: current_uid,
+            to_base: current_uid + 1000,
+            range_size: 1,
+            dry_run: false, // NOT dry run - testing logic
+            verbose: false,
+            exclude: vec![],
+            uid_only: true, // Only check UIDs
+            gid_only: false,
+            ..Default::default()
+        };
+
+        let command = RemapCommand::new(args);
+        let should_remap = command.metadata_in_range(&command.entry_metadata(&file_path)?);
+        assert!(
+            should_remap,
+            "File with UID {current_uid} should be identified for UID-only remapping"
+        );
+
+        Ok(())
+    }
+
+    /// Test GID-only flag decision logic - NO DRY RUN
+    #[test]
+    fn test_should_remap_file_gid_only_flag() -> std::result::Result<(), Box<dyn std::error::Error>>
+    {
+        let temp_dir = TempDir::new()?;
+        let file_path = temp_dir.path().join("test_file.txt");
+        File::create(&file_path)?;
+
+        let current_gid = getgid().as_raw(); // Files typically get user's primary GID
+
+        let args = RemapArgs {
+            base_directory: temp_dir.path().to_path_buf(),
+            from_base: current_gid,
+            to_base: current_gid + 1000,
+            range_size: 1,
+            dry_run: false, // NOT dry run - testing logic
+            verbose: false,
+            exclude: vec![],
+            uid_only: false,
+            gid_only: true, // Only check GIDs
+            ..Default::default()
+        };
+
+        let command = RemapCommand::new(args);
+        let should_remap = command.metadata_in_range(&command.entry_metadata(&file_path)?);
+        assert!(
+            should_remap,
+            "File with GID {current_gid} should be identified for GID-only remapping"
+        );
+
+        Ok(())
+    }
+
+    /// Test files outside remap range - NO DRY RUN
+    #[test]
+    fn test_should_remap_file_out_of_range() -> std::result::Result<(), Box<dyn std::error::Error>>
+    {
+        let temp_dir = TempDir::new()?;
+        let file_path = temp_dir.path().join("test_file.txt");
+        File::create(&file_path)?;
+
+        // Use a range that definitely won't include current user
+        let args = RemapArgs {
+            base_directory: temp_dir.path().to_path_buf(),
+            from_base: 100000, // High UID range unlikely to match current user
+            to_base: 200000,
+            range_size: 65536,
+            dry_run: false, // NOT dry run - testing logic
+            verbose: false,
+            exclude: vec![],
+            uid_only: false,
+            gid_only: false,
+            ..Default::default()
+        };
+
+        let command = RemapCommand::new(args);
+        let should_remap = command.metadata_in_range(&command.entry_metadata(&file_path)?);
+        assert!(
+            !should_remap,
+            "File with current user ownership should not be in high UID range"
+        );
+
+        Ok(())
+    }
+
+    /// Test nonexistent directory error - NO DRY RUN
+    #[test]
+    fn test_execute_nonexistent_directory() {
+        let args = RemapArgs {
+            base_directory: PathBuf::from("/nonexistent/directory/that/does/not/exist"),
+            from_base: 100000,
+            to_base: 50000000,
+            range_size: 65536,
+            dry_run: false, // NOT dry run - testing error handling
+            verbose: false,
+            exclude: vec![],
+            uid_only: false,
+            gid_only: false,
+            ..Default::default()
+        };
+
+        let command = RemapCommand::new(args);
+        let result = command.execute();
+        assert!(result.is_err());
+
+        let error_msg = result.unwrap_err().to_string();
+        assert!(error_msg.contains("nonexistent") || error_msg.contains("not found"));
+    }
+
+    /// Test file instead of directory error - NO DRY RUN
+    #[test]
+    fn test_execute_file_instead_of_directory(
+    ) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = TempDir::new()?;
+        let file_path = temp_dir.path().join("test_file.txt");
+        File::create(&file_path)?;
+
+        let args = RemapArgs {
+            base_directory: file_path, // File instead of directory
+            from_base: 100000,
+            to_base: 50000000,
+            range_size: 65536,
+            dry_run: false, // NOT dry run - testing error handling
+            verbose: false,
+            exclude: vec![],
+            uid_only: false,
+            gid_only: false,
+            ..Default::default()
+        };
+
+        let command = RemapCommand::new(args);
+        let result = command.execute();
+        assert!(result.is_err());
+
+        let error_msg = result.unwrap_err().to_string();
+        assert!(error_msg.contains("not a directory"));
+
+        Ok(())
+    }
+
+    /// Test hard link detection - NO DRY RUN needed for this logic
+    #[test]
+    fn test_hard_link_detection() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = TempDir::new()?;
+        let file1 = temp_dir.path().join("file1.txt");
+        let file2 = temp_dir.path().join("file2.txt");
+
+        // Create original file
+        File::create(&file1)?;
+
+        // Create hard link
+        fs::hard_link(&file1, &file2)?;
+
+        let mut command = RemapCommand::new(RemapArgs {
+            base_directory: temp_dir.path().to_path_buf(),
+            from_base: 100000,
+            to_base: 50000000,
+            range_size: 65536,
+            dry_run: false, // NOT dry run - testing hard link logic
+            verbose: false,
+            exclude: vec![],
+            uid_only: false,
+            gid_only: false,
+            ..Default::default()
+        });
+
+        // Process first file
+        let result1 = command.process_file(&file1);
+        assert!(result1.is_ok());
+
+        // Process hard link - should be skipped due to inode tracking
+        let result2 = command.process_file(&file2);
+        assert!(result2.is_ok());
+
+        // Verify that the hard link was tracked
+        let metadata = get_file_metadata(&file1)?;
+        let key = (metadata.dev(), metadata.ino());
+        assert!(command.seen_inodes.contains_key(&key));
+
+        // Verify both files have the same inode
+        let metadata1 = get_file_metadata(&file1)?;
+        let metadata2 = get_file_metadata(&file2)?;
+        assert_eq!(metadata1.ino(), metadata2.ino());
+        assert_eq!(metadata1.dev(), metadata2.dev());
+
+        Ok(())
+    }
+
+    /// Test that --hardlinks fail aborts on links spanning directories
+    #[test]
+    fn test_hard_link_policy_fail_cross_directory(
+    ) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = TempDir::new()?;
+        let subdir = temp_dir.path().join("subdir");
+        fs::create_dir(&subdir)?;
+        let file1 = temp_dir.path().join("file1.txt");
+        let file2 = subdir.join("file2.txt");
+        let file3 = temp_dir.path().join("file3.txt");
+        File::create(&file1)?;
+        fs::hard_link(&file1, &file2)?;
+        fs::hard_link(&file1, &file3)?;
+
+        let mut command = RemapCommand::new(RemapArgs {
+            base_directory: temp_dir.path().to_path_buf(),
+            from_base: 100000,
+            to_base: 50000000,
+            hardlinks: HardLinkPolicy::Fail,
+            ..Default::default()
+        });
+
+        assert!(command.process_file(&file1).is_ok());
+        // Same directory as the first path is expected and tolerated
+        assert!(command.process_file(&file3).is_ok());
+
+        let result = command.process_file(&file2);
+        assert!(matches!(result, Err(RustUtilsError::UnexpectedHardLink(_))));
+
+        let command = RemapCommand::new(RemapArgs {
+            base_directory: temp_dir.path().to_path_buf(),
+            from_base: 100000,
+            to_base: 50000000,
+            dry_run: true,
+            hardlinks: HardLinkPolicy::Fail,
+            ..Default::default()
+        });
+        let result = command.execute();
+        assert!(result.is_err());
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .contains("Unexpected hard link"));
+
+        Ok(())
+    }
+
+    /// Test that --hardlinks all remaps later links from the first-seen ownership
+    #[test]
+    fn test_hard_link_policy_all() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = TempDir::new()?;
+        let file1 = temp_dir.path().join("file1.txt");
+        let file2 = temp_dir.path().join("file2.txt");
+        File::create(&file1)?;
+        fs::hard_link(&file1, &file2)?;
+
+        let current_uid = getuid().as_raw();
+
+        let mut command = RemapCommand::new(RemapArgs {
+            base_directory: temp_dir.path().to_path_buf(),
+            from_base: current_uid,
+            to_base: current_uid + 1000,
+            range_size: 1,
+            dry_run: true,
+            uid_only: true,
+            hardlinks: HardLinkPolicy::All,
+            ..Default::default()
+        });
+
+        assert!(command.process_file(&file1).is_ok());
+        assert!(command.process_file(&file2).is_ok());
+
+        let metadata = get_file_metadata(&file1)?;
+        let record = &command.seen_inodes[&(metadata.dev(), metadata.ino())];
+        assert_eq!(record.first_path, file1);
+        assert!(command.metadata_in_range(&record.metadata));
+
+        Ok(())
+    }
+
+    /// Test that --all-hardlinks processes every link path and both policies show in the
+    /// report
+    #[test]
+    fn test_execute_all_hardlinks() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        for all_hardlinks in [false, true] {
+            let temp_dir = TempDir::new()?;
+            let root = temp_dir.path();
+            fs::write(root.join("a"), "")?;
+            for link in ["b", "c"] {
+                fs::hard_link(root.join("a"), root.join(link))?;
+            }
+            for path in [root, &root.join("a")] {
+                lchown(path, Some(100000), Some(100000))?;
+            }
+
+            let report = RemapCommand::new(RemapArgs {
+                base_directory: root.to_path_buf(),
+                from_base: 100000,
+                to_base: 200000,
+                all_hardlinks,
+                ..Default::default()
+            })
+            .execute()?;
+            assert_eq!(report.counts["hardlink_paths"], 2);
+            let (skipped, remapped) = if all_hardlinks { (0, 4) } else { (2, 2) };
+            assert_eq!(report.counts["hardlink_paths_skipped"], skipped);
+            assert_eq!(report.counts["remapped"], remapped);
+            for link in ["a", "b", "c"] {
+                assert_eq!(fs::symlink_metadata(root.join(link))?.uid(), 200000);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_phases() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let args = RemapArgs {
+            from_base: 100000,
+            to_base: 130000,
+            map_uid: vec![
+                "100033:5000:1".parse()?,
+                "165530:7000:10".parse()?,
+                "10:11:1".parse()?,
+            ],
+            ..Default::default()
+        };
+        assert!(args.is_chained()?);
+        let temporary = args.temporary_base()?.unwrap();
+        assert_eq!(temporary, u32::MAX - 1 - (65536 + 4 + 1));
+        let [first, second] = args.phases(temporary);
+        let (first_uid, second_uid) = (first.id_map(IdKind::Uid)?, second.id_map(IdKind::Uid)?);
+        for id in [
+            100000, 100033, 165529, 165530, 165535, 165536, 165539, 10, 11,
+        ] {
+            let through = second_uid.map(first_uid.map(id));
+            assert_eq!(through, args.id_map(IdKind::Uid)?.map(id), "{id}");
+        }
+        assert!(!first_uid.is_chained() && !second_uid.is_chained());
+        assert_eq!(
+            second
+                .id_map(IdKind::Gid)?
+                .map(first.id_map(IdKind::Gid)?.map(100033)),
+            130033
+        );
+
+        // Below every mapping when the top of the ID space is taken
+        let args = RemapArgs {
+            from_base: u32::MAX - 70000,
+            to_base: u32::MAX - 69000,
+            range_size: 65536,
+            ..Default::default()
+        };
+        assert_eq!(args.temporary_base()?, Some(u32::MAX - 70000 - 65536));
+        let args = RemapArgs {
+            to_base: 200000,
+            ..args
+        };
+        assert_eq!(args.temporary_base()?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_execute_two_phase() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = TempDir::new()?;
+        let root = temp_dir.path().join("rootfs");
+        fs::create_dir(&root)?;
+        fs::write(root.join("a"), "")?;
+        fs::hard_link(root.join("a"), root.join("b"))?;
+        fs::write(root.join("c"), "")?;
+        for (path, id) in [("", 100000), ("a", 100005), ("c", 130000)] {
+            lchown(root.join(path), Some(id), Some(id))?;
+        }
+        let args = RemapArgs {
+            base_directory: root.clone(),
+            from_base: 100000,
+            to_base: 130000,
+            // Every path of the link is met, each a second look at its inode
+            all_hardlinks: true,
+            ..Default::default()
+        };
+        let owner = |path: &str| -> std::io::Result<u32> {
+            Ok(fs::symlink_metadata(root.join(path))?.uid())
+        };
+        let state_dir = temp_dir.path().join("state");
+        let run = |args: &RemapArgs| {
+            RemapCommand::new(args.clone())
+                .with_state_dir(Some(state_dir.clone()))
+                .execute()
+        };
+
+        let report = run(&RemapArgs {
+            dry_run: true,
+            ..args.clone()
+        })?;
+        assert_eq!(report.counts["remapped"], 4);
+        assert!(!report.counts.contains_key("temporary_base"));
+
+        let report = run(&args)?;
+        assert!(report.counts.contains_key("temporary_base"));
+        for (path, id) in [("", 130000), ("a", 130005), ("b", 130005), ("c", 160000)] {
+            assert_eq!(owner(path)?, id, "{path}");
+        }
+
+        // A run stopped during phase 2 finishes it, not starting over
+        let command = RemapCommand::new(args.clone()).with_state_dir(Some(state_dir.clone()));
+        let marker = StateDir::new(&state_dir).two_phase_marker(&command.job_key()?)?;
+        assert!(!marker.exists());
+        fs::File::create(&marker)?;
+        let temporary = args.temporary_base()?.unwrap();
+        lchown(
+            root.join("c"),
+            Some(temporary + 30000),
+            Some(temporary + 30000),
+        )?;
+        run(&args)?;
+        assert_eq!(owner("")?, 130000);
+        assert_eq!(owner("c")?, 160000);
+        assert!(!marker.exists());
+
+        // Checked against the final target, not the temporary one; IDs in both ranges are
+        // no sign of an earlier remap
+        fs::write(root.join("d"), "")?;
+        lchown(root.join("d"), Some(170000), Some(170000))?;
+        let error = run(&RemapArgs {
+            assert_unmapped: true,
+            ..args.clone()
+        })
+        .unwrap_err()
+        .to_string();
+        assert!(error.contains("d is owned by 170000:170000"), "{error}");
+        assert_eq!(owner("")?, 130000);
+
+        for undo_journal in [Some(temp_dir.path().join("undo")), None] {
+            let coordinate = undo_journal
+                .is_none()
+                .then(|| temp_dir.path().to_path_buf());
+            let error = run(&RemapArgs {
+                undo_journal,
+                coordinate,
+                ..args.clone()
+            })
+            .unwrap_err()
+            .to_string();
+            assert!(error.contains("overlapping ranges"), "{error}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_execute_already_mapped() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = TempDir::new()?;
+        let root = temp_dir.path();
+        for name in ["new", "mapped", "half", "host"] {
+            fs::write(root.join(name), "")?;
+        }
+        for (path, uid, gid) in [
+            ("", 100000, 100000),
+            ("new", 100000, 100000),
+            ("mapped", 200033, 200033),
+            ("half", 200033, 33),
+            ("host", 5, 5),
+        ] {
+            lchown(root.join(path), Some(uid), Some(gid))?;
+        }
+        let args = RemapArgs {
+            base_directory: root.to_path_buf(),
+            from_base: 100000,
+            to_base: 200000,
+            dry_run: true,
+            ..Default::default()
+        };
+
+        let report = RemapCommand::new(args.clone()).execute()?;
+        assert_eq!(report.counts["already_mapped"], 1);
+        let report = RemapCommand::new(RemapArgs {
+            uid_only: true,
+            ..args.clone()
+        })
+        .execute()?;
+        assert_eq!(report.counts["already_mapped"], 2);
+
+        let error = RemapCommand::new(RemapArgs {
+            assert_unmapped: true,
+            dry_run: false,
+            ..args.clone()
+        })
+        .execute()
+        .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(RustUtilsError::AlreadyMapped(_))
+        ));
+        assert!(error
+            .to_string()
+            .contains("mapped is owned by 200033:200033"));
+        // Nothing was changed
+        assert_eq!(fs::symlink_metadata(root)?.uid(), 100000);
+
+        fs::remove_file(root.join("mapped"))?;
+        RemapCommand::new(RemapArgs {
+            assert_unmapped: true,
+            dry_run: false,
+            ..args
+        })
+        .execute()?;
+        assert_eq!(fs::symlink_metadata(root.join("new"))?.uid(), 200000);
+        Ok(())
+    }
+
+    #[test]
+    fn test_execute_audit() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = TempDir::new()?;
+        let root = temp_dir.path();
+        for name in ["new", "mapped", "stray", "leaked", "group"] {
+            fs::write(root.join(name), "")?;
+        }
+        for (path, uid, gid) in [
+            ("", 100000, 100000),
+            ("new", 100033, 100033),
+            ("mapped", 200033, 200033),
+            ("stray", 0, 0),
+            ("leaked", 0, 0),
+            ("group", 100033, 7),
+        ] {
+            lchown(root.join(path), Some(uid), Some(gid))?;
+        }
+        let args = RemapArgs {
+            base_directory: root.to_path_buf(),
+            from_base: 100000,
+            to_base: 200000,
+            audit: true,
+            ..Default::default()
+        };
+
+        let mut command = RemapCommand::new(args.clone());
+        command.args.dry_run = true;
+        for path in ["", "new", "mapped", "stray", "leaked", "group"] {
+            command.process_file(&root.join(path))?;
+        }
+        assert_eq!(
+            command.unexpected.paths,
+            BTreeMap::from([
+                ((0, 0), vec![root.join("stray"), root.join("leaked")]),
+                ((100033, 7), vec![root.join("group")]),
+            ])
+        );
+
+        let report = RemapCommand::new(args.clone()).execute()?;
+        assert_eq!(report.counts["unexpected_entries"], 3);
+        assert_eq!(report.counts["unexpected_owners"], 2);
+        // Nothing is changed
+        assert_eq!(fs::symlink_metadata(root.join("new"))?.uid(), 100033);
+
+        // GIDs outside both ranges do not count under --uid-only
+        let report = RemapCommand::new(RemapArgs {
+            uid_only: true,
+            ..args
+        })
+        .execute()?;
+        assert_eq!(report.counts["unexpected_entries"], 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_execute_failure_classes() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = TempDir::new()?;
+        let root = temp_dir.path();
+        for name in ["locked", "open"] {
+            fs::write(root.join(name), "")?;
+        }
+        // Setting the attribute needs CAP_LINUX_IMMUTABLE and a filesystem that has it
+        let set = std::process::Command::new("chattr")
+            .arg("+i")
+            .arg(root.join("locked"))
+            .status();
+        if !set.is_ok_and(|status| status.success()) {
+            return Ok(());
+        }
+        let report = RemapCommand::new(RemapArgs {
+            base_directory: root.to_path_buf(),
+            from_base: 0,
+            to_base: 100000,
+            ..Default::default()
+        })
+        .execute();
+        std::process::Command::new("chattr")
+            .arg("-i")
+            .arg(root.join("locked"))
+            .status()?;
+
+        let report = report?;
+        assert_eq!(report.errors.len(), 1);
+        assert_eq!(report.counts["failed_immutable"], 1);
+        assert!(!report.counts.contains_key("failed_other"));
+        assert_eq!(fs::symlink_metadata(root.join("open"))?.uid(), 100000);
+        Ok(())
+    }
+
+    /// Test that probing predicts the outcome without changing ownership
+    #[test]
+    fn test_probe_dry_run() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = TempDir::new()?;
+        let file1 = temp_dir.path().join("file1.txt");
+        let file2 = temp_dir.path().join("file2.txt");
+        File::create(&file1)?;
+        File::create(&file2)?;
+
+        let current_uid = getuid().as_raw();
+
+        let command = RemapCommand::new(RemapArgs {
+            base_directory: temp_dir.path().to_path_buf(),
+            from_base: current_uid,
+            to_base: current_uid + 1000,
+            range_size: 1,
+            dry_run: true,
+            uid_only: true,
+            probe: true,
+            ..Default::default()
+        });
+        let report = command.execute()?;
+
+        // One probe for the single filesystem, covering the directory and both files
+        assert_eq!(report.counts["probed_filesystems"], 1);
+        let expected_failures = if probe::has_cap_chown()? { 0 } else { 3 };
+        assert_eq!(report.counts["predicted_failures"], expected_failures);
+        assert_eq!(report.filesystems.len(), 1);
+        assert_eq!(report.filesystems[0].entries, 3);
+        assert_eq!(report.filesystems[0].changed, 3);
+        for path in [temp_dir.path(), file1.as_path(), file2.as_path()] {
+            assert_eq!(get_file_metadata(path)?.uid(), current_uid);
+        }
+
+        Ok(())
+    }
+
+    /// Test that processes of the frozen cgroup do not block the remap and are thawed after
+    #[test]
+    fn test_freeze_fs_refused() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = TempDir::new()?;
+        let args = RemapArgs {
+            base_directory: temp_dir.path().to_path_buf(),
+            from_base: 100000,
+            to_base: 200000,
+            freeze_fs: true,
+            ..Default::default()
+        };
+        let error = RemapCommand::new(args).execute().unwrap_err();
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(RustUtilsError::InvalidArguments(message)) if message.contains("--freeze-cgroup")
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_freeze_cgroup() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = TempDir::new()?;
+        let rootfs = temp_dir.path().join("rootfs");
+        let cgroup = temp_dir.path().join("cgroup");
+        fs::create_dir(&rootfs)?;
+        fs::create_dir(&cgroup)?;
+        let mut container = std::process::Command::new("sleep")
+            .arg("30")
+            .current_dir(&rootfs)
+            .spawn()?;
+        // A stand-in for a cgroup v2 directory whose processes stop at once
+        fs::write(cgroup.join("cgroup.freeze"), "0")?;
+        fs::write(cgroup.join("cgroup.events"), "populated 1\nfrozen 1\n")?;
+        fs::write(cgroup.join("cgroup.procs"), format!("{}\n", container.id()))?;
+
+        let result = RemapCommand::new(RemapArgs {
+            base_directory: rootfs.clone(),
+            from_base: 100000,
+            to_base: 200000,
+            freeze_cgroup: Some(cgroup.clone()),
+            ..Default::default()
+        })
+        .execute();
+        container.kill()?;
+        container.wait()?;
+
+        let report = result?;
+        assert!(report.durations_ms.contains_key("frozen"));
+        assert_eq!(fs::read_to_string(cgroup.join("cgroup.freeze"))?, "0");
+
+        Ok(())
+    }
+
+    /// Test that partitions together remap the whole tree exactly once
+    #[test]
+    fn test_partitions_cover_tree() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = TempDir::new()?;
+        let tree = temp_dir.path().join("tree");
+        let state = temp_dir.path().join("state");
+        for name in ["home", "srv", "var", "opt"] {
+            fs::create_dir_all(tree.join(name))?;
+            File::create(tree.join(name).join("file"))?;
+        }
+        for entry in WalkDir::new(&tree) {
+            let entry = entry?;
+            nix::unistd::chown(entry.path(), Some(100005.into()), Some(100005.into()))?;
+        }
+
+        let mut entries = 0;
+        let mut units = 0;
+        for partition in ["1/2", "2/2"] {
+            let report = RemapCommand::new(RemapArgs {
+                base_directory: tree.clone(),
+                from_base: 100000,
+                to_base: 200000,
+                range_size: 65536,
+                partition: Some(partition.parse()?),
+                ..Default::default()
+            })
+            .with_state_dir(Some(state.clone()))
+            .execute()?;
+            entries += report.counts["entries"];
+            units += report.counts["units"];
+        }
+        assert_eq!(entries, 9);
+        // Four top-level directories and the base directory itself
+        assert_eq!(units, 5);
+        for entry in WalkDir::new(&tree) {
+            assert_eq!(entry?.metadata()?.uid(), 200005);
+        }
+        // Completed jobs leave no journal behind
+        assert_eq!(fs::read_dir(state.join("partitions"))?.count(), 0);
+
+        Ok(())
+    }
+
+    /// Test that --resume skips the units an interrupted job completed
+    #[test]
+    fn test_parallel_jobs() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = TempDir::new()?;
+        let mut reports = Vec::new();
+        for jobs in [1, 4] {
+            let tree = temp_dir.path().join(format!("tree{jobs}"));
+            for dir in ["a/b/c", "d", "e/f"] {
+                fs::create_dir_all(tree.join(dir))?;
+                File::create(tree.join(dir).join("file"))?;
+            }
+            fs::write(tree.join("top"), "data")?;
+            fs::hard_link(tree.join("a/b/c/file"), tree.join("d/link"))?;
+            for entry in WalkDir::new(&tree) {
+                let entry = entry?;
+                nix::unistd::chown(entry.path(), Some(100005.into()), Some(100007.into()))?;
+            }
+            // Out of range, so skipped like the second link to the hard-linked file
+            nix::unistd::chown(&tree.join("e/f/file"), Some(5.into()), Some(5.into()))?;
+
+            let args = |dry_run| RemapArgs {
+                base_directory: tree.clone(),
+                from_base: 100000,
+                to_base: 200000,
+                range_size: 65536,
+                dry_run,
+                jobs: NonZeroUsize::new(jobs).unwrap(),
+                ..Default::default()
+            };
+            let planned = RemapCommand::new(args(true)).execute()?;
+            assert_eq!(planned.counts["remapped"], 10);
+            assert_eq!(planned.counts["skipped"], 2);
+            let report = RemapCommand::new(args(false)).execute()?;
+            assert_eq!(report.counts["remapped"], 10);
+            assert_eq!(report.ranges.len(), 2);
+            for entry in WalkDir::new(&tree) {
+                let metadata = entry?.metadata()?;
+                if metadata.uid() != 5 {
+                    assert_eq!((metadata.uid(), metadata.gid()), (200005, 200007));
+                }
+            }
+            reports.push(report);
+        }
+
+        assert_eq!(reports[0].counts["entries"], 12);
+        assert_eq!(reports[0].counts, reports[1].counts);
+        assert_eq!(reports[0].filesystems, reports[1].filesystems);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_resume_subtrees() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = TempDir::new()?;
+        let tree = temp_dir.path().join("tree");
+        let state = temp_dir.path().join("state");
+        for name in ["done", "pending"] {
+            fs::create_dir_all(tree.join(name))?;
+            nix::unistd::chown(&tree.join(name), Some(100005.into()), None)?;
+        }
+        let args = |resume| RemapArgs {
+            base_directory: tree.clone(),
+            from_base: 100000,
+            to_base: 200000,
+            range_size: 65536,
+            subtree: vec![PathBuf::from("done"), PathBuf::from("pending")],
+            uid_only: true,
+            resume,
+            ..Default::default()
+        };
+
+        // An earlier run completed "done" and was then interrupted
+        let interrupted = RemapCommand::new(args(false)).with_state_dir(Some(state.clone()));
+        let mut journal = interrupted
+            .open_journal()?
+            .expect("subtree jobs keep a journal");
+        journal.complete(Path::new("done"))?;
+        drop(journal);
+
+        let report = RemapCommand::new(args(true))
+            .with_state_dir(Some(state.clone()))
+            .execute()?;
+        assert_eq!(report.counts["units"], 2);
+        assert_eq!(report.counts["units_resumed"], 1);
+        assert_eq!(fs::metadata(tree.join("done"))?.uid(), 100005);
+        assert_eq!(fs::metadata(tree.join("pending"))?.uid(), 200005);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_resume_after_failures() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = TempDir::new()?;
+        let tree = temp_dir.path().join("tree");
+        let state = temp_dir.path().join("state");
+        for dir in ["etc", "srv"] {
+            fs::create_dir_all(tree.join(dir))?;
+            fs::write(tree.join(dir).join("file"), "")?;
+        }
+        // Setting the attribute needs CAP_LINUX_IMMUTABLE and a filesystem that has it
+        let chattr = |flag: &str| {
+            std::process::Command::new("chattr")
+                .arg(flag)
+                .arg(tree.join("srv/file"))
+                .status()
+                .is_ok_and(|status| status.success())
+        };
+        if !chattr("+i") {
+            return Ok(());
+        }
+        let run = |resume| {
+            RemapCommand::new(RemapArgs {
+                base_directory: tree.clone(),
+                from_base: 0,
+                to_base: 100000,
+                resume,
+                ..Default::default()
+            })
+            .with_state_dir(Some(state.clone()))
+            .execute()
+        };
+        let report = run(false);
+        chattr("-i");
+        assert_eq!(report?.errors.len(), 1);
+        assert_eq!(fs::metadata(tree.join("etc/file"))?.uid(), 100000);
+
+        // Only the directory with the failure is walked again
+        lchown(tree.join("etc/file"), Some(7), None)?;
+        let report = run(true)?;
+        assert_eq!(report.counts["subtrees_resumed"], 1);
+        assert!(report.errors.is_empty());
+        assert_eq!(fs::metadata(tree.join("srv/file"))?.uid(), 100000);
+        assert_eq!(fs::metadata(tree.join("etc/file"))?.uid(), 7);
+        // The run that completes the tree leaves no journal behind
+        assert_eq!(fs::read_dir(state.join("partitions"))?.count(), 0);
+        Ok(())
+    }
+
+    /// Test that --verify walks the tree again and fails for entries left in the source range
+    #[test]
+    fn test_execute_verify() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = TempDir::new()?;
+        let tree = temp_dir.path().join("tree");
+        fs::create_dir_all(tree.join("srv"))?;
+        fs::write(tree.join("srv/file"), "")?;
+        fs::write(tree.join("stuck"), "")?;
+        let run = |from_base, to_base, verify_readonly| {
+            RemapCommand::new(RemapArgs {
+                base_directory: tree.clone(),
+                from_base,
+                to_base,
+                verify: true,
+                verify_readonly,
+                ..Default::default()
+            })
+            .execute()
+        };
+
+        let report = run(0, 100000, false)?;
+        assert!(report.errors.is_empty());
+        assert_eq!(report.counts["verified_entries"], 4);
+        assert_eq!(report.counts["verify_mismatches"], 0);
+
+        // Setting the attribute needs CAP_LINUX_IMMUTABLE and a filesystem that has it
+        let chattr = |flag: &str| {
+            std::process::Command::new("chattr")
+                .arg(flag)
+                .arg(tree.join("stuck"))
+                .status()
+                .is_ok_and(|status| status.success())
+        };
+        if !chattr("+i") {
+            return Ok(());
+        }
+        let report = run(100000, 200000, false);
+        chattr("-i");
+        let report = report?;
+        // The failed change, and the verify pass finding the entry unchanged
+        assert_eq!(report.errors.len(), 2);
+        assert_eq!(report.counts["verify_mismatches"], 1);
+        assert!(report.errors[1]
+            .message
+            .contains("still owned by 100000:100000"));
+
+        // Making the bind needs CAP_SYS_ADMIN
+        if ReadOnlyView::open(&tree).is_err() {
+            return Ok(());
+        }
+        let report = run(200000, 300000, true)?;
+        assert!(report.errors.is_empty(), "{:?}", report.errors);
+        assert_eq!(report.counts["verified_entries"], 4);
+        assert_eq!(fs::metadata(tree.join("stuck"))?.uid(), 100000);
+        Ok(())
+    }
+
+    /// Test that --spot-check stats a share of the changes again and reports those not kept
+    #[test]
+    fn test_spot_check() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let mut sampling = SpotCheck::default();
+        for i in 0..25 {
+            sampling.offer(10, Path::new(&i.to_string()), 0, Owner { uid: 0, gid: 0 });
+        }
+        let sampled: Vec<_> = sampling
+            .sampled
+            .iter()
+            .map(|(path, ..)| path.clone())
+            .collect();
+        assert_eq!(sampled, ["0", "10", "20"].map(PathBuf::from));
+
+        let temp_dir = TempDir::new()?;
+        for name in ["a", "b", "c"] {
+            fs::write(temp_dir.path().join(name), "")?;
+        }
+        let report = RemapCommand::new(RemapArgs {
+            base_directory: temp_dir.path().to_path_buf(),
+            from_base: 0,
+            to_base: 100000,
+            spot_check: Some(100),
+            ..Default::default()
+        })
+        .execute()?;
+        assert!(report.errors.is_empty());
+        assert_eq!(report.counts["spot_checked"], 4);
+        assert_eq!(report.counts["spot_check_mismatches"], 0);
+
+        // A change the filesystem did not keep, and an entry removed since
+        let command = RemapCommand::new(RemapArgs::default());
+        let path = temp_dir.path().join("a");
+        let device = fs::metadata(&path)?.dev();
+        let new = Owner {
+            uid: 200000,
+            gid: 200000,
+        };
+        command.spot_check.borrow_mut().sampled = vec![
+            (path.clone(), device, new),
+            (temp_dir.path().join("gone"), device, new),
+        ];
+        let mut report = RunReport::new("remap");
+        command.spot_check(&[], &mut report);
+        assert_eq!(report.counts["spot_checked"], 1);
+        assert_eq!(report.counts["spot_check_mismatches"], 1);
+        assert_eq!(report.errors.len(), 1);
+        assert_eq!(
+            report.errors[0].message,
+            "spot check: owned by 100000:100000 after the change to 200000:200000"
+        );
+        Ok(())
+    }
+
+    /// Test that a change below a directory swapped for a symlink stays inside the tree
+    #[test]
+    fn test_chown_by_descriptor() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = TempDir::new()?;
+        let tree = temp_dir.path().join("tree");
+        let outside = temp_dir.path().join("outside");
+        fs::create_dir_all(tree.join("dir"))?;
+        fs::create_dir(&outside)?;
+        for dir in [&tree.join("dir"), &outside] {
+            fs::write(dir.join("file"), "")?;
+            fs::set_permissions(dir.join("file"), Permissions::from_mode(0o4755))?;
+        }
+
+        let chown = Chown {
+            uid: Some(4242),
+            gid: None,
+            mode: Some(0o4755),
+            xattrs: None,
+            helper: None,
+            anchor: Some(Arc::new(Anchor::open(&tree)?)),
+        };
+        let restored = chown.apply(&tree.join("dir/file"))?;
+        assert_eq!(restored.modes, 1);
+        let metadata = fs::metadata(tree.join("dir/file"))?;
+        assert_eq!((metadata.uid(), metadata.mode() & 0o7777), (4242, 0o4755));
+
+        chown.apply(&tree)?;
+        assert_eq!(fs::metadata(&tree)?.uid(), 4242);
+
+        fs::rename(tree.join("dir"), temp_dir.path().join("moved"))?;
+        symlink(&outside, tree.join("dir"))?;
+        // Missing, but moves the directory kept open back to the root
+        chown.apply(&tree.join("file")).unwrap_err();
+        let error = chown.apply(&tree.join("dir/file")).unwrap_err();
+        assert_eq!(error.errno(), Some(nix::errno::Errno::ELOOP));
+        assert_ne!(fs::metadata(outside.join("file"))?.uid(), 4242);
+        Ok(())
+    }
+
+    /// Test that whether to change an entry is decided from the entry the change is made to
+    #[test]
+    fn test_decide_by_descriptor() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = TempDir::new()?;
+        let tree = temp_dir.path().join("tree");
+        let outside = temp_dir.path().join("outside");
+        fs::create_dir_all(tree.join("dir"))?;
+        fs::create_dir(&outside)?;
+        for dir in [&tree.join("dir"), &outside] {
+            fs::write(dir.join("file"), "")?;
+            nix::unistd::chown(&dir.join("file"), Some(100005.into()), Some(100005.into()))?;
+        }
+        let mut command = RemapCommand::new(RemapArgs {
+            base_directory: tree.clone(),
+            from_base: 100000,
+            to_base: 200000,
+            range_size: 65536,
+            ..Default::default()
+        });
+        command.anchor = Some(Arc::new(Anchor::open(&tree)?));
+        let metadata = command.entry_metadata(&tree.join("dir/file"))?;
+        assert!(command.metadata_in_range(&metadata));
+
+        fs::rename(tree.join("dir"), temp_dir.path().join("moved"))?;
+        symlink(&outside, tree.join("dir"))?;
+        // Missing, but moves the directory kept open back to the root
+        command.entry_metadata(&tree.join("file")).unwrap_err();
+        let error = command.process_file(&tree.join("dir/file")).unwrap_err();
+        assert_eq!(error.errno(), Some(nix::errno::Errno::ELOOP));
+        assert_eq!(fs::metadata(outside.join("file"))?.uid(), 100005);
+        Ok(())
+    }
 
-        for entry in entries? {
-            let path = entry.path();
+    /// Test that entries seen again through a bind mount are remapped and counted once
+    #[test]
+    fn test_execute_bind_mount_aliases() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        use nix::mount::{mount, umount2, MntFlags, MsFlags};
 
-            files_processed += 1;
+        let temp_dir = TempDir::new()?;
+        let tree = temp_dir.path().canonicalize()?.join("tree");
+        for dir in ["data", "again"] {
+            fs::create_dir_all(tree.join(dir))?;
+        }
+        fs::write(tree.join("data/file"), "")?;
+        // Mounting needs CAP_SYS_ADMIN
+        let bound = mount(
+            Some(&tree.join("data")),
+            &tree.join("again"),
+            None::<&str>,
+            MsFlags::MS_BIND,
+            None::<&str>,
+        );
+        if bound.is_err() {
+            return Ok(());
+        }
+        // Both runs shift by 1000, so an entry remapped twice would end up past 2000
+        let remap = |subtree: Vec<PathBuf>| {
+            RemapCommand::new(RemapArgs {
+                base_directory: tree.clone(),
+                from_base: 0,
+                to_base: 1000,
+                subtree,
+                allow_in_use: true,
+                ..Default::default()
+            })
+            .with_state_dir(Some(temp_dir.path().join("state")))
+            .execute()
+        };
+        let whole = remap(Vec::new());
+        let subtrees = remap(vec![PathBuf::from("data"), PathBuf::from("again")]);
+        let file = fs::metadata(tree.join("data/file"))?.uid();
+        umount2(&tree.join("again"), MntFlags::MNT_DETACH)?;
 
-            if let Err(e) = self.process_file(path) {
-                warn!("Failed to process {}: {}", path.display(), e);
-                continue;
-            }
+        let whole = whole?;
+        assert_eq!(whole.counts["aliases"], 1);
+        // The tree, again or data, and the file
+        assert_eq!(whole.counts["remapped"], 3);
+        let subtrees = subtrees?;
+        assert_eq!(subtrees.counts["remapped"], 2);
+        assert_eq!(file, 2000);
+        Ok(())
+    }
 
-            if self.should_remap_file(path)? {
-                files_remapped += 1;
-            }
+    /// Test that coordinated jobs skip units claimed or completed by others
+    #[test]
+    fn test_coordinate() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        use sha2::{Digest, Sha256};
 
-            if self.args.verbose && files_processed % 1000 == 0 {
-                info!(
-                    "Processed {} files, remapped {}",
-                    files_processed, files_remapped
-                );
-            }
+        let temp_dir = TempDir::new()?;
+        let tree = temp_dir.path().join("tree");
+        let shared = temp_dir.path().join("shared");
+        for name in ["home", "srv"] {
+            fs::create_dir_all(tree.join(name))?;
+            nix::unistd::chown(&tree.join(name), Some(100005.into()), None)?;
         }
+        // A job on another host is working on srv
+        fs::create_dir_all(shared.join("claims"))?;
+        let srv = hex(&Sha256::digest(b"srv")[..16]);
+        fs::write(shared.join("claims").join(srv), "elsewhere 1\n")?;
+
+        let run = || {
+            RemapCommand::new(RemapArgs {
+                base_directory: tree.clone(),
+                from_base: 100000,
+                to_base: 200000,
+                range_size: 65536,
+                uid_only: true,
+                coordinate: Some(shared.clone()),
+                ..Default::default()
+            })
+            .execute()
+        };
+        let report = run()?;
+        assert_eq!(report.counts["units"], 3);
+        assert_eq!(report.counts["units_claimed_elsewhere"], 1);
+        assert_eq!(fs::metadata(tree.join("home"))?.uid(), 200005);
+        assert_eq!(fs::metadata(tree.join("srv"))?.uid(), 100005);
+
+        let report = run()?;
+        assert_eq!(report.counts["units_resumed"], 2);
+        assert_eq!(report.counts["entries"], 0);
 
-        info!("Remapping completed");
-        info!("Files processed: {}", files_processed);
-        info!("Files remapped: {}", files_remapped);
+        // Jobs with other ranges cannot join
+        let other = RemapCommand::new(RemapArgs {
+            base_directory: tree.clone(),
+            from_base: 100000,
+            to_base: 300000,
+            coordinate: Some(shared.clone()),
+            ..Default::default()
+        });
+        assert!(other.execute().is_err());
 
         Ok(())
     }
 
-    fn validate_args(&self) -> RustUtilsResult<()> {
-        if self.args.from_base >= u32::MAX - self.args.range_size {
-            return Err(RustUtilsError::InvalidRange(
-                "from_base + range_size would overflow".to_string(),
-            ));
+    /// Test that entries with only one ID in the source range are counted
+    #[test]
+    fn test_preserve_xattrs() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        // Namespaced CAP_NET_RAW for the container whose root is the given UID
+        let capability = |rootid: u32| {
+            let mut value = 0x0300_0001u32.to_le_bytes().to_vec();
+            value.extend_from_slice(&(1u32 << 13).to_le_bytes());
+            value.extend_from_slice(&[0; 12]);
+            value.extend_from_slice(&rootid.to_le_bytes());
+            value
+        };
+        let temp_dir = TempDir::new()?;
+        let ping = temp_dir.path().join("ping");
+        File::create(&ping)?;
+        nix::unistd::chown(&ping, Some(100000.into()), Some(100000.into()))?;
+        if xattr::set(&ping, CAPABILITY_XATTR, &capability(100000)).is_err() {
+            // Setting file capabilities needs CAP_SETFCAP
+            return Ok(());
         }
+        let args = |from_base, to_base, jobs| RemapArgs {
+            base_directory: temp_dir.path().to_path_buf(),
+            from_base,
+            to_base,
+            jobs: NonZeroUsize::new(jobs).unwrap(),
+            ..Default::default()
+        };
 
-        if self.args.to_base >= u32::MAX - self.args.range_size {
-            return Err(RustUtilsError::InvalidRange(
-                "to_base + range_size would overflow".to_string(),
-            ));
-        }
+        let report = RemapCommand::new(args(100000, 200000, 1)).execute()?;
+        assert_eq!(report.counts["xattrs_restored"], 1);
+        assert_eq!(
+            xattr::get(&ping, CAPABILITY_XATTR)?,
+            Some(capability(200000))
+        );
 
-        if self.args.uid_only && self.args.gid_only {
-            return Err(RustUtilsError::InvalidRange(
-                "Cannot specify both --uid-only and --gid-only".to_string(),
-            ));
-        }
+        // The same with the changes applied on worker threads
+        let report = RemapCommand::new(args(200000, 100000, 2)).execute()?;
+        assert_eq!(report.counts["xattrs_restored"], 1);
+        assert_eq!(
+            xattr::get(&ping, CAPABILITY_XATTR)?,
+            Some(capability(100000))
+        );
+
+        // Without preserving, the capability goes with the chown
+        let no_preserve = RemapArgs {
+            no_preserve_xattrs: true,
+            ..args(100000, 200000, 1)
+        };
+        let report = RemapCommand::new(no_preserve).execute()?;
+        assert_eq!(report.counts["xattrs_restored"], 0);
+        assert_eq!(xattr::get(&ping, CAPABILITY_XATTR)?, None);
 
         Ok(())
     }
 
-    fn process_file(&mut self, path: &Path) -> RustUtilsResult<()> {
-        let metadata = get_file_metadata(path)?;
+    #[test]
+    fn test_restore_mode() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        use std::os::unix::fs::PermissionsExt;
 
-        // Check for hard links
-        if metadata.nlink() > 1 {
-            let key = (metadata.dev(), metadata.ino());
-            if let Some(first_path) = self.seen_inodes.get(&key) {
-                debug!(
-                    "Skipping hard link: {} -> {}",
-                    path.display(),
-                    first_path.display()
-                );
-                return Ok(());
+        let temp_dir = TempDir::new()?;
+        let entries = [("sudo", 0o4755), ("wall", 0o2755), ("tmp", 0o1777)];
+        for (name, mode) in entries {
+            let path = temp_dir.path().join(name);
+            if name == "tmp" {
+                fs::create_dir(&path)?;
+            } else {
+                File::create(&path)?;
             }
-            self.seen_inodes.insert(key, path.to_path_buf());
+            nix::unistd::chown(&path, Some(100000.into()), Some(100000.into()))?;
+            fs::set_permissions(&path, fs::Permissions::from_mode(mode))?;
         }
+        let mode = |name: &str| fs::metadata(temp_dir.path().join(name)).unwrap().mode() & 0o7777;
+        let args = |from_base, to_base, no_restore_mode| RemapArgs {
+            base_directory: temp_dir.path().to_path_buf(),
+            from_base,
+            to_base,
+            no_restore_mode,
+            ..Default::default()
+        };
 
-        if self.should_remap_file(path)? {
-            self.remap_file(path, &metadata)?;
+        let report = RemapCommand::new(args(100000, 200000, false)).execute()?;
+        for (name, expected) in entries {
+            assert_eq!(mode(name), expected, "{name}");
         }
+        // Directories keep their sticky bit through a chown anyway
+        assert_eq!(report.counts["modes_restored"], 2);
+
+        let report = RemapCommand::new(args(200000, 100000, true)).execute()?;
+        assert_eq!(report.counts["modes_restored"], 0);
+        assert_eq!(mode("sudo"), 0o755);
+        assert_eq!(mode("tmp"), 0o1777);
 
         Ok(())
     }
 
-    fn should_remap_file(&self, path: &Path) -> RustUtilsResult<bool> {
-        let metadata = get_file_metadata(path)?;
-        let uid = metadata.uid();
-        let gid = metadata.gid();
-
-        let uid_in_range =
-            uid >= self.args.from_base && uid < self.args.from_base + self.args.range_size;
-        let gid_in_range =
-            gid >= self.args.from_base && gid < self.args.from_base + self.args.range_size;
+    #[test]
+    fn test_execute_atomic_dirs() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        for jobs in [1, 4] {
+            let temp_dir = TempDir::new()?;
+            let root = temp_dir.path();
+            fs::create_dir_all(root.join("etc/ssl"))?;
+            fs::create_dir_all(root.join("srv"))?;
+            for file in ["etc/passwd", "etc/ssl/key", "srv/data"] {
+                fs::write(root.join(file), file)?;
+            }
+            fs::hard_link(root.join("etc/passwd"), root.join("etc/ssl/passwd"))?;
+            for entry in WalkDir::new(root) {
+                lchown(entry?.path(), Some(100000), Some(100000))?;
+            }
+            fs::set_permissions(root.join("etc/ssl/key"), Permissions::from_mode(0o4750))?;
+            let etc = fs::symlink_metadata(root.join("etc"))?.ino();
 
-        let should_remap = match (self.args.uid_only, self.args.gid_only) {
-            (true, false) => uid_in_range,
-            (false, true) => gid_in_range,
-            (false, false) => uid_in_range || gid_in_range,
-            (true, true) => unreachable!(), // Validated in validate_args
-        };
+            let report = RemapCommand::new(RemapArgs {
+                base_directory: root.to_path_buf(),
+                from_base: 100000,
+                to_base: 200000,
+                atomic_dirs: vec!["etc".to_string()],
+                jobs: NonZeroUsize::new(jobs).unwrap(),
+                ..Default::default()
+            })
+            .execute()?;
+            assert_eq!(report.counts["atomic_dirs"], 1);
+            assert_eq!(report.counts["remapped"], 7, "jobs: {jobs}");
+            // A new directory took the place of the old one
+            assert_ne!(fs::symlink_metadata(root.join("etc"))?.ino(), etc);
+            for entry in WalkDir::new(root) {
+                let entry = entry?;
+                assert_eq!(
+                    entry.metadata()?.uid(),
+                    200000,
+                    "{}",
+                    entry.path().display()
+                );
+            }
+            let key = fs::symlink_metadata(root.join("etc/ssl/key"))?;
+            assert_eq!(key.mode() & MODE_BITS, 0o4750);
+            assert_eq!(fs::symlink_metadata(root.join("etc/passwd"))?.nlink(), 2);
+            assert_eq!(
+                fs::read_to_string(root.join("etc/ssl/passwd"))?,
+                "etc/passwd"
+            );
+            assert_eq!(fs::read_dir(root)?.count(), 2);
+        }
 
-        Ok(should_remap)
+        Ok(())
     }
 
-    fn remap_file(&self, path: &Path, metadata: &Metadata) -> RustUtilsResult<()> {
-        let current_uid = metadata.uid();
-        let current_gid = metadata.gid();
-
-        let new_uid = if self.args.gid_only {
-            current_uid
-        } else if current_uid >= self.args.from_base
-            && current_uid < self.args.from_base + self.args.range_size
-        {
-            let offset = current_uid - self.args.from_base;
-            self.args.to_base + offset
-        } else {
-            current_uid
+    #[test]
+    fn test_audit_symlinks() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = TempDir::new()?;
+        let root = temp_dir.path();
+        fs::create_dir_all(root.join("keep"))?;
+        fs::create_dir_all(root.join("etc"))?;
+        File::create(root.join("keep/data"))?;
+        File::create(root.join("etc/conf"))?;
+        std::os::unix::fs::symlink("/keep/data", root.join("etc/data"))?;
+        std::os::unix::fs::symlink("conf", root.join("etc/conf.link"))?;
+        std::os::unix::fs::symlink("missing", root.join("etc/dangling"))?;
+        for entry in WalkDir::new(root) {
+            lchown(entry?.path(), Some(100000), Some(100000))?;
+        }
+        let args = RemapArgs {
+            base_directory: root.to_path_buf(),
+            from_base: 100000,
+            to_base: 200000,
+            exclude: vec!["keep".to_string()],
+            audit_symlinks: true,
+            ..Default::default()
         };
 
-        let new_gid = if self.args.uid_only {
-            current_gid
-        } else if current_gid >= self.args.from_base
-            && current_gid < self.args.from_base + self.args.range_size
-        {
-            let offset = current_gid - self.args.from_base;
-            self.args.to_base + offset
-        } else {
-            current_gid
+        // The link to an excluded file ends up remapped, its target does not
+        for dry_run in [true, false] {
+            let report = RemapCommand::new(RemapArgs {
+                dry_run,
+                ..args.clone()
+            })
+            .execute()?;
+            assert_eq!(report.counts["symlinks_audited"], 3);
+            assert_eq!(report.counts["symlinks_mixed"], 1, "dry run: {dry_run}");
+            assert_eq!(report.counts["symlinks_dangling"], 1);
+        }
+        let unit = Unit {
+            name: None,
+            root: root.to_path_buf(),
+            max_depth: usize::MAX,
         };
+        assert_eq!(
+            RemapCommand::new(args).audit_symlinks(&[unit], &[])?.listed,
+            [MixedSymlink {
+                path: root.join("etc/data"),
+                target: root.join("keep/data"),
+                owner: (200000, 200000),
+                target_owner: (100000, 100000),
+            }]
+        );
 
-        if (self.args.verbose || self.args.dry_run)
-            && (new_uid != current_uid || new_gid != current_gid)
-        {
-            info!(
-                "{}: {}:{} -> {}:{}{}",
-                path.display(),
-                current_uid,
-                current_gid,
-                new_uid,
-                new_gid,
-                if self.args.dry_run { " (dry run)" } else { "" }
-            );
+        Ok(())
+    }
+
+    #[test]
+    fn test_asymmetric_entries() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = TempDir::new()?;
+        for (name, uid, gid) in [
+            ("both", 100005, 100005),
+            ("uid", 100005, 0),
+            ("half-done", 100005, 200005),
+            ("gid", 0, 100005),
+        ] {
+            let path = temp_dir.path().join(name);
+            File::create(&path)?;
+            nix::unistd::chown(&path, Some(uid.into()), Some(gid.into()))?;
+        }
+        // Sockets and FIFOs are counted apart, as they are recreated anyway
+        let fifo = temp_dir.path().join("fifo");
+        nix::unistd::mkfifo(&fifo, nix::sys::stat::Mode::S_IRWXU)?;
+        let socket = temp_dir.path().join("socket");
+        let _listener = std::os::unix::net::UnixListener::bind(&socket)?;
+        for path in [&fifo, &socket] {
+            nix::unistd::chown(path, Some(100005.into()), Some(0.into()))?;
         }
+        let args = |uid_only| RemapArgs {
+            base_directory: temp_dir.path().to_path_buf(),
+            from_base: 100000,
+            to_base: 200000,
+            range_size: 65536,
+            dry_run: true,
+            uid_only,
+            ..Default::default()
+        };
 
-        if !self.args.dry_run && (new_uid != current_uid || new_gid != current_gid) {
-            let uid = if new_uid != current_uid {
-                Some(new_uid)
-            } else {
-                None
-            };
+        let report = RemapCommand::new(args(false)).execute()?;
+        assert_eq!(report.counts["asymmetric_uid"], 2);
+        assert_eq!(report.counts["asymmetric_gid"], 1);
+        assert_eq!(report.counts["asymmetric_ephemeral"], 2);
+        let types: Vec<_> = report
+            .entry_types
+            .iter()
+            .map(|summary| (summary.kind.as_str(), summary.ephemeral, summary.entries))
+            .collect();
+        assert_eq!(
+            types,
+            [
+                ("file", false, 4),
+                ("directory", false, 1),
+                ("socket", true, 1),
+                ("fifo", true, 1)
+            ]
+        );
 
-            let gid = if new_gid != current_gid {
-                Some(new_gid)
-            } else {
-                None
-            };
+        let command = RemapCommand::new(args(false));
+        let metadata = fs::metadata(temp_dir.path().join("half-done"))?;
+        let found = command
+            .asymmetry(Path::new("half-done"), &metadata)
+            .unwrap();
+        assert!(found.uid_in_range && found.other_mapped);
 
-            lchown(path, uid, gid).map_err(|e| {
-                RustUtilsError::RemapFailed(format!("Failed to chown {}: {}", path.display(), e))
-            })?;
-        }
+        // Expected when remapping only one of the two
+        let report = RemapCommand::new(args(true)).execute()?;
+        assert_eq!(report.counts["asymmetric_uid"], 0);
 
         Ok(())
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::fs::{self, File};
-    use std::os::unix::fs::{MetadataExt, symlink};
-    use tempfile::TempDir;
-    use nix::unistd::{getuid, geteuid};
 
-    /// Test argument validation logic - no filesystem operations needed
     #[test]
-    fn test_remap_args_validation() {
+    fn test_container_view_overrides() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = TempDir::new()?;
+        let path = temp_dir.path().join("www");
+        File::create(&path)?;
+        // Half done: the GID went through its override already
+        nix::unistd::chown(&path, Some(100005.into()), Some(900033.into()))?;
         let args = RemapArgs {
-            base_directory: PathBuf::from("/tmp"),
+            base_directory: temp_dir.path().to_path_buf(),
             from_base: 100000,
-            to_base: 50000000,
+            to_base: 200000,
             range_size: 65536,
-            dry_run: false,
-            verbose: false,
-            exclude: vec![],
-            uid_only: false,
-            gid_only: false,
+            map_gid: vec![IdMapping {
+                from: 100033,
+                to: 900033,
+                count: 1,
+            }],
+            dry_run: true,
+            view: View::Container,
+            ..Default::default()
         };
+        let owners = args.owner_view()?;
+        assert_eq!(owners.current((100005, 900033)), "5:33");
+        assert_eq!(owners.mapped((200005, 900033)), "5:33");
 
-        let command = RemapCommand::new(args);
-        assert!(command.validate_args().is_ok());
+        let report = RemapCommand::new(args).execute()?;
+        assert_eq!(report.counts["asymmetric_uid"], 1);
+        let ranges: Vec<_> = report
+            .ranges
+            .iter()
+            .map(|range| (range.kind.as_str(), range.from, range.to, range.count))
+            .collect();
+        assert_eq!(
+            ranges,
+            [
+                ("uid", 0, 0, 65536),
+                ("gid", 0, 0, 65536),
+                ("gid", 33, 33, 1)
+            ]
+        );
+        Ok(())
     }
 
-    /// Test overflow detection in validation
+    /// Test detection of inodes linked from outside the walked tree
     #[test]
-    fn test_remap_args_validation_from_base_overflow() {
-        let args = RemapArgs {
-            base_directory: PathBuf::from("/tmp"),
-            from_base: u32::MAX - 1000,
-            to_base: 50000000,
-            range_size: 65536, // This would overflow from_base + range_size
-            dry_run: false,
-            verbose: false,
-            exclude: vec![],
-            uid_only: false,
-            gid_only: false,
-        };
+    fn test_find_external_links() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = TempDir::new()?;
+        let tree = temp_dir.path().join("tree");
+        fs::create_dir(&tree)?;
+        let inside_a = tree.join("a.txt");
+        let inside_b = tree.join("b.txt");
+        let shared = tree.join("shared.txt");
+        File::create(&inside_a)?;
+        fs::hard_link(&inside_a, &inside_b)?;
+        File::create(&shared)?;
+        fs::hard_link(&shared, temp_dir.path().join("outside.txt"))?;
 
-        let command = RemapCommand::new(args);
-        let result = command.validate_args();
+        let paths = [inside_a.as_path(), inside_b.as_path(), shared.as_path()];
+        let external = find_external_links(paths.into_iter())?;
+        assert_eq!(
+            external,
+            vec![ExternalLink {
+                path: shared.clone(),
+                nlink: 2,
+                paths_seen: 1,
+            }]
+        );
+
+        let command = RemapCommand::new(RemapArgs {
+            base_directory: tree,
+            from_base: 100000,
+            to_base: 50000000,
+            dry_run: true,
+            fail_on_external_links: true,
+            ..Default::default()
+        });
+        let result = command.execute();
         assert!(result.is_err());
-        assert!(result.unwrap_err().to_string().contains("from_base + range_size would overflow"));
+        assert!(result.unwrap_err().to_string().contains("links outside"));
+
+        Ok(())
     }
 
-    /// Test to_base overflow detection
+    /// Test that custom visitors plug into the remap walk
     #[test]
-    fn test_remap_args_validation_to_base_overflow() {
+    fn test_execute_map_points() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = TempDir::new()?;
+        let root = temp_dir.path();
+        let files = [
+            ("www", 100033, 100033),
+            ("postgres", 170000, 100033),
+            ("other", 100034, 100001),
+        ];
+        for (name, uid, gid) in files {
+            fs::write(root.join(name), "")?;
+            lchown(root.join(name), Some(uid), Some(gid))?;
+        }
+        lchown(root, Some(100000), Some(100000))?;
+
         let args = RemapArgs {
-            base_directory: PathBuf::from("/tmp"),
+            base_directory: root.to_path_buf(),
             from_base: 100000,
-            to_base: u32::MAX - 1000,
-            range_size: 65536, // This would overflow to_base + range_size
-            dry_run: false,
-            verbose: false,
-            exclude: vec![],
-            uid_only: false,
-            gid_only: false,
+            to_base: 200000,
+            map_uid: vec![
+                idmap::parse_point("100033:3333")?,
+                idmap::parse_point("170000:5432")?,
+            ],
+            map_gid: vec![idmap::parse_point("100033:3333")?],
+            ..Default::default()
         };
+        let report = RemapCommand::new(args.clone()).execute()?;
+        assert_eq!(report.counts["remapped"], 4);
+        let owner = |name: &str| -> std::io::Result<(u32, u32)> {
+            let metadata = fs::symlink_metadata(root.join(name))?;
+            Ok((metadata.uid(), metadata.gid()))
+        };
+        assert_eq!(owner("www")?, (3333, 3333));
+        assert_eq!(owner("postgres")?, (5432, 3333));
+        assert_eq!(owner("other")?, (200034, 200001));
+        assert_eq!(owner("")?, (200000, 200000));
 
-        let command = RemapCommand::new(args);
-        let result = command.validate_args();
-        assert!(result.is_err());
-        assert!(result.unwrap_err().to_string().contains("to_base + range_size would overflow"));
+        // The range's own target of a point-mapped ID is not in the target range
+        let command = RemapCommand::new(args.clone());
+        assert!(command.is_target(IdKind::Uid, 5432));
+        assert!(!command.is_target(IdKind::Uid, 200033));
+        assert!(command.is_target(IdKind::Gid, 200034));
+
+        // Two IDs must not end up on one host ID
+        let error = RemapCommand::new(RemapArgs {
+            map_uid: vec![idmap::parse_point("170000:200001")?],
+            ..args
+        })
+        .execute()
+        .unwrap_err();
+        assert!(error.to_string().contains("map to the same ID"), "{error}");
+        Ok(())
     }
 
-    /// Test conflicting flags validation
     #[test]
-    fn test_remap_args_validation_both_uid_gid_only() {
+    fn test_load_named_overrides() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = TempDir::new()?;
+        let root = temp_dir.path();
+        fs::create_dir(root.join("etc"))?;
+        fs::write(
+            root.join("etc/passwd"),
+            "root:x:0:0::/root:/bin/sh\npostgres:x:999:998::/var/lib/postgresql:/bin/sh\n\
+             nobody:x:65534:65534::/:/bin/false\n",
+        )?;
+        fs::write(root.join("etc/group"), "root:x:0:\npostgres:x:998:\n")?;
+
         let args = RemapArgs {
-            base_directory: PathBuf::from("/tmp"),
+            base_directory: root.to_path_buf(),
             from_base: 100000,
-            to_base: 50000000,
-            range_size: 65536,
-            dry_run: false,
-            verbose: false,
-            exclude: vec![],
-            uid_only: true,
-            gid_only: true, // Both flags set - should error
+            to_base: 200000,
+            map_user: vec!["postgres:64000".parse()?],
+            map_group: vec!["postgres:64000".parse()?],
+            ..Default::default()
         };
+        args.check_ranges()?;
+        let mut loaded = args.clone();
+        loaded.load_overrides()?;
+        assert_eq!(loaded.map_uid, [idmap::parse_point("100999:64000")?]);
+        assert_eq!(loaded.map_gid, [idmap::parse_point("100998:64000")?]);
+        assert!(loaded.map_user.is_empty() && loaded.map_group.is_empty());
 
-        let command = RemapCommand::new(args);
-        let result = command.validate_args();
-        assert!(result.is_err());
-        assert!(result.unwrap_err().to_string().contains("Cannot specify both --uid-only and --gid-only"));
+        let error = RemapArgs {
+            map_user: vec!["mysql:64001".parse()?],
+            ..args.clone()
+        }
+        .check_ranges()
+        .unwrap_err();
+        assert!(error.to_string().contains("no user 'mysql'"), "{error}");
+        let error = RemapArgs {
+            map_user: vec!["nobody:64001".parse()?],
+            range_size: 65534,
+            ..args
+        }
+        .check_ranges()
+        .unwrap_err();
+        assert!(
+            error.to_string().contains("beyond the 65534 IDs"),
+            "{error}"
+        );
+        Ok(())
     }
 
-    /// Test decision logic for files with current user ownership - NO DRY RUN
     #[test]
-    fn test_should_remap_file_with_current_user_ownership() -> std::result::Result<(), Box<dyn std::error::Error>> {
+    fn test_load_idmap_overrides() -> std::result::Result<(), Box<dyn std::error::Error>> {
         let temp_dir = TempDir::new()?;
-        let file_path = temp_dir.path().join("test_file.txt");
-        File::create(&file_path)?;
-
-        let current_uid = getuid().as_raw();
-
-        // Test with current user's UID in the remap range
+        let config = temp_dir.path().join("config");
+        fs::write(
+            &config,
+            "lxc.idmap = g 0 300000 65536
+",
+        )?;
         let args = RemapArgs {
             base_directory: temp_dir.path().to_path_buf(),
-            from_base: current_uid,
-            to_base: current_uid + 1000,
-            range_size: 1, // Exactly matches current_uid
-            dry_run: false, // NOT dry run - testing decision logic
-            verbose: false,
-            exclude: vec![],
-            uid_only: false,
-            gid_only: false,
+            from_base: 100000,
+            to_base: 200000,
+            lxc: LxcIdMapArgs {
+                idmap: vec!["u 1000 64000 1".parse()?],
+                idmap_file: Some(config),
+            },
+            ..Default::default()
         };
+        let mut loaded = args.clone();
+        loaded.load_overrides()?;
+        assert_eq!(loaded.map_uid, [idmap::parse_point("101000:64000")?]);
+        assert_eq!(loaded.map_gid, ["100000:300000:65536".parse()?]);
+        assert_eq!(loaded.id_map(IdKind::Gid)?.get(100005), Some(300005));
+        assert!(loaded.lxc.is_empty());
 
-        let command = RemapCommand::new(args);
-        let should_remap = command.should_remap_file(&file_path)?;
-        assert!(should_remap, "File with UID {current_uid} should be identified for remapping");
-
+        let error = RemapArgs {
+            lxc: LxcIdMapArgs {
+                idmap: vec!["u 65000 64000 1000".parse()?],
+                idmap_file: None,
+            },
+            ..args.clone()
+        }
+        .check_ranges()
+        .unwrap_err();
+        assert!(
+            error.to_string().contains("beyond the 65536 IDs"),
+            "{error}"
+        );
+        let error = RemapArgs {
+            uid_only: true,
+            ..args
+        }
+        .check_ranges()
+        .unwrap_err();
+        assert!(error.to_string().contains("--uid-only"), "{error}");
         Ok(())
     }
 
-    /// Test UID-only flag decision logic - NO DRY RUN  
     #[test]
-    fn test_should_remap_file_uid_only_flag() -> std::result::Result<(), Box<dyn std::error::Error>> {
+    fn test_execute_throttled() -> std::result::Result<(), Box<dyn std::error::Error>> {
         let temp_dir = TempDir::new()?;
-        let file_path = temp_dir.path().join("test_file.txt");
-        File::create(&file_path)?;
-
-        let current_uid = getuid().as_raw();
+        let root = temp_dir.path();
+        for i in 0..10 {
+            fs::write(root.join(i.to_string()), "")?;
+            lchown(root.join(i.to_string()), Some(100000), Some(100000))?;
+        }
 
         let args = RemapArgs {
-            base_directory: temp_dir.path().to_path_buf(),
-            from_base: current_uid,
-            to_base: current_uid + 1000,
-            range_size: 1,
-            dry_run: false, // NOT dry run - testing logic
-            verbose: false,
-            exclude: vec![],
-            uid_only: true, // Only check UIDs
-            gid_only: false,
+            base_directory: root.to_path_buf(),
+            from_base: 100000,
+            to_base: 200000,
+            throttle: Some("200".parse()?),
+            jobs: NonZeroUsize::new(2).unwrap(),
+            ..Default::default()
         };
+        let started = Instant::now();
+        let report = RemapCommand::new(args).execute()?;
+        assert_eq!(report.counts["remapped"], 10);
+        // A read, a stat and a change of each entry, 5ms apart
+        assert!(started.elapsed() >= Duration::from_millis(150));
+        assert!(report.durations_ms["throttled"] > 0);
+        assert!(throttle::current().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_execute_known_groups_only() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = TempDir::new()?;
+        let root = temp_dir.path();
+        fs::create_dir(root.join("etc"))?;
+        fs::write(
+            root.join("etc/group"),
+            "root:x:0:
+www-data:x:33:
+",
+        )?;
+        let files = [
+            ("www", 100033, 100033),
+            ("orphan", 100500, 100500),
+            ("pinned", 100500, 100501),
+        ];
+        for (name, uid, gid) in files {
+            fs::write(root.join(name), "")?;
+            lchown(root.join(name), Some(uid), Some(gid))?;
+        }
+        for path in ["", "etc", "etc/group"] {
+            lchown(root.join(path), Some(100000), Some(100000))?;
+        }
 
-        let command = RemapCommand::new(args);
-        let should_remap = command.should_remap_file(&file_path)?;
-        assert!(should_remap, "File with UID {current_uid} should be identified for UID-only remapping");
+        let report = RemapCommand::new(RemapArgs {
+            base_directory: root.to_path_buf(),
+            from_base: 100000,
+            to_base: 200000,
+            known_groups_only: true,
+            map_gid: vec![idmap::parse_point("100501:4000")?],
+            ..Default::default()
+        })
+        .execute()?;
+        let owner = |name: &str| -> std::io::Result<(u32, u32)> {
+            let metadata = fs::symlink_metadata(root.join(name))?;
+            Ok((metadata.uid(), metadata.gid()))
+        };
+        assert_eq!(owner("www")?, (200033, 200033));
+        assert_eq!(owner("etc/group")?, (200000, 200000));
+        // UIDs are remapped as ever, and overrides apply whatever the groups
+        assert_eq!(owner("orphan")?, (200500, 100500));
+        assert_eq!(owner("pinned")?, (200500, 4000));
+        assert_eq!(report.counts["group_root"], 3);
+        assert_eq!(report.counts["group_www-data"], 1);
+        assert_eq!(report.counts["orphaned_gid_entries"], 1);
 
+        fs::remove_file(root.join("etc/group"))?;
+        let error = RemapCommand::new(RemapArgs {
+            base_directory: root.to_path_buf(),
+            from_base: 200000,
+            known_groups_only: true,
+            ..Default::default()
+        })
+        .execute()
+        .unwrap_err();
+        assert!(error.to_string().contains("does not exist"), "{error}");
         Ok(())
     }
 
-    /// Test GID-only flag decision logic - NO DRY RUN
     #[test]
-    fn test_should_remap_file_gid_only_flag() -> std::result::Result<(), Box<dyn std::error::Error>> {
+    fn test_execute_fakeroot_db() -> std::result::Result<(), Box<dyn std::error::Error>> {
         let temp_dir = TempDir::new()?;
-        let file_path = temp_dir.path().join("test_file.txt");
-        File::create(&file_path)?;
-
-        let current_gid = getgid().as_raw(); // Files typically get user's primary GID
+        let root = temp_dir.path().join("rootfs");
+        fs::create_dir_all(root.join("etc"))?;
+        fs::write(root.join("etc/passwd"), "")?;
+        fs::write(root.join("unrecorded"), "")?;
+        let state = temp_dir.path().join("state");
+        // As a fakeroot session would have recorded them
+        let mut db = FakerootDb::open(&state)?;
+        for path in ["", "etc", "etc/passwd"] {
+            db.set_owner(&fs::symlink_metadata(root.join(path))?, 100000, 100033);
+        }
+        db.save()?;
+        let on_disk = fs::symlink_metadata(root.join("etc/passwd"))?;
 
         let args = RemapArgs {
-            base_directory: temp_dir.path().to_path_buf(),
-            from_base: current_gid,
-            to_base: current_gid + 1000,
-            range_size: 1,
-            dry_run: false, // NOT dry run - testing logic
-            verbose: false,
-            exclude: vec![],
-            uid_only: false,
-            gid_only: true, // Only check GIDs
+            base_directory: root.clone(),
+            from_base: 100000,
+            to_base: 200000,
+            fakeroot_db: Some(state.clone()),
+            ..Default::default()
         };
+        let report = RemapCommand::new(RemapArgs {
+            dry_run: true,
+            ..args.clone()
+        })
+        .execute()?;
+        assert_eq!(report.counts["remapped"], 3);
+        assert_eq!(report.counts["fakeroot_owners"], 0);
 
-        let command = RemapCommand::new(args);
-        let should_remap = command.should_remap_file(&file_path)?;
-        assert!(should_remap, "File with GID {current_gid} should be identified for GID-only remapping");
-
+        let report = RemapCommand::new(args).execute()?;
+        assert_eq!(report.counts["remapped"], 3);
+        assert_eq!(report.counts["fakeroot_owners"], 3);
+        let db = FakerootDb::open(&state)?;
+        for path in ["", "etc", "etc/passwd"] {
+            let metadata = fs::symlink_metadata(root.join(path))?;
+            assert_eq!(db.owner(&metadata), (200000, 200033), "{path}");
+        }
+        // Nothing changed on disk
+        let metadata = fs::symlink_metadata(root.join("etc/passwd"))?;
+        assert_eq!(
+            (metadata.uid(), metadata.gid()),
+            (on_disk.uid(), on_disk.gid())
+        );
         Ok(())
     }
 
-    /// Test files outside remap range - NO DRY RUN
     #[test]
-    fn test_should_remap_file_out_of_range() -> std::result::Result<(), Box<dyn std::error::Error>> {
+    fn test_execute_with_custom_visitor() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        use std::cell::Cell;
+        use std::rc::Rc;
+
+        struct CountFiles(Rc<Cell<u32>>);
+
+        impl TreeVisitor for CountFiles {
+            fn name(&self) -> &str {
+                "count-files"
+            }
+
+            fn visit_file(
+                &mut self,
+                _path: &Path,
+                _metadata: &Metadata,
+                _ctx: &mut crate::pipeline::VisitContext,
+            ) -> crate::error::Result<()> {
+                self.0.set(self.0.get() + 1);
+                Ok(())
+            }
+        }
+
         let temp_dir = TempDir::new()?;
-        let file_path = temp_dir.path().join("test_file.txt");
-        File::create(&file_path)?;
+        fs::create_dir(temp_dir.path().join("dir"))?;
+        File::create(temp_dir.path().join("dir/a"))?;
+        File::create(temp_dir.path().join("b"))?;
 
-        // Use a range that definitely won't include current user
+        let count = Rc::new(Cell::new(0));
         let args = RemapArgs {
             base_directory: temp_dir.path().to_path_buf(),
-            from_base: 100000, // High UID range unlikely to match current user
+            from_base: 100000,
             to_base: 200000,
-            range_size: 65536,
-            dry_run: false, // NOT dry run - testing logic
-            verbose: false,
-            exclude: vec![],
-            uid_only: false,
-            gid_only: false,
+            dry_run: true,
+            ..Default::default()
         };
+        RemapCommand::new(args)
+            .with_visitor(Box::new(CountFiles(Rc::clone(&count))))
+            .execute()?;
+        assert_eq!(count.get(), 2);
 
-        let command = RemapCommand::new(args);
-        let should_remap = command.should_remap_file(&file_path)?;
-        assert!(!should_remap, "File with current user ownership should not be in high UID range");
-
-        Ok(())
-    }
-
-    /// Test nonexistent directory error - NO DRY RUN
-    #[test]
-    fn test_execute_nonexistent_directory() {
         let args = RemapArgs {
-            base_directory: PathBuf::from("/nonexistent/directory/that/does/not/exist"),
+            base_directory: temp_dir.path().to_path_buf(),
             from_base: 100000,
-            to_base: 50000000,
-            range_size: 65536,
-            dry_run: false, // NOT dry run - testing error handling
-            verbose: false,
-            exclude: vec![],
-            uid_only: false,
-            gid_only: false,
+            to_base: 200000,
+            dry_run: true,
+            with: vec!["count-files".to_string()],
+            ..Default::default()
         };
+        let result = RemapCommand::new(args)
+            .with_registry(VisitorRegistry::empty())
+            .execute();
+        assert!(result.unwrap_err().to_string().contains("unknown task"));
 
-        let command = RemapCommand::new(args);
-        let result = command.execute();
-        assert!(result.is_err());
-        
-        let error_msg = result.unwrap_err().to_string();
-        assert!(error_msg.contains("nonexistent") || error_msg.contains("not found"));
+        Ok(())
     }
 
-    /// Test file instead of directory error - NO DRY RUN
+    /// Test that --expect-clean stops at an entry whose owner changed after the plan check
     #[test]
-    fn test_execute_file_instead_of_directory() -> std::result::Result<(), Box<dyn std::error::Error>> {
-        let temp_dir = TempDir::new()?;
-        let file_path = temp_dir.path().join("test_file.txt");
-        File::create(&file_path)?;
+    fn test_execute_expect_clean() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        /// Changes the owner of a file once the walk has started, as another process might
+        struct Tamper(PathBuf);
+
+        impl TreeVisitor for Tamper {
+            fn name(&self) -> &str {
+                "tamper"
+            }
 
+            fn visit_dir(
+                &mut self,
+                _path: &Path,
+                _metadata: &Metadata,
+                _ctx: &mut crate::pipeline::VisitContext,
+            ) -> crate::error::Result<()> {
+                lchown(&self.0, Some(100005), Some(100005))?;
+                Ok(())
+            }
+        }
+
+        let temp_dir = TempDir::new()?;
+        let file = temp_dir.path().join("dir/file");
+        fs::create_dir(temp_dir.path().join("dir"))?;
+        File::create(&file)?;
+        for path in [temp_dir.path(), &temp_dir.path().join("dir"), &file] {
+            lchown(path, Some(100000), Some(100000))?;
+        }
         let args = RemapArgs {
-            base_directory: file_path, // File instead of directory
+            base_directory: temp_dir.path().to_path_buf(),
             from_base: 100000,
-            to_base: 50000000,
-            range_size: 65536,
-            dry_run: false, // NOT dry run - testing error handling
-            verbose: false,
-            exclude: vec![],
-            uid_only: false,
-            gid_only: false,
+            to_base: 200000,
+            ..Default::default()
         };
+        let planned = RemapCommand::new(RemapArgs {
+            dry_run: true,
+            ..args.clone()
+        })
+        .execute()?
+        .plan_hash;
 
-        let command = RemapCommand::new(args);
-        let result = command.execute();
-        assert!(result.is_err());
-        
-        let error_msg = result.unwrap_err().to_string();
-        assert!(error_msg.contains("not a directory"));
+        let result = RemapCommand::new(RemapArgs {
+            plan_hash: planned,
+            expect_clean: true,
+            ..args
+        })
+        .with_visitor(Box::new(Tamper(file.clone())))
+        .execute();
+        let error = result.unwrap_err().to_string();
+        assert!(error.contains("Unexpected change"), "{error}");
+        assert!(error.contains("not 100000:100000 as planned"), "{error}");
+        assert_eq!(fs::symlink_metadata(&file)?.uid(), 100005);
 
         Ok(())
     }
 
-    /// Test hard link detection - NO DRY RUN needed for this logic
     #[test]
-    fn test_hard_link_detection() -> std::result::Result<(), Box<dyn std::error::Error>> {
-        let temp_dir = TempDir::new()?;
-        let file1 = temp_dir.path().join("file1.txt");
-        let file2 = temp_dir.path().join("file2.txt");
+    fn test_execute_resume_by_xattr() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        /// Changes the owner of a file on coming to its directory, aborting an expect-clean run
+        struct Tamper(PathBuf);
 
-        // Create original file
-        File::create(&file1)?;
+        impl TreeVisitor for Tamper {
+            fn name(&self) -> &str {
+                "tamper"
+            }
 
-        // Create hard link
-        fs::hard_link(&file1, &file2)?;
+            fn visit_dir(
+                &mut self,
+                path: &Path,
+                _metadata: &Metadata,
+                _ctx: &mut crate::pipeline::VisitContext,
+            ) -> crate::error::Result<()> {
+                if self.0.parent() == Some(path) {
+                    lchown(&self.0, Some(100005), Some(100005))?;
+                }
+                Ok(())
+            }
+        }
 
-        let mut command = RemapCommand::new(RemapArgs {
+        let temp_dir = TempDir::new()?;
+        let marker = ResumeMarker::new("100000:200000:65536");
+        if let Err(e) = marker.mark(temp_dir.path()) {
+            assert_eq!(e.raw_os_error(), Some(libc::ENOTSUP));
+            return Ok(());
+        }
+        marker.clear(temp_dir.path())?;
+        let mut paths = vec![temp_dir.path().to_path_buf()];
+        for name in ["a", "b", "c", "d"] {
+            let dir = temp_dir.path().join(name);
+            fs::create_dir_all(dir.join("sub"))?;
+            File::create(dir.join("sub/file"))?;
+            paths.extend([dir.clone(), dir.join("sub"), dir.join("sub/file")]);
+        }
+        for path in &paths {
+            lchown(path, Some(100000), Some(100000))?;
+        }
+        let args = RemapArgs {
             base_directory: temp_dir.path().to_path_buf(),
             from_base: 100000,
-            to_base: 50000000,
-            range_size: 65536,
-            dry_run: false, // NOT dry run - testing hard link logic
-            verbose: false,
-            exclude: vec![],
-            uid_only: false,
-            gid_only: false,
-        });
-
-        // Process first file
-        let result1 = command.process_file(&file1);
-        assert!(result1.is_ok());
-
-        // Process hard link - should be skipped due to inode tracking
-        let result2 = command.process_file(&file2);
-        assert!(result2.is_ok());
+            to_base: 200000,
+            resume_by_xattr: true,
+            ..Default::default()
+        };
+        let planned = RemapCommand::new(RemapArgs {
+            dry_run: true,
+            ..args.clone()
+        })
+        .execute()?
+        .plan_hash;
 
-        // Verify that the hard link was tracked
-        let metadata = get_file_metadata(&file1)?;
-        let key = (metadata.dev(), metadata.ino());
-        assert!(command.seen_inodes.contains_key(&key));
+        let tampered = temp_dir.path().join("c/sub/file");
+        let result = RemapCommand::new(RemapArgs {
+            plan_hash: planned,
+            expect_clean: true,
+            ..args.clone()
+        })
+        .with_visitor(Box::new(Tamper(tampered.clone())))
+        .execute();
+        assert!(result.is_err());
+        // Directories the walk finished are marked, those it was inside of are not
+        let marked: Vec<&PathBuf> = paths.iter().filter(|p| marker.is_marked(p)).collect();
+        for dir in &marked {
+            assert!(!tampered.starts_with(dir), "{}", dir.display());
+            for path in paths.iter().filter(|p| p.starts_with(dir)) {
+                assert_eq!(fs::symlink_metadata(path)?.uid(), 200000);
+            }
+        }
 
-        // Verify both files have the same inode
-        let metadata1 = get_file_metadata(&file1)?;
-        let metadata2 = get_file_metadata(&file2)?;
-        assert_eq!(metadata1.ino(), metadata2.ino());
-        assert_eq!(metadata1.dev(), metadata2.dev());
+        let report = RemapCommand::new(args).execute()?;
+        let top_marked = marked
+            .iter()
+            .filter(|dir| {
+                !marked
+                    .iter()
+                    .any(|other| dir != &other && dir.starts_with(other))
+            })
+            .count();
+        assert_eq!(report.counts["directories_resumed"], top_marked as u64);
+        // Every directory ends up marked by one run or the other before the markers go
+        let dirs = paths.iter().filter(|p| p.is_dir()).count();
+        assert_eq!(report.counts["markers_removed"], dirs as u64);
+        for path in &paths {
+            let expected = if path == &tampered { 200005 } else { 200000 };
+            assert_eq!(fs::symlink_metadata(path)?.uid(), expected);
+            assert!(!marker.is_marked(path), "{}", path.display());
+        }
 
         Ok(())
     }
@@ -591,90 +6973,107 @@ mod tests {
             exclude: vec!["*.log".to_string(), "tmp".to_string()],
             uid_only: false,
             gid_only: false,
+            ..Default::default()
         };
 
         let command = RemapCommand::new(args);
         let result = command.execute();
-        assert!(result.is_ok(), "Exclusion pattern processing should succeed");
+        assert!(
+            result.is_ok(),
+            "Exclusion pattern processing should succeed"
+        );
 
         Ok(())
     }
 
     /// Test permission denied gracefully - NO DRY RUN (that's the point)
     #[test]
-    fn test_actual_remap_permission_denied_non_root() -> std::result::Result<(), Box<dyn std::error::Error>> {
-    // Skip if running as root
-    if geteuid().is_root() {
-        info!("Skipping permission test - running as root");
-        return Ok(());
-    }
-
-    let temp_dir = TempDir::new()?;
-    let file_path = temp_dir.path().join("test_file.txt");
-    File::create(&file_path)?;
-
-    let current_uid = getuid().as_raw();
-    let current_gid = getgid().as_raw(); // Use same for GID
-
-    // Verify the file actually has current user ownership and is in range
-    let metadata = get_file_metadata(&file_path)?;
-    let file_uid = metadata.uid();
-    let file_gid = metadata.gid();
-    
-    debug!("Test file ownership - UID: {}, GID: {}", file_uid, file_gid);
-    debug!("Current process - UID: {}, GID: {}", current_uid, current_gid);
-
-    // Create args that target files owned by current user
-    let args = RemapArgs {
-        base_directory: temp_dir.path().to_path_buf(),
-        from_base: file_uid, // Use actual file UID
-        to_base: file_uid + 1000, // This should fail for non-root
-        range_size: 1,
-        dry_run: false, // NOT dry run - testing actual permission failure
-        verbose: true,
-        exclude: vec![],
-        uid_only: false,
-        gid_only: false,
-    };
+    fn test_actual_remap_permission_denied_non_root(
+    ) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        // Skip if running as root
+        if geteuid().is_root() {
+            info!("Skipping permission test - running as root");
+            return Ok(());
+        }
 
-    // Verify the file would be identified for remapping
-    let command = RemapCommand::new(args);
-    let should_remap = command.should_remap_file(&file_path)?;
-    
-    if !should_remap {
-        // File won't be remapped, so test won't demonstrate permission failure
-        warn!("File UID {} not in range {}-{}, adjusting test", 
-              file_uid, file_uid, file_uid);
-        return Ok(());
-    }
+        let temp_dir = TempDir::new()?;
+        let file_path = temp_dir.path().join("test_file.txt");
+        File::create(&file_path)?;
 
-    debug!("File {} should be remapped (UID {} -> {})", 
-           file_path.display(), file_uid, file_uid + 1000);
+        let current_uid = getuid().as_raw();
+        let current_gid = getgid().as_raw(); // Use same for GID
 
-    let result = command.execute();
+        // Verify the file actually has current user ownership and is in range
+        let metadata = get_file_metadata(&file_path)?;
+        let file_uid = metadata.uid();
+        let file_gid = metadata.gid();
 
-    // Should fail due to permission denied when trying to lchown to arbitrary UID
-    if result.is_ok() {
-        // This might happen if the system allows the change for some reason
-        warn!("Expected permission failure but command succeeded - system may allow UID change");
-        return Ok(());
-    }
+        debug!("Test file ownership - UID: {}, GID: {}", file_uid, file_gid);
+        debug!(
+            "Current process - UID: {}, GID: {}",
+            current_uid, current_gid
+        );
+
+        // Create args that target files owned by current user
+        let args = RemapArgs {
+            base_directory: temp_dir.path().to_path_buf(),
+            from_base: file_uid,      // Use actual file UID
+            to_base: file_uid + 1000, // This should fail for non-root
+            range_size: 1,
+            dry_run: false, // NOT dry run - testing actual permission failure
+            verbose: true,
+            exclude: vec![],
+            uid_only: false,
+            gid_only: false,
+            ..Default::default()
+        };
 
-    // Verify we got the expected permission error
-    let error_message = format!("{}", result.unwrap_err());
-    debug!("Got expected error: {}", error_message);
-    
-    assert!(
-        error_message.contains("Operation not permitted") || 
-        error_message.contains("Permission denied") ||
-        error_message.contains("chown") ||
-        error_message.contains("lchown") ||
-        error_message.contains("RemapFailed"),
-        "Error should indicate permission/ownership issue, got: {error_message}"
-    );
+        // Verify the file would be identified for remapping
+        let command = RemapCommand::new(args);
+        let should_remap = command.metadata_in_range(&command.entry_metadata(&file_path)?);
 
-    Ok(())
-}
+        if !should_remap {
+            // File won't be remapped, so test won't demonstrate permission failure
+            warn!(
+                "File UID {} not in range {}-{}, adjusting test",
+                file_uid, file_uid, file_uid
+            );
+            return Ok(());
+        }
+
+        debug!(
+            "File {} should be remapped (UID {} -> {})",
+            file_path.display(),
+            file_uid,
+            file_uid + 1000
+        );
+
+        let result = command.execute();
+
+        // Should fail due to permission denied when trying to lchown to arbitrary UID
+        if result.is_ok() {
+            // This might happen if the system allows the change for some reason
+            warn!(
+                "Expected permission failure but command succeeded - system may allow UID change"
+            );
+            return Ok(());
+        }
+
+        // Verify we got the expected permission error
+        let error_message = format!("{}", result.unwrap_err());
+        debug!("Got expected error: {}", error_message);
+
+        assert!(
+            error_message.contains("Operation not permitted")
+                || error_message.contains("Permission denied")
+                || error_message.contains("chown")
+                || error_message.contains("lchown")
+                || error_message.contains("RemapFailed"),
+            "Error should indicate permission/ownership issue, got: {error_message}"
+        );
+
+        Ok(())
+    }
 
     // PRIVILEGED TESTS - These require root and test actual ownership changes
 
@@ -682,27 +7081,28 @@ mod tests {
     #[cfg(test)]
     #[ignore = "Requires root privileges - run with 'sudo cargo test test_symbolic_link_ownership_requires_root -- --ignored --nocapture'"]
     #[test]
-    fn test_symbolic_link_ownership_requires_root() -> std::result::Result<(), Box<dyn std::error::Error>> {
+    fn test_symbolic_link_ownership_requires_root(
+    ) -> std::result::Result<(), Box<dyn std::error::Error>> {
         let temp_dir = TempDir::new()?;
         let target_file = temp_dir.path().join("target.txt");
         let symlink_path = temp_dir.path().join("symlink");
-        
+
         File::create(&target_file)?;
         symlink(&target_file, &symlink_path)?;
-        
+
         const INITIAL_UID: u32 = 100000;
         const TARGET_UID: u32 = 200000;
-        
+
         // Set initial ownership - only works as root
         lchown(&target_file, Some(INITIAL_UID), Some(INITIAL_UID))?;
         lchown(&symlink_path, Some(INITIAL_UID), Some(INITIAL_UID))?;
-        
+
         // Verify initial state
         let target_before = get_file_metadata(&target_file)?;
         let symlink_before = get_file_metadata(&symlink_path)?;
         assert_eq!(target_before.uid(), INITIAL_UID);
         assert_eq!(symlink_before.uid(), INITIAL_UID);
-        
+
         let args = RemapArgs {
             base_directory: temp_dir.path().to_path_buf(),
             from_base: INITIAL_UID,
@@ -713,19 +7113,28 @@ mod tests {
             exclude: vec![],
             uid_only: false,
             gid_only: false,
+            ..Default::default()
         };
-        
+
         let command = RemapCommand::new(args);
         let result = command.execute();
         assert!(result.is_ok(), "Root should be able to change ownership");
-        
+
         // Verify both target and symlink were updated
         let target_after = get_file_metadata(&target_file)?;
         let symlink_after = get_file_metadata(&symlink_path)?;
-        
-        assert_eq!(target_after.uid(), TARGET_UID, "Target file UID should be updated");
-        assert_eq!(symlink_after.uid(), TARGET_UID, "Symbolic link UID should be updated with lchown");
-        
+
+        assert_eq!(
+            target_after.uid(),
+            TARGET_UID,
+            "Target file UID should be updated"
+        );
+        assert_eq!(
+            symlink_after.uid(),
+            TARGET_UID,
+            "Symbolic link UID should be updated with lchown"
+        );
+
         Ok(())
     }
 
@@ -733,32 +7142,33 @@ mod tests {
     #[cfg(test)]
     #[ignore = "Requires root privileges - run with 'sudo cargo test test_comprehensive_ownership_scenarios_requires_root -- --ignored --nocapture'"]
     #[test]
-    fn test_comprehensive_ownership_scenarios_requires_root() -> std::result::Result<(), Box<dyn std::error::Error>> {
+    fn test_comprehensive_ownership_scenarios_requires_root(
+    ) -> std::result::Result<(), Box<dyn std::error::Error>> {
         let temp_dir = TempDir::new()?;
-        
+
         // Create various file types
         let regular_file = temp_dir.path().join("regular.txt");
         let target_file = temp_dir.path().join("target.txt");
         let symlink_to_file = temp_dir.path().join("symlink_to_file");
         let subdir = temp_dir.path().join("subdir");
         let symlink_to_dir = temp_dir.path().join("symlink_to_dir");
-        
+
         File::create(&regular_file)?;
         File::create(&target_file)?;
         fs::create_dir(&subdir)?;
         symlink(&target_file, &symlink_to_file)?;
         symlink(&subdir, &symlink_to_dir)?;
-        
+
         const FROM_UID: u32 = 100000;
         const TO_UID: u32 = 200000;
-        
+
         // Set ownership on all files - requires root
         lchown(&regular_file, Some(FROM_UID), Some(FROM_UID))?;
         lchown(&target_file, Some(FROM_UID), Some(FROM_UID))?;
         lchown(&subdir, Some(FROM_UID), Some(FROM_UID))?;
         lchown(&symlink_to_file, Some(FROM_UID), Some(FROM_UID))?;
         lchown(&symlink_to_dir, Some(FROM_UID), Some(FROM_UID))?;
-        
+
         let args = RemapArgs {
             base_directory: temp_dir.path().to_path_buf(),
             from_base: FROM_UID,
@@ -769,25 +7179,281 @@ mod tests {
             exclude: vec![],
             uid_only: false,
             gid_only: false,
+            ..Default::default()
         };
-        
+
         let command = RemapCommand::new(args);
         let result = command.execute();
         assert!(result.is_ok());
-        
+
         // Verify all file types were updated correctly
         let regular_after = get_file_metadata(&regular_file)?;
         let target_after = get_file_metadata(&target_file)?;
         let subdir_after = get_file_metadata(&subdir)?;
         let symlink_file_after = get_file_metadata(&symlink_to_file)?;
         let symlink_dir_after = get_file_metadata(&symlink_to_dir)?;
-        
-        assert_eq!(regular_after.uid(), TO_UID, "Regular file should be updated");
+
+        assert_eq!(
+            regular_after.uid(),
+            TO_UID,
+            "Regular file should be updated"
+        );
         assert_eq!(target_after.uid(), TO_UID, "Target file should be updated");
         assert_eq!(subdir_after.uid(), TO_UID, "Directory should be updated");
-        assert_eq!(symlink_file_after.uid(), TO_UID, "Symbolic link to file should be updated");
-        assert_eq!(symlink_dir_after.uid(), TO_UID, "Symbolic link to directory should be updated");
-        
+        assert_eq!(
+            symlink_file_after.uid(),
+            TO_UID,
+            "Symbolic link to file should be updated"
+        );
+        assert_eq!(
+            symlink_dir_after.uid(),
+            TO_UID,
+            "Symbolic link to directory should be updated"
+        );
+
+        Ok(())
+    }
+
+    /// Test that `remap undo` restores the owners recorded by --undo-journal
+    #[test]
+    fn test_undo_journal() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = TempDir::new()?;
+        let tree = temp_dir.path().join("tree");
+        fs::create_dir_all(tree.join("dir"))?;
+        File::create(tree.join("dir/file"))?;
+        File::create(tree.join("moved"))?;
+        fs::hard_link(tree.join("dir/file"), tree.join("link"))?;
+        for entry in WalkDir::new(&tree) {
+            nix::unistd::chown(entry?.path(), Some(1000.into()), Some(1001.into()))?;
+        }
+        let journal = temp_dir.path().join("undo.journal");
+
+        let report = RemapCommand::new(RemapArgs {
+            base_directory: tree.clone(),
+            from_base: 0,
+            to_base: 100000,
+            hardlinks: HardLinkPolicy::All,
+            undo_journal: Some(journal.clone()),
+            ..Default::default()
+        })
+        .execute()?;
+        assert_eq!(report.counts["remapped"], 5);
+        assert_eq!(report.artifacts[0].kind, "undo-journal");
+        // Changed again after the remap, so left alone
+        nix::unistd::chown(&tree.join("moved"), Some(7.into()), None)?;
+
+        let undo = |dry_run| {
+            UndoCommand::new(UndoArgs {
+                journal: journal.clone(),
+                dry_run,
+                ..Default::default()
+            })
+            .execute()
+        };
+        let planned = undo(true)?;
+        // Without changes, the second link to the file still looks remapped
+        assert_eq!(planned.counts["remapped"], 4);
+        assert_eq!(get_file_metadata(&tree)?.uid(), 101000);
+
+        let report = undo(false)?;
+        assert_eq!(report.counts["entries"], 5);
+        assert_eq!(report.counts["remapped"], 3);
+        // The second link to the file found it restored
+        assert_eq!(report.counts["already_restored"], 1);
+        assert_eq!(report.errors.len(), 1);
+        assert_eq!(
+            report.errors[0].path.as_deref(),
+            Some(tree.join("moved").to_str().unwrap())
+        );
+        for path in [tree.clone(), tree.join("dir"), tree.join("dir/file")] {
+            let metadata = get_file_metadata(&path)?;
+            assert_eq!((metadata.uid(), metadata.gid()), (1000, 1001));
+        }
+        assert_eq!(get_file_metadata(&tree.join("moved"))?.uid(), 7);
+
+        // A second undo finds everything restored
+        assert_eq!(undo(false)?.counts["already_restored"], 4);
+
+        // The journal of an earlier run is never overwritten
+        let again = RemapCommand::new(RemapArgs {
+            base_directory: tree,
+            from_base: 0,
+            to_base: 100000,
+            undo_journal: Some(journal),
+            ..Default::default()
+        });
+        assert!(again.execute().is_err());
+
+        Ok(())
+    }
+
+    /// Test that `remap undo` restores the owners written by --snapshot-manifest
+    #[test]
+    fn test_snapshot_manifest() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = TempDir::new()?;
+        let tree = temp_dir.path().join("tree");
+        fs::create_dir_all(tree.join("dir"))?;
+        File::create(tree.join("dir/file"))?;
+        File::create(tree.join("mapped"))?;
+        for entry in WalkDir::new(&tree) {
+            nix::unistd::chown(entry?.path(), Some(1000.into()), Some(1001.into()))?;
+        }
+        nix::unistd::chown(
+            &tree.join("mapped"),
+            Some(100000.into()),
+            Some(100000.into()),
+        )?;
+        let manifest = temp_dir.path().join("owners.manifest");
+        let remap = |dry_run| {
+            RemapCommand::new(RemapArgs {
+                base_directory: tree.clone(),
+                from_base: 0,
+                to_base: 100000,
+                dry_run,
+                snapshot_manifest: Some(manifest.clone()),
+                ..Default::default()
+            })
+            .execute()
+        };
+
+        // A dry run changes nothing, so it has nothing to snapshot
+        remap(true)?;
+        assert!(!manifest.exists());
+
+        let report = remap(false)?;
+        assert_eq!(report.counts["remapped"], 3);
+        assert_eq!(report.artifacts[0].kind, "snapshot-manifest");
+        // Only the entries the run changes, from the owners they had before it
+        let entries = undo::read_journal(&manifest)?;
+        assert_eq!(entries.len(), 3);
+        assert!(entries.iter().all(|entry| entry.old
+            == Owner {
+                uid: 1000,
+                gid: 1001
+            }));
+
+        let report = UndoCommand::new(UndoArgs {
+            journal: manifest.clone(),
+            ..Default::default()
+        })
+        .execute()?;
+        assert_eq!(report.counts["remapped"], 3);
+        for path in [tree.clone(), tree.join("dir"), tree.join("dir/file")] {
+            let metadata = get_file_metadata(&path)?;
+            assert_eq!((metadata.uid(), metadata.gid()), (1000, 1001));
+        }
+        assert_eq!(get_file_metadata(&tree.join("mapped"))?.uid(), 100000);
+
+        // The manifest of an earlier run is never overwritten, and nothing changes
+        assert!(remap(false).is_err());
+        assert_eq!(get_file_metadata(&tree)?.uid(), 1000);
+
+        Ok(())
+    }
+
+    /// Test that `remap verify` finds the entries a remap did not give the owner expected
+    #[test]
+    fn test_verify_command() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = TempDir::new()?;
+        let tree = temp_dir.path().join("tree");
+        fs::create_dir_all(tree.join("dir"))?;
+        for name in ["dir/file", "service", "root"] {
+            File::create(tree.join(name))?;
+        }
+        for entry in WalkDir::new(&tree) {
+            nix::unistd::chown(entry?.path(), Some(1000.into()), Some(1000.into()))?;
+        }
+        nix::unistd::chown(&tree.join("service"), Some(33.into()), Some(33.into()))?;
+        nix::unistd::chown(&tree.join("root"), Some(500000.into()), Some(500000.into()))?;
+        let manifest = temp_dir.path().join("owners.manifest");
+        RemapCommand::new(RemapArgs {
+            base_directory: tree.clone(),
+            from_base: 0,
+            to_base: 100000,
+            map_uid: vec![idmap::parse_point("33:3333")?],
+            snapshot_manifest: Some(manifest.clone()),
+            ..Default::default()
+        })
+        .execute()?;
+
+        let verify = |args| VerifyCommand::new(args).execute();
+        let mapping = VerifyArgs {
+            base_directory: Some(tree.clone()),
+            from_base: Some(0),
+            to_base: Some(100000),
+            map_uid: vec![idmap::parse_point("33:3333")?],
+            ..Default::default()
+        };
+        let report = verify(mapping.clone())?;
+        assert_eq!(report.counts["entries"], 5);
+        assert_eq!(report.counts["mismatches"], 0);
+        // The owner outside both ranges only fails strict checks
+        let report = verify(VerifyArgs {
+            strict: true,
+            ..mapping.clone()
+        })?;
+        assert_eq!(report.counts["mismatches"], 1);
+        assert_eq!(
+            report.errors[0].message,
+            "uid 500000 is in neither range, gid 500000 is in neither range"
+        );
+
+        // Left behind, or changed since
+        nix::unistd::chown(&tree.join("dir/file"), Some(1000.into()), None)?;
+        let report = verify(mapping)?;
+        assert_eq!(report.counts["mismatches"], 1);
+        assert_eq!(
+            report.errors[0].path.as_deref(),
+            Some(tree.join("dir/file").to_str().unwrap())
+        );
+        assert_eq!(
+            report.errors[0].message,
+            "uid 1000 is still unmapped, to become 101000"
+        );
+
+        let report = verify(VerifyArgs {
+            manifest: Some(manifest),
+            ..Default::default()
+        })?;
+        assert_eq!(report.counts["entries"], 4);
+        assert_eq!(report.counts["mismatches"], 1);
+        assert_eq!(
+            report.errors[0].message,
+            "owned by 1000:101000, not 101000:101000 as remapped"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_subid_user() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = TempDir::new()?;
+        let subuid = temp_dir.path().join("subuid");
+        let subgid = temp_dir.path().join("subgid");
+        fs::write(&subuid, "12345:100000:1000\n12345:300000:65536\n")?;
+        fs::write(&subgid, "12345:300000:65536\n")?;
+        let args = |range_size| RemapArgs {
+            subid_user: Some("12345".to_string()),
+            subuid_file: subuid.clone(),
+            subgid_file: subgid.clone(),
+            range_size,
+            ..Default::default()
+        };
+
+        // The first range large enough is used
+        let mut remap = args(65536);
+        remap.apply_subid_user()?;
+        assert_eq!(remap.to_base, 300000);
+        let mut remap = args(1000);
+        assert!(remap.apply_subid_user().is_err());
+        remap.gid_only = true;
+        remap.apply_subid_user()?;
+        assert_eq!(remap.to_base, 300000);
+        remap.gid_only = false;
+        remap.uid_only = true;
+        remap.apply_subid_user()?;
+        assert_eq!(remap.to_base, 100000);
+        assert!(args(65537).apply_subid_user().is_err());
+
         Ok(())
     }
 }
@@ -0,0 +1,66 @@
+use std::path::PathBuf;
+
+use anyhow::Result;
+use clap::{Args, Subcommand};
+use tracing::info;
+
+use crate::report::RunReport;
+
+#[derive(Args)]
+pub struct ReportArgs {
+    #[command(subcommand)]
+    pub command: ReportCommands,
+}
+
+#[derive(Subcommand)]
+pub enum ReportCommands {
+    /// Combine the run reports of jobs that together made up one run, e.g. partitions
+    Merge(MergeArgs),
+}
+
+#[derive(Args)]
+pub struct MergeArgs {
+    /// Run reports to merge
+    #[arg(required = true)]
+    pub inputs: Vec<PathBuf>,
+
+    /// Where to write the merged report
+    #[arg(long, short)]
+    pub output: PathBuf,
+}
+
+pub struct ReportCommand {
+    args: ReportArgs,
+}
+
+impl ReportCommand {
+    pub fn new(args: ReportArgs) -> Self {
+        Self { args }
+    }
+
+    pub fn execute(self) -> Result<RunReport> {
+        match self.args.command {
+            ReportCommands::Merge(args) => {
+                let reports = args
+                    .inputs
+                    .iter()
+                    .map(|path| RunReport::read(path))
+                    .collect::<crate::error::Result<Vec<_>>>()?;
+                let merged = RunReport::merge(&reports)?;
+                merged.write(&args.output)?;
+                info!(
+                    "Merged {} {} reports into {}",
+                    reports.len(),
+                    merged.command,
+                    args.output.display()
+                );
+
+                let mut report = RunReport::new("report-merge");
+                report
+                    .count("reports", reports.len() as u64)
+                    .artifact("report", &args.output);
+                Ok(report)
+            }
+        }
+    }
+}
@@ -0,0 +1,109 @@
+use std::io::{self, Write};
+
+use anyhow::Result;
+use clap::{Args, ValueEnum};
+use schemars::schema::RootSchema;
+use schemars::schema_for;
+
+use crate::progress;
+use crate::report::RunReport;
+
+#[derive(Args)]
+pub struct SchemaArgs {
+    /// Machine-readable format whose schema should be printed
+    #[arg(value_enum)]
+    pub format: SchemaFormat,
+}
+
+/// Formats with a published JSON Schema.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum SchemaFormat {
+    /// Run report written by `--report`
+    Report,
+    /// Progress event written to `--progress-fd`, one per line
+    Event,
+}
+
+impl SchemaFormat {
+    /// JSON Schema generated from the type the format is serialized from.
+    pub fn schema(self) -> RootSchema {
+        match self {
+            SchemaFormat::Report => schema_for!(RunReport),
+            SchemaFormat::Event => progress::event_schema(),
+        }
+    }
+}
+
+pub struct SchemaCommand {
+    args: SchemaArgs,
+}
+
+impl SchemaCommand {
+    pub fn new(args: SchemaArgs) -> Self {
+        Self { args }
+    }
+
+    pub fn execute(self) -> Result<RunReport> {
+        let schema = self.args.format.schema();
+        let mut stdout = io::stdout().lock();
+        serde_json::to_writer_pretty(&mut stdout, &schema)?;
+        writeln!(stdout)?;
+        Ok(RunReport::new("schema"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    #[test]
+    fn test_report_schema() {
+        let schema = serde_json::to_value(SchemaFormat::Report.schema()).unwrap();
+        assert_eq!(schema["title"], "RunReport");
+        let required: Vec<&str> = schema["required"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|v| v.as_str().unwrap())
+            .collect();
+        for field in [
+            "command",
+            "success",
+            "counts",
+            "durations_ms",
+            "errors",
+            "artifacts",
+        ] {
+            assert!(required.contains(&field), "{field}");
+        }
+        // Optional paths of errors are not required
+        let error = &schema["definitions"]["ReportError"];
+        assert_eq!(error["required"], serde_json::json!(["message"]));
+    }
+
+    #[test]
+    fn test_event_schema_matches_events() {
+        let schema = serde_json::to_value(SchemaFormat::Event.schema()).unwrap();
+        let properties = schema["properties"].as_object().unwrap();
+        for field in [
+            "event",
+            "command",
+            "elapsed_ms",
+            "entries",
+            "changed",
+            "bytes",
+            "current",
+        ] {
+            assert!(properties.contains_key(field), "{field}");
+        }
+        assert_eq!(
+            schema["definitions"]["EventKind"]["enum"],
+            serde_json::json!(["start", "progress", "done"])
+        );
+        assert!(!schema["required"]
+            .as_array()
+            .unwrap()
+            .contains(&Value::from("current")));
+    }
+}
@@ -0,0 +1,418 @@
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use anyhow::Result;
+use clap::Args;
+use tracing::{debug, info};
+
+use crate::error::{Result as RustUtilsResult, RustUtilsError};
+use crate::idmap::{self, IdMap, IdMapping};
+use crate::lxc::LxcIdMapArgs;
+use crate::report::RunReport;
+
+/// Magic at the start of every `btrfs send` stream, including the trailing NUL.
+const STREAM_MAGIC: &[u8; 13] = b"btrfs-stream\0";
+/// Stream header: magic followed by a le32 version.
+const STREAM_HEADER_LEN: usize = STREAM_MAGIC.len() + 4;
+/// Highest stream version whose command framing is known (v3 added fs-verity).
+const MAX_STREAM_VERSION: u32 = 3;
+
+/// Command header: le32 payload length, le16 command, le32 CRC32C.
+const CMD_HEADER_LEN: usize = 10;
+/// Largest command `btrfs send` emits: its v2 buffer of 16 KiB plus the largest compressed
+/// extent, aligned to 64 KiB. Longer payloads are corrupt, so are refused before allocating.
+const MAX_COMMAND_LEN: usize = 192 * 1024;
+const BTRFS_SEND_C_CHOWN: u16 = 19;
+const BTRFS_SEND_A_UID: u16 = 6;
+const BTRFS_SEND_A_GID: u16 = 7;
+
+#[derive(Args)]
+pub struct SendStreamArgs {
+    /// ID mapping applied to both UIDs and GIDs (repeatable)
+    #[arg(
+        long = "map",
+        value_name = "FROM:TO:COUNT",
+        required_unless_present_any = ["idmap", "idmap_file"]
+    )]
+    pub map: Vec<IdMapping>,
+
+    #[command(flatten)]
+    pub lxc: LxcIdMapArgs,
+}
+
+/// Counters reported after translating a stream.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct StreamStats {
+    pub streams: u64,
+    pub commands: u64,
+    pub chowns: u64,
+    /// Individual UID/GID attributes whose value changed
+    pub remapped: u64,
+}
+
+pub struct SendStreamCommand {
+    args: SendStreamArgs,
+}
+
+impl SendStreamCommand {
+    pub fn new(args: SendStreamArgs) -> Self {
+        Self { args }
+    }
+
+    /// Translate a stream from stdin to stdout.
+    pub fn execute(self) -> Result<RunReport> {
+        let (uid_map, gid_map) = self.args.lxc.id_maps(&self.args.map)?;
+        idmap::log_mappings(&uid_map, &gid_map);
+
+        let stdin = io::stdin().lock();
+        let stdout = io::stdout().lock();
+        let stats = translate(
+            BufReader::new(stdin),
+            BufWriter::new(stdout),
+            &uid_map,
+            &gid_map,
+        )?;
+
+        info!(
+            "Translated {} stream(s), {} commands",
+            stats.streams, stats.commands
+        );
+        info!("Ownership commands: {}", stats.chowns);
+        info!("IDs remapped: {}", stats.remapped);
+
+        let mut report = RunReport::new("send-stream");
+        report
+            .count("streams", stats.streams)
+            .count("commands", stats.commands)
+            .count("chowns", stats.chowns)
+            .count("remapped", stats.remapped)
+            .artifact("stream", Path::new("-"));
+        Ok(report)
+    }
+}
+
+/// Copy a (possibly multi-subvolume) `btrfs send` stream from `input` to `output`, rewriting
+/// the UID and GID attributes of every CHOWN command through `uid_map` and `gid_map`.
+///
+/// All other commands pass through byte for byte. Checksums of incoming commands are
+/// verified so corruption is not hidden by the recomputed CRC of rewritten ones.
+pub fn translate<R: Read, W: Write>(
+    mut input: R,
+    mut output: W,
+    uid_map: &IdMap,
+    gid_map: &IdMap,
+) -> RustUtilsResult<StreamStats> {
+    let mut stats = StreamStats::default();
+    let mut header = [0u8; CMD_HEADER_LEN];
+
+    loop {
+        // A boundary is either EOF, the start of another stream, or a command header
+        let mut prefix = [0u8; 4];
+        if !read_or_eof(&mut input, &mut prefix)? {
+            break;
+        }
+
+        if prefix == STREAM_MAGIC[..4] {
+            let mut stream_header = [0u8; STREAM_HEADER_LEN];
+            stream_header[..4].copy_from_slice(&prefix);
+            input.read_exact(&mut stream_header[4..])?;
+            check_stream_header(&stream_header)?;
+            output.write_all(&stream_header)?;
+            stats.streams += 1;
+            continue;
+        }
+        if stats.streams == 0 {
+            return Err(invalid_stream("missing btrfs-stream header"));
+        }
+
+        header[..4].copy_from_slice(&prefix);
+        input.read_exact(&mut header[4..])?;
+        let len = u32::from_le_bytes(header[0..4].try_into().unwrap()) as usize;
+        let cmd = u16::from_le_bytes(header[4..6].try_into().unwrap());
+        let crc = u32::from_le_bytes(header[6..10].try_into().unwrap());
+
+        if len > MAX_COMMAND_LEN - CMD_HEADER_LEN {
+            return Err(invalid_stream(&format!(
+                "command {} (type {cmd}) claims {len} bytes, more than btrfs send writes",
+                stats.commands + 1
+            )));
+        }
+        let mut payload = vec![0u8; len];
+        input.read_exact(&mut payload)?;
+        stats.commands += 1;
+
+        if command_crc(&header, &payload) != crc {
+            return Err(invalid_stream(&format!(
+                "checksum mismatch in command {} (type {cmd})",
+                stats.commands
+            )));
+        }
+
+        if cmd == BTRFS_SEND_C_CHOWN {
+            stats.chowns += 1;
+            let changed = remap_attributes(&mut payload, uid_map, gid_map)?;
+            if changed > 0 {
+                stats.remapped += changed;
+                let crc = command_crc(&header, &payload);
+                header[6..10].copy_from_slice(&crc.to_le_bytes());
+            }
+        }
+
+        output.write_all(&header)?;
+        output.write_all(&payload)?;
+    }
+
+    output.flush()?;
+    debug!("Stream stats: {:?}", stats);
+    Ok(stats)
+}
+
+fn check_stream_header(header: &[u8; STREAM_HEADER_LEN]) -> RustUtilsResult<()> {
+    if header[..STREAM_MAGIC.len()] != STREAM_MAGIC[..] {
+        return Err(invalid_stream("bad stream magic"));
+    }
+    let version = u32::from_le_bytes(header[STREAM_MAGIC.len()..].try_into().unwrap());
+    if version == 0 || version > MAX_STREAM_VERSION {
+        return Err(invalid_stream(&format!(
+            "unsupported stream version {version}"
+        )));
+    }
+    debug!("btrfs send stream version {}", version);
+    Ok(())
+}
+
+/// Rewrite UID/GID attributes of a CHOWN payload in place, returning how many changed.
+fn remap_attributes(payload: &mut [u8], uid_map: &IdMap, gid_map: &IdMap) -> RustUtilsResult<u64> {
+    let mut changed = 0;
+    let mut pos = 0;
+
+    while pos < payload.len() {
+        if payload.len() - pos < 4 {
+            return Err(invalid_stream("truncated attribute header"));
+        }
+        let kind = u16::from_le_bytes([payload[pos], payload[pos + 1]]);
+        let len = u16::from_le_bytes([payload[pos + 2], payload[pos + 3]]) as usize;
+        let value = pos + 4..pos + 4 + len;
+        if value.end > payload.len() {
+            return Err(invalid_stream("attribute exceeds command length"));
+        }
+
+        let idmap = match kind {
+            BTRFS_SEND_A_UID => Some(uid_map),
+            BTRFS_SEND_A_GID => Some(gid_map),
+            _ => None,
+        };
+        if let (Some(idmap), 8) = (idmap, len) {
+            let id = u64::from_le_bytes(payload[value.clone()].try_into().unwrap());
+            if let Ok(id) = u32::try_from(id) {
+                let mapped = idmap.map(id);
+                if mapped != id {
+                    payload[value.clone()].copy_from_slice(&u64::from(mapped).to_le_bytes());
+                    changed += 1;
+                }
+            }
+        }
+
+        pos = value.end;
+    }
+
+    Ok(changed)
+}
+
+/// CRC32C of a command with its checksum field zeroed, as computed by `btrfs send`.
+fn command_crc(header: &[u8; CMD_HEADER_LEN], payload: &[u8]) -> u32 {
+    let mut zeroed = *header;
+    zeroed[6..10].fill(0);
+    let crc = crc32c(0, &zeroed);
+    crc32c(crc, payload)
+}
+
+/// Lookup table for the reflected CRC32C (Castagnoli) polynomial.
+const CRC32C_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0x82F6_3B78
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// Raw CRC32C without pre/post inversion, matching btrfs' `crc32c(seed, ...)`.
+fn crc32c(crc: u32, data: &[u8]) -> u32 {
+    data.iter().fold(crc, |crc, &byte| {
+        CRC32C_TABLE[((crc ^ u32::from(byte)) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+/// Fill `buf`, returning `false` on a clean EOF before the first byte.
+fn read_or_eof<R: Read>(input: &mut R, buf: &mut [u8]) -> RustUtilsResult<bool> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match input.read(&mut buf[filled..]) {
+            Ok(0) if filled == 0 => return Ok(false),
+            Ok(0) => return Err(invalid_stream("truncated command header")),
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(true)
+}
+
+fn invalid_stream(reason: &str) -> RustUtilsError {
+    RustUtilsError::OperationFailed(format!("invalid send stream: {reason}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BTRFS_SEND_C_SUBVOL: u16 = 1;
+    const BTRFS_SEND_C_END: u16 = 21;
+    const BTRFS_SEND_A_PATH: u16 = 15;
+
+    fn attr(kind: u16, value: &[u8]) -> Vec<u8> {
+        let mut out = kind.to_le_bytes().to_vec();
+        out.extend_from_slice(&(value.len() as u16).to_le_bytes());
+        out.extend_from_slice(value);
+        out
+    }
+
+    fn command(cmd: u16, payload: &[u8]) -> Vec<u8> {
+        let mut header = [0u8; CMD_HEADER_LEN];
+        header[0..4].copy_from_slice(&(payload.len() as u32).to_le_bytes());
+        header[4..6].copy_from_slice(&cmd.to_le_bytes());
+        let crc = command_crc(&header, payload);
+        header[6..10].copy_from_slice(&crc.to_le_bytes());
+        let mut out = header.to_vec();
+        out.extend_from_slice(payload);
+        out
+    }
+
+    fn chown(path: &str, uid: u64, gid: u64) -> Vec<u8> {
+        let mut payload = attr(BTRFS_SEND_A_PATH, path.as_bytes());
+        payload.extend(attr(BTRFS_SEND_A_UID, &uid.to_le_bytes()));
+        payload.extend(attr(BTRFS_SEND_A_GID, &gid.to_le_bytes()));
+        command(BTRFS_SEND_C_CHOWN, &payload)
+    }
+
+    fn stream(commands: &[Vec<u8>]) -> Vec<u8> {
+        let mut out = STREAM_MAGIC.to_vec();
+        out.extend_from_slice(&1u32.to_le_bytes());
+        for command in commands {
+            out.extend_from_slice(command);
+        }
+        out
+    }
+
+    fn idmap(map: &str) -> IdMap {
+        IdMap::new(vec![map.parse().unwrap()]).unwrap()
+    }
+
+    #[test]
+    fn test_crc32c_matches_castagnoli() {
+        // Standard CRC-32C check value uses ~0 seed and final inversion
+        assert_eq!(!crc32c(!0, b"123456789"), 0xE306_9283);
+    }
+
+    #[test]
+    fn test_translate_rewrites_chown() {
+        let subvol = command(BTRFS_SEND_C_SUBVOL, &attr(BTRFS_SEND_A_PATH, b"snap"));
+        let input = stream(&[
+            subvol.clone(),
+            chown("etc/passwd", 0, 0),
+            chown("home/app", 1000, 5),
+            command(BTRFS_SEND_C_END, &[]),
+        ]);
+
+        let mut output = Vec::new();
+        let map = idmap("0:100000:1001");
+        let stats = translate(&input[..], &mut output, &map, &map).unwrap();
+        assert_eq!(
+            stats,
+            StreamStats {
+                streams: 1,
+                commands: 4,
+                chowns: 2,
+                remapped: 4,
+            }
+        );
+
+        let expected = stream(&[
+            subvol,
+            chown("etc/passwd", 100000, 100000),
+            chown("home/app", 101000, 100005),
+            command(BTRFS_SEND_C_END, &[]),
+        ]);
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn test_translate_multiple_streams() {
+        let mut input = stream(&[chown("a", 1, 1), command(BTRFS_SEND_C_END, &[])]);
+        input.extend(stream(&[chown("b", 2, 2), command(BTRFS_SEND_C_END, &[])]));
+
+        let mut output = Vec::new();
+        let map = idmap("0:100000:10");
+        let stats = translate(&input[..], &mut output, &map, &map).unwrap();
+        assert_eq!(stats.streams, 2);
+        assert_eq!(stats.remapped, 4);
+        assert_eq!(output.len(), input.len());
+    }
+
+    #[test]
+    fn test_translate_uid_and_gid_maps() {
+        let input = stream(&[chown("a", 1, 1), command(BTRFS_SEND_C_END, &[])]);
+        let mut output = Vec::new();
+        let stats = translate(
+            &input[..],
+            &mut output,
+            &idmap("0:100000:10"),
+            &idmap("0:200000:10"),
+        )
+        .unwrap();
+        assert_eq!(stats.remapped, 2);
+        assert_eq!(
+            output,
+            stream(&[chown("a", 100001, 200001), command(BTRFS_SEND_C_END, &[])])
+        );
+    }
+
+    #[test]
+    fn test_translate_rejects_bad_input() {
+        let map = idmap("0:100000:10");
+
+        let result = translate(&b"not a send stream"[..], Vec::new(), &map, &map);
+        assert!(matches!(result, Err(RustUtilsError::OperationFailed(_))));
+
+        let mut corrupt = stream(&[chown("a", 1, 1)]);
+        let last = corrupt.len() - 1;
+        corrupt[last] ^= 0xff;
+        let result = translate(&corrupt[..], Vec::new(), &map, &map);
+        assert!(matches!(result, Err(RustUtilsError::OperationFailed(_))));
+
+        let mut future = STREAM_MAGIC.to_vec();
+        future.extend_from_slice(&99u32.to_le_bytes());
+        let result = translate(&future[..], Vec::new(), &map, &map);
+        assert!(matches!(result, Err(RustUtilsError::OperationFailed(_))));
+
+        // An oversized length is refused before its payload is read or checked
+        let mut huge = stream(&[chown("a", 1, 1)]);
+        huge[STREAM_HEADER_LEN..STREAM_HEADER_LEN + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        let result = translate(&huge[..], Vec::new(), &map, &map);
+        assert!(
+            matches!(result, Err(RustUtilsError::OperationFailed(ref e)) if e.contains("more than btrfs send writes"))
+        );
+    }
+}
@@ -0,0 +1,827 @@
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{lchown, FileTypeExt, MetadataExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::Result;
+use clap::{Args, Subcommand};
+use nix::sys::stat::{major, minor};
+use tar::{Archive, Builder, EntryType, Header};
+use tracing::{debug, info, warn};
+use walkdir::WalkDir;
+
+use crate::compress::{self, Compression, Encoder};
+use crate::error::{Result as RustUtilsResult, RustUtilsError};
+use crate::fs::{ensure_empty_destination, get_file_metadata, should_exclude};
+use crate::idmap::{self, IdMap, IdMapping};
+use crate::iostats::{self, Syscall};
+use crate::lxc::LxcIdMapArgs;
+use crate::remote;
+use crate::report::RunReport;
+use crate::stream::{self, ByteSize};
+use crate::subid::{self, SUBGID_FILE, SUBUID_FILE};
+use crate::xattrs::{capability_without_rootid, CAPABILITY_XATTR};
+
+#[derive(Args)]
+pub struct TemplateArgs {
+    #[command(subcommand)]
+    pub command: TemplateCommands,
+}
+
+#[derive(Subcommand)]
+pub enum TemplateCommands {
+    /// Pack a root filesystem into a reproducible template tarball
+    Pack(PackArgs),
+    /// Extract a template, shifting ownership into the target ID range as it unpacks
+    Import(ImportArgs),
+}
+
+#[derive(Args)]
+pub struct PackArgs {
+    /// Root filesystem to pack
+    pub rootfs: PathBuf,
+
+    /// Tarball to write ("-" writes to stdout; s3:// and http(s):// URLs need the
+    /// object-storage feature)
+    #[arg(short, long)]
+    pub output: PathBuf,
+
+    /// Shift ownership down to a canonical 0-based range
+    #[arg(long)]
+    pub normalize_ids: bool,
+
+    /// Host ID that becomes 0 when normalizing (defaults to the owner of the rootfs directory)
+    #[arg(long, requires = "normalize_ids")]
+    pub from_base: Option<u32>,
+
+    /// Size of the container ID range
+    #[arg(long, default_value = "65536")]
+    pub range_size: u32,
+
+    /// Modification time recorded for every entry (defaults to $SOURCE_DATE_EPOCH, then 0)
+    #[arg(long)]
+    pub mtime: Option<u64>,
+
+    /// Exclude paths matching pattern (can be used multiple times)
+    #[arg(long)]
+    pub exclude: Vec<String>,
+
+    /// Compression for the tarball (defaults to the output file's extension)
+    #[arg(long, value_enum)]
+    pub compress: Option<Compression>,
+
+    /// Compression level (gzip/xz 0-9, zstd 1-22)
+    #[arg(long)]
+    pub level: Option<u32>,
+
+    /// Split the tarball into OUTPUT.000, OUTPUT.001, ... parts of at most this size (e.g. 4G)
+    #[arg(long, value_name = "SIZE")]
+    pub split_size: Option<ByteSize>,
+}
+
+#[derive(Args)]
+pub struct ImportArgs {
+    /// Template tarball to extract, optionally gzip/xz/zstd compressed or split into
+    /// ARCHIVE.000, ... parts ("-" reads from stdin; may be an s3:// or http(s):// URL)
+    pub archive: PathBuf,
+
+    /// Directory to extract into (must not exist or be empty)
+    #[arg(long)]
+    pub target: PathBuf,
+
+    /// Map template IDs onto the subordinate ranges of this user
+    #[arg(
+        long,
+        value_name = "USER",
+        required_unless_present_any = ["map", "idmap", "idmap_file"],
+        conflicts_with_all = ["idmap", "idmap_file"]
+    )]
+    pub subid_user: Option<String>,
+
+    /// Explicit ID mapping applied to UIDs and GIDs instead of subordinate ranges (repeatable)
+    #[arg(
+        long = "map",
+        value_name = "FROM:TO:COUNT",
+        conflicts_with = "subid_user"
+    )]
+    pub map: Vec<IdMapping>,
+
+    #[command(flatten)]
+    pub lxc: LxcIdMapArgs,
+
+    /// Subordinate UID file consulted for --subid-user
+    #[arg(long, default_value = SUBUID_FILE)]
+    pub subuid_file: PathBuf,
+
+    /// Subordinate GID file consulted for --subid-user
+    #[arg(long, default_value = SUBGID_FILE)]
+    pub subgid_file: PathBuf,
+}
+
+impl TemplateArgs {
+    /// Whether a tarball is written to stdout, which must then stay free of log output.
+    pub fn writes_stdout(&self) -> bool {
+        match &self.command {
+            TemplateCommands::Pack(args) => stream::is_stdio(&args.output),
+            TemplateCommands::Import(_) => false,
+        }
+    }
+}
+
+/// Counters reported after packing.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct PackStats {
+    pub entries: u64,
+    pub hard_links: u64,
+    pub xattrs_stripped: u64,
+    /// Entries tar cannot represent, such as sockets
+    pub skipped: u64,
+}
+
+/// Counters reported after importing.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ImportStats {
+    pub entries: u64,
+    pub hard_links: u64,
+    /// Entries whose UID or GID changed
+    pub remapped: u64,
+}
+
+pub struct TemplateCommand {
+    args: TemplateArgs,
+}
+
+impl TemplateCommand {
+    pub fn new(args: TemplateArgs) -> Self {
+        Self { args }
+    }
+
+    pub fn execute(self) -> Result<RunReport> {
+        let report = match self.args.command {
+            TemplateCommands::Pack(args) => {
+                let stats = pack(&args)?;
+                info!(
+                    "Packed {} entries ({} hard links) into {}",
+                    stats.entries,
+                    stats.hard_links,
+                    args.output.display()
+                );
+                info!("Host-specific xattrs stripped: {}", stats.xattrs_stripped);
+                if stats.skipped > 0 {
+                    warn!("Skipped {} entries tar cannot represent", stats.skipped);
+                }
+                let mut report = RunReport::new("template-pack");
+                report
+                    .count("entries", stats.entries)
+                    .count("hard_links", stats.hard_links)
+                    .count("xattrs_stripped", stats.xattrs_stripped)
+                    .count("skipped", stats.skipped)
+                    .artifact("archive", &args.output);
+                report
+            }
+            TemplateCommands::Import(args) => {
+                let stats = import(&args)?;
+                info!(
+                    "Imported {} entries ({} hard links) into {}",
+                    stats.entries,
+                    stats.hard_links,
+                    args.target.display()
+                );
+                info!("Entries remapped: {}", stats.remapped);
+                let mut report = RunReport::new("template-import");
+                report
+                    .count("entries", stats.entries)
+                    .count("hard_links", stats.hard_links)
+                    .count("remapped", stats.remapped)
+                    .artifact("tree", &args.target);
+                report
+            }
+        };
+        Ok(report)
+    }
+}
+
+/// Per-kind ID shift applied while packing.
+#[derive(Clone, Copy, Debug)]
+struct Normalizer {
+    uid_base: u32,
+    gid_base: u32,
+    range_size: u32,
+}
+
+impl Normalizer {
+    fn apply(&self, path: &Path, uid: u32, gid: u32) -> RustUtilsResult<(u32, u32)> {
+        Ok((
+            normalize_id(path, uid, self.uid_base, self.range_size)?,
+            normalize_id(path, gid, self.gid_base, self.range_size)?,
+        ))
+    }
+}
+
+/// Shift `id` from `base..base + range_size` down to `0..range_size`.
+pub fn normalize_id(path: &Path, id: u32, base: u32, range_size: u32) -> RustUtilsResult<u32> {
+    if id >= base && id - base < range_size {
+        Ok(id - base)
+    } else {
+        Err(RustUtilsError::InvalidRange(format!(
+            "{}: ID {} is outside the container range {}-{}",
+            path.display(),
+            id,
+            base,
+            u64::from(base) + u64::from(range_size) - 1
+        )))
+    }
+}
+
+/// Write `args.rootfs` to `args.output` as a tarball that only depends on the tree's
+/// names, contents, modes and (normalized) ownership.
+pub fn pack(args: &PackArgs) -> RustUtilsResult<PackStats> {
+    let root = get_file_metadata(&args.rootfs)?;
+    if !root.is_dir() {
+        return Err(RustUtilsError::DirectoryNotFound(
+            args.rootfs.display().to_string(),
+        ));
+    }
+
+    let normalizer = args.normalize_ids.then(|| Normalizer {
+        uid_base: args.from_base.unwrap_or(root.uid()),
+        gid_base: args.from_base.unwrap_or(root.gid()),
+        range_size: args.range_size,
+    });
+    if let Some(n) = normalizer {
+        info!(
+            "Normalizing UIDs from {} and GIDs from {} (range {})",
+            n.uid_base, n.gid_base, n.range_size
+        );
+    }
+
+    let mtime = match args.mtime {
+        Some(mtime) => mtime,
+        None => source_date_epoch()?,
+    };
+
+    let output = stream::create_output(&args.output, args.split_size)?;
+    let output_files = OutputFiles::new(&args.output, args.split_size.is_some())?;
+
+    let compression = Compression::for_output(args.compress, &args.output, Compression::None);
+    let encoder = Encoder::new(BufWriter::new(output), compression, args.level)?;
+    let mut builder = Builder::new(encoder);
+    let mut stats = PackStats::default();
+    let mut links: HashMap<(u64, u64), PathBuf> = HashMap::new();
+
+    for entry in WalkDir::new(&args.rootfs)
+        .follow_links(false)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_entry(|e| !should_exclude(e.path(), &args.exclude))
+    {
+        let entry = entry.map_err(|e| RustUtilsError::Io(e.into()))?;
+        let path = entry.path();
+        let metadata = get_file_metadata(path)?;
+        // Never pack the tarball into itself when it is written below the rootfs
+        if output_files.contains(path, &metadata)? {
+            continue;
+        }
+        let relative = path.strip_prefix(&args.rootfs).unwrap_or(path);
+        let name = if relative.as_os_str().is_empty() {
+            PathBuf::from(".")
+        } else {
+            relative.to_path_buf()
+        };
+
+        let (uid, gid) = match normalizer {
+            Some(n) => n.apply(path, metadata.uid(), metadata.gid())?,
+            None => (metadata.uid(), metadata.gid()),
+        };
+
+        let mut header = Header::new_gnu();
+        header.set_mode(metadata.mode() & 0o7777);
+        header.set_uid(u64::from(uid));
+        header.set_gid(u64::from(gid));
+        header.set_mtime(mtime);
+        header.set_size(0);
+
+        if !metadata.is_dir() && metadata.nlink() > 1 {
+            let key = (metadata.dev(), metadata.ino());
+            if let Some(first) = links.get(&key) {
+                header.set_entry_type(EntryType::Link);
+                builder.append_link(&mut header, &name, first)?;
+                stats.entries += 1;
+                stats.hard_links += 1;
+                continue;
+            }
+            links.insert(key, name.clone());
+        }
+
+        let file_type = metadata.file_type();
+        if file_type.is_socket() {
+            debug!("Skipping socket: {}", path.display());
+            stats.skipped += 1;
+            continue;
+        }
+
+        let xattrs = portable_xattrs(path, &mut stats)?;
+        if !xattrs.is_empty() {
+            builder.append_pax_extensions(
+                xattrs
+                    .iter()
+                    .map(|(key, value)| (key.as_str(), value.as_slice())),
+            )?;
+        }
+
+        if file_type.is_dir() {
+            header.set_entry_type(EntryType::Directory);
+            builder.append_data(&mut header, &name, io::empty())?;
+        } else if file_type.is_file() {
+            header.set_entry_type(EntryType::Regular);
+            header.set_size(metadata.len());
+            builder.append_data(&mut header, &name, File::open(path)?)?;
+        } else if file_type.is_symlink() {
+            header.set_entry_type(EntryType::Symlink);
+            builder.append_link(&mut header, &name, fs::read_link(path)?)?;
+        } else {
+            header.set_entry_type(if file_type.is_fifo() {
+                EntryType::Fifo
+            } else if file_type.is_char_device() {
+                EntryType::Char
+            } else {
+                EntryType::Block
+            });
+            if !file_type.is_fifo() {
+                header.set_device_major(major(metadata.rdev()) as u32)?;
+                header.set_device_minor(minor(metadata.rdev()) as u32)?;
+            }
+            builder.append_data(&mut header, &name, io::empty())?;
+        }
+        stats.entries += 1;
+    }
+
+    stream::finish_output(builder.into_inner()?.finish()?)?;
+    Ok(stats)
+}
+
+/// The file(s) `pack` writes, recognised so they can be skipped if they lie inside the rootfs.
+struct OutputFiles {
+    /// The output file or first part; `None` for stdout and URLs
+    first: Option<(u64, u64)>,
+    /// Directory and name prefix of later parts of a split archive
+    parts: Option<((u64, u64), OsString)>,
+}
+
+impl OutputFiles {
+    fn new(output: &Path, split: bool) -> RustUtilsResult<Self> {
+        if stream::is_stdio(output) || remote::url(output).is_some() {
+            return Ok(Self {
+                first: None,
+                parts: None,
+            });
+        }
+        if !split {
+            let metadata = fs::metadata(output)?;
+            return Ok(Self {
+                first: Some((metadata.dev(), metadata.ino())),
+                parts: None,
+            });
+        }
+
+        let metadata = fs::metadata(stream::part_path(output, 0))?;
+        let dir = match output.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => fs::metadata(parent)?,
+            _ => fs::metadata(".")?,
+        };
+        let mut prefix = output.file_name().unwrap_or_default().to_owned();
+        prefix.push(".");
+        Ok(Self {
+            first: Some((metadata.dev(), metadata.ino())),
+            parts: Some(((dir.dev(), dir.ino()), prefix)),
+        })
+    }
+
+    fn contains(&self, path: &Path, metadata: &fs::Metadata) -> RustUtilsResult<bool> {
+        if Some((metadata.dev(), metadata.ino())) == self.first {
+            return Ok(true);
+        }
+        let Some((dir, prefix)) = &self.parts else {
+            return Ok(false);
+        };
+        let (Some(name), Some(parent)) = (path.file_name(), path.parent()) else {
+            return Ok(false);
+        };
+        if !name.as_bytes().starts_with(prefix.as_bytes()) {
+            return Ok(false);
+        }
+        let parent = fs::metadata(parent)?;
+        Ok((parent.dev(), parent.ino()) == *dir)
+    }
+}
+
+/// Extract `args.archive` into `args.target`, applying the target idmap to every entry as it
+/// is unpacked so the tree never exists on disk with template ownership.
+pub fn import(args: &ImportArgs) -> RustUtilsResult<ImportStats> {
+    let (uid_map, gid_map) = match &args.subid_user {
+        Some(user) => (
+            subid::idmap_for(&args.subuid_file, user)?,
+            subid::idmap_for(&args.subgid_file, user)?,
+        ),
+        None => args.lxc.id_maps(&args.map)?,
+    };
+    idmap::log_mappings(&uid_map, &gid_map);
+
+    ensure_empty_destination(&args.target)?;
+    fs::create_dir_all(&args.target)?;
+
+    let input = stream::open_input(&args.archive)?;
+    let (compression, input) = compress::decoder(BufReader::new(input))?;
+    debug!("Template compression: {:?}", compression);
+    unpack(input, &args.target, &uid_map, &gid_map)
+}
+
+/// Unpack a tar stream below `target`, remapping ownership entry by entry.
+fn unpack<R: Read>(
+    input: R,
+    target: &Path,
+    uid_map: &IdMap,
+    gid_map: &IdMap,
+) -> RustUtilsResult<ImportStats> {
+    let mut archive = Archive::new(input);
+    archive.set_preserve_permissions(true);
+    archive.set_preserve_mtime(true);
+    archive.set_unpack_xattrs(true);
+
+    let mut stats = ImportStats::default();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        let header = entry.header();
+        let entry_type = header.entry_type();
+        let mode = header.mode()?;
+        let (uid, gid) = (
+            header_id(&path, header.uid()?)?,
+            header_id(&path, header.gid()?)?,
+        );
+
+        let unmapped = |kind: &str, id: u32| {
+            RustUtilsError::InvalidRange(format!(
+                "{}: {} {} has no mapping in the target range",
+                path.display(),
+                kind,
+                id
+            ))
+        };
+        let new_uid = uid_map.get(uid).ok_or_else(|| unmapped("UID", uid))?;
+        let new_gid = gid_map.get(gid).ok_or_else(|| unmapped("GID", gid))?;
+
+        let is_root = path.components().all(|c| c == Component::CurDir);
+        let dst = if is_root {
+            // unpack_in skips the root entry, so apply its mode here
+            fs::set_permissions(target, fs::Permissions::from_mode(mode & 0o7777))?;
+            target.to_path_buf()
+        } else {
+            if !entry.unpack_in(target)? {
+                warn!("Skipping entry outside the target: {}", path.display());
+                continue;
+            }
+            target.join(&path)
+        };
+        stats.entries += 1;
+
+        // A hard link shares the inode, and therefore the ownership, of its first path
+        if entry_type == EntryType::Link {
+            stats.hard_links += 1;
+            continue;
+        }
+
+        iostats::timed(Syscall::Fchownat, || {
+            lchown(&dst, Some(new_uid), Some(new_gid))
+        })?;
+        if (new_uid, new_gid) != (uid, gid) {
+            stats.remapped += 1;
+        }
+        if entry_type == EntryType::Regular && mode & 0o6000 != 0 {
+            // chown cleared the set-ID bits unpack restored
+            fs::set_permissions(&dst, fs::Permissions::from_mode(mode & 0o7777))?;
+        }
+    }
+
+    Ok(stats)
+}
+
+fn header_id(path: &Path, id: u64) -> RustUtilsResult<u32> {
+    u32::try_from(id).map_err(|_| {
+        RustUtilsError::InvalidRange(format!("{}: ID {} is out of range", path.display(), id))
+    })
+}
+
+/// Extended attributes worth shipping in a template, as PAX `SCHILY.xattr.*` records.
+///
+/// Only `user.*` and file capabilities are kept; SELinux labels, IMA/EVM signatures,
+/// overlayfs internals and ACLs (which embed host IDs) are specific to the host.
+fn portable_xattrs(path: &Path, stats: &mut PackStats) -> RustUtilsResult<Vec<(String, Vec<u8>)>> {
+    let names = match xattr::list(path) {
+        Ok(names) => names,
+        Err(e) if e.raw_os_error() == Some(nix::libc::ENOTSUP) => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut names: Vec<_> = names.collect();
+    names.sort_by(|a, b| a.as_bytes().cmp(b.as_bytes()));
+
+    let mut kept = Vec::new();
+    for name in names {
+        let key = name.to_string_lossy();
+        if !(key.starts_with("user.") || key == CAPABILITY_XATTR) {
+            debug!("Stripping {} from {}", key, path.display());
+            stats.xattrs_stripped += 1;
+            continue;
+        }
+        let Some(mut value) = xattr::get(path, &name)? else {
+            continue;
+        };
+        if key == CAPABILITY_XATTR {
+            value = capability_without_rootid(value);
+        }
+        kept.push((format!("SCHILY.xattr.{key}"), value));
+    }
+    Ok(kept)
+}
+
+fn source_date_epoch() -> RustUtilsResult<u64> {
+    match std::env::var("SOURCE_DATE_EPOCH") {
+        Ok(value) => value.parse().map_err(|_| {
+            RustUtilsError::InvalidArguments(format!("invalid SOURCE_DATE_EPOCH '{value}'"))
+        }),
+        Err(_) => Ok(0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+    use tempfile::TempDir;
+
+    fn pack_args(rootfs: &Path, output: &Path) -> PackArgs {
+        PackArgs {
+            rootfs: rootfs.to_path_buf(),
+            output: output.to_path_buf(),
+            normalize_ids: false,
+            from_base: None,
+            range_size: 65536,
+            mtime: Some(0),
+            exclude: vec![],
+            compress: None,
+            level: None,
+            split_size: None,
+        }
+    }
+
+    /// Identity mapping for the current owner, so imports also run unprivileged.
+    fn identity_map() -> Vec<IdMapping> {
+        let uid = nix::unistd::getuid().as_raw();
+        let gid = nix::unistd::getgid().as_raw();
+        let mut map = vec![IdMapping {
+            from: uid,
+            to: uid,
+            count: 1,
+        }];
+        if gid != uid {
+            map.push(IdMapping {
+                from: gid,
+                to: gid,
+                count: 1,
+            });
+        }
+        map
+    }
+
+    fn build_tree(root: &Path, reverse: bool) -> io::Result<()> {
+        let mut names = vec!["bin", "etc"];
+        if reverse {
+            names.reverse();
+        }
+        for name in names {
+            fs::create_dir(root.join(name))?;
+            fs::write(root.join(name).join("file"), name)?;
+        }
+        fs::hard_link(root.join("bin/file"), root.join("bin/link"))?;
+        symlink("../etc/file", root.join("bin/sym"))?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_pack_is_reproducible() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let first = TempDir::new()?;
+        let second = TempDir::new()?;
+        let out = TempDir::new()?;
+        build_tree(first.path(), false)?;
+        build_tree(second.path(), true)?;
+
+        let a = out.path().join("a.tar");
+        let b = out.path().join("b.tar");
+        let stats = pack(&pack_args(first.path(), &a))?;
+        pack(&pack_args(second.path(), &b))?;
+
+        assert_eq!(stats.entries, 7);
+        assert_eq!(stats.hard_links, 1);
+        assert_eq!(fs::read(&a)?, fs::read(&b)?);
+
+        let mut archive = tar::Archive::new(File::open(&a)?);
+        let names: Vec<_> = archive
+            .entries()?
+            .map(|e| e.unwrap().path().unwrap().into_owned())
+            .collect();
+        assert_eq!(
+            names,
+            ["./", "bin/", "bin/file", "bin/link", "bin/sym", "etc/", "etc/file"]
+                .map(PathBuf::from)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pack_skips_output_inside_rootfs() -> std::result::Result<(), Box<dyn std::error::Error>>
+    {
+        let rootfs = TempDir::new()?;
+        fs::write(rootfs.path().join("data"), "payload")?;
+
+        let stats = pack(&pack_args(rootfs.path(), &rootfs.path().join("out.tar")))?;
+        assert_eq!(stats.entries, 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_split_template() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let rootfs = TempDir::new()?;
+        let out = TempDir::new()?;
+        let data: Vec<u8> = (0..64 * 1024u32).map(|i| (i % 251) as u8).collect();
+        fs::create_dir(rootfs.path().join("a"))?;
+        fs::write(rootfs.path().join("a/big"), &data)?;
+        fs::create_dir(rootfs.path().join("z"))?;
+
+        // Parts land in z/ after a/big was written, so all of them exist when z/ is listed
+        let tarball = rootfs.path().join("z/out.tar");
+        let mut args = pack_args(rootfs.path(), &tarball);
+        args.split_size = Some(ByteSize(10_000));
+        assert_eq!(pack(&args)?.entries, 4);
+        assert!(stream::part_path(&tarball, 6).exists());
+        assert!(!tarball.exists());
+
+        let target = out.path().join("rootfs");
+        let stats = import(&import_args(&tarball, &target, identity_map()))?;
+        assert_eq!(stats.entries, 4);
+        assert_eq!(fs::read(target.join("a/big"))?, data);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pack_normalize_out_of_range() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let rootfs = TempDir::new()?;
+        let out = TempDir::new()?;
+        fs::write(rootfs.path().join("data"), "payload")?;
+
+        let uid = nix::unistd::getuid().as_raw();
+        let mut args = pack_args(rootfs.path(), &out.path().join("t.tar"));
+        args.normalize_ids = true;
+        args.from_base = Some(uid.wrapping_add(1));
+        assert!(matches!(pack(&args), Err(RustUtilsError::InvalidRange(_))));
+
+        Ok(())
+    }
+
+    fn import_args(archive: &Path, target: &Path, map: Vec<IdMapping>) -> ImportArgs {
+        ImportArgs {
+            archive: archive.to_path_buf(),
+            target: target.to_path_buf(),
+            subid_user: None,
+            map,
+            lxc: Default::default(),
+            subuid_file: PathBuf::from(SUBUID_FILE),
+            subgid_file: PathBuf::from(SUBGID_FILE),
+        }
+    }
+
+    #[test]
+    fn test_import_round_trip() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let rootfs = TempDir::new()?;
+        let out = TempDir::new()?;
+        build_tree(rootfs.path(), false)?;
+        fs::set_permissions(
+            rootfs.path().join("etc/file"),
+            fs::Permissions::from_mode(0o640),
+        )?;
+
+        let tarball = out.path().join("t.tar");
+        pack(&pack_args(rootfs.path(), &tarball))?;
+
+        let target = out.path().join("rootfs");
+        let stats = import(&import_args(&tarball, &target, identity_map()))?;
+        assert_eq!(stats.entries, 7);
+        assert_eq!(stats.hard_links, 1);
+
+        assert_eq!(fs::read_to_string(target.join("etc/file"))?, "etc");
+        let file = fs::metadata(target.join("bin/file"))?;
+        assert_eq!(file.nlink(), 2);
+        assert_eq!(
+            fs::metadata(target.join("etc/file"))?.mode() & 0o7777,
+            0o640
+        );
+        assert_eq!(
+            fs::read_link(target.join("bin/sym"))?,
+            PathBuf::from("../etc/file")
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_compressed_template() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let rootfs = TempDir::new()?;
+        let out = TempDir::new()?;
+        build_tree(rootfs.path(), false)?;
+
+        // The extension picks the format; import detects it from the magic bytes
+        let tarball = out.path().join("t.tar.zst");
+        pack(&pack_args(rootfs.path(), &tarball))?;
+        assert_eq!(Compression::detect(&fs::read(&tarball)?), Compression::Zstd);
+
+        let target = out.path().join("rootfs");
+        let stats = import(&import_args(&tarball, &target, identity_map()))?;
+        assert_eq!(stats.entries, 7);
+        assert_eq!(fs::read_to_string(target.join("bin/file"))?, "bin");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_import_shifts_ownership() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        if !nix::unistd::geteuid().is_root() {
+            return Ok(());
+        }
+
+        let rootfs = TempDir::new()?;
+        let out = TempDir::new()?;
+        fs::write(rootfs.path().join("data"), "payload")?;
+        fs::set_permissions(
+            rootfs.path().join("data"),
+            fs::Permissions::from_mode(0o4755),
+        )?;
+
+        let tarball = out.path().join("t.tar");
+        let mut args = pack_args(rootfs.path(), &tarball);
+        args.normalize_ids = true;
+        pack(&args)?;
+
+        let subid = out.path().join("subid");
+        fs::write(&subid, "lxc:200000:65536\n")?;
+        let target = out.path().join("rootfs");
+        let mut args = import_args(&tarball, &target, vec![]);
+        args.subid_user = Some("lxc".to_string());
+        args.subuid_file = subid.clone();
+        args.subgid_file = subid;
+        assert_eq!(import(&args)?.remapped, 2);
+
+        let data = fs::metadata(target.join("data"))?;
+        assert_eq!((data.uid(), data.gid()), (200000, 200000));
+        // Set-ID bits survive the chown
+        assert_eq!(data.mode() & 0o7777, 0o4755);
+        assert_eq!(fs::metadata(&target)?.uid(), 200000);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_import_rejects_unmapped_ids() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let rootfs = TempDir::new()?;
+        let out = TempDir::new()?;
+        fs::write(rootfs.path().join("data"), "payload")?;
+
+        let tarball = out.path().join("t.tar");
+        pack(&pack_args(rootfs.path(), &tarball))?;
+
+        let uid = nix::unistd::getuid().as_raw();
+        let map = vec![IdMapping {
+            from: uid.wrapping_add(1),
+            to: 200000,
+            count: 1,
+        }];
+        let result = import(&import_args(&tarball, &out.path().join("rootfs"), map));
+        assert!(matches!(result, Err(RustUtilsError::InvalidRange(_))));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_normalize_id() {
+        let path = Path::new("etc/passwd");
+        assert_eq!(normalize_id(path, 100000, 100000, 65536).unwrap(), 0);
+        assert_eq!(normalize_id(path, 165535, 100000, 65536).unwrap(), 65535);
+        assert!(normalize_id(path, 99999, 100000, 65536).is_err());
+        assert!(normalize_id(path, 165536, 100000, 65536).is_err());
+    }
+}
@@ -0,0 +1,82 @@
+use std::ffi::OsString;
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+
+use anyhow::Result;
+use clap::{Args, Parser};
+use tracing::info;
+
+use crate::caps::{self, Capability};
+use crate::cli::Cli;
+use crate::error::RustUtilsError;
+use crate::report::RunReport;
+
+#[derive(Args, Debug)]
+pub struct WithCapsArgs {
+    /// The rust-utils command line to run, after `--`
+    #[arg(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+    pub command: Vec<OsString>,
+}
+
+impl WithCapsArgs {
+    /// The command line of the command to run, program name included.
+    pub fn command_line(&self) -> impl Iterator<Item = OsString> + '_ {
+        std::iter::once(OsString::from("rust-utils")).chain(self.command.iter().cloned())
+    }
+}
+
+/// Runs a command of this binary again with only the capabilities it needs.
+pub struct WithCapsCommand {
+    args: WithCapsArgs,
+}
+
+impl WithCapsCommand {
+    pub fn new(args: WithCapsArgs) -> Self {
+        Self { args }
+    }
+
+    pub fn execute(self) -> Result<RunReport> {
+        let cli = Cli::try_parse_from(self.args.command_line())
+            .map_err(|e| RustUtilsError::InvalidArguments(e.to_string()))?;
+        let needed = cli.command.capabilities().ok_or_else(|| {
+            RustUtilsError::InvalidArguments(
+                "with-caps runs neither itself nor remap profiles, which elevate through pkexec"
+                    .to_string(),
+            )
+        })?;
+
+        let exe = std::env::current_exe()?;
+        let permitted = caps::current()?.permitted;
+        let missing: Vec<Capability> = needed
+            .iter()
+            .copied()
+            .filter(|&capability| !permitted.contains(capability))
+            .collect();
+        if !missing.is_empty() {
+            return Err(RustUtilsError::Permission(format!(
+                "{} needs {}; grant them with `setcap {}+ep {}`",
+                cli.command.name(),
+                caps::names(&missing),
+                caps::names(needed),
+                exe.display()
+            ))
+            .into());
+        }
+
+        info!(
+            "Running {} with {}",
+            cli.command.name(),
+            match needed {
+                [] => "no capabilities".to_string(),
+                _ => caps::names(needed),
+            }
+        );
+        caps::keep_only(needed)?;
+        // Only returns if the command could not be started; it reports itself otherwise
+        let error = Command::new(&exe).args(&self.args.command).exec();
+        Err(
+            RustUtilsError::OperationFailed(format!("cannot run {}: {error}", exe.display()))
+                .into(),
+        )
+    }
+}
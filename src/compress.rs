@@ -0,0 +1,493 @@
+//! Transparent gzip/xz/zstd handling for archive streams.
+//!
+//! Besides plain streaming coders this provides threaded variants: [`threaded_decoder`]
+//! decompresses on a thread of its own, and [`Encoder::with_threads`] compresses fixed-size
+//! blocks on a pool of workers. Each block becomes an independent gzip member, xz stream or
+//! zstd frame, which [`decoder`] reads back as one stream, like `pigz` output.
+
+use std::collections::VecDeque;
+use std::io::{self, BufRead, Read, Write};
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use clap::ValueEnum;
+use flate2::write::GzEncoder;
+use xz2::write::XzEncoder;
+
+use crate::error::{Result, RustUtilsError};
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const XZ_MAGIC: &[u8] = &[0xfd, b'7', b'z', b'X', b'Z', 0x00];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+/// Decompressed bytes handed over by the decoder thread at a time.
+const DECODED_CHUNK: usize = 1 << 20;
+/// Decoded chunks queued ahead of the reader.
+const DECODED_DEPTH: usize = 4;
+
+/// Compression format of an archive stream.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Compression {
+    None,
+    Gzip,
+    Xz,
+    Zstd,
+}
+
+impl Compression {
+    /// Identify the format from the first bytes of a stream.
+    pub fn detect(magic: &[u8]) -> Self {
+        if magic.starts_with(GZIP_MAGIC) {
+            Compression::Gzip
+        } else if magic.starts_with(XZ_MAGIC) {
+            Compression::Xz
+        } else if magic.starts_with(ZSTD_MAGIC) {
+            Compression::Zstd
+        } else {
+            Compression::None
+        }
+    }
+
+    /// Infer the format from a file name such as `rootfs.tar.zst` or `base.tgz`, or `None`
+    /// when the name does not say (e.g. `-` for stdout).
+    pub fn from_extension(path: &Path) -> Option<Self> {
+        match path.extension().and_then(|ext| ext.to_str())? {
+            "tar" | "cpio" => Some(Compression::None),
+            "gz" | "tgz" => Some(Compression::Gzip),
+            "xz" | "txz" => Some(Compression::Xz),
+            "zst" | "zstd" | "tzst" => Some(Compression::Zstd),
+            _ => None,
+        }
+    }
+
+    /// Format for an output: the explicit choice, else the file extension, else `fallback`.
+    pub fn for_output(explicit: Option<Self>, path: &Path, fallback: Self) -> Self {
+        explicit
+            .or_else(|| Self::from_extension(path))
+            .unwrap_or(fallback)
+    }
+
+    /// Accepted `--level` values.
+    fn levels(self) -> std::ops::RangeInclusive<u32> {
+        match self {
+            Compression::None => 0..=0,
+            Compression::Gzip | Compression::Xz => 0..=9,
+            Compression::Zstd => 1..=22,
+        }
+    }
+
+    fn default_level(self) -> u32 {
+        match self {
+            Compression::None => 0,
+            Compression::Gzip | Compression::Xz => 6,
+            Compression::Zstd => 3,
+        }
+    }
+
+    /// Uncompressed bytes per independently compressed block. Larger blocks compress
+    /// better; xz and zstd need more context than gzip's 32 KiB window to pay off.
+    fn block_size(self) -> usize {
+        match self {
+            Compression::None | Compression::Gzip => 1 << 20,
+            Compression::Zstd => 4 << 20,
+            Compression::Xz => 8 << 20,
+        }
+    }
+
+    /// Resolve `level` against the format's default and accepted range.
+    fn checked_level(self, level: Option<u32>) -> Result<u32> {
+        let level = level.unwrap_or(self.default_level());
+        if !self.levels().contains(&level) {
+            let levels = self.levels();
+            return Err(RustUtilsError::InvalidArguments(format!(
+                "compression level {} is out of range for {:?} ({}-{})",
+                level,
+                self,
+                levels.start(),
+                levels.end()
+            )));
+        }
+        Ok(level)
+    }
+}
+
+/// Wrap `input` in the decoder matching its leading magic bytes.
+pub fn decoder<'a, R: BufRead + 'a>(mut input: R) -> Result<(Compression, Box<dyn Read + 'a>)> {
+    let compression = Compression::detect(input.fill_buf()?);
+    let reader: Box<dyn Read + 'a> = match compression {
+        Compression::None => Box::new(input),
+        // Multi-member decoders so concatenated gzip/xz files decode fully
+        Compression::Gzip => Box::new(flate2::bufread::MultiGzDecoder::new(input)),
+        Compression::Xz => Box::new(xz2::bufread::XzDecoder::new_multi_decoder(input)),
+        Compression::Zstd => Box::new(zstd::Decoder::with_buffer(input)?),
+    };
+    Ok((compression, reader))
+}
+
+/// [`decoder`] running on a thread of its own, so decompression overlaps with whatever
+/// consumes the data. At most a few megabytes of decoded data are queued.
+pub fn threaded_decoder<R: BufRead + Send + 'static>(
+    mut input: R,
+) -> Result<(Compression, Box<dyn Read>)> {
+    let compression = Compression::detect(input.fill_buf()?);
+    let (sender, receiver) = mpsc::sync_channel(DECODED_DEPTH);
+
+    thread::spawn(move || {
+        let mut reader = match decoder(input) {
+            Ok((_, reader)) => reader,
+            Err(e) => {
+                let _ = sender.send(Err(io::Error::other(e.to_string())));
+                return;
+            }
+        };
+        loop {
+            let mut chunk = vec![0u8; DECODED_CHUNK];
+            let result = match reader.read(&mut chunk) {
+                Ok(n) => {
+                    chunk.truncate(n);
+                    Ok(chunk)
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => Err(e),
+            };
+            // An empty chunk or an error ends the stream; a send error means the reader
+            // went away
+            let last = !matches!(&result, Ok(chunk) if !chunk.is_empty());
+            if sender.send(result).is_err() || last {
+                return;
+            }
+        }
+    });
+
+    let reader = DecodedReader {
+        receiver,
+        chunk: Vec::new(),
+        position: 0,
+        done: false,
+    };
+    Ok((compression, Box::new(reader)))
+}
+
+/// Reading end of [`threaded_decoder`].
+struct DecodedReader {
+    receiver: Receiver<io::Result<Vec<u8>>>,
+    chunk: Vec<u8>,
+    position: usize,
+    done: bool,
+}
+
+impl Read for DecodedReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.chunk.len() {
+            if self.done {
+                return Ok(0);
+            }
+            self.chunk = match self.receiver.recv() {
+                Ok(chunk) => chunk?,
+                // The thread only hangs up early if it panicked
+                Err(_) => return Err(io::Error::other("decompression thread failed")),
+            };
+            self.position = 0;
+            self.done = self.chunk.is_empty();
+        }
+        let n = buf.len().min(self.chunk.len() - self.position);
+        buf[..n].copy_from_slice(&self.chunk[self.position..self.position + n]);
+        self.position += n;
+        Ok(n)
+    }
+}
+
+/// A writer compressing into `W`; call [`Encoder::finish`] to flush the trailer.
+pub enum Encoder<W: Write> {
+    None(W),
+    Gzip(GzEncoder<W>),
+    Xz(XzEncoder<W>),
+    Zstd(zstd::Encoder<'static, W>),
+    Parallel(ParallelEncoder<W>),
+}
+
+impl<W: Write> Encoder<W> {
+    /// Create an encoder, using the format's default level when `level` is `None`.
+    pub fn new(output: W, compression: Compression, level: Option<u32>) -> Result<Self> {
+        let level = compression.checked_level(level)?;
+        Ok(match compression {
+            Compression::None => Encoder::None(output),
+            Compression::Gzip => {
+                Encoder::Gzip(GzEncoder::new(output, flate2::Compression::new(level)))
+            }
+            Compression::Xz => Encoder::Xz(XzEncoder::new(output, level)),
+            Compression::Zstd => Encoder::Zstd(zstd::Encoder::new(output, level as i32)?),
+        })
+    }
+
+    /// Like [`Encoder::new`], but compressing blocks on `threads` worker threads when there
+    /// is more than one.
+    pub fn with_threads(
+        output: W,
+        compression: Compression,
+        level: Option<u32>,
+        threads: usize,
+    ) -> Result<Self> {
+        if threads <= 1 || compression == Compression::None {
+            return Self::new(output, compression, level);
+        }
+        let level = compression.checked_level(level)?;
+        Ok(Encoder::Parallel(ParallelEncoder::new(
+            output,
+            compression,
+            level,
+            threads,
+        )))
+    }
+
+    /// Write any buffered data and the format trailer, returning the inner writer.
+    pub fn finish(self) -> io::Result<W> {
+        let mut output = match self {
+            Encoder::None(output) => output,
+            Encoder::Gzip(encoder) => encoder.finish()?,
+            Encoder::Xz(encoder) => encoder.finish()?,
+            Encoder::Zstd(encoder) => encoder.finish()?,
+            Encoder::Parallel(encoder) => encoder.finish()?,
+        };
+        output.flush()?;
+        Ok(output)
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Encoder::None(output) => output.write(buf),
+            Encoder::Gzip(encoder) => encoder.write(buf),
+            Encoder::Xz(encoder) => encoder.write(buf),
+            Encoder::Zstd(encoder) => encoder.write(buf),
+            Encoder::Parallel(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Encoder::None(output) => output.flush(),
+            Encoder::Gzip(encoder) => encoder.flush(),
+            Encoder::Xz(encoder) => encoder.flush(),
+            Encoder::Zstd(encoder) => encoder.flush(),
+            Encoder::Parallel(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// A block to compress and where to send the result.
+type Job = (Vec<u8>, SyncSender<io::Result<Vec<u8>>>);
+
+/// Compresses blocks on worker threads and writes them to `W` in order.
+///
+/// At most two blocks per worker are in flight, bounding memory to a small multiple of
+/// `threads` times the block size.
+pub struct ParallelEncoder<W: Write> {
+    output: W,
+    block: Vec<u8>,
+    block_size: usize,
+    jobs: Sender<Job>,
+    /// Results of submitted blocks, oldest first
+    pending: VecDeque<Receiver<io::Result<Vec<u8>>>>,
+    max_pending: usize,
+    /// Whether any block was submitted, so even empty output is a valid stream
+    started: bool,
+}
+
+impl<W: Write> ParallelEncoder<W> {
+    fn new(output: W, compression: Compression, level: u32, threads: usize) -> Self {
+        let (jobs, queue) = mpsc::channel::<Job>();
+        let queue = Arc::new(Mutex::new(queue));
+        for _ in 0..threads {
+            let queue = Arc::clone(&queue);
+            // Workers exit once the encoder, and with it the job sender, is dropped
+            thread::spawn(move || loop {
+                let job = queue
+                    .lock()
+                    .map_err(|_| ())
+                    .and_then(|q| q.recv().map_err(|_| ()));
+                let Ok((block, result)) = job else {
+                    return;
+                };
+                let _ = result.send(compress_block(&block, compression, level));
+            });
+        }
+
+        Self {
+            output,
+            block: Vec::with_capacity(compression.block_size()),
+            block_size: compression.block_size(),
+            jobs,
+            pending: VecDeque::new(),
+            max_pending: threads * 2,
+            started: false,
+        }
+    }
+
+    /// Hand the current block to the workers, first writing out the oldest result if too
+    /// many are in flight.
+    fn submit(&mut self) -> io::Result<()> {
+        while self.pending.len() >= self.max_pending {
+            self.write_oldest()?;
+        }
+        let block = std::mem::replace(&mut self.block, Vec::with_capacity(self.block_size));
+        let (sender, receiver) = mpsc::sync_channel(1);
+        self.jobs
+            .send((block, sender))
+            .map_err(|_| io::Error::other("compression threads failed"))?;
+        self.pending.push_back(receiver);
+        self.started = true;
+        Ok(())
+    }
+
+    fn write_oldest(&mut self) -> io::Result<()> {
+        if let Some(receiver) = self.pending.pop_front() {
+            let compressed = receiver
+                .recv()
+                .map_err(|_| io::Error::other("compression thread failed"))??;
+            self.output.write_all(&compressed)?;
+        }
+        Ok(())
+    }
+
+    /// Submit the partial block and write everything still in flight.
+    fn drain(&mut self) -> io::Result<()> {
+        if !self.block.is_empty() {
+            self.submit()?;
+        }
+        while !self.pending.is_empty() {
+            self.write_oldest()?;
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) -> io::Result<W> {
+        if !self.started {
+            self.submit()?;
+        }
+        self.drain()?;
+        Ok(self.output)
+    }
+}
+
+impl<W: Write> Write for ParallelEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = buf.len().min(self.block_size - self.block.len());
+        self.block.extend_from_slice(&buf[..n]);
+        if self.block.len() == self.block_size {
+            self.submit()?;
+        }
+        Ok(n)
+    }
+
+    /// Completes the current block early, so frequent flushes cost compression ratio.
+    fn flush(&mut self) -> io::Result<()> {
+        self.drain()?;
+        self.output.flush()
+    }
+}
+
+fn compress_block(block: &[u8], compression: Compression, level: u32) -> io::Result<Vec<u8>> {
+    let mut encoder = Encoder::new(Vec::new(), compression, Some(level))
+        .map_err(|e| io::Error::other(e.to_string()))?;
+    encoder.write_all(block)?;
+    encoder.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAYLOAD: &[u8] = b"ownership is metadata, not data\n";
+
+    fn round_trip(compression: Compression) -> (Compression, Vec<u8>) {
+        let mut encoder = Encoder::new(Vec::new(), compression, None).unwrap();
+        encoder.write_all(PAYLOAD).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        let (detected, mut reader) = decoder(&compressed[..]).unwrap();
+        let mut decoded = Vec::new();
+        reader.read_to_end(&mut decoded).unwrap();
+        (detected, decoded)
+    }
+
+    #[test]
+    fn test_round_trip_all_formats() {
+        for compression in [
+            Compression::None,
+            Compression::Gzip,
+            Compression::Xz,
+            Compression::Zstd,
+        ] {
+            assert_eq!(round_trip(compression), (compression, PAYLOAD.to_vec()));
+        }
+    }
+
+    #[test]
+    fn test_from_extension() {
+        let ext = |name: &str| Compression::from_extension(Path::new(name));
+        assert_eq!(ext("base.tar.zst"), Some(Compression::Zstd));
+        assert_eq!(ext("base.tgz"), Some(Compression::Gzip));
+        assert_eq!(ext("base.tar.xz"), Some(Compression::Xz));
+        assert_eq!(ext("base.tar"), Some(Compression::None));
+        assert_eq!(ext("-"), None);
+    }
+
+    #[test]
+    fn test_for_output() {
+        let path = Path::new("out.tar.gz");
+        assert_eq!(
+            Compression::for_output(Some(Compression::Xz), path, Compression::None),
+            Compression::Xz
+        );
+        assert_eq!(
+            Compression::for_output(None, path, Compression::None),
+            Compression::Gzip
+        );
+        assert_eq!(
+            Compression::for_output(None, Path::new("-"), Compression::Zstd),
+            Compression::Zstd
+        );
+    }
+
+    #[test]
+    fn test_parallel_round_trip() {
+        // Several blocks of poorly compressible data, so results arrive out of order
+        let data: Vec<u8> = (0..3 * (1 << 20) + 12345u32)
+            .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+            .collect();
+        for compression in [Compression::Gzip, Compression::Zstd] {
+            let mut encoder = Encoder::with_threads(Vec::new(), compression, Some(1), 3).unwrap();
+            assert!(matches!(encoder, Encoder::Parallel(_)));
+            encoder.write_all(&data).unwrap();
+            let compressed = encoder.finish().unwrap();
+
+            let (detected, mut reader) = threaded_decoder(io::Cursor::new(compressed)).unwrap();
+            assert_eq!(detected, compression);
+            let mut decoded = Vec::new();
+            reader.read_to_end(&mut decoded).unwrap();
+            assert!(decoded == data, "{compression:?} output differs");
+        }
+    }
+
+    #[test]
+    fn test_parallel_empty_output() {
+        let encoder = Encoder::with_threads(Vec::new(), Compression::Xz, None, 2).unwrap();
+        let compressed = encoder.finish().unwrap();
+        let (detected, mut reader) = decoder(&compressed[..]).unwrap();
+        assert_eq!(detected, Compression::Xz);
+        assert_eq!(reader.read(&mut [0u8; 16]).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_invalid_level() {
+        let result = Encoder::new(Vec::new(), Compression::Gzip, Some(10));
+        assert!(matches!(result, Err(RustUtilsError::InvalidArguments(_))));
+        assert!(Encoder::new(Vec::new(), Compression::Zstd, Some(19)).is_ok());
+        let result = Encoder::with_threads(Vec::new(), Compression::Zstd, Some(23), 4);
+        assert!(matches!(result, Err(RustUtilsError::InvalidArguments(_))));
+    }
+}
@@ -0,0 +1,229 @@
+//! Classification of the entries a run failed on, so that its summary says what to do about
+//! each kind of failure once instead of leaving raw errno text to research.
+//!
+//! A failure is classified from the errno of its error and, for `EPERM`, from what the entry
+//! and the process turn out to be: an immutable entry refuses even root, an unprivileged
+//! process lacks `CAP_CHOWN`, and a privileged one refused anyway is on a filesystem that
+//! decides owners elsewhere.
+
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+use nix::errno::Errno;
+use nix::libc;
+use rustix::fs::{ioctl_getflags, IFlags};
+use tracing::warn;
+
+use crate::error::RustUtilsError;
+use crate::probe;
+use crate::report::RunReport;
+
+/// What kind of failure an entry met, by what would fix it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum FailureClass {
+    /// The process lacks the privilege to change owners
+    NeedsRoot,
+    /// The entry is on a read-only mount
+    ReadOnly,
+    /// The entry has the immutable or append-only attribute
+    Immutable,
+    /// The filesystem refuses the change whoever asks, as NFS, FUSE and vfat can
+    ForeignFilesystem,
+    /// The entry was removed or renamed during the run
+    Vanished,
+    /// Anything else
+    Other,
+}
+
+impl FailureClass {
+    /// Name of the class in run report counts.
+    pub fn name(self) -> &'static str {
+        match self {
+            FailureClass::NeedsRoot => "needs_root",
+            FailureClass::ReadOnly => "read_only",
+            FailureClass::Immutable => "immutable",
+            FailureClass::ForeignFilesystem => "foreign_filesystem",
+            FailureClass::Vanished => "vanished",
+            FailureClass::Other => "other",
+        }
+    }
+
+    /// Why entries of the class failed, completing "N entry(ies) ...".
+    fn reason(self) -> &'static str {
+        match self {
+            FailureClass::NeedsRoot => "need privileges this process lacks",
+            FailureClass::ReadOnly => "are on a read-only mount",
+            FailureClass::Immutable => "have the immutable or append-only attribute",
+            FailureClass::ForeignFilesystem => "are on a filesystem that refuses new owners",
+            FailureClass::Vanished => "were removed or renamed during the run",
+            FailureClass::Other => "failed for other reasons",
+        }
+    }
+
+    /// What to do about entries of the class.
+    pub fn hint(self) -> &'static str {
+        match self {
+            FailureClass::NeedsRoot => {
+                "run as root, under fakeroot, with --fakeroot-db or with --chown-helper"
+            }
+            FailureClass::ReadOnly => {
+                "remount it read-write, or leave it out with --one-file-system or --exclude"
+            }
+            FailureClass::Immutable => {
+                "clear the attribute with chattr -i -a, remap, and set it again"
+            }
+            FailureClass::ForeignFilesystem => {
+                "change owners where the filesystem is served (NFS root_squash, FUSE without \
+                 allow_other, vfat), or leave it out with --one-file-system"
+            }
+            FailureClass::Vanished => {
+                "nothing is lost; stop what changes the tree, e.g. with --freeze-cgroup, and \
+                 run the remap again for entries created since"
+            }
+            FailureClass::Other => "see the errors logged above or in the run report",
+        }
+    }
+}
+
+/// Whether `path` has the immutable or append-only attribute, as far as it can be read.
+pub fn is_immutable(path: &Path) -> bool {
+    let Ok(file) = OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NOFOLLOW | libc::O_NONBLOCK)
+        .open(path)
+    else {
+        return false;
+    };
+    ioctl_getflags(&file).is_ok_and(|flags| flags.intersects(IFlags::IMMUTABLE | IFlags::APPEND))
+}
+
+/// Failed entries by class, with the first path of each.
+#[derive(Debug, Default)]
+pub struct Failures {
+    classes: BTreeMap<FailureClass, (u64, PathBuf)>,
+    /// Whether the process may change owners, read at the first `EPERM`
+    privileged: Option<bool>,
+}
+
+impl Failures {
+    /// Classify a failure with `errno` at `path` and count it.
+    pub fn record(&mut self, errno: Option<Errno>, path: Option<&Path>) -> FailureClass {
+        let class = self.classify(errno, path);
+        let (count, _) = self
+            .classes
+            .entry(class)
+            .or_insert_with(|| (0, path.map(Path::to_path_buf).unwrap_or_default()));
+        *count += 1;
+        class
+    }
+
+    /// Classify and count `error` at `path`.
+    pub fn record_error(&mut self, error: &RustUtilsError, path: Option<&Path>) -> FailureClass {
+        self.record(error.errno(), path)
+    }
+
+    /// How many failures of `class` were recorded.
+    pub fn count(&self, class: FailureClass) -> u64 {
+        self.classes.get(&class).map_or(0, |(count, _)| *count)
+    }
+
+    fn classify(&mut self, errno: Option<Errno>, path: Option<&Path>) -> FailureClass {
+        match errno {
+            Some(Errno::ENOENT | Errno::ENOTDIR) => FailureClass::Vanished,
+            Some(Errno::EROFS) => FailureClass::ReadOnly,
+            Some(Errno::EPERM) if path.is_some_and(is_immutable) => FailureClass::Immutable,
+            Some(Errno::EPERM | Errno::EACCES) if !self.privileged() => FailureClass::NeedsRoot,
+            Some(Errno::EPERM | Errno::EACCES | Errno::EINVAL | Errno::EOPNOTSUPP) => {
+                FailureClass::ForeignFilesystem
+            }
+            _ => FailureClass::Other,
+        }
+    }
+
+    fn privileged(&mut self) -> bool {
+        *self
+            .privileged
+            .get_or_insert_with(|| probe::has_cap_chown().unwrap_or(false))
+    }
+
+    /// Log one line with a hint for each class met.
+    pub fn log(&self) {
+        for (class, (count, example)) in &self.classes {
+            warn!(
+                "{} entry(ies) {}, e.g. {}: {}",
+                count,
+                class.reason(),
+                example.display(),
+                class.hint()
+            );
+        }
+    }
+
+    /// Count each class met in `report` as `failed_<class>`.
+    pub fn summarize(&self, report: &mut RunReport) {
+        for (class, (count, _)) in &self.classes {
+            report.count(&format!("failed_{}", class.name()), *count);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::process::Command;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_classify() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = TempDir::new()?;
+        let path = temp_dir.path().join("file");
+        fs::write(&path, "")?;
+
+        let mut failures = Failures::default();
+        let missing = temp_dir.path().join("missing");
+        assert_eq!(
+            failures.record(Some(Errno::ENOENT), Some(&missing)),
+            FailureClass::Vanished
+        );
+        assert_eq!(
+            failures.record(Some(Errno::EROFS), Some(&path)),
+            FailureClass::ReadOnly
+        );
+        assert_eq!(failures.record(None, None), FailureClass::Other);
+
+        failures.privileged = Some(false);
+        assert_eq!(
+            failures.record(Some(Errno::EPERM), Some(&path)),
+            FailureClass::NeedsRoot
+        );
+        failures.privileged = Some(true);
+        assert_eq!(
+            failures.record(Some(Errno::EPERM), Some(&path)),
+            FailureClass::ForeignFilesystem
+        );
+        let error = RustUtilsError::Io(std::io::Error::from_raw_os_error(libc::ENOENT));
+        assert_eq!(
+            failures.record_error(&error, Some(&missing)),
+            FailureClass::Vanished
+        );
+        assert_eq!(failures.count(FailureClass::Vanished), 2);
+
+        let mut report = RunReport::new("remap");
+        failures.summarize(&mut report);
+        assert_eq!(report.counts["failed_vanished"], 2);
+        assert_eq!(report.counts["failed_needs_root"], 1);
+        assert!(!report.counts.contains_key("failed_immutable"));
+
+        // Setting the attribute needs CAP_LINUX_IMMUTABLE and a filesystem that has it
+        let set = Command::new("chattr").arg("+i").arg(&path).status();
+        if set.is_ok_and(|status| status.success()) {
+            let class = failures.record(Some(Errno::EPERM), Some(&path));
+            Command::new("chattr").arg("-i").arg(&path).status()?;
+            assert_eq!(class, FailureClass::Immutable);
+        }
+        Ok(())
+    }
+}
@@ -17,6 +17,13 @@ pub enum RustUtilsError {
     #[error("Remapping failed: {0}")]
     RemapFailed(String),
 
+    #[error("Remapping failed: {message}")]
+    ChownFailed {
+        message: String,
+        #[source]
+        source: std::io::Error,
+    },
+
     #[error("System error: {0}")]
     System(#[from] nix::errno::Errno),
 
@@ -25,6 +32,49 @@ pub enum RustUtilsError {
 
     #[error("Operation failed: {0}")]
     OperationFailed(String),
+
+    #[error("Unexpected hard link: {0}")]
+    UnexpectedHardLink(String),
+
+    #[error("Plugin error: {0}")]
+    Plugin(String),
+
+    #[error("Probe failed: {0}")]
+    Probe(String),
+
+    #[error("Unsupported format: {0}")]
+    UnsupportedFormat(String),
+
+    #[error("Tree in use: {0}")]
+    InUse(String),
+
+    #[error("Plan mismatch: {0}")]
+    PlanMismatch(String),
+
+    #[error("Unexpected change: {0}")]
+    UnexpectedChange(String),
+
+    #[error("Too many errors: {0}")]
+    TooManyErrors(String),
+
+    #[error("Already remapped: {0}")]
+    AlreadyMapped(String),
+
+    #[error("Interrupted: {0}")]
+    Interrupted(String),
+}
+
+impl RustUtilsError {
+    /// The errno behind the error, if it came from a system call.
+    pub fn errno(&self) -> Option<nix::errno::Errno> {
+        match self {
+            RustUtilsError::Io(e) | RustUtilsError::ChownFailed { source: e, .. } => {
+                e.raw_os_error().map(nix::errno::Errno::from_i32)
+            }
+            RustUtilsError::System(errno) => Some(*errno),
+            _ => None,
+        }
+    }
 }
 
 pub type Result<T> = std::result::Result<T, RustUtilsError>;
@@ -53,6 +103,30 @@ mod tests {
 
         let error = RustUtilsError::OperationFailed("test op".to_string());
         assert_eq!(error.to_string(), "Operation failed: test op");
+
+        let error = RustUtilsError::UnexpectedHardLink("a is linked to b".to_string());
+        assert_eq!(error.to_string(), "Unexpected hard link: a is linked to b");
+
+        let error = RustUtilsError::Plugin("decide trapped".to_string());
+        assert_eq!(error.to_string(), "Plugin error: decide trapped");
+
+        let error = RustUtilsError::TooManyErrors("stopping after 1 failed entry".to_string());
+        assert_eq!(
+            error.to_string(),
+            "Too many errors: stopping after 1 failed entry"
+        );
+
+        let error = RustUtilsError::AlreadyMapped("/srv/a is owned by 100000:100000".to_string());
+        assert_eq!(
+            error.to_string(),
+            "Already remapped: /srv/a is owned by 100000:100000"
+        );
+
+        let error = RustUtilsError::Interrupted("SIGINT before the plan was checked".to_string());
+        assert_eq!(
+            error.to_string(),
+            "Interrupted: SIGINT before the plan was checked"
+        );
     }
 
     #[test]
@@ -66,6 +140,24 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_error_errno() {
+        let error = RustUtilsError::ChownFailed {
+            message: "Failed to chown /srv/a: Read-only file system".to_string(),
+            source: io::Error::from_raw_os_error(nix::libc::EROFS),
+        };
+        assert_eq!(
+            error.to_string(),
+            "Remapping failed: Failed to chown /srv/a: Read-only file system"
+        );
+        assert_eq!(error.errno(), Some(nix::errno::Errno::EROFS));
+        assert_eq!(
+            RustUtilsError::System(nix::errno::Errno::EPERM).errno(),
+            Some(nix::errno::Errno::EPERM)
+        );
+        assert_eq!(RustUtilsError::RemapFailed("x".to_string()).errno(), None);
+    }
+
     #[test]
     fn test_result_type() {
         let success: Result<i32> = Ok(42);
@@ -0,0 +1,221 @@
+//! fakeroot sessions and state files, for building container templates without root.
+//!
+//! Under `fakeroot`, ownership changes never reach the disk: the `faked` daemon keeps them
+//! per inode, and `fakeroot -s FILE` saves them to a state file that `fakeroot -i FILE`
+//! loads again. [`FakerootDb`] reads and writes such a file directly, so `remap
+//! --fakeroot-db` can remap the recorded owners of a tree built that way with no daemon
+//! and no privileges. [`session`] tells whether the process runs under `fakeroot` or
+//! `fakechroot` itself.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::ffi::OsString;
+use std::fs::{self, Metadata};
+use std::io::{self, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use crate::error::{Result, RustUtilsError};
+
+/// Faking environment the process runs in, as its environment tells.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Session {
+    /// `fakeroot`, with ownership changes recorded by `faked`
+    Fakeroot,
+    /// `fakechroot`, with paths translated but ownership real
+    Fakechroot,
+}
+
+/// The faking environment of this process, if any.
+pub fn session() -> Option<Session> {
+    if env::var_os("FAKEROOTKEY").is_some() {
+        Some(Session::Fakeroot)
+    } else if env::var_os("FAKECHROOT").is_some_and(|value| value == "true") {
+        Some(Session::Fakechroot)
+    } else {
+        None
+    }
+}
+
+/// What `faked` records of one inode.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct FakeInode {
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    nlink: u64,
+    rdev: u64,
+}
+
+/// A fakeroot state file, one `dev=…,ino=…,mode=…,uid=…,gid=…,nlink=…,rdev=…` line per
+/// inode, held in memory until [`save`](Self::save)d.
+#[derive(Debug)]
+pub struct FakerootDb {
+    path: PathBuf,
+    inodes: BTreeMap<(u64, u64), FakeInode>,
+    changed: u64,
+}
+
+impl FakerootDb {
+    /// Read the state file at `path`, or start an empty one if there is none yet.
+    pub fn open(path: &Path) -> Result<Self> {
+        let mut db = Self {
+            path: path.to_path_buf(),
+            inodes: BTreeMap::new(),
+            changed: 0,
+        };
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(db),
+            Err(e) => return Err(e.into()),
+        };
+        for (number, line) in contents.lines().enumerate() {
+            if line.is_empty() {
+                continue;
+            }
+            let (key, inode) = parse_line(line).ok_or_else(|| {
+                RustUtilsError::InvalidArguments(format!(
+                    "{}:{}: not a fakeroot state line",
+                    path.display(),
+                    number + 1
+                ))
+            })?;
+            db.inodes.insert(key, inode);
+        }
+        Ok(db)
+    }
+
+    /// The owner recorded for the inode of `metadata`, or its owner on disk.
+    pub fn owner(&self, metadata: &Metadata) -> (u32, u32) {
+        match self.inodes.get(&(metadata.dev(), metadata.ino())) {
+            Some(inode) => (inode.uid, inode.gid),
+            None => (metadata.uid(), metadata.gid()),
+        }
+    }
+
+    /// Record `uid` and `gid` as the owner of the inode of `metadata`, keeping what else
+    /// the file records of it.
+    pub fn set_owner(&mut self, metadata: &Metadata, uid: u32, gid: u32) {
+        let inode = self
+            .inodes
+            .entry((metadata.dev(), metadata.ino()))
+            .or_insert(FakeInode {
+                mode: metadata.mode(),
+                uid: metadata.uid(),
+                gid: metadata.gid(),
+                nlink: metadata.nlink(),
+                rdev: metadata.rdev(),
+            });
+        inode.uid = uid;
+        inode.gid = gid;
+        self.changed += 1;
+    }
+
+    /// Owners recorded since the file was read.
+    pub fn changed(&self) -> u64 {
+        self.changed
+    }
+
+    /// Replace the state file with the recorded owners in one rename.
+    pub fn save(&self) -> Result<()> {
+        let mut partial = OsString::from(&self.path);
+        partial.push(format!(".{}", std::process::id()));
+        let mut file = io::BufWriter::new(fs::File::create(&partial)?);
+        for ((dev, ino), inode) in &self.inodes {
+            writeln!(
+                file,
+                "dev={:x},ino={},mode={:o},uid={},gid={},nlink={},rdev={}",
+                dev, ino, inode.mode, inode.uid, inode.gid, inode.nlink, inode.rdev
+            )?;
+        }
+        file.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        fs::rename(&partial, &self.path)?;
+        Ok(())
+    }
+}
+
+fn parse_line(line: &str) -> Option<((u64, u64), FakeInode)> {
+    let mut fields = line.split(',').map(|field| field.split_once('='));
+    let mut next = |name: &str, radix: u32| match fields.next() {
+        Some(Some((key, value))) if key == name => u64::from_str_radix(value, radix).ok(),
+        _ => None,
+    };
+    let dev = next("dev", 16)?;
+    let ino = next("ino", 10)?;
+    let inode = FakeInode {
+        mode: next("mode", 8)?.try_into().ok()?,
+        uid: next("uid", 10)?.try_into().ok()?,
+        gid: next("gid", 10)?.try_into().ok()?,
+        nlink: next("nlink", 10)?,
+        rdev: next("rdev", 10)?,
+    };
+    Some(((dev, ino), inode))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_parse_line() {
+        let (key, inode) =
+            parse_line("dev=fd01,ino=1234,mode=104755,uid=0,gid=33,nlink=2,rdev=0").unwrap();
+        assert_eq!(key, (0xfd01, 1234));
+        assert_eq!(
+            inode,
+            FakeInode {
+                mode: 0o104755,
+                uid: 0,
+                gid: 33,
+                nlink: 2,
+                rdev: 0
+            }
+        );
+        for line in [
+            "dev=fd01,ino=1234,mode=104755,uid=0,gid=33,nlink=2",
+            "ino=1234,dev=fd01,mode=104755,uid=0,gid=33,nlink=2,rdev=0",
+            "dev=fd01,ino=1234,mode=104755,uid=-1,gid=33,nlink=2,rdev=0",
+        ] {
+            assert_eq!(parse_line(line), None, "{line}");
+        }
+    }
+
+    #[test]
+    fn test_fakeroot_db() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = TempDir::new()?;
+        let file = temp_dir.path().join("file");
+        fs::write(&file, "")?;
+        let metadata = fs::symlink_metadata(&file)?;
+        let state = temp_dir.path().join("state");
+
+        let mut db = FakerootDb::open(&state)?;
+        assert_eq!(db.owner(&metadata), (metadata.uid(), metadata.gid()));
+        db.set_owner(&metadata, 0, 33);
+        assert_eq!(db.changed(), 1);
+        db.save()?;
+
+        let saved = fs::read_to_string(&state)?;
+        assert_eq!(
+            saved,
+            format!(
+                "dev={:x},ino={},mode={:o},uid=0,gid=33,nlink=1,rdev=0\n",
+                metadata.dev(),
+                metadata.ino(),
+                metadata.mode()
+            )
+        );
+        let mut db = FakerootDb::open(&state)?;
+        assert_eq!(db.owner(&metadata), (0, 33));
+        assert_eq!(db.changed(), 0);
+        db.set_owner(&metadata, 100000, 100033);
+        db.save()?;
+        assert_eq!(FakerootDb::open(&state)?.owner(&metadata), (100000, 100033));
+        assert_eq!(fs::read_dir(temp_dir.path())?.count(), 2);
+
+        fs::write(&state, "not a state file\n")?;
+        let error = FakerootDb::open(&state).unwrap_err();
+        assert!(error.to_string().contains("state:1"), "{error}");
+        Ok(())
+    }
+}
@@ -0,0 +1,199 @@
+//! Freezing a container's cgroup through the cgroup v2 freezer, so a quick fixup can run
+//! without a full stop and restart.
+//!
+//! Writing `1` to `cgroup.freeze` asks the kernel to stop every process in the cgroup and
+//! its descendants; `cgroup.events` reports `frozen 1` once they all are. Writing `0` lets
+//! them continue where they were.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use tracing::{info, warn};
+
+use crate::error::{Result, RustUtilsError};
+
+/// How long to wait for every process of the cgroup to stop.
+pub const FREEZE_TIMEOUT: Duration = Duration::from_secs(10);
+
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// A cgroup frozen by [`FrozenCgroup::freeze`]; thawed again on [`thaw`](Self::thaw) or
+/// drop.
+pub struct FrozenCgroup {
+    dir: PathBuf,
+    /// Whether someone else froze the cgroup before, in which case it is left frozen
+    was_frozen: bool,
+    thawed: bool,
+    since: Instant,
+}
+
+impl FrozenCgroup {
+    /// Freeze the cgroup v2 directory `dir` and wait until all its processes have stopped.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`RustUtilsError::InvalidArguments`] if `dir` is not a cgroup v2 directory and
+    /// [`RustUtilsError::OperationFailed`] if the processes do not stop within
+    /// [`FREEZE_TIMEOUT`], after thawing the cgroup again.
+    pub fn freeze(dir: &Path) -> Result<Self> {
+        check_cgroup(dir)?;
+        let was_frozen = fs::read_to_string(dir.join("cgroup.freeze"))?.trim() == "1";
+        let mut frozen = Self {
+            dir: dir.to_path_buf(),
+            was_frozen,
+            thawed: false,
+            since: Instant::now(),
+        };
+        if was_frozen {
+            info!("Cgroup {} is already frozen", dir.display());
+            return Ok(frozen);
+        }
+
+        fs::write(dir.join("cgroup.freeze"), "1")?;
+        let started = Instant::now();
+        while !is_frozen(dir)? {
+            if started.elapsed() >= FREEZE_TIMEOUT {
+                frozen.thaw()?;
+                return Err(RustUtilsError::OperationFailed(format!(
+                    "cgroup {} did not freeze within {}s",
+                    dir.display(),
+                    FREEZE_TIMEOUT.as_secs()
+                )));
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+        info!("Froze cgroup {}", dir.display());
+        Ok(frozen)
+    }
+
+    /// Time since the freeze was requested.
+    pub fn frozen_for(&self) -> Duration {
+        self.since.elapsed()
+    }
+
+    /// Let the processes continue, unless the cgroup was frozen before.
+    pub fn thaw(&mut self) -> Result<()> {
+        if self.thawed {
+            return Ok(());
+        }
+        self.thawed = true;
+        if !self.was_frozen {
+            fs::write(self.dir.join("cgroup.freeze"), "0")?;
+            info!(
+                "Thawed cgroup {} after {:.2}s",
+                self.dir.display(),
+                self.frozen_for().as_secs_f64()
+            );
+        }
+        Ok(())
+    }
+}
+
+impl Drop for FrozenCgroup {
+    fn drop(&mut self) {
+        if let Err(e) = self.thaw() {
+            warn!("Failed to thaw cgroup {}: {}", self.dir.display(), e);
+        }
+    }
+}
+
+/// PIDs of the processes in the cgroup `dir` and its descendants.
+pub fn cgroup_pids(dir: &Path) -> Result<Vec<u32>> {
+    check_cgroup(dir)?;
+    let mut pids = Vec::new();
+    collect_pids(dir, &mut pids)?;
+    pids.sort_unstable();
+    Ok(pids)
+}
+
+fn collect_pids(dir: &Path, pids: &mut Vec<u32>) -> io::Result<()> {
+    let procs = fs::read_to_string(dir.join("cgroup.procs"))?;
+    pids.extend(
+        procs
+            .lines()
+            .filter_map(|line| line.trim().parse::<u32>().ok()),
+    );
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            collect_pids(&entry.path(), pids)?;
+        }
+    }
+    Ok(())
+}
+
+fn check_cgroup(dir: &Path) -> Result<()> {
+    if dir.join("cgroup.freeze").is_file() {
+        return Ok(());
+    }
+    Err(RustUtilsError::InvalidArguments(format!(
+        "{} is not a cgroup v2 directory with a freezer (no cgroup.freeze)",
+        dir.display()
+    )))
+}
+
+fn is_frozen(dir: &Path) -> Result<bool> {
+    let events = fs::read_to_string(dir.join("cgroup.events"))?;
+    Ok(events.lines().any(|line| line.trim() == "frozen 1"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    /// A directory laid out like a cgroup whose processes stop instantly.
+    fn fake_cgroup(frozen: bool) -> TempDir {
+        let dir = TempDir::new().unwrap();
+        fs::write(
+            dir.path().join("cgroup.freeze"),
+            if frozen { "1" } else { "0" },
+        )
+        .unwrap();
+        fs::write(dir.path().join("cgroup.events"), "populated 1\nfrozen 1\n").unwrap();
+        fs::write(dir.path().join("cgroup.procs"), "812\n").unwrap();
+        fs::create_dir(dir.path().join("init.scope")).unwrap();
+        fs::write(dir.path().join("init.scope/cgroup.procs"), "77\n90\n").unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_freeze_and_thaw() {
+        let cgroup = fake_cgroup(false);
+        let freeze = cgroup.path().join("cgroup.freeze");
+
+        let mut frozen = FrozenCgroup::freeze(cgroup.path()).unwrap();
+        assert_eq!(fs::read_to_string(&freeze).unwrap(), "1");
+        frozen.thaw().unwrap();
+        assert_eq!(fs::read_to_string(&freeze).unwrap(), "0");
+
+        // Dropping without an explicit thaw thaws too
+        drop(FrozenCgroup::freeze(cgroup.path()).unwrap());
+        assert_eq!(fs::read_to_string(&freeze).unwrap(), "0");
+    }
+
+    #[test]
+    fn test_already_frozen_cgroup_stays_frozen() {
+        let cgroup = fake_cgroup(true);
+        drop(FrozenCgroup::freeze(cgroup.path()).unwrap());
+        assert_eq!(
+            fs::read_to_string(cgroup.path().join("cgroup.freeze")).unwrap(),
+            "1"
+        );
+    }
+
+    #[test]
+    fn test_cgroup_pids() {
+        let cgroup = fake_cgroup(false);
+        assert_eq!(cgroup_pids(cgroup.path()).unwrap(), [77, 90, 812]);
+
+        let not_a_cgroup = TempDir::new().unwrap();
+        assert!(matches!(
+            cgroup_pids(not_a_cgroup.path()),
+            Err(RustUtilsError::InvalidArguments(_))
+        ));
+    }
+}
@@ -1,12 +1,176 @@
-use std::fs::Metadata;
-use std::path::Path;
+use std::collections::VecDeque;
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::{FileType, Metadata};
+use std::io;
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Component, Path, PathBuf};
+
+use nix::libc;
 
 use crate::error::{Result, RustUtilsError};
+use crate::glob;
+use crate::iostats::{self, Syscall};
+
+/// Type of an entry in a tree.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum EntryType {
+    File,
+    Directory,
+    Symlink,
+    Socket,
+    Fifo,
+    CharDevice,
+    BlockDevice,
+}
+
+impl EntryType {
+    pub fn of(file_type: FileType) -> Self {
+        if file_type.is_dir() {
+            EntryType::Directory
+        } else if file_type.is_symlink() {
+            EntryType::Symlink
+        } else if file_type.is_socket() {
+            EntryType::Socket
+        } else if file_type.is_fifo() {
+            EntryType::Fifo
+        } else if file_type.is_char_device() {
+            EntryType::CharDevice
+        } else if file_type.is_block_device() {
+            EntryType::BlockDevice
+        } else {
+            EntryType::File
+        }
+    }
+
+    /// Whether entries of this type are created afresh by the programs using them, usually
+    /// when a container starts, so the owners a tree holds for them do not last.
+    pub fn is_ephemeral(self) -> bool {
+        matches!(self, EntryType::Socket | EntryType::Fifo)
+    }
+}
+
+impl fmt::Display for EntryType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            EntryType::File => "file",
+            EntryType::Directory => "directory",
+            EntryType::Symlink => "symlink",
+            EntryType::Socket => "socket",
+            EntryType::Fifo => "fifo",
+            EntryType::CharDevice => "char-device",
+            EntryType::BlockDevice => "block-device",
+        })
+    }
+}
 
 pub fn get_file_metadata(path: &Path) -> Result<Metadata> {
-    std::fs::symlink_metadata(path).map_err(RustUtilsError::Io)
+    iostats::timed(Syscall::Statx, || std::fs::symlink_metadata(path)).map_err(RustUtilsError::Io)
+}
+
+/// Resolve `path` (absolute, or relative to `base`) to the form it takes while walking `base`.
+///
+/// The path must be an existing directory strictly below `base`; symlinks are resolved before
+/// the containment check so a link cannot point the result outside the tree.
+pub fn resolve_subdirectory(base: &Path, path: &Path) -> Result<PathBuf> {
+    let candidate = if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        base.join(path)
+    };
+
+    if !candidate.is_dir() {
+        return Err(RustUtilsError::DirectoryNotFound(
+            candidate.display().to_string(),
+        ));
+    }
+
+    let canonical_base = base.canonicalize()?;
+    let canonical = candidate.canonicalize()?;
+    match canonical.strip_prefix(&canonical_base) {
+        Ok(relative) if !relative.as_os_str().is_empty() => Ok(base.join(relative)),
+        _ => Err(RustUtilsError::InvalidArguments(format!(
+            "{} is not below base directory {}",
+            path.display(),
+            base.display()
+        ))),
+    }
 }
 
+/// Symlinks followed in one resolution before giving up, as the kernel's `MAXSYMLINKS`.
+const MAX_SYMLINKS: usize = 40;
+
+/// Resolve the symlink `link` below `root` the way a process whose root directory is `root`
+/// would, as the container of a rootfs sees it: absolute targets and `..` stay inside
+/// `root`. Returns the path of the final target, or `None` if the link dangles or loops.
+pub fn resolve_in_root(root: &Path, link: &Path) -> Result<Option<PathBuf>> {
+    let Ok(relative) = link.strip_prefix(root) else {
+        return Err(RustUtilsError::InvalidArguments(format!(
+            "{} is not below {}",
+            link.display(),
+            root.display()
+        )));
+    };
+    let mut resolved = PathBuf::new();
+    let mut pending: VecDeque<OsString> = relative
+        .components()
+        .map(|component| component.as_os_str().to_os_string())
+        .collect();
+    let mut followed = 0;
+    while let Some(name) = pending.pop_front() {
+        match Path::new(&name).components().next() {
+            Some(Component::ParentDir) => {
+                resolved.pop();
+                continue;
+            }
+            Some(Component::Normal(_)) => {}
+            _ => continue,
+        }
+        let candidate = resolved.join(&name);
+        let metadata = match std::fs::symlink_metadata(root.join(&candidate)) {
+            Ok(metadata) => metadata,
+            Err(e)
+                if e.kind() == io::ErrorKind::NotFound
+                    || e.raw_os_error() == Some(libc::ENOTDIR) =>
+            {
+                return Ok(None);
+            }
+            Err(e) => return Err(e.into()),
+        };
+        // `link` itself is followed too, like every symlink on the way
+        if !metadata.file_type().is_symlink() {
+            resolved = candidate;
+            continue;
+        }
+        followed += 1;
+        if followed > MAX_SYMLINKS {
+            return Ok(None);
+        }
+        let target = std::fs::read_link(root.join(&candidate))?;
+        if target.is_absolute() {
+            resolved.clear();
+        }
+        for component in target.components().rev() {
+            pending.push_front(component.as_os_str().to_os_string());
+        }
+    }
+    Ok(Some(root.join(resolved)))
+}
+
+/// Require `path` to be absent or an empty directory, so it can safely receive a new tree.
+pub fn ensure_empty_destination(path: &Path) -> Result<()> {
+    if path.symlink_metadata().is_ok()
+        && (!path.is_dir() || std::fs::read_dir(path)?.next().is_some())
+    {
+        return Err(RustUtilsError::InvalidArguments(format!(
+            "destination {} exists and is not an empty directory",
+            path.display()
+        )));
+    }
+    Ok(())
+}
+
+/// Whether `path` matches one of the exclude `patterns`.
 pub fn should_exclude(path: &Path, patterns: &[String]) -> bool {
     if patterns.is_empty() {
         return false;
@@ -23,39 +187,25 @@ pub fn should_exclude(path: &Path, patterns: &[String]) -> bool {
     false
 }
 
-fn matches_pattern(path: &str, pattern: &str) -> bool {
-    // Simple glob-like pattern matching
-    // This is a basic implementation - for production use, consider using the `glob` crate
-
-    // Empty pattern should not match anything
-    if pattern.is_empty() {
-        return false;
-    }
-
-    if pattern.contains('*') {
-        // Handle simple wildcard patterns
-        let parts: Vec<&str> = pattern.split('*').collect();
-
-        if parts.len() == 2 {
-            let prefix = parts[0];
-            let suffix = parts[1];
-
-            if prefix.is_empty() {
-                return path.ends_with(suffix);
-            }
-            if suffix.is_empty() {
-                return path.starts_with(prefix);
-            }
-            return path.starts_with(prefix) && path.ends_with(suffix);
-        }
-
-        // More complex patterns would need a proper glob implementation
-        // For now, fall back to exact match
-        return path == pattern;
-    }
+/// Whether `relative`, a path relative to the root of a walk, is covered by include
+/// `patterns`: it or one of the directories above it matches one, or there are none. The
+/// root itself, the empty path, is only covered when there are no patterns.
+pub fn should_include(relative: &Path, patterns: &[String]) -> bool {
+    patterns.is_empty()
+        || relative
+            .ancestors()
+            .filter(|path| !path.as_os_str().is_empty())
+            .any(|path| {
+                let path = path.to_string_lossy();
+                patterns
+                    .iter()
+                    .any(|pattern| matches_pattern(&path, pattern))
+            })
+}
 
-    // Exact match or substring match for directory patterns
-    path == pattern || path.contains(pattern)
+/// Whether glob `pattern` matches `path`; see [`crate::glob`] for the syntax.
+fn matches_pattern(path: &str, pattern: &str) -> bool {
+    glob::matches_path(pattern, path)
 }
 
 #[cfg(test)]
@@ -81,12 +231,13 @@ mod tests {
         // Pattern with only asterisk
         assert!(matches_pattern("anything", "*"));
 
-        // Multiple asterisks (fallback to exact match)
-        assert!(!matches_pattern("a.b.c", "a*b*c"));
+        // Multiple asterisks
+        assert!(matches_pattern("a.b.c", "a*b*c"));
 
-        // Substring matching
+        // Whole components anywhere in the path, not substrings
         assert!(matches_pattern("path/to/file", "path/to"));
         assert!(matches_pattern("long/path/name", "path"));
+        assert!(!matches_pattern("long/xpathy/name", "path"));
 
         // Case sensitivity
         assert!(!matches_pattern("File.LOG", "*.log"));
@@ -103,6 +254,26 @@ mod tests {
         assert!(!should_exclude(Path::new("src/main.rs"), &patterns));
     }
 
+    #[test]
+    fn test_should_include() {
+        let patterns = vec!["home/*".to_string(), "var/lib".to_string()];
+
+        assert!(should_include(Path::new("home/alice"), &patterns));
+        assert!(should_include(Path::new("var/lib"), &patterns));
+        // Everything below an included directory is included
+        assert!(should_include(
+            Path::new("var/lib/mysql/ibdata1"),
+            &patterns
+        ));
+        // Directories above included paths are not
+        assert!(!should_include(Path::new("home"), &patterns));
+        assert!(!should_include(Path::new(""), &patterns));
+        assert!(!should_include(Path::new("etc/passwd"), &patterns));
+
+        assert!(should_include(Path::new(""), &[]));
+        assert!(should_include(Path::new("etc/passwd"), &[]));
+    }
+
     #[test]
     fn test_should_exclude_empty_patterns() {
         let patterns: Vec<String> = vec![];
@@ -147,6 +318,73 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_resolve_subdirectory() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = TempDir::new()?;
+        let base = temp_dir.path();
+        fs::create_dir_all(base.join("mnt/data"))?;
+
+        assert_eq!(
+            resolve_subdirectory(base, Path::new("mnt/data"))?,
+            base.join("mnt/data")
+        );
+        assert_eq!(
+            resolve_subdirectory(base, &base.join("mnt/data"))?,
+            base.join("mnt/data")
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_resolve_subdirectory_rejects_outside_paths(
+    ) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = TempDir::new()?;
+        let base = temp_dir.path().join("rootfs");
+        let outside = temp_dir.path().join("outside");
+        fs::create_dir(&base)?;
+        fs::create_dir(&outside)?;
+        File::create(base.join("file"))?;
+
+        assert!(resolve_subdirectory(&base, &outside).is_err());
+        assert!(resolve_subdirectory(&base, Path::new("../outside")).is_err());
+        assert!(resolve_subdirectory(&base, &base).is_err());
+        assert!(resolve_subdirectory(&base, Path::new("missing")).is_err());
+        assert!(resolve_subdirectory(&base, Path::new("file")).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_resolve_in_root() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        use std::os::unix::fs::symlink;
+
+        let dir = TempDir::new()?;
+        let root = dir.path();
+        fs::create_dir_all(root.join("usr/share/zoneinfo"))?;
+        File::create(root.join("usr/share/zoneinfo/UTC"))?;
+        fs::create_dir(root.join("etc"))?;
+        // Absolute, relative and climbing out of the root, all inside the container
+        symlink("/usr/share/zoneinfo/UTC", root.join("etc/localtime"))?;
+        symlink("../usr/share", root.join("etc/share"))?;
+        symlink("../../../../usr", root.join("etc/usr"))?;
+        symlink("share/zoneinfo/UTC", root.join("usr/utc"))?;
+        symlink("/usr/utc", root.join("etc/chained"))?;
+        symlink("missing", root.join("etc/dangling"))?;
+        symlink("loop", root.join("etc/loop"))?;
+
+        let resolve = |link: &str| resolve_in_root(root, &root.join(link));
+        let utc = Some(root.join("usr/share/zoneinfo/UTC"));
+        assert_eq!(resolve("etc/localtime")?, utc);
+        assert_eq!(resolve("etc/chained")?, utc);
+        assert_eq!(resolve("etc/share")?, Some(root.join("usr/share")));
+        assert_eq!(resolve("etc/usr")?, Some(root.join("usr")));
+        assert_eq!(resolve("etc/dangling")?, None);
+        assert_eq!(resolve("etc/loop")?, None);
+        assert!(resolve_in_root(root, Path::new("/elsewhere")).is_err());
+        Ok(())
+    }
+
     #[test]
     fn test_get_file_metadata_nonexistent() {
         let result = get_file_metadata(Path::new("/nonexistent/file"));
@@ -0,0 +1,236 @@
+//! Glob patterns for `--exclude` and `--include`.
+//!
+//! A pattern matches a path if it matches some run of the path's components, so `tmp`
+//! matches `/srv/rootfs/tmp` and, through it, everything below. Within a component `*`
+//! matches any characters, `?` one character, `[a-z]` one of a class (`[!a-z]` or `[^a-z]`
+//! one outside it), and `\` takes the next character literally. A `**` component matches
+//! any number of components, none included, and `{a,b}` expands to each alternative.
+//! None of these match a `/`.
+
+/// Whether `pattern` matches some run of the components of `path`.
+pub fn matches_path(pattern: &str, path: &str) -> bool {
+    let path: Vec<&str> = components(path).collect();
+    expand_braces(pattern).iter().any(|alternative| {
+        let pattern: Vec<&str> = components(alternative).collect();
+        !pattern.is_empty()
+            && (0..path.len()).any(|start| match_components(&pattern, &path[start..]))
+    })
+}
+
+fn components(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|component| !component.is_empty())
+}
+
+/// Whether `pattern` matches the leading components of `path`.
+fn match_components(pattern: &[&str], path: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => true,
+        Some((&"**", rest)) => (0..=path.len()).any(|skip| match_components(rest, &path[skip..])),
+        Some((first, rest)) => match path.split_first() {
+            Some((component, path)) => {
+                match_component(first, component) && match_components(rest, path)
+            }
+            None => false,
+        },
+    }
+}
+
+/// Whether `pattern` matches all of the single component `name`.
+fn match_component(pattern: &str, name: &str) -> bool {
+    let mut chars = pattern.chars();
+    match chars.next() {
+        None => name.is_empty(),
+        Some('*') => {
+            let rest = chars.as_str().trim_start_matches('*');
+            name.char_indices()
+                .map(|(index, _)| index)
+                .chain([name.len()])
+                .any(|index| match_component(rest, &name[index..]))
+        }
+        Some('?') => {
+            let mut name = name.chars();
+            name.next().is_some() && match_component(chars.as_str(), name.as_str())
+        }
+        Some('[') => match parse_class(chars.as_str()) {
+            Some((class, rest)) => {
+                let mut name = name.chars();
+                name.next().is_some_and(|c| class.contains(c))
+                    && match_component(rest, name.as_str())
+            }
+            None => match_literal('[', chars.as_str(), name),
+        },
+        Some('\\') => {
+            let literal = chars.next().unwrap_or('\\');
+            match_literal(literal, chars.as_str(), name)
+        }
+        Some(c) => match_literal(c, chars.as_str(), name),
+    }
+}
+
+fn match_literal(literal: char, rest: &str, name: &str) -> bool {
+    name.strip_prefix(literal)
+        .is_some_and(|name| match_component(rest, name))
+}
+
+/// A `[...]` character class.
+struct Class<'a> {
+    negated: bool,
+    /// The characters and ranges between the brackets
+    body: &'a str,
+}
+
+impl Class<'_> {
+    fn contains(&self, c: char) -> bool {
+        let mut chars = self.body.chars().peekable();
+        let mut found = false;
+        while let Some(start) = chars.next() {
+            let mut end = start;
+            let mut lookahead = chars.clone();
+            if lookahead.next() == Some('-') {
+                if let Some(&last) = lookahead.peek() {
+                    end = last;
+                    chars = lookahead;
+                    chars.next();
+                }
+            }
+            found |= (start..=end).contains(&c);
+        }
+        found != self.negated
+    }
+}
+
+/// Parse the class after a `[`, returning it and the rest of the pattern, or `None` if it
+/// is not closed. A `]` right after the `[` or its negation belongs to the class.
+fn parse_class(pattern: &str) -> Option<(Class<'_>, &str)> {
+    let (negated, body) = match pattern.strip_prefix(['!', '^']) {
+        Some(body) => (true, body),
+        None => (false, pattern),
+    };
+    let skip = usize::from(body.starts_with(']'));
+    let close = skip + body[skip..].find(']')?;
+    Some((
+        Class {
+            negated,
+            body: &body[..close],
+        },
+        &body[close + 1..],
+    ))
+}
+
+/// Expand the first `{a,b,...}` set of `pattern` into one pattern per alternative, and
+/// those in turn. Sets may nest; unclosed braces and braces without a comma are literal.
+fn expand_braces(pattern: &str) -> Vec<String> {
+    let bytes = pattern.as_bytes();
+    let mut index = 0;
+    while index < bytes.len() {
+        match bytes[index] {
+            b'\\' => index += 1,
+            b'{' => {
+                if let Some((close, commas)) = brace_set(pattern, index) {
+                    let (prefix, suffix) = (&pattern[..index], &pattern[close + 1..]);
+                    let mut start = index + 1;
+                    let mut expanded = Vec::new();
+                    for end in commas.into_iter().chain([close]) {
+                        let alternative = format!("{prefix}{}{suffix}", &pattern[start..end]);
+                        expanded.extend(expand_braces(&alternative));
+                        start = end + 1;
+                    }
+                    return expanded;
+                }
+            }
+            _ => {}
+        }
+        index += 1;
+    }
+    vec![pattern.to_string()]
+}
+
+/// The closing brace and top-level commas of the set opening at `open`, if it is closed
+/// and has at least one comma.
+fn brace_set(pattern: &str, open: usize) -> Option<(usize, Vec<usize>)> {
+    let bytes = pattern.as_bytes();
+    let mut depth = 0;
+    let mut commas = Vec::new();
+    let mut index = open + 1;
+    while index < bytes.len() {
+        match bytes[index] {
+            b'\\' => index += 1,
+            b'{' => depth += 1,
+            b'}' if depth == 0 => return (!commas.is_empty()).then_some((index, commas)),
+            b'}' => depth -= 1,
+            b',' if depth == 0 => commas.push(index),
+            _ => {}
+        }
+        index += 1;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_components() {
+        // Any run of whole components, and so everything below a match
+        assert!(matches_path("tmp", "/srv/rootfs/tmp"));
+        assert!(matches_path("tmp", "/srv/rootfs/tmp/x/y"));
+        assert!(matches_path("var/log/*", "/srv/rootfs/var/log/a/b.log"));
+        assert!(!matches_path("tmp", "/srv/rootfs/tmpfiles"));
+        assert!(!matches_path("log/var", "/var/log"));
+        assert!(!matches_path("", "/srv"));
+        assert!(!matches_path("/", "/srv"));
+    }
+
+    #[test]
+    fn test_double_star() {
+        assert!(matches_path("var/**/cache/*", "var/cache/apt"));
+        assert!(matches_path(
+            "var/**/cache/*",
+            "/rootfs/var/lib/apt/cache/pkg"
+        ));
+        assert!(!matches_path("var/**/cache/*", "var/lib/cache"));
+        assert!(matches_path("**/*.log", "a/b/c.log"));
+        assert!(matches_path("**", "anything"));
+    }
+
+    #[test]
+    fn test_wildcards() {
+        assert!(matches_path("a*b*c", "a.b.c"));
+        assert!(matches_path("*.log", "x/error.log"));
+        assert!(!matches_path("*.log", "x/error.log.1"));
+        assert!(!matches_path("v*r", "vax/ar"));
+        assert!(matches_path("file?.txt", "file1.txt"));
+        assert!(!matches_path("file?.txt", "file.txt"));
+        assert!(matches_path("é?", "éé"));
+    }
+
+    #[test]
+    fn test_classes() {
+        assert!(matches_path("log.[0-9]", "log.7"));
+        assert!(!matches_path("log.[0-9]", "log.a"));
+        assert!(matches_path("log.[!0-9]", "log.a"));
+        assert!(matches_path("log.[^0-9]", "log.a"));
+        assert!(matches_path("[]x]", "]"));
+        assert!(matches_path("[a-]", "-"));
+        assert!(matches_path("[ab", "[ab"));
+    }
+
+    #[test]
+    fn test_braces() {
+        assert!(matches_path("*.{log,tmp}", "a.log"));
+        assert!(matches_path("*.{log,tmp}", "a.tmp"));
+        assert!(!matches_path("*.{log,tmp}", "a.txt"));
+        assert!(matches_path("{var/{log,cache},tmp}", "var/cache/x"));
+        assert_eq!(expand_braces("a{b,c{d,e}}f").len(), 3);
+        assert_eq!(expand_braces("{x}"), ["{x}"]);
+        assert_eq!(expand_braces("{a,b"), ["{a,b"]);
+    }
+
+    #[test]
+    fn test_escapes() {
+        assert!(matches_path(r"\*", "*"));
+        assert!(!matches_path(r"\*", "x"));
+        assert!(matches_path(r"\{a,b}", "{a,b}"));
+    }
+}
@@ -0,0 +1,227 @@
+//! Machine-readable description of the command line for `--help-json`, from which wrappers
+//! and GUIs can build forms for every command without parsing `--help` text.
+
+use std::any::TypeId;
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+
+use clap::{Arg, ArgAction, Command};
+use serde::Serialize;
+
+/// Version of the `--help-json` format written by this build.
+pub const HELP_FORMAT_VERSION: u32 = 1;
+
+/// The whole command line: the top-level command with its options and subcommands.
+#[derive(Serialize)]
+pub struct HelpTree {
+    pub format_version: u32,
+    pub version: String,
+    #[serde(flatten)]
+    pub command: HelpCommand,
+}
+
+/// A command or subcommand.
+#[derive(Serialize)]
+pub struct HelpCommand {
+    pub name: String,
+    /// Other names the command is accepted under
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub aliases: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub about: Option<String>,
+    pub args: Vec<HelpArg>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub subcommands: Vec<HelpCommand>,
+}
+
+/// An option or positional argument.
+#[derive(Serialize)]
+pub struct HelpArg {
+    pub id: String,
+    /// `--long` name without the dashes; absent for positional arguments
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub long: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub short: Option<char>,
+    /// Other long names the option is accepted under
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub aliases: Vec<String>,
+    /// `flag`, `count`, `boolean`, `integer`, `path`, `enum` or `string`
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    /// Whether the argument may be given several times or take several values
+    pub multiple: bool,
+    pub required: bool,
+    /// Whether the option is accepted after any subcommand too
+    pub global: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value_name: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub default: Vec<String>,
+    /// Accepted values of `enum` arguments
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub values: Vec<HelpValue>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub help: Option<String>,
+}
+
+/// One accepted value of an `enum` argument.
+#[derive(Serialize)]
+pub struct HelpValue {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub help: Option<String>,
+}
+
+impl HelpTree {
+    /// Describe `command`, the top-level command of the CLI.
+    pub fn new(command: &Command) -> Self {
+        Self {
+            format_version: HELP_FORMAT_VERSION,
+            version: command.get_version().unwrap_or_default().to_string(),
+            command: HelpCommand::new(command),
+        }
+    }
+}
+
+impl HelpCommand {
+    fn new(command: &Command) -> Self {
+        Self {
+            name: command.get_name().to_string(),
+            aliases: command.get_visible_aliases().map(str::to_string).collect(),
+            about: command.get_about().map(ToString::to_string),
+            args: command
+                .get_arguments()
+                .filter(|arg| !arg.is_hide_set())
+                .map(HelpArg::new)
+                .collect(),
+            subcommands: command
+                .get_subcommands()
+                .filter(|subcommand| !subcommand.is_hide_set())
+                .map(HelpCommand::new)
+                .collect(),
+        }
+    }
+}
+
+impl HelpArg {
+    fn new(arg: &Arg) -> Self {
+        Self {
+            id: arg.get_id().to_string(),
+            long: arg.get_long().map(str::to_string),
+            short: arg.get_short(),
+            aliases: arg
+                .get_visible_aliases()
+                .unwrap_or_default()
+                .into_iter()
+                .map(str::to_string)
+                .collect(),
+            kind: kind(arg),
+            multiple: matches!(arg.get_action(), ArgAction::Append)
+                || arg
+                    .get_num_args()
+                    .is_some_and(|range| range.max_values() > 1),
+            required: arg.is_required_set(),
+            global: arg.is_global_set(),
+            value_name: arg
+                .get_value_names()
+                .and_then(|names| names.first())
+                .map(ToString::to_string),
+            default: arg
+                .get_default_values()
+                .iter()
+                .map(|value| value.to_string_lossy().into_owned())
+                .collect(),
+            values: arg
+                .get_possible_values()
+                .iter()
+                .filter(|value| !value.is_hide_set())
+                .map(|value| HelpValue {
+                    name: value.get_name().to_string(),
+                    help: value.get_help().map(ToString::to_string),
+                })
+                .collect(),
+            help: arg.get_help().map(ToString::to_string),
+        }
+    }
+}
+
+/// What kind of value `arg` takes, as far as a form needs to know.
+fn kind(arg: &Arg) -> &'static str {
+    match arg.get_action() {
+        ArgAction::SetTrue | ArgAction::SetFalse => return "flag",
+        ArgAction::Count => return "count",
+        _ => {}
+    }
+    if !arg.get_possible_values().is_empty() {
+        return "enum";
+    }
+    let type_id = arg.get_value_parser().type_id();
+    if type_id == TypeId::of::<bool>() {
+        "boolean"
+    } else if [
+        TypeId::of::<u8>(),
+        TypeId::of::<u16>(),
+        TypeId::of::<u32>(),
+        TypeId::of::<u64>(),
+        TypeId::of::<usize>(),
+        TypeId::of::<i32>(),
+        TypeId::of::<i64>(),
+        TypeId::of::<NonZeroUsize>(),
+    ]
+    .iter()
+    .any(|id| type_id == *id)
+    {
+        "integer"
+    } else if type_id == TypeId::of::<PathBuf>() {
+        "path"
+    } else {
+        "string"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::Cli;
+    use clap::CommandFactory;
+
+    #[test]
+    fn test_help_tree() {
+        let tree = serde_json::to_value(HelpTree::new(&Cli::command())).unwrap();
+        assert_eq!(tree["format_version"], HELP_FORMAT_VERSION);
+        assert_eq!(tree["name"], "rust-utils");
+
+        let remap = tree["subcommands"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|command| command["name"] == "remap")
+            .unwrap();
+        assert_eq!(remap["aliases"], serde_json::json!(["shift"]));
+        let arg = |id: &str| {
+            remap["args"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .find(|arg| arg["id"] == id)
+                .unwrap()
+                .clone()
+        };
+        assert_eq!(arg("base_directory")["type"], "path");
+        assert_eq!(arg("base_directory")["required"], true);
+        assert!(arg("base_directory").get("long").is_none());
+        assert_eq!(arg("from_base")["type"], "integer");
+        assert_eq!(arg("range_size")["default"], serde_json::json!(["65536"]));
+        assert_eq!(arg("uid_only")["type"], "flag");
+        assert_eq!(arg("exclude")["multiple"], true);
+        assert_eq!(arg("hardlinks")["type"], "enum");
+        assert_eq!(arg("hardlinks")["values"][0]["name"], "first");
+        assert_eq!(remap["subcommands"][0]["name"], "undo");
+
+        let globals = tree["args"].as_array().unwrap();
+        let threads = globals.iter().find(|arg| arg["id"] == "threads").unwrap();
+        assert_eq!(threads["global"], true);
+        assert_eq!(threads["aliases"], serde_json::json!(["jobs"]));
+    }
+}
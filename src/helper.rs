@@ -0,0 +1,335 @@
+//! A privileged helper changing owners on behalf of unprivileged `remap` runs.
+//!
+//! `rust-utils chown-helper` holds `CAP_CHOWN`, with the capabilities to put back what a
+//! chown drops, and listens on a Unix socket. A client sends one JSON [`Request`] line per
+//! entry and reads one [`Response`] line back. The helper changes an owner only as far as
+//! its [`Policy`] allows: the client's UID must be allowed, the entry must lie in one of the
+//! allowed directories once every symlink on the way is resolved, and each new ID must be
+//! what one of the policy's mappings makes of the old one. Setuid and setgid bits and file
+//! capabilities are put back by the helper itself, from what it read before the chown, so
+//! a client never chooses anything written beyond the owner. [`Client`] is the other end,
+//! used by `remap --chown-helper`.
+
+use std::fs::{self, File, OpenOptions, Permissions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use nix::libc;
+use nix::sys::socket::{getsockopt, sockopt};
+use rustix::fs::{chownat, AtFlags, Gid, Uid};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+use xattr::FileExt;
+
+use crate::error::{Result, RustUtilsError};
+use crate::idmap::{IdKind, IdMap};
+use crate::iostats::{self, Syscall};
+use crate::xattrs::{capability_with_rootid, CAPABILITY_XATTR};
+
+/// Setuid, setgid and sticky bits, which a chown may clear.
+const SPECIAL_MODE_BITS: u32 = 0o7000;
+
+/// An ownership change a client asks for.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Request {
+    /// Absolute path of the entry; a symlink itself is changed, not its target
+    pub path: PathBuf,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    /// Put back setuid, setgid and sticky bits the chown clears
+    pub restore_mode: bool,
+    /// Put back the file capabilities the chown drops
+    pub preserve_xattrs: bool,
+}
+
+/// The helper's answer to a [`Request`].
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Response {
+    /// Why the change was refused or failed, if it was
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Whether special mode bits were put back
+    #[serde(default)]
+    pub mode_restored: bool,
+    /// Extended attributes put back
+    #[serde(default)]
+    pub xattrs_restored: u64,
+}
+
+/// What a helper lets its clients change.
+#[derive(Debug, Default)]
+pub struct Policy {
+    /// Canonical directories whose entries may be changed
+    pub dirs: Vec<PathBuf>,
+    /// UIDs of the users who may connect
+    pub clients: Vec<u32>,
+    /// The UIDs entries may be given, as a mapping of the UIDs they have
+    pub uid: IdMap,
+    /// Like `uid`, for GIDs
+    pub gid: IdMap,
+}
+
+impl Policy {
+    fn check_id(&self, kind: IdKind, from: u32, to: u32) -> Result<()> {
+        let map = match kind {
+            IdKind::Uid => &self.uid,
+            IdKind::Gid => &self.gid,
+        };
+        if map.get(from) != Some(to) {
+            return Err(RustUtilsError::Permission(format!(
+                "{kind} {from} may not become {to} under this helper's policy"
+            )));
+        }
+        Ok(())
+    }
+
+    /// Carry out `request` if the policy allows it.
+    fn apply(&self, request: &Request) -> Result<Response> {
+        let entry = OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_PATH | libc::O_NOFOLLOW)
+            .open(&request.path)?;
+        // Where the entry really is, whatever the path went through to reach it
+        let proc = PathBuf::from(format!("/proc/self/fd/{}", entry.as_raw_fd()));
+        let real = fs::read_link(&proc)?;
+        if !self.dirs.iter().any(|dir| real.starts_with(dir)) {
+            return Err(RustUtilsError::Permission(format!(
+                "{} is outside the directories this helper serves",
+                real.display()
+            )));
+        }
+
+        let metadata = entry.metadata()?;
+        let uid = request.uid.filter(|&uid| uid != metadata.uid());
+        let gid = request.gid.filter(|&gid| gid != metadata.gid());
+        if let Some(uid) = uid {
+            self.check_id(IdKind::Uid, metadata.uid(), uid)?;
+        }
+        if let Some(gid) = gid {
+            self.check_id(IdKind::Gid, metadata.gid(), gid)?;
+        }
+        let mut response = Response::default();
+        if uid.is_none() && gid.is_none() {
+            return Ok(response);
+        }
+
+        let file_type = metadata.file_type();
+        // File capabilities only exist on regular files, opened through the descriptor
+        let capability = if request.preserve_xattrs && file_type.is_file() {
+            let file = File::open(&proc)?;
+            let value = file.get_xattr(CAPABILITY_XATTR)?;
+            value.map(|value| (file, value))
+        } else {
+            None
+        };
+
+        // The descriptor itself, so a symlink is changed rather than what it links to
+        iostats::timed(Syscall::Fchownat, || {
+            chownat(
+                &entry,
+                "",
+                uid.map(Uid::from_raw),
+                gid.map(Gid::from_raw),
+                AtFlags::EMPTY_PATH,
+            )
+        })
+        .map_err(io::Error::from)?;
+
+        let mode = metadata.mode() & 0o7777;
+        if request.restore_mode && !file_type.is_symlink() && mode & SPECIAL_MODE_BITS != 0 {
+            let current = entry.metadata()?.mode() & 0o7777;
+            if current != mode {
+                fs::set_permissions(&proc, Permissions::from_mode(mode))?;
+                response.mode_restored = true;
+            }
+        }
+        if let Some((file, value)) = capability {
+            let value = capability_with_rootid(value, |id| self.uid.get(id).unwrap_or(id));
+            if file.get_xattr(CAPABILITY_XATTR)?.as_ref() != Some(&value) {
+                file.set_xattr(CAPABILITY_XATTR, &value)?;
+                response.xattrs_restored = 1;
+            }
+        }
+        Ok(response)
+    }
+}
+
+/// Serve the clients of `listener` under `policy`, each on a thread of its own, until
+/// accepting fails.
+pub fn serve(listener: UnixListener, policy: Policy) -> Result<()> {
+    let policy = Arc::new(policy);
+    loop {
+        let (stream, _) = listener.accept()?;
+        let policy = Arc::clone(&policy);
+        thread::spawn(move || {
+            if let Err(e) = serve_client(stream, &policy) {
+                warn!("Helper client failed: {}", e);
+            }
+        });
+    }
+}
+
+fn serve_client(stream: UnixStream, policy: &Policy) -> Result<()> {
+    let client = peer_uid(&stream)?;
+    let allowed = policy.clients.contains(&client);
+    if !allowed {
+        warn!("Refusing requests of UID {}", client);
+    }
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        let result = if allowed {
+            serde_json::from_str::<Request>(&line)
+                .map_err(|e| RustUtilsError::InvalidArguments(format!("bad request: {e}")))
+                .and_then(|request| {
+                    let response = policy.apply(&request);
+                    match &response {
+                        Ok(_) => debug!("Changed {} for UID {}", request.path.display(), client),
+                        Err(e) => debug!(
+                            "Refused {} for UID {}: {}",
+                            request.path.display(),
+                            client,
+                            e
+                        ),
+                    }
+                    response
+                })
+        } else {
+            Err(RustUtilsError::Permission(format!(
+                "UID {client} may not use this helper"
+            )))
+        };
+        let response = result.unwrap_or_else(|e| Response {
+            error: Some(e.to_string()),
+            ..Default::default()
+        });
+        let mut line = serde_json::to_vec(&response).expect("responses always serialize");
+        line.push(b'\n');
+        writer.write_all(&line)?;
+    }
+    Ok(())
+}
+
+/// UID of the process at the other end of `stream`, as the kernel recorded it on connect.
+fn peer_uid(stream: &UnixStream) -> Result<u32> {
+    Ok(getsockopt(stream, sockopt::PeerCredentials)?.uid())
+}
+
+/// A connection to a chown helper, shared by the threads changing owners.
+#[derive(Debug)]
+pub struct Client {
+    socket: PathBuf,
+    connection: Mutex<(BufReader<UnixStream>, UnixStream)>,
+}
+
+impl Client {
+    /// Connect to the helper listening on `socket`.
+    pub fn connect(socket: &Path) -> Result<Self> {
+        let stream = UnixStream::connect(socket).map_err(|e| {
+            RustUtilsError::OperationFailed(format!(
+                "cannot connect to the chown helper at {}: {e}",
+                socket.display()
+            ))
+        })?;
+        Ok(Self {
+            socket: socket.to_path_buf(),
+            connection: Mutex::new((BufReader::new(stream.try_clone()?), stream)),
+        })
+    }
+
+    /// Have the helper carry out `request`, failing with its reason if it does not.
+    pub fn chown(&self, request: &Request) -> Result<Response> {
+        let mut connection = self.connection.lock().map_err(|_| {
+            RustUtilsError::OperationFailed(format!(
+                "connection to the chown helper at {} was left unusable by a failed request",
+                self.socket.display()
+            ))
+        })?;
+        let (reader, writer) = &mut *connection;
+        let mut line = serde_json::to_vec(request).expect("requests always serialize");
+        line.push(b'\n');
+        writer.write_all(&line)?;
+        line.clear();
+        reader.read_until(b'\n', &mut line)?;
+        let response: Response = serde_json::from_slice(&line).map_err(|e| {
+            RustUtilsError::OperationFailed(format!(
+                "bad response from the chown helper at {}: {e}",
+                self.socket.display()
+            ))
+        })?;
+        match response.error {
+            Some(error) => Err(RustUtilsError::RemapFailed(format!(
+                "chown helper refused {}: {error}",
+                request.path.display()
+            ))),
+            None => Ok(response),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::{lchown, MetadataExt};
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_helper() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = TempDir::new()?;
+        let root = temp_dir.path().canonicalize()?;
+        let served = root.join("served");
+        let other = root.join("other");
+        fs::create_dir(&served)?;
+        fs::create_dir(&other)?;
+        for dir in [&served, &other] {
+            fs::write(dir.join("file"), "")?;
+            lchown(dir.join("file"), Some(100000), Some(100000))?;
+        }
+        fs::set_permissions(served.join("file"), Permissions::from_mode(0o4755))?;
+        std::os::unix::fs::symlink(&other, served.join("escape"))?;
+
+        let socket = root.join("helper.sock");
+        let listener = UnixListener::bind(&socket)?;
+        let policy = Policy {
+            dirs: vec![served.clone()],
+            clients: vec![nix::unistd::geteuid().as_raw()],
+            uid: IdMap::new(vec!["100000:200000:65536".parse()?])?,
+            gid: IdMap::new(vec!["100000:200000:65536".parse()?])?,
+        };
+        thread::spawn(move || serve(listener, policy));
+
+        let client = Client::connect(&socket)?;
+        let request = |path: PathBuf, id: u32| Request {
+            path,
+            uid: Some(id),
+            gid: Some(id),
+            restore_mode: true,
+            preserve_xattrs: true,
+        };
+        let response = client.chown(&request(served.join("file"), 200000))?;
+        assert!(response.mode_restored);
+        let metadata = fs::symlink_metadata(served.join("file"))?;
+        assert_eq!((metadata.uid(), metadata.gid()), (200000, 200000));
+        assert_eq!(metadata.mode() & 0o7777, 0o4755);
+
+        // Only what the policy maps, only below the served directories
+        for (path, id, message) in [
+            (served.join("file"), 0, "may not become 0"),
+            (
+                served.join("escape/file"),
+                200000,
+                "outside the directories",
+            ),
+        ] {
+            let error = client.chown(&request(path, id)).unwrap_err().to_string();
+            assert!(error.contains(message), "{error}");
+        }
+        assert_eq!(fs::symlink_metadata(other.join("file"))?.uid(), 100000);
+        Ok(())
+    }
+}
@@ -0,0 +1,200 @@
+//! Translated user-facing messages, kept as Fluent catalogs in `locales/<lang>/messages.ftl`.
+//!
+//! The language is `--lang` if given, else the first of `LC_ALL`, `LC_MESSAGES` and `LANG`
+//! naming a supported one, else English. Messages are logged with their catalog ID as the
+//! `code` field, which stays the same in every language so logs remain machine-parseable.
+//! Errors and run reports are not translated.
+
+use std::env;
+use std::sync::OnceLock;
+
+use clap::ValueEnum;
+use fluent_bundle::concurrent::FluentBundle;
+use fluent_bundle::{FluentArgs, FluentResource};
+use unic_langid::LanguageIdentifier;
+
+const ENGLISH: &str = include_str!("../locales/en/messages.ftl");
+const GERMAN: &str = include_str!("../locales/de/messages.ftl");
+
+/// Languages with a message catalog.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Lang {
+    #[default]
+    En,
+    De,
+}
+
+impl Lang {
+    /// The language of a POSIX locale name such as `de_DE.UTF-8`, if supported.
+    pub fn from_locale(locale: &str) -> Option<Self> {
+        let language = locale.split(['_', '.', '@']).next()?;
+        Self::from_str(language, true).ok()
+    }
+
+    /// The language selected by the locale environment variables.
+    pub fn from_env() -> Self {
+        ["LC_ALL", "LC_MESSAGES", "LANG"]
+            .into_iter()
+            .filter_map(|name| env::var(name).ok())
+            .find(|locale| !locale.is_empty())
+            .and_then(|locale| Self::from_locale(&locale))
+            .unwrap_or_default()
+    }
+
+    fn catalog(self) -> (&'static str, &'static str) {
+        match self {
+            Lang::En => ("en", ENGLISH),
+            Lang::De => ("de", GERMAN),
+        }
+    }
+}
+
+/// Bundles to look a message up in, the selected language first and English last.
+pub struct Catalog {
+    bundles: Vec<FluentBundle<FluentResource>>,
+}
+
+impl Catalog {
+    pub fn new(lang: Lang) -> Self {
+        let mut langs = vec![lang];
+        if lang != Lang::En {
+            langs.push(Lang::En);
+        }
+        let bundles = langs
+            .into_iter()
+            .map(|lang| {
+                let (id, source) = lang.catalog();
+                let id: LanguageIdentifier = id.parse().expect("catalog languages are valid");
+                let resource =
+                    FluentResource::try_new(source.to_string()).expect("bundled catalogs parse");
+                let mut bundle = FluentBundle::new_concurrent(vec![id]);
+                // Isolation marks around arguments would end up in log files and terminals
+                bundle.set_use_isolating(false);
+                bundle
+                    .add_resource(resource)
+                    .expect("bundled catalogs have unique IDs");
+                bundle
+            })
+            .collect();
+        Self { bundles }
+    }
+
+    /// Format message `id`, falling back to its ID if no catalog has it.
+    pub fn format(&self, id: &str, args: &FluentArgs) -> String {
+        for bundle in &self.bundles {
+            let Some(pattern) = bundle.get_message(id).and_then(|message| message.value()) else {
+                continue;
+            };
+            let mut errors = Vec::new();
+            return bundle
+                .format_pattern(pattern, Some(args), &mut errors)
+                .into_owned();
+        }
+        id.to_string()
+    }
+}
+
+static CATALOG: OnceLock<Catalog> = OnceLock::new();
+
+/// Select the language for this process; without it, the environment decides on first use.
+pub fn init(lang: Option<Lang>) {
+    CATALOG.get_or_init(|| Catalog::new(lang.unwrap_or_else(Lang::from_env)));
+}
+
+/// Format message `id` in the selected language.
+pub fn message(id: &str, args: &FluentArgs) -> String {
+    CATALOG
+        .get_or_init(|| Catalog::new(Lang::from_env()))
+        .format(id, args)
+}
+
+/// Format a catalog message, e.g. `tr!("remap-files-processed", count = 42)`.
+#[macro_export]
+macro_rules! tr {
+    ($id:literal $(, $name:ident = $value:expr)* $(,)?) => {{
+        #[allow(unused_mut)]
+        let mut args = fluent_bundle::FluentArgs::new();
+        $(args.set(stringify!($name), $value);)*
+        $crate::i18n::message($id, &args)
+    }};
+}
+
+/// Log a catalog message at `level` with its ID as the `code` field, e.g.
+/// `log_message!(INFO, "remap-completed")`.
+#[macro_export]
+macro_rules! log_message {
+    ($level:ident, $id:literal $(, $name:ident = $value:expr)* $(,)?) => {
+        tracing::event!(
+            tracing::Level::$level,
+            code = $id,
+            "{}",
+            $crate::tr!($id $(, $name = $value)*)
+        )
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(pairs: &[(&'static str, u64)]) -> FluentArgs<'static> {
+        let mut args = FluentArgs::new();
+        for &(name, value) in pairs {
+            args.set(name, value);
+        }
+        args
+    }
+
+    #[test]
+    fn test_from_locale() {
+        assert_eq!(Lang::from_locale("de_DE.UTF-8"), Some(Lang::De));
+        assert_eq!(Lang::from_locale("de"), Some(Lang::De));
+        assert_eq!(Lang::from_locale("en_GB@euro"), Some(Lang::En));
+        assert_eq!(Lang::from_locale("C.UTF-8"), None);
+        assert_eq!(Lang::from_locale("fr_FR"), None);
+    }
+
+    #[test]
+    fn test_format() {
+        let english = Catalog::new(Lang::En);
+        let german = Catalog::new(Lang::De);
+        let count = args(&[("count", 42)]);
+        assert_eq!(
+            english.format("remap-files-processed", &count),
+            "Files processed: 42"
+        );
+        assert_eq!(
+            german.format("remap-files-processed", &count),
+            "Verarbeitete Dateien: 42"
+        );
+        assert_eq!(german.format("no-such-message", &count), "no-such-message");
+    }
+
+    #[test]
+    fn test_german_plurals() {
+        let german = Catalog::new(Lang::De);
+        let mut args = args(&[("entries", 1), ("changed", 0), ("failed", 0)]);
+        args.set("device", "8:1");
+        args.set("fstype", "ext4");
+        args.set("mountpoint", "/");
+        assert_eq!(
+            german.format("remap-filesystem", &args),
+            "Dateisystem 8:1 (ext4 auf /): 1 Eintrag, 0 neu zugeordnet, 0 fehlgeschlagen"
+        );
+    }
+
+    #[test]
+    fn test_catalogs_have_the_same_messages() {
+        let ids = |source: &str| -> Vec<String> {
+            let mut ids: Vec<String> = source
+                .lines()
+                .filter(|line| !line.starts_with([' ', '#']))
+                .filter_map(|line| line.split_once(" = "))
+                .map(|(id, _)| id.to_string())
+                .collect();
+            ids.sort();
+            ids
+        };
+        assert_eq!(ids(ENGLISH), ids(GERMAN));
+    }
+}
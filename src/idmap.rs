@@ -0,0 +1,378 @@
+use std::fmt;
+use std::str::FromStr;
+
+use tracing::info;
+
+use crate::error::{Result, RustUtilsError};
+
+/// Which of an entry's IDs a mapping or range applies to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum IdKind {
+    Uid,
+    Gid,
+}
+
+impl fmt::Display for IdKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            IdKind::Uid => "uid",
+            IdKind::Gid => "gid",
+        })
+    }
+}
+
+/// A contiguous ID range mapping in `FROM:TO:COUNT` form, as used by
+/// `/proc/<pid>/uid_map` and `lxc.idmap`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IdMapping {
+    pub from: u32,
+    pub to: u32,
+    pub count: u32,
+}
+
+impl IdMapping {
+    /// Translate `id` if it falls inside this mapping's source range.
+    pub fn map(&self, id: u32) -> Option<u32> {
+        if id >= self.from && id - self.from < self.count {
+            Some(self.to + (id - self.from))
+        } else {
+            None
+        }
+    }
+
+    fn overlaps(&self, other: &IdMapping) -> bool {
+        self.from < other.from.saturating_add(other.count)
+            && other.from < self.from.saturating_add(self.count)
+    }
+}
+
+impl FromStr for IdMapping {
+    type Err = RustUtilsError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || {
+            RustUtilsError::InvalidArguments(format!(
+                "invalid mapping '{s}' (expected FROM:TO:COUNT)"
+            ))
+        };
+
+        let mut fields = s
+            .split(':')
+            .map(|field| field.parse::<u32>().map_err(|_| invalid()));
+        let (Some(from), Some(to), Some(count), None) =
+            (fields.next(), fields.next(), fields.next(), fields.next())
+        else {
+            return Err(invalid());
+        };
+        let mapping = IdMapping {
+            from: from?,
+            to: to?,
+            count: count?,
+        };
+
+        if mapping.count == 0 {
+            return Err(RustUtilsError::InvalidRange(format!(
+                "mapping '{s}' is empty"
+            )));
+        }
+        if mapping.from.checked_add(mapping.count).is_none()
+            || mapping.to.checked_add(mapping.count).is_none()
+        {
+            return Err(RustUtilsError::InvalidRange(format!(
+                "mapping '{s}' would overflow"
+            )));
+        }
+
+        Ok(mapping)
+    }
+}
+
+/// Parse the mapping of a single ID in `FROM:TO` form.
+pub fn parse_point(s: &str) -> Result<IdMapping> {
+    let invalid =
+        || RustUtilsError::InvalidArguments(format!("invalid mapping '{s}' (expected FROM:TO)"));
+    let (from, to) = s.split_once(':').ok_or_else(invalid)?;
+    Ok(IdMapping {
+        from: from.parse().map_err(|_| invalid())?,
+        to: to.parse().map_err(|_| invalid())?,
+        count: 1,
+    })
+}
+
+/// `FIRST-LAST` for a range of `count` IDs, or just `FIRST` for a single ID.
+pub fn id_span(first: u32, count: u32) -> String {
+    match count {
+        1 => first.to_string(),
+        count => format!("{}-{}", first, first + (count - 1)),
+    }
+}
+
+impl fmt::Display for IdMapping {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.from, self.to, self.count)
+    }
+}
+
+/// A set of non-overlapping [`IdMapping`]s. IDs outside every range are left unchanged.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct IdMap {
+    mappings: Vec<IdMapping>,
+}
+
+impl IdMap {
+    /// Build a map, rejecting mappings whose source ranges overlap.
+    pub fn new(mappings: Vec<IdMapping>) -> Result<Self> {
+        for (i, a) in mappings.iter().enumerate() {
+            if let Some(b) = mappings[i + 1..].iter().find(|b| a.overlaps(b)) {
+                return Err(RustUtilsError::InvalidRange(format!(
+                    "mappings {a} and {b} overlap"
+                )));
+            }
+        }
+        Ok(Self { mappings })
+    }
+
+    /// Map `range` except for the IDs `overrides` cover, which go to targets of their own.
+    /// Rejects overrides overlapping each other, and overrides with a target another ID of
+    /// the map is mapped to.
+    pub fn with_overrides(range: IdMapping, overrides: &[IdMapping]) -> Result<Self> {
+        let mut overrides = overrides.to_vec();
+        overrides.sort_by_key(|mapping| mapping.from);
+        let end = range.from.saturating_add(range.count);
+        // First ID of the range not yet mapped
+        let mut next = range.from;
+        let mut mappings = Vec::new();
+        for mapping in &overrides {
+            let mapping_end = mapping.from.saturating_add(mapping.count);
+            if mapping.from < end && mapping_end > next {
+                if mapping.from > next {
+                    mappings.push(IdMapping {
+                        from: next,
+                        to: range.to + (next - range.from),
+                        count: mapping.from - next,
+                    });
+                }
+                next = mapping_end.min(end);
+            }
+            mappings.push(*mapping);
+        }
+        if next < end {
+            mappings.push(IdMapping {
+                from: next,
+                to: range.to + (next - range.from),
+                count: end - next,
+            });
+        }
+
+        let map = Self::new(mappings)?;
+        for mapping in &overrides {
+            let shares_target = |other: &&IdMapping| {
+                other.from != mapping.from
+                    && mapping.to < other.to.saturating_add(other.count)
+                    && other.to < mapping.to.saturating_add(mapping.count)
+            };
+            if let Some(other) = map.mappings.iter().find(shares_target) {
+                return Err(RustUtilsError::InvalidRange(format!(
+                    "mappings {mapping} and {other} map to the same IDs"
+                )));
+            }
+        }
+        Ok(map)
+    }
+
+    pub fn mappings(&self) -> &[IdMapping] {
+        &self.mappings
+    }
+
+    /// The map taking the IDs this one maps to back to the IDs mapped to them. Fails if
+    /// two mappings have targets in common.
+    pub fn inverse(&self) -> Result<Self> {
+        Self::new(
+            self.mappings
+                .iter()
+                .map(|mapping| IdMapping {
+                    from: mapping.to,
+                    to: mapping.from,
+                    count: mapping.count,
+                })
+                .collect(),
+        )
+    }
+
+    /// Translate `id` through the first matching range, or `None` if no range covers it.
+    pub fn get(&self, id: u32) -> Option<u32> {
+        self.mappings.iter().find_map(|mapping| mapping.map(id))
+    }
+
+    /// Translate `id` through the first matching range, or return it unchanged.
+    pub fn map(&self, id: u32) -> u32 {
+        self.get(id).unwrap_or(id)
+    }
+
+    /// Whether some ID is mapped to `id`.
+    pub fn is_target(&self, id: u32) -> bool {
+        self.mappings
+            .iter()
+            .any(|mapping| id.wrapping_sub(mapping.to) < mapping.count)
+    }
+
+    /// Whether some ID is changed to one the map also changes, as when a range is shifted
+    /// by less than its size: a second look at an entry would change it again.
+    pub fn is_chained(&self) -> bool {
+        self.mappings.iter().filter(|a| a.from != a.to).any(|a| {
+            let targets = IdMapping { from: a.to, ..*a };
+            self.mappings.iter().any(|b| targets.overlaps(b))
+        })
+    }
+}
+
+/// Log the mappings a command applies, once for both kinds when they are the same.
+pub fn log_mappings(uid_map: &IdMap, gid_map: &IdMap) {
+    if uid_map == gid_map {
+        for mapping in uid_map.mappings() {
+            info!("Mapping: {}", mapping);
+        }
+        return;
+    }
+    for (kind, map) in [("UID", uid_map), ("GID", gid_map)] {
+        for mapping in map.mappings() {
+            info!("{} mapping: {}", kind, mapping);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_mapping() {
+        let mapping: IdMapping = "0:100000:65536".parse().unwrap();
+        assert_eq!(
+            mapping,
+            IdMapping {
+                from: 0,
+                to: 100000,
+                count: 65536
+            }
+        );
+        assert_eq!(mapping.to_string(), "0:100000:65536");
+    }
+
+    #[test]
+    fn test_parse_mapping_invalid() {
+        for input in ["", "0:100000", "0:100000:65536:1", "a:1:1", "-1:0:1"] {
+            assert!(matches!(
+                input.parse::<IdMapping>(),
+                Err(RustUtilsError::InvalidArguments(_))
+            ));
+        }
+        assert!(matches!(
+            "0:0:0".parse::<IdMapping>(),
+            Err(RustUtilsError::InvalidRange(_))
+        ));
+        assert!(matches!(
+            "4294967295:0:2".parse::<IdMapping>(),
+            Err(RustUtilsError::InvalidRange(_))
+        ));
+    }
+
+    #[test]
+    fn test_idmap_map() {
+        let map = IdMap::new(vec![
+            "0:100000:1000".parse().unwrap(),
+            "1000:5000:1".parse().unwrap(),
+        ])
+        .unwrap();
+
+        assert_eq!(map.map(0), 100000);
+        assert_eq!(map.map(999), 100999);
+        assert_eq!(map.map(1000), 5000);
+        // Unmapped IDs pass through
+        assert_eq!(map.map(1001), 1001);
+        assert_eq!(map.get(1001), None);
+    }
+
+    #[test]
+    fn test_parse_point() {
+        assert_eq!(
+            parse_point("33:3333").unwrap(),
+            IdMapping {
+                from: 33,
+                to: 3333,
+                count: 1
+            }
+        );
+        for input in ["33", "33:3333:1", "a:1", ":1"] {
+            assert!(matches!(
+                parse_point(input),
+                Err(RustUtilsError::InvalidArguments(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_idmap_with_overrides() {
+        let range = "0:100000:65536".parse().unwrap();
+        let points = [
+            parse_point("1000:5000").unwrap(),
+            parse_point("33:3333").unwrap(),
+            parse_point("70000:7000").unwrap(),
+        ];
+        let map = IdMap::with_overrides(range, &points).unwrap();
+
+        assert_eq!(map.map(0), 100000);
+        assert_eq!(map.map(33), 3333);
+        assert_eq!(map.map(34), 100034);
+        assert_eq!(map.map(1000), 5000);
+        assert_eq!(map.map(65535), 165535);
+        // Points outside the range are mapped too
+        assert_eq!(map.map(70000), 7000);
+        assert_eq!(map.get(65536), None);
+        assert!(map.is_target(5000));
+        assert!(!map.is_target(100033));
+        assert!(map.is_target(100034));
+
+        // Two targets for one ID, and two IDs for one target
+        assert!(
+            IdMap::with_overrides(range, &[points[0], parse_point("1000:6000").unwrap()]).is_err()
+        );
+        assert!(IdMap::with_overrides(range, &[parse_point("70000:100001").unwrap()]).is_err());
+        assert!(
+            IdMap::with_overrides(range, &[points[0], parse_point("70000:5000").unwrap()]).is_err()
+        );
+        // The range's own target for the ID is no conflict
+        assert!(IdMap::with_overrides(range, &[parse_point("1:100001").unwrap()]).is_ok());
+
+        // A range of IDs across the end of the range
+        let map = IdMap::with_overrides(range, &["60000:900000:10000".parse().unwrap()]).unwrap();
+        assert_eq!(map.map(59999), 159999);
+        assert_eq!(map.map(60000), 900000);
+        assert_eq!(map.map(69999), 909999);
+        assert!(!map.is_target(160000));
+    }
+
+    #[test]
+    fn test_idmap_is_chained() {
+        let chained = |mappings: &[&str]| {
+            let mappings = mappings.iter().map(|m| m.parse().unwrap()).collect();
+            IdMap::new(mappings).unwrap().is_chained()
+        };
+        assert!(!chained(&["100000:200000:65536"]));
+        assert!(!chained(&["100000:100000:65536"]));
+        assert!(chained(&["100000:130000:65536"]));
+        assert!(chained(&["130000:100000:65536"]));
+        // Through another mapping
+        assert!(chained(&["0:100000:1000", "100500:7:1"]));
+        assert!(!chained(&["0:100000:1000", "101000:5000:1"]));
+    }
+
+    #[test]
+    fn test_idmap_rejects_overlap() {
+        let result = IdMap::new(vec![
+            "0:100000:1000".parse().unwrap(),
+            "999:5000:10".parse().unwrap(),
+        ]);
+        assert!(matches!(result, Err(RustUtilsError::InvalidRange(_))));
+    }
+}
@@ -0,0 +1,244 @@
+//! The host's ID space: which ranges are delegated in `/etc/subuid` and `/etc/subgid`,
+//! mapped in LXC container configurations or used by running user namespaces, and which are
+//! still free for a new container.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use tracing::warn;
+
+use crate::error::{Result, RustUtilsError};
+use crate::idmap::IdKind;
+use crate::lxc;
+use crate::subid::{self, SubIdRange};
+
+/// A host ID range some configuration or process already uses.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UsedRange {
+    pub kind: IdKind,
+    pub range: SubIdRange,
+    /// Where the range comes from, e.g. `/etc/subuid: lxc`
+    pub source: String,
+}
+
+impl UsedRange {
+    fn end(&self) -> u64 {
+        u64::from(self.range.start) + u64::from(self.range.count)
+    }
+}
+
+impl fmt::Display for UsedRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}-{} ({}) {}",
+            self.range.start,
+            self.end() - 1,
+            self.kind,
+            self.source
+        )
+    }
+}
+
+/// Ranges delegated in a subordinate ID file; a missing file delegates nothing.
+pub fn from_subid_file(path: &Path, kind: IdKind) -> Result<Vec<UsedRange>> {
+    let entries = match subid::entries(path) {
+        Err(RustUtilsError::Io(e)) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        entries => entries?,
+    };
+    Ok(entries
+        .into_iter()
+        .map(|entry| UsedRange {
+            kind,
+            range: entry.range,
+            source: format!("{}: {}", path.display(), entry.owner),
+        })
+        .collect())
+}
+
+/// Host ranges of the `lxc.idmap` entries of every container configuration `DIR/*/config`.
+/// Configurations that cannot be read or parsed are skipped with a warning.
+pub fn from_lxc_configs(dir: &Path) -> Result<Vec<UsedRange>> {
+    let containers = match fs::read_dir(dir) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        containers => containers?,
+    };
+    let mut configs: Vec<_> = containers
+        .map(|entry| entry.map(|entry| entry.path().join("config")))
+        .collect::<io::Result<_>>()?;
+    configs.sort();
+
+    let mut used = Vec::new();
+    for config in configs.into_iter().filter(|config| config.is_file()) {
+        let idmaps = fs::read_to_string(&config)
+            .map_err(Into::into)
+            .and_then(|text| lxc::config_idmaps(&text));
+        match idmaps {
+            Ok(idmaps) => used.extend(idmaps.into_iter().map(|idmap| UsedRange {
+                kind: idmap.kind,
+                range: SubIdRange {
+                    start: idmap.mapping.to,
+                    count: idmap.mapping.count,
+                },
+                source: config.display().to_string(),
+            })),
+            Err(e) => warn!("Skipping {}: {}", config.display(), e),
+        }
+    }
+    Ok(used)
+}
+
+/// Host ranges mapped into the user namespaces of running processes, once per range. The
+/// identity mapping of the initial namespace is left out.
+pub fn from_namespaces() -> Result<Vec<UsedRange>> {
+    let mut seen = HashSet::new();
+    let mut used = Vec::new();
+    let mut pids: Vec<u32> = fs::read_dir("/proc")?
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+        .collect();
+    pids.sort_unstable();
+
+    for pid in pids {
+        for (kind, file) in [(IdKind::Uid, "uid_map"), (IdKind::Gid, "gid_map")] {
+            // Processes may exit while being looked at
+            let Ok(map) = fs::read_to_string(format!("/proc/{pid}/{file}")) else {
+                continue;
+            };
+            for line in map.lines() {
+                let fields: Vec<u32> = line
+                    .split_whitespace()
+                    .filter_map(|field| field.parse().ok())
+                    .collect();
+                let [_, start, count] = fields[..] else {
+                    continue;
+                };
+                if count == u32::MAX || !seen.insert((kind, start, count)) {
+                    continue;
+                }
+                let command = fs::read_to_string(format!("/proc/{pid}/comm")).unwrap_or_default();
+                used.push(UsedRange {
+                    kind,
+                    range: SubIdRange { start, count },
+                    source: format!("pid {} ({})", pid, command.trim_end()),
+                });
+            }
+        }
+    }
+    Ok(used)
+}
+
+/// Gaps between the `used` ranges of either kind within `first..=last`, lowest first.
+pub fn free_ranges(used: &[UsedRange], first: u32, last: u32) -> Vec<SubIdRange> {
+    let mut taken: Vec<(u64, u64)> = used
+        .iter()
+        .map(|used| (u64::from(used.range.start), used.end()))
+        .collect();
+    taken.sort_unstable();
+
+    let mut free = Vec::new();
+    let mut next = u64::from(first);
+    let end = u64::from(last) + 1;
+    for (start, stop) in taken {
+        if start > next && next < end {
+            free.push(gap(next, start.min(end)));
+        }
+        next = next.max(stop);
+    }
+    if next < end {
+        free.push(gap(next, end));
+    }
+    free
+}
+
+fn gap(start: u64, end: u64) -> SubIdRange {
+    SubIdRange {
+        start: start as u32,
+        count: (end - start) as u32,
+    }
+}
+
+/// Up to `count` ranges of `size` IDs inside the `free` ranges, lowest first. They start at
+/// `first` plus a multiple of `size`, the way `useradd` hands out subordinate IDs.
+pub fn propose(free: &[SubIdRange], size: u32, first: u32, count: usize) -> Vec<SubIdRange> {
+    let size64 = u64::from(size);
+    let mut proposed = Vec::new();
+    for range in free {
+        let end = u64::from(range.start) + u64::from(range.count);
+        let offset = u64::from(range.start).saturating_sub(u64::from(first));
+        let mut start = u64::from(first) + offset.div_ceil(size64) * size64;
+        while start + size64 <= end && proposed.len() < count {
+            proposed.push(SubIdRange {
+                start: start as u32,
+                count: size,
+            });
+            start += size64;
+        }
+    }
+    proposed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn used(start: u32, count: u32) -> UsedRange {
+        UsedRange {
+            kind: IdKind::Uid,
+            range: SubIdRange { start, count },
+            source: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_free_ranges() {
+        let taken = [used(100000, 65536), used(231072, 65536), used(120000, 10)];
+        assert_eq!(
+            free_ranges(&taken, 100000, 400000),
+            vec![
+                SubIdRange {
+                    start: 165536,
+                    count: 65536
+                },
+                SubIdRange {
+                    start: 296608,
+                    count: 103393
+                },
+            ]
+        );
+        assert!(free_ranges(&[used(0, 1000000)], 100000, 400000).is_empty());
+        assert_eq!(free_ranges(&[], 10, 19)[0].count, 10);
+    }
+
+    #[test]
+    fn test_propose() {
+        let free = free_ranges(&[used(100000, 65536), used(290000, 1)], 100000, 600100000);
+        let proposed = propose(&free, 65536, 100000, 3);
+        // 231072 would overlap 290000, so the next aligned start past it is used
+        let starts: Vec<u32> = proposed.iter().map(|range| range.start).collect();
+        assert_eq!(starts, [165536, 296608, 362144]);
+        assert!(propose(&free, 65536, 100000, 0).is_empty());
+    }
+
+    #[test]
+    fn test_from_lxc_configs() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::TempDir::new()?;
+        fs::create_dir_all(dir.path().join("web"))?;
+        fs::write(
+            dir.path().join("web/config"),
+            "lxc.idmap = u 0 100000 65536\nlxc.idmap = g 0 100000 65536\n",
+        )?;
+        fs::create_dir_all(dir.path().join("broken"))?;
+        fs::write(dir.path().join("broken/config"), "lxc.idmap = u 0\n")?;
+
+        let used = from_lxc_configs(dir.path())?;
+        assert_eq!(used.len(), 2);
+        assert_eq!(used[1].kind, IdKind::Gid);
+        assert!(used[0].source.ends_with("web/config"));
+        assert!(from_lxc_configs(&dir.path().join("missing"))?.is_empty());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,279 @@
+//! Latency of the system calls a run spends its time in, for `--profile-io`.
+//!
+//! Each call timed through [`timed`] lands in a histogram of power-of-two buckets of
+//! nanoseconds for its [`Syscall`], shared by all threads. A timed call made inside another
+//! counts towards its own system call only, as a profiler attributes self time, so the
+//! directory reads of a walk do not include the `statx` calls of its filter.
+//!
+//! Timed calls are also where `--throttle` paces a run, through [`throttle::paced`].
+//!
+//! At the end of the run the histograms are logged and written in the folded-stack format
+//! of `flamegraph.pl` and `inferno-flamegraph`, one line per command, system call and
+//! latency bucket, weighted by the microseconds spent there.
+
+use std::cell::Cell;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+use tracing::info;
+
+use crate::error::Result;
+use crate::report::RunReport;
+use crate::throttle;
+
+/// Buckets of a histogram: bucket `i` holds calls of 2^i to 2^(i+1) nanoseconds, the
+/// first also the faster ones and the last the slower ones.
+const BUCKETS: usize = 40;
+
+/// System calls that are timed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Syscall {
+    /// Reading an entry's metadata without following symlinks
+    Statx,
+    /// Changing an entry's owner
+    Fchownat,
+    /// Reading directory entries, together with opening the directory
+    Getdents,
+}
+
+impl Syscall {
+    pub const ALL: [Syscall; 3] = [Syscall::Statx, Syscall::Fchownat, Syscall::Getdents];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Syscall::Statx => "statx",
+            Syscall::Fchownat => "fchownat",
+            Syscall::Getdents => "getdents",
+        }
+    }
+}
+
+/// Calls of one system call by latency.
+#[derive(Debug)]
+pub struct Histogram {
+    calls: [AtomicU64; BUCKETS],
+    nanos: [AtomicU64; BUCKETS],
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            calls: std::array::from_fn(|_| AtomicU64::new(0)),
+            nanos: std::array::from_fn(|_| AtomicU64::new(0)),
+        }
+    }
+}
+
+impl Histogram {
+    fn record(&self, elapsed: Duration) {
+        let nanos = elapsed.as_nanos().min(u128::from(u64::MAX)) as u64;
+        let bucket = (nanos.max(1).ilog2() as usize).min(BUCKETS - 1);
+        self.calls[bucket].fetch_add(1, Ordering::Relaxed);
+        self.nanos[bucket].fetch_add(nanos, Ordering::Relaxed);
+    }
+
+    /// How many calls were timed.
+    pub fn calls(&self) -> u64 {
+        self.calls.iter().map(|c| c.load(Ordering::Relaxed)).sum()
+    }
+
+    /// Time spent in the calls.
+    pub fn total(&self) -> Duration {
+        Duration::from_nanos(self.nanos.iter().map(|n| n.load(Ordering::Relaxed)).sum())
+    }
+
+    /// Upper bound of the bucket holding the `percent`th percentile call.
+    pub fn percentile(&self, percent: u64) -> Duration {
+        let wanted = (self.calls() * percent).div_ceil(100).max(1);
+        let mut seen = 0;
+        for (bucket, calls) in self.calls.iter().enumerate() {
+            seen += calls.load(Ordering::Relaxed);
+            if seen >= wanted {
+                return Duration::from_nanos(1 << (bucket + 1));
+            }
+        }
+        Duration::from_nanos(1 << BUCKETS)
+    }
+}
+
+/// Histograms of every timed system call.
+#[derive(Debug, Default)]
+pub struct IoProfile {
+    histograms: [Histogram; 3],
+}
+
+impl IoProfile {
+    pub fn histogram(&self, syscall: Syscall) -> &Histogram {
+        &self.histograms[syscall as usize]
+    }
+
+    /// The profile in folded-stack format, with `command` as the root frame.
+    pub fn folded(&self, command: &str) -> String {
+        let mut folded = String::new();
+        for syscall in Syscall::ALL {
+            let histogram = self.histogram(syscall);
+            for bucket in 0..BUCKETS {
+                let micros = histogram.nanos[bucket]
+                    .load(Ordering::Relaxed)
+                    .div_ceil(1000);
+                if histogram.calls[bucket].load(Ordering::Relaxed) > 0 {
+                    let _ = writeln!(
+                        folded,
+                        "rust-utils;{};{};{}-{} {}",
+                        command,
+                        syscall.name(),
+                        format_nanos(1 << bucket),
+                        format_nanos(1 << (bucket + 1)),
+                        micros
+                    );
+                }
+            }
+        }
+        folded
+    }
+
+    /// Log a line for each system call and count the calls and their time in `report`.
+    pub fn summarize(&self, report: &mut RunReport) {
+        for syscall in Syscall::ALL {
+            let histogram = self.histogram(syscall);
+            let calls = histogram.calls();
+            if calls == 0 {
+                continue;
+            }
+            info!(
+                "{}: {} calls, {:.3}s, median under {}, 99th percentile under {}",
+                syscall.name(),
+                calls,
+                histogram.total().as_secs_f64(),
+                format_nanos(histogram.percentile(50).as_nanos() as u64),
+                format_nanos(histogram.percentile(99).as_nanos() as u64)
+            );
+            report
+                .count(&format!("{}_calls", syscall.name()), calls)
+                .duration(syscall.name(), histogram.total());
+        }
+    }
+
+    /// Write the profile of `command` to `path`.
+    pub fn write(&self, path: &Path, command: &str) -> Result<()> {
+        fs::write(path, self.folded(command))?;
+        Ok(())
+    }
+}
+
+static PROFILE: OnceLock<IoProfile> = OnceLock::new();
+
+thread_local! {
+    /// Time spent in timed calls made inside the one being timed on this thread
+    static NESTED: Cell<Duration> = const { Cell::new(Duration::ZERO) };
+}
+
+/// Start timing system calls for the rest of the process.
+pub fn enable() {
+    PROFILE.get_or_init(IoProfile::default);
+}
+
+/// The histograms collected since [`enable`], if it was called.
+pub fn profile() -> Option<&'static IoProfile> {
+    PROFILE.get()
+}
+
+/// Run `f`, which makes `syscall`, within the throttle of the thread, and record how long
+/// it took if timing is enabled.
+pub fn timed<T>(syscall: Syscall, f: impl FnOnce() -> T) -> T {
+    throttle::paced(|| {
+        let Some(profile) = PROFILE.get() else {
+            return f();
+        };
+        let outer = NESTED.replace(Duration::ZERO);
+        let started = Instant::now();
+        let result = f();
+        let elapsed = started.elapsed();
+        let inner = NESTED.replace(outer + elapsed);
+        profile
+            .histogram(syscall)
+            .record(elapsed.saturating_sub(inner));
+        result
+    })
+}
+
+/// An iterator whose steps are timed as `syscall`, for walks and directory listings that
+/// make their system calls as they go.
+pub struct Timed<I> {
+    syscall: Syscall,
+    inner: I,
+}
+
+impl<I> Timed<I> {
+    pub fn new(syscall: Syscall, inner: I) -> Self {
+        Self { syscall, inner }
+    }
+}
+
+impl<I: Iterator> Iterator for Timed<I> {
+    type Item = I::Item;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        timed(self.syscall, || self.inner.next())
+    }
+}
+
+/// `nanos` in the largest unit that keeps it whole, rounded down.
+fn format_nanos(nanos: u64) -> String {
+    match nanos {
+        0..1_000 => format!("{nanos}ns"),
+        1_000..1_000_000 => format!("{}us", nanos / 1_000),
+        1_000_000..1_000_000_000 => format!("{}ms", nanos / 1_000_000),
+        _ => format!("{}s", nanos / 1_000_000_000),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram() {
+        let histogram = Histogram::default();
+        for micros in [3, 3, 3, 200] {
+            histogram.record(Duration::from_micros(micros));
+        }
+        histogram.record(Duration::ZERO);
+        assert_eq!(histogram.calls(), 5);
+        assert_eq!(histogram.total(), Duration::from_micros(209));
+        // 3us falls in 2048-4096ns
+        assert_eq!(histogram.percentile(50), Duration::from_nanos(4096));
+        assert_eq!(histogram.percentile(99), Duration::from_nanos(262144));
+
+        let profile = IoProfile::default();
+        profile
+            .histogram(Syscall::Fchownat)
+            .record(Duration::from_micros(3));
+        profile
+            .histogram(Syscall::Fchownat)
+            .record(Duration::from_micros(3));
+        assert_eq!(
+            profile.folded("remap"),
+            "rust-utils;remap;fchownat;2us-4us 6\n"
+        );
+    }
+
+    #[test]
+    fn test_timed_nested() {
+        enable();
+        let before = profile().unwrap().histogram(Syscall::Getdents).total();
+        timed(Syscall::Getdents, || {
+            timed(Syscall::Statx, || {
+                std::thread::sleep(Duration::from_millis(50))
+            })
+        });
+        // The nested call's time is its own, not the directory read's
+        let getdents = profile().unwrap().histogram(Syscall::Getdents).total() - before;
+        assert!(getdents < Duration::from_millis(40), "{getdents:?}");
+        assert!(profile().unwrap().histogram(Syscall::Statx).total() >= Duration::from_millis(50));
+    }
+}
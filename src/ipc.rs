@@ -0,0 +1,191 @@
+//! POSIX IPC objects in a tree: message queues on an mqueue filesystem, and shared memory
+//! segments and named semaphores on the tmpfs mounted at `/dev/shm`.
+//!
+//! They show up as regular files, but only their owner and mode are file-like: reading a
+//! message queue returns a status line, and a segment's contents belong to the processes
+//! sharing it. Their owners decide who may open them, so a remap changes them like any other
+//! entry without ever opening them.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::fs::Metadata;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use crate::mounts::Mount;
+
+/// Where this system mounts the filesystems of its own IPC namespace.
+const HOST_IPC_MOUNTS: [&str; 2] = ["/dev/mqueue", "/dev/shm"];
+
+/// Mount points below a root that hold shared memory, relative to the root.
+const SHM_DIRS: [&str; 2] = ["dev/shm", "run/shm"];
+
+/// Kind of POSIX IPC object.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IpcKind {
+    MessageQueue,
+    SharedMemory,
+    /// Named semaphore, kept as `sem.NAME` next to the shared memory segments
+    Semaphore,
+}
+
+impl fmt::Display for IpcKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            IpcKind::MessageQueue => "message queue",
+            IpcKind::SharedMemory => "shared memory segment",
+            IpcKind::Semaphore => "semaphore",
+        })
+    }
+}
+
+/// The devices of the IPC filesystems mounted below a root.
+#[derive(Clone, Debug, Default)]
+pub struct IpcMounts {
+    mqueue: HashSet<u64>,
+    shm: HashSet<u64>,
+}
+
+impl IpcMounts {
+    /// Find the IPC filesystems among `mounts` below the canonical path `root`: every
+    /// mqueue mount, and tmpfs mounts at `dev/shm` or `run/shm`.
+    pub fn below(root: &Path, mounts: &[Mount]) -> Self {
+        let mut ipc = Self::default();
+        for mount in mounts {
+            let Ok(relative) = mount.mountpoint.strip_prefix(root) else {
+                continue;
+            };
+            match mount.fstype.as_str() {
+                "mqueue" => {
+                    ipc.mqueue.insert(mount.device);
+                }
+                "tmpfs" if SHM_DIRS.iter().any(|dir| relative == Path::new(dir)) => {
+                    ipc.shm.insert(mount.device);
+                }
+                _ => {}
+            }
+        }
+        ipc
+    }
+
+    /// The kind of IPC object at `path`, if it is one.
+    pub fn classify(&self, path: &Path, metadata: &Metadata) -> Option<IpcKind> {
+        if !metadata.is_file() {
+            return None;
+        }
+        if self.mqueue.contains(&metadata.dev()) {
+            return Some(IpcKind::MessageQueue);
+        }
+        if !self.shm.contains(&metadata.dev()) {
+            return None;
+        }
+        let semaphore = path
+            .file_name()
+            .is_some_and(|name| name.as_bytes().starts_with(b"sem."));
+        Some(if semaphore {
+            IpcKind::Semaphore
+        } else {
+            IpcKind::SharedMemory
+        })
+    }
+}
+
+/// Mounts below the canonical path `root` of this system's own `/dev/mqueue` or `/dev/shm`,
+/// e.g. bind mounts of them. Their objects belong to processes running here, not to the
+/// tree, and must be left alone.
+pub fn host_ipc_mounts(root: &Path, mounts: &[Mount]) -> Vec<PathBuf> {
+    let host: HashSet<u64> = mounts
+        .iter()
+        .filter(|mount| {
+            HOST_IPC_MOUNTS
+                .iter()
+                .any(|dir| mount.mountpoint == Path::new(dir))
+        })
+        .map(|mount| mount.device)
+        .collect();
+    mounts
+        .iter()
+        .filter(|mount| {
+            host.contains(&mount.device)
+                && mount.mountpoint.starts_with(root)
+                && mount.mountpoint != root
+                && !HOST_IPC_MOUNTS
+                    .iter()
+                    .any(|dir| mount.mountpoint == Path::new(dir))
+        })
+        .map(|mount| mount.mountpoint.clone())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    fn mount(device: u64, mountpoint: &Path, fstype: &str) -> Mount {
+        Mount {
+            device,
+            mountpoint: mountpoint.to_path_buf(),
+            fstype: fstype.to_string(),
+            source: fstype.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_classify() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let dir = TempDir::new()?;
+        let root = dir.path().canonicalize()?;
+        let shm = root.join("dev/shm");
+        fs::create_dir_all(&shm)?;
+        for name in ["segment", "sem.lock"] {
+            fs::write(shm.join(name), "")?;
+        }
+        let device = fs::metadata(&root)?.dev();
+        let classify = |ipc: &IpcMounts, name: &str| {
+            let path = shm.join(name);
+            ipc.classify(&path, &fs::symlink_metadata(&path).unwrap())
+        };
+
+        // The test files stand in for objects on a tmpfs mounted at dev/shm
+        let ipc = IpcMounts::below(&root, &[mount(device, &shm, "tmpfs")]);
+        assert_eq!(classify(&ipc, "segment"), Some(IpcKind::SharedMemory));
+        assert_eq!(classify(&ipc, "sem.lock"), Some(IpcKind::Semaphore));
+        assert_eq!(
+            ipc.classify(&shm, &fs::symlink_metadata(&shm)?),
+            None,
+            "directories are not IPC objects"
+        );
+
+        let ipc = IpcMounts::below(&root, &[mount(device, &root.join("dev/mqueue"), "mqueue")]);
+        assert_eq!(classify(&ipc, "segment"), Some(IpcKind::MessageQueue));
+
+        // tmpfs elsewhere, and IPC mounts outside the root, hold ordinary files
+        let ipc = IpcMounts::below(
+            &root,
+            &[
+                mount(device, &root.join("tmp"), "tmpfs"),
+                mount(device, Path::new("/dev/mqueue"), "mqueue"),
+            ],
+        );
+        assert_eq!(classify(&ipc, "segment"), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_host_ipc_mounts() {
+        let root = Path::new("/var/lib/lxc/web/rootfs");
+        let mounts = [
+            mount(20, Path::new("/dev/shm"), "tmpfs"),
+            mount(21, Path::new("/dev/mqueue"), "mqueue"),
+            mount(20, &root.join("dev/shm"), "tmpfs"),
+            mount(30, &root.join("dev/mqueue"), "mqueue"),
+            mount(21, Path::new("/srv/mqueue"), "mqueue"),
+        ];
+        assert_eq!(host_ipc_mounts(root, &mounts), [root.join("dev/shm")]);
+        // The tree is this system's own root
+        assert!(host_ipc_mounts(Path::new("/"), &mounts[..2]).is_empty());
+    }
+}
@@ -1,4 +1,55 @@
+#![deny(unsafe_code)]
+
+pub mod anchor;
+pub mod atomic;
+pub mod caps;
+pub mod checkpoint;
 pub mod cli;
 pub mod commands;
+pub mod compress;
+pub mod diagnose;
 pub mod error;
+pub mod fakeroot;
+pub mod freezer;
 pub mod fs;
+pub mod glob;
+pub mod help;
+pub mod helper;
+pub mod i18n;
+pub mod idmap;
+pub mod idspace;
+pub mod iostats;
+pub mod ipc;
+pub mod live;
+pub mod lock;
+pub mod logfile;
+pub mod lxc;
+pub mod mapfile;
+pub mod marker;
+pub mod metacache;
+pub mod mounts;
+pub mod nested;
+pub mod overlay;
+pub mod partition;
+pub mod passwd;
+pub mod pipeline;
+pub mod plan;
+pub mod plugin;
+pub mod probe;
+pub mod profile;
+pub mod progress;
+pub mod project;
+pub mod remote;
+pub mod report;
+pub mod safety;
+pub mod shadow;
+pub mod signals;
+pub mod state;
+pub mod stream;
+pub mod subid;
+pub mod throttle;
+pub mod time;
+pub mod undo;
+pub mod util;
+pub mod walk;
+pub mod xattrs;
@@ -0,0 +1,151 @@
+//! Detection of processes using a tree, such as the init of a container still running on its
+//! rootfs, by scanning `/proc`.
+//!
+//! A process uses a tree if its root directory, working directory or any open file lies
+//! inside it. Processes in other mount namespaces may report paths that do not resolve in
+//! ours, so a root directory is also matched by device and inode against the tree's top.
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+/// How a process uses the tree.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Usage {
+    /// Chrooted or pivoted into the tree, as the processes of a running container are
+    Root,
+    /// Working directory inside the tree
+    Cwd,
+    /// Holds a file inside the tree open
+    OpenFile,
+}
+
+impl fmt::Display for Usage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Usage::Root => "has its root directory in it",
+            Usage::Cwd => "has its working directory in it",
+            Usage::OpenFile => "has a file in it open",
+        })
+    }
+}
+
+/// A process using the tree.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LiveUser {
+    pub pid: u32,
+    /// Command name from `/proc/PID/comm`
+    pub command: String,
+    pub usage: Usage,
+    /// Path through which the process uses the tree
+    pub path: PathBuf,
+}
+
+impl fmt::Display for LiveUser {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "process {} ({}) {}: {}",
+            self.pid,
+            self.command,
+            self.usage,
+            self.path.display()
+        )
+    }
+}
+
+/// Processes other than this one using the tree at `base`, at most one entry per process.
+///
+/// Processes that vanish or whose details cannot be read (e.g. other users' when not root)
+/// are skipped.
+pub fn find_users(base: &Path) -> io::Result<Vec<LiveUser>> {
+    let base = fs::canonicalize(base)?;
+    let top = fs::metadata(&base)?;
+    let own_pid = std::process::id();
+
+    let mut users = Vec::new();
+    for entry in fs::read_dir("/proc")? {
+        let entry = entry?;
+        let Some(pid) = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.parse().ok())
+        else {
+            continue;
+        };
+        if pid == own_pid {
+            continue;
+        }
+        if let Some((usage, path)) = usage(&entry.path(), &base, &top) {
+            let command = fs::read_to_string(entry.path().join("comm"))
+                .map(|comm| comm.trim_end().to_string())
+                .unwrap_or_default();
+            users.push(LiveUser {
+                pid,
+                command,
+                usage,
+                path,
+            });
+        }
+    }
+    users.sort_by_key(|user| user.pid);
+    Ok(users)
+}
+
+fn usage(process: &Path, base: &Path, top: &fs::Metadata) -> Option<(Usage, PathBuf)> {
+    let inside = |path: &Path| path.starts_with(base);
+
+    if let Ok(root) = fs::read_link(process.join("root")) {
+        let same_inode = fs::metadata(process.join("root"))
+            .is_ok_and(|metadata| metadata.dev() == top.dev() && metadata.ino() == top.ino());
+        if inside(&root) || same_inode {
+            return Some((Usage::Root, root));
+        }
+    }
+    if let Ok(cwd) = fs::read_link(process.join("cwd")) {
+        if inside(&cwd) {
+            return Some((Usage::Cwd, cwd));
+        }
+    }
+    fs::read_dir(process.join("fd"))
+        .ok()?
+        .filter_map(|fd| fs::read_link(fd.ok()?.path()).ok())
+        .find(|target| inside(target))
+        .map(|target| (Usage::OpenFile, target))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_find_users() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let dir = TempDir::new()?;
+        let busy = dir.path().join("busy");
+        let idle = dir.path().join("idle");
+        fs::create_dir(&busy)?;
+        fs::create_dir(&idle)?;
+
+        let mut child = Command::new("sleep").arg("30").current_dir(&busy).spawn()?;
+        let users = find_users(&busy);
+        let idle_users = find_users(&idle);
+        child.kill()?;
+        child.wait()?;
+
+        let users = users?;
+        let user = users
+            .iter()
+            .find(|user| user.pid == child.id())
+            .expect("the child is found");
+        assert_eq!(user.usage, Usage::Cwd);
+        assert_eq!(user.command, "sleep");
+        assert_eq!(user.path, fs::canonicalize(&busy)?);
+        assert!(idle_users?.is_empty());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,103 @@
+//! Advisory locks keeping two runs from changing the same tree at once, such as a retried
+//! provisioning task starting a remap while the first one is still going, which would shift
+//! some entries twice.
+//!
+//! Locks are `flock`s on files in the state directory, so they are released however the
+//! holder ends, and a run finding one held fails at once instead of waiting. The holder
+//! writes its PID to the file for the error message of the run refused.
+
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, Write};
+use std::os::fd::AsRawFd;
+use std::path::Path;
+
+use nix::errno::Errno;
+use nix::fcntl::{flock, FlockArg};
+
+use crate::error::{Result, RustUtilsError};
+
+/// A lock held until dropped.
+#[derive(Debug)]
+pub struct RunLock {
+    _file: File,
+}
+
+impl RunLock {
+    /// Lock the file at `path` on behalf of a run changing `tree`, exclusively or shared with
+    /// other shared holders, and fail if a conflicting lock is held.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`RustUtilsError::InUse`] naming the holder if the lock is held.
+    pub fn acquire(path: &Path, exclusive: bool, tree: &Path) -> Result<Self> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        let arg = if exclusive {
+            FlockArg::LockExclusiveNonblock
+        } else {
+            FlockArg::LockSharedNonblock
+        };
+        match flock(file.as_raw_fd(), arg) {
+            Ok(()) => {}
+            Err(Errno::EWOULDBLOCK) => {
+                let mut holder = String::new();
+                file.read_to_string(&mut holder)?;
+                let holder = match holder.trim().parse::<u32>() {
+                    Ok(pid) => format!("process {pid}"),
+                    Err(_) => "another process".to_string(),
+                };
+                return Err(RustUtilsError::InUse(format!(
+                    "{} is being changed by {} (lock {}); wait for it to finish",
+                    tree.display(),
+                    holder,
+                    path.display()
+                )));
+            }
+            Err(e) => return Err(e.into()),
+        }
+        file.set_len(0)?;
+        file.rewind()?;
+        write!(file, "{}", std::process::id())?;
+        Ok(Self { _file: file })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_run_lock() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = TempDir::new()?;
+        let path = temp_dir.path().join("tree.lock");
+        let tree = Path::new("/srv/web");
+
+        let lock = RunLock::acquire(&path, true, tree)?;
+        let error = RunLock::acquire(&path, false, tree).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "Tree in use: /srv/web is being changed by process {} (lock {}); wait for it to finish",
+                std::process::id(),
+                path.display()
+            )
+        );
+        drop(lock);
+
+        // Shared holders exclude only exclusive ones
+        let first = RunLock::acquire(&path, false, tree)?;
+        let second = RunLock::acquire(&path, false, tree)?;
+        assert!(matches!(
+            RunLock::acquire(&path, true, tree),
+            Err(RustUtilsError::InUse(_))
+        ));
+        drop((first, second));
+        RunLock::acquire(&path, true, tree)?;
+        Ok(())
+    }
+}
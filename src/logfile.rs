@@ -0,0 +1,387 @@
+//! Log file written with `--log-file`, rotated by size or age so long-running and regularly
+//! scheduled runs do not grow it without bound.
+//!
+//! When the file reaches `--log-max-size` or is older than `--log-rotate-every`, it is
+//! compressed to `FILE.1.gz`, older generations move up to `FILE.2.gz` and so on, and
+//! anything beyond `--log-keep` generations is deleted.
+//!
+//! With `--log-format json` the file holds one JSON object per line instead: every event at
+//! info level and above whatever `--quiet`, `--verbose` or `RUST_LOG` say, including the
+//! [`ENTRY_TARGET`] records of each entry changed or failed, which the terminal never shows.
+
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use clap::{Args, ValueEnum};
+use flate2::write::GzEncoder;
+use serde_json::{Map, Value};
+use tracing::field::{Field, Visit};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+
+use crate::error::{Result, RustUtilsError};
+use crate::progress::parse_duration;
+use crate::stream::ByteSize;
+
+#[derive(Args, Clone, Debug)]
+pub struct LogFileArgs {
+    /// Also write log output to FILE, rotating it by size and age
+    #[arg(long, global = true, value_name = "FILE")]
+    pub log_file: Option<PathBuf>,
+
+    /// Rotate the log file once it reaches this size (e.g. 10M)
+    #[arg(long, global = true, value_name = "SIZE", default_value = "10M")]
+    pub log_max_size: ByteSize,
+
+    /// Rotate the log file once it is older than this (e.g. 12h, 1d)
+    #[arg(long, global = true, value_name = "DURATION")]
+    pub log_rotate_every: Option<MaxAge>,
+
+    /// Number of compressed old log files to keep
+    #[arg(long, global = true, value_name = "N", default_value_t = 5)]
+    pub log_keep: u32,
+
+    /// Format of the log file
+    #[arg(long, global = true, value_enum, default_value_t = LogFormat::Text)]
+    pub log_format: LogFormat,
+}
+
+/// Format of the log file.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    /// The lines shown on the terminal, without colours
+    #[default]
+    Text,
+    /// One JSON object per event, with a record of every entry changed or failed
+    Json,
+}
+
+/// Target of the events recording one entry changed or failed, written only to JSON log
+/// files.
+pub const ENTRY_TARGET: &str = "rust_utils::entry";
+
+/// Age after which the log file is rotated.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MaxAge(pub Duration);
+
+impl FromStr for MaxAge {
+    type Err = RustUtilsError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match parse_duration(s) {
+            Some(duration) if !duration.is_zero() => Ok(MaxAge(duration)),
+            _ => Err(RustUtilsError::InvalidArguments(format!(
+                "invalid rotation age '{s}' (expected a duration such as 30m, 12h or 1d)"
+            ))),
+        }
+    }
+}
+
+/// Log file that rotates itself before a write would take it past its limits.
+pub struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    created: SystemTime,
+    max_size: u64,
+    max_age: Option<Duration>,
+    keep: u32,
+    rotation_failed: bool,
+}
+
+impl RotatingFile {
+    /// Open `args.log_file` for appending, creating it if missing.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`RustUtilsError::InvalidArguments`] if no log file was given, and an I/O
+    /// error if it cannot be opened.
+    pub fn open(args: &LogFileArgs) -> Result<Self> {
+        let path = args
+            .log_file
+            .clone()
+            .ok_or_else(|| RustUtilsError::InvalidArguments("no log file given".to_string()))?;
+        let (file, size, created) = open_append(&path)?;
+        Ok(Self {
+            path,
+            file,
+            size,
+            created,
+            max_size: args.log_max_size.0,
+            max_age: args.log_rotate_every.map(|age| age.0),
+            keep: args.log_keep,
+            rotation_failed: false,
+        })
+    }
+
+    fn is_due(&self, incoming: usize) -> bool {
+        if self.size == 0 {
+            return false;
+        }
+        let too_big = self.size + incoming as u64 > self.max_size;
+        let too_old = self.max_age.is_some_and(|max_age| {
+            SystemTime::now()
+                .duration_since(self.created)
+                .is_ok_and(|age| age >= max_age)
+        });
+        too_big || too_old
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        let generation = |n: u32| {
+            let mut name = self.path.clone().into_os_string();
+            name.push(format!(".{n}.gz"));
+            PathBuf::from(name)
+        };
+
+        if self.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            remove_if_exists(&generation(self.keep))?;
+            for n in (1..self.keep).rev() {
+                if generation(n).exists() {
+                    fs::rename(generation(n), generation(n + 1))?;
+                }
+            }
+            compress(&self.path, &generation(1))?;
+            fs::remove_file(&self.path)?;
+        }
+
+        (self.file, self.size, self.created) = open_append(&self.path)?;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !self.rotation_failed && self.is_due(buf.len()) {
+            if let Err(e) = self.rotate() {
+                // Logging through tracing from inside its writer would recurse; keep
+                // appending to the current file rather than losing log lines
+                eprintln!(
+                    "Rotating log file {} failed, no longer rotating it: {}",
+                    self.path.display(),
+                    e
+                );
+                self.rotation_failed = true;
+            }
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+fn open_append(path: &Path) -> io::Result<(File, u64, SystemTime)> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let metadata = file.metadata()?;
+    // Age counts from when the file was started, so runs appending to it rotate on schedule
+    let created = metadata
+        .created()
+        .or_else(|_| metadata.modified())
+        .unwrap_or_else(|_| SystemTime::now());
+    Ok((file, metadata.len(), created))
+}
+
+fn compress(source: &Path, destination: &Path) -> io::Result<()> {
+    let mut input = BufReader::new(File::open(source)?);
+    let mut encoder = GzEncoder::new(File::create(destination)?, flate2::Compression::default());
+    io::copy(&mut input, &mut encoder)?;
+    encoder.finish()?.sync_all()
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Layer writing each event as a line of JSON with its time, level, target and fields.
+pub struct JsonLines<W> {
+    writer: Mutex<W>,
+}
+
+impl<W: Write> JsonLines<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer: Mutex::new(writer),
+        }
+    }
+}
+
+impl<S: Subscriber, W: Write + 'static> Layer<S> for JsonLines<W> {
+    fn on_event(&self, event: &Event<'_>, _: Context<'_, S>) {
+        let metadata = event.metadata();
+        let mut record = Map::new();
+        record.insert("time".to_string(), rfc3339(SystemTime::now()).into());
+        record.insert("level".to_string(), metadata.level().as_str().into());
+        record.insert("target".to_string(), metadata.target().into());
+        event.record(&mut JsonFields(&mut record));
+
+        let mut line = Value::Object(record).to_string();
+        line.push('\n');
+        // A line that cannot be written is lost, as with the text format
+        if let Ok(mut writer) = self.writer.lock() {
+            let _ = writer.write_all(line.as_bytes());
+        }
+    }
+}
+
+/// Visitor collecting the fields of an event, keeping numbers and booleans as such.
+struct JsonFields<'a>(&'a mut Map<String, Value>);
+
+impl Visit for JsonFields<'_> {
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0
+            .insert(field.name().to_string(), format!("{value:?}").into());
+    }
+}
+
+/// Format `time` as `YYYY-MM-DDTHH:MM:SS.mmmZ`.
+fn rfc3339(time: SystemTime) -> String {
+    let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let (year, month, day, hour, minute, second) = crate::time::civil(since.as_secs());
+    format!(
+        "{year:04}-{month:02}-{day:02}T{hour:02}:{minute:02}:{second:02}.{:03}Z",
+        since.subsec_millis()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+    use tempfile::TempDir;
+
+    fn args(path: &Path, max_size: u64, keep: u32) -> LogFileArgs {
+        LogFileArgs {
+            log_file: Some(path.to_path_buf()),
+            log_max_size: ByteSize(max_size),
+            log_rotate_every: None,
+            log_keep: keep,
+            log_format: LogFormat::Text,
+        }
+    }
+
+    fn gunzip(path: &Path) -> String {
+        let mut text = String::new();
+        GzDecoder::new(File::open(path).unwrap())
+            .read_to_string(&mut text)
+            .unwrap();
+        text
+    }
+
+    #[test]
+    fn test_rotates_by_size() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("rust-utils.log");
+        let mut log = RotatingFile::open(&args(&path, 10, 2))?;
+
+        for line in ["one\n", "two\n", "three\n", "four\n", "five\n", "six\n"] {
+            log.write_all(line.as_bytes())?;
+        }
+        log.flush()?;
+
+        assert_eq!(fs::read_to_string(&path)?, "six\n");
+        assert_eq!(
+            gunzip(&dir.path().join("rust-utils.log.1.gz")),
+            "four\nfive\n"
+        );
+        assert_eq!(gunzip(&dir.path().join("rust-utils.log.2.gz")), "three\n");
+        assert!(!dir.path().join("rust-utils.log.3.gz").exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rotates_by_age() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("rust-utils.log");
+        fs::write(&path, "yesterday\n")?;
+        let mut args = args(&path, u64::MAX, 1);
+        args.log_rotate_every = Some("1h".parse()?);
+
+        let mut log = RotatingFile::open(&args)?;
+        log.write_all(b"first\n")?;
+        assert!(!dir.path().join("rust-utils.log.1.gz").exists());
+        log.created -= Duration::from_secs(3600);
+        log.write_all(b"today\n")?;
+
+        assert_eq!(fs::read_to_string(&path)?, "today\n");
+        assert_eq!(
+            gunzip(&dir.path().join("rust-utils.log.1.gz")),
+            "yesterday\nfirst\n"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_json_lines() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let dir = TempDir::new()?;
+        let path = dir.path().join("rust-utils.log");
+        let log = RotatingFile::open(&args(&path, u64::MAX, 1))?;
+        let subscriber = tracing_subscriber::registry().with(JsonLines::new(log));
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!(
+                target: ENTRY_TARGET,
+                event = "remapped",
+                path = "/srv/web/index.html",
+                uid = 0u32,
+                dry_run = false
+            );
+            tracing::warn!("Failed to process {}: {}", "a \"b\"", "denied");
+        });
+
+        let text = fs::read_to_string(&path)?;
+        let lines: Vec<Value> = text
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<std::result::Result<_, _>>()?;
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["target"], ENTRY_TARGET);
+        assert_eq!(lines[0]["level"], "INFO");
+        assert_eq!(lines[0]["event"], "remapped");
+        assert_eq!(lines[0]["path"], "/srv/web/index.html");
+        assert_eq!(lines[0]["uid"], 0);
+        assert_eq!(lines[0]["dry_run"], false);
+        assert_eq!(lines[1]["level"], "WARN");
+        assert_eq!(lines[1]["message"], "Failed to process a \"b\": denied");
+
+        assert_eq!(
+            rfc3339(UNIX_EPOCH + Duration::from_millis(1_700_000_000_042)),
+            "2023-11-14T22:13:20.042Z"
+        );
+        Ok(())
+    }
+}
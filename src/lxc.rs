@@ -0,0 +1,192 @@
+//! LXC container configuration, as far as ID mappings are concerned.
+//!
+//! `lxc.idmap = u 0 100000 65536` maps container UIDs `0..65536` to host UIDs from
+//! `100000`; `g` lines do the same for GIDs. LXC before 3.0 spelled the key `lxc.id_map`.
+//!
+//! LXD and Incus, which run containers through LXC, take extra mappings in their
+//! `raw.idmap` option instead: `uid|gid|both HOST CONTAINER`, each side a single ID or an
+//! inclusive `FIRST-LAST` range.
+
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use clap::Args;
+
+use crate::error::{Result, RustUtilsError};
+use crate::idmap::{id_span, IdKind, IdMap, IdMapping};
+
+/// One `lxc.idmap` entry: a mapping from container to host IDs of one kind.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LxcIdMap {
+    pub kind: IdKind,
+    /// Container IDs in `from`, host IDs in `to`
+    pub mapping: IdMapping,
+}
+
+impl FromStr for LxcIdMap {
+    type Err = RustUtilsError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || {
+            RustUtilsError::InvalidArguments(format!(
+                "invalid lxc.idmap '{s}' (expected u|g CONTAINER HOST COUNT)"
+            ))
+        };
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        let [kind, from, to, count] = fields[..] else {
+            return Err(invalid());
+        };
+        let kind = match kind {
+            "u" => IdKind::Uid,
+            "g" => IdKind::Gid,
+            _ => return Err(invalid()),
+        };
+        let mapping = format!("{from}:{to}:{count}")
+            .parse()
+            .map_err(|_| invalid())?;
+        Ok(LxcIdMap { kind, mapping })
+    }
+}
+
+impl fmt::Display for LxcIdMap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self.kind {
+            IdKind::Uid => 'u',
+            IdKind::Gid => 'g',
+        };
+        write!(
+            f,
+            "{} {} {} {}",
+            kind, self.mapping.from, self.mapping.to, self.mapping.count
+        )
+    }
+}
+
+/// `raw.idmap` lines for the given UID and GID mappings, using `both` when they are the same.
+pub fn raw_idmap(uid_maps: &[IdMapping], gid_maps: &[IdMapping]) -> Vec<String> {
+    let line = |kind: &str, mapping: &IdMapping| {
+        format!(
+            "{} {} {}",
+            kind,
+            id_span(mapping.to, mapping.count),
+            id_span(mapping.from, mapping.count)
+        )
+    };
+    if uid_maps == gid_maps {
+        return uid_maps
+            .iter()
+            .map(|mapping| line("both", mapping))
+            .collect();
+    }
+    let uids = uid_maps.iter().map(|mapping| line("uid", mapping));
+    let gids = gid_maps.iter().map(|mapping| line("gid", mapping));
+    uids.chain(gids).collect()
+}
+
+/// `--idmap` and `--idmap-file`: mappings given the way an LXC container config writes them.
+#[derive(Args, Clone, Debug, Default)]
+pub struct LxcIdMapArgs {
+    /// An lxc.idmap entry, e.g. "u 0 100000 65536" (can be used multiple times)
+    #[arg(long, value_name = "u|g CONTAINER HOST COUNT")]
+    pub idmap: Vec<LxcIdMap>,
+
+    /// Take the lxc.idmap entries of an LXC container config
+    #[arg(long, value_name = "FILE")]
+    pub idmap_file: Option<PathBuf>,
+}
+
+impl LxcIdMapArgs {
+    pub fn is_empty(&self) -> bool {
+        self.idmap.is_empty() && self.idmap_file.is_none()
+    }
+
+    /// The UID mappings and the GID mappings of --idmap, then of --idmap-file, each in the
+    /// order given. A config without lxc.idmap entries is refused.
+    pub fn by_kind(&self) -> Result<(Vec<IdMapping>, Vec<IdMapping>)> {
+        let mut idmaps = self.idmap.clone();
+        if let Some(path) = &self.idmap_file {
+            let config = std::fs::read_to_string(path)?;
+            let entries = config_idmaps(&config).map_err(|e| match e {
+                RustUtilsError::InvalidArguments(detail) => {
+                    RustUtilsError::InvalidArguments(format!("{}: {}", path.display(), detail))
+                }
+                e => e,
+            })?;
+            if entries.is_empty() {
+                return Err(RustUtilsError::InvalidArguments(format!(
+                    "{}: no lxc.idmap entries",
+                    path.display()
+                )));
+            }
+            idmaps.extend(entries);
+        }
+        let of_kind = |kind: IdKind| {
+            idmaps
+                .iter()
+                .filter(|idmap| idmap.kind == kind)
+                .map(|idmap| idmap.mapping)
+                .collect()
+        };
+        Ok((of_kind(IdKind::Uid), of_kind(IdKind::Gid)))
+    }
+
+    /// The UID and GID maps of `map`, mappings that apply to both, followed by the entries
+    /// of each kind.
+    pub fn id_maps(&self, map: &[IdMapping]) -> Result<(IdMap, IdMap)> {
+        let (uids, gids) = self.by_kind()?;
+        Ok((
+            IdMap::new([map, &uids].concat())?,
+            IdMap::new([map, &gids].concat())?,
+        ))
+    }
+}
+
+/// The `lxc.idmap` entries of a container configuration, in file order.
+pub fn config_idmaps(config: &str) -> Result<Vec<LxcIdMap>> {
+    config
+        .lines()
+        .filter_map(|line| {
+            let (key, value) = line.trim().split_once('=')?;
+            matches!(key.trim(), "lxc.idmap" | "lxc.id_map").then(|| value.trim())
+        })
+        .map(str::parse)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_idmaps() {
+        let config = "\
+lxc.uts.name = web
+# lxc.idmap = u 0 1 1
+lxc.idmap = u 0 100000 65536
+lxc.id_map=g 0 200000 65536
+";
+        let idmaps = config_idmaps(config).unwrap();
+        assert_eq!(idmaps.len(), 2);
+        assert_eq!(idmaps[0].kind, IdKind::Uid);
+        assert_eq!(idmaps[0].mapping.to, 100000);
+        assert_eq!(idmaps[1].to_string(), "g 0 200000 65536");
+
+        assert!(config_idmaps("lxc.idmap = x 0 100000 65536").is_err());
+        assert!(config_idmaps("lxc.idmap = u 0 100000").is_err());
+    }
+
+    #[test]
+    fn test_raw_idmap() {
+        let range: IdMapping = "0:100000:65536".parse().unwrap();
+        let single: IdMapping = "1000:1000:1".parse().unwrap();
+        assert_eq!(
+            raw_idmap(&[range, single], &[range, single]),
+            ["both 100000-165535 0-65535", "both 1000 1000"]
+        );
+        assert_eq!(
+            raw_idmap(&[range], &[single]),
+            ["uid 100000-165535 0-65535", "gid 1000 1000"]
+        );
+    }
+}
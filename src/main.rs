@@ -1,22 +1,238 @@
+#![deny(unsafe_code)]
+
+use std::process::ExitCode;
+use std::sync::Mutex;
+use std::time::Instant;
+
 use anyhow::Result;
-use clap::Parser;
 use rust_utils::cli::{Cli, Commands};
-use rust_utils::commands::remap::RemapCommand;
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+use rust_utils::commands::archive::ArchiveCommand;
+use rust_utils::commands::chown_helper::ChownHelperCommand;
+use rust_utils::commands::copy::CopyCommand;
+use rust_utils::commands::fingerprint::FingerprintCommand;
+use rust_utils::commands::homes::RemapHomesCommand;
+use rust_utils::commands::idmap::IdmapCommand;
+use rust_utils::commands::journal::JournalCommand;
+use rust_utils::commands::normalize::NormalizeCommand;
+use rust_utils::commands::remap::{
+    ProfileCommand, RemapCliArgs, RemapCommand, RemapCommands, UndoCommand, VerifyCommand,
+};
+use rust_utils::commands::report::ReportCommand;
+use rust_utils::commands::schema::SchemaCommand;
+use rust_utils::commands::send_stream::SendStreamCommand;
+use rust_utils::commands::template::TemplateCommand;
+use rust_utils::commands::with_caps::WithCapsCommand;
+use rust_utils::error::RustUtilsError;
+use rust_utils::i18n;
+use rust_utils::iostats;
+use rust_utils::logfile::{JsonLines, LogFormat, RotatingFile, ENTRY_TARGET};
+use rust_utils::report::{OutputFormat, RunReport};
+use rust_utils::signals;
+use tracing::Level;
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::layer::{Layer, SubscriberExt};
+use tracing_subscriber::{util::SubscriberInitExt, EnvFilter};
+use uuid::Uuid;
+
+/// Exit status of a run that completed with some entries failing.
+const EXIT_ENTRIES_FAILED: u8 = 4;
+
+fn main() -> Result<ExitCode> {
+    let cli = Cli::parse_checked();
+
+    // Commands that stream data or progress events on stdout must keep log output off it
+    let data_on_stdout = cli.command.progress_fd() == Some(1)
+        || match cli.command {
+            Commands::SendStream(_) | Commands::Schema(_) => true,
+            Commands::Archive(ref args) => args.writes_stdout(),
+            Commands::Template(ref args) => args.writes_stdout(),
+            _ => false,
+        };
+    let json = cli.globals.output_format == Some(OutputFormat::Json);
+    let writer = if data_on_stdout || json {
+        BoxMakeWriter::new(std::io::stderr)
+    } else {
+        BoxMakeWriter::new(std::io::stdout)
+    };
+
+    i18n::init(cli.lang);
+    let log_file = match cli.log.log_file {
+        Some(_) => Some(RotatingFile::open(&cli.log)?),
+        None => None,
+    };
 
-fn main() -> Result<()> {
-    // Initialize tracing
+    // Initialize tracing; entry records go to JSON log files only
+    let filter = || -> Result<EnvFilter> {
+        let filter = if cli.globals.quiet {
+            EnvFilter::new("error")
+        } else if cli.globals.verbose && std::env::var_os("RUST_LOG").is_none() {
+            EnvFilter::new("info")
+        } else {
+            EnvFilter::from_default_env()
+        };
+        Ok(filter.add_directive(format!("{ENTRY_TARGET}=off").parse()?))
+    };
+    let file_layer = match (log_file, cli.log.log_format) {
+        (Some(file), LogFormat::Text) => Some(
+            tracing_subscriber::fmt::layer()
+                .with_ansi(false)
+                .with_writer(Mutex::new(file))
+                .with_filter(filter()?)
+                .boxed(),
+        ),
+        (Some(file), LogFormat::Json) => Some(
+            JsonLines::new(file)
+                .with_filter(Targets::new().with_default(Level::INFO))
+                .boxed(),
+        ),
+        (None, _) => None,
+    };
     tracing_subscriber::registry()
-        .with(tracing_subscriber::EnvFilter::from_default_env())
-        .with(tracing_subscriber::fmt::layer())
+        .with(file_layer)
+        .with(
+            tracing_subscriber::fmt::layer()
+                .with_writer(writer)
+                .with_filter(filter()?),
+        )
         .init();
 
-    let cli = Cli::parse();
-
-    match cli.command {
-        Commands::Remap(args) => {
-            let command = RemapCommand::new(args);
+    let name = cli.command.name();
+    if cli.profile_io.is_some() {
+        iostats::enable();
+    }
+    let started = Instant::now();
+    let result = match cli.command {
+        Commands::Remap(RemapCliArgs::Command(RemapCommands::Undo(args))) => {
+            let command = UndoCommand::new(args);
+            command.execute()
+        }
+        Commands::Remap(RemapCliArgs::Command(RemapCommands::Verify(args))) => {
+            let command = VerifyCommand::new(args);
+            command.execute()
+        }
+        Commands::Remap(RemapCliArgs::Command(RemapCommands::Profile(args))) => {
+            let command = ProfileCommand::new(args);
+            signals::install()
+                .map_err(Into::into)
+                .and_then(|()| command.execute())
+        }
+        Commands::Remap(RemapCliArgs::Run(args)) => {
+            let command = RemapCommand::new(*args).with_state_dir(cli.state_dir);
+            signals::install()
+                .map_err(Into::into)
+                .and_then(|()| command.execute())
+        }
+        Commands::RemapHomes(args) => {
+            let command = RemapHomesCommand::new(args).with_state_dir(cli.state_dir);
+            signals::install()
+                .map_err(Into::into)
+                .and_then(|()| command.execute())
+        }
+        Commands::Normalize(args) => {
+            let command = NormalizeCommand::new(args);
+            command.execute()
+        }
+        Commands::Fingerprint(args) => {
+            let command = FingerprintCommand::new(args).with_state_dir(cli.state_dir);
+            command.execute()
+        }
+        Commands::Copy(args) => {
+            let command = CopyCommand::new(args);
+            command.execute()
+        }
+        Commands::SendStream(args) => {
+            let command = SendStreamCommand::new(args);
+            command.execute()
+        }
+        Commands::Template(args) => {
+            let command = TemplateCommand::new(args);
             command.execute()
         }
+        Commands::Archive(args) => {
+            let command = ArchiveCommand::new(args).with_state_dir(cli.state_dir);
+            command.execute()
+        }
+        Commands::Schema(args) => {
+            let command = SchemaCommand::new(args);
+            command.execute()
+        }
+        Commands::Report(args) => {
+            let command = ReportCommand::new(args);
+            command.execute()
+        }
+        Commands::Journal(args) => {
+            let command = JournalCommand::new(args);
+            command.execute()
+        }
+        Commands::Idmap(args) => {
+            let command = IdmapCommand::new(args);
+            command.execute()
+        }
+        Commands::WithCaps(args) => {
+            let command = WithCapsCommand::new(args);
+            command.execute()
+        }
+        Commands::ChownHelper(args) => {
+            let command = ChownHelperCommand::new(args);
+            command.execute()
+        }
+    };
+
+    // Failures still produce a report and a RESULT line, so tooling always finds one
+    let (mut report, mut result) = match result {
+        Ok(report) => (report, Ok(())),
+        Err(e) => {
+            let mut report = RunReport::failed(name, format!("{e:#}"));
+            if let Some(RustUtilsError::Interrupted(_)) = e.downcast_ref() {
+                report.interrupted = signals::received().map(|signal| signal.to_string());
+            }
+            (report, Err(e))
+        }
+    };
+    report.run_id = Some(Uuid::new_v4().to_string());
+    report.duration("total", started.elapsed());
+    if let (Some(path), Some(profile)) = (&cli.profile_io, iostats::profile()) {
+        profile.summarize(&mut report);
+        match profile.write(path, name) {
+            Ok(()) => {
+                report.artifact("io-profile", path);
+            }
+            Err(e) => {
+                report.success = false;
+                report.error(Some(path), &e);
+                result = result.and(Err(e.into()));
+            }
+        }
+    }
+    if let Some(path) = cli.report {
+        if let Err(e) = report.write(&path) {
+            report.success = false;
+            report.error(Some(&path), &e);
+            result = result.and(Err(e.into()));
+        }
+    }
+
+    let summary = if json {
+        report.json_line()
+    } else {
+        report.result_line()
+    };
+    if data_on_stdout {
+        eprintln!("{summary}");
+    } else {
+        println!("{summary}");
+    }
+    if let (Some(_), Some(signal)) = (&report.interrupted, signals::received()) {
+        if let Err(e) = result {
+            eprintln!("Error: {e:?}");
+        }
+        return Ok(ExitCode::from(signals::exit_code(signal)));
     }
+    result?;
+    Ok(if report.errors.is_empty() {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::from(EXIT_ENTRIES_FAILED)
+    })
 }
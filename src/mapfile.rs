@@ -0,0 +1,170 @@
+//! Mapping files for `remap --mapping-file`: UID and GID translations generated by
+//! configuration management, as CSV or JSON.
+//!
+//! A CSV file has one `KIND,FROM,TO[,COUNT]` line per mapping, with an optional
+//! `kind,from,to,count` header, blank lines and `#` comments. A JSON file is an array of
+//! `{"kind": …, "from": …, "to": …, "count": …}` objects. `KIND` is `uid`, `gid` or `both`,
+//! and `COUNT` defaults to 1. Files starting with `[` are read as JSON.
+
+use std::fs;
+use std::path::Path;
+
+use serde::Deserialize;
+
+use crate::error::{Result, RustUtilsError};
+use crate::idmap::{IdMap, IdMapping};
+
+/// The UID and GID mappings of a mapping file.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct MappingFile {
+    pub uid: Vec<IdMapping>,
+    pub gid: Vec<IdMapping>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Kind {
+    Uid,
+    Gid,
+    Both,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Entry {
+    kind: Kind,
+    from: u32,
+    to: u32,
+    #[serde(default = "one")]
+    count: u32,
+}
+
+fn one() -> u32 {
+    1
+}
+
+/// Read the mapping file at `path`, rejecting mappings that are empty, overflow the ID
+/// space or overlap each other.
+pub fn read(path: &Path) -> Result<MappingFile> {
+    let contents = fs::read_to_string(path)?;
+    let in_file = |message: String| {
+        RustUtilsError::InvalidArguments(format!("{}: {message}", path.display()))
+    };
+    let entries = if contents.trim_start().starts_with('[') {
+        serde_json::from_str::<Vec<Entry>>(&contents).map_err(|e| in_file(e.to_string()))?
+    } else {
+        parse_csv(&contents).map_err(in_file)?
+    };
+
+    let mut file = MappingFile::default();
+    for entry in entries {
+        let mapping = IdMapping {
+            from: entry.from,
+            to: entry.to,
+            count: entry.count,
+        };
+        if mapping.count == 0
+            || mapping.from.checked_add(mapping.count).is_none()
+            || mapping.to.checked_add(mapping.count).is_none()
+        {
+            return Err(RustUtilsError::InvalidRange(format!(
+                "{}: mapping {mapping} is empty or overflows",
+                path.display()
+            )));
+        }
+        if matches!(entry.kind, Kind::Uid | Kind::Both) {
+            file.uid.push(mapping);
+        }
+        if matches!(entry.kind, Kind::Gid | Kind::Both) {
+            file.gid.push(mapping);
+        }
+    }
+    for mappings in [&file.uid, &file.gid] {
+        IdMap::new(mappings.clone()).map_err(|e| match e {
+            RustUtilsError::InvalidRange(message) => {
+                RustUtilsError::InvalidRange(format!("{}: {message}", path.display()))
+            }
+            e => e,
+        })?;
+    }
+    Ok(file)
+}
+
+fn parse_csv(contents: &str) -> std::result::Result<Vec<Entry>, String> {
+    let mut entries = Vec::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || (number == 0 && line.starts_with("kind")) {
+            continue;
+        }
+        let invalid = || format!("line {}: expected KIND,FROM,TO[,COUNT]", number + 1);
+        let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+        let kind = match fields.first() {
+            Some(&"uid") => Kind::Uid,
+            Some(&"gid") => Kind::Gid,
+            Some(&"both") => Kind::Both,
+            _ => return Err(invalid()),
+        };
+        let number = |field: &str| field.parse::<u32>().map_err(|_| invalid());
+        let (from, to, count) = match fields[1..] {
+            [from, to] => (number(from)?, number(to)?, 1),
+            [from, to, count] => (number(from)?, number(to)?, number(count)?),
+            _ => return Err(invalid()),
+        };
+        entries.push(Entry {
+            kind,
+            from,
+            to,
+            count,
+        });
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_read_mapping_file() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = TempDir::new()?;
+        let csv = temp_dir.path().join("map.csv");
+        fs::write(
+            &csv,
+            "kind,from,to,count\n# service accounts\nboth, 33, 3333\nuid,1000,5000,10\n\ngid,999,4444,1\n",
+        )?;
+        let json = temp_dir.path().join("map.json");
+        fs::write(
+            &json,
+            r#"[{"kind": "both", "from": 33, "to": 3333},
+                {"kind": "uid", "from": 1000, "to": 5000, "count": 10},
+                {"kind": "gid", "from": 999, "to": 4444}]"#,
+        )?;
+        let expected = MappingFile {
+            uid: vec!["33:3333:1".parse()?, "1000:5000:10".parse()?],
+            gid: vec!["33:3333:1".parse()?, "999:4444:1".parse()?],
+        };
+        assert_eq!(read(&csv)?, expected);
+        assert_eq!(read(&json)?, expected);
+
+        for (contents, message) in [
+            ("uid,1000,5000,10\nuid,1005,6000\n", "overlap"),
+            ("uid,33,3333\nboth,33,4444\n", "overlap"),
+            ("user,33,3333\n", "line 1"),
+            ("uid,33\n", "line 1"),
+            ("uid,33,3333,0\n", "empty"),
+            (r#"[{"kind": "uid", "from": 33}]"#, "missing field"),
+            (
+                r#"[{"kind": "uid", "from": 33, "to": 1, "size": 1}]"#,
+                "unknown field",
+            ),
+        ] {
+            fs::write(&csv, contents)?;
+            let error = read(&csv).unwrap_err().to_string();
+            assert!(error.contains(message), "{contents}: {error}");
+            assert!(error.contains("map.csv"), "{error}");
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,167 @@
+//! Directory markers for `remap --resume-by-xattr`.
+//!
+//! Each directory the remap has finished, with everything below it, gets a small `user.*`
+//! extended attribute naming the mapping. A rerun of the same remap skips marked directories,
+//! so an interrupted run can be resumed with nothing kept outside the tree. The run that
+//! completes the walk removes every marker again.
+
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use nix::libc;
+
+/// Extended attribute marking a finished directory.
+pub const RESUME_XATTR: &str = "user.rust-utils.remapped";
+
+/// The marker of one mapping, and a count of the marked directories a walk skipped.
+#[derive(Clone, Debug, Default)]
+pub struct ResumeMarker {
+    value: Vec<u8>,
+    resumed: Arc<AtomicU64>,
+}
+
+impl ResumeMarker {
+    /// The marker of the mapping described by `key`, such as `0:100000:65536`.
+    pub fn new(key: &str) -> Self {
+        Self {
+            value: key.as_bytes().to_vec(),
+            resumed: Arc::default(),
+        }
+    }
+
+    /// Mark `dir` as finished.
+    ///
+    /// # Errors
+    ///
+    /// Fails like `setxattr`, with `ENOTSUP` where the filesystem has no user extended
+    /// attributes.
+    pub fn mark(&self, dir: &Path) -> io::Result<()> {
+        xattr::set(dir, RESUME_XATTR, &self.value)
+    }
+
+    /// Whether `path` carries this marker; any other value, such as one of another
+    /// mapping, does not count.
+    pub fn is_marked(&self, path: &Path) -> bool {
+        matches!(xattr::get(path, RESUME_XATTR), Ok(Some(value)) if value == self.value)
+    }
+
+    /// Like [`is_marked`](Self::is_marked), counting a marked path as skipped.
+    pub fn skips(&self, path: &Path) -> bool {
+        let marked = self.is_marked(path);
+        if marked {
+            self.resumed.fetch_add(1, Ordering::Relaxed);
+        }
+        marked
+    }
+
+    /// Marked directories skipped so far.
+    pub fn resumed(&self) -> u64 {
+        self.resumed.load(Ordering::Relaxed)
+    }
+
+    /// Remove this marker from `path`, returning whether it had it.
+    pub fn clear(&self, path: &Path) -> io::Result<bool> {
+        if !self.is_marked(path) {
+            return Ok(false);
+        }
+        match xattr::remove(path, RESUME_XATTR) {
+            Ok(()) => Ok(true),
+            Err(e) if e.raw_os_error() == Some(libc::ENODATA) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// A directory the walk is inside of.
+#[derive(Debug, PartialEq, Eq)]
+pub struct OpenDir {
+    pub path: PathBuf,
+    /// Errors reported when the walk entered it
+    pub errors: usize,
+}
+
+/// Directories the walk is inside of, innermost last, reported as finished once the walk
+/// comes to an entry outside them. Relies on the walk yielding a directory right before
+/// everything below it.
+#[derive(Debug, Default)]
+pub struct OpenDirs {
+    open: Vec<OpenDir>,
+}
+
+impl OpenDirs {
+    /// Return the directories the walk left on coming to `path`, innermost first.
+    pub fn leave_for(&mut self, path: &Path) -> Vec<OpenDir> {
+        let mut left = Vec::new();
+        while self
+            .open
+            .last()
+            .is_some_and(|dir| !path.starts_with(&dir.path))
+        {
+            left.extend(self.open.pop());
+        }
+        left
+    }
+
+    /// Note the walk entering directory `path` with `errors` reported so far.
+    pub fn open(&mut self, path: &Path, errors: usize) {
+        self.open.push(OpenDir {
+            path: path.to_path_buf(),
+            errors,
+        });
+    }
+
+    /// Return the directories still open at the end of a walk, innermost first.
+    pub fn finish(&mut self) -> Vec<OpenDir> {
+        self.open.drain(..).rev().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_open_dirs() {
+        let mut dirs = OpenDirs::default();
+        let paths = |left: Vec<OpenDir>| left.into_iter().map(|dir| dir.path).collect::<Vec<_>>();
+        dirs.open(Path::new("/t"), 0);
+        assert!(dirs.leave_for(Path::new("/t/a")).is_empty());
+        dirs.open(Path::new("/t/a"), 0);
+        assert!(dirs.leave_for(Path::new("/t/a/f")).is_empty());
+        dirs.open(Path::new("/t/a/b"), 1);
+        // Leaving b and a for a sibling of a that only shares a prefix of its name
+        assert_eq!(
+            paths(dirs.leave_for(Path::new("/t/ab"))),
+            ["/t/a/b", "/t/a"].map(PathBuf::from)
+        );
+        assert_eq!(
+            dirs.finish(),
+            [OpenDir {
+                path: "/t".into(),
+                errors: 0
+            }]
+        );
+    }
+
+    #[test]
+    fn test_resume_marker() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let dir = TempDir::new()?;
+        let marker = ResumeMarker::new("0:100000:65536");
+        if let Err(e) = marker.mark(dir.path()) {
+            // Some filesystems, such as tmpfs before Linux 6.6, have no user xattrs
+            assert_eq!(e.raw_os_error(), Some(libc::ENOTSUP));
+            return Ok(());
+        }
+        assert!(marker.skips(dir.path()));
+        assert_eq!(marker.resumed(), 1);
+        assert!(!ResumeMarker::new("0:200000:65536").is_marked(dir.path()));
+
+        assert!(marker.clear(dir.path())?);
+        assert!(!marker.clear(dir.path())?);
+        assert!(!marker.is_marked(dir.path()));
+        Ok(())
+    }
+}
@@ -0,0 +1,261 @@
+//! Metadata cache for `fingerprint --metadata-cache`: the owners of each directory's
+//! entries as last seen, so that audits repeated over an unchanged tree skip most `lstat`
+//! calls, which on cold network storage cost a round trip each.
+//!
+//! A directory is identified by device, inode and ctime. Adding, removing or renaming an
+//! entry changes the ctime of its directory, and so does a change to the directory itself,
+//! after which its entries are read again. A change to an entry's own owner or mode changes
+//! only the entry's ctime, which the cache cannot see without the `lstat` it saves.
+//!
+//! The file starts with a `rust-utils-metadata-cache VERSION` line, followed for each
+//! directory by a `D DEV INO CTIME CTIME_NSEC COUNT` line and `COUNT` lines of
+//! `TYPE UID GID NAME`, with the file type as in [`type_tag`] and the name in hex.
+
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+use tracing::warn;
+
+use crate::error::Result;
+use crate::util::hex;
+
+const CACHE_MAGIC: &str = "rust-utils-metadata-cache";
+
+/// Version of the cache format written by this build; caches of other versions are
+/// ignored, as they can always be rebuilt.
+pub const CACHE_FORMAT_VERSION: u32 = 1;
+
+/// File type bits of `metadata`, as recorded for each entry.
+pub fn type_tag(metadata: &fs::Metadata) -> u8 {
+    // The S_IFMT bits identify regular files, directories, links, devices, FIFOs and sockets
+    ((metadata.mode() & 0o170000) >> 12) as u8
+}
+
+/// Type tag of directories.
+pub const DIR_TAG: u8 = 0o04;
+
+/// What identifies one state of a directory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct DirKey {
+    pub dev: u64,
+    pub ino: u64,
+    pub ctime: i64,
+    pub ctime_nsec: i64,
+}
+
+impl DirKey {
+    pub fn of(metadata: &fs::Metadata) -> Self {
+        Self {
+            dev: metadata.dev(),
+            ino: metadata.ino(),
+            ctime: metadata.ctime(),
+            ctime_nsec: metadata.ctime_nsec(),
+        }
+    }
+}
+
+/// An entry of a directory as last seen.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CachedEntry {
+    pub name: OsString,
+    pub tag: u8,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+/// The entries of directories, read from the cache file and collected for the next one.
+#[derive(Debug, Default)]
+pub struct MetadataCache {
+    previous: HashMap<DirKey, Vec<CachedEntry>>,
+    current: HashMap<DirKey, Vec<CachedEntry>>,
+}
+
+impl MetadataCache {
+    /// Read the cache at `path`. A missing file gives an empty cache, and so does one that
+    /// cannot be read, with a warning: the run then reads every directory again.
+    pub fn load(path: &Path) -> Self {
+        let previous = match File::open(path) {
+            Ok(file) => match read_dirs(BufReader::new(file)) {
+                Ok(Some(dirs)) => dirs,
+                Ok(None) => {
+                    warn!(
+                        "{}: not a metadata cache of this version, ignored",
+                        path.display()
+                    );
+                    HashMap::new()
+                }
+                Err(e) => {
+                    warn!(
+                        "{}: cannot read metadata cache, ignored: {}",
+                        path.display(),
+                        e
+                    );
+                    HashMap::new()
+                }
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => {
+                warn!(
+                    "{}: cannot open metadata cache, ignored: {}",
+                    path.display(),
+                    e
+                );
+                HashMap::new()
+            }
+        };
+        Self {
+            previous,
+            current: HashMap::new(),
+        }
+    }
+
+    /// The entries of the directory in state `key`, if cached, kept for the next cache.
+    pub fn get(&mut self, key: DirKey) -> Option<Vec<CachedEntry>> {
+        let entries = self.previous.remove(&key)?;
+        self.current.insert(key, entries.clone());
+        Some(entries)
+    }
+
+    /// Record the entries of the directory in state `key` for the next cache.
+    pub fn insert(&mut self, key: DirKey, entries: Vec<CachedEntry>) {
+        self.current.insert(key, entries);
+    }
+
+    /// Replace the cache at `path` with the directories met in this run, dropping those
+    /// no longer seen.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut temporary = path.as_os_str().to_owned();
+        temporary.push(".tmp");
+        let mut out = BufWriter::new(File::create(&temporary)?);
+        writeln!(out, "{CACHE_MAGIC} {CACHE_FORMAT_VERSION}")?;
+        for (key, entries) in &self.current {
+            writeln!(
+                out,
+                "D {} {} {} {} {}",
+                key.dev,
+                key.ino,
+                key.ctime,
+                key.ctime_nsec,
+                entries.len()
+            )?;
+            for entry in entries {
+                let name = hex(entry.name.as_bytes());
+                writeln!(out, "{} {} {} {}", entry.tag, entry.uid, entry.gid, name)?;
+            }
+        }
+        out.into_inner().map_err(io::Error::from)?.sync_all()?;
+        fs::rename(&temporary, path)?;
+        Ok(())
+    }
+}
+
+/// The directories of a cache file, or `None` if it is not one of this version.
+fn read_dirs(reader: impl BufRead) -> io::Result<Option<HashMap<DirKey, Vec<CachedEntry>>>> {
+    let invalid = |line: &str| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unexpected line '{line}'"),
+        )
+    };
+    let mut lines = reader.lines();
+    let header = lines.next().transpose()?.unwrap_or_default();
+    if header != format!("{CACHE_MAGIC} {CACHE_FORMAT_VERSION}") {
+        return Ok(None);
+    }
+
+    let mut dirs = HashMap::new();
+    while let Some(line) = lines.next().transpose()? {
+        let fields: Vec<&str> = line.split(' ').collect();
+        let ["D", dev, ino, ctime, ctime_nsec, count] = fields[..] else {
+            return Err(invalid(&line));
+        };
+        let parse = |field: &str| field.parse::<i64>().map_err(|_| invalid(&line));
+        let key = DirKey {
+            dev: dev.parse().map_err(|_| invalid(&line))?,
+            ino: ino.parse().map_err(|_| invalid(&line))?,
+            ctime: parse(ctime)?,
+            ctime_nsec: parse(ctime_nsec)?,
+        };
+        let count: usize = count.parse().map_err(|_| invalid(&line))?;
+        let mut entries = Vec::with_capacity(count);
+        for _ in 0..count {
+            let line = lines.next().transpose()?.unwrap_or_default();
+            let [tag, uid, gid, name] = line.split(' ').collect::<Vec<_>>()[..] else {
+                return Err(invalid(&line));
+            };
+            let name = (0..name.len())
+                .step_by(2)
+                .map(|i| {
+                    name.get(i..i + 2)
+                        .and_then(|b| u8::from_str_radix(b, 16).ok())
+                })
+                .collect::<Option<Vec<u8>>>()
+                .ok_or_else(|| invalid(&line))?;
+            entries.push(CachedEntry {
+                name: OsString::from_vec(name),
+                tag: tag.parse().map_err(|_| invalid(&line))?,
+                uid: uid.parse().map_err(|_| invalid(&line))?,
+                gid: gid.parse().map_err(|_| invalid(&line))?,
+            });
+        }
+        dirs.insert(key, entries);
+    }
+    Ok(Some(dirs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_metadata_cache() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = TempDir::new()?;
+        let path = temp_dir.path().join("tree.cache");
+        let key = DirKey::of(&fs::metadata(temp_dir.path())?);
+        let entries = vec![
+            CachedEntry {
+                name: OsString::from_vec(b"caf\xe9 menu".to_vec()),
+                tag: 0o10,
+                uid: 100033,
+                gid: 100033,
+            },
+            CachedEntry {
+                name: OsString::from("etc"),
+                tag: DIR_TAG,
+                uid: 0,
+                gid: 0,
+            },
+        ];
+
+        let mut cache = MetadataCache::load(&path);
+        assert_eq!(cache.get(key), None);
+        cache.insert(key, entries.clone());
+        let other = DirKey { ctime: 1, ..key };
+        cache.insert(other, Vec::new());
+        cache.save(&path)?;
+
+        let mut cache = MetadataCache::load(&path);
+        assert_eq!(cache.get(key), Some(entries.clone()));
+        // Directories not met again are left out of the next cache
+        cache.save(&path)?;
+        let mut cache = MetadataCache::load(&path);
+        assert_eq!(cache.get(other), None);
+        assert_eq!(cache.get(key), Some(entries));
+
+        for contents in [
+            "rust-utils-metadata-cache 99\n",
+            "D 1 2 3\n",
+            "rust-utils-metadata-cache 1\nD 1 2 3 4 1\n0 0 0 zz\n",
+        ] {
+            fs::write(&path, contents)?;
+            assert_eq!(MetadataCache::load(&path).get(key), None);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,239 @@
+//! Filesystems met during a walk, identified through `/proc/self/mountinfo`, and the
+//! per-filesystem statistics reported at the end of a run.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use nix::sys::stat::{major, makedev, minor};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// One line of `/proc/self/mountinfo`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Mount {
+    /// `st_dev` of files on this mount
+    pub device: u64,
+    pub mountpoint: PathBuf,
+    pub fstype: String,
+    /// Mount source, e.g. `/dev/sda1` or `server:/export`
+    pub source: String,
+}
+
+/// Read the mount table of the current mount namespace.
+pub fn read_mounts() -> io::Result<Vec<Mount>> {
+    Ok(parse_mountinfo(&fs::read_to_string(
+        "/proc/self/mountinfo",
+    )?))
+}
+
+/// Parse mountinfo text, skipping malformed lines.
+pub fn parse_mountinfo(text: &str) -> Vec<Mount> {
+    text.lines().filter_map(parse_line).collect()
+}
+
+fn parse_line(line: &str) -> Option<Mount> {
+    // ID PARENT MAJOR:MINOR ROOT MOUNTPOINT OPTIONS [OPTIONAL...] - FSTYPE SOURCE SUPEROPTIONS
+    let (head, tail) = line.split_once(" - ")?;
+    let head: Vec<&str> = head.split(' ').collect();
+    let mut tail = tail.split(' ');
+    let (major, minor) = head.get(2)?.split_once(':')?;
+    Some(Mount {
+        device: makedev(major.parse().ok()?, minor.parse().ok()?),
+        mountpoint: PathBuf::from(unescape(head.get(4)?)),
+        fstype: unescape(tail.next()?),
+        source: unescape(tail.next()?),
+    })
+}
+
+/// Undo the octal escapes (`\040` for a space) mountinfo uses for whitespace and `\`.
+fn unescape(field: &str) -> String {
+    let bytes = field.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let code = bytes
+            .get(i + 1..i + 4)
+            .filter(|_| bytes[i] == b'\\')
+            .and_then(|digits| u8::from_str_radix(std::str::from_utf8(digits).ok()?, 8).ok());
+        match code {
+            Some(code) => {
+                out.push(code);
+                i += 4;
+            }
+            None => {
+                out.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// The mount `path` on `device` belongs to: among mounts of the device, the one with the
+/// longest mountpoint containing `path` (bind mounts share a device).
+pub fn find_mount<'a>(mounts: &'a [Mount], device: u64, path: &Path) -> Option<&'a Mount> {
+    let mut candidates = mounts.iter().filter(|mount| mount.device == device);
+    candidates
+        .clone()
+        .filter(|mount| path.starts_with(&mount.mountpoint))
+        .max_by_key(|mount| mount.mountpoint.as_os_str().len())
+        .or_else(|| candidates.next_back())
+}
+
+/// Mounts strictly below the canonical path `root`, such as bind mounts, volumes and tmpfs
+/// mounted into a container's rootfs.
+pub fn mounts_below<'a>(root: &Path, mounts: &'a [Mount]) -> Vec<&'a Mount> {
+    mounts
+        .iter()
+        .filter(|mount| mount.mountpoint.starts_with(root) && mount.mountpoint != root)
+        .collect()
+}
+
+/// Statistics of one filesystem in a run report.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct FilesystemSummary {
+    /// Device number as `MAJOR:MINOR`
+    pub device: String,
+    /// Filesystem type, e.g. `ext4` or `nfs4`, if the mount table lists the device
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fstype: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mountpoint: Option<String>,
+    pub entries: u64,
+    /// Entries whose ownership changed (or would change in a dry run)
+    pub changed: u64,
+    /// Entries that could not be processed
+    pub failed: u64,
+}
+
+#[derive(Default)]
+struct Tally {
+    first_path: PathBuf,
+    entries: u64,
+    changed: u64,
+    failed: u64,
+}
+
+/// Counts entries per filesystem during a walk.
+#[derive(Default)]
+pub struct FilesystemStats {
+    devices: BTreeMap<u64, Tally>,
+}
+
+impl FilesystemStats {
+    /// Count an entry found at `path` on `device`.
+    pub fn entry(&mut self, device: u64, path: &Path) {
+        let tally = self.devices.entry(device).or_insert_with(|| Tally {
+            first_path: path.to_path_buf(),
+            ..Tally::default()
+        });
+        tally.entries += 1;
+    }
+
+    pub fn changed(&mut self, device: u64) {
+        self.devices.entry(device).or_default().changed += 1;
+    }
+
+    pub fn failed(&mut self, device: u64) {
+        self.devices.entry(device).or_default().failed += 1;
+    }
+
+    /// Per-filesystem summaries in device order, described from `mounts`.
+    pub fn summarize(&self, mounts: &[Mount]) -> Vec<FilesystemSummary> {
+        self.devices
+            .iter()
+            .map(|(&device, tally)| {
+                let mount = find_mount(mounts, device, &tally.first_path);
+                FilesystemSummary {
+                    device: format!("{}:{}", major(device), minor(device)),
+                    fstype: mount.map(|mount| mount.fstype.clone()),
+                    mountpoint: mount.map(|mount| mount.mountpoint.display().to_string()),
+                    entries: tally.entries,
+                    changed: tally.changed,
+                    failed: tally.failed,
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MOUNTINFO: &str = "\
+22 1 8:1 / / rw,relatime shared:1 - ext4 /dev/sda1 rw
+40 22 0:52 / /srv/nfs\\040share rw,relatime shared:20 - nfs4 filer:/export ro
+41 22 8:1 /var/lib/lxc /srv/lxc rw,relatime - ext4 /dev/sda1 rw
+garbage
+";
+
+    #[test]
+    fn test_parse_mountinfo() {
+        let mounts = parse_mountinfo(MOUNTINFO);
+        assert_eq!(mounts.len(), 3);
+        assert_eq!(
+            mounts[1],
+            Mount {
+                device: makedev(0, 52),
+                mountpoint: PathBuf::from("/srv/nfs share"),
+                fstype: "nfs4".to_string(),
+                source: "filer:/export".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_mounts_below() {
+        let mounts = parse_mountinfo(MOUNTINFO);
+        let below = mounts_below(Path::new("/srv"), &mounts);
+        let mountpoints: Vec<_> = below.iter().map(|m| m.mountpoint.as_path()).collect();
+        assert_eq!(
+            mountpoints,
+            [Path::new("/srv/nfs share"), Path::new("/srv/lxc")]
+        );
+        assert!(mounts_below(Path::new("/srv/lxc"), &mounts).is_empty());
+        assert_eq!(mounts_below(Path::new("/"), &mounts).len(), 2);
+    }
+
+    #[test]
+    fn test_find_mount_prefers_bind_mount_containing_path() {
+        let mounts = parse_mountinfo(MOUNTINFO);
+        let sda1 = makedev(8, 1);
+        let mount = find_mount(&mounts, sda1, Path::new("/srv/lxc/web/rootfs")).unwrap();
+        assert_eq!(mount.mountpoint, Path::new("/srv/lxc"));
+        let mount = find_mount(&mounts, sda1, Path::new("/etc")).unwrap();
+        assert_eq!(mount.mountpoint, Path::new("/"));
+        assert!(find_mount(&mounts, makedev(9, 9), Path::new("/")).is_none());
+    }
+
+    #[test]
+    fn test_summarize() {
+        let mounts = parse_mountinfo(MOUNTINFO);
+        let nfs = makedev(0, 52);
+        let mut stats = FilesystemStats::default();
+        stats.entry(makedev(8, 1), Path::new("/srv/lxc/web"));
+        stats.changed(makedev(8, 1));
+        stats.entry(nfs, Path::new("/srv/nfs share/data"));
+        stats.entry(nfs, Path::new("/srv/nfs share/data/a"));
+        stats.failed(nfs);
+        stats.failed(nfs);
+        stats.entry(makedev(7, 3), Path::new("/srv/lxc/web/loop"));
+
+        let summary = stats.summarize(&mounts);
+        assert_eq!(
+            summary
+                .iter()
+                .map(|fs| fs.device.as_str())
+                .collect::<Vec<_>>(),
+            ["0:52", "7:3", "8:1"]
+        );
+        assert_eq!(summary[0].fstype.as_deref(), Some("nfs4"));
+        assert_eq!(summary[0].mountpoint.as_deref(), Some("/srv/nfs share"));
+        assert_eq!((summary[0].entries, summary[0].failed), (2, 2));
+        assert_eq!(summary[1].fstype, None);
+        assert_eq!((summary[2].entries, summary[2].changed), (1, 1));
+    }
+}
@@ -0,0 +1,268 @@
+//! Archives embedded in the tree or archive being remapped, such as a `.tar.gz` image shipped
+//! inside a rootfs. Their entries carry ownership of their own, which a remap only changes
+//! when told to open them.
+//!
+//! A file or entry counts as a nested archive when its name looks like a tar archive and its
+//! (decompressed) content starts with a ustar header.
+
+use std::env;
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read};
+use std::os::unix::fs::{lchown, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use clap::ValueEnum;
+use nix::sys::stat::{utimensat, UtimensatFlags};
+use nix::sys::time::TimeSpec;
+use tracing::debug;
+
+use crate::commands::archive::{remap_tar, ArchiveStats, RemapRules};
+use crate::compress::{self, Compression, Encoder};
+use crate::error::{Result, RustUtilsError};
+
+/// Leading bytes read from a file or entry to recognise a nested archive.
+pub const SNIFF_LEN: u64 = 64 << 10;
+
+/// Archives nested deeper than this are reported instead of rewritten.
+pub const MAX_DEPTH: usize = 8;
+
+const ARCHIVE_SUFFIXES: &[&str] = &[
+    ".tar",
+    ".tgz",
+    ".txz",
+    ".tzst",
+    ".tar.gz",
+    ".tar.xz",
+    ".tar.zst",
+    ".tar.zstd",
+];
+
+/// What to do with archives found inside the tree or archive being remapped.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum NestedPolicy {
+    /// Do not look for nested archives
+    Skip,
+    /// Report nested archives but leave their contents unchanged
+    #[default]
+    Warn,
+    /// Rewrite the ownership inside nested archives as well
+    Recurse,
+}
+
+/// Whether `path` is named like a tar archive, e.g. `image.tar.zst` or `base.tgz`.
+pub fn has_archive_name(path: &Path) -> bool {
+    let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+        return false;
+    };
+    let name = name.to_ascii_lowercase();
+    ARCHIVE_SUFFIXES
+        .iter()
+        .any(|suffix| name.len() > suffix.len() && name.ends_with(suffix))
+}
+
+/// The compression of `head` if it is the start of a tar archive, possibly compressed.
+pub fn sniff(head: &[u8]) -> Option<Compression> {
+    let (compression, mut reader) = compress::decoder(head).ok()?;
+    let mut block = [0u8; 512];
+    reader.read_exact(&mut block).ok()?;
+    (&block[257..262] == b"ustar").then_some(compression)
+}
+
+/// Sniff the file at `path`, returning its compression if it is a nested archive.
+pub fn sniff_file(path: &Path) -> Result<Option<Compression>> {
+    let mut head = Vec::new();
+    File::open(path)?.take(SNIFF_LEN).read_to_end(&mut head)?;
+    Ok(sniff(&head))
+}
+
+/// An anonymous temporary file for spooling a rewritten nested archive, removed as soon as
+/// it is closed.
+pub fn spool_file() -> io::Result<File> {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let path = env::temp_dir().join(format!(
+        "rust-utils-nested-{}-{}",
+        process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&path)?;
+    fs::remove_file(&path)?;
+    Ok(file)
+}
+
+/// Rewrite the ownership inside the archive file at `path`, keeping its compression.
+///
+/// The new archive is written next to the original and renamed over it, keeping the
+/// original's mode, owner, timestamps and xattrs; nothing is written when no entry changes
+/// or in a dry run. Files with several hard links are refused, as the rename would detach
+/// the other links.
+pub fn rewrite_file(path: &Path, rules: &RemapRules, dry_run: bool) -> Result<ArchiveStats> {
+    let metadata = fs::symlink_metadata(path)?;
+    let (compression, input) = compress::decoder(BufReader::new(File::open(path)?))?;
+
+    if dry_run {
+        let (_, stats) = remap_tar(input, io::sink(), rules)?;
+        return Ok(stats);
+    }
+    if metadata.nlink() > 1 {
+        return Err(RustUtilsError::UnexpectedHardLink(format!(
+            "{} has {} links; not rewriting it",
+            path.display(),
+            metadata.nlink()
+        )));
+    }
+
+    let temp = sibling_temp_path(path);
+    // Left behind only by an interrupted earlier run
+    let _ = fs::remove_file(&temp);
+    let result = write_replacement(path, &temp, input, compression, rules, &metadata);
+    match result {
+        Ok(stats) if stats.remapped > 0 || stats.nested > 0 => {
+            fs::rename(&temp, path)?;
+            Ok(stats)
+        }
+        Ok(stats) => {
+            debug!("No entries to remap in {}", path.display());
+            fs::remove_file(&temp)?;
+            Ok(stats)
+        }
+        Err(e) => {
+            let _ = fs::remove_file(&temp);
+            Err(e)
+        }
+    }
+}
+
+/// Write the remapped archive to `temp` and give it the metadata of `source`.
+fn write_replacement(
+    source: &Path,
+    temp: &Path,
+    input: impl Read,
+    compression: Compression,
+    rules: &RemapRules,
+    metadata: &fs::Metadata,
+) -> Result<ArchiveStats> {
+    let file = OpenOptions::new().write(true).create_new(true).open(temp)?;
+    let encoder = Encoder::new(BufWriter::new(file), compression, None)?;
+    let (encoder, stats) = remap_tar(input, encoder, rules)?;
+    let file = encoder
+        .finish()?
+        .into_inner()
+        .map_err(io::IntoInnerError::into_error)?;
+    file.sync_all()?;
+
+    // chown clears setuid bits and capabilities, so mode and xattrs follow it
+    lchown(temp, Some(metadata.uid()), Some(metadata.gid()))?;
+    fs::set_permissions(temp, metadata.permissions())?;
+    copy_xattrs(source, temp)?;
+    let atime = TimeSpec::new(metadata.atime(), metadata.atime_nsec());
+    let mtime = TimeSpec::new(metadata.mtime(), metadata.mtime_nsec());
+    utimensat(None, temp, &atime, &mtime, UtimensatFlags::NoFollowSymlink)?;
+    Ok(stats)
+}
+
+/// `DIR/.NAME.rust-utils-nested` beside `path`, so the rename stays on one filesystem.
+fn sibling_temp_path(path: &Path) -> PathBuf {
+    let mut name = OsString::from(".");
+    name.push(path.file_name().unwrap_or_default());
+    name.push(".rust-utils-nested");
+    path.with_file_name(name)
+}
+
+/// Copy every xattr of `source` onto `target`.
+fn copy_xattrs(source: &Path, target: &Path) -> io::Result<()> {
+    for name in xattr::list(source)? {
+        if let Some(value) = xattr::get(source, &name)? {
+            xattr::set(target, &name, &value)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::idmap::IdMap;
+    use std::io::Write;
+    use std::os::unix::fs::PermissionsExt;
+    use tempfile::TempDir;
+
+    fn tarball(compression: Compression) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_mode(0o644);
+        header.set_size(3);
+        builder
+            .append_data(&mut header, "etc/motd", &b"hi\n"[..])
+            .unwrap();
+        let mut encoder = Encoder::new(Vec::new(), compression, None).unwrap();
+        encoder.write_all(&builder.into_inner().unwrap()).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn test_has_archive_name() {
+        for name in ["base.tar", "img/Base.TAR.GZ", "root.tgz", "layer.tar.zst"] {
+            assert!(has_archive_name(Path::new(name)), "{name}");
+        }
+        for name in ["notes.gz", "tar", ".tar", "backup.tar.bak", "zip.zip"] {
+            assert!(!has_archive_name(Path::new(name)), "{name}");
+        }
+    }
+
+    #[test]
+    fn test_sniff() {
+        assert_eq!(sniff(&tarball(Compression::None)), Some(Compression::None));
+        assert_eq!(sniff(&tarball(Compression::Zstd)), Some(Compression::Zstd));
+
+        let mut log = Encoder::new(Vec::new(), Compression::Gzip, None).unwrap();
+        log.write_all(&[b'x'; 1024]).unwrap();
+        assert_eq!(sniff(&log.finish().unwrap()), None);
+        assert_eq!(sniff(b"short"), None);
+    }
+
+    #[test]
+    fn test_rewrite_file() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("base.tar.xz");
+        fs::write(&path, tarball(Compression::Xz))?;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600))?;
+        let before = fs::metadata(&path)?;
+
+        let idmap = IdMap::new(vec!["0:100000:65536".parse()?])?;
+        let rules = RemapRules::new(&idmap);
+        let stats = rewrite_file(&path, &rules, true)?;
+        assert_eq!((stats.entries, stats.remapped), (1, 1));
+        assert_eq!(fs::metadata(&path)?.ino(), before.ino());
+
+        rewrite_file(&path, &rules, false)?;
+        let after = fs::metadata(&path)?;
+        assert_ne!(after.ino(), before.ino());
+        assert_eq!(after.mode(), before.mode());
+        assert_eq!(after.mtime(), before.mtime());
+        assert!(!sibling_temp_path(&path).exists());
+
+        let (compression, reader) = compress::decoder(BufReader::new(File::open(&path)?))?;
+        assert_eq!(compression, Compression::Xz);
+        let mut archive = tar::Archive::new(reader);
+        let entry = archive.entries()?.next().unwrap()?;
+        assert_eq!(entry.header().uid()?, 100000);
+
+        // Another link to the file would be left pointing at the old archive
+        fs::hard_link(&path, dir.path().join("link.tar.xz"))?;
+        assert!(matches!(
+            rewrite_file(&path, &rules, false),
+            Err(RustUtilsError::UnexpectedHardLink(_))
+        ));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,165 @@
+//! Throwaway overlay mounts for `remap --preview-overlay`.
+//!
+//! The tree is mounted read-only as the lower layer of an overlayfs whose upper layer lives
+//! in a private tmpfs, so a real remap of the overlay changes only copies held in memory.
+//! Dropping the [`PreviewOverlay`] unmounts both and the changes are gone.
+
+use std::fs::{self, DirBuilder};
+use std::io;
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Path, PathBuf};
+
+use nix::errno::Errno;
+use nix::mount::{mount, umount2, MntFlags, MsFlags};
+use tracing::{debug, warn};
+
+use crate::error::{Result, RustUtilsError};
+
+/// An overlay of a tree with its changes kept in a tmpfs until dropped.
+pub struct PreviewOverlay {
+    /// Private directory the tmpfs is mounted on
+    dir: PathBuf,
+    merged: PathBuf,
+    /// Mount points still to unmount, innermost last
+    mounted: Vec<PathBuf>,
+}
+
+impl PreviewOverlay {
+    /// Overlay `lower` with a tmpfs upper layer in a new directory below `parent`.
+    pub fn mount(lower: &Path, parent: &Path) -> Result<Self> {
+        let lower = lower.canonicalize()?;
+        let dir = parent.join(format!("rust-utils-preview-{}", std::process::id()));
+        DirBuilder::new().mode(0o700).create(&dir)?;
+        let mut overlay = Self {
+            merged: dir.join("merged"),
+            dir,
+            mounted: Vec::new(),
+        };
+
+        mount(
+            Some("rust-utils-preview"),
+            &overlay.dir,
+            Some("tmpfs"),
+            MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+            Some("mode=0700"),
+        )
+        .map_err(|e| mount_error("tmpfs", &overlay.dir, e))?;
+        overlay.mounted.push(overlay.dir.clone());
+        for sub in ["upper", "work", "merged"] {
+            fs::create_dir(overlay.dir.join(sub))?;
+        }
+
+        let options = format!(
+            "lowerdir={},upperdir={},workdir={}",
+            escape(&lower),
+            escape(&overlay.dir.join("upper")),
+            escape(&overlay.dir.join("work"))
+        );
+        // Metadata-only copy-up spares copying file data to change an owner, where the
+        // kernel supports it
+        let flags = MsFlags::MS_NOSUID | MsFlags::MS_NODEV;
+        mount(
+            Some("overlay"),
+            &overlay.merged,
+            Some("overlay"),
+            flags,
+            Some(format!("{options},metacopy=on").as_str()),
+        )
+        .or_else(|e| {
+            debug!("Overlay without metacopy ({})", e);
+            mount(
+                Some("overlay"),
+                &overlay.merged,
+                Some("overlay"),
+                flags,
+                Some(options.as_str()),
+            )
+        })
+        .map_err(|e| mount_error("overlay", &overlay.merged, e))?;
+        overlay.mounted.push(overlay.merged.clone());
+        Ok(overlay)
+    }
+
+    /// The merged tree, where the overlay shows the tree and any changes made to it.
+    pub fn merged(&self) -> &Path {
+        &self.merged
+    }
+
+    /// Leave the merged tree for the user to look at until they press Enter, or right
+    /// away if stdin has nothing to read.
+    pub fn wait_for_inspection(&self) -> io::Result<()> {
+        eprintln!(
+            "The remapped preview is at {}; press Enter to discard it",
+            self.merged.display()
+        );
+        io::stdin().read_line(&mut String::new())?;
+        Ok(())
+    }
+}
+
+impl Drop for PreviewOverlay {
+    fn drop(&mut self) {
+        while let Some(mountpoint) = self.mounted.pop() {
+            // Detached, so a shell still inside the preview cannot keep it mounted
+            if let Err(e) = umount2(&mountpoint, MntFlags::MNT_DETACH) {
+                warn!("Cannot unmount {}: {}", mountpoint.display(), e);
+                return;
+            }
+        }
+        if let Err(e) = fs::remove_dir(&self.dir) {
+            warn!("Cannot remove {}: {}", self.dir.display(), e);
+        }
+    }
+}
+
+fn mount_error(fstype: &str, path: &Path, errno: Errno) -> RustUtilsError {
+    RustUtilsError::OperationFailed(format!(
+        "cannot mount {fstype} on {}: {errno}",
+        path.display()
+    ))
+}
+
+/// Escape the characters overlayfs options split on.
+fn escape(path: &Path) -> String {
+    let mut escaped = String::new();
+    for c in path.to_string_lossy().chars() {
+        if matches!(c, '\\' | ',' | ':') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape(Path::new("/srv/a,b:c")), r"/srv/a\,b\:c");
+    }
+
+    #[test]
+    fn test_preview_overlay() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let tree = TempDir::new()?;
+        let parent = TempDir::new()?;
+        fs::write(tree.path().join("file"), "data")?;
+
+        let Ok(overlay) = PreviewOverlay::mount(tree.path(), parent.path()) else {
+            // Mounting needs CAP_SYS_ADMIN; nothing is left behind either way
+            assert_eq!(fs::read_dir(parent.path())?.count(), 0);
+            return Ok(());
+        };
+        let merged = overlay.merged().to_path_buf();
+        fs::write(merged.join("file"), "changed")?;
+        fs::write(merged.join("new"), "")?;
+        assert_eq!(fs::read_to_string(tree.path().join("file"))?, "data");
+        assert!(!tree.path().join("new").exists());
+
+        drop(overlay);
+        assert_eq!(fs::read_dir(parent.path())?.count(), 0);
+        Ok(())
+    }
+}
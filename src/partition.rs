@@ -0,0 +1,494 @@
+//! Splitting a tree into units that run as independent, resumable jobs, possibly on several
+//! hosts, whose run reports are merged afterwards.
+//!
+//! A unit is a top-level entry of the tree, or a subtree named with `--subtree`. With
+//! `--partition I/N`, a job takes the units whose name hashes to `I` out of `N`, so every host
+//! given the same `N` agrees on the split without coordination. Each job appends the units
+//! it completes to a journal in the state directory; `--resume` skips them after an
+//! interruption. A job holds an exclusive `flock` on its journal while it runs.
+//!
+//! With `--coordinate DIR`, jobs on hosts sharing `DIR` instead hand out units between
+//! themselves: a job claims a unit by creating `claims/<unit>` exclusively and marks it done
+//! by renaming a file into `done/<unit>`, both atomic on local and NFS storage alike.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use nix::fcntl::{flock, FlockArg};
+use sha2::{Digest, Sha256};
+
+use crate::error::{Result, RustUtilsError};
+use crate::util::{hex, unhex};
+
+/// First word of every partition journal.
+pub const JOURNAL_MAGIC: &str = "rust-utils-partition-journal";
+
+/// Version of the partition journal format written by this build.
+pub const JOURNAL_FORMAT_VERSION: u32 = 1;
+
+/// Partition `index` (1-based) of `count`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Partition {
+    pub index: u32,
+    pub count: u32,
+}
+
+impl Partition {
+    /// Whether the unit named `name` belongs to this partition.
+    pub fn contains(&self, name: &Path) -> bool {
+        let digest = Sha256::digest(name.as_os_str().as_bytes());
+        let hash = u64::from_be_bytes(digest[..8].try_into().expect("digest is long enough"));
+        hash % u64::from(self.count) == u64::from(self.index - 1)
+    }
+}
+
+impl FromStr for Partition {
+    type Err = RustUtilsError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (index, count) = s
+            .split_once('/')
+            .and_then(|(index, count)| Some((index.parse().ok()?, count.parse().ok()?)))
+            .ok_or_else(|| {
+                RustUtilsError::InvalidArguments(format!(
+                    "invalid partition '{s}' (expected INDEX/COUNT, e.g. 2/8)"
+                ))
+            })?;
+        if count == 0 || index == 0 || index > count {
+            return Err(RustUtilsError::InvalidRange(format!(
+                "partition '{s}' must have 1 <= INDEX <= COUNT"
+            )));
+        }
+        Ok(Partition { index, count })
+    }
+}
+
+impl fmt::Display for Partition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.index, self.count)
+    }
+}
+
+/// Units completed by a job, kept in its journal.
+pub struct Journal {
+    path: PathBuf,
+    file: File,
+    done: HashSet<Vec<u8>>,
+}
+
+impl Journal {
+    /// Open the journal at `path` for the job identified by `key`, a string without
+    /// whitespace. With `resume`, units recorded by an earlier run of the same job count as
+    /// done; otherwise the journal starts empty.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`RustUtilsError::InvalidArguments`] when resuming from a journal of a
+    /// different job and [`RustUtilsError::UnsupportedFormat`] for journals written by a
+    /// newer version.
+    pub fn open(path: &Path, key: &str, resume: bool) -> Result<Self> {
+        let existed = path.exists();
+        let mut file = loop {
+            let file = OpenOptions::new()
+                .create(true)
+                .write(true)
+                .truncate(false)
+                .open(path)?;
+            // Waits out a `journal compact` of the file, which is brief
+            flock(file.as_raw_fd(), FlockArg::LockExclusive)?;
+            // Compaction renames the compacted journal over the one this job opened
+            let locked = file.metadata()?;
+            if fs::metadata(path)
+                .is_ok_and(|current| (current.dev(), current.ino()) == (locked.dev(), locked.ino()))
+            {
+                break file;
+            }
+        };
+        let done = if resume && existed {
+            read_journal(path, key)?
+        } else {
+            HashSet::new()
+        };
+        file.set_len(0)?;
+        writeln!(file, "{JOURNAL_MAGIC} {JOURNAL_FORMAT_VERSION} {key}")?;
+        for unit in &done {
+            writeln!(file, "{}", hex(unit))?;
+        }
+        file.sync_data()?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+            done,
+        })
+    }
+
+    pub fn is_done(&self, unit: &Path) -> bool {
+        self.done.contains(unit.as_os_str().as_bytes())
+    }
+
+    /// Units recorded as completed.
+    pub fn done(&self) -> impl Iterator<Item = &Path> {
+        self.done
+            .iter()
+            .map(|unit| Path::new(std::ffi::OsStr::from_bytes(unit)))
+    }
+
+    pub fn done_count(&self) -> usize {
+        self.done.len()
+    }
+
+    /// Record `unit` as completed, durably.
+    pub fn complete(&mut self, unit: &Path) -> Result<()> {
+        let bytes = unit.as_os_str().as_bytes();
+        writeln!(self.file, "{}", hex(bytes))?;
+        self.file.sync_data()?;
+        self.done.insert(bytes.to_vec());
+        Ok(())
+    }
+
+    /// Remove the journal once the whole job has completed.
+    pub fn finish(self) -> Result<()> {
+        fs::remove_file(&self.path)?;
+        Ok(())
+    }
+}
+
+/// Outcome of trying to claim a unit for this job.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Claim {
+    /// The unit is this job's to process
+    Claimed,
+    /// Some job already completed the unit
+    Done,
+    /// Another job is processing the unit; holds that job's `HOST PID`
+    Taken(String),
+}
+
+/// Units handed out between the jobs sharing a coordination directory.
+pub struct Coordinator {
+    dir: PathBuf,
+    /// `HOST PID` of this job, written into its claims
+    owner: String,
+    /// Units claimed and not yet completed, released again on drop
+    held: HashSet<String>,
+}
+
+impl Coordinator {
+    /// Join the jobs coordinating through `dir` for the job identified by `key`, creating the
+    /// directory layout if this is the first.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`RustUtilsError::InvalidArguments`] if `dir` coordinates a different job.
+    pub fn open(dir: &Path, key: &str) -> Result<Self> {
+        fs::create_dir_all(dir.join("claims"))?;
+        fs::create_dir_all(dir.join("done"))?;
+        // Linking a complete file into place publishes the key atomically
+        let job = dir.join("job");
+        let partial = dir.join(format!(".job.{}", std::process::id()));
+        fs::write(&partial, format!("{key}\n"))?;
+        let linked = fs::hard_link(&partial, &job);
+        fs::remove_file(&partial)?;
+        match linked {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                if fs::read_to_string(&job)?.trim() != key {
+                    return Err(RustUtilsError::InvalidArguments(format!(
+                        "{} coordinates a different job (other ranges or options)",
+                        dir.display()
+                    )));
+                }
+            }
+            Err(e) => return Err(e.into()),
+        }
+        let host = fs::read_to_string("/proc/sys/kernel/hostname")
+            .map(|host| host.trim().to_string())
+            .unwrap_or_else(|_| "localhost".to_string());
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            owner: format!("{} {}", host, std::process::id()),
+            held: HashSet::new(),
+        })
+    }
+
+    /// Try to claim `unit`. A claim left behind by a job on this host that no longer runs is
+    /// taken over; claims of other hosts are only ever released by their job.
+    pub fn claim(&mut self, unit: &Path) -> Result<Claim> {
+        let id = hex(&Sha256::digest(unit.as_os_str().as_bytes())[..16]);
+        if self.done_path(&id).exists() {
+            return Ok(Claim::Done);
+        }
+        let claim = self.claim_path(&id);
+        let mut took_over = false;
+        loop {
+            match OpenOptions::new().write(true).create_new(true).open(&claim) {
+                Ok(mut file) => {
+                    writeln!(file, "{}", self.owner)?;
+                    writeln!(file, "{}", hex(unit.as_os_str().as_bytes()))?;
+                    file.sync_all()?;
+                    break;
+                }
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                    let owner = fs::read_to_string(&claim)
+                        .map(|text| text.lines().next().unwrap_or_default().to_string())
+                        .unwrap_or_default();
+                    if took_over || !self.is_stale(&owner) {
+                        return Ok(Claim::Taken(owner));
+                    }
+                    // Renaming is atomic, so only one of several jobs takes the claim over
+                    let stale = claim.with_extension(format!("stale-{}", std::process::id()));
+                    if fs::rename(&claim, &stale).is_ok() {
+                        fs::remove_file(&stale)?;
+                    }
+                    took_over = true;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        // Another job may have completed the unit between the check and the claim
+        if self.done_path(&id).exists() {
+            fs::remove_file(&claim)?;
+            return Ok(Claim::Done);
+        }
+        self.held.insert(id);
+        Ok(Claim::Claimed)
+    }
+
+    /// Mark a claimed `unit` done, so no job processes it again.
+    pub fn complete(&mut self, unit: &Path) -> Result<()> {
+        let id = hex(&Sha256::digest(unit.as_os_str().as_bytes())[..16]);
+        let partial = self
+            .dir
+            .join("done")
+            .join(format!(".{}.{}", id, std::process::id()));
+        let mut file = File::create(&partial)?;
+        writeln!(file, "{}", self.owner)?;
+        file.sync_all()?;
+        fs::rename(&partial, self.done_path(&id))?;
+        fs::remove_file(self.claim_path(&id))?;
+        self.held.remove(&id);
+        Ok(())
+    }
+
+    fn claim_path(&self, id: &str) -> PathBuf {
+        self.dir.join("claims").join(id)
+    }
+
+    fn done_path(&self, id: &str) -> PathBuf {
+        self.dir.join("done").join(id)
+    }
+
+    /// Whether `owner` is a job on this host that is no longer running.
+    fn is_stale(&self, owner: &str) -> bool {
+        let (ours, _) = self.owner.split_once(' ').unwrap_or_default();
+        match owner.split_once(' ') {
+            Some((host, pid)) if host == ours => pid
+                .parse::<u32>()
+                .is_ok_and(|pid| !Path::new("/proc").join(pid.to_string()).exists()),
+            _ => false,
+        }
+    }
+}
+
+impl Drop for Coordinator {
+    fn drop(&mut self) {
+        // Units of a failed job go back to the others
+        for id in self.held.drain() {
+            let _ = fs::remove_file(self.dir.join("claims").join(id));
+        }
+    }
+}
+
+/// Identifies a job from everything that decides what it changes.
+pub fn job_key(parts: &[&[u8]]) -> String {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        hasher.update((part.len() as u64).to_be_bytes());
+        hasher.update(part);
+    }
+    hex(&hasher.finalize()[..16])
+}
+
+fn read_journal(path: &Path, key: &str) -> Result<HashSet<Vec<u8>>> {
+    let invalid =
+        |detail: &str| RustUtilsError::InvalidArguments(format!("{}: {}", path.display(), detail));
+
+    // Only newline-terminated lines count; a torn final line from a crash mid-append is
+    // ignored
+    let text = fs::read_to_string(path)?;
+    let complete = text.rsplit_once('\n').map_or("", |(complete, _)| complete);
+    let mut lines = complete.lines();
+    let header = lines.next().unwrap_or_default();
+    let mut fields = header.split_whitespace();
+    let (Some(JOURNAL_MAGIC), Some(Ok(version)), Some(found)) = (
+        fields.next(),
+        fields.next().map(str::parse::<u32>),
+        fields.next(),
+    ) else {
+        return Err(invalid("not a partition journal"));
+    };
+    if version > JOURNAL_FORMAT_VERSION {
+        return Err(RustUtilsError::UnsupportedFormat(format!(
+            "{}: partition journal version {} is newer than the supported version {}",
+            path.display(),
+            version,
+            JOURNAL_FORMAT_VERSION
+        )));
+    }
+    if found != key {
+        return Err(invalid(
+            "journal belongs to a different job; remove it or drop --resume",
+        ));
+    }
+
+    lines
+        .map(|line| unhex(line.trim()).ok_or_else(|| invalid("corrupt journal line")))
+        .collect()
+}
+
+/// Drop the units recorded more than once in the journal at `path`, replacing it with a
+/// compacted copy, and return the number of lines dropped. The caller holds the journal's
+/// lock.
+pub fn compact_journal(path: &Path) -> Result<usize> {
+    let text = fs::read_to_string(path)?;
+    let complete = text.rsplit_once('\n').map_or("", |(complete, _)| complete);
+    let mut lines = complete.lines();
+    let header = lines.next().unwrap_or_default();
+    if header.split_whitespace().next() != Some(JOURNAL_MAGIC) {
+        return Err(RustUtilsError::InvalidArguments(format!(
+            "{}: not a partition journal",
+            path.display()
+        )));
+    }
+    let mut seen = HashSet::new();
+    let mut contents = format!("{header}\n");
+    let mut dropped = 0;
+    for line in lines {
+        if seen.insert(line.trim()) {
+            contents.push_str(line.trim());
+            contents.push('\n');
+        } else {
+            dropped += 1;
+        }
+    }
+    // The journal stays whole until the compacted copy is on disk to take its place
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(format!(".{}", std::process::id()));
+    let partial = PathBuf::from(partial);
+    let written = File::create(&partial).and_then(|mut file| {
+        file.set_permissions(fs::metadata(path)?.permissions())?;
+        file.write_all(contents.as_bytes())?;
+        file.sync_all()
+    });
+    if let Err(e) = written.and_then(|()| fs::rename(&partial, path)) {
+        let _ = fs::remove_file(&partial);
+        return Err(e.into());
+    }
+    Ok(dropped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_partition_parsing() {
+        assert_eq!(
+            "2/8".parse::<Partition>().unwrap(),
+            Partition { index: 2, count: 8 }
+        );
+        assert!("0/8".parse::<Partition>().is_err());
+        assert!("9/8".parse::<Partition>().is_err());
+        assert!("2".parse::<Partition>().is_err());
+    }
+
+    #[test]
+    fn test_partitions_cover_every_unit_once() {
+        let partitions: Vec<Partition> =
+            (1..=4).map(|index| Partition { index, count: 4 }).collect();
+        for name in ["home", "srv", "var", "usr", "etc", "opt", "data-0042"] {
+            let owners = partitions
+                .iter()
+                .filter(|partition| partition.contains(Path::new(name)))
+                .count();
+            assert_eq!(owners, 1, "{name}");
+        }
+    }
+
+    #[test]
+    fn test_coordinator_claims() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let dir = TempDir::new()?;
+        let mut first = Coordinator::open(dir.path(), "job")?;
+        let mut second = Coordinator::open(dir.path(), "job")?;
+        assert!(Coordinator::open(dir.path(), "other-job").is_err());
+        // Both handles belong to this process, so tell them apart as two live hosts would
+        second.owner = "elsewhere 1".to_string();
+
+        let home = Path::new("home");
+        assert_eq!(first.claim(home)?, Claim::Claimed);
+        assert_eq!(second.claim(home)?, Claim::Taken(first.owner.clone()));
+        first.complete(home)?;
+        assert_eq!(second.claim(home)?, Claim::Done);
+
+        // A failed job releases its claims
+        assert_eq!(second.claim(Path::new("srv"))?, Claim::Claimed);
+        drop(second);
+        assert_eq!(first.claim(Path::new("srv"))?, Claim::Claimed);
+
+        // Claims of jobs on this host that died are taken over
+        let id = hex(&Sha256::digest(b"var")[..16]);
+        let (host, _) = first.owner.split_once(' ').unwrap();
+        fs::write(
+            dir.path().join("claims").join(&id),
+            format!("{host} 4294967295\n"),
+        )?;
+        assert_eq!(first.claim(Path::new("var"))?, Claim::Claimed);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_journal_resume() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("job.journal");
+
+        let mut journal = Journal::open(&path, "job", false)?;
+        journal.complete(Path::new("home"))?;
+        journal.complete(Path::new("srv/data\nset"))?;
+        drop(journal);
+        // Simulate a crash in the middle of an append
+        let mut file = OpenOptions::new().append(true).open(&path)?;
+        write!(file, "76")?;
+        drop(file);
+
+        let journal = Journal::open(&path, "job", true)?;
+        assert_eq!(journal.done_count(), 2);
+        // Units recorded twice, as by a job completing one again, are kept once
+        fs::write(
+            &path,
+            fs::read_to_string(&path)? + &format!("{}\n", hex(b"home")),
+        )?;
+        assert_eq!(compact_journal(&path)?, 1);
+        assert_eq!(fs::read_to_string(&path)?.lines().count(), 3);
+        assert_eq!(fs::read_dir(dir.path())?.count(), 1);
+        assert!(journal.is_done(Path::new("srv/data\nset")));
+        assert!(!journal.is_done(Path::new("var")));
+        drop(journal);
+
+        assert!(Journal::open(&path, "other-job", true).is_err());
+        let journal = Journal::open(&path, "job", false)?;
+        assert_eq!(journal.done_count(), 0);
+        journal.finish()?;
+        assert!(!path.exists());
+
+        Ok(())
+    }
+}
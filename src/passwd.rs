@@ -0,0 +1,179 @@
+//! User and group databases of a rootfs, for `remap --map-user`, `--map-group` and
+//! `--known-groups-only`.
+//!
+//! A name in a container image stands for the ID the image's own `/etc/passwd` or
+//! `/etc/group` gives it, which need not be the one the host gives the same name.
+//! [`Database`] reads them from the tree, following symlinks the way the container would,
+//! so a database linked to an absolute path is still the tree's and never the host's.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::error::{Result, RustUtilsError};
+use crate::fs::resolve_in_root;
+use crate::idmap::IdKind;
+
+/// User database of a tree, relative to its root.
+pub const PASSWD_FILE: &str = "etc/passwd";
+
+/// Group database of a tree, relative to its root.
+pub const GROUP_FILE: &str = "etc/group";
+
+/// A user or group of the tree, by name, mapped to a single ID, as `NAME:TO`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NamedMapping {
+    pub name: String,
+    pub to: u32,
+}
+
+impl FromStr for NamedMapping {
+    type Err = RustUtilsError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || {
+            RustUtilsError::InvalidArguments(format!("invalid mapping '{s}' (expected NAME:TO)"))
+        };
+        let (name, to) = s.rsplit_once(':').ok_or_else(invalid)?;
+        if name.is_empty() || name.contains(':') {
+            return Err(invalid());
+        }
+        Ok(Self {
+            name: name.to_string(),
+            to: to.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+/// The names and IDs of a tree's user or group database.
+#[derive(Debug)]
+pub struct Database {
+    path: PathBuf,
+    kind: IdKind,
+    ids: HashMap<String, u32>,
+    names: HashMap<u32, String>,
+}
+
+impl Database {
+    /// Read the user database of the tree at `root` for UIDs, or its group database for
+    /// GIDs.
+    pub fn read(root: &Path, kind: IdKind) -> Result<Self> {
+        let file = match kind {
+            IdKind::Uid => PASSWD_FILE,
+            IdKind::Gid => GROUP_FILE,
+        };
+        let path = root.join(file);
+        let resolved = resolve_in_root(root, &path)?.ok_or_else(|| {
+            RustUtilsError::InvalidArguments(format!(
+                "{} does not exist in the tree",
+                path.display()
+            ))
+        })?;
+        let contents = fs::read_to_string(&resolved)?;
+        let mut ids = HashMap::new();
+        let mut names = HashMap::new();
+        for line in contents.lines() {
+            if line.starts_with('#') {
+                continue;
+            }
+            // NAME:PASSWORD:ID:…; the first entry of a name wins, as with getpwnam
+            let mut fields = line.split(':');
+            let (Some(name), Some(_), Some(id)) = (fields.next(), fields.next(), fields.next())
+            else {
+                continue;
+            };
+            if let Ok(id) = id.parse() {
+                ids.entry(name.to_string()).or_insert(id);
+                names.entry(id).or_insert_with(|| name.to_string());
+            }
+        }
+        Ok(Self {
+            path,
+            kind,
+            ids,
+            names,
+        })
+    }
+
+    /// The ID of `name` in the database.
+    pub fn id(&self, name: &str) -> Result<u32> {
+        self.ids.get(name).copied().ok_or_else(|| {
+            RustUtilsError::InvalidArguments(format!(
+                "no {} '{name}' in {}",
+                match self.kind {
+                    IdKind::Uid => "user",
+                    IdKind::Gid => "group",
+                },
+                self.path.display()
+            ))
+        })
+    }
+
+    /// The first name the database gives `id`, if any.
+    pub fn name(&self, id: u32) -> Option<&str> {
+        self.names.get(&id).map(String::as_str)
+    }
+
+    /// Where the database was read from, as the path in the tree.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_parse_named_mapping() {
+        assert_eq!(
+            "postgres:64000".parse::<NamedMapping>().unwrap(),
+            NamedMapping {
+                name: "postgres".to_string(),
+                to: 64000
+            }
+        );
+        for s in ["postgres", ":64000", "postgres:", "postgres:-1", "a:b:1"] {
+            assert!(s.parse::<NamedMapping>().is_err(), "{s}");
+        }
+    }
+
+    #[test]
+    fn test_read_database() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = TempDir::new()?;
+        let root = temp_dir.path().join("rootfs");
+        fs::create_dir_all(root.join("etc"))?;
+        fs::create_dir_all(root.join("usr/share/base"))?;
+        fs::write(
+            root.join("etc/passwd"),
+            "root:x:0:0:root:/root:/bin/sh\n# local\n\npostgres:x:999:999::/var/lib/postgresql:/bin/sh\n\
+             postgres:x:70:70::/:/bin/sh\n+nis\n",
+        )?;
+        // Absolute links resolve inside the tree, not on the host
+        fs::write(
+            root.join("usr/share/base/group"),
+            "root:x:0:\npostgres:x:998:\n",
+        )?;
+        std::os::unix::fs::symlink("/usr/share/base/group", root.join("etc/group"))?;
+
+        let users = Database::read(&root, IdKind::Uid)?;
+        assert_eq!(users.id("root")?, 0);
+        assert_eq!(users.id("postgres")?, 999);
+        let error = users.id("nis").unwrap_err().to_string();
+        assert!(error.contains("no user 'nis'"), "{error}");
+        assert!(error.contains("etc/passwd"), "{error}");
+
+        let groups = Database::read(&root, IdKind::Gid)?;
+        assert_eq!(groups.id("postgres")?, 998);
+        assert_eq!(groups.name(998), Some("postgres"));
+        assert_eq!(groups.name(999), None);
+        assert_eq!(groups.path(), root.join("etc/group"));
+
+        fs::remove_file(root.join("etc/passwd"))?;
+        let error = Database::read(&root, IdKind::Uid).unwrap_err().to_string();
+        assert!(error.contains("does not exist"), "{error}");
+        Ok(())
+    }
+}
@@ -0,0 +1,572 @@
+use std::collections::BTreeMap;
+use std::fs::{File, Metadata};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+use walkdir::WalkDir;
+
+use crate::error::{Result, RustUtilsError};
+use crate::fs::{get_file_metadata, should_exclude};
+use crate::util::hex;
+
+/// Something emitted by a visitor while walking the tree.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum VisitEvent {
+    /// The visitor changed (or in dry-run mode, would change) an entry
+    Change {
+        visitor: String,
+        path: PathBuf,
+        description: String,
+    },
+    /// The visitor noticed something worth reporting without changing it
+    Finding {
+        visitor: String,
+        path: PathBuf,
+        description: String,
+    },
+}
+
+/// Per-entry context handed to [`TreeVisitor`] callbacks.
+pub struct VisitContext<'a> {
+    visitor: &'a str,
+    /// Entry path relative to the root of the walk
+    pub relative: &'a Path,
+    /// Whether the run must not modify the tree
+    pub dry_run: bool,
+    events: &'a mut Vec<VisitEvent>,
+}
+
+impl VisitContext<'_> {
+    /// Record a change made to `path`; visitors must only modify the tree when `dry_run` is false.
+    pub fn emit_change(&mut self, path: &Path, description: impl Into<String>) {
+        self.events.push(VisitEvent::Change {
+            visitor: self.visitor.to_string(),
+            path: path.to_path_buf(),
+            description: description.into(),
+        });
+    }
+
+    /// Record an observation about `path`.
+    pub fn emit_finding(&mut self, path: &Path, description: impl Into<String>) {
+        self.events.push(VisitEvent::Finding {
+            visitor: self.visitor.to_string(),
+            path: path.to_path_buf(),
+            description: description.into(),
+        });
+    }
+}
+
+/// Custom per-entry action plugged into the shared walker.
+///
+/// Directories are passed to [`visit_dir`](TreeVisitor::visit_dir) and every other entry
+/// (regular files, symlinks, devices, FIFOs, sockets) to
+/// [`visit_file`](TreeVisitor::visit_file). Metadata is not followed through symlinks.
+///
+/// # Examples
+///
+/// ```
+/// use std::fs::Metadata;
+/// use std::path::Path;
+///
+/// use rust_utils::error::Result;
+/// use rust_utils::pipeline::{TreeVisitor, VisitContext};
+///
+/// struct EmptyFiles;
+///
+/// impl TreeVisitor for EmptyFiles {
+///     fn name(&self) -> &str {
+///         "empty-files"
+///     }
+///
+///     fn visit_file(&mut self, path: &Path, metadata: &Metadata, ctx: &mut VisitContext) -> Result<()> {
+///         if metadata.is_file() && metadata.len() == 0 {
+///             ctx.emit_finding(path, "empty file");
+///         }
+///         Ok(())
+///     }
+/// }
+/// ```
+pub trait TreeVisitor {
+    /// Short name used to label the visitor's events and summary lines.
+    fn name(&self) -> &str;
+
+    /// Inspect a non-directory entry.
+    fn visit_file(
+        &mut self,
+        path: &Path,
+        metadata: &Metadata,
+        ctx: &mut VisitContext,
+    ) -> Result<()> {
+        let _ = (path, metadata, ctx);
+        Ok(())
+    }
+
+    /// Inspect a directory entry, including the root of the walk.
+    fn visit_dir(
+        &mut self,
+        path: &Path,
+        metadata: &Metadata,
+        ctx: &mut VisitContext,
+    ) -> Result<()> {
+        let _ = (path, metadata, ctx);
+        Ok(())
+    }
+
+    /// Summary lines reported once the walk has finished.
+    fn finish(&mut self) -> Vec<String> {
+        Vec::new()
+    }
+}
+
+type VisitorFactory = Box<dyn Fn() -> Box<dyn TreeVisitor>>;
+
+/// Named visitor constructors that `--with` selections are resolved against.
+pub struct VisitorRegistry {
+    factories: BTreeMap<String, VisitorFactory>,
+}
+
+impl VisitorRegistry {
+    /// Registry without any visitors.
+    pub fn empty() -> Self {
+        Self {
+            factories: BTreeMap::new(),
+        }
+    }
+
+    /// Registry containing the built-in `owners`, `perms` and `checksum` visitors.
+    pub fn with_builtins() -> Self {
+        let mut registry = Self::empty();
+        registry.register("owners", || Box::<OwnersTask>::default());
+        registry.register("perms", || Box::<PermsTask>::default());
+        registry.register("checksum", || Box::<ChecksumTask>::default());
+        registry
+    }
+
+    /// Register a visitor under `name`, replacing any previous registration.
+    pub fn register<F>(&mut self, name: &str, factory: F)
+    where
+        F: Fn() -> Box<dyn TreeVisitor> + 'static,
+    {
+        self.factories.insert(name.to_string(), Box::new(factory));
+    }
+
+    /// Names of all registered visitors in sorted order.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.factories.keys().map(String::as_str)
+    }
+
+    /// Instantiate the visitor registered under `name`.
+    pub fn create(&self, name: &str) -> Option<Box<dyn TreeVisitor>> {
+        self.factories.get(name).map(|factory| factory())
+    }
+}
+
+impl Default for VisitorRegistry {
+    fn default() -> Self {
+        Self::with_builtins()
+    }
+}
+
+/// Set of visitors fed from one walk of the tree.
+#[derive(Default)]
+pub struct Pipeline {
+    visitors: Vec<Box<dyn TreeVisitor>>,
+    events: Vec<VisitEvent>,
+}
+
+impl Pipeline {
+    /// Build a pipeline from registry names, ignoring duplicates.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`RustUtilsError::InvalidArguments`] naming the available visitors when a
+    /// name is not registered.
+    pub fn from_names(registry: &VisitorRegistry, names: &[String]) -> Result<Self> {
+        let mut pipeline = Self::default();
+        let mut added: Vec<&str> = Vec::new();
+        for name in names {
+            if added.contains(&name.as_str()) {
+                continue;
+            }
+            let visitor = registry.create(name).ok_or_else(|| {
+                RustUtilsError::InvalidArguments(format!(
+                    "unknown task '{}' (available: {})",
+                    name,
+                    registry.names().collect::<Vec<_>>().join(", ")
+                ))
+            })?;
+            added.push(name);
+            pipeline.push(visitor);
+        }
+        Ok(pipeline)
+    }
+
+    pub fn push(&mut self, visitor: Box<dyn TreeVisitor>) {
+        self.visitors.push(visitor);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.visitors.is_empty()
+    }
+
+    /// Feed an entry to every visitor, stopping at the first failure.
+    pub fn visit(
+        &mut self,
+        path: &Path,
+        relative: &Path,
+        metadata: &Metadata,
+        dry_run: bool,
+    ) -> Result<()> {
+        for visitor in &mut self.visitors {
+            let name = visitor.name().to_string();
+            let mut ctx = VisitContext {
+                visitor: &name,
+                relative,
+                dry_run,
+                events: &mut self.events,
+            };
+            if metadata.is_dir() {
+                visitor.visit_dir(path, metadata, &mut ctx)?;
+            } else {
+                visitor.visit_file(path, metadata, &mut ctx)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Take the events emitted since the last call.
+    pub fn drain_events(&mut self) -> Vec<VisitEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    /// Walk `root` on its own, skipping paths matching `exclude`, and return every event.
+    pub fn walk(
+        &mut self,
+        root: &Path,
+        exclude: &[String],
+        dry_run: bool,
+    ) -> Result<Vec<VisitEvent>> {
+        for entry in WalkDir::new(root)
+            .follow_links(false)
+            .into_iter()
+            .filter_entry(|e| !should_exclude(e.path(), exclude))
+        {
+            let entry = entry.map_err(|e| RustUtilsError::OperationFailed(e.to_string()))?;
+            let metadata = get_file_metadata(entry.path())?;
+            let relative = entry.path().strip_prefix(root).unwrap_or(entry.path());
+            self.visit(entry.path(), relative, &metadata, dry_run)?;
+        }
+        Ok(self.drain_events())
+    }
+
+    /// Collect `(visitor name, line)` summaries from every visitor.
+    pub fn finish(&mut self) -> Vec<(String, String)> {
+        self.visitors
+            .iter_mut()
+            .flat_map(|visitor| {
+                let name = visitor.name().to_string();
+                visitor
+                    .finish()
+                    .into_iter()
+                    .map(move |line| (name.clone(), line))
+            })
+            .collect()
+    }
+}
+
+#[derive(Default)]
+struct OwnersTask {
+    owners: BTreeMap<(u32, u32), u64>,
+}
+
+impl OwnersTask {
+    fn count(&mut self, metadata: &Metadata) -> Result<()> {
+        *self
+            .owners
+            .entry((metadata.uid(), metadata.gid()))
+            .or_default() += 1;
+        Ok(())
+    }
+}
+
+impl TreeVisitor for OwnersTask {
+    fn name(&self) -> &str {
+        "owners"
+    }
+
+    fn visit_file(
+        &mut self,
+        _path: &Path,
+        metadata: &Metadata,
+        _ctx: &mut VisitContext,
+    ) -> Result<()> {
+        self.count(metadata)
+    }
+
+    fn visit_dir(
+        &mut self,
+        _path: &Path,
+        metadata: &Metadata,
+        _ctx: &mut VisitContext,
+    ) -> Result<()> {
+        self.count(metadata)
+    }
+
+    fn finish(&mut self) -> Vec<String> {
+        self.owners
+            .iter()
+            .map(|((uid, gid), count)| format!("{uid}:{gid} {count}"))
+            .collect()
+    }
+}
+
+#[derive(Default)]
+struct PermsTask {
+    setuid: u64,
+    setgid: u64,
+    world_writable_files: u64,
+    world_writable_dirs: u64,
+}
+
+impl TreeVisitor for PermsTask {
+    fn name(&self) -> &str {
+        "perms"
+    }
+
+    fn visit_file(
+        &mut self,
+        _path: &Path,
+        metadata: &Metadata,
+        _ctx: &mut VisitContext,
+    ) -> Result<()> {
+        let mode = metadata.mode();
+        if metadata.is_file() {
+            if mode & 0o4000 != 0 {
+                self.setuid += 1;
+            }
+            if mode & 0o2000 != 0 {
+                self.setgid += 1;
+            }
+            if mode & 0o002 != 0 {
+                self.world_writable_files += 1;
+            }
+        }
+        Ok(())
+    }
+
+    fn visit_dir(
+        &mut self,
+        _path: &Path,
+        metadata: &Metadata,
+        _ctx: &mut VisitContext,
+    ) -> Result<()> {
+        let mode = metadata.mode();
+        if mode & 0o002 != 0 && mode & 0o1000 == 0 {
+            self.world_writable_dirs += 1;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Vec<String> {
+        vec![
+            format!("setuid files: {}", self.setuid),
+            format!("setgid files: {}", self.setgid),
+            format!("world-writable files: {}", self.world_writable_files),
+            format!(
+                "world-writable directories without sticky bit: {}",
+                self.world_writable_dirs
+            ),
+        ]
+    }
+}
+
+#[derive(Default)]
+struct ChecksumTask {
+    combined: [u8; 32],
+    files: u64,
+    bytes: u64,
+}
+
+impl TreeVisitor for ChecksumTask {
+    fn name(&self) -> &str {
+        "checksum"
+    }
+
+    fn visit_file(
+        &mut self,
+        path: &Path,
+        metadata: &Metadata,
+        ctx: &mut VisitContext,
+    ) -> Result<()> {
+        let relative = ctx.relative;
+        if !metadata.is_file() {
+            return Ok(());
+        }
+
+        let mut content = Sha256::new();
+        self.bytes += io::copy(&mut File::open(path)?, &mut content)?;
+        self.files += 1;
+
+        // XOR-combining per-file digests keeps the result independent of walk order
+        let mut entry = Sha256::new();
+        entry.update(relative.as_os_str().as_bytes());
+        entry.update([0]);
+        entry.update(content.finalize());
+        for (acc, byte) in self.combined.iter_mut().zip(entry.finalize()) {
+            *acc ^= byte;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Vec<String> {
+        let digest = hex(&self.combined);
+        vec![
+            format!("files: {} ({} bytes)", self.files, self.bytes),
+            format!("content: {digest}"),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{self, Permissions};
+    use std::io::Write;
+    use std::os::unix::fs::PermissionsExt;
+    use tempfile::TempDir;
+
+    fn run(names: &[&str], root: &Path) -> Vec<(String, String)> {
+        let names: Vec<String> = names.iter().map(|n| n.to_string()).collect();
+        let mut pipeline = Pipeline::from_names(&VisitorRegistry::with_builtins(), &names).unwrap();
+        pipeline.walk(root, &[], true).unwrap();
+        pipeline.finish()
+    }
+
+    fn line(task: &str, text: &str) -> (String, String) {
+        (task.to_string(), text.to_string())
+    }
+
+    struct EmptyFiles;
+
+    impl TreeVisitor for EmptyFiles {
+        fn name(&self) -> &str {
+            "empty-files"
+        }
+
+        fn visit_file(
+            &mut self,
+            path: &Path,
+            metadata: &Metadata,
+            ctx: &mut VisitContext,
+        ) -> Result<()> {
+            if metadata.len() == 0 {
+                ctx.emit_finding(path, "empty file");
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_pipeline_empty() {
+        let mut pipeline = Pipeline::from_names(&VisitorRegistry::with_builtins(), &[]).unwrap();
+        assert!(pipeline.is_empty());
+        assert!(pipeline.finish().is_empty());
+    }
+
+    #[test]
+    fn test_pipeline_deduplicates_tasks() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = TempDir::new()?;
+        let lines = run(&["owners", "owners"], temp_dir.path());
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0].0, "owners");
+        Ok(())
+    }
+
+    #[test]
+    fn test_pipeline_unknown_task() {
+        let result = Pipeline::from_names(&VisitorRegistry::with_builtins(), &["nope".to_string()]);
+        let message = result.err().unwrap().to_string();
+        assert!(message.contains("unknown task 'nope'"));
+        assert!(message.contains("checksum, owners, perms"));
+    }
+
+    #[test]
+    fn test_registry_custom_visitor() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = TempDir::new()?;
+        let empty = temp_dir.path().join("empty");
+        File::create(&empty)?;
+        fs::write(temp_dir.path().join("full"), "data")?;
+
+        let mut registry = VisitorRegistry::empty();
+        registry.register("empty-files", || Box::new(EmptyFiles));
+        assert_eq!(registry.names().collect::<Vec<_>>(), vec!["empty-files"]);
+
+        let mut pipeline = Pipeline::from_names(&registry, &["empty-files".to_string()])?;
+        let events = pipeline.walk(temp_dir.path(), &[], true)?;
+        assert_eq!(
+            events,
+            vec![VisitEvent::Finding {
+                visitor: "empty-files".to_string(),
+                path: empty,
+                description: "empty file".to_string(),
+            }]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_owners_task() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = TempDir::new()?;
+        File::create(temp_dir.path().join("a"))?;
+        File::create(temp_dir.path().join("b"))?;
+
+        let uid = nix::unistd::getuid().as_raw();
+        let gid = nix::unistd::getgid().as_raw();
+        let lines = run(&["owners"], temp_dir.path());
+        assert_eq!(lines, vec![line("owners", &format!("{uid}:{gid} 3"))]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_perms_task() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = TempDir::new()?;
+        let binary = temp_dir.path().join("ping");
+        File::create(&binary)?;
+        fs::set_permissions(&binary, Permissions::from_mode(0o4755))?;
+        let open = temp_dir.path().join("open");
+        fs::create_dir(&open)?;
+        fs::set_permissions(&open, Permissions::from_mode(0o777))?;
+
+        let lines = run(&["perms"], temp_dir.path());
+        assert!(lines.contains(&line("perms", "setuid files: 1")));
+        assert!(lines.contains(&line(
+            "perms",
+            "world-writable directories without sticky bit: 1"
+        )));
+        Ok(())
+    }
+
+    #[test]
+    fn test_checksum_task_is_order_independent(
+    ) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let first = TempDir::new()?;
+        let second = TempDir::new()?;
+        for (dir, order) in [(&first, ["x", "y"]), (&second, ["y", "x"])] {
+            for name in order {
+                File::create(dir.path().join(name))?.write_all(name.as_bytes())?;
+            }
+        }
+
+        let a = run(&["checksum"], first.path());
+        let b = run(&["checksum"], second.path());
+        assert_eq!(a, b);
+        assert_eq!(a[0].1, "files: 2 (2 bytes)");
+
+        File::create(second.path().join("x"))?.write_all(b"changed")?;
+        assert_ne!(a, run(&["checksum"], second.path()));
+        Ok(())
+    }
+}
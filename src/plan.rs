@@ -0,0 +1,203 @@
+//! Plan hashes: a digest of the ownership changes a remap makes, so that the changes shown
+//! by an approved dry run can be required of the run that applies them.
+//!
+//! Each change is hashed on its own and the digests are added up as 256-bit integers, which
+//! makes the result independent of the order the tree is walked in without keeping the
+//! changes in memory. The sum is hashed once more together with the tree and the number
+//! of changes.
+//!
+//! [`PlannedOwners`] keeps the owner each planned change starts from, for `--expect-clean`
+//! to hold the run applying the plan to them entry by entry.
+
+use std::collections::HashMap;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+
+use crate::error::{Result, RustUtilsError};
+use crate::undo::Owner;
+use crate::util::hex;
+
+/// Prefix of the written form of a plan hash.
+const PREFIX: &str = "sha256:";
+
+/// Version of the hashed content, changed whenever the same changes would hash differently.
+const PLAN_HASH_VERSION: &[u8] = b"rust-utils plan 1\n";
+
+/// Running digest of the changes of a remap.
+#[derive(Clone, Debug, Default)]
+pub struct PlanHash {
+    /// Sum of the digests of all changes, as little-endian 64-bit limbs
+    sum: [u64; 4],
+    changes: u64,
+}
+
+impl PlanHash {
+    /// Add the change of the entry at `path`, relative to the tree, from `old` to `new`.
+    pub fn add(&mut self, path: &Path, old: Owner, new: Owner) {
+        let mut hasher = Sha256::new();
+        hasher.update(path.as_os_str().as_bytes());
+        hasher.update([0]);
+        for id in [old.uid, old.gid, new.uid, new.gid] {
+            hasher.update(id.to_le_bytes());
+        }
+        let digest = hasher.finalize();
+
+        let mut carry = false;
+        for (limb, bytes) in self.sum.iter_mut().zip(digest.chunks_exact(8)) {
+            let value = u64::from_le_bytes(bytes.try_into().unwrap());
+            let (sum, overflow) = limb.overflowing_add(value);
+            let (sum, overflow_carry) = sum.overflowing_add(u64::from(carry));
+            *limb = sum;
+            carry = overflow || overflow_carry;
+        }
+        self.changes += 1;
+    }
+
+    /// Number of changes added.
+    pub fn changes(&self) -> u64 {
+        self.changes
+    }
+
+    /// The plan hash of the changes to the tree at the canonical path `root`, written
+    /// `sha256:HEX`.
+    pub fn finish(&self, root: &Path) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(PLAN_HASH_VERSION);
+        hasher.update(root.as_os_str().as_bytes());
+        hasher.update([0]);
+        hasher.update(self.changes.to_le_bytes());
+        for limb in self.sum {
+            hasher.update(limb.to_le_bytes());
+        }
+        format!("{PREFIX}{}", hex(&hasher.finalize()))
+    }
+}
+
+/// Owners that the planned changes start from, by path.
+#[derive(Debug, Default)]
+pub struct PlannedOwners {
+    before: HashMap<PathBuf, Owner>,
+}
+
+impl PlannedOwners {
+    /// Record that the plan changes the entry at `path` from `old`.
+    pub fn record(&mut self, path: &Path, old: Owner) {
+        self.before.insert(path.to_path_buf(), old);
+    }
+
+    /// Check that the entry at `path`, about to change from `current`, was planned to
+    /// change from that owner, and strike it off.
+    pub fn check(&mut self, path: &Path, current: Owner) -> Result<()> {
+        match self.before.remove(path) {
+            Some(planned) if planned == current => Ok(()),
+            Some(planned) => Err(RustUtilsError::UnexpectedChange(format!(
+                "{} is owned by {current}, not {planned} as planned",
+                path.display()
+            ))),
+            None => Err(RustUtilsError::UnexpectedChange(format!(
+                "{} is owned by {current}, which the plan does not change",
+                path.display()
+            ))),
+        }
+    }
+
+    /// Fail if any planned change was not checked off, because its entry is gone or no
+    /// longer needs the change.
+    pub fn finish(&self) -> Result<()> {
+        match self.before.keys().min() {
+            Some(path) => Err(RustUtilsError::UnexpectedChange(format!(
+                "{} planned change(s) no longer apply, such as that of {}",
+                self.before.len(),
+                path.display()
+            ))),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Check a plan hash given on the command line, with or without its `sha256:` prefix, and
+/// return it in its written form.
+pub fn parse_plan_hash(value: &str) -> Result<String> {
+    let digest = value.strip_prefix(PREFIX).unwrap_or(value);
+    if digest.len() != 64 || !digest.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(RustUtilsError::InvalidArguments(format!(
+            "'{value}' is not a plan hash (sha256: and 64 hex digits)"
+        )));
+    }
+    Ok(format!("{PREFIX}{}", digest.to_ascii_lowercase()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn owner(uid: u32, gid: u32) -> Owner {
+        Owner { uid, gid }
+    }
+
+    #[test]
+    fn test_plan_hash() {
+        let root = Path::new("/var/lib/lxc/web/rootfs");
+        let changes = [
+            ("", owner(0, 0), owner(100000, 100000)),
+            ("etc/shadow", owner(0, 42), owner(100000, 100042)),
+            ("var/www", owner(33, 33), owner(100033, 100033)),
+        ];
+        let hash = |order: &[usize]| {
+            let mut plan = PlanHash::default();
+            for &index in order {
+                let (path, old, new) = changes[index];
+                plan.add(Path::new(path), old, new);
+            }
+            plan.finish(root)
+        };
+
+        // The walk order does not matter, but every change and the tree do
+        let planned = hash(&[0, 1, 2]);
+        assert_eq!(planned, hash(&[2, 0, 1]));
+        assert!(planned.starts_with("sha256:"));
+        assert_ne!(planned, hash(&[0, 1]));
+        let mut other = PlanHash::default();
+        other.add(Path::new("etc/shadow"), owner(0, 42), owner(100000, 100000));
+        assert_ne!(hash(&[1]), other.finish(root));
+        assert_ne!(
+            PlanHash::default().finish(root),
+            PlanHash::default().finish(Path::new("/"))
+        );
+    }
+
+    #[test]
+    fn test_planned_owners() {
+        let mut planned = PlannedOwners::default();
+        planned.record(Path::new("/rootfs/etc"), owner(0, 0));
+        planned.record(Path::new("/rootfs/etc/shadow"), owner(0, 42));
+        planned.record(Path::new("/rootfs/tmp"), owner(0, 0));
+
+        planned
+            .check(Path::new("/rootfs/etc"), owner(0, 0))
+            .unwrap();
+        let changed = planned.check(Path::new("/rootfs/etc/shadow"), owner(0, 0));
+        assert!(matches!(changed, Err(RustUtilsError::UnexpectedChange(_))));
+        assert!(planned
+            .check(Path::new("/rootfs/new"), owner(0, 0))
+            .is_err());
+        let error = planned.finish().unwrap_err();
+        assert!(error.to_string().contains("1 planned change(s)"));
+        planned
+            .check(Path::new("/rootfs/tmp"), owner(0, 0))
+            .unwrap();
+        planned.finish().unwrap();
+    }
+
+    #[test]
+    fn test_parse_plan_hash() {
+        let digest = "AB".repeat(32);
+        let written = format!("sha256:{}", digest.to_lowercase());
+        assert_eq!(parse_plan_hash(&digest).unwrap(), written);
+        assert_eq!(parse_plan_hash(&written).unwrap(), written);
+        assert!(parse_plan_hash("sha256:abc").is_err());
+        assert!(parse_plan_hash(&"g".repeat(64)).is_err());
+    }
+}
@@ -0,0 +1,291 @@
+//! WebAssembly filter/transform plugins for `remap --plugin`.
+//!
+//! A plugin is a core Wasm module (binary or text format) with no imports, so it has no
+//! access to the host beyond the values passed to it. It must export:
+//!
+//! - `memory`: linear memory the host writes paths into
+//! - `alloc(len: i32) -> i32`: return an offset where `len` bytes may be written
+//! - `decide(path_ptr: i32, path_len: i32, uid: i32, gid: i32, mode: i32) -> i32`:
+//!   return `0` to process the entry or `1` to skip it. The path is relative to the base
+//!   directory and IDs are passed as their raw 32-bit values.
+//!
+//! It may additionally export `map_uid(id: i32) -> i64` and `map_gid(id: i32) -> i64`,
+//! which are consulted for IDs inside the source range and return the new ID, or `-1` to
+//! keep the default offset mapping.
+//!
+//! Every call runs with a fixed fuel budget so a misbehaving plugin cannot stall the walk.
+
+use std::path::Path;
+
+use crate::error::Result;
+#[cfg(not(feature = "wasm-plugins"))]
+use crate::error::RustUtilsError;
+
+/// Decision returned by a plugin's `decide` export.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PluginDecision {
+    Process,
+    Skip,
+}
+
+#[cfg(feature = "wasm-plugins")]
+mod wasm {
+    use std::cell::RefCell;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::Path;
+
+    use wasmi::{Config, Engine, Instance, Linker, Memory, Module, Store, TypedFunc};
+
+    use super::PluginDecision;
+    use crate::error::{Result, RustUtilsError};
+
+    /// Fuel granted to each call into the plugin.
+    const FUEL_PER_CALL: u64 = 10_000_000;
+
+    struct Exports {
+        memory: Memory,
+        alloc: TypedFunc<i32, i32>,
+        decide: TypedFunc<(i32, i32, i32, i32, i32), i32>,
+        map_uid: Option<TypedFunc<i32, i64>>,
+        map_gid: Option<TypedFunc<i32, i64>>,
+    }
+
+    /// A loaded, sandboxed WebAssembly plugin.
+    pub struct WasmPlugin {
+        store: RefCell<Store<()>>,
+        exports: Exports,
+    }
+
+    fn plugin_error(context: &str, e: impl std::fmt::Display) -> RustUtilsError {
+        RustUtilsError::Plugin(format!("{context}: {e}"))
+    }
+
+    impl WasmPlugin {
+        /// Compile and instantiate a plugin from Wasm binary or text bytes.
+        pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+            let mut config = Config::default();
+            config.consume_fuel(true);
+            let engine = Engine::new(&config);
+            let module =
+                Module::new(&engine, bytes).map_err(|e| plugin_error("invalid module", e))?;
+
+            let mut store = Store::new(&engine, ());
+            // No host functions are linked, so plugins cannot perform any I/O
+            let linker = Linker::<()>::new(&engine);
+            store
+                .set_fuel(FUEL_PER_CALL)
+                .map_err(|e| plugin_error("fuel", e))?;
+            let instance: Instance = linker
+                .instantiate_and_start(&mut store, &module)
+                .map_err(|e| plugin_error("instantiation failed", e))?;
+
+            let memory = instance
+                .get_memory(&store, "memory")
+                .ok_or_else(|| RustUtilsError::Plugin("missing export: memory".to_string()))?;
+            let alloc = instance
+                .get_typed_func(&store, "alloc")
+                .map_err(|e| plugin_error("export alloc", e))?;
+            let decide = instance
+                .get_typed_func(&store, "decide")
+                .map_err(|e| plugin_error("export decide", e))?;
+            let map_uid = instance.get_typed_func(&store, "map_uid").ok();
+            let map_gid = instance.get_typed_func(&store, "map_gid").ok();
+
+            Ok(Self {
+                store: RefCell::new(store),
+                exports: Exports {
+                    memory,
+                    alloc,
+                    decide,
+                    map_uid,
+                    map_gid,
+                },
+            })
+        }
+
+        pub fn decide(
+            &self,
+            relative: &Path,
+            uid: u32,
+            gid: u32,
+            mode: u32,
+        ) -> Result<PluginDecision> {
+            let mut store = self.store.borrow_mut();
+            store
+                .set_fuel(FUEL_PER_CALL)
+                .map_err(|e| plugin_error("fuel", e))?;
+
+            let path = relative.as_os_str().as_bytes();
+            let len = i32::try_from(path.len())
+                .map_err(|_| RustUtilsError::Plugin("path too long".to_string()))?;
+            let ptr = self
+                .exports
+                .alloc
+                .call(&mut *store, len)
+                .map_err(|e| plugin_error("alloc trapped", e))?;
+            let offset = usize::try_from(ptr)
+                .map_err(|_| RustUtilsError::Plugin(format!("alloc returned {ptr}")))?;
+            self.exports
+                .memory
+                .write(&mut *store, offset, path)
+                .map_err(|e| plugin_error("writing path", e))?;
+
+            let decision = self
+                .exports
+                .decide
+                .call(&mut *store, (ptr, len, uid as i32, gid as i32, mode as i32))
+                .map_err(|e| plugin_error("decide trapped", e))?;
+            match decision {
+                0 => Ok(PluginDecision::Process),
+                1 => Ok(PluginDecision::Skip),
+                other => Err(RustUtilsError::Plugin(format!(
+                    "decide returned unknown decision {other}"
+                ))),
+            }
+        }
+
+        pub fn map_uid(&self, uid: u32) -> Result<Option<u32>> {
+            self.map(self.exports.map_uid.as_ref(), uid)
+        }
+
+        pub fn map_gid(&self, gid: u32) -> Result<Option<u32>> {
+            self.map(self.exports.map_gid.as_ref(), gid)
+        }
+
+        fn map(&self, func: Option<&TypedFunc<i32, i64>>, id: u32) -> Result<Option<u32>> {
+            let Some(func) = func else {
+                return Ok(None);
+            };
+            let mut store = self.store.borrow_mut();
+            store
+                .set_fuel(FUEL_PER_CALL)
+                .map_err(|e| plugin_error("fuel", e))?;
+            let mapped = func
+                .call(&mut *store, id as i32)
+                .map_err(|e| plugin_error("map trapped", e))?;
+            match mapped {
+                -1 => Ok(None),
+                value => u32::try_from(value).map(Some).map_err(|_| {
+                    RustUtilsError::Plugin(format!("mapped ID {value} is out of range"))
+                }),
+            }
+        }
+    }
+}
+
+#[cfg(feature = "wasm-plugins")]
+pub use wasm::WasmPlugin;
+
+/// Placeholder used when the crate is built without WebAssembly support.
+#[cfg(not(feature = "wasm-plugins"))]
+pub struct WasmPlugin {
+    _private: (),
+}
+
+#[cfg(not(feature = "wasm-plugins"))]
+impl WasmPlugin {
+    pub fn from_bytes(_bytes: &[u8]) -> Result<Self> {
+        Err(RustUtilsError::Plugin(
+            "rust-utils was built without the wasm-plugins feature".to_string(),
+        ))
+    }
+
+    pub fn decide(
+        &self,
+        _relative: &Path,
+        _uid: u32,
+        _gid: u32,
+        _mode: u32,
+    ) -> Result<PluginDecision> {
+        Ok(PluginDecision::Process)
+    }
+
+    pub fn map_uid(&self, _uid: u32) -> Result<Option<u32>> {
+        Ok(None)
+    }
+
+    pub fn map_gid(&self, _gid: u32) -> Result<Option<u32>> {
+        Ok(None)
+    }
+}
+
+impl WasmPlugin {
+    /// Load a plugin from a `.wasm` (or `.wat`) file.
+    pub fn load(path: &Path) -> Result<Self> {
+        let bytes = std::fs::read(path)?;
+        Self::from_bytes(&bytes)
+    }
+}
+
+#[cfg(all(test, feature = "wasm-plugins"))]
+mod tests {
+    use super::*;
+    use crate::error::RustUtilsError;
+
+    /// Skips paths starting with "tmp" and maps UID 100033 to 500033.
+    const POLICY: &str = r#"
+        (module
+          (memory (export "memory") 1)
+          (func (export "alloc") (param i32) (result i32) (i32.const 1024))
+          (func (export "decide") (param $ptr i32) (param $len i32) (param i32 i32 i32) (result i32)
+            (if (result i32) (i32.lt_u (local.get $len) (i32.const 3))
+              (then (i32.const 0))
+              (else
+                (i32.and
+                  (i32.and
+                    (i32.eq (i32.load8_u (local.get $ptr)) (i32.const 116))
+                    (i32.eq (i32.load8_u offset=1 (local.get $ptr)) (i32.const 109)))
+                  (i32.eq (i32.load8_u offset=2 (local.get $ptr)) (i32.const 112))))))
+          (func (export "map_uid") (param i32) (result i64)
+            (if (result i64) (i32.eq (local.get 0) (i32.const 100033))
+              (then (i64.const 500033))
+              (else (i64.const -1)))))
+    "#;
+
+    #[test]
+    fn test_plugin_decide() {
+        let plugin = WasmPlugin::from_bytes(POLICY.as_bytes()).unwrap();
+        assert_eq!(
+            plugin.decide(Path::new("tmp/cache"), 0, 0, 0o644).unwrap(),
+            PluginDecision::Skip
+        );
+        assert_eq!(
+            plugin.decide(Path::new("etc/passwd"), 0, 0, 0o644).unwrap(),
+            PluginDecision::Process
+        );
+        assert_eq!(
+            plugin.decide(Path::new(""), 0, 0, 0o755).unwrap(),
+            PluginDecision::Process
+        );
+    }
+
+    #[test]
+    fn test_plugin_map() {
+        let plugin = WasmPlugin::from_bytes(POLICY.as_bytes()).unwrap();
+        assert_eq!(plugin.map_uid(100033).unwrap(), Some(500033));
+        assert_eq!(plugin.map_uid(100034).unwrap(), None);
+        // No map_gid export
+        assert_eq!(plugin.map_gid(100033).unwrap(), None);
+    }
+
+    #[test]
+    fn test_plugin_runaway_is_stopped() {
+        let looping = r#"
+            (module
+              (memory (export "memory") 1)
+              (func (export "alloc") (param i32) (result i32) (i32.const 0))
+              (func (export "decide") (param i32 i32 i32 i32 i32) (result i32)
+                (loop $forever (br $forever))
+                (i32.const 0)))
+        "#;
+        let plugin = WasmPlugin::from_bytes(looping.as_bytes()).unwrap();
+        let result = plugin.decide(Path::new("a"), 0, 0, 0);
+        assert!(matches!(result, Err(RustUtilsError::Plugin(_))));
+    }
+
+    #[test]
+    fn test_plugin_missing_exports() {
+        let result = WasmPlugin::from_bytes(b"(module)");
+        assert!(matches!(result, Err(RustUtilsError::Plugin(_))));
+    }
+}
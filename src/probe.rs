@@ -0,0 +1,130 @@
+//! Permission probes for `remap --dry-run --probe`, predicting chown failures before the real
+//! run hits them.
+//!
+//! A probe changes the owner of one entry to its new IDs and straight back, which exercises
+//! everything that can refuse the real change: missing capabilities, read-only mounts,
+//! root-squashing NFS servers and IDs unmapped in the current user namespace. Entries whose
+//! metadata a chown would alter beyond the owner are never probed.
+
+use std::fs::{self, Metadata};
+use std::io;
+use std::os::unix::fs::{lchown, MetadataExt};
+use std::path::{Path, PathBuf};
+
+use nix::errno::Errno;
+
+use crate::error::{Result, RustUtilsError};
+
+/// Bit of `CAP_CHOWN` in the capability sets.
+const CAP_CHOWN: u32 = 0;
+
+const SETID_BITS: u32 = 0o6000;
+
+/// Outcome of probing one filesystem.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Probe {
+    /// Entry whose owner was changed and restored
+    pub path: PathBuf,
+    /// Error the chown failed with, if any
+    pub error: Option<Errno>,
+}
+
+/// Whether the process holds `CAP_CHOWN` in its effective set.
+pub fn has_cap_chown() -> io::Result<bool> {
+    let status = fs::read_to_string("/proc/self/status")?;
+    let effective = status
+        .lines()
+        .find_map(|line| line.strip_prefix("CapEff:"))
+        .and_then(|value| u64::from_str_radix(value.trim(), 16).ok())
+        .ok_or_else(|| io::Error::other("no CapEff line in /proc/self/status"))?;
+    Ok(effective & (1 << CAP_CHOWN) != 0)
+}
+
+/// Whether changing the owner of `path` and back leaves it exactly as it was.
+///
+/// chown clears the setuid and setgid bits and file capabilities of non-directories, so
+/// entries carrying any of them are skipped.
+pub fn is_probeable(path: &Path, metadata: &Metadata) -> bool {
+    if metadata.is_dir() {
+        return true;
+    }
+    metadata.mode() & SETID_BITS == 0
+        && !matches!(xattr::get(path, "security.capability"), Ok(Some(_)))
+}
+
+/// Change the owner of `path` to `uid`:`gid` and restore it from `metadata`.
+///
+/// # Errors
+///
+/// A refused chown is reported in the returned [`Probe`]. An error is only returned if the
+/// original owner could not be restored, which needs attention before anything else.
+pub fn probe_chown(path: &Path, metadata: &Metadata, uid: u32, gid: u32) -> Result<Probe> {
+    let error = match lchown(path, Some(uid), Some(gid)) {
+        Ok(()) => None,
+        Err(e) => Some(Errno::from_i32(e.raw_os_error().unwrap_or(0))),
+    };
+    if error.is_none() {
+        lchown(path, Some(metadata.uid()), Some(metadata.gid())).map_err(|e| {
+            RustUtilsError::Probe(format!(
+                "could not restore owner {}:{} of {}: {}",
+                metadata.uid(),
+                metadata.gid(),
+                path.display(),
+                e
+            ))
+        })?;
+    }
+    Ok(Probe {
+        path: path.to_path_buf(),
+        error,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::os::unix::fs::PermissionsExt;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_is_probeable() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let dir = TempDir::new()?;
+        let plain = dir.path().join("plain");
+        File::create(&plain)?;
+        assert!(is_probeable(&plain, &fs::symlink_metadata(&plain)?));
+
+        let setuid = dir.path().join("setuid");
+        File::create(&setuid)?;
+        fs::set_permissions(&setuid, fs::Permissions::from_mode(0o4755))?;
+        assert!(!is_probeable(&setuid, &fs::symlink_metadata(&setuid)?));
+
+        // Directories keep their setgid bit across chown
+        fs::set_permissions(dir.path(), fs::Permissions::from_mode(0o2755))?;
+        assert!(is_probeable(dir.path(), &fs::symlink_metadata(dir.path())?));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_probe_chown_restores_owner() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("file");
+        File::create(&path)?;
+        let before = fs::symlink_metadata(&path)?;
+
+        let probe = probe_chown(&path, &before, 100_000, 100_000)?;
+        let after = fs::symlink_metadata(&path)?;
+        assert_eq!((after.uid(), after.gid()), (before.uid(), before.gid()));
+        assert_eq!(after.mode(), before.mode());
+
+        // Only a process with CAP_CHOWN may give files away
+        if has_cap_chown()? {
+            assert_eq!(probe.error, None);
+        } else {
+            assert_eq!(probe.error, Some(Errno::EPERM));
+        }
+
+        Ok(())
+    }
+}
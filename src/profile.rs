@@ -0,0 +1,208 @@
+//! Remap profiles: remaps an administrator has set up in advance, which users without root
+//! can run by name through pkexec.
+//!
+//! Each profile is a JSON file in [`PROFILE_DIR`], owned by root, fixing the tree and the
+//! ranges of one remap. `remap profile NAME` run by anyone but root re-executes itself
+//! through `pkexec`, which has polkit authenticate the user. Under pkexec only
+//! `remap profile` is accepted, and no option naming a file, so all the user chooses is a
+//! profile and whether it is a dry run.
+
+use std::fs::{File, Metadata, OpenOptions};
+use std::io;
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use serde::Deserialize;
+
+use crate::commands::remap::RemapArgs;
+use crate::error::{Result, RustUtilsError};
+
+/// Directory holding the profiles.
+pub const PROFILE_DIR: &str = "/etc/rust-utils/profiles";
+
+/// One predefined remap, read from `NAME.json` in the profile directory.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Profile {
+    pub base_directory: PathBuf,
+    pub from_base: u32,
+    pub to_base: u32,
+    #[serde(default = "default_range_size")]
+    pub range_size: u32,
+    #[serde(default)]
+    pub uid_only: bool,
+    #[serde(default)]
+    pub gid_only: bool,
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    #[serde(default)]
+    pub exclude_regex: Vec<String>,
+    #[serde(default)]
+    pub include: Vec<String>,
+    #[serde(default)]
+    pub one_file_system: bool,
+}
+
+fn default_range_size() -> u32 {
+    RemapArgs::default().range_size
+}
+
+impl Profile {
+    /// Read the profile `name` from `dir`, which like the profile itself must be owned by
+    /// root and writable by no one else.
+    pub fn load(dir: &Path, name: &str) -> Result<Self> {
+        check_name(name)?;
+        check_trusted(dir, &dir.metadata()?)?;
+        let path = dir.join(format!("{name}.json"));
+        let file = open(&path).map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => RustUtilsError::InvalidArguments(format!(
+                "no profile '{name}' in {}",
+                dir.display()
+            )),
+            _ => e.into(),
+        })?;
+        check_trusted(&path, &file.metadata()?)?;
+        let profile: Self = serde_json::from_reader(file).map_err(|e| {
+            RustUtilsError::InvalidArguments(format!("invalid profile {}: {e}", path.display()))
+        })?;
+        if profile.uid_only && profile.gid_only {
+            return Err(RustUtilsError::InvalidArguments(format!(
+                "profile {} sets both uid-only and gid-only",
+                path.display()
+            )));
+        }
+        Ok(profile)
+    }
+
+    /// The arguments of the remap the profile describes.
+    pub fn remap_args(&self) -> RemapArgs {
+        RemapArgs {
+            base_directory: self.base_directory.clone(),
+            from_base: self.from_base,
+            to_base: self.to_base,
+            range_size: self.range_size,
+            uid_only: self.uid_only,
+            gid_only: self.gid_only,
+            exclude: self.exclude.clone(),
+            exclude_regex: self.exclude_regex.clone(),
+            include: self.include.clone(),
+            one_file_system: self.one_file_system,
+            ..Default::default()
+        }
+    }
+}
+
+/// Open `path` without following a symlink, which could point anywhere.
+fn open(path: &Path) -> io::Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .custom_flags(nix::fcntl::OFlag::O_NOFOLLOW.bits())
+        .open(path)
+}
+
+/// Profile names are plain file names: letters, digits, `-` and `_`, not starting with `-`.
+fn check_name(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && !name.starts_with('-')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !valid {
+        return Err(RustUtilsError::InvalidArguments(format!(
+            "invalid profile name '{name}'"
+        )));
+    }
+    Ok(())
+}
+
+fn check_trusted(path: &Path, metadata: &Metadata) -> Result<()> {
+    if metadata.uid() != 0 || metadata.mode() & 0o022 != 0 {
+        return Err(RustUtilsError::Permission(format!(
+            "{} must be owned by root and writable by no one else",
+            path.display()
+        )));
+    }
+    Ok(())
+}
+
+/// The UID of the user who ran pkexec, when running under it.
+pub fn pkexec_uid() -> Option<u32> {
+    std::env::var("PKEXEC_UID").ok()?.parse().ok()
+}
+
+/// The command line running profile `name` as root through pkexec, by the same `exe`.
+pub fn pkexec_command(exe: &Path, name: &str, dry_run: bool, verbose: bool) -> Command {
+    let mut command = Command::new("pkexec");
+    command.arg(exe).args(["remap", "profile", name]);
+    if dry_run {
+        command.arg("--dry-run");
+    }
+    if verbose {
+        command.arg("--verbose");
+    }
+    command
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{self, Permissions};
+    use std::os::unix::fs::PermissionsExt;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_load_profile() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("web.json");
+        fs::write(
+            &path,
+            r#"{"base-directory": "/var/lib/lxc/web/rootfs", "from-base": 0, "to-base": 100000,
+                "exclude": ["tmp"]}"#,
+        )?;
+
+        let profile = Profile::load(dir.path(), "web")?;
+        let args = profile.remap_args();
+        assert_eq!(args.base_directory, Path::new("/var/lib/lxc/web/rootfs"));
+        assert_eq!(args.to_base, 100000);
+        assert_eq!(args.range_size, 65536);
+        assert_eq!(args.exclude, ["tmp"]);
+
+        // Only plain names, and only files no one but root can change
+        assert!(Profile::load(dir.path(), "../web").is_err());
+        assert!(Profile::load(dir.path(), "missing")
+            .unwrap_err()
+            .to_string()
+            .contains("no profile 'missing'"));
+        fs::set_permissions(&path, Permissions::from_mode(0o666))?;
+        assert!(matches!(
+            Profile::load(dir.path(), "web"),
+            Err(RustUtilsError::Permission(_))
+        ));
+        fs::set_permissions(&path, Permissions::from_mode(0o644))?;
+        fs::write(
+            dir.path().join("bad.json"),
+            r#"{"base-directory": "/", "jobs": 8}"#,
+        )?;
+        assert!(Profile::load(dir.path(), "bad").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pkexec_command() {
+        let command = pkexec_command(Path::new("/usr/bin/rust-utils"), "web", true, false);
+        assert_eq!(command.get_program(), "pkexec");
+        let args: Vec<_> = command.get_args().collect();
+        assert_eq!(
+            args,
+            [
+                "/usr/bin/rust-utils",
+                "remap",
+                "profile",
+                "web",
+                "--dry-run"
+            ]
+        );
+    }
+}
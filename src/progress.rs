@@ -0,0 +1,426 @@
+//! Machine-readable progress for long-running commands, written as newline-delimited JSON
+//! to a file descriptor chosen with `--progress-fd`.
+//!
+//! Every line is one event object: `start` when the command begins, `progress` at most once
+//! per [`INTERVAL`] while it runs, and `done` when it completes. All events carry the same
+//! counters, so a consumer can treat every mode alike.
+//!
+//! `--status-file` keeps the latest event in a file instead, replaced atomically, for
+//! checking on a run from another terminal.
+
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::os::fd::RawFd;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use clap::Args;
+use schemars::schema::RootSchema;
+use schemars::{schema_for, JsonSchema};
+use serde::Serialize;
+use tracing::warn;
+
+use crate::error::{Result, RustUtilsError};
+
+/// Minimum time between two `progress` events.
+pub const INTERVAL: Duration = Duration::from_secs(1);
+
+/// Version of the event format carried in every event. Events without a
+/// `format_version` field are version 1.
+pub const EVENT_FORMAT_VERSION: u32 = 2;
+
+#[derive(Args, Clone, Debug, Default)]
+pub struct ProgressArgs {
+    /// Write progress as newline-delimited JSON to this open file descriptor
+    /// (e.g. `--progress-fd 3 3>progress.ndjson`)
+    #[arg(long, value_name = "FD")]
+    pub progress_fd: Option<RawFd>,
+
+    /// Keep this file replaced with the latest progress event, to check on the run from
+    /// elsewhere
+    #[arg(long, value_name = "FILE")]
+    pub status_file: Option<PathBuf>,
+}
+
+/// Counters carried by every progress event.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, JsonSchema)]
+pub struct Counters {
+    /// Entries (files, directories, archive members) handled so far
+    pub entries: u64,
+    /// Entries whose ownership changed
+    pub changed: u64,
+    /// File data bytes handled so far
+    pub bytes: u64,
+}
+
+/// `start`, then `progress` at most once per interval, then `done` on success.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+enum EventKind {
+    Start,
+    Progress,
+    Done,
+}
+
+/// One line of progress output.
+#[derive(Serialize, JsonSchema)]
+#[schemars(rename = "ProgressEvent")]
+struct Event<'a> {
+    /// Version of the event format, see [`EVENT_FORMAT_VERSION`]
+    format_version: u32,
+    event: EventKind,
+    /// Command reporting progress, e.g. `remap` or `archive-remap`
+    command: &'a str,
+    /// Milliseconds since the command started
+    elapsed_ms: u64,
+    #[serde(flatten)]
+    counters: Counters,
+    /// Last entry handled (`progress` events only)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    current: Option<String>,
+}
+
+/// JSON Schema of the events written to `--progress-fd`.
+pub fn event_schema() -> RootSchema {
+    schema_for!(Event<'static>)
+}
+
+/// Progress reporter for one command run; does nothing unless `--progress-fd` or
+/// `--status-file` was given.
+pub struct Progress {
+    output: Option<File>,
+    status: Option<PathBuf>,
+    command: &'static str,
+    started: Instant,
+    last: Instant,
+}
+
+impl Progress {
+    /// Start reporting for `command`, emitting the `start` event.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`RustUtilsError::InvalidArguments`] if the descriptor is not open.
+    pub fn open(args: &ProgressArgs, command: &'static str) -> Result<Self> {
+        let output = match args.progress_fd {
+            Some(fd) => Some(duplicate(fd)?),
+            None => None,
+        };
+        let now = Instant::now();
+        let mut progress = Self {
+            output,
+            status: args.status_file.clone(),
+            command,
+            started: now,
+            last: now,
+        };
+        progress.emit(EventKind::Start, Counters::default(), None);
+        Ok(progress)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.output.is_some() || self.status.is_some()
+    }
+
+    /// Report `counters` after handling `current`, unless an event went out recently.
+    pub fn update(&mut self, current: &Path, counters: Counters) {
+        if !self.is_enabled() || self.last.elapsed() < INTERVAL {
+            return;
+        }
+        self.last = Instant::now();
+        self.emit(EventKind::Progress, counters, Some(current));
+    }
+
+    /// Emit the final `done` event.
+    pub fn finish(&mut self, counters: Counters) {
+        self.emit(EventKind::Done, counters, None);
+    }
+
+    fn emit(&mut self, event: EventKind, counters: Counters, current: Option<&Path>) {
+        if !self.is_enabled() {
+            return;
+        }
+        let event = Event {
+            format_version: EVENT_FORMAT_VERSION,
+            event,
+            command: self.command,
+            elapsed_ms: self.started.elapsed().as_millis() as u64,
+            counters,
+            current: current.map(|path| path.display().to_string()),
+        };
+        let mut line = serde_json::to_vec(&event).expect("progress events always serialize");
+        line.push(b'\n');
+        // A consumer going away must not abort the command itself
+        if let Some(output) = &mut self.output {
+            if let Err(e) = output.write_all(&line) {
+                warn!("Progress output failed, disabling it: {}", e);
+                self.output = None;
+            }
+        }
+        if let Some(path) = &self.status {
+            if let Err(e) = replace_file(path, &line) {
+                warn!(
+                    "Cannot update status file {}, disabling it: {}",
+                    path.display(),
+                    e
+                );
+                self.status = None;
+            }
+        }
+    }
+}
+
+/// Replace the file at `path` with `contents` in one rename, so readers see either the old
+/// or the new contents in full.
+fn replace_file(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut partial = OsString::from(path);
+    partial.push(format!(".{}", std::process::id()));
+    fs::write(&partial, contents)?;
+    fs::rename(&partial, path)
+}
+
+/// How often `--verbose` logs a "Processed N files" heartbeat: after a number of entries,
+/// or after some time has passed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProgressInterval {
+    Entries(u64),
+    Time(Duration),
+}
+
+impl Default for ProgressInterval {
+    fn default() -> Self {
+        Self::Time(Duration::from_secs(10))
+    }
+}
+
+impl FromStr for ProgressInterval {
+    type Err = RustUtilsError;
+
+    /// A plain number counts entries; anything else is a duration, see [`parse_duration`].
+    fn from_str(s: &str) -> Result<Self> {
+        let interval = match s.trim().parse::<u64>() {
+            Ok(entries) => Some(Self::Entries(entries)),
+            Err(_) => parse_duration(s).map(Self::Time),
+        };
+        match interval {
+            Some(Self::Entries(0)) => Err(RustUtilsError::InvalidRange(format!(
+                "progress interval '{s}' must be greater than zero"
+            ))),
+            Some(Self::Time(duration)) if duration.is_zero() => Err(RustUtilsError::InvalidRange(
+                format!("progress interval '{s}' must be greater than zero"),
+            )),
+            Some(interval) => Ok(interval),
+            None => Err(RustUtilsError::InvalidArguments(format!(
+                "invalid progress interval '{s}' (expected a number of entries or a \
+                 duration such as 500ms, 30s, 5m or 1h)"
+            ))),
+        }
+    }
+}
+
+/// Parse a number with an `ms`, `s`, `m`, `h` or `d` suffix, e.g. `30s` or `1d`.
+pub fn parse_duration(s: &str) -> Option<Duration> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit())?;
+    let (digits, unit) = s.split_at(split);
+    let value: u64 = digits.parse().ok()?;
+    let seconds = |factor: u64| value.checked_mul(factor).map(Duration::from_secs);
+    match unit {
+        "ms" => Some(Duration::from_millis(value)),
+        "s" => seconds(1),
+        "m" => seconds(60),
+        "h" => seconds(3600),
+        "d" => seconds(86400),
+        _ => None,
+    }
+}
+
+impl fmt::Display for ProgressInterval {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Entries(entries) => write!(f, "{entries}"),
+            Self::Time(duration) if duration.subsec_millis() != 0 => {
+                write!(f, "{}ms", duration.as_millis())
+            }
+            Self::Time(duration) => write!(f, "{}s", duration.as_secs()),
+        }
+    }
+}
+
+/// Decides when the next heartbeat is due.
+pub struct Heartbeat {
+    interval: ProgressInterval,
+    last: Instant,
+}
+
+impl Heartbeat {
+    pub fn new(interval: ProgressInterval) -> Self {
+        Self {
+            interval,
+            last: Instant::now(),
+        }
+    }
+
+    /// Whether to log a heartbeat now that `entries` entries have been handled.
+    pub fn is_due(&mut self, entries: u64) -> bool {
+        match self.interval {
+            ProgressInterval::Entries(every) => entries.is_multiple_of(every),
+            ProgressInterval::Time(every) => {
+                if self.last.elapsed() < every {
+                    return false;
+                }
+                self.last = Instant::now();
+                true
+            }
+        }
+    }
+}
+
+/// Open `fd` again through `/proc`, so closing it later leaves the caller's descriptor
+/// alone. Writes append, as the caller's may be to a file already written to.
+fn duplicate(fd: RawFd) -> Result<File> {
+    if fd < 0 {
+        return Err(RustUtilsError::InvalidArguments(format!(
+            "invalid progress descriptor {fd}"
+        )));
+    }
+    fs::OpenOptions::new()
+        .append(true)
+        .open(format!("/proc/self/fd/{fd}"))
+        .map_err(|e| {
+            RustUtilsError::InvalidArguments(format!("progress descriptor {fd} is not usable: {e}"))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::fd::AsRawFd;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_events_written_as_ndjson() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("progress.ndjson");
+        let file = File::create(&path)?;
+        let args = ProgressArgs {
+            progress_fd: Some(file.as_raw_fd()),
+            ..Default::default()
+        };
+
+        let mut progress = Progress::open(&args, "copy")?;
+        // Rate limited: nothing is written until an interval has passed
+        progress.update(Path::new("etc/hosts"), Counters::default());
+        progress.last -= INTERVAL;
+        let counters = Counters {
+            entries: 3,
+            changed: 2,
+            bytes: 42,
+        };
+        progress.update(Path::new("etc/passwd"), counters);
+        progress.finish(counters);
+        drop(progress);
+
+        let events: Vec<serde_json::Value> = fs::read_to_string(&path)?
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<std::result::Result<_, _>>()?;
+        let kinds: Vec<&str> = events
+            .iter()
+            .map(|e| e["event"].as_str().unwrap())
+            .collect();
+        assert_eq!(kinds, ["start", "progress", "done"]);
+        assert_eq!(events[0]["format_version"], EVENT_FORMAT_VERSION);
+        assert_eq!(events[1]["command"], "copy");
+        assert_eq!(events[1]["current"], "etc/passwd");
+        assert_eq!(events[1]["entries"], 3);
+        assert_eq!(events[2]["bytes"], 42);
+        assert!(events[2].get("current").is_none());
+
+        // The caller's descriptor stays open
+        assert!(fs::metadata(format!("/proc/self/fd/{}", file.as_raw_fd())).is_ok());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_status_file() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("status.json");
+        let args = ProgressArgs {
+            status_file: Some(path.clone()),
+            ..Default::default()
+        };
+        let status = || -> std::result::Result<serde_json::Value, Box<dyn std::error::Error>> {
+            Ok(serde_json::from_slice(&fs::read(&path)?)?)
+        };
+
+        let mut progress = Progress::open(&args, "remap")?;
+        assert!(progress.is_enabled());
+        assert_eq!(status()?["event"], "start");
+        progress.last -= INTERVAL;
+        let counters = Counters {
+            entries: 5,
+            ..Default::default()
+        };
+        progress.update(Path::new("etc/passwd"), counters);
+        assert_eq!(status()?["current"], "etc/passwd");
+        assert_eq!(status()?["entries"], 5);
+        progress.finish(counters);
+        assert_eq!(status()?["event"], "done");
+        // Only the status file itself is left
+        assert_eq!(fs::read_dir(dir.path())?.count(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_closed_descriptor_rejected() {
+        let args = ProgressArgs {
+            progress_fd: Some(987_654),
+            ..Default::default()
+        };
+        assert!(matches!(
+            Progress::open(&args, "remap"),
+            Err(RustUtilsError::InvalidArguments(_))
+        ));
+    }
+
+    #[test]
+    fn test_progress_interval_parsing() {
+        assert_eq!(
+            "500".parse::<ProgressInterval>().unwrap(),
+            ProgressInterval::Entries(500)
+        );
+        assert_eq!(
+            "250ms".parse::<ProgressInterval>().unwrap(),
+            ProgressInterval::Time(Duration::from_millis(250))
+        );
+        assert_eq!(
+            "5m".parse::<ProgressInterval>().unwrap(),
+            ProgressInterval::Time(Duration::from_secs(300))
+        );
+        assert_eq!(ProgressInterval::default().to_string(), "10s");
+        assert!("0".parse::<ProgressInterval>().is_err());
+        assert!("0s".parse::<ProgressInterval>().is_err());
+        assert!("10w".parse::<ProgressInterval>().is_err());
+        assert!("s".parse::<ProgressInterval>().is_err());
+    }
+
+    #[test]
+    fn test_heartbeat() {
+        let mut heartbeat = Heartbeat::new(ProgressInterval::Entries(3));
+        let due: Vec<bool> = (1..=6).map(|entries| heartbeat.is_due(entries)).collect();
+        assert_eq!(due, [false, false, true, false, false, true]);
+
+        let mut heartbeat = Heartbeat::new(ProgressInterval::Time(Duration::from_secs(60)));
+        assert!(!heartbeat.is_due(1));
+        heartbeat.last -= Duration::from_secs(60);
+        assert!(heartbeat.is_due(2));
+        assert!(!heartbeat.is_due(3));
+    }
+}
@@ -0,0 +1,659 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+use clap::ValueEnum;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Result, RustUtilsError};
+use crate::fs::EntryType;
+use crate::idmap::{IdKind, IdMap};
+use crate::mounts::FilesystemSummary;
+
+/// ID reported by the kernel for host IDs that have no mapping inside a
+/// user namespace (`/proc/sys/kernel/overflowuid`).
+pub const OVERFLOW_ID: u32 = 65534;
+
+/// Version of the [`RunReport`] format written by this build. Reports without a
+/// `format_version` field are version 1; [`RunReport::read`] accepts every version up to
+/// this one.
+pub const REPORT_FORMAT_VERSION: u32 = 2;
+
+/// Perspective used when presenting UIDs/GIDs in reports.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum View {
+    /// Raw IDs as stored on the host filesystem
+    #[default]
+    Host,
+    /// IDs as seen from inside the container (inverse idmap applied)
+    Container,
+}
+
+/// Shows the host IDs of one kind a remap reports in a [`View`].
+///
+/// In container view, IDs of the container's range are shifted down to their in-container
+/// value, IDs the remap maps entries to are shown as the in-container ID they were mapped
+/// from, overrides included, and anything else is shown as [`OVERFLOW_ID`], matching what
+/// `stat` reports from within the namespace.
+#[derive(Clone, Debug, Default)]
+pub struct IdView {
+    view: View,
+    /// First host ID of the container's range, seen inside it as 0
+    base: u32,
+    count: u32,
+    /// The remap's map turned around, from the IDs it gives entries to the IDs they had
+    inverse: IdMap,
+}
+
+impl IdView {
+    /// View of the IDs `map` remaps, of a container whose range is `count` IDs from `base`.
+    pub fn new(view: View, base: u32, count: u32, map: &IdMap) -> Result<Self> {
+        Ok(Self {
+            view,
+            base,
+            count,
+            inverse: map.inverse()?,
+        })
+    }
+
+    /// Show `id` as found on an entry, before or after the remap.
+    pub fn current(&self, id: u32) -> u32 {
+        match self.view {
+            View::Container if id.wrapping_sub(self.base) < self.count => id - self.base,
+            _ => self.mapped(id),
+        }
+    }
+
+    /// Show `id` as the remap gives it to an entry.
+    pub fn mapped(&self, id: u32) -> u32 {
+        match self.view {
+            View::Host => id,
+            View::Container => {
+                let source = self.inverse.get(id).unwrap_or(id);
+                if source.wrapping_sub(self.base) < self.count {
+                    source - self.base
+                } else {
+                    OVERFLOW_ID
+                }
+            }
+        }
+    }
+}
+
+/// The [`IdView`]s of a remap's UIDs and GIDs.
+#[derive(Clone, Debug, Default)]
+pub struct OwnerView {
+    pub uid: IdView,
+    pub gid: IdView,
+}
+
+impl OwnerView {
+    /// `UID:GID` of an owner found on an entry, before or after the remap.
+    pub fn current(&self, (uid, gid): (u32, u32)) -> String {
+        format!("{}:{}", self.uid.current(uid), self.gid.current(gid))
+    }
+
+    /// `UID:GID` of an owner the remap gives an entry.
+    pub fn mapped(&self, (uid, gid): (u32, u32)) -> String {
+        format!("{}:{}", self.uid.mapped(uid), self.gid.mapped(gid))
+    }
+}
+
+/// How the summary at the end of a command is printed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OutputFormat {
+    /// The `RESULT` line
+    #[default]
+    Text,
+    /// The run report as one line of JSON
+    Json,
+}
+
+/// Outcome of one command run, in the same shape for every subcommand so tooling can
+/// parse a single schema whatever ran.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct RunReport {
+    /// Version of the report format, see [`REPORT_FORMAT_VERSION`]
+    #[serde(default = "first_version")]
+    pub format_version: u32,
+    /// Subcommand that ran, e.g. `remap` or `archive-remap`
+    pub command: String,
+    /// Random identifier of the run, also printed on its `RESULT` line
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub run_id: Option<String>,
+    /// Whether the command completed without a fatal error
+    pub success: bool,
+    /// Signal that stopped the run before it finished, e.g. `SIGINT`; the counts cover
+    /// what was done until then
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interrupted: Option<String>,
+    /// Named counters such as `entries` or `remapped`
+    pub counts: BTreeMap<String, u64>,
+    /// Milliseconds spent in named phases; `total` covers the whole run
+    pub durations_ms: BTreeMap<String, u64>,
+    /// Problems hit during the run, fatal or not
+    pub errors: Vec<ReportError>,
+    /// Files and trees the run produced
+    pub artifacts: Vec<Artifact>,
+    /// Statistics per filesystem met while walking a tree
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub filesystems: Vec<FilesystemSummary>,
+    /// ID ranges the run mapped
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ranges: Vec<ReportRange>,
+    /// Statistics per type of entry met while walking a tree
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub entry_types: Vec<EntryTypeSummary>,
+    /// Digest of the ownership changes made, or planned by a dry run
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub plan_hash: Option<String>,
+}
+
+/// An error recorded in a [`RunReport`].
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct ReportError {
+    /// Entry the error concerns, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    pub message: String,
+}
+
+/// Something a run produced, such as an archive or a copied tree.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct Artifact {
+    /// What was produced: `archive`, `tree`, `stream`, ...
+    pub kind: String,
+    /// Path or URL of the artifact (`-` for stdout)
+    pub path: String,
+}
+
+/// An ID range mapped by a run, as recorded in a [`RunReport`].
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct ReportRange {
+    /// `uid` or `gid`
+    pub kind: String,
+    /// First ID of the source range
+    pub from: u32,
+    /// First ID the source range maps to
+    pub to: u32,
+    pub count: u32,
+}
+
+/// Statistics of one type of entry in a [`RunReport`].
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct EntryTypeSummary {
+    /// `file`, `directory`, `symlink`, `socket`, `fifo`, `char-device` or `block-device`
+    #[serde(rename = "type")]
+    pub kind: String,
+    /// Whether programs recreate entries of this type, usually when the container starts,
+    /// so that owners left on them do not last: true for sockets and FIFOs
+    pub ephemeral: bool,
+    pub entries: u64,
+    /// Entries whose ownership changed (or would change in a dry run)
+    pub changed: u64,
+    /// Entries left with the owner they had
+    pub skipped: u64,
+    /// Entries that could not be processed
+    pub failed: u64,
+}
+
+/// Counts entries per type during a walk.
+#[derive(Default)]
+pub struct EntryTypeStats {
+    types: BTreeMap<EntryType, EntryTypeSummary>,
+}
+
+impl EntryTypeStats {
+    fn tally(&mut self, kind: EntryType) -> &mut EntryTypeSummary {
+        self.types.entry(kind).or_insert_with(|| EntryTypeSummary {
+            kind: kind.to_string(),
+            ephemeral: kind.is_ephemeral(),
+            entries: 0,
+            changed: 0,
+            skipped: 0,
+            failed: 0,
+        })
+    }
+
+    pub fn entry(&mut self, kind: EntryType) {
+        self.tally(kind).entries += 1;
+    }
+
+    pub fn changed(&mut self, kind: EntryType) {
+        self.tally(kind).changed += 1;
+    }
+
+    pub fn skipped(&mut self, kind: EntryType) {
+        self.tally(kind).skipped += 1;
+    }
+
+    pub fn failed(&mut self, kind: EntryType) {
+        self.tally(kind).failed += 1;
+    }
+
+    /// Per-type summaries, persistent types first.
+    pub fn summarize(&self) -> Vec<EntryTypeSummary> {
+        let mut summaries: Vec<_> = self.types.values().cloned().collect();
+        summaries.sort_by_key(|summary| summary.ephemeral);
+        summaries
+    }
+}
+
+impl RunReport {
+    /// An empty, successful report for `command`.
+    pub fn new(command: &str) -> Self {
+        Self {
+            format_version: REPORT_FORMAT_VERSION,
+            command: command.to_string(),
+            success: true,
+            ..Self::default()
+        }
+    }
+
+    /// A report for a run of `command` that failed with `error` before producing its own.
+    pub fn failed(command: &str, error: impl fmt::Display) -> Self {
+        let mut report = Self::new(command);
+        report.success = false;
+        report.error(None, error);
+        report
+    }
+
+    pub fn count(&mut self, name: &str, value: u64) -> &mut Self {
+        self.counts.insert(name.to_string(), value);
+        self
+    }
+
+    pub fn duration(&mut self, name: &str, elapsed: Duration) -> &mut Self {
+        self.durations_ms
+            .insert(name.to_string(), elapsed.as_millis() as u64);
+        self
+    }
+
+    /// Record a problem, optionally tied to the entry at `path`.
+    pub fn error(&mut self, path: Option<&Path>, message: impl fmt::Display) -> &mut Self {
+        self.errors.push(ReportError {
+            path: path.map(|path| path.display().to_string()),
+            message: message.to_string(),
+        });
+        self
+    }
+
+    pub fn artifact(&mut self, kind: &str, path: &Path) -> &mut Self {
+        self.artifacts.push(Artifact {
+            kind: kind.to_string(),
+            path: path.display().to_string(),
+        });
+        self
+    }
+
+    /// Record an ID range the run maps, once however often it is recorded.
+    pub fn range(&mut self, kind: IdKind, from: u32, to: u32, count: u32) -> &mut Self {
+        let range = ReportRange {
+            kind: kind.to_string(),
+            from,
+            to,
+            count,
+        };
+        if !self.ranges.contains(&range) {
+            self.ranges.push(range);
+        }
+        self
+    }
+
+    /// One-line summary printed at the end of every run, e.g.
+    /// `RESULT status=ok changed=123 failed=0 duration=42.017s run=<uuid>`.
+    ///
+    /// `changed` is the `remapped` count and `failed` the number of recorded errors. Runs
+    /// with a plan hash end the line with `plan=sha256:HEX`.
+    pub fn result_line(&self) -> String {
+        let total_ms = self.durations_ms.get("total").copied().unwrap_or(0);
+        let mut line = format!(
+            "RESULT status={} changed={} failed={} duration={}.{:03}s run={}",
+            match (self.success, &self.interrupted) {
+                (_, Some(_)) => "interrupted",
+                (true, None) => "ok",
+                (false, None) => "failed",
+            },
+            self.counts.get("remapped").copied().unwrap_or(0),
+            self.errors.len(),
+            total_ms / 1000,
+            total_ms % 1000,
+            self.run_id.as_deref().unwrap_or("-")
+        );
+        if let Some(plan_hash) = &self.plan_hash {
+            line.push_str(" plan=");
+            line.push_str(plan_hash);
+        }
+        line
+    }
+
+    /// Combine the reports of jobs that together made up one run, such as the partitions of
+    /// a remap: counts and per-filesystem statistics are added up, durations are those of
+    /// the longest job, and errors and artifacts are collected from all of them.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`RustUtilsError::InvalidArguments`] if there are no reports or they come
+    /// from different commands.
+    pub fn merge(reports: &[RunReport]) -> Result<Self> {
+        let Some(first) = reports.first() else {
+            return Err(RustUtilsError::InvalidArguments(
+                "no reports to merge".to_string(),
+            ));
+        };
+        let mut merged = Self::new(&first.command);
+        let mut filesystems: BTreeMap<String, FilesystemSummary> = BTreeMap::new();
+        let mut entry_types: Vec<EntryTypeSummary> = Vec::new();
+        for report in reports {
+            if report.command != merged.command {
+                return Err(RustUtilsError::InvalidArguments(format!(
+                    "cannot merge a {} report into {} reports",
+                    report.command, merged.command
+                )));
+            }
+            merged.success &= report.success;
+            if merged.interrupted.is_none() {
+                merged.interrupted.clone_from(&report.interrupted);
+            }
+            for (name, value) in &report.counts {
+                *merged.counts.entry(name.clone()).or_default() += value;
+            }
+            for (name, &value) in &report.durations_ms {
+                let longest = merged.durations_ms.entry(name.clone()).or_default();
+                *longest = (*longest).max(value);
+            }
+            merged.errors.extend(report.errors.iter().cloned());
+            merged.artifacts.extend(report.artifacts.iter().cloned());
+            for range in &report.ranges {
+                if !merged.ranges.contains(range) {
+                    merged.ranges.push(range.clone());
+                }
+            }
+            for fs in &report.filesystems {
+                let total =
+                    filesystems
+                        .entry(fs.device.clone())
+                        .or_insert_with(|| FilesystemSummary {
+                            entries: 0,
+                            changed: 0,
+                            failed: 0,
+                            ..fs.clone()
+                        });
+                total.entries += fs.entries;
+                total.changed += fs.changed;
+                total.failed += fs.failed;
+            }
+            for summary in &report.entry_types {
+                match entry_types
+                    .iter_mut()
+                    .find(|total| total.kind == summary.kind)
+                {
+                    Some(total) => {
+                        total.entries += summary.entries;
+                        total.changed += summary.changed;
+                        total.skipped += summary.skipped;
+                        total.failed += summary.failed;
+                    }
+                    None => entry_types.push(summary.clone()),
+                }
+            }
+        }
+        merged.filesystems = filesystems.into_values().collect();
+        merged.entry_types = entry_types;
+        Ok(merged)
+    }
+
+    /// The report as one line of JSON, for `--output-format json`.
+    pub fn json_line(&self) -> String {
+        serde_json::to_string(self).expect("reports always serialize")
+    }
+
+    /// Write the report as pretty-printed JSON to `path`.
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let mut json = serde_json::to_string_pretty(self).expect("reports always serialize");
+        json.push('\n');
+        fs::write(path, json)?;
+        Ok(())
+    }
+
+    /// Read a report written by this or an earlier version, upgrading it to the current
+    /// format.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`RustUtilsError::UnsupportedFormat`] for reports that do not parse or were
+    /// written by a newer version.
+    pub fn read(path: &Path) -> Result<Self> {
+        let invalid = |detail: String| {
+            RustUtilsError::UnsupportedFormat(format!("{}: {}", path.display(), detail))
+        };
+        let mut report: Self =
+            serde_json::from_slice(&fs::read(path)?).map_err(|e| invalid(e.to_string()))?;
+        if report.format_version > REPORT_FORMAT_VERSION {
+            return Err(invalid(format!(
+                "report format version {} is newer than the supported version {}",
+                report.format_version, REPORT_FORMAT_VERSION
+            )));
+        }
+        // Version 1 differs only in lacking the version field
+        report.format_version = REPORT_FORMAT_VERSION;
+        Ok(report)
+    }
+}
+
+fn first_version() -> u32 {
+    1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::idmap::IdMapping;
+
+    #[test]
+    fn test_merge() {
+        let mut first = RunReport::new("remap");
+        first
+            .count("entries", 10)
+            .count("remapped", 7)
+            .duration("total", Duration::from_millis(900))
+            .error(Some(Path::new("/srv/a")), "Permission denied")
+            .range(IdKind::Uid, 0, 100000, 65536);
+        first.filesystems.push(FilesystemSummary {
+            device: "0:52".to_string(),
+            fstype: Some("nfs4".to_string()),
+            mountpoint: Some("/srv".to_string()),
+            entries: 10,
+            changed: 7,
+            failed: 1,
+        });
+        let mut types = EntryTypeStats::default();
+        types.entry(EntryType::Socket);
+        types.skipped(EntryType::Socket);
+        types.entry(EntryType::File);
+        types.changed(EntryType::File);
+        first.entry_types = types.summarize();
+        assert_eq!(first.entry_types[0].kind, "file");
+        assert!(first.entry_types[1].ephemeral);
+        let mut second = first.clone();
+        second.success = false;
+        second.range(IdKind::Gid, 0, 100000, 65536);
+        second.durations_ms.insert("total".to_string(), 1500);
+
+        let merged = RunReport::merge(&[first, second]).unwrap();
+        assert!(!merged.success);
+        assert_eq!(merged.counts["entries"], 20);
+        assert_eq!(merged.counts["remapped"], 14);
+        assert_eq!(merged.durations_ms["total"], 1500);
+        assert_eq!(merged.errors.len(), 2);
+        assert_eq!(merged.filesystems.len(), 1);
+        assert_eq!(merged.filesystems[0].failed, 2);
+        assert_eq!(merged.filesystems[0].fstype.as_deref(), Some("nfs4"));
+        assert_eq!(merged.ranges.len(), 2);
+        assert_eq!(merged.ranges[1].kind, "gid");
+        assert_eq!(merged.entry_types.len(), 2);
+        assert_eq!(merged.entry_types[1].skipped, 2);
+
+        assert!(RunReport::merge(&[]).is_err());
+        assert!(RunReport::merge(&[RunReport::new("remap"), RunReport::new("copy")]).is_err());
+    }
+
+    #[test]
+    fn test_result_line() {
+        let mut report = RunReport::new("remap");
+        report.run_id = Some("5f0c6b2e-8d1a-4c3e-9b7a-2e4f6d8c0a1b".to_string());
+        report
+            .count("entries", 200)
+            .count("remapped", 123)
+            .duration("total", Duration::from_millis(42017));
+        assert_eq!(
+            report.result_line(),
+            "RESULT status=ok changed=123 failed=0 duration=42.017s \
+             run=5f0c6b2e-8d1a-4c3e-9b7a-2e4f6d8c0a1b"
+        );
+        // Remap runs end it with their plan hash
+        let plan = format!("sha256:{}", "0".repeat(64));
+        report.plan_hash = Some(plan.clone());
+        assert!(report.result_line().ends_with(&format!(
+            "run=5f0c6b2e-8d1a-4c3e-9b7a-2e4f6d8c0a1b plan={plan}"
+        )));
+
+        let report = RunReport::failed("copy", "destination exists");
+        assert_eq!(
+            report.result_line(),
+            "RESULT status=failed changed=0 failed=1 duration=0.000s run=-"
+        );
+
+        let mut report = RunReport::new("remap");
+        report.count("remapped", 7).success = false;
+        report.interrupted = Some("SIGINT".to_string());
+        assert!(report
+            .result_line()
+            .starts_with("RESULT status=interrupted changed=7 failed=0"));
+    }
+
+    fn id_view(view: View) -> IdView {
+        let range = IdMapping {
+            from: 100000,
+            to: 200000,
+            count: 65536,
+        };
+        let overrides = [IdMapping {
+            from: 100033,
+            to: 900033,
+            count: 1,
+        }];
+        let map = IdMap::with_overrides(range, &overrides).unwrap();
+        IdView::new(view, 100000, 65536, &map).unwrap()
+    }
+
+    #[test]
+    fn test_id_view_host() {
+        let view = id_view(View::Host);
+        assert_eq!(view.current(100033), 100033);
+        assert_eq!(view.mapped(900033), 900033);
+        assert_eq!(view.current(0), 0);
+    }
+
+    #[test]
+    fn test_id_view_container() {
+        let view = id_view(View::Container);
+        assert_eq!(view.current(100000), 0);
+        assert_eq!(view.current(165535), 65535);
+        assert_eq!(view.mapped(200000), 0);
+        assert_eq!(view.mapped(265535), 65535);
+        // Entries already remapped show the ID they were mapped from
+        assert_eq!(view.current(200005), 5);
+    }
+
+    #[test]
+    fn test_id_view_container_overrides() {
+        let view = id_view(View::Container);
+        assert_eq!(view.mapped(900033), 33);
+        assert_eq!(view.current(900033), 33);
+        // The range's own target of 33 is taken by no one
+        assert_eq!(view.mapped(200033), OVERFLOW_ID);
+    }
+
+    #[test]
+    fn test_id_view_container_unmapped() {
+        let view = id_view(View::Container);
+        assert_eq!(view.current(0), OVERFLOW_ID);
+        assert_eq!(view.current(165536), OVERFLOW_ID);
+        assert_eq!(view.mapped(0), OVERFLOW_ID);
+    }
+
+    #[test]
+    fn test_run_report_json() {
+        let mut report = RunReport::new("copy");
+        report
+            .count("entries", 12)
+            .count("remapped", 10)
+            .duration("total", Duration::from_millis(1500))
+            .error(Some(Path::new("dev/null")), "Operation not permitted")
+            .artifact("tree", Path::new("/srv/copy"));
+
+        let json: serde_json::Value = serde_json::to_value(&report).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "format_version": REPORT_FORMAT_VERSION,
+                "command": "copy",
+                "success": true,
+                "counts": {"entries": 12, "remapped": 10},
+                "durations_ms": {"total": 1500},
+                "errors": [{"path": "dev/null", "message": "Operation not permitted"}],
+                "artifacts": [{"kind": "tree", "path": "/srv/copy"}]
+            })
+        );
+    }
+
+    #[test]
+    fn test_failed_report() {
+        let report = RunReport::failed("remap", "Directory not found: /nope");
+        assert!(!report.success);
+        assert_eq!(report.errors[0].path, None);
+        assert_eq!(report.errors[0].message, "Directory not found: /nope");
+        assert!(report.counts.is_empty());
+    }
+
+    #[test]
+    fn test_read_report_versions() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::TempDir::new()?;
+        let path = dir.path().join("report.json");
+
+        let mut report = RunReport::new("archive-remap");
+        report
+            .count("entries", 3)
+            .artifact("archive", Path::new("-"));
+        report.write(&path)?;
+        assert_eq!(RunReport::read(&path)?, report);
+
+        // Version 1 reports carried no version field
+        fs::write(
+            &path,
+            r#"{"command": "copy", "success": false, "counts": {}, "durations_ms": {"total": 7},
+                "errors": [{"message": "Directory not found: /srv"}], "artifacts": []}"#,
+        )?;
+        let report = RunReport::read(&path)?;
+        assert_eq!(report.format_version, REPORT_FORMAT_VERSION);
+        assert_eq!(report.errors[0].path, None);
+        assert_eq!(report.durations_ms["total"], 7);
+
+        fs::write(
+            &path,
+            r#"{"format_version": 99, "command": "copy", "success": true, "counts": {},
+                "durations_ms": {}, "errors": [], "artifacts": []}"#,
+        )?;
+        assert!(matches!(
+            RunReport::read(&path),
+            Err(RustUtilsError::UnsupportedFormat(message)) if message.contains("version 99")
+        ));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,140 @@
+use std::fmt;
+use std::fs::Metadata;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Component, Path, PathBuf};
+
+const S_ISUID: u32 = 0o4000;
+const S_ISGID: u32 = 0o2000;
+const S_ISVTX: u32 = 0o1000;
+const S_IWOTH: u32 = 0o0002;
+
+/// Category of potentially dangerous content found in a tree.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum FindingKind {
+    /// setuid/setgid file that will be owned by the target root ID
+    PrivilegedSetId,
+    /// World-writable directory without the sticky bit that will be owned by the target root ID
+    WorldWritableRootDir,
+    /// Character or block device outside of `dev/`
+    DeviceOutsideDev,
+}
+
+impl fmt::Display for FindingKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let description = match self {
+            FindingKind::PrivilegedSetId => "setuid/setgid file owned by root after remap",
+            FindingKind::WorldWritableRootDir => {
+                "world-writable directory owned by root after remap"
+            }
+            FindingKind::DeviceOutsideDev => "device node outside /dev",
+        };
+        f.write_str(description)
+    }
+}
+
+/// A single safety finding.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Finding {
+    pub path: PathBuf,
+    pub kind: FindingKind,
+}
+
+/// Inspect one entry given the owner it will have after remapping.
+///
+/// `relative` is the entry's path relative to the tree root and `root_uid` the host UID that
+/// acts as root for the remapped tree (the target base). Returns every finding that applies.
+pub fn inspect(
+    relative: &Path,
+    metadata: &Metadata,
+    new_uid: u32,
+    root_uid: u32,
+) -> Vec<FindingKind> {
+    let mut findings = Vec::new();
+    let mode = metadata.mode();
+    let file_type = metadata.file_type();
+
+    if file_type.is_file() && mode & (S_ISUID | S_ISGID) != 0 && new_uid == root_uid {
+        findings.push(FindingKind::PrivilegedSetId);
+    }
+
+    if file_type.is_dir() && mode & S_IWOTH != 0 && mode & S_ISVTX == 0 && new_uid == root_uid {
+        findings.push(FindingKind::WorldWritableRootDir);
+    }
+
+    if (file_type.is_char_device() || file_type.is_block_device())
+        && relative.components().next() != Some(Component::Normal("dev".as_ref()))
+    {
+        findings.push(FindingKind::DeviceOutsideDev);
+    }
+
+    findings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{self, File, Permissions};
+    use std::os::unix::fs::PermissionsExt;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_inspect_setuid_root() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = TempDir::new()?;
+        let binary = temp_dir.path().join("su");
+        File::create(&binary)?;
+        fs::set_permissions(&binary, Permissions::from_mode(0o4755))?;
+        let metadata = fs::symlink_metadata(&binary)?;
+
+        assert_eq!(
+            inspect(Path::new("bin/su"), &metadata, 100000, 100000),
+            vec![FindingKind::PrivilegedSetId]
+        );
+        assert!(inspect(Path::new("bin/su"), &metadata, 101000, 100000).is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_inspect_world_writable_dir() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = TempDir::new()?;
+        let open_dir = temp_dir.path().join("open");
+        let sticky_dir = temp_dir.path().join("tmp");
+        fs::create_dir(&open_dir)?;
+        fs::create_dir(&sticky_dir)?;
+        fs::set_permissions(&open_dir, Permissions::from_mode(0o777))?;
+        fs::set_permissions(&sticky_dir, Permissions::from_mode(0o1777))?;
+
+        let metadata = fs::symlink_metadata(&open_dir)?;
+        assert_eq!(
+            inspect(Path::new("srv/open"), &metadata, 0, 0),
+            vec![FindingKind::WorldWritableRootDir]
+        );
+
+        let metadata = fs::symlink_metadata(&sticky_dir)?;
+        assert!(inspect(Path::new("tmp"), &metadata, 0, 0).is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_inspect_device_location() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        // /dev/null is always present and is a character device
+        let metadata = fs::symlink_metadata("/dev/null")?;
+
+        assert!(inspect(Path::new("dev/null"), &metadata, 0, 100000).is_empty());
+        assert_eq!(
+            inspect(Path::new("home/user/null"), &metadata, 0, 100000),
+            vec![FindingKind::DeviceOutsideDev]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_finding_kind_display() {
+        assert_eq!(
+            FindingKind::DeviceOutsideDev.to_string(),
+            "device node outside /dev"
+        );
+    }
+}
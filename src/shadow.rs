@@ -0,0 +1,357 @@
+//! Entries hidden under mounts inside a tree, for `remap --beneath-mounts`.
+//!
+//! A bind mount or other mount on a directory of the tree hides what that directory holds
+//! on the tree's own filesystem: a walk sees the mounted content, and the entries
+//! underneath keep their old owners, to show up once the mount is gone. [`find_shadowed`]
+//! looks beneath the mounts through a detached, non-recursive clone of the tree's mount,
+//! which has nothing mounted on it and is visible to no other process. [`BeneathMounts`]
+//! gives a remap the same view to walk, bound in a private mount namespace. A
+//! [`ReadOnlyView`] binds the tree with its mounts instead, read-only, in the mount namespace
+//! of a thread of its own, for the verify pass.
+
+use std::fs::{self, DirBuilder};
+use std::io;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+
+use nix::errno::Errno;
+use nix::mount::{mount, umount2, MntFlags, MsFlags};
+use nix::sched::{unshare, CloneFlags};
+use nix::sys::statvfs::{statvfs, FsFlags};
+use nix::unistd::gettid;
+use rustix::fs::CWD;
+use rustix::mount::{open_tree, OpenTreeFlags};
+use tracing::warn;
+
+use crate::error::{Result, RustUtilsError};
+use crate::mounts;
+
+/// A mount inside the tree with entries of the tree's filesystem under it.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Shadowed {
+    pub mountpoint: PathBuf,
+    /// Entries of the directory mounted on, or 1 for a file mounted on
+    pub entries: u64,
+}
+
+/// Look beneath `mountpoints`, absolute paths of mounts below `base`, for entries of the
+/// filesystem of `base` they hide. Mounts on a path another of them already hides are left
+/// out.
+///
+/// # Errors
+///
+/// Fails like `open_tree`: with `EPERM` without `CAP_SYS_ADMIN`, or `ENOSYS` before Linux
+/// 5.2.
+pub fn find_shadowed(base: &Path, mountpoints: &[PathBuf]) -> io::Result<Vec<Shadowed>> {
+    // A detached copy of the mount alone, without the mounts inside it
+    let clone = open_tree(
+        CWD,
+        base,
+        OpenTreeFlags::OPEN_TREE_CLONE | OpenTreeFlags::OPEN_TREE_CLOEXEC,
+    )?;
+    let root = PathBuf::from(format!("/proc/self/fd/{}", clone.as_raw_fd()));
+    let mut shadowed = Vec::new();
+    for mountpoint in mountpoints {
+        let Ok(relative) = mountpoint.strip_prefix(base) else {
+            continue;
+        };
+        if relative.as_os_str().is_empty()
+            || mountpoints
+                .iter()
+                .any(|other| other != mountpoint && mountpoint.starts_with(other))
+        {
+            continue;
+        }
+        let underneath = root.join(relative);
+        let metadata = match fs::symlink_metadata(&underneath) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        let entries = if metadata.is_dir() {
+            fs::read_dir(&underneath)?.count() as u64
+        } else {
+            1
+        };
+        if entries > 0 {
+            shadowed.push(Shadowed {
+                mountpoint: mountpoint.clone(),
+                entries,
+            });
+        }
+    }
+    Ok(shadowed)
+}
+
+/// A tree and the mounts inside it as they were when bound, read-only and visible to no
+/// other process. Writes through the tree's own mounts still show, as the binds share
+/// their filesystems; the mounts made or removed inside the tree after binding do not.
+pub struct ReadOnlyView {
+    root: PathBuf,
+    /// Dropped with the view, which lets the thread holding the namespace end
+    _release: mpsc::Sender<()>,
+}
+
+impl ReadOnlyView {
+    /// Bind `base` with the mounts inside it in a private mount namespace, held by a thread
+    /// of its own, and make each bind read-only.
+    ///
+    /// # Errors
+    ///
+    /// Fails like `unshare` and `mount`: with `EPERM` without `CAP_SYS_ADMIN`.
+    pub fn open(base: &Path) -> io::Result<Self> {
+        let base = base.canonicalize()?;
+        let (ready, ready_rx) = mpsc::channel();
+        let (release, held) = mpsc::channel::<()>();
+        let bound = base.clone();
+        thread::Builder::new()
+            .name("read-only-view".to_string())
+            .spawn(move || {
+                let result = bind_read_only(&bound).map(|()| gettid());
+                let failed = result.is_err();
+                if ready.send(result).is_ok() && !failed {
+                    // Returns once the view, and with it the sender, is dropped
+                    let _ = held.recv();
+                }
+            })?;
+        let tid = ready_rx
+            .recv()
+            .map_err(|_| io::Error::other("the thread binding the view ended early"))?
+            .map_err(io::Error::from)?;
+        // The thread's root, resolved in its namespace
+        let root = PathBuf::from(format!("/proc/{}/task/{tid}/root", std::process::id()))
+            .join(base.strip_prefix("/").unwrap_or(&base));
+        Ok(Self {
+            root,
+            _release: release,
+        })
+    }
+
+    /// Where the view is seen, for as long as it is held.
+    pub fn path(&self) -> &Path {
+        &self.root
+    }
+}
+
+/// Move the calling thread to a private mount namespace, bind `base` on itself with the
+/// mounts inside it, and remount each of them read-only.
+fn bind_read_only(base: &Path) -> std::result::Result<(), Errno> {
+    // A thread may only leave the mount namespace with a root and working directory of
+    // its own
+    unshare(CloneFlags::CLONE_FS | CloneFlags::CLONE_NEWNS)?;
+    mount(
+        None::<&str>,
+        "/",
+        None::<&str>,
+        MsFlags::MS_REC | MsFlags::MS_PRIVATE,
+        None::<&str>,
+    )?;
+    mount(
+        Some(base),
+        base,
+        None::<&str>,
+        MsFlags::MS_BIND | MsFlags::MS_REC,
+        None::<&str>,
+    )?;
+    let mountinfo = fs::read_to_string("/proc/thread-self/mountinfo")
+        .map_err(|e| e.raw_os_error().map_or(Errno::EIO, Errno::from_i32))?;
+    for inner in mounts::parse_mountinfo(&mountinfo)
+        .iter()
+        .filter(|inner| inner.mountpoint.starts_with(base))
+    {
+        // A bind remount sets every per-mount flag, so those already set are kept
+        let kept = statvfs(&inner.mountpoint)?.flags();
+        let mut flags = MsFlags::MS_REMOUNT | MsFlags::MS_BIND | MsFlags::MS_RDONLY;
+        for (statvfs_flag, mount_flag) in [
+            (FsFlags::ST_NOSUID, MsFlags::MS_NOSUID),
+            (FsFlags::ST_NODEV, MsFlags::MS_NODEV),
+            (FsFlags::ST_NOEXEC, MsFlags::MS_NOEXEC),
+            (FsFlags::ST_NOATIME, MsFlags::MS_NOATIME),
+            (FsFlags::ST_NODIRATIME, MsFlags::MS_NODIRATIME),
+            (FsFlags::ST_RELATIME, MsFlags::MS_RELATIME),
+        ] {
+            if kept.contains(statvfs_flag) {
+                flags |= mount_flag;
+            }
+        }
+        mount(
+            None::<&str>,
+            &inner.mountpoint,
+            None::<&str>,
+            flags,
+            None::<&str>,
+        )?;
+    }
+    Ok(())
+}
+
+/// A tree seen without the mounts inside it, bound non-recursively in a private mount
+/// namespace of the process.
+pub struct BeneathMounts {
+    dir: PathBuf,
+    mounted: bool,
+}
+
+impl BeneathMounts {
+    /// Move the process to a private mount namespace and bind `base` on a new directory
+    /// below `parent`. The process must be single-threaded.
+    pub fn mount(base: &Path, parent: &Path) -> Result<Self> {
+        unshare(CloneFlags::CLONE_NEWNS)
+            .map_err(|e| mount_error("enter a private mount namespace", e))?;
+        // Nothing mounted or unmounted from here on propagates to other namespaces
+        mount(
+            None::<&str>,
+            "/",
+            None::<&str>,
+            MsFlags::MS_REC | MsFlags::MS_PRIVATE,
+            None::<&str>,
+        )
+        .map_err(|e| mount_error("make the mount namespace private", e))?;
+
+        let dir = parent.join(format!("rust-utils-beneath-{}", std::process::id()));
+        DirBuilder::new().mode(0o700).create(&dir)?;
+        let mut beneath = Self {
+            dir,
+            mounted: false,
+        };
+        mount(
+            Some(base),
+            &beneath.dir,
+            None::<&str>,
+            MsFlags::MS_BIND,
+            None::<&str>,
+        )
+        .map_err(|e| mount_error(&format!("bind {}", base.display()), e))?;
+        beneath.mounted = true;
+        Ok(beneath)
+    }
+
+    /// Where the tree is bound, with what mounts inside it hide in view.
+    pub fn path(&self) -> &Path {
+        &self.dir
+    }
+}
+
+impl Drop for BeneathMounts {
+    fn drop(&mut self) {
+        if self.mounted {
+            if let Err(e) = umount2(&self.dir, MntFlags::MNT_DETACH) {
+                warn!("Cannot unmount {}: {}", self.dir.display(), e);
+                return;
+            }
+        }
+        if let Err(e) = fs::remove_dir(&self.dir) {
+            warn!("Cannot remove {}: {}", self.dir.display(), e);
+        }
+    }
+}
+
+fn mount_error(action: &str, errno: Errno) -> RustUtilsError {
+    RustUtilsError::OperationFailed(format!("cannot {action}: {errno}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::libc;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_find_shadowed() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = TempDir::new()?;
+        let base = temp_dir.path().canonicalize()?;
+        for dir in ["data/inner", "empty"] {
+            fs::create_dir_all(base.join(dir))?;
+        }
+        fs::write(base.join("data/hidden"), "")?;
+        fs::write(base.join("file"), "")?;
+        fs::write(base.join("source"), "")?;
+
+        let tmpfs = |path: &Path| {
+            mount(
+                Some("tmpfs"),
+                path,
+                Some("tmpfs"),
+                MsFlags::empty(),
+                None::<&str>,
+            )
+        };
+        if tmpfs(&base.join("data")).is_err() {
+            // Mounting needs CAP_SYS_ADMIN
+            return Ok(());
+        }
+        tmpfs(&base.join("empty"))?;
+        mount(
+            Some(&base.join("source")),
+            &base.join("file"),
+            None::<&str>,
+            MsFlags::MS_BIND,
+            None::<&str>,
+        )?;
+        fs::create_dir(base.join("data/sub"))?;
+        tmpfs(&base.join("data/sub"))?;
+
+        let mountpoints = ["data", "empty", "file", "data/sub"].map(|path| base.join(path));
+        let result = find_shadowed(&base, &mountpoints);
+        for mountpoint in mountpoints.iter().rev() {
+            umount2(mountpoint, MntFlags::MNT_DETACH)?;
+        }
+        let shadowed = match result {
+            Ok(shadowed) => shadowed,
+            Err(e) if e.raw_os_error() == Some(libc::ENOSYS) => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        // The empty directory hides nothing, and data/sub is hidden by data itself
+        assert_eq!(
+            shadowed,
+            [
+                Shadowed {
+                    mountpoint: base.join("data"),
+                    entries: 2
+                },
+                Shadowed {
+                    mountpoint: base.join("file"),
+                    entries: 1
+                }
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_only_view() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = TempDir::new()?;
+        let base = temp_dir.path().canonicalize()?;
+        fs::create_dir(base.join("data"))?;
+        fs::write(base.join("file"), "")?;
+        let mounted = mount(
+            Some("tmpfs"),
+            &base.join("data"),
+            Some("tmpfs"),
+            MsFlags::empty(),
+            None::<&str>,
+        )
+        .is_ok();
+        if mounted {
+            fs::write(base.join("data/inner"), "")?;
+        }
+
+        let result = ReadOnlyView::open(&base);
+        if mounted {
+            umount2(&base.join("data"), MntFlags::MNT_DETACH)?;
+        }
+        // Binding needs CAP_SYS_ADMIN
+        let Ok(view) = result else { return Ok(()) };
+        assert!(view.path().join("file").exists());
+        // The mounts inside the tree are cloned with it, and stay after they are unmounted
+        assert_eq!(view.path().join("data/inner").exists(), mounted);
+        let error = fs::write(view.path().join("file"), "changed").unwrap_err();
+        assert_eq!(error.raw_os_error(), Some(libc::EROFS));
+        // The tree itself is left writable
+        fs::write(base.join("file"), "changed")?;
+        Ok(())
+    }
+}
@@ -0,0 +1,57 @@
+//! SIGINT and SIGTERM handling for runs that change a tree entry by entry.
+//!
+//! Killed mid-walk, such a run leaves no record of how far it got. Once [`install`]ed, the
+//! first SIGINT or SIGTERM is only recorded: the run checks [`received`] between entries,
+//! lets the changes it started complete and ends with a summary of what it did. A second
+//! signal terminates the process as usual.
+//!
+//! The signals are blocked rather than handled, and a thread of their own waits for them,
+//! so no system call of the run is interrupted. Threads started later inherit the block;
+//! commands the run executes do not, as the standard library clears it for them.
+
+use std::io;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::thread;
+
+use nix::sys::signal::{SigSet, Signal};
+use tracing::warn;
+
+use crate::error::Result;
+
+/// The signal received, or 0 if none was.
+static RECEIVED: AtomicI32 = AtomicI32::new(0);
+
+/// Record SIGINT and SIGTERM instead of terminating, for the first of them. Call it before
+/// starting threads: one started earlier would still take the signals as usual.
+pub fn install() -> Result<()> {
+    let mut set = SigSet::empty();
+    set.add(Signal::SIGINT);
+    set.add(Signal::SIGTERM);
+    set.thread_block().map_err(io::Error::from)?;
+    thread::Builder::new()
+        .name("signals".to_string())
+        .spawn(move || {
+            match set.wait() {
+                Ok(signal) => RECEIVED.store(signal as i32, Ordering::SeqCst),
+                Err(e) => warn!("Cannot wait for signals: {}", e),
+            }
+            // From now on this thread takes the signals, with their default action
+            if let Err(e) = set.thread_unblock() {
+                warn!("Cannot take a second signal: {}", e);
+            }
+            loop {
+                thread::park();
+            }
+        })?;
+    Ok(())
+}
+
+/// The signal asking the run to stop, if one was received since [`install`].
+pub fn received() -> Option<Signal> {
+    Signal::try_from(RECEIVED.load(Ordering::SeqCst)).ok()
+}
+
+/// Exit status of a run stopped by `signal`, as shells report one killed by it.
+pub fn exit_code(signal: Signal) -> u8 {
+    128 + signal as u8
+}
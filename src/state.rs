@@ -0,0 +1,153 @@
+//! Files rust-utils keeps between runs, such as checkpoint logs, collected in one state
+//! directory instead of next to the trees and archives being processed.
+//!
+//! The directory is `--state-dir` if given, else `$RUST_UTILS_STATE_DIR`, else
+//! [`SYSTEM_STATE_DIR`] when running as root and `$XDG_DATA_HOME/rust-utils` (by default
+//! `~/.local/share/rust-utils`) otherwise. It is created on first use, readable only by its
+//! owner, as its files name the paths being worked on.
+
+use std::env;
+use std::fs::DirBuilder;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{self, Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+
+use crate::error::{Result, RustUtilsError};
+use crate::util::hex;
+
+/// State directory used by root.
+pub const SYSTEM_STATE_DIR: &str = "/var/lib/rust-utils";
+
+/// Environment variable overriding the default state directory.
+pub const STATE_DIR_ENV: &str = "RUST_UTILS_STATE_DIR";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StateDir {
+    root: PathBuf,
+}
+
+impl StateDir {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    /// `explicit` if given, then [`STATE_DIR_ENV`], otherwise the default location for the
+    /// current user.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`RustUtilsError::InvalidArguments`] if a non-root user has neither
+    /// `XDG_DATA_HOME` nor `HOME` set.
+    pub fn resolve(explicit: Option<&Path>) -> Result<Self> {
+        if let Some(root) = explicit {
+            return Ok(Self::new(root));
+        }
+        if let Some(root) = env::var_os(STATE_DIR_ENV).filter(|root| !root.is_empty()) {
+            return Ok(Self::new(root));
+        }
+        if nix::unistd::geteuid().is_root() {
+            return Ok(Self::new(SYSTEM_STATE_DIR));
+        }
+        let data_home = env::var_os("XDG_DATA_HOME")
+            .filter(|dir| Path::new(dir).is_absolute())
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".local/share")))
+            .ok_or_else(|| {
+                RustUtilsError::InvalidArguments(
+                    "cannot find a state directory: HOME is not set; use --state-dir".to_string(),
+                )
+            })?;
+        Ok(Self::new(data_home.join("rust-utils")))
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// The subdirectory `name`, created if missing.
+    pub fn subdir(&self, name: &str) -> Result<PathBuf> {
+        let dir = self.root.join(name);
+        DirBuilder::new().recursive(true).mode(0o700).create(&dir)?;
+        Ok(dir)
+    }
+
+    /// Journal of the partitioned job identified by `key`.
+    pub fn partition_journal(&self, key: &str) -> Result<PathBuf> {
+        Ok(self.subdir("partitions")?.join(format!("{key}.journal")))
+    }
+
+    /// Metadata cache of the tree at `root`.
+    pub fn metadata_cache(&self, root: &Path) -> Result<PathBuf> {
+        let digest = Sha256::digest(path::absolute(root)?.as_os_str().as_bytes());
+        let name = hex(&digest[..16]);
+        Ok(self.subdir("metadata-cache")?.join(format!("{name}.cache")))
+    }
+
+    /// Lock file of runs changing the tree at `root`, a canonical path.
+    pub fn tree_lock(&self, root: &Path) -> Result<PathBuf> {
+        let digest = Sha256::digest(root.as_os_str().as_bytes());
+        let name = hex(&digest[..16]);
+        Ok(self.subdir("locks")?.join(format!("{name}.lock")))
+    }
+
+    /// Lock file of the partitioned job identified by `key`.
+    pub fn job_lock(&self, key: &str) -> Result<PathBuf> {
+        Ok(self.subdir("locks")?.join(format!("{key}.job.lock")))
+    }
+
+    /// Marker of the remap identified by `key` having finished the first of its two phases.
+    pub fn two_phase_marker(&self, key: &str) -> Result<PathBuf> {
+        Ok(self.subdir("two-phase")?.join(format!("{key}.phase1")))
+    }
+
+    /// Checkpoint log for the archive being written to `output`.
+    pub fn checkpoint_log(&self, output: &Path) -> Result<PathBuf> {
+        let digest = Sha256::digest(path::absolute(output)?.as_os_str().as_bytes());
+        let name = hex(&digest[..16]);
+        Ok(self.subdir("checkpoints")?.join(format!("{name}.ckpt")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::MetadataExt;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_resolve() {
+        let explicit = StateDir::resolve(Some(Path::new("/srv/state"))).unwrap();
+        assert_eq!(explicit.root(), Path::new("/srv/state"));
+
+        // Tests run as root in CI; other users get a per-user directory
+        let default = StateDir::resolve(None).unwrap();
+        if nix::unistd::geteuid().is_root() {
+            assert_eq!(default.root(), Path::new(SYSTEM_STATE_DIR));
+        } else {
+            assert!(default.root().ends_with("rust-utils"));
+        }
+    }
+
+    #[test]
+    fn test_checkpoint_log() {
+        let dir = TempDir::new().unwrap();
+        let state = StateDir::new(dir.path().join("state"));
+
+        let log = state.checkpoint_log(Path::new("/srv/web.tar.zst")).unwrap();
+        assert_eq!(log.parent().unwrap(), dir.path().join("state/checkpoints"));
+        let mode = fs::metadata(log.parent().unwrap()).unwrap().mode();
+        assert_eq!(mode & 0o777, 0o700);
+
+        assert_eq!(
+            state.checkpoint_log(Path::new("/srv/web.tar.zst")).unwrap(),
+            log
+        );
+        assert_ne!(
+            state.checkpoint_log(Path::new("/srv/db.tar.zst")).unwrap(),
+            log
+        );
+    }
+}
@@ -0,0 +1,280 @@
+//! Archive inputs and outputs: stdio, plain files, split (multi-part) volumes and, with the
+//! `object-storage` feature, URLs (see [`crate::remote`]).
+//!
+//! A split archive is a byte stream cut into `NAME.000`, `NAME.001`, ... parts, as produced
+//! by `split -d -a 3 NAME NAME.`, so `cat NAME.* > NAME` reassembles it.
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use tracing::debug;
+
+use crate::error::{Result, RustUtilsError};
+use crate::remote;
+
+/// A byte count with an optional binary `K`, `M`, `G` or `T` suffix, such as `4G`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ByteSize(pub u64);
+
+impl FromStr for ByteSize {
+    type Err = RustUtilsError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || {
+            RustUtilsError::InvalidArguments(format!(
+                "invalid size '{s}' (expected a number with optional K, M, G or T suffix)"
+            ))
+        };
+
+        let upper = s.trim().to_ascii_uppercase();
+        let digits = upper.trim_end_matches(['B', 'I']);
+        let (digits, shift) = match digits.chars().last() {
+            Some('K') => (&digits[..digits.len() - 1], 10),
+            Some('M') => (&digits[..digits.len() - 1], 20),
+            Some('G') => (&digits[..digits.len() - 1], 30),
+            Some('T') => (&digits[..digits.len() - 1], 40),
+            _ => (digits, 0),
+        };
+        let value: u64 = digits.parse().map_err(|_| invalid())?;
+        let bytes = value.checked_mul(1 << shift).ok_or_else(invalid)?;
+        if bytes == 0 {
+            return Err(RustUtilsError::InvalidRange(format!(
+                "size '{s}' must be greater than zero"
+            )));
+        }
+        Ok(ByteSize(bytes))
+    }
+}
+
+impl fmt::Display for ByteSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// An archive destination that must be committed once everything has been written.
+pub trait Sink: Write {
+    /// Flush and commit the output, e.g. complete a remote upload. Dropping a sink without
+    /// finishing it may leave a partial or no output behind.
+    fn finish(self: Box<Self>) -> io::Result<()>;
+}
+
+impl Sink for File {
+    fn finish(mut self: Box<Self>) -> io::Result<()> {
+        self.flush()
+    }
+}
+
+impl Sink for io::StdoutLock<'static> {
+    fn finish(mut self: Box<Self>) -> io::Result<()> {
+        self.flush()
+    }
+}
+
+/// Whether `path` stands for stdin or stdout.
+pub fn is_stdio(path: &Path) -> bool {
+    path.as_os_str() == "-"
+}
+
+/// Name of part `index` of the split archive `path`.
+pub fn part_path(path: &Path, index: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{index:03}"));
+    PathBuf::from(name)
+}
+
+/// Open an archive for reading: stdin for `-`, a URL, the file itself if it exists, otherwise
+/// the parts `PATH.000`, `PATH.001`, ... read back to back.
+pub fn open_input(path: &Path) -> Result<Box<dyn Read + Send>> {
+    if is_stdio(path) {
+        return Ok(Box::new(io::stdin()));
+    }
+    if let Some(url) = remote::url(path) {
+        return remote::open(url);
+    }
+    if path.exists() || !part_path(path, 0).exists() {
+        return Ok(Box::new(File::open(path)?));
+    }
+    Ok(Box::new(PartReader::new(path)?))
+}
+
+/// Create an archive for writing: stdout for `-`, an upload for a URL, otherwise the file, or
+/// numbered parts of at most `split_size` bytes each.
+pub fn create_output(path: &Path, split_size: Option<ByteSize>) -> Result<Box<dyn Sink>> {
+    if split_size.is_some() && (is_stdio(path) || remote::url(path).is_some()) {
+        return Err(RustUtilsError::InvalidArguments(
+            "--split-size needs a local output file".to_string(),
+        ));
+    }
+    if let Some(url) = remote::url(path) {
+        return remote::create(url);
+    }
+    match split_size {
+        Some(ByteSize(size)) => Ok(Box::new(SplitWriter::new(path, size)?)),
+        None if is_stdio(path) => Ok(Box::new(io::stdout().lock())),
+        None => Ok(Box::new(File::create(path)?)),
+    }
+}
+
+/// Flush a buffered output and commit it.
+pub fn finish_output(output: BufWriter<Box<dyn Sink>>) -> io::Result<()> {
+    output
+        .into_inner()
+        .map_err(io::IntoInnerError::into_error)?
+        .finish()
+}
+
+/// Reads the parts of a split archive in order, opening each only when it is reached.
+struct PartReader {
+    base: PathBuf,
+    index: usize,
+    current: Option<File>,
+}
+
+impl PartReader {
+    fn new(base: &Path) -> Result<Self> {
+        let first = part_path(base, 0);
+        debug!("Reading split archive from {}", first.display());
+        Ok(Self {
+            base: base.to_path_buf(),
+            index: 0,
+            current: Some(File::open(first)?),
+        })
+    }
+}
+
+impl Read for PartReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while let Some(file) = &mut self.current {
+            let n = file.read(buf)?;
+            if n > 0 || buf.is_empty() {
+                return Ok(n);
+            }
+            self.index += 1;
+            let next = part_path(&self.base, self.index);
+            self.current = match File::open(&next) {
+                Ok(file) => Some(file),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+                Err(e) => return Err(e),
+            };
+        }
+        Ok(0)
+    }
+}
+
+/// Writes `base.000`, `base.001`, ... starting a new part every `part_size` bytes.
+pub struct SplitWriter {
+    base: PathBuf,
+    part_size: u64,
+    index: usize,
+    written: u64,
+    current: File,
+}
+
+impl SplitWriter {
+    pub fn new(base: &Path, part_size: u64) -> Result<Self> {
+        // Stale parts from an earlier, longer archive would be read back as its tail
+        let first = part_path(base, 0);
+        if first.exists() {
+            return Err(RustUtilsError::InvalidArguments(format!(
+                "{} already exists",
+                first.display()
+            )));
+        }
+
+        Ok(Self {
+            base: base.to_path_buf(),
+            part_size,
+            index: 0,
+            written: 0,
+            current: File::create(first)?,
+        })
+    }
+}
+
+impl Sink for SplitWriter {
+    fn finish(mut self: Box<Self>) -> io::Result<()> {
+        self.flush()
+    }
+}
+
+impl Write for SplitWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.written == self.part_size {
+            self.current.flush()?;
+            self.index += 1;
+            self.written = 0;
+            self.current = File::create(part_path(&self.base, self.index))?;
+        }
+        let room = (self.part_size - self.written).min(buf.len() as u64) as usize;
+        let n = self.current.write(&buf[..room])?;
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.current.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_parse_byte_size() {
+        assert_eq!("512".parse::<ByteSize>().unwrap(), ByteSize(512));
+        assert_eq!("4K".parse::<ByteSize>().unwrap(), ByteSize(4096));
+        assert_eq!("2GiB".parse::<ByteSize>().unwrap(), ByteSize(2 << 30));
+        assert_eq!("1m".parse::<ByteSize>().unwrap(), ByteSize(1 << 20));
+        assert!(matches!(
+            "0".parse::<ByteSize>(),
+            Err(RustUtilsError::InvalidRange(_))
+        ));
+        for input in ["", "K", "1.5G", "4X", "99999999999T"] {
+            assert!(matches!(
+                input.parse::<ByteSize>(),
+                Err(RustUtilsError::InvalidArguments(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_split_round_trip() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let dir = TempDir::new()?;
+        let base = dir.path().join("base.tar");
+        let data: Vec<u8> = (0..2500u32).map(|i| i as u8).collect();
+
+        let mut output = create_output(&base, Some(ByteSize(1000)))?;
+        output.write_all(&data)?;
+        output.finish()?;
+
+        assert!(!base.exists());
+        let sizes: Vec<u64> = (0..3)
+            .map(|i| std::fs::metadata(part_path(&base, i)).unwrap().len())
+            .collect();
+        assert_eq!(sizes, [1000, 1000, 500]);
+        assert!(!part_path(&base, 3).exists());
+
+        let mut read = Vec::new();
+        open_input(&base)?.read_to_end(&mut read)?;
+        assert_eq!(read, data);
+
+        // Refuse to mix new parts with old ones
+        assert!(create_output(&base, Some(ByteSize(1000))).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_split_to_stdout_rejected() {
+        assert!(matches!(
+            create_output(Path::new("-"), Some(ByteSize(1))),
+            Err(RustUtilsError::InvalidArguments(_))
+        ));
+    }
+}
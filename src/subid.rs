@@ -0,0 +1,402 @@
+//! Subordinate ID ranges from `/etc/subuid` and `/etc/subgid` (see `subuid(5)`).
+
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+use nix::fcntl::{flock, FlockArg};
+use nix::unistd::{Uid, User};
+
+use crate::error::{Result, RustUtilsError};
+use crate::idmap::{IdMap, IdMapping};
+
+pub const SUBUID_FILE: &str = "/etc/subuid";
+pub const SUBGID_FILE: &str = "/etc/subgid";
+
+/// One `owner:start:count` delegation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SubIdRange {
+    pub start: u32,
+    pub count: u32,
+}
+
+/// One line of a subordinate ID file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SubIdEntry {
+    /// Login name or numeric UID, as written in the file
+    pub owner: String,
+    pub range: SubIdRange,
+}
+
+/// All ranges delegated to `user`, in file order.
+///
+/// Entries may name the owner either by login name or by numeric UID, so both forms of
+/// `user` are matched against both forms in the file.
+pub fn ranges_for(path: &Path, user: &str) -> Result<Vec<SubIdRange>> {
+    let (name, uid) = resolve_user(user);
+    Ok(entries(path)?
+        .into_iter()
+        .filter(|entry| {
+            Some(entry.owner.as_str()) == name.as_deref()
+                || uid.is_some_and(|uid| entry.owner.parse::<u32>() == Ok(uid))
+        })
+        .map(|entry| entry.range)
+        .collect())
+}
+
+/// Every delegation in the file, whoever it belongs to, in file order.
+pub fn entries(path: &Path) -> Result<Vec<SubIdEntry>> {
+    let contents = std::fs::read_to_string(path)?;
+
+    let mut entries = Vec::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let invalid = || {
+            RustUtilsError::InvalidArguments(format!(
+                "{}:{}: malformed entry '{}'",
+                path.display(),
+                number + 1,
+                line
+            ))
+        };
+        let mut fields = line.split(':');
+        let (Some(owner), Some(start), Some(count), None) =
+            (fields.next(), fields.next(), fields.next(), fields.next())
+        else {
+            return Err(invalid());
+        };
+
+        entries.push(SubIdEntry {
+            owner: owner.to_string(),
+            range: SubIdRange {
+                start: start.parse().map_err(|_| invalid())?,
+                count: count.parse().map_err(|_| invalid())?,
+            },
+        });
+    }
+
+    Ok(entries)
+}
+
+/// A subordinate ID file locked for changes with `flock`, so concurrent allocations see each
+/// other's entries. The lock is released on drop.
+pub struct LockedSubIdFile {
+    path: PathBuf,
+    file: File,
+}
+
+impl LockedSubIdFile {
+    /// Lock `path` exclusively, creating an empty file if it does not exist.
+    pub fn lock(path: &Path) -> Result<Self> {
+        loop {
+            let file = OpenOptions::new()
+                .read(true)
+                .append(true)
+                .create(true)
+                .open(path)?;
+            flock(file.as_raw_fd(), FlockArg::LockExclusive)?;
+            // A writer that held the lock before may have replaced the file meanwhile
+            let locked = file.metadata()?;
+            let current = fs::metadata(path)?;
+            if locked.dev() == current.dev() && locked.ino() == current.ino() {
+                return Ok(Self {
+                    path: path.to_path_buf(),
+                    file,
+                });
+            }
+        }
+    }
+
+    pub fn entries(&self) -> Result<Vec<SubIdEntry>> {
+        entries(&self.path)
+    }
+
+    /// Fail where [`append`](Self::append) would: for an owner that cannot be written as
+    /// one field of a line, or a range overlapping an existing entry.
+    pub fn check_append(&self, owner: &str, range: SubIdRange) -> Result<()> {
+        check_owner(owner)?;
+        let end = u64::from(range.start) + u64::from(range.count);
+        if let Some(entry) = self.entries()?.into_iter().find(|entry| {
+            u64::from(entry.range.start) < end
+                && u64::from(range.start)
+                    < u64::from(entry.range.start) + u64::from(entry.range.count)
+        }) {
+            return Err(RustUtilsError::InvalidRange(format!(
+                "{}:{} overlaps {}:{}:{} in {}",
+                range.start,
+                range.count,
+                entry.owner,
+                entry.range.start,
+                entry.range.count,
+                self.path.display()
+            )));
+        }
+        Ok(())
+    }
+
+    /// Delegate `range` to `owner`, refusing what [`check_append`](Self::check_append)
+    /// refuses. The previous contents are kept as `FILE-`, as shadow's tools do, and the new
+    /// file replaces the old one atomically.
+    pub fn append(&self, owner: &str, range: SubIdRange) -> Result<()> {
+        self.check_append(owner, range)?;
+        let mut contents = fs::read_to_string(&self.path)?;
+        let mut backup = self.path.clone().into_os_string();
+        backup.push("-");
+        fs::write(&backup, &contents)?;
+        if !contents.is_empty() && !contents.ends_with('\n') {
+            contents.push('\n');
+        }
+        contents.push_str(&format!("{}:{}:{}\n", owner, range.start, range.count));
+
+        let mut partial = self.path.clone().into_os_string();
+        partial.push(format!(".{}", std::process::id()));
+        let partial = PathBuf::from(partial);
+        let metadata = self.file.metadata()?;
+        let mut file = File::create(&partial)?;
+        file.write_all(contents.as_bytes())?;
+        file.set_permissions(fs::Permissions::from_mode(metadata.mode() & 0o7777))?;
+        std::os::unix::fs::fchown(&file, Some(metadata.uid()), Some(metadata.gid()))?;
+        file.sync_all()?;
+        fs::rename(&partial, &self.path)?;
+        self.sync_dir()
+    }
+
+    /// Put back the contents [`append`](Self::append) kept as `FILE-`, undoing it.
+    pub fn restore_backup(&self) -> Result<()> {
+        let mut backup = self.path.clone().into_os_string();
+        backup.push("-");
+        fs::write(&self.path, fs::read(&backup)?)?;
+        self.sync_dir()
+    }
+
+    fn sync_dir(&self) -> Result<()> {
+        if let Some(dir) = self.path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    }
+}
+
+/// Refuse owners that are empty or would split into other fields or lines of the file.
+pub fn check_owner(owner: &str) -> Result<()> {
+    if owner.is_empty() || owner.contains(|c: char| c == ':' || c.is_whitespace()) {
+        return Err(RustUtilsError::InvalidArguments(format!(
+            "invalid subordinate ID owner '{}' (expected a login name or UID without ':' or \
+             whitespace)",
+            owner.escape_debug()
+        )));
+    }
+    Ok(())
+}
+
+/// The first part of `start..start + count` that none of `ranges` covers, as `(first, last)`.
+pub fn uncovered(ranges: &[SubIdRange], start: u32, count: u32) -> Option<(u32, u32)> {
+    let mut sorted = ranges.to_vec();
+    sorted.sort_by_key(|range| range.start);
+    let end = u64::from(start) + u64::from(count);
+    let mut next = u64::from(start);
+    for range in sorted {
+        if next >= end {
+            break;
+        }
+        if u64::from(range.start) > next {
+            break;
+        }
+        next = next.max(u64::from(range.start) + u64::from(range.count));
+    }
+    if next >= end {
+        return None;
+    }
+    let gap_end = ranges
+        .iter()
+        .map(|range| u64::from(range.start))
+        .filter(|&range_start| range_start > next)
+        .min()
+        .unwrap_or(end)
+        .min(end);
+    Some((next as u32, (gap_end - 1) as u32))
+}
+
+/// Map container IDs `0..` onto the ranges delegated to `user`, laid end to end.
+pub fn idmap_for(path: &Path, user: &str) -> Result<IdMap> {
+    let ranges = ranges_for(path, user)?;
+    if ranges.is_empty() {
+        return Err(RustUtilsError::InvalidArguments(format!(
+            "no subordinate IDs for '{}' in {}",
+            user,
+            path.display()
+        )));
+    }
+
+    let mut mappings = Vec::with_capacity(ranges.len());
+    let mut next = 0u32;
+    for range in ranges {
+        mappings.push(IdMapping {
+            from: next,
+            to: range.start,
+            count: range.count,
+        });
+        next = next.checked_add(range.count).ok_or_else(|| {
+            RustUtilsError::InvalidRange(format!("subordinate IDs for '{user}' overflow"))
+        })?;
+    }
+    IdMap::new(mappings)
+}
+
+/// Login name and UID for `user`, which may be given in either form.
+fn resolve_user(user: &str) -> (Option<String>, Option<u32>) {
+    if let Ok(uid) = user.parse::<u32>() {
+        let name = User::from_uid(Uid::from_raw(uid))
+            .ok()
+            .flatten()
+            .map(|u| u.name);
+        return (name, Some(uid));
+    }
+    let uid = User::from_name(user).ok().flatten().map(|u| u.uid.as_raw());
+    (Some(user.to_string()), uid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::NamedTempFile;
+
+    fn subid_file(contents: &str) -> NamedTempFile {
+        let file = NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), contents).unwrap();
+        file
+    }
+
+    #[test]
+    fn test_ranges_for_user() {
+        let file = subid_file("lxc:100000:65536\nother:300000:65536\n\nlxc:500000:1000\n");
+        let ranges = ranges_for(file.path(), "lxc").unwrap();
+        assert_eq!(
+            ranges,
+            vec![
+                SubIdRange {
+                    start: 100000,
+                    count: 65536
+                },
+                SubIdRange {
+                    start: 500000,
+                    count: 1000
+                },
+            ]
+        );
+        assert!(ranges_for(file.path(), "nobody-here").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_ranges_for_numeric_owner() {
+        // root always resolves, so "0" and "root" entries refer to the same user
+        let file = subid_file("0:100000:65536\n");
+        assert_eq!(ranges_for(file.path(), "root").unwrap().len(), 1);
+        assert_eq!(ranges_for(file.path(), "0").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_ranges_for_malformed() {
+        let file = subid_file("lxc:100000\n");
+        assert!(matches!(
+            ranges_for(file.path(), "lxc"),
+            Err(RustUtilsError::InvalidArguments(_))
+        ));
+    }
+
+    #[test]
+    fn test_append() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::TempDir::new()?;
+        let path = dir.path().join("subuid");
+        fs::write(&path, "lxc:100000:65536")?;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o640))?;
+
+        let locked = LockedSubIdFile::lock(&path)?;
+        let overlapping = SubIdRange {
+            start: 165535,
+            count: 10,
+        };
+        assert!(matches!(
+            locked.append("web", overlapping),
+            Err(RustUtilsError::InvalidRange(_))
+        ));
+        locked.append(
+            "web",
+            SubIdRange {
+                start: 165536,
+                count: 65536,
+            },
+        )?;
+        drop(locked);
+
+        assert_eq!(
+            fs::read_to_string(&path)?,
+            "lxc:100000:65536\nweb:165536:65536\n"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.path().join("subuid-"))?,
+            "lxc:100000:65536"
+        );
+        assert_eq!(fs::metadata(&path)?.mode() & 0o777, 0o640);
+
+        // Missing files start out empty
+        let fresh = LockedSubIdFile::lock(&dir.path().join("subgid"))?;
+        assert!(fresh.entries()?.is_empty());
+
+        let locked = LockedSubIdFile::lock(&path)?;
+        let range = SubIdRange {
+            start: 300000,
+            count: 1,
+        };
+        for owner in ["", "bob:0:1", "bob\nroot", "two words"] {
+            assert!(matches!(
+                locked.append(owner, range),
+                Err(RustUtilsError::InvalidArguments(_))
+            ));
+        }
+        locked.append("db", range)?;
+        locked.restore_backup()?;
+        assert_eq!(
+            fs::read_to_string(&path)?,
+            "lxc:100000:65536\nweb:165536:65536\n"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_uncovered() {
+        let ranges = [
+            SubIdRange {
+                start: 165536,
+                count: 65536,
+            },
+            SubIdRange {
+                start: 100000,
+                count: 65536,
+            },
+        ];
+        assert_eq!(uncovered(&ranges, 100000, 131072), None);
+        assert_eq!(uncovered(&ranges, 100000, 131073), Some((231072, 231072)));
+        assert_eq!(uncovered(&ranges, 99990, 20), Some((99990, 99999)));
+        assert_eq!(uncovered(&[], 0, 10), Some((0, 9)));
+    }
+
+    #[test]
+    fn test_idmap_for_concatenates_ranges() {
+        let file = subid_file("lxc:100000:1000\nlxc:500000:1000\n");
+        let map = idmap_for(file.path(), "lxc").unwrap();
+        assert_eq!(map.get(0), Some(100000));
+        assert_eq!(map.get(999), Some(100999));
+        assert_eq!(map.get(1000), Some(500000));
+        assert_eq!(map.get(2000), None);
+
+        assert!(idmap_for(file.path(), "missing").is_err());
+    }
+}
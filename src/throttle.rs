@@ -0,0 +1,218 @@
+//! Pacing of the metadata operations of a run for `remap --throttle`, so that a remap of a
+//! tree on shared storage leaves IOPS to the containers running on it.
+//!
+//! Every operation timed by [`iostats::timed`](crate::iostats::timed), from reading a
+//! directory entry to changing an owner, goes through [`paced`]. A [`Limiter`] installed on
+//! the threads of a run then either spaces the operations of all of them to a rate, or has
+//! each thread rest after its operations so that they take at most a share of its time.
+
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::error::{Result, RustUtilsError};
+
+/// Rests shorter than this are saved up, as sleeping costs more than it saves.
+const MIN_REST: Duration = Duration::from_millis(5);
+
+/// How far operations are limited.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Throttle {
+    /// At most this many operations a second, across all threads of the run
+    Rate(u32),
+    /// Operations take at most this percentage of each thread's time
+    Duty(u8),
+}
+
+impl FromStr for Throttle {
+    type Err = RustUtilsError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || {
+            RustUtilsError::InvalidArguments(format!(
+                "invalid throttle '{s}' (expected operations a second such as 500 or 500/s, \
+                 or a share of time such as 25%)"
+            ))
+        };
+        if let Some(percent) = s.strip_suffix('%') {
+            match percent.parse() {
+                Ok(percent @ 1..=99) => Ok(Throttle::Duty(percent)),
+                _ => Err(invalid()),
+            }
+        } else {
+            match s.strip_suffix("/s").unwrap_or(s).parse() {
+                Ok(0) | Err(_) => Err(invalid()),
+                Ok(rate) => Ok(Throttle::Rate(rate)),
+            }
+        }
+    }
+}
+
+impl fmt::Display for Throttle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Throttle::Rate(rate) => write!(f, "{rate} operations a second"),
+            Throttle::Duty(percent) => write!(f, "{percent}% of the time"),
+        }
+    }
+}
+
+/// The limit of a run, shared by its threads.
+#[derive(Debug)]
+pub struct Limiter {
+    throttle: Throttle,
+    /// When the next operation may start, with [`Throttle::Rate`]
+    next: Mutex<Instant>,
+    /// Nanoseconds spent waiting, by all threads
+    waited: AtomicU64,
+}
+
+impl Limiter {
+    pub fn new(throttle: Throttle) -> Arc<Self> {
+        Arc::new(Self {
+            throttle,
+            next: Mutex::new(Instant::now()),
+            waited: AtomicU64::new(0),
+        })
+    }
+
+    /// Time the threads of the run spent waiting so far.
+    pub fn waited(&self) -> Duration {
+        Duration::from_nanos(self.waited.load(Ordering::Relaxed))
+    }
+
+    fn wait(&self, duration: Duration) {
+        // Sleeps overrun, and the next turn is that much closer
+        let started = Instant::now();
+        thread::sleep(duration);
+        self.waited
+            .fetch_add(started.elapsed().as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    /// Wait for the turn of the next operation at `rate` a second. Turns are not saved up
+    /// while no operations are made, so a slow stretch is not followed by a burst.
+    fn take_turn(&self, rate: u32) {
+        let now = Instant::now();
+        let start = {
+            let mut next = self.next.lock().unwrap_or_else(|e| e.into_inner());
+            let start = (*next).max(now);
+            *next = start + Duration::from_secs(1) / rate;
+            start
+        };
+        if start > now {
+            self.wait(start - now);
+        }
+    }
+}
+
+thread_local! {
+    static CURRENT: RefCell<Option<Arc<Limiter>>> = const { RefCell::new(None) };
+    /// Operations in progress on this thread, which nest as in `iostats`
+    static DEPTH: Cell<u32> = const { Cell::new(0) };
+    /// Rest this thread owes for its operations with [`Throttle::Duty`]
+    static OWED: Cell<Duration> = const { Cell::new(Duration::ZERO) };
+}
+
+/// Limit the operations of this thread by `limiter` until the guard is dropped.
+pub fn install(limiter: Option<Arc<Limiter>>) -> Installed {
+    Installed {
+        previous: CURRENT.replace(limiter),
+    }
+}
+
+/// Puts back the limiter installed before, when dropped.
+#[must_use = "the limiter is uninstalled when this is dropped"]
+pub struct Installed {
+    previous: Option<Arc<Limiter>>,
+}
+
+impl Drop for Installed {
+    fn drop(&mut self) {
+        CURRENT.set(self.previous.take());
+    }
+}
+
+/// The limiter installed on this thread, to install on threads it starts.
+pub fn current() -> Option<Arc<Limiter>> {
+    CURRENT.with_borrow(Clone::clone)
+}
+
+/// Run `f`, one metadata operation, within the limit installed on this thread.
+pub fn paced<T>(f: impl FnOnce() -> T) -> T {
+    let Some(limiter) = current() else {
+        return f();
+    };
+    match limiter.throttle {
+        Throttle::Rate(rate) => {
+            limiter.take_turn(rate);
+            f()
+        }
+        Throttle::Duty(percent) => {
+            // Operations made inside another are part of its time
+            let outermost = DEPTH.replace(DEPTH.get() + 1) == 0;
+            let started = Instant::now();
+            let result = f();
+            DEPTH.set(DEPTH.get() - 1);
+            if outermost {
+                let busy = started.elapsed();
+                let owed = OWED.get() + busy * u32::from(100 - percent) / u32::from(percent);
+                if owed >= MIN_REST {
+                    limiter.wait(owed);
+                    OWED.set(Duration::ZERO);
+                } else {
+                    OWED.set(owed);
+                }
+            }
+            result
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_throttle() {
+        assert_eq!("500".parse::<Throttle>().unwrap(), Throttle::Rate(500));
+        assert_eq!("500/s".parse::<Throttle>().unwrap(), Throttle::Rate(500));
+        assert_eq!("25%".parse::<Throttle>().unwrap(), Throttle::Duty(25));
+        for s in ["0", "0%", "100%", "fast", "-5", "5/m", ""] {
+            assert!(s.parse::<Throttle>().is_err(), "{s}");
+        }
+    }
+
+    #[test]
+    fn test_paced() {
+        // Nothing is limited without a limiter
+        let started = Instant::now();
+        for _ in 0..1000 {
+            paced(|| ());
+        }
+        assert!(started.elapsed() < Duration::from_millis(50));
+
+        let limiter = Limiter::new(Throttle::Rate(100));
+        let installed = install(Some(limiter.clone()));
+        let started = Instant::now();
+        for _ in 0..11 {
+            paced(|| ());
+        }
+        // The first operation goes at once and the other ten 10ms apart
+        assert!(started.elapsed() >= Duration::from_millis(100));
+        assert!(limiter.waited() >= Duration::from_millis(90));
+        drop(installed);
+        assert!(current().is_none());
+
+        let limiter = Limiter::new(Throttle::Duty(50));
+        let _installed = install(Some(limiter.clone()));
+        paced(|| paced(|| thread::sleep(Duration::from_millis(20))));
+        // Half the time resting, for the outer operation only
+        let waited = limiter.waited();
+        assert!(waited >= Duration::from_millis(20), "{waited:?}");
+        assert!(waited < Duration::from_millis(35), "{waited:?}");
+    }
+}
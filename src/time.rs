@@ -0,0 +1,30 @@
+//! Calendar dates of Unix timestamps, for the times written to log files and into request
+//! signatures.
+
+/// The UTC date and time `secs` seconds after the epoch, in the proleptic Gregorian
+/// calendar, as `(year, month, day, hour, minute, second)`.
+pub fn civil(secs: u64) -> (u64, u64, u64, u64, u64, u64) {
+    let (days, rem) = (secs / 86400, secs % 86400);
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z % 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    (year, month, day, rem / 3600, rem / 60 % 60, rem % 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_civil() {
+        assert_eq!(civil(0), (1970, 1, 1, 0, 0, 0));
+        assert_eq!(civil(951_782_400), (2000, 2, 29, 0, 0, 0));
+        assert_eq!(civil(4_107_542_399), (2100, 2, 28, 23, 59, 59));
+    }
+}
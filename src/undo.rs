@@ -0,0 +1,268 @@
+//! Journals of the ownership changes a remap makes, so that `remap undo` can put back the
+//! previous owners of a tree whose migration failed part-way.
+//!
+//! A journal is a text file: a header line, then one line per change with the old and new
+//! `UID:GID` and the hex-encoded absolute path. Each line is written before the change it
+//! records is made, so the journal of an interrupted run covers every change the run made.
+//! Lines reach the file as they are written but are only synced when the run completes.
+//! The run holds an exclusive `flock` on the journal meanwhile, so that `journal compact`
+//! leaves it alone.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{self, Path, PathBuf};
+use std::str::FromStr;
+
+use nix::fcntl::{flock, FlockArg};
+
+use crate::error::{Result, RustUtilsError};
+use crate::util::{hex, unhex};
+
+/// First word of every undo journal and snapshot manifest.
+pub const UNDO_MAGIC: &str = "rust-utils-undo-journal";
+
+/// Version of the undo journal format written by this build.
+pub const UNDO_FORMAT_VERSION: u32 = 1;
+
+/// The owner of an entry, written `UID:GID`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Owner {
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl fmt::Display for Owner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.uid, self.gid)
+    }
+}
+
+impl FromStr for Owner {
+    type Err = RustUtilsError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        s.split_once(':')
+            .and_then(|(uid, gid)| {
+                Some(Owner {
+                    uid: uid.parse().ok()?,
+                    gid: gid.parse().ok()?,
+                })
+            })
+            .ok_or_else(|| RustUtilsError::InvalidArguments(format!("invalid owner '{s}'")))
+    }
+}
+
+/// One ownership change recorded in an undo journal.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UndoEntry {
+    /// Absolute path of the entry
+    pub path: PathBuf,
+    /// Owner before the change
+    pub old: Owner,
+    /// Owner after the change
+    pub new: Owner,
+}
+
+/// An undo journal being written by a remap.
+pub struct UndoJournal {
+    file: File,
+}
+
+impl UndoJournal {
+    /// Start a journal at `path`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`RustUtilsError::InvalidArguments`] if `path` already exists, since it may be
+    /// the only record of an earlier run's changes.
+    pub fn create(path: &Path) -> Result<Self> {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)
+            .map_err(|e| match e.kind() {
+                io::ErrorKind::AlreadyExists => RustUtilsError::InvalidArguments(format!(
+                    "undo journal {} already exists; undo or remove it first",
+                    path.display()
+                )),
+                _ => e.into(),
+            })?;
+        flock(file.as_raw_fd(), FlockArg::LockExclusiveNonblock)?;
+        writeln!(file, "{UNDO_MAGIC} {UNDO_FORMAT_VERSION}")?;
+        file.sync_data()?;
+        Ok(Self { file })
+    }
+
+    /// Record that the owner of `path` is about to change from `old` to `new`.
+    pub fn record(&self, path: &Path, old: Owner, new: Owner) -> Result<()> {
+        let path = path::absolute(path)?;
+        let line = format!("{old} {new} {}\n", hex(path.as_os_str().as_bytes()));
+        // One write per line, so an interruption can only tear the last one
+        (&self.file).write_all(line.as_bytes())?;
+        Ok(())
+    }
+
+    /// Sync the journal once the run has completed.
+    pub fn finish(self) -> Result<()> {
+        self.file.sync_all()?;
+        Ok(())
+    }
+}
+
+/// Read the changes recorded in the undo journal at `path`, in the order they were made.
+///
+/// # Errors
+///
+/// Returns [`RustUtilsError::InvalidArguments`] for files that are not undo journals and
+/// [`RustUtilsError::UnsupportedFormat`] for journals written by a newer version.
+pub fn read_journal(path: &Path) -> Result<Vec<UndoEntry>> {
+    let invalid =
+        |detail: &str| RustUtilsError::InvalidArguments(format!("{}: {}", path.display(), detail));
+
+    // Only newline-terminated lines count; a torn final line from a crash mid-append is
+    // ignored, as its change was never made
+    let text = fs::read_to_string(path)?;
+    let complete = text.rsplit_once('\n').map_or("", |(complete, _)| complete);
+    let mut lines = complete.lines();
+    let header = lines.next().unwrap_or_default();
+    let mut fields = header.split_whitespace();
+    let (Some(UNDO_MAGIC), Some(Ok(version))) =
+        (fields.next(), fields.next().map(str::parse::<u32>))
+    else {
+        return Err(invalid("not an undo journal"));
+    };
+    if version > UNDO_FORMAT_VERSION {
+        return Err(RustUtilsError::UnsupportedFormat(format!(
+            "{}: undo journal version {} is newer than the supported version {}",
+            path.display(),
+            version,
+            UNDO_FORMAT_VERSION
+        )));
+    }
+
+    lines
+        .map(|line| {
+            let mut fields = line.split_whitespace();
+            let (Some(old), Some(new), Some(path), None) =
+                (fields.next(), fields.next(), fields.next(), fields.next())
+            else {
+                return Err(invalid("corrupt journal line"));
+            };
+            let path = unhex(path).ok_or_else(|| invalid("corrupt journal line"))?;
+            Ok(UndoEntry {
+                path: PathBuf::from(std::ffi::OsStr::from_bytes(&path)),
+                old: old.parse()?,
+                new: new.parse()?,
+            })
+        })
+        .collect()
+}
+
+/// Merge the changes recorded for the same path into one, from the first old owner to the
+/// last new one, and drop those that end where they started. Entries keep the place of
+/// their path's first change.
+pub fn compact(entries: &[UndoEntry]) -> Vec<UndoEntry> {
+    let mut merged: Vec<UndoEntry> = Vec::new();
+    let mut index: HashMap<&Path, usize> = HashMap::new();
+    for entry in entries {
+        match index.get(entry.path.as_path()) {
+            Some(&i) => merged[i].new = entry.new,
+            None => {
+                index.insert(&entry.path, merged.len());
+                merged.push(entry.clone());
+            }
+        }
+    }
+    merged.retain(|entry| entry.old != entry.new);
+    merged
+}
+
+/// Replace the journal at `path` with one recording `entries`, atomically and with the
+/// same mode.
+pub fn write_journal(path: &Path, entries: &[UndoEntry]) -> Result<()> {
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(format!(".{}", std::process::id()));
+    let partial = PathBuf::from(partial);
+    let mut contents = format!("{UNDO_MAGIC} {UNDO_FORMAT_VERSION}\n");
+    for entry in entries {
+        let path = hex(entry.path.as_os_str().as_bytes());
+        contents.push_str(&format!("{} {} {}\n", entry.old, entry.new, path));
+    }
+    let mut file = File::create(&partial)?;
+    file.set_permissions(fs::metadata(path)?.permissions())?;
+    file.write_all(contents.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&partial, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_journal_round_trip() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("undo.journal");
+        let journal = UndoJournal::create(&path)?;
+        let old = Owner { uid: 0, gid: 0 };
+        let new = Owner {
+            uid: 100000,
+            gid: 100000,
+        };
+        let odd = dir.path().join("with space\nand newline");
+        journal.record(&dir.path().join("a"), old, new)?;
+        journal.record(&odd, old, new)?;
+        journal.finish()?;
+        assert!(UndoJournal::create(&path).is_err());
+
+        let entries = read_journal(&path)?;
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[1].path, odd);
+        assert_eq!((entries[1].old, entries[1].new), (old, new));
+
+        // A torn last line is ignored
+        fs::write(&path, fs::read_to_string(&path)? + "0:0 1:1 2f")?;
+        assert_eq!(read_journal(&path)?.len(), 2);
+
+        fs::write(&path, format!("{UNDO_MAGIC} 99\n"))?;
+        assert!(matches!(
+            read_journal(&path),
+            Err(RustUtilsError::UnsupportedFormat(_))
+        ));
+        fs::write(&path, "rust-utils-partition-journal 1 key\n")?;
+        assert!(read_journal(&path).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_compact() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("undo.journal");
+        let owner = |id| Owner { uid: id, gid: id };
+        let journal = UndoJournal::create(&path)?;
+        for (name, old, new) in [
+            ("a", 0, 1000),
+            ("b", 5, 1005),
+            ("a", 1000, 2000),
+            ("b", 1005, 5),
+        ] {
+            journal.record(&dir.path().join(name), owner(old), owner(new))?;
+        }
+        journal.finish()?;
+
+        let entries = compact(&read_journal(&path)?);
+        write_journal(&path, &entries)?;
+        let entries = read_journal(&path)?;
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].path, dir.path().join("a"));
+        assert_eq!((entries[0].old, entries[0].new), (owner(0), owner(2000)));
+        Ok(())
+    }
+}
@@ -0,0 +1,31 @@
+//! Small helpers shared across modules.
+
+/// Lowercase hex encoding of `bytes`, as journals store paths and digests are shown.
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Decode the output of [`hex`], or `None` if `text` is not such output.
+pub fn unhex(text: &str) -> Option<Vec<u8>> {
+    if text.is_empty() || !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hex_round_trip() {
+        assert_eq!(hex(b"/srv\xff"), "2f737276ff");
+        assert_eq!(unhex("2f737276ff"), Some(b"/srv\xff".to_vec()));
+        assert_eq!(unhex(""), None);
+        assert_eq!(unhex("2f7"), None);
+        assert_eq!(unhex("zz"), None);
+    }
+}
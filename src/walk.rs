@@ -0,0 +1,483 @@
+//! Streaming tree walks that hand out entries as they are found instead of collecting the
+//! whole tree first, so memory use does not grow with the number of files.
+//!
+//! With more than one job, the top-level directories are listed first and their subtrees
+//! walked ahead on worker threads. Each subtree streams through a bounded channel and the
+//! subtrees are read in order, so entries come out in the order of a sequential walk.
+//!
+//! A bind mount inside the tree shows an inode the walk may also come to at its own path.
+//! [`Aliases`] watches the inodes at the roots of the mounts below the tree and skips every
+//! path to one but the first found, with everything below it.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use regex::RegexSet;
+use walkdir::{DirEntry, DirEntryExt, WalkDir};
+
+use crate::atomic;
+use crate::fs::should_exclude;
+use crate::iostats::{self, Syscall, Timed};
+use crate::marker::ResumeMarker;
+use crate::throttle;
+
+/// Entries a subtree walker may run ahead of the consumer.
+const SUBTREE_BUFFER: usize = 1024;
+
+/// What a [`TreeWalk`] leaves out.
+#[derive(Clone, Debug, Default)]
+pub struct WalkFilter {
+    /// Patterns of paths skipped together with everything below them
+    pub exclude: Vec<String>,
+    /// Regular expressions of paths relative to `base` skipped together with everything
+    /// below them
+    pub exclude_regex: Option<RegexSet>,
+    /// Directory `exclude_regex` paths are relative to
+    pub base: PathBuf,
+    /// Directories skipped together with everything below them
+    pub mountpoints: Vec<PathBuf>,
+    /// Device the walk stays on; entries of other filesystems are skipped together with
+    /// everything below them
+    pub device: Option<u64>,
+    /// Marker of directories finished by an earlier run, skipped together with everything
+    /// below them
+    pub resume: Option<ResumeMarker>,
+    /// Directories journaled as finished by an earlier run, skipped together with
+    /// everything below them
+    pub completed: HashSet<PathBuf>,
+    /// Inodes at the roots of mounts below the tree, walked at one path only
+    pub aliases: Option<Arc<Aliases>>,
+}
+
+impl WalkFilter {
+    /// Whether `path` is skipped by an exclude pattern or expression, as a mount point or
+    /// as the copy of a directory being remapped atomically, whatever is above it.
+    pub fn excludes(&self, path: &Path) -> bool {
+        atomic::is_copy(path)
+            || should_exclude(path, &self.exclude)
+            || self.exclude_regex.as_ref().is_some_and(|set| {
+                let relative = path.strip_prefix(&self.base).unwrap_or(path);
+                !relative.as_os_str().is_empty() && set.is_match(&relative.to_string_lossy())
+            })
+            || self.mountpoints.iter().any(|m| m == path)
+    }
+
+    fn keeps(&self, entry: &DirEntry) -> bool {
+        !self.excludes(entry.path())
+            && self.device.is_none_or(|device| {
+                iostats::timed(Syscall::Statx, || entry.metadata())
+                    .map_or(true, |metadata| metadata.dev() == device)
+            })
+            && !self
+                .resume
+                .as_ref()
+                .is_some_and(|marker| entry.file_type().is_dir() && marker.skips(entry.path()))
+            && (!entry.file_type().is_dir() || !self.completed.contains(entry.path()))
+            && !self
+                .aliases
+                .as_ref()
+                .is_some_and(|aliases| aliases.is_alias(entry))
+    }
+}
+
+/// A path the walk skipped as a second path to an inode.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Alias {
+    pub path: PathBuf,
+    /// The path the inode was walked at
+    pub original: PathBuf,
+}
+
+/// The inodes at the roots of the mounts below a tree, each of which the walk may come to
+/// at more than one path: through a bind mount of a directory of the tree, the same
+/// directory bound twice, or a bind mount's source inside the tree.
+#[derive(Debug, Default)]
+pub struct Aliases {
+    /// Mount points, the paths at which an inode may be found under another number
+    mountpoints: HashSet<PathBuf>,
+    /// Watched inode numbers, to stat only entries that may be one of them
+    inodes: HashSet<u64>,
+    /// Path each watched device and inode was first found at
+    first: Mutex<HashMap<(u64, u64), Option<PathBuf>>>,
+    found: Mutex<Vec<Alias>>,
+}
+
+impl Aliases {
+    /// Watch the inodes mounted at `mountpoints`, leaving out those that cannot be read.
+    pub fn watch(mountpoints: &[PathBuf]) -> Self {
+        let mut aliases = Self::default();
+        let first = aliases.first.get_mut().unwrap();
+        for mountpoint in mountpoints {
+            if let Ok(metadata) = std::fs::symlink_metadata(mountpoint) {
+                aliases.mountpoints.insert(mountpoint.clone());
+                aliases.inodes.insert(metadata.ino());
+                first.insert((metadata.dev(), metadata.ino()), None);
+            }
+        }
+        aliases
+    }
+
+    /// Whether `entry` is a path to a watched inode other than the one it was first found
+    /// at, which is then recorded.
+    fn is_alias(&self, entry: &DirEntry) -> bool {
+        // A mount point's directory entry has the number of the inode it covers
+        if !self.inodes.contains(&entry.ino()) && !self.mountpoints.contains(entry.path()) {
+            return false;
+        }
+        let Ok(metadata) = iostats::timed(Syscall::Statx, || entry.metadata()) else {
+            return false;
+        };
+        let mut first = self.first.lock().unwrap_or_else(|e| e.into_inner());
+        let Some(original) = first.get_mut(&(metadata.dev(), metadata.ino())) else {
+            return false;
+        };
+        match original {
+            None => {
+                *original = Some(entry.path().to_path_buf());
+                false
+            }
+            // Walked again, as by a later pass over the tree
+            Some(original) if original == entry.path() => false,
+            Some(original) => {
+                let alias = Alias {
+                    path: entry.path().to_path_buf(),
+                    original: original.clone(),
+                };
+                drop(first);
+                let mut found = self.found.lock().unwrap_or_else(|e| e.into_inner());
+                if !found.contains(&alias) {
+                    found.push(alias);
+                }
+                true
+            }
+        }
+    }
+
+    /// The mount points whose inodes are watched.
+    pub fn mountpoints(&self) -> impl Iterator<Item = &Path> {
+        self.mountpoints.iter().map(PathBuf::as_path)
+    }
+
+    /// The paths skipped so far, in the order they were found.
+    pub fn found(&self) -> Vec<Alias> {
+        self.found.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+}
+
+/// A walk of the tree below a root that does not follow symlinks, yielding the root first
+/// and each directory before its contents.
+///
+/// Walk errors, such as unreadable directories, are yielded in place of the entries they
+/// hide and the walk goes on. Entries below top-level directories walked on a worker
+/// thread report their depth from that directory.
+pub struct TreeWalk {
+    top: Box<dyn Iterator<Item = walkdir::Result<DirEntry>> + Send>,
+    /// Streams of the top-level subtrees still to be read, in walk order
+    subtrees: VecDeque<Receiver<walkdir::Result<DirEntry>>>,
+    current: Option<Receiver<walkdir::Result<DirEntry>>>,
+    workers: Vec<thread::JoinHandle<()>>,
+}
+
+impl TreeWalk {
+    /// Walk `root` down to `max_depth`, following `root` itself if it is a symlink and
+    /// `follow_root` is set, with the subtrees of its top-level directories walked on up to
+    /// `jobs` threads.
+    pub fn new(
+        root: PathBuf,
+        follow_root: bool,
+        max_depth: usize,
+        filter: WalkFilter,
+        jobs: usize,
+    ) -> Self {
+        let filter = Arc::new(filter);
+        let walk = |depth| {
+            let filter = Arc::clone(&filter);
+            Timed::new(
+                Syscall::Getdents,
+                WalkDir::new(&root)
+                    .follow_links(false)
+                    .follow_root_links(follow_root)
+                    .max_depth(depth)
+                    .into_iter()
+                    .filter_entry(move |entry| filter.keeps(entry)),
+            )
+        };
+        if jobs <= 1 || max_depth <= 1 {
+            return Self {
+                top: Box::new(walk(max_depth)),
+                subtrees: VecDeque::new(),
+                current: None,
+                workers: Vec::new(),
+            };
+        }
+
+        // The top level is a single directory listing, small enough to hold
+        let top: Vec<_> = walk(1).collect();
+        let mut queue = VecDeque::new();
+        let mut subtrees = VecDeque::new();
+        for entry in top.iter().flatten() {
+            if is_subtree(entry) {
+                let (sender, receiver) = mpsc::sync_channel(SUBTREE_BUFFER);
+                queue.push_back((entry.path().to_path_buf(), sender));
+                subtrees.push_back(receiver);
+            }
+        }
+
+        let queue = Arc::new(Mutex::new(queue));
+        let workers = (0..jobs.min(subtrees.len()))
+            .map(|_| {
+                let queue = Arc::clone(&queue);
+                let filter = Arc::clone(&filter);
+                let limiter = throttle::current();
+                thread::spawn(move || {
+                    let _throttle = throttle::install(limiter);
+                    walk_subtrees(&queue, &filter, max_depth - 1)
+                })
+            })
+            .collect();
+        Self {
+            top: Box::new(top.into_iter()),
+            subtrees,
+            current: None,
+            workers,
+        }
+    }
+}
+
+/// Whether `entry` is a top-level directory whose contents a worker walks.
+fn is_subtree(entry: &DirEntry) -> bool {
+    entry.depth() == 1 && entry.file_type().is_dir()
+}
+
+type SubtreeQueue = Mutex<VecDeque<(PathBuf, SyncSender<walkdir::Result<DirEntry>>)>>;
+
+/// Take subtrees off `queue` in order and stream their entries until none are left or the
+/// walk is dropped.
+fn walk_subtrees(queue: &SubtreeQueue, filter: &WalkFilter, max_depth: usize) {
+    loop {
+        let Some((dir, sender)) = queue.lock().expect("queue lock").pop_front() else {
+            return;
+        };
+        let entries = Timed::new(
+            Syscall::Getdents,
+            WalkDir::new(dir)
+                .follow_links(false)
+                .min_depth(1)
+                .max_depth(max_depth)
+                .into_iter()
+                .filter_entry(|entry| filter.keeps(entry)),
+        );
+        for entry in entries {
+            if sender.send(entry).is_err() {
+                // The walk was dropped
+                return;
+            }
+        }
+    }
+}
+
+impl Iterator for TreeWalk {
+    type Item = walkdir::Result<DirEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(subtree) = &self.current {
+            match subtree.recv() {
+                Ok(entry) => return Some(entry),
+                Err(_) => self.current = None,
+            }
+        }
+        let entry = self.top.next()?;
+        if !self.workers.is_empty() && entry.as_ref().is_ok_and(is_subtree) {
+            self.current = self.subtrees.pop_front();
+        }
+        Some(entry)
+    }
+}
+
+impl Drop for TreeWalk {
+    fn drop(&mut self) {
+        // Workers blocked on a full stream stop once it is closed
+        self.current = None;
+        self.subtrees.clear();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    fn paths(walk: TreeWalk) -> Vec<PathBuf> {
+        walk.map(|entry| entry.unwrap().into_path()).collect()
+    }
+
+    #[test]
+    fn test_parallel_walk_keeps_order() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let dir = TempDir::new()?;
+        for sub in ["a/b/c", "a/d", "e", "f/g", "cache/h"] {
+            fs::create_dir_all(dir.path().join(sub))?;
+            fs::write(dir.path().join(sub).join("file"), "")?;
+        }
+        fs::write(dir.path().join("top"), "")?;
+        let filter = WalkFilter {
+            exclude: vec!["cache".to_string()],
+            mountpoints: vec![dir.path().join("f/g")],
+            ..Default::default()
+        };
+
+        let root = dir.path().to_path_buf();
+        let sequential = paths(TreeWalk::new(
+            root.clone(),
+            true,
+            usize::MAX,
+            filter.clone(),
+            1,
+        ));
+        assert_eq!(sequential.len(), 11);
+        assert!(!sequential.iter().any(|path| path.ends_with("cache")));
+        assert!(!sequential
+            .iter()
+            .any(|path| path.starts_with(root.join("f/g"))));
+        for jobs in [2, 8] {
+            let parallel = TreeWalk::new(root.clone(), true, usize::MAX, filter.clone(), jobs);
+            assert_eq!(paths(parallel), sequential);
+        }
+        let shallow = TreeWalk::new(root.clone(), true, 2, filter, 4);
+        assert_eq!(paths(shallow).len(), 8);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_walk_stays_on_device() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let dir = TempDir::new()?;
+        fs::create_dir(dir.path().join("sub"))?;
+        fs::write(dir.path().join("sub/file"), "")?;
+        let root = dir.path().to_path_buf();
+        let on = |device| WalkFilter {
+            device: Some(device),
+            ..Default::default()
+        };
+
+        let device = fs::metadata(&root)?.dev();
+        assert_eq!(
+            paths(TreeWalk::new(root.clone(), true, usize::MAX, on(device), 2)).len(),
+            3
+        );
+        // Nothing of the tree is on another device
+        let other = TreeWalk::new(root, true, usize::MAX, on(device.wrapping_add(1)), 2);
+        assert!(paths(other).is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_aliases() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        use nix::mount::{mount, umount2, MntFlags, MsFlags};
+
+        let dir = TempDir::new()?;
+        let root = dir.path().to_path_buf();
+        for sub in ["data", "again", "other"] {
+            fs::create_dir(root.join(sub))?;
+        }
+        fs::write(root.join("data/file"), "")?;
+        let bind = |source: &str, target: &str| {
+            mount(
+                Some(&root.join(source)),
+                &root.join(target),
+                None::<&str>,
+                MsFlags::MS_BIND,
+                None::<&str>,
+            )
+        };
+        // Mounting needs CAP_SYS_ADMIN
+        if bind("data", "again").is_err() {
+            return Ok(());
+        }
+        let walked = |jobs| {
+            let aliases = Arc::new(Aliases::watch(&[root.join("again")]));
+            let filter = WalkFilter {
+                aliases: Some(Arc::clone(&aliases)),
+                ..Default::default()
+            };
+            let walked = paths(TreeWalk::new(root.clone(), true, usize::MAX, filter, jobs));
+            (walked, aliases.found())
+        };
+        let results = [walked(1), walked(4)];
+        umount2(&root.join("again"), MntFlags::MNT_DETACH)?;
+
+        for (walked, found) in results {
+            // The root, other, and data or again with its file
+            assert_eq!(walked.len(), 4, "{walked:?}");
+            assert_eq!(found.len(), 1);
+            let alias = &found[0];
+            assert!(!walked.contains(&alias.path));
+            assert!(walked.contains(&alias.original));
+            let mut pair = [alias.path.clone(), alias.original.clone()];
+            pair.sort();
+            assert_eq!(pair, [root.join("again"), root.join("data")]);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_walk_exclude_regex() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let dir = TempDir::new()?;
+        for sub in [
+            "cache/v1.2/a",
+            "cache/v1.10",
+            "cache/current",
+            "backup-20240101/b",
+        ] {
+            fs::create_dir_all(dir.path().join(sub))?;
+        }
+        let filter = WalkFilter {
+            exclude_regex: Some(RegexSet::new([r"^cache/v[0-9.]+$", r"^backup-\d{8}$"])?),
+            base: dir.path().to_path_buf(),
+            ..Default::default()
+        };
+
+        // Matches are relative to the base and skip everything below them
+        let walk = TreeWalk::new(dir.path().to_path_buf(), true, usize::MAX, filter, 2);
+        let mut found: Vec<_> = paths(walk)
+            .into_iter()
+            .map(|path| path.strip_prefix(dir.path()).unwrap().to_path_buf())
+            .collect();
+        found.sort();
+        assert_eq!(found, ["", "cache", "cache/current"].map(PathBuf::from));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_dropped_walk_stops_workers() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let dir = TempDir::new()?;
+        for sub in 0..4 {
+            let sub = dir.path().join(sub.to_string());
+            fs::create_dir(&sub)?;
+            for file in 0..SUBTREE_BUFFER + 10 {
+                fs::write(sub.join(file.to_string()), "")?;
+            }
+        }
+        let mut walk = TreeWalk::new(
+            dir.path().to_path_buf(),
+            true,
+            usize::MAX,
+            WalkFilter::default(),
+            2,
+        );
+        assert!(walk.next().is_some());
+        // Joins the workers, which must not stay blocked on their full streams
+        drop(walk);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,160 @@
+//! Extended attributes kept across ownership changes.
+//!
+//! The kernel drops `security.capability` when a file changes owner, and some filesystems
+//! and security modules drop or rewrite other attributes too. A [`Snapshot`] taken before
+//! the chown puts back whatever went missing or changed.
+
+use std::ffi::OsString;
+use std::io;
+use std::path::Path;
+
+use nix::libc;
+
+/// File capability xattr.
+pub const CAPABILITY_XATTR: &str = "security.capability";
+const VFS_CAP_REVISION_MASK: u32 = 0xFF00_0000;
+const VFS_CAP_REVISION_2: u32 = 0x0200_0000;
+const VFS_CAP_REVISION_3: u32 = 0x0300_0000;
+/// Size of a revision 2 capability; revision 3 appends a le32 namespace root UID.
+const XATTR_CAPS_SZ_2: usize = 20;
+
+fn capability_revision(value: &[u8]) -> Option<u32> {
+    let magic = u32::from_le_bytes(value.get(..4)?.try_into().unwrap());
+    Some(magic & VFS_CAP_REVISION_MASK)
+}
+
+/// Downgrade a revision 3 (namespaced) file capability to revision 2, dropping the
+/// host-specific root UID it is bound to.
+pub fn capability_without_rootid(mut value: Vec<u8>) -> Vec<u8> {
+    if value.len() <= XATTR_CAPS_SZ_2 || capability_revision(&value) != Some(VFS_CAP_REVISION_3) {
+        return value;
+    }
+    let magic = u32::from_le_bytes(value[..4].try_into().unwrap());
+    let magic = (magic & !VFS_CAP_REVISION_MASK) | VFS_CAP_REVISION_2;
+    value[..4].copy_from_slice(&magic.to_le_bytes());
+    value.truncate(XATTR_CAPS_SZ_2);
+    value
+}
+
+/// Pass the root UID of a revision 3 (namespaced) file capability through `map`, so the
+/// capability keeps applying to the namespace whose root owns the tree.
+pub fn capability_with_rootid(mut value: Vec<u8>, map: impl Fn(u32) -> u32) -> Vec<u8> {
+    if value.len() < XATTR_CAPS_SZ_2 + 4 || capability_revision(&value) != Some(VFS_CAP_REVISION_3)
+    {
+        return value;
+    }
+    let rootid = &mut value[XATTR_CAPS_SZ_2..XATTR_CAPS_SZ_2 + 4];
+    let mapped = map(u32::from_le_bytes((&*rootid).try_into().unwrap()));
+    rootid.copy_from_slice(&mapped.to_le_bytes());
+    value
+}
+
+/// Extended attributes of one entry, as they should be after its ownership change.
+#[derive(Clone, Debug, Default)]
+pub struct Snapshot {
+    attrs: Vec<(OsString, Vec<u8>)>,
+}
+
+impl Snapshot {
+    /// Read every extended attribute of `path`, without following a symlink. Filesystems
+    /// without xattrs give an empty snapshot.
+    pub fn capture(path: &Path) -> io::Result<Self> {
+        let names = match xattr::list(path) {
+            Ok(names) => names,
+            Err(e) if e.raw_os_error() == Some(libc::ENOTSUP) => return Ok(Self::default()),
+            Err(e) => return Err(e),
+        };
+        let mut attrs = Vec::new();
+        for name in names {
+            if let Some(value) = xattr::get(path, &name)? {
+                attrs.push((name, value));
+            }
+        }
+        Ok(Self { attrs })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.attrs.is_empty()
+    }
+
+    /// Map the root UID of a namespaced file capability, as the owner it belongs with is.
+    pub fn map_capability_rootid(&mut self, map: impl Fn(u32) -> u32) {
+        for (name, value) in &mut self.attrs {
+            if name == CAPABILITY_XATTR {
+                *value = capability_with_rootid(std::mem::take(value), &map);
+            }
+        }
+    }
+
+    /// Set again every attribute that `path` lost or that now has another value, and
+    /// return the names of those set.
+    pub fn restore(&self, path: &Path) -> io::Result<Vec<OsString>> {
+        let mut restored = Vec::new();
+        for (name, value) in &self.attrs {
+            if xattr::get(path, name)?.as_ref() != Some(value) {
+                xattr::set(path, name, value)?;
+                restored.push(name.clone());
+            }
+        }
+        Ok(restored)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    fn capability_v3(rootid: u32) -> Vec<u8> {
+        let mut value = (VFS_CAP_REVISION_3 | 1).to_le_bytes().to_vec();
+        value.extend_from_slice(&[0x00, 0x20, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        value.extend_from_slice(&rootid.to_le_bytes());
+        value
+    }
+
+    #[test]
+    fn test_capability_without_rootid() {
+        let v3 = capability_v3(100000);
+        let v2 = capability_without_rootid(v3.clone());
+        assert_eq!(v2.len(), XATTR_CAPS_SZ_2);
+        assert_eq!(&v2[..4], &(VFS_CAP_REVISION_2 | 1).to_le_bytes());
+        assert_eq!(&v2[4..], &v3[4..XATTR_CAPS_SZ_2]);
+
+        // Revision 2 values pass through untouched
+        assert_eq!(capability_without_rootid(v2.clone()), v2);
+    }
+
+    #[test]
+    fn test_capability_with_rootid() {
+        let shift = |id: u32| id + 100000;
+        assert_eq!(
+            capability_with_rootid(capability_v3(100000), shift),
+            capability_v3(200000)
+        );
+
+        let v2 = capability_without_rootid(capability_v3(0));
+        assert_eq!(capability_with_rootid(v2.clone(), shift), v2);
+        assert_eq!(capability_with_rootid(vec![1, 2], shift), [1, 2]);
+    }
+
+    #[test]
+    fn test_snapshot_restore() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let dir = TempDir::new()?;
+        let file = dir.path().join("file");
+        fs::write(&file, "")?;
+        if xattr::set(&file, "user.kept", b"1").is_err() {
+            // No user xattrs on the test filesystem
+            return Ok(());
+        }
+        xattr::set(&file, "user.lost", b"2")?;
+
+        let snapshot = Snapshot::capture(&file)?;
+        xattr::remove(&file, "user.lost")?;
+        assert_eq!(snapshot.restore(&file)?, [OsString::from("user.lost")]);
+        assert_eq!(xattr::get(&file, "user.lost")?, Some(b"2".to_vec()));
+        assert!(snapshot.restore(&file)?.is_empty());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,290 @@
+//! Disposable container rootfs for integration tests and benchmarks.
+//!
+//! [`Rootfs::build`] lays out a small busybox-style tree with the ownership a real
+//! container has: mostly root, with system accounts (`daemon`, `tty`, `shadow`, `adm`),
+//! service users (`www-data`, `mysql`), a login user and `nobody` at the top of the range,
+//! plus device nodes, a FIFO, a socket, symlinks, hard links and a setuid binary. Every ID
+//! is shifted by the base the tree is built at, as if it belonged to a container mapped
+//! there.
+#![allow(dead_code)]
+
+use std::collections::BTreeMap;
+use std::fs::{self, Permissions};
+use std::io;
+use std::os::unix::fs::{lchown, symlink, MetadataExt, PermissionsExt};
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+
+use nix::sys::stat::{makedev, mknod, Mode, SFlag};
+use nix::unistd::mkfifo;
+use walkdir::WalkDir;
+
+pub const ROOT: u32 = 0;
+pub const DAEMON: u32 = 1;
+pub const ADM: u32 = 4;
+pub const TTY: u32 = 5;
+pub const DISK: u32 = 6;
+pub const WWW_DATA: u32 = 33;
+pub const SHADOW: u32 = 42;
+pub const MYSQL: u32 = 101;
+pub const ALICE: u32 = 1000;
+pub const NOBODY: u32 = 65534;
+
+const PASSWD: &str = "\
+root:x:0:0:root:/root:/bin/sh
+daemon:x:1:1:daemon:/usr/sbin:/usr/sbin/nologin
+www-data:x:33:33:www-data:/var/www:/usr/sbin/nologin
+mysql:x:101:101:MySQL Server:/nonexistent:/bin/false
+alice:x:1000:1000:Alice:/home/alice:/bin/sh
+nobody:x:65534:65534:nobody:/nonexistent:/usr/sbin/nologin
+";
+
+const GROUP: &str = "\
+root:x:0:
+daemon:x:1:
+adm:x:4:
+tty:x:5:
+disk:x:6:
+www-data:x:33:
+shadow:x:42:
+mysql:x:101:
+alice:x:1000:
+nogroup:x:65534:
+";
+
+/// What an entry of the fixture is.
+#[derive(Clone, Copy, Debug)]
+enum Kind {
+    Directory,
+    File(&'static str),
+    Symlink(&'static str),
+    /// Another name for an earlier entry, sharing its owner
+    HardLink(&'static str),
+    CharDevice(u64, u64),
+    BlockDevice(u64, u64),
+    Fifo,
+    Socket,
+}
+
+/// Path relative to the root, kind, unshifted UID and GID, and mode.
+const ENTRIES: &[(&str, Kind, u32, u32, u32)] = &[
+    ("bin", Kind::Directory, ROOT, ROOT, 0o755),
+    ("bin/busybox", Kind::File("#!busybox\n"), ROOT, ROOT, 0o755),
+    ("bin/sh", Kind::Symlink("busybox"), ROOT, ROOT, 0o777),
+    ("bin/ls", Kind::Symlink("busybox"), ROOT, ROOT, 0o777),
+    ("dev", Kind::Directory, ROOT, ROOT, 0o755),
+    ("dev/null", Kind::CharDevice(1, 3), ROOT, ROOT, 0o666),
+    ("dev/zero", Kind::CharDevice(1, 5), ROOT, ROOT, 0o666),
+    ("dev/tty", Kind::CharDevice(5, 0), ROOT, TTY, 0o666),
+    ("dev/console", Kind::CharDevice(5, 1), ROOT, TTY, 0o620),
+    ("dev/sda", Kind::BlockDevice(8, 0), ROOT, DISK, 0o660),
+    ("etc", Kind::Directory, ROOT, ROOT, 0o755),
+    ("etc/passwd", Kind::File(PASSWD), ROOT, ROOT, 0o644),
+    ("etc/group", Kind::File(GROUP), ROOT, ROOT, 0o644),
+    (
+        "etc/shadow",
+        Kind::File("root:*:19000:0:99999:7:::\n"),
+        ROOT,
+        SHADOW,
+        0o640,
+    ),
+    ("home", Kind::Directory, ROOT, ROOT, 0o755),
+    ("home/alice", Kind::Directory, ALICE, ALICE, 0o750),
+    (
+        "home/alice/.profile",
+        Kind::File("export PATH=/bin:/usr/bin\n"),
+        ALICE,
+        ALICE,
+        0o644,
+    ),
+    ("run", Kind::Directory, ROOT, ROOT, 0o755),
+    ("run/initctl", Kind::Fifo, ROOT, ROOT, 0o600),
+    ("run/mysqld", Kind::Directory, MYSQL, MYSQL, 0o755),
+    ("run/mysqld/mysqld.sock", Kind::Socket, MYSQL, MYSQL, 0o777),
+    ("tmp", Kind::Directory, ROOT, ROOT, 0o1777),
+    ("usr", Kind::Directory, ROOT, ROOT, 0o755),
+    ("usr/bin", Kind::Directory, ROOT, ROOT, 0o755),
+    (
+        "usr/bin/vi",
+        Kind::HardLink("bin/busybox"),
+        ROOT,
+        ROOT,
+        0o755,
+    ),
+    (
+        "usr/bin/passwd",
+        Kind::File("#!passwd\n"),
+        ROOT,
+        ROOT,
+        0o4755,
+    ),
+    ("usr/sbin", Kind::Directory, ROOT, ROOT, 0o755),
+    ("usr/sbin/crond", Kind::File("#!crond\n"), ROOT, ROOT, 0o755),
+    ("var", Kind::Directory, ROOT, ROOT, 0o755),
+    ("var/lib", Kind::Directory, ROOT, ROOT, 0o755),
+    ("var/lib/mysql", Kind::Directory, MYSQL, MYSQL, 0o700),
+    (
+        "var/lib/mysql/ibdata1",
+        Kind::File("ibdata"),
+        MYSQL,
+        MYSQL,
+        0o660,
+    ),
+    ("var/lib/misc", Kind::Directory, DAEMON, DAEMON, 0o755),
+    (
+        "var/lib/misc/nobody.lock",
+        Kind::File(""),
+        NOBODY,
+        NOBODY,
+        0o600,
+    ),
+    ("var/log", Kind::Directory, ROOT, ROOT, 0o755),
+    ("var/log/apache2", Kind::Directory, ROOT, ADM, 0o750),
+    (
+        "var/log/apache2/access.log",
+        Kind::File("GET / 200\n"),
+        ROOT,
+        ADM,
+        0o640,
+    ),
+    ("var/www", Kind::Directory, ROOT, ROOT, 0o755),
+    ("var/www/html", Kind::Directory, WWW_DATA, WWW_DATA, 0o755),
+    (
+        "var/www/html/index.html",
+        Kind::File("<html></html>\n"),
+        WWW_DATA,
+        WWW_DATA,
+        0o644,
+    ),
+    (
+        "var/www/html/current",
+        Kind::Symlink("index.html"),
+        WWW_DATA,
+        WWW_DATA,
+        0o777,
+    ),
+];
+
+/// A rootfs built in a directory the caller owns, such as a `TempDir`.
+pub struct Rootfs {
+    root: PathBuf,
+    base: u32,
+    /// Whether device nodes could be created, which needs `CAP_MKNOD`
+    devices: bool,
+    /// Files added by [`Rootfs::with_files`]
+    extra_files: usize,
+}
+
+impl Rootfs {
+    /// Build the fixture at `root`, which must not exist yet, with every ID shifted by `base`.
+    pub fn build(root: &Path, base: u32) -> io::Result<Self> {
+        fs::create_dir(root)?;
+        lchown(root, Some(base), Some(base))?;
+        let mut devices = true;
+        for &(relative, kind, uid, gid, mode) in ENTRIES {
+            let path = root.join(relative);
+            match kind {
+                Kind::Directory => fs::create_dir(&path)?,
+                Kind::File(contents) => fs::write(&path, contents)?,
+                Kind::Symlink(target) => symlink(target, &path)?,
+                Kind::HardLink(existing) => fs::hard_link(root.join(existing), &path)?,
+                Kind::CharDevice(major, minor) | Kind::BlockDevice(major, minor) => {
+                    let flag = match kind {
+                        Kind::CharDevice(..) => SFlag::S_IFCHR,
+                        _ => SFlag::S_IFBLK,
+                    };
+                    let node = Mode::from_bits_truncate(mode);
+                    match mknod(&path, flag, node, makedev(major, minor)) {
+                        Ok(()) => {}
+                        Err(nix::errno::Errno::EPERM) => {
+                            devices = false;
+                            continue;
+                        }
+                        Err(errno) => return Err(errno.into()),
+                    }
+                }
+                Kind::Fifo => mkfifo(&path, Mode::from_bits_truncate(mode))?,
+                Kind::Socket => drop(UnixListener::bind(&path)?),
+            }
+            lchown(&path, Some(base + uid), Some(base + gid))?;
+            // After the chown, which clears the setuid bit
+            if !matches!(kind, Kind::Symlink(_) | Kind::HardLink(_)) {
+                fs::set_permissions(&path, Permissions::from_mode(mode))?;
+            }
+        }
+        Ok(Self {
+            root: root.to_path_buf(),
+            base,
+            devices,
+            extra_files: 0,
+        })
+    }
+
+    /// Add `count` root-owned files under `usr/share/doc`, 100 per package directory, to
+    /// give benchmarks a tree of realistic size.
+    pub fn with_files(mut self, count: usize) -> io::Result<Self> {
+        let doc = self.root.join("usr/share/doc");
+        for index in self.extra_files..self.extra_files + count {
+            let package = doc.join(format!("pkg{}", index / 100));
+            if !package.exists() {
+                fs::create_dir_all(&package)?;
+            }
+            fs::write(package.join(format!("file{index}")), "documentation\n")?;
+        }
+        self.extra_files += count;
+        for entry in WalkDir::new(self.root.join("usr/share")) {
+            let path = entry?.into_path();
+            lchown(&path, Some(self.base), Some(self.base))?;
+        }
+        Ok(self)
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn base(&self) -> u32 {
+        self.base
+    }
+
+    /// Whether the device nodes are part of the tree.
+    pub fn has_devices(&self) -> bool {
+        self.devices
+    }
+
+    /// Owners the fixture's entries would have if built at `base`, by path relative to the
+    /// root (the root itself being the empty path).
+    pub fn expected(&self, base: u32) -> BTreeMap<PathBuf, (u32, u32)> {
+        let mut owners = BTreeMap::new();
+        owners.insert(PathBuf::new(), (base, base));
+        for &(relative, kind, uid, gid, _) in ENTRIES {
+            if matches!(kind, Kind::CharDevice(..) | Kind::BlockDevice(..)) && !self.devices {
+                continue;
+            }
+            owners.insert(PathBuf::from(relative), (base + uid, base + gid));
+        }
+        if self.extra_files > 0 {
+            for dir in ["usr/share", "usr/share/doc"] {
+                owners.insert(PathBuf::from(dir), (base, base));
+            }
+            for index in 0..self.extra_files {
+                let package = PathBuf::from(format!("usr/share/doc/pkg{}", index / 100));
+                owners.insert(package.join(format!("file{index}")), (base, base));
+                owners.insert(package, (base, base));
+            }
+        }
+        owners
+    }
+}
+
+/// Owners of every entry below `root` as found on disk, by path relative to the root.
+pub fn owners(root: &Path) -> io::Result<BTreeMap<PathBuf, (u32, u32)>> {
+    let mut owners = BTreeMap::new();
+    for entry in WalkDir::new(root) {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        let relative = entry.path().strip_prefix(root).unwrap_or(entry.path());
+        owners.insert(relative.to_path_buf(), (metadata.uid(), metadata.gid()));
+    }
+    Ok(owners)
+}
@@ -1,8 +1,14 @@
 use assert_cmd::Command;
 use predicates::prelude::*;
 use std::fs::{self, File};
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
 use tempfile::TempDir;
 
+mod common;
+
+use common::{owners, Rootfs};
+
 #[test]
 fn test_cli_help() {
     let mut cmd = Command::cargo_bin("rust-utils").unwrap();
@@ -23,6 +29,31 @@ fn test_cli_version() {
         .stdout(predicate::str::contains("rust-utils"));
 }
 
+#[test]
+fn test_cli_help_json() -> Result<(), Box<dyn std::error::Error>> {
+    // Accepted without a command, and after one whose required arguments are missing
+    for args in [&["--help-json"][..], &["remap", "--help-json"]] {
+        let mut cmd = Command::cargo_bin("rust-utils").unwrap();
+        let output = cmd
+            .args(args)
+            .assert()
+            .success()
+            .get_output()
+            .stdout
+            .clone();
+        let tree: serde_json::Value = serde_json::from_slice(&output)?;
+        assert_eq!(tree["name"], "rust-utils");
+        assert_eq!(tree["version"], env!("CARGO_PKG_VERSION"));
+        assert!(tree["subcommands"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|command| command["name"] == "idmap"));
+    }
+
+    Ok(())
+}
+
 #[test]
 fn test_remap_help() {
     let mut cmd = Command::cargo_bin("rust-utils").unwrap();
@@ -140,133 +171,2776 @@ fn test_remap_verbose_output() -> Result<(), Box<dyn std::error::Error>> {
 }
 
 #[test]
-fn test_remap_with_exclusions() -> Result<(), Box<dyn std::error::Error>> {
+fn test_remap_undo() -> Result<(), Box<dyn std::error::Error>> {
     let temp_dir = TempDir::new()?;
+    let tree = temp_dir.path().join("rootfs");
+    fs::create_dir(&tree)?;
+    File::create(tree.join("file"))?;
+    let journal = temp_dir.path().join("undo.journal");
 
-    // Create files that should be excluded
-    File::create(temp_dir.path().join("app.log"))?;
-    let tmp_dir = temp_dir.path().join("tmp");
-    fs::create_dir(&tmp_dir)?;
-    File::create(tmp_dir.join("temp.txt"))?;
-    File::create(temp_dir.path().join("regular.txt"))?;
+    let mut cmd = Command::cargo_bin("rust-utils").unwrap();
+    cmd.args([
+        "remap",
+        tree.to_str().unwrap(),
+        "--from-base",
+        "0",
+        "--to-base",
+        "100000",
+        "--undo-journal",
+        journal.to_str().unwrap(),
+    ])
+    .assert()
+    .success()
+    .stdout(predicate::str::contains("RESULT status=ok changed=2 "));
+    assert_eq!(fs::metadata(tree.join("file"))?.uid(), 100000);
 
     let mut cmd = Command::cargo_bin("rust-utils").unwrap();
-    cmd.env("RUST_LOG", "info")
+    cmd.args(["remap", "undo", journal.to_str().unwrap()])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("RESULT status=ok changed=2 "));
+    assert_eq!(fs::metadata(tree.join("file"))?.uid(), 0);
+    assert_eq!(fs::metadata(&tree)?.gid(), 0);
+
+    Ok(())
+}
+
+#[test]
+fn test_remap_undo_expect_clean() -> Result<(), Box<dyn std::error::Error>> {
+    let temp_dir = TempDir::new()?;
+    let tree = temp_dir.path().join("rootfs");
+    fs::create_dir(&tree)?;
+    File::create(tree.join("file"))?;
+    let journal = temp_dir.path().join("undo.journal");
+
+    let mut cmd = Command::cargo_bin("rust-utils")?;
+    cmd.arg("remap")
+        .arg(&tree)
+        .args(["--from-base", "0", "--to-base", "100000", "--undo-journal"])
+        .arg(&journal)
+        .assert()
+        .success();
+    std::os::unix::fs::lchown(tree.join("file"), Some(7), Some(7))?;
+
+    // Nothing is restored once one entry changed since the remap
+    let mut cmd = Command::cargo_bin("rust-utils")?;
+    cmd.args(["remap", "undo", "--expect-clean"])
+        .arg(&journal)
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "is owned by 7:7, not 100000:100000 as remapped",
+        ));
+    assert_eq!(fs::metadata(&tree)?.uid(), 100000);
+
+    Ok(())
+}
+
+#[test]
+fn test_remap_output_json() -> Result<(), Box<dyn std::error::Error>> {
+    let temp_dir = TempDir::new()?;
+    File::create(temp_dir.path().join("in-range"))?;
+    File::create(temp_dir.path().join("outside"))?;
+    std::os::unix::fs::lchown(temp_dir.path().join("outside"), Some(5), Some(5))?;
+
+    let mut cmd = Command::cargo_bin("rust-utils").unwrap();
+    let output = cmd
+        .env("RUST_LOG", "info")
         .args([
             "remap",
             temp_dir.path().to_str().unwrap(),
             "--from-base",
-            "100000",
+            "0",
             "--to-base",
-            "50000000",
+            "100000",
+            "--range-size",
+            "1",
             "--dry-run",
-            "--verbose",
-            "--exclude",
-            "*.log",
-            "--exclude",
-            "tmp/*",
+            "--output",
+            "json",
         ])
         .assert()
         .success()
-        .stdout(predicate::str::contains("Starting UID/GID remapping"));
+        .stderr(predicate::str::contains("From range: 0-0"))
+        .get_output()
+        .stdout
+        .clone();
+
+    // Logs go to stderr, leaving only the summary on stdout
+    let summary: serde_json::Value = serde_json::from_slice(&output)?;
+    assert_eq!(summary["command"], "remap");
+    assert_eq!(summary["counts"]["entries"], 3);
+    assert_eq!(summary["counts"]["remapped"], 2);
+    assert_eq!(summary["counts"]["skipped"], 1);
+    assert_eq!(summary["errors"], serde_json::json!([]));
+    assert_eq!(
+        summary["ranges"],
+        serde_json::json!([
+            {"kind": "uid", "from": 0, "to": 100000, "count": 1},
+            {"kind": "gid", "from": 0, "to": 100000, "count": 1}
+        ])
+    );
 
     Ok(())
 }
 
 #[test]
-fn test_remap_uid_only() -> Result<(), Box<dyn std::error::Error>> {
+fn test_remap_rootfs_fixture() -> Result<(), Box<dyn std::error::Error>> {
     let temp_dir = TempDir::new()?;
-    File::create(temp_dir.path().join("test.txt"))?;
+    let rootfs = Rootfs::build(&temp_dir.path().join("rootfs"), 0)?;
+    assert_eq!(owners(rootfs.root())?, rootfs.expected(0));
+    let journal = temp_dir.path().join("undo.jsonl");
 
-    let mut cmd = Command::cargo_bin("rust-utils").unwrap();
-    cmd.args([
-        "remap",
-        temp_dir.path().to_str().unwrap(),
-        "--from-base",
-        "100000",
-        "--to-base",
-        "50000000",
-        "--dry-run",
-        "--uid-only",
-    ])
-    .assert()
-    .success();
+    let mut cmd = Command::cargo_bin("rust-utils")?;
+    cmd.arg("remap")
+        .arg(rootfs.root())
+        .args(["--from-base", "0", "--to-base", "100000", "--undo-journal"])
+        .arg(&journal)
+        .assert()
+        .success();
+    assert_eq!(owners(rootfs.root())?, rootfs.expected(100000));
+    // The setuid bit the chown cleared is restored
+    let passwd = fs::metadata(rootfs.root().join("usr/bin/passwd"))?;
+    assert_eq!(passwd.mode() & 0o7777, 0o4755);
+
+    let copy = temp_dir.path().join("copy");
+    let mut cmd = Command::cargo_bin("rust-utils")?;
+    cmd.arg("copy")
+        .arg(rootfs.root())
+        .arg(&copy)
+        .args(["--map", "100000:200000:65536"])
+        .assert()
+        .success();
+    assert_eq!(owners(&copy)?, rootfs.expected(200000));
+
+    let mut cmd = Command::cargo_bin("rust-utils")?;
+    cmd.args(["remap", "undo"]).arg(&journal).assert().success();
+    assert_eq!(owners(rootfs.root())?, rootfs.expected(0));
+    let passwd = fs::metadata(rootfs.root().join("usr/bin/passwd"))?;
+    assert_eq!(passwd.mode() & 0o7777, 0o4755);
 
     Ok(())
 }
 
 #[test]
-fn test_remap_gid_only() -> Result<(), Box<dyn std::error::Error>> {
+fn test_remap_include() -> Result<(), Box<dyn std::error::Error>> {
     let temp_dir = TempDir::new()?;
-    File::create(temp_dir.path().join("test.txt"))?;
+    let rootfs = Rootfs::build(&temp_dir.path().join("rootfs"), 0)?;
 
-    let mut cmd = Command::cargo_bin("rust-utils").unwrap();
-    cmd.args([
-        "remap",
-        temp_dir.path().to_str().unwrap(),
-        "--from-base",
-        "100000",
-        "--to-base",
-        "50000000",
-        "--dry-run",
-        "--gid-only",
-    ])
-    .assert()
-    .success();
+    let mut cmd = Command::cargo_bin("rust-utils")?;
+    cmd.arg("remap")
+        .arg(rootfs.root())
+        .args(["--from-base", "0", "--to-base", "100000"])
+        .args(["--include", "home/*", "--include", "var/lib/*"])
+        .args(["--exclude", "mysql"])
+        .assert()
+        .success();
+
+    // Included paths and everything below them change, except what is excluded
+    let mut expected = rootfs.expected(0);
+    let remapped = rootfs.expected(100000);
+    for path in [
+        "home/alice",
+        "home/alice/.profile",
+        "var/lib/misc",
+        "var/lib/misc/nobody.lock",
+    ] {
+        expected.insert(path.into(), remapped[Path::new(path)]);
+    }
+    assert_eq!(owners(rootfs.root())?, expected);
 
     Ok(())
 }
 
 #[test]
-fn test_remap_custom_range_size() -> Result<(), Box<dyn std::error::Error>> {
+fn test_remap_exclude_regex() -> Result<(), Box<dyn std::error::Error>> {
     let temp_dir = TempDir::new()?;
-    File::create(temp_dir.path().join("test.txt"))?;
+    let rootfs = Rootfs::build(&temp_dir.path().join("rootfs"), 0)?;
 
-    let mut cmd = Command::cargo_bin("rust-utils").unwrap();
-    cmd.env("RUST_LOG", "info")
+    let mut cmd = Command::cargo_bin("rust-utils")?;
+    cmd.arg("remap")
+        .arg(rootfs.root())
+        .args(["--from-base", "0", "--to-base", "100000"])
+        .args(["--exclude-regex", "^var/lib/my[a-z]+$"])
+        .args(["--exclude-regex", r"^usr/share/doc/pkg[0-9]+$"])
+        .assert()
+        .success();
+
+    // Matching paths and everything below them keep their owners
+    let original = rootfs.expected(0);
+    let mut expected = rootfs.expected(100000);
+    for (path, owner) in expected.iter_mut() {
+        let path = path.to_string_lossy();
+        if path.starts_with("var/lib/mysql") || path.starts_with("usr/share/doc/pkg") {
+            *owner = original[Path::new(&*path)];
+        }
+    }
+    assert_eq!(owners(rootfs.root())?, expected);
+
+    let mut cmd = Command::cargo_bin("rust-utils")?;
+    cmd.arg("remap")
+        .arg(rootfs.root())
+        .args(["--from-base", "0", "--to-base", "100000"])
+        .args(["--exclude-regex", "pkg[0-9"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("invalid --exclude-regex"));
+
+    Ok(())
+}
+
+#[test]
+fn test_remap_resume_by_xattr() -> Result<(), Box<dyn std::error::Error>> {
+    let temp_dir = TempDir::new()?;
+    let rootfs = Rootfs::build(&temp_dir.path().join("rootfs"), 0)?;
+    let report = temp_dir.path().join("report.json");
+
+    // Marked as finished by an interrupted run of the same remap
+    let mysql = rootfs.root().join("var/lib/mysql");
+    if let Err(e) = xattr::set(&mysql, "user.rust-utils.remapped", b"0:100000:65536") {
+        // No user extended attributes on this filesystem
+        assert_eq!(e.raw_os_error(), Some(95));
+        return Ok(());
+    }
+
+    let mut cmd = Command::cargo_bin("rust-utils")?;
+    cmd.args(["--report", report.to_str().unwrap(), "remap"])
+        .arg(rootfs.root())
+        .args(["--from-base", "0", "--to-base", "100000"])
+        .arg("--resume-by-xattr")
+        .assert()
+        .success();
+
+    let original = rootfs.expected(0);
+    let mut expected = rootfs.expected(100000);
+    for (path, owner) in expected.iter_mut() {
+        if path.starts_with("var/lib/mysql") {
+            *owner = original[path];
+        }
+    }
+    assert_eq!(owners(rootfs.root())?, expected);
+    assert_eq!(xattr::get(&mysql, "user.rust-utils.remapped")?, None);
+
+    let json: serde_json::Value = serde_json::from_str(&fs::read_to_string(&report)?)?;
+    assert_eq!(json["counts"]["directories_resumed"], 1);
+    assert_eq!(
+        json["counts"]["markers_removed"],
+        json["counts"]["directories_marked"].as_u64().unwrap() + 1
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_remap_atomic_dirs() -> Result<(), Box<dyn std::error::Error>> {
+    let temp_dir = TempDir::new()?;
+    let rootfs = Rootfs::build(&temp_dir.path().join("rootfs"), 0)?;
+    let etc = fs::symlink_metadata(rootfs.root().join("etc"))?.ino();
+
+    let mut cmd = Command::cargo_bin("rust-utils")?;
+    cmd.arg("remap")
+        .arg(rootfs.root())
+        .args(["--from-base", "0", "--to-base", "100000"])
+        .args(["--atomic-dirs", "etc"])
+        .assert()
+        .success();
+
+    // The remapped copy took the place of etc and nothing else is left
+    assert_eq!(owners(rootfs.root())?, rootfs.expected(100000));
+    assert_ne!(fs::symlink_metadata(rootfs.root().join("etc"))?.ino(), etc);
+    assert!(!fs::read_dir(rootfs.root())?.any(|entry| entry
+        .unwrap()
+        .file_name()
+        .to_string_lossy()
+        .starts_with('.')));
+
+    Ok(())
+}
+
+#[test]
+fn test_remap_error_policy() -> Result<(), Box<dyn std::error::Error>> {
+    let temp_dir = TempDir::new()?;
+    // Archives cut short fail to remap as nested archives
+    for name in ["a.tar", "b.tar", "c.tar"] {
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(20000);
+        header.set_cksum();
+        builder.append_data(&mut header, "data", &[0u8; 20000][..])?;
+        let mut archive = builder.into_inner()?;
+        archive.truncate(5000);
+        fs::write(temp_dir.path().join(name), archive)?;
+    }
+    let remap = |args: &[&str]| -> Result<Command, Box<dyn std::error::Error>> {
+        let mut cmd = Command::cargo_bin("rust-utils")?;
+        cmd.arg("remap")
+            .arg(temp_dir.path())
+            .args(["--from-base", "0", "--to-base", "100000", "--dry-run"])
+            .args(["--nested", "recurse"])
+            .args(args);
+        Ok(cmd)
+    };
+
+    // The run completes, but its exit status tells that entries failed
+    remap(&[])?
+        .assert()
+        .code(4)
+        .stdout(predicate::str::contains("status=ok"))
+        .stdout(predicate::str::contains("failed=3"));
+    remap(&["--fail-fast"])?
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("stopping after 1 failed entry"));
+    remap(&["--max-errors", "2"])?
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("stopping after 2 failed entries"));
+    remap(&["--max-errors", "3"])?
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("stopping after 3 failed entries"));
+    remap(&["--max-errors", "4"])?.assert().code(4);
+
+    Ok(())
+}
+
+#[test]
+fn test_remap_profile() -> Result<(), Box<dyn std::error::Error>> {
+    let temp_dir = TempDir::new()?;
+    let rootfs = Rootfs::build(&temp_dir.path().join("rootfs"), 0)?;
+    let profiles = temp_dir.path().join("profiles");
+    fs::create_dir(&profiles)?;
+    let profile = serde_json::json!({
+        "base-directory": rootfs.root(),
+        "from-base": 0,
+        "to-base": 100000,
+    });
+    fs::write(profiles.join("web.json"), profile.to_string())?;
+
+    // Run as root, the profile's remap runs directly
+    let mut cmd = Command::cargo_bin("rust-utils")?;
+    cmd.args(["remap", "profile", "web", "--profile-dir"])
+        .arg(&profiles)
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("RESULT status=ok"));
+    assert_eq!(owners(rootfs.root())?, rootfs.expected(100000));
+
+    // As run by pkexec, nothing but a profile from the profile directory is accepted
+    let mut cmd = Command::cargo_bin("rust-utils")?;
+    cmd.env("PKEXEC_UID", "1000")
+        .args(["remap", "profile", "web", "--profile-dir"])
+        .arg(&profiles)
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("only `remap profile NAME` runs"));
+    let mut cmd = Command::cargo_bin("rust-utils")?;
+    cmd.env("PKEXEC_UID", "1000")
+        .arg("remap")
+        .arg(rootfs.root())
+        .args(["--from-base", "100000", "--to-base", "0"])
+        .assert()
+        .failure();
+    assert_eq!(owners(rootfs.root())?, rootfs.expected(100000));
+
+    Ok(())
+}
+
+#[test]
+fn test_remap_plan_hash() -> Result<(), Box<dyn std::error::Error>> {
+    let temp_dir = TempDir::new()?;
+    let rootfs = Rootfs::build(&temp_dir.path().join("rootfs"), 0)?;
+    let remap = |extra: &[&str]| {
+        let mut cmd = Command::cargo_bin("rust-utils").unwrap();
+        cmd.args(["--output-format", "json", "remap"])
+            .arg(rootfs.root())
+            .args(["--from-base", "0", "--to-base", "100000"])
+            .args(extra);
+        cmd
+    };
+    let plan_hash = || -> Result<String, Box<dyn std::error::Error>> {
+        let output = remap(&["--dry-run"])
+            .assert()
+            .success()
+            .get_output()
+            .stdout
+            .clone();
+        let summary: serde_json::Value = serde_json::from_slice(&output)?;
+        Ok(summary["plan_hash"].as_str().unwrap().to_string())
+    };
+
+    let approved = plan_hash()?;
+    assert_eq!(plan_hash()?, approved);
+    remap(&["--dry-run", "--plan-hash", &approved])
+        .assert()
+        .success();
+
+    // A tree that changed since the approval is left alone
+    fs::write(rootfs.root().join("tmp/new"), "")?;
+    remap(&["--plan-hash", &approved])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("Plan mismatch"));
+    assert_eq!(owners(rootfs.root())?[Path::new("etc")], (0, 0));
+
+    let approved = plan_hash()?;
+    let output = remap(&["--plan-hash", &approved])
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let summary: serde_json::Value = serde_json::from_slice(&output)?;
+    assert_eq!(summary["plan_hash"], approved.as_str());
+    assert_eq!(owners(rootfs.root())?[Path::new("etc")], (100000, 100000));
+
+    remap(&["--plan-hash", "sha256:abc"]).assert().code(2);
+
+    Ok(())
+}
+
+#[test]
+fn test_remap_project_ids() -> Result<(), Box<dyn std::error::Error>> {
+    let temp_dir = TempDir::new()?;
+    fs::create_dir(temp_dir.path().join("dir"))?;
+    fs::write(temp_dir.path().join("dir/file"), "")?;
+
+    let summary = |mode: Option<&str>| -> Result<serde_json::Value, Box<dyn std::error::Error>> {
+        let mut cmd = Command::cargo_bin("rust-utils")?;
+        cmd.args(["--dry-run", "--output-format", "json", "remap"])
+            .arg(temp_dir.path())
+            .args(["--from-base", "0", "--to-base", "100000"]);
+        if let Some(mode) = mode {
+            cmd.args(["--project-ids", mode]);
+        }
+        let output = cmd.assert().success().get_output().stdout.clone();
+        Ok(serde_json::from_slice(&output)?)
+    };
+
+    // Files without a project ID, or on filesystems without them, have none to remap
+    let counts = &summary(Some("remap"))?["counts"];
+    assert_eq!(counts["project_ids"], 0);
+    assert_eq!(
+        counts["project_ids_remapped"],
+        counts["project_ids_in_range"]
+    );
+    let counts = &summary(Some("report"))?["counts"];
+    assert_eq!(counts["project_ids_remapped"], 0);
+    assert!(summary(None)?["counts"].get("project_ids").is_none());
+
+    Ok(())
+}
+
+/// A bind mount, unmounted again when dropped.
+struct BindMount(std::path::PathBuf);
+
+impl BindMount {
+    /// Bind `source` onto `target`, or `None` where mounting is not allowed.
+    fn new(source: &Path, target: &Path) -> Option<Self> {
+        let status = std::process::Command::new("mount")
+            .arg("--bind")
+            .arg(source)
+            .arg(target)
+            .stderr(std::process::Stdio::null())
+            .status()
+            .ok()?;
+        status.success().then(|| Self(target.to_path_buf()))
+    }
+}
+
+impl Drop for BindMount {
+    fn drop(&mut self) {
+        let _ = std::process::Command::new("umount").arg(&self.0).status();
+    }
+}
+
+#[test]
+fn test_remap_one_file_system() -> Result<(), Box<dyn std::error::Error>> {
+    let temp_dir = TempDir::new()?;
+    let host = temp_dir.path().join("host");
+    let tree = temp_dir.path().join("rootfs");
+    fs::create_dir(&host)?;
+    fs::write(host.join("data"), "")?;
+    fs::create_dir_all(tree.join("mnt/host"))?;
+    fs::write(tree.join("file"), "")?;
+    let Some(_mount) = BindMount::new(&host, &tree.join("mnt/host")) else {
+        // Mounting needs CAP_SYS_ADMIN
+        return Ok(());
+    };
+
+    let mut cmd = Command::cargo_bin("rust-utils")?;
+    cmd.arg("remap")
+        .arg(&tree)
         .args([
-            "remap",
-            temp_dir.path().to_str().unwrap(),
             "--from-base",
-            "1000",
+            "0",
             "--to-base",
-            "101000",
-            "--range-size",
-            "1000",
-            "--dry-run",
+            "100000",
+            "--one-file-system",
         ])
         .assert()
-        .success()
-        .stdout(predicate::str::contains("From range: 1000-1999"))
-        .stdout(predicate::str::contains("To range: 101000-101999"));
+        .success();
+
+    // The mount point and what is mounted there are left alone
+    assert_eq!(fs::metadata(tree.join("file"))?.uid(), 100000);
+    assert_eq!(fs::metadata(tree.join("mnt"))?.uid(), 100000);
+    assert_eq!(fs::metadata(tree.join("mnt/host"))?.uid(), 0);
+    assert_eq!(fs::metadata(host.join("data"))?.uid(), 0);
 
     Ok(())
 }
 
 #[test]
-fn test_remap_invalid_range_overflow() {
-    let mut cmd = Command::cargo_bin("rust-utils").unwrap();
-    cmd.args([
-        "remap",
-        "/tmp",
-        "--from-base",
-        "4294900000", // Near u32::MAX
-        "--to-base",
-        "50000000",
-        "--range-size",
-        "100000", // Would overflow
-        "--dry-run",
-    ])
-    .assert()
-    .failure()
-    .stderr(predicate::str::contains("overflow"));
+fn test_remap_beneath_mounts() -> Result<(), Box<dyn std::error::Error>> {
+    let temp_dir = TempDir::new()?;
+    let host = temp_dir.path().join("host");
+    let tree = temp_dir.path().join("rootfs");
+    fs::create_dir(&host)?;
+    fs::write(host.join("data"), "")?;
+    fs::create_dir_all(tree.join("var/lib"))?;
+    // Left behind under the mount point before something was mounted there
+    fs::write(tree.join("var/lib/stale"), "")?;
+    let Some(mount) = BindMount::new(&host, &tree.join("var/lib")) else {
+        // Mounting needs CAP_SYS_ADMIN
+        return Ok(());
+    };
+
+    let mut cmd = Command::cargo_bin("rust-utils")?;
+    let output = cmd
+        .args(["--dry-run", "--verbose", "--output-format", "json", "remap"])
+        .arg(&tree)
+        .args(["--from-base", "0", "--to-base", "100000"])
+        .output()?;
+    assert!(output.status.success());
+    let summary: serde_json::Value = serde_json::from_slice(&output.stdout)?;
+    if summary["counts"]["shadowed_mounts"].is_null() {
+        // Looking beneath mounts needs open_tree, from Linux 5.2
+        return Ok(());
+    }
+    assert_eq!(summary["counts"]["shadowed_mounts"], 1);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("hides 1 entry(ies)"));
+
+    let mut cmd = Command::cargo_bin("rust-utils")?;
+    cmd.arg("remap")
+        .arg(&tree)
+        .args([
+            "--from-base",
+            "0",
+            "--to-base",
+            "100000",
+            "--beneath-mounts",
+        ])
+        .assert()
+        .success();
+
+    // The mounted directory is left alone, and the tree's own entries are remapped
+    // including the one under it
+    assert_eq!(fs::metadata(host.join("data"))?.uid(), 0);
+    assert_eq!(fs::metadata(tree.join("var/lib"))?.uid(), 0);
+    drop(mount);
+    assert_eq!(fs::metadata(tree.join("var/lib"))?.uid(), 100000);
+    assert_eq!(fs::metadata(tree.join("var/lib/stale"))?.uid(), 100000);
+    assert_eq!(
+        fs::read_dir(std::env::temp_dir())?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry
+                .file_name()
+                .to_string_lossy()
+                .starts_with("rust-utils-beneath-"))
+            .count(),
+        0
+    );
+
+    Ok(())
 }
 
 #[test]
-fn test_invalid_command() {
-    let mut cmd = Command::cargo_bin("rust-utils").unwrap();
-    cmd.arg("invalid-command")
+fn test_remap_map_uid_gid() -> Result<(), Box<dyn std::error::Error>> {
+    let temp_dir = TempDir::new()?;
+    let tree = temp_dir.path().join("rootfs");
+    fs::create_dir(&tree)?;
+
+    let mut cmd = Command::cargo_bin("rust-utils")?;
+    let output = cmd
+        .args(["--dry-run", "--output-format", "json", "remap"])
+        .arg(&tree)
+        .args([
+            "--from-base",
+            "0",
+            "--to-base",
+            "100000",
+            "--map-uid",
+            "33:3333",
+            "--map-gid",
+            "33:3333",
+            "--map-gid",
+            "999:4444",
+        ])
         .assert()
-        .failure()
-        .stderr(predicate::str::contains("unrecognized subcommand"));
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let summary: serde_json::Value = serde_json::from_slice(&output)?;
+    let ranges = summary["ranges"].as_array().unwrap();
+    assert_eq!(ranges.len(), 5);
+    assert!(ranges.iter().any(|range| range["kind"] == "gid"
+        && range["from"] == 999
+        && range["to"] == 4444
+        && range["count"] == 1));
+
+    let mut cmd = Command::cargo_bin("rust-utils")?;
+    cmd.arg("remap")
+        .arg(&tree)
+        .args(["--from-base", "0", "--to-base", "100000", "--gid-only"])
+        .args(["--map-uid", "33:3333"])
+        .assert()
+        .code(2);
+
+    let mut cmd = Command::cargo_bin("rust-utils")?;
+    cmd.arg("remap")
+        .arg(&tree)
+        .args(["--from-base", "0", "--to-base", "100000", "--map-uid", "33"])
+        .assert()
+        .code(2)
+        .stderr(predicate::str::contains("expected FROM:TO"));
+
+    Ok(())
+}
+
+#[test]
+fn test_remap_fakeroot_db() -> Result<(), Box<dyn std::error::Error>> {
+    let temp_dir = TempDir::new()?;
+    let tree = temp_dir.path().join("rootfs");
+    let state = temp_dir.path().join("state");
+    fs::create_dir_all(tree.join("etc"))?;
+    fs::write(tree.join("etc/passwd"), "")?;
+    // Owners recorded the way a template build under fakeroot records them
+    let Ok(status) = std::process::Command::new("fakeroot")
+        .arg("-s")
+        .arg(&state)
+        .args(["chown", "-R", "100000:100033"])
+        .arg(&tree)
+        .status()
+    else {
+        // fakeroot is not installed
+        return Ok(());
+    };
+    assert!(status.success());
+
+    let mut cmd = Command::cargo_bin("rust-utils")?;
+    cmd.arg("remap")
+        .arg(&tree)
+        .args([
+            "--from-base",
+            "100000",
+            "--to-base",
+            "200000",
+            "--fakeroot-db",
+        ])
+        .arg(&state)
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("changed=3"));
+
+    let output = std::process::Command::new("fakeroot")
+        .arg("-i")
+        .arg(&state)
+        .args(["stat", "-c", "%u:%g"])
+        .arg(tree.join("etc/passwd"))
+        .output()?;
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout).trim(),
+        "200000:200033"
+    );
+    assert_ne!(fs::metadata(tree.join("etc/passwd"))?.uid(), 200000);
+
+    Ok(())
+}
+
+#[test]
+fn test_remap_mapping_file() -> Result<(), Box<dyn std::error::Error>> {
+    let temp_dir = TempDir::new()?;
+    let tree = temp_dir.path().join("rootfs");
+    fs::create_dir(&tree)?;
+    for (name, id) in [("www", 33), ("app", 1005), ("other", 34)] {
+        fs::write(tree.join(name), "")?;
+        std::os::unix::fs::lchown(tree.join(name), Some(id), Some(id))?;
+    }
+    let mapping = temp_dir.path().join("mapping.json");
+    fs::write(
+        &mapping,
+        r#"[{"kind": "both", "from": 33, "to": 3333},
+            {"kind": "uid", "from": 1000, "to": 5000, "count": 10}]"#,
+    )?;
+
+    let mut cmd = Command::cargo_bin("rust-utils")?;
+    cmd.arg("remap")
+        .arg(&tree)
+        .args(["--from-base", "0", "--to-base", "100000", "--mapping-file"])
+        .arg(&mapping)
+        .assert()
+        .success();
+    let owner = |name: &str| -> std::io::Result<(u32, u32)> {
+        let metadata = fs::symlink_metadata(tree.join(name))?;
+        Ok((metadata.uid(), metadata.gid()))
+    };
+    assert_eq!(owner("www")?, (3333, 3333));
+    assert_eq!(owner("app")?, (5005, 101005));
+    assert_eq!(owner("other")?, (100034, 100034));
+
+    // Overlapping mappings are refused before anything is walked
+    let mapping = temp_dir.path().join("mapping.csv");
+    fs::write(
+        &mapping,
+        "kind,from,to,count\nuid,1000,5000,10\nboth,1009,7000\n",
+    )?;
+    let mut cmd = Command::cargo_bin("rust-utils")?;
+    cmd.arg("remap")
+        .arg(&tree)
+        .args([
+            "--from-base",
+            "100000",
+            "--to-base",
+            "200000",
+            "--mapping-file",
+        ])
+        .arg(&mapping)
+        .assert()
+        .code(2)
+        .stderr(predicate::str::contains("overlap"));
+    assert_eq!(owner("other")?, (100034, 100034));
+
+    Ok(())
+}
+
+#[test]
+fn test_remap_all_hardlinks_dry_run() -> Result<(), Box<dyn std::error::Error>> {
+    let temp_dir = TempDir::new()?;
+    let tree = temp_dir.path().join("rootfs");
+    fs::create_dir(&tree)?;
+    fs::write(tree.join("a"), "")?;
+    fs::hard_link(tree.join("a"), tree.join("b"))?;
+    std::os::unix::fs::lchown(tree.join("a"), Some(100000), Some(100000))?;
+
+    let dry_run = |extra: &[&str]| -> Result<String, Box<dyn std::error::Error>> {
+        let mut cmd = Command::cargo_bin("rust-utils")?;
+        let output = cmd
+            .arg("remap")
+            .arg(&tree)
+            .args(["--from-base", "100000", "--to-base", "200000"])
+            .args(["--dry-run", "--verbose"])
+            .args(extra)
+            .assert()
+            .success()
+            .get_output()
+            .stdout
+            .clone();
+        Ok(String::from_utf8(output)?)
+    };
+    // The link left out is named, rather than missing from the output
+    let output = dry_run(&[])?;
+    assert_eq!(output.matches(": hard link to ").count(), 1, "{output}");
+    assert!(output.contains("skipped (dry run)"), "{output}");
+    let output = dry_run(&["--all-hardlinks"])?;
+    for link in ["a", "b"] {
+        let line = format!("{link}: 100000:100000 -> 200000:200000 (dry run)");
+        assert!(output.contains(&line), "{output}");
+    }
+
+    let mut cmd = Command::cargo_bin("rust-utils")?;
+    cmd.arg("remap")
+        .arg(&tree)
+        .args(["--from-base", "100000", "--to-base", "200000"])
+        .args(["--all-hardlinks", "--hardlinks", "fail"])
+        .assert()
+        .code(2);
+
+    Ok(())
+}
+
+#[test]
+fn test_remap_map_user_group() -> Result<(), Box<dyn std::error::Error>> {
+    let temp_dir = TempDir::new()?;
+    let tree = temp_dir.path().join("rootfs");
+    fs::create_dir_all(tree.join("etc"))?;
+    fs::create_dir(tree.join("host"))?;
+    // The tree gives postgres other IDs than the host, and links its group database
+    // absolutely, which must resolve inside the tree
+    fs::write(
+        tree.join("etc/passwd"),
+        "root:x:0:0::/root:/bin/sh\npostgres:x:999:70::/var/lib/postgresql:/bin/sh\n",
+    )?;
+    fs::write(tree.join("host/group"), "root:x:0:\npostgres:x:70:\n")?;
+    std::os::unix::fs::symlink("/host/group", tree.join("etc/group"))?;
+    fs::write(tree.join("data"), "")?;
+    std::os::unix::fs::lchown(tree.join("data"), Some(100999), Some(100070))?;
+
+    let mut cmd = Command::cargo_bin("rust-utils")?;
+    cmd.arg("remap")
+        .arg(&tree)
+        .args([
+            "--from-base",
+            "100000",
+            "--to-base",
+            "200000",
+            "--map-user",
+            "postgres:64000",
+            "--map-group",
+            "postgres:64000",
+        ])
+        .assert()
+        .success();
+    let metadata = fs::symlink_metadata(tree.join("data"))?;
+    assert_eq!((metadata.uid(), metadata.gid()), (64000, 64000));
+
+    // Names the tree does not know are refused before anything is walked
+    let mut cmd = Command::cargo_bin("rust-utils")?;
+    cmd.arg("remap")
+        .arg(&tree)
+        .args([
+            "--from-base",
+            "200000",
+            "--to-base",
+            "300000",
+            "--map-user",
+            "mysql:64001",
+        ])
+        .assert()
+        .code(2)
+        .stderr(predicate::str::contains("no user 'mysql'"));
+
+    Ok(())
+}
+
+/// A `chown-helper` serving for the duration of a test.
+struct ChownHelper(std::process::Child);
+
+impl Drop for ChownHelper {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+#[test]
+fn test_remap_chown_helper() -> Result<(), Box<dyn std::error::Error>> {
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::process::CommandExt;
+
+    const NOBODY: u32 = 65534;
+    let temp_dir = TempDir::new()?;
+    fs::set_permissions(temp_dir.path(), fs::Permissions::from_mode(0o755))?;
+    let tree = temp_dir.path().join("rootfs");
+    fs::create_dir(&tree)?;
+    fs::write(tree.join("suid"), "")?;
+    for path in [&tree, &tree.join("suid")] {
+        std::os::unix::fs::lchown(path, Some(100000), Some(100000))?;
+    }
+    fs::set_permissions(tree.join("suid"), fs::Permissions::from_mode(0o4755))?;
+    // Where nobody can run it from, unlike the build directory below /root
+    let binary = temp_dir.path().join("rust-utils");
+    fs::copy(assert_cmd::cargo::cargo_bin("rust-utils"), &binary)?;
+
+    let socket = temp_dir.path().join("helper.sock");
+    let _helper = ChownHelper(
+        std::process::Command::new(assert_cmd::cargo::cargo_bin("rust-utils"))
+            .arg("chown-helper")
+            .arg("--socket")
+            .arg(&socket)
+            .arg("--allow-dir")
+            .arg(&tree)
+            .args(["--allow-client", &NOBODY.to_string()])
+            .args(["--allow-uid-map", "100000:200000:65536"])
+            .args(["--allow-gid-map", "100000:200000:65536"])
+            .spawn()?,
+    );
+    for _ in 0..100 {
+        if socket.exists() {
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(20));
+    }
+    let remap = |from: &str, to: &str| {
+        let mut cmd = std::process::Command::new(&binary);
+        cmd.uid(NOBODY).gid(NOBODY).arg("remap").arg(&tree).args([
+            "--from-base",
+            from,
+            "--to-base",
+            to,
+            "--chown-helper",
+        ]);
+        cmd.arg(&socket);
+        Command::from_std(cmd)
+    };
+
+    // Without privileges of its own, with the special bits put back by the helper
+    remap("100000", "200000").assert().success();
+    let metadata = fs::symlink_metadata(tree.join("suid"))?;
+    assert_eq!((metadata.uid(), metadata.gid()), (200000, 200000));
+    assert_eq!(metadata.mode() & 0o7777, 0o4755);
+
+    // Changes the policy does not map are refused
+    remap("200000", "0")
+        .arg("--verbose")
+        .assert()
+        .code(4)
+        .stdout(predicate::str::contains("may not become 0"));
+    assert_eq!(fs::symlink_metadata(&tree)?.uid(), 200000);
+
+    Ok(())
+}
+
+#[test]
+fn test_remap_subid_user() -> Result<(), Box<dyn std::error::Error>> {
+    let temp_dir = TempDir::new()?;
+    let tree = temp_dir.path().join("rootfs");
+    fs::create_dir(&tree)?;
+    let subuid = temp_dir.path().join("subuid");
+    let subgid = temp_dir.path().join("subgid");
+    fs::write(&subuid, "12345:200000:65536\n")?;
+    fs::write(&subgid, "12345:200000:65536\n")?;
+
+    let mut cmd = Command::cargo_bin("rust-utils")?;
+    let output = cmd
+        .args(["--dry-run", "--output-format", "json", "remap"])
+        .arg(&tree)
+        .args(["--from-base", "0", "--subid-user", "12345", "--subuid-file"])
+        .arg(&subuid)
+        .arg("--subgid-file")
+        .arg(&subgid)
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let summary: serde_json::Value = serde_json::from_slice(&output)?;
+    assert_eq!(summary["ranges"][0]["to"], 200000);
+    assert_eq!(summary["ranges"][1]["to"], 200000);
+
+    let mut cmd = Command::cargo_bin("rust-utils")?;
+    cmd.args(["--dry-run", "remap"])
+        .arg(&tree)
+        .args([
+            "--from-base",
+            "0",
+            "--subid-user",
+            "nobody-here",
+            "--subuid-file",
+        ])
+        .arg(&subuid)
+        .arg("--subgid-file")
+        .arg(&subgid)
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "no range of 65536 subordinate uids for 'nobody-here'",
+        ));
+
+    Ok(())
+}
+
+#[test]
+fn test_remap_progress_interval() -> Result<(), Box<dyn std::error::Error>> {
+    let temp_dir = TempDir::new()?;
+    File::create(temp_dir.path().join("a.txt"))?;
+    File::create(temp_dir.path().join("b.txt"))?;
+
+    let mut cmd = Command::cargo_bin("rust-utils").unwrap();
+    cmd.env("RUST_LOG", "info")
+        .args([
+            "remap",
+            temp_dir.path().to_str().unwrap(),
+            "--from-base",
+            "100000",
+            "--to-base",
+            "50000000",
+            "--dry-run",
+            "--verbose",
+            "--progress-interval",
+            "1",
+        ])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Processed 3 files"));
+
+    let mut cmd = Command::cargo_bin("rust-utils").unwrap();
+    cmd.args([
+        "remap",
+        temp_dir.path().to_str().unwrap(),
+        "--from-base",
+        "100000",
+        "--to-base",
+        "50000000",
+        "--progress-interval",
+        "10w",
+    ])
+    .assert()
+    .failure()
+    .stderr(predicate::str::contains("invalid progress interval"));
+
+    Ok(())
+}
+
+#[test]
+fn test_remap_with_exclusions() -> Result<(), Box<dyn std::error::Error>> {
+    let temp_dir = TempDir::new()?;
+
+    // Create files that should be excluded
+    File::create(temp_dir.path().join("app.log"))?;
+    let tmp_dir = temp_dir.path().join("tmp");
+    fs::create_dir(&tmp_dir)?;
+    File::create(tmp_dir.join("temp.txt"))?;
+    File::create(temp_dir.path().join("regular.txt"))?;
+
+    let mut cmd = Command::cargo_bin("rust-utils").unwrap();
+    cmd.env("RUST_LOG", "info")
+        .args([
+            "remap",
+            temp_dir.path().to_str().unwrap(),
+            "--from-base",
+            "100000",
+            "--to-base",
+            "50000000",
+            "--dry-run",
+            "--verbose",
+            "--exclude",
+            "*.log",
+            "--exclude",
+            "tmp/*",
+        ])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Starting UID/GID remapping"));
+
+    Ok(())
+}
+
+#[test]
+fn test_remap_uid_only() -> Result<(), Box<dyn std::error::Error>> {
+    let temp_dir = TempDir::new()?;
+    File::create(temp_dir.path().join("test.txt"))?;
+
+    let mut cmd = Command::cargo_bin("rust-utils").unwrap();
+    cmd.args([
+        "remap",
+        temp_dir.path().to_str().unwrap(),
+        "--from-base",
+        "100000",
+        "--to-base",
+        "50000000",
+        "--dry-run",
+        "--uid-only",
+    ])
+    .assert()
+    .success();
+
+    Ok(())
+}
+
+#[test]
+fn test_remap_gid_only() -> Result<(), Box<dyn std::error::Error>> {
+    let temp_dir = TempDir::new()?;
+    File::create(temp_dir.path().join("test.txt"))?;
+
+    let mut cmd = Command::cargo_bin("rust-utils").unwrap();
+    cmd.args([
+        "remap",
+        temp_dir.path().to_str().unwrap(),
+        "--from-base",
+        "100000",
+        "--to-base",
+        "50000000",
+        "--dry-run",
+        "--gid-only",
+    ])
+    .assert()
+    .success();
+
+    Ok(())
+}
+
+#[test]
+fn test_remap_custom_range_size() -> Result<(), Box<dyn std::error::Error>> {
+    let temp_dir = TempDir::new()?;
+    File::create(temp_dir.path().join("test.txt"))?;
+
+    let mut cmd = Command::cargo_bin("rust-utils").unwrap();
+    cmd.env("RUST_LOG", "info")
+        .args([
+            "remap",
+            temp_dir.path().to_str().unwrap(),
+            "--from-base",
+            "1000",
+            "--to-base",
+            "101000",
+            "--range-size",
+            "1000",
+            "--dry-run",
+        ])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("From range: 1000-1999"))
+        .stdout(predicate::str::contains("To range: 101000-101999"));
+
+    Ok(())
+}
+
+#[test]
+fn test_remap_invalid_range_overflow() {
+    let mut cmd = Command::cargo_bin("rust-utils").unwrap();
+    cmd.args([
+        "remap",
+        "/tmp",
+        "--from-base",
+        "4294900000", // Near u32::MAX
+        "--to-base",
+        "50000000",
+        "--range-size",
+        "100000", // Would overflow
+        "--dry-run",
+    ])
+    .assert()
+    .code(2)
+    .stderr(predicate::str::contains("overflow"))
+    .stderr(predicate::str::contains("Usage: rust-utils remap"));
+}
+
+#[test]
+fn test_remap_container_view() -> Result<(), Box<dyn std::error::Error>> {
+    let temp_dir = TempDir::new()?;
+    File::create(temp_dir.path().join("owned.txt"))?;
+
+    let uid = nix::unistd::getuid().as_raw();
+    let gid = nix::unistd::getgid().as_raw();
+
+    // Host view shows the shifted IDs, container view shows them unchanged
+    let mut cmd = Command::cargo_bin("rust-utils").unwrap();
+    cmd.env("RUST_LOG", "info")
+        .args([
+            "remap",
+            temp_dir.path().to_str().unwrap(),
+            "--from-base",
+            "0",
+            "--to-base",
+            "100000",
+            "--dry-run",
+        ])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(format!(
+            "{uid}:{gid} -> {}:{}",
+            uid + 100000,
+            gid + 100000
+        )));
+
+    let mut cmd = Command::cargo_bin("rust-utils").unwrap();
+    cmd.env("RUST_LOG", "info")
+        .args([
+            "remap",
+            temp_dir.path().to_str().unwrap(),
+            "--from-base",
+            "0",
+            "--to-base",
+            "100000",
+            "--dry-run",
+            "--view",
+            "container",
+        ])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(format!(
+            "{uid}:{gid} -> {uid}:{gid}"
+        )));
+
+    Ok(())
+}
+
+#[test]
+fn test_remap_container_view_overrides() -> Result<(), Box<dyn std::error::Error>> {
+    if !nix::unistd::geteuid().is_root() {
+        return Ok(());
+    }
+    let temp_dir = TempDir::new()?;
+    let path = temp_dir.path().join("www");
+    File::create(&path)?;
+    // Its GID went through the override already, its UID has yet to be remapped
+    nix::unistd::chown(&path, Some(100005.into()), Some(900033.into()))?;
+    let report = temp_dir.path().join("report.json");
+
+    let mut cmd = Command::cargo_bin("rust-utils").unwrap();
+    cmd.env("RUST_LOG", "info")
+        .args([
+            "--report",
+            report.to_str().unwrap(),
+            "remap",
+            temp_dir.path().to_str().unwrap(),
+            "--from-base",
+            "100000",
+            "--to-base",
+            "200000",
+            "--map-gid",
+            "100033:900033",
+            "--dry-run",
+            "--view",
+            "container",
+        ])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("5:33 -> 5:33"))
+        .stdout(predicate::str::contains(
+            "has only its UID in the source range (5:33), its GID 33 is already in the target range",
+        ));
+
+    let report: serde_json::Value = serde_json::from_str(&fs::read_to_string(&report)?)?;
+    assert!(report["ranges"]
+        .as_array()
+        .unwrap()
+        .contains(&serde_json::json!({"kind": "gid", "from": 33, "to": 33, "count": 1})));
+    Ok(())
+}
+
+#[test]
+fn test_remap_exclude_mountpoint() -> Result<(), Box<dyn std::error::Error>> {
+    let temp_dir = TempDir::new()?;
+    fs::create_dir_all(temp_dir.path().join("mnt/volume"))?;
+    File::create(temp_dir.path().join("mnt/volume/host-data.txt"))?;
+    File::create(temp_dir.path().join("container.txt"))?;
+
+    let mut cmd = Command::cargo_bin("rust-utils").unwrap();
+    cmd.env("RUST_LOG", "info")
+        .args([
+            "remap",
+            temp_dir.path().to_str().unwrap(),
+            "--from-base",
+            "0",
+            "--to-base",
+            "100000",
+            "--dry-run",
+            "--exclude-mountpoint",
+            "mnt/volume",
+        ])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Excluding mount point"))
+        .stdout(predicate::str::contains("container.txt"))
+        .stdout(predicate::str::contains("host-data.txt").not());
+
+    Ok(())
+}
+
+#[test]
+fn test_remap_exclude_mountpoint_outside_base() -> Result<(), Box<dyn std::error::Error>> {
+    let temp_dir = TempDir::new()?;
+    let other_dir = TempDir::new()?;
+
+    let mut cmd = Command::cargo_bin("rust-utils").unwrap();
+    cmd.args([
+        "remap",
+        temp_dir.path().to_str().unwrap(),
+        "--from-base",
+        "0",
+        "--to-base",
+        "100000",
+        "--dry-run",
+        "--exclude-mountpoint",
+        other_dir.path().to_str().unwrap(),
+    ])
+    .assert()
+    .failure()
+    .stderr(predicate::str::contains("not below base directory"));
+
+    Ok(())
+}
+
+#[test]
+fn test_remap_safety_scan() -> Result<(), Box<dyn std::error::Error>> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let temp_dir = TempDir::new()?;
+    let shared = temp_dir.path().join("srv/shared");
+    fs::create_dir_all(&shared)?;
+    fs::set_permissions(&shared, fs::Permissions::from_mode(0o777))?;
+
+    let uid = nix::unistd::getuid().as_raw();
+
+    // The current user's files become owned by the target root ID
+    let mut cmd = Command::cargo_bin("rust-utils").unwrap();
+    cmd.env("RUST_LOG", "info")
+        .args([
+            "remap",
+            temp_dir.path().to_str().unwrap(),
+            "--from-base",
+            &uid.to_string(),
+            "--to-base",
+            "100000",
+            "--range-size",
+            "1",
+            "--uid-only",
+            "--dry-run",
+            "--safety-scan",
+        ])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(
+            "srv/shared: world-writable directory owned by root after remap",
+        ))
+        .stdout(predicate::str::contains("Safety scan findings: 1"));
+
+    Ok(())
+}
+
+#[test]
+fn test_remap_with_tasks() -> Result<(), Box<dyn std::error::Error>> {
+    let temp_dir = TempDir::new()?;
+    fs::write(temp_dir.path().join("data.txt"), "payload")?;
+
+    let uid = nix::unistd::getuid().as_raw();
+    let gid = nix::unistd::getgid().as_raw();
+
+    let mut cmd = Command::cargo_bin("rust-utils").unwrap();
+    cmd.env("RUST_LOG", "info")
+        .args([
+            "remap",
+            temp_dir.path().to_str().unwrap(),
+            "--from-base",
+            "100000",
+            "--to-base",
+            "200000",
+            "--dry-run",
+            "--with",
+            "owners,checksum",
+        ])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(format!("[owners] {uid}:{gid} 2")))
+        .stdout(predicate::str::contains("[checksum] files: 1 (7 bytes)"));
+
+    Ok(())
+}
+
+#[cfg(feature = "wasm-plugins")]
+#[test]
+fn test_remap_plugin() -> Result<(), Box<dyn std::error::Error>> {
+    let temp_dir = TempDir::new()?;
+    File::create(temp_dir.path().join("keep.txt"))?;
+    File::create(temp_dir.path().join("skip.txt"))?;
+
+    // Skips entries whose path starts with "s" and sends every UID to 4242
+    let plugin = temp_dir.path().join("policy.wat");
+    fs::write(
+        &plugin,
+        r#"(module
+             (memory (export "memory") 1)
+             (func (export "alloc") (param i32) (result i32) (i32.const 0))
+             (func (export "decide") (param $ptr i32) (param $len i32) (param i32 i32 i32) (result i32)
+               (if (result i32) (i32.eqz (local.get $len))
+                 (then (i32.const 0))
+                 (else (i32.eq (i32.load8_u (local.get $ptr)) (i32.const 115)))))
+             (func (export "map_uid") (param i32) (result i64) (i64.const 4242)))"#,
+    )?;
+
+    let uid = nix::unistd::getuid().as_raw();
+
+    let mut cmd = Command::cargo_bin("rust-utils").unwrap();
+    cmd.env("RUST_LOG", "info")
+        .args([
+            "remap",
+            temp_dir.path().to_str().unwrap(),
+            "--from-base",
+            &uid.to_string(),
+            "--to-base",
+            "200000",
+            "--range-size",
+            "1",
+            "--uid-only",
+            "--dry-run",
+            "--plugin",
+            plugin.to_str().unwrap(),
+        ])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("keep.txt: ").and(predicate::str::contains("-> 4242:")))
+        .stdout(predicate::str::contains("skip.txt").not());
+
+    Ok(())
+}
+
+#[cfg(not(feature = "wasm-plugins"))]
+#[test]
+fn test_remap_plugin_requires_feature() -> Result<(), Box<dyn std::error::Error>> {
+    let temp_dir = TempDir::new()?;
+    let plugin = temp_dir.path().join("policy.wasm");
+    fs::write(&plugin, b"\0asm\x01\0\0\0")?;
+
+    let mut cmd = Command::cargo_bin("rust-utils").unwrap();
+    cmd.args([
+        "remap",
+        temp_dir.path().to_str().unwrap(),
+        "--from-base",
+        "100000",
+        "--to-base",
+        "200000",
+        "--plugin",
+        plugin.to_str().unwrap(),
+    ])
+    .assert()
+    .failure()
+    .stderr(predicate::str::contains(
+        "built without the wasm-plugins feature",
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn test_remap_nested_archives() -> Result<(), Box<dyn std::error::Error>> {
+    let image = TempDir::new()?;
+    let rootfs = TempDir::new()?;
+    fs::write(image.path().join("motd"), "welcome\n")?;
+    fs::create_dir(rootfs.path().join("images"))?;
+
+    let tarball = rootfs.path().join("images/base.tar.gz");
+    Command::cargo_bin("rust-utils")
+        .unwrap()
+        .args(["template", "pack", image.path().to_str().unwrap(), "-o"])
+        .arg(&tarball)
+        .arg("--normalize-ids")
+        .assert()
+        .success();
+
+    let remap = |nested: &str| {
+        let mut cmd = Command::cargo_bin("rust-utils").unwrap();
+        cmd.env("RUST_LOG", "info").args([
+            "remap",
+            rootfs.path().to_str().unwrap(),
+            "--from-base",
+            "0",
+            "--to-base",
+            "100000",
+            "--dry-run",
+            "--nested",
+            nested,
+        ]);
+        cmd.assert().success()
+    };
+
+    remap("warn")
+        .stdout(predicate::str::contains("Nested archive"))
+        .stdout(predicate::str::contains("use --nested recurse"));
+    remap("recurse").stdout(predicate::str::contains(
+        "base.tar.gz: 2 of 2 nested archive entries remapped (dry run)",
+    ));
+    remap("skip").stdout(predicate::str::contains("Nested archive").not());
+
+    Ok(())
+}
+
+#[test]
+fn test_copy_with_map() -> Result<(), Box<dyn std::error::Error>> {
+    let source = TempDir::new()?;
+    let destination = TempDir::new()?;
+    fs::create_dir(source.path().join("etc"))?;
+    fs::write(source.path().join("etc/hostname"), "web\n")?;
+
+    let uid = nix::unistd::getuid().as_raw();
+    let target = destination.path().join("rootfs");
+
+    let mut cmd = Command::cargo_bin("rust-utils").unwrap();
+    cmd.env("RUST_LOG", "info")
+        .args([
+            "copy",
+            source.path().to_str().unwrap(),
+            target.to_str().unwrap(),
+            "--map",
+            &format!("{uid}:{uid}:1"),
+        ])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Copied 1 files, 2 directories"));
+
+    assert_eq!(fs::read_to_string(target.join("etc/hostname"))?, "web\n");
+
+    Ok(())
+}
+
+#[test]
+fn test_copy_with_idmap() -> Result<(), Box<dyn std::error::Error>> {
+    if !nix::unistd::geteuid().is_root() {
+        return Ok(());
+    }
+    let source = TempDir::new()?;
+    let destination = TempDir::new()?;
+    fs::write(source.path().join("file"), "")?;
+    let config = destination.path().join("config");
+    fs::write(&config, "lxc.idmap = g 0 200000 65536\n")?;
+    let target = destination.path().join("rootfs");
+
+    Command::cargo_bin("rust-utils")
+        .unwrap()
+        .arg("copy")
+        .arg(source.path())
+        .arg(&target)
+        .args(["--idmap", "u 0 100000 65536", "--idmap-file"])
+        .arg(&config)
+        .assert()
+        .success();
+
+    let metadata = fs::symlink_metadata(target.join("file"))?;
+    assert_eq!((metadata.uid(), metadata.gid()), (100000, 200000));
+    Ok(())
+}
+
+#[test]
+fn test_progress_fd() -> Result<(), Box<dyn std::error::Error>> {
+    let source = TempDir::new()?;
+    let destination = TempDir::new()?;
+    fs::write(source.path().join("hostname"), "web\n")?;
+    let target = destination.path().join("rootfs");
+
+    // Without RUST_LOG set, stdout carries only the progress events
+    let output = Command::cargo_bin("rust-utils")
+        .unwrap()
+        .env("RUST_LOG", "off")
+        .args([
+            "copy",
+            source.path().to_str().unwrap(),
+            target.to_str().unwrap(),
+            "--map",
+            "0:0:1",
+            "--progress-fd",
+            "1",
+        ])
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+
+    let events: Vec<serde_json::Value> = String::from_utf8(output)?
+        .lines()
+        .map(serde_json::from_str)
+        .collect::<Result<_, _>>()?;
+    assert_eq!(events.first().unwrap()["event"], "start");
+    let done = events.last().unwrap();
+    assert_eq!(done["event"], "done");
+    assert_eq!(done["command"], "copy");
+    assert_eq!(done["entries"], 2);
+    assert_eq!(done["bytes"], 4);
+
+    Command::cargo_bin("rust-utils")
+        .unwrap()
+        .args(["copy", source.path().to_str().unwrap()])
+        .arg(destination.path().join("other"))
+        .args(["--map", "0:0:1", "--progress-fd", "999"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("progress descriptor 999"));
+
+    Ok(())
+}
+
+#[test]
+fn test_status_file() -> Result<(), Box<dyn std::error::Error>> {
+    let temp_dir = TempDir::new()?;
+    let rootfs = Rootfs::build(&temp_dir.path().join("rootfs"), 0)?;
+    let status = temp_dir.path().join("status.json");
+
+    let mut cmd = Command::cargo_bin("rust-utils")?;
+    cmd.arg("remap")
+        .arg(rootfs.root())
+        .args(["--from-base", "0", "--to-base", "100000", "--threads", "4"])
+        .arg("--status-file")
+        .arg(&status)
+        .assert()
+        .success();
+
+    let done: serde_json::Value = serde_json::from_slice(&fs::read(&status)?)?;
+    assert_eq!(done["event"], "done");
+    assert_eq!(done["command"], "remap");
+    assert_eq!(done["entries"], rootfs.expected(0).len() as u64);
+
+    Ok(())
+}
+
+#[test]
+fn test_copy_invalid_map() -> Result<(), Box<dyn std::error::Error>> {
+    let source = TempDir::new()?;
+
+    let mut cmd = Command::cargo_bin("rust-utils").unwrap();
+    cmd.args([
+        "copy",
+        source.path().to_str().unwrap(),
+        "/tmp/rust-utils-copy-target",
+        "--map",
+        "100000:0",
+    ])
+    .assert()
+    .failure()
+    .stderr(predicate::str::contains("expected FROM:TO:COUNT"));
+
+    Ok(())
+}
+
+#[test]
+fn test_send_stream_passthrough() {
+    // Stream header followed by a bare END command
+    let mut stream = b"btrfs-stream\0".to_vec();
+    stream.extend_from_slice(&1u32.to_le_bytes());
+    stream.extend_from_slice(&0u32.to_le_bytes());
+    stream.extend_from_slice(&21u16.to_le_bytes());
+    stream.extend_from_slice(&0x9dc9_6c50u32.to_le_bytes());
+
+    let mut cmd = Command::cargo_bin("rust-utils").unwrap();
+    cmd.env("RUST_LOG", "info")
+        .args(["send-stream", "--map", "0:100000:65536"])
+        .write_stdin(stream.clone())
+        .assert()
+        .success()
+        .stdout(stream)
+        .stderr(predicate::str::contains(
+            "Translated 1 stream(s), 1 commands",
+        ));
+}
+
+#[test]
+fn test_send_stream_rejects_garbage() {
+    let mut cmd = Command::cargo_bin("rust-utils").unwrap();
+    cmd.args(["send-stream", "--map", "0:100000:65536"])
+        .write_stdin("definitely not a send stream")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("invalid send stream"));
+}
+
+#[test]
+fn test_template_pack() -> Result<(), Box<dyn std::error::Error>> {
+    let rootfs = TempDir::new()?;
+    let out = TempDir::new()?;
+    fs::create_dir(rootfs.path().join("etc"))?;
+    fs::write(rootfs.path().join("etc/hostname"), "template\n")?;
+
+    let tarball = out.path().join("template.tar");
+    let mut cmd = Command::cargo_bin("rust-utils").unwrap();
+    cmd.env("RUST_LOG", "info")
+        .env("SOURCE_DATE_EPOCH", "1700000000")
+        .args([
+            "template",
+            "pack",
+            rootfs.path().to_str().unwrap(),
+            "--output",
+            tarball.to_str().unwrap(),
+            "--normalize-ids",
+        ])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Packed 3 entries"));
+
+    let listing = std::process::Command::new("tar")
+        .args(["--numeric-owner", "-tvf", tarball.to_str().unwrap()])
+        .output()?;
+    let listing = String::from_utf8(listing.stdout)?;
+    assert!(listing.contains(" 0/0 "));
+    assert!(listing.contains("2023-11-14"));
+    assert!(listing.contains("etc/hostname"));
+
+    Ok(())
+}
+
+#[test]
+fn test_template_import_from_stdin() -> Result<(), Box<dyn std::error::Error>> {
+    let rootfs = TempDir::new()?;
+    let out = TempDir::new()?;
+    fs::write(rootfs.path().join("motd"), "welcome\n")?;
+
+    let tarball = out.path().join("template.tar");
+    Command::cargo_bin("rust-utils")
+        .unwrap()
+        .args(["template", "pack", rootfs.path().to_str().unwrap(), "-o"])
+        .arg(&tarball)
+        .assert()
+        .success();
+
+    let uid = nix::unistd::getuid().as_raw();
+    let gid = nix::unistd::getgid().as_raw();
+    let target = out.path().join("rootfs");
+
+    let mut cmd = Command::cargo_bin("rust-utils").unwrap();
+    cmd.env("RUST_LOG", "info")
+        .args([
+            "template",
+            "import",
+            "-",
+            "--target",
+            target.to_str().unwrap(),
+        ])
+        .args(["--map", &format!("{uid}:{uid}:1")]);
+    if gid != uid {
+        cmd.args(["--map", &format!("{gid}:{gid}:1")]);
+    }
+    cmd.write_stdin(fs::read(&tarball)?)
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Imported 2 entries"));
+
+    assert_eq!(fs::read_to_string(target.join("motd"))?, "welcome\n");
+
+    Ok(())
+}
+
+#[test]
+fn test_archive_remap_to_stdout() -> Result<(), Box<dyn std::error::Error>> {
+    let rootfs = TempDir::new()?;
+    let out = TempDir::new()?;
+    fs::write(rootfs.path().join("motd"), "welcome\n")?;
+
+    let tarball = out.path().join("template.tar.gz");
+    Command::cargo_bin("rust-utils")
+        .unwrap()
+        .args(["template", "pack", rootfs.path().to_str().unwrap(), "-o"])
+        .arg(&tarball)
+        .arg("--normalize-ids")
+        .assert()
+        .success();
+
+    // Logs go to stderr so stdout carries only the (uncompressed) archive
+    let mut cmd = Command::cargo_bin("rust-utils").unwrap();
+    let output = cmd
+        .env("RUST_LOG", "info")
+        .args(["archive", "remap", tarball.to_str().unwrap(), "-"])
+        .args(["--map", "0:100000:65536", "--compress", "none"])
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("Entries remapped: 2"))
+        .get_output()
+        .stdout
+        .clone();
+
+    let remapped = out.path().join("remapped.tar");
+    fs::write(&remapped, output)?;
+    let listing = std::process::Command::new("tar")
+        .args(["--numeric-owner", "-tvf", remapped.to_str().unwrap()])
+        .output()?;
+    let listing = String::from_utf8(listing.stdout)?;
+    assert!(listing.contains(" 100000/100000 "));
+    assert!(listing.contains("motd"));
+
+    Ok(())
+}
+
+#[test]
+fn test_archive_remap_split_volumes() -> Result<(), Box<dyn std::error::Error>> {
+    let rootfs = TempDir::new()?;
+    let out = TempDir::new()?;
+    fs::write(rootfs.path().join("data"), vec![7u8; 4096])?;
+
+    let tarball = out.path().join("template.tar");
+    Command::cargo_bin("rust-utils")
+        .unwrap()
+        .args(["template", "pack", rootfs.path().to_str().unwrap(), "-o"])
+        .arg(&tarball)
+        .assert()
+        .success();
+
+    let split = out.path().join("split.tar");
+    Command::cargo_bin("rust-utils")
+        .unwrap()
+        .args(["archive", "remap", tarball.to_str().unwrap()])
+        .arg(&split)
+        .args(["--map", "0:100000:65536", "--split-size", "1K"])
+        .assert()
+        .success();
+    assert!(out.path().join("split.tar.000").exists());
+    assert!(out.path().join("split.tar.005").exists());
+
+    // The parts are read back as one archive when the unsplit name is given
+    let mut cmd = Command::cargo_bin("rust-utils").unwrap();
+    cmd.env("RUST_LOG", "info")
+        .args(["archive", "remap", split.to_str().unwrap(), "-"])
+        .args(["--map", "0:0:1"])
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("Rewrote 2 entries"));
+
+    Ok(())
+}
+
+#[test]
+fn test_archive_remap_resume() -> Result<(), Box<dyn std::error::Error>> {
+    let rootfs = TempDir::new()?;
+    let out = TempDir::new()?;
+    fs::write(rootfs.path().join("motd"), "welcome\n")?;
+
+    let tarball = out.path().join("template.tar");
+    Command::cargo_bin("rust-utils")
+        .unwrap()
+        .args(["template", "pack", rootfs.path().to_str().unwrap(), "-o"])
+        .arg(&tarball)
+        .assert()
+        .success();
+
+    // With nothing to resume the run starts over and still moves the output into place
+    let output = out.path().join("remapped.tar.gz");
+    let state = out.path().join("state");
+    let mut cmd = Command::cargo_bin("rust-utils").unwrap();
+    cmd.env("RUST_LOG", "info")
+        .args(["archive", "remap", tarball.to_str().unwrap()])
+        .arg(&output)
+        .args(["--map", "0:100000:65536", "--resume", "--state-dir"])
+        .arg(&state)
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Nothing to resume"));
+    assert!(output.exists());
+    assert!(!out.path().join("remapped.tar.gz.partial").exists());
+    // The checkpoint log lived in the state directory and is gone once the archive is done
+    assert!(!out.path().join("remapped.tar.gz.partial.ckpt").exists());
+    assert_eq!(fs::read_dir(state.join("checkpoints"))?.count(), 0);
+
+    let mut cmd = Command::cargo_bin("rust-utils").unwrap();
+    cmd.args(["archive", "remap", tarball.to_str().unwrap(), "-"])
+        .args(["--map", "0:100000:65536", "--resume"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "--resume needs a local output file",
+        ));
+
+    Ok(())
+}
+
+#[cfg(not(feature = "object-storage"))]
+#[test]
+fn test_archive_url_requires_feature() -> Result<(), Box<dyn std::error::Error>> {
+    let out = TempDir::new()?;
+
+    let mut cmd = Command::cargo_bin("rust-utils").unwrap();
+    cmd.args(["archive", "remap", "s3://templates/base.tar"])
+        .arg(out.path().join("out.tar"))
+        .args(["--map", "0:100000:65536"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "built without the object-storage feature",
+        ));
+
+    Ok(())
+}
+
+#[test]
+fn test_invalid_command() {
+    let mut cmd = Command::cargo_bin("rust-utils").unwrap();
+    cmd.arg("invalid-command")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("unrecognized subcommand"));
+}
+
+#[test]
+fn test_run_report() -> Result<(), Box<dyn std::error::Error>> {
+    let source = TempDir::new()?;
+    let destination = TempDir::new()?;
+    fs::write(source.path().join("hostname"), "web\n")?;
+    let target = destination.path().join("rootfs");
+    let report = destination.path().join("report.json");
+
+    Command::cargo_bin("rust-utils")
+        .unwrap()
+        .args(["--report", report.to_str().unwrap(), "copy"])
+        .arg(source.path())
+        .arg(&target)
+        .args(["--map", "0:0:1"])
+        .assert()
+        .success();
+
+    let json: serde_json::Value = serde_json::from_str(&fs::read_to_string(&report)?)?;
+    assert_eq!(json["format_version"], 2);
+    assert_eq!(json["command"], "copy");
+    assert_eq!(json["success"], true);
+    assert_eq!(json["counts"]["files"], 1);
+    assert_eq!(json["counts"]["bytes"], 4);
+    assert!(json["durations_ms"]["total"].is_u64());
+    assert_eq!(json["errors"], serde_json::json!([]));
+    assert_eq!(json["artifacts"][0]["kind"], "tree");
+    assert_eq!(json["artifacts"][0]["path"], target.to_str().unwrap());
+
+    // A failed run still leaves a report behind, in the same schema
+    Command::cargo_bin("rust-utils")
+        .unwrap()
+        .args(["fingerprint", "/nonexistent/rootfs", "--report"])
+        .arg(&report)
+        .assert()
+        .failure();
+
+    let json: serde_json::Value = serde_json::from_str(&fs::read_to_string(&report)?)?;
+    assert_eq!(json["command"], "fingerprint");
+    assert_eq!(json["success"], false);
+    assert_eq!(json["counts"], serde_json::json!({}));
+    let message = json["errors"][0]["message"].as_str().unwrap();
+    assert!(message.contains("/nonexistent/rootfs"), "{message}");
+
+    Ok(())
+}
+
+#[test]
+fn test_result_line() -> Result<(), Box<dyn std::error::Error>> {
+    let temp_dir = TempDir::new()?;
+    let report = temp_dir.path().join("report.json");
+
+    // Printed even with logging turned off, as the last line of output
+    let output = Command::cargo_bin("rust-utils")
+        .unwrap()
+        .env("RUST_LOG", "off")
+        .args(["--report", report.to_str().unwrap(), "fingerprint"])
+        .arg(temp_dir.path())
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let stdout = String::from_utf8(output)?;
+    let line = stdout.lines().last().unwrap();
+    assert!(
+        line.starts_with("RESULT status=ok changed=0 failed=0 duration="),
+        "{line}"
+    );
+    let json: serde_json::Value = serde_json::from_str(&fs::read_to_string(&report)?)?;
+    let run_id = json["run_id"].as_str().unwrap();
+    assert!(line.ends_with(&format!(" run={run_id}")), "{line}");
+
+    // Commands writing data to stdout print it on stderr instead
+    Command::cargo_bin("rust-utils")
+        .unwrap()
+        .args(["schema", "report"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("RESULT status").not())
+        .stderr(predicate::str::starts_with("RESULT status=ok"));
+
+    Command::cargo_bin("rust-utils")
+        .unwrap()
+        .args(["fingerprint", "/nonexistent/rootfs"])
+        .assert()
+        .failure()
+        .stdout(predicate::str::contains(
+            "RESULT status=failed changed=0 failed=1",
+        ));
+
+    Ok(())
+}
+
+#[test]
+fn test_lang() -> Result<(), Box<dyn std::error::Error>> {
+    let temp_dir = TempDir::new()?;
+    let args = [
+        "remap",
+        temp_dir.path().to_str().unwrap(),
+        "--from-base",
+        "100000",
+        "--to-base",
+        "50000000",
+        "--dry-run",
+    ];
+
+    let mut cmd = Command::cargo_bin("rust-utils").unwrap();
+    cmd.env("RUST_LOG", "info")
+        .env("LANG", "de_DE.UTF-8")
+        .args(args)
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Quellbereich: 100000-165535"))
+        .stdout(predicate::str::contains("remap-from-range"));
+
+    // --lang wins over the environment
+    let mut cmd = Command::cargo_bin("rust-utils").unwrap();
+    cmd.env("RUST_LOG", "info")
+        .env("LANG", "de_DE.UTF-8")
+        .args(["--lang", "en"])
+        .args(args)
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("From range: 100000-165535"));
+
+    Ok(())
+}
+
+#[test]
+fn test_log_file() -> Result<(), Box<dyn std::error::Error>> {
+    let temp_dir = TempDir::new()?;
+    let log_dir = TempDir::new()?;
+    let log = log_dir.path().join("rust-utils.log");
+    fs::write(&log, "x".repeat(64 * 1024))?;
+
+    let mut cmd = Command::cargo_bin("rust-utils").unwrap();
+    cmd.env("RUST_LOG", "info")
+        .args(["--log-file", log.to_str().unwrap(), "--log-max-size", "64K"])
+        .args([
+            "remap",
+            temp_dir.path().to_str().unwrap(),
+            "--from-base",
+            "100000",
+            "--to-base",
+            "50000000",
+            "--dry-run",
+        ])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("From range: 100000-165535"));
+
+    // The oversized log was rotated away before the first line was written
+    assert!(fs::read_to_string(&log)?.contains("From range: 100000-165535"));
+    assert!(log_dir.path().join("rust-utils.log.1.gz").exists());
+
+    Ok(())
+}
+
+#[test]
+fn test_log_file_json() -> Result<(), Box<dyn std::error::Error>> {
+    let temp_dir = TempDir::new()?;
+    let tree = temp_dir.path().join("tree");
+    fs::create_dir(&tree)?;
+    fs::write(tree.join("file"), "")?;
+    std::os::unix::fs::lchown(tree.join("file"), Some(100033), Some(100034))?;
+    let log = temp_dir.path().join("rust-utils.log");
+
+    // Entry records are written whatever the terminal shows, and never shown there
+    Command::cargo_bin("rust-utils")
+        .unwrap()
+        .args(["--quiet", "--log-file", log.to_str().unwrap()])
+        .args(["--log-format", "json"])
+        .args(["remap", tree.to_str().unwrap()])
+        .args(["--from-base", "100000", "--to-base", "0", "--dry-run"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("remapped").not());
+
+    let records: Vec<serde_json::Value> = fs::read_to_string(&log)?
+        .lines()
+        .map(serde_json::from_str)
+        .collect::<Result<_, _>>()?;
+    assert!(records
+        .iter()
+        .any(|record| record["message"] == "Starting UID/GID remapping"));
+    let entry = records
+        .iter()
+        .find(|record| record["path"] == tree.join("file").to_str().unwrap())
+        .expect("no record of the file");
+    assert_eq!(entry["target"], "rust_utils::entry");
+    assert_eq!(entry["event"], "remapped");
+    assert_eq!(entry["old_uid"], 100033);
+    assert_eq!(entry["old_gid"], 100034);
+    assert_eq!(entry["uid"], 33);
+    assert_eq!(entry["gid"], 34);
+    assert_eq!(entry["dry_run"], true);
+
+    Ok(())
+}
+
+#[test]
+fn test_schema() -> Result<(), Box<dyn std::error::Error>> {
+    let output = Command::cargo_bin("rust-utils")
+        .unwrap()
+        .args(["schema", "report"])
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+
+    let schema: serde_json::Value = serde_json::from_slice(&output)?;
+    assert_eq!(schema["title"], "RunReport");
+    assert!(schema["properties"]["counts"].is_object());
+
+    Command::cargo_bin("rust-utils")
+        .unwrap()
+        .args(["schema", "journal"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("possible values: report, event"));
+
+    Ok(())
+}
+
+#[test]
+fn test_remap_refuses_tree_in_use() -> Result<(), Box<dyn std::error::Error>> {
+    let temp_dir = TempDir::new()?;
+    File::create(temp_dir.path().join("test.txt"))?;
+    let remap = |extra: &[&str]| {
+        let mut cmd = Command::cargo_bin("rust-utils").unwrap();
+        cmd.env("RUST_LOG", "warn")
+            .args([
+                "remap",
+                temp_dir.path().to_str().unwrap(),
+                "--from-base",
+                "100000",
+                "--to-base",
+                "50000000",
+            ])
+            .args(extra);
+        cmd
+    };
+
+    let mut sleeper = std::process::Command::new("sleep")
+        .arg("30")
+        .current_dir(temp_dir.path())
+        .spawn()?;
+    let refused = remap(&[]).assert().failure();
+    let dry_run = remap(&["--dry-run"]).assert().success();
+    let allowed = remap(&["--allow-in-use"]).assert().success();
+    sleeper.kill()?;
+    sleeper.wait()?;
+
+    refused.stderr(predicate::str::contains("Tree in use: 1 process(es)"));
+    dry_run.stdout(predicate::str::contains(format!(
+        "In use: process {} (sleep) has its working directory in it",
+        sleeper.id()
+    )));
+    allowed.stdout(predicate::str::contains("In use: process"));
+
+    Ok(())
+}
+
+#[test]
+fn test_remap_probe_requires_dry_run() {
+    Command::cargo_bin("rust-utils")
+        .unwrap()
+        .args([
+            "remap",
+            "/tmp",
+            "--from-base",
+            "0",
+            "--to-base",
+            "100000",
+            "--probe",
+        ])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("--dry-run"));
+}
+
+#[test]
+fn test_remap_partitions_and_report_merge() -> Result<(), Box<dyn std::error::Error>> {
+    let temp_dir = TempDir::new()?;
+    let tree = temp_dir.path().join("tree");
+    let state = temp_dir.path().join("state");
+    for name in ["home", "srv", "var"] {
+        fs::create_dir_all(tree.join(name))?;
+    }
+
+    let mut reports = Vec::new();
+    for partition in ["1/2", "2/2"] {
+        let report = temp_dir
+            .path()
+            .join(format!("part-{}.json", &partition[..1]));
+        Command::cargo_bin("rust-utils")
+            .unwrap()
+            .arg("--report")
+            .arg(&report)
+            .arg("--state-dir")
+            .arg(&state)
+            .args(["remap", "--partition", partition, "--from-base", "100000"])
+            .args(["--to-base", "200000"])
+            .arg(&tree)
+            .assert()
+            .success();
+        reports.push(report);
+    }
+
+    let merged = temp_dir.path().join("merged.json");
+    Command::cargo_bin("rust-utils")
+        .unwrap()
+        .args(["report", "merge"])
+        .args(&reports)
+        .arg("-o")
+        .arg(&merged)
+        .assert()
+        .success();
+    let json: serde_json::Value = serde_json::from_str(&fs::read_to_string(&merged)?)?;
+    assert_eq!(json["command"], "remap");
+    assert_eq!(json["counts"]["entries"], 4);
+    assert_eq!(json["counts"]["units"], 4);
+
+    Command::cargo_bin("rust-utils")
+        .unwrap()
+        .args([
+            "remap",
+            "--partition",
+            "3/2",
+            "--from-base",
+            "1",
+            "--to-base",
+            "2",
+        ])
+        .arg(&tree)
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("1 <= INDEX <= COUNT"));
+
+    Ok(())
+}
+
+#[test]
+fn test_idmap_free() -> Result<(), Box<dyn std::error::Error>> {
+    let temp_dir = TempDir::new()?;
+    let subuid = temp_dir.path().join("subuid");
+    let subgid = temp_dir.path().join("subgid");
+    let lxc = temp_dir.path().join("lxc");
+    fs::write(&subuid, "lxc:100000:65536\n")?;
+    fs::write(&subgid, "lxc:100000:65536\n")?;
+    fs::create_dir_all(lxc.join("web"))?;
+    fs::write(lxc.join("web/config"), "lxc.idmap = g 0 165536 65536\n")?;
+
+    let free = |extra: &[&str]| {
+        let mut cmd = Command::cargo_bin("rust-utils").unwrap();
+        cmd.args(["idmap", "free", "--ignore-running", "--subuid"])
+            .arg(&subuid)
+            .arg("--subgid")
+            .arg(&subgid)
+            .arg("--lxc-path")
+            .arg(&lxc)
+            .args(extra);
+        cmd.assert()
+    };
+    free(&["--count", "2"])
+        .success()
+        .stdout(predicate::str::contains("used: 165536-231071 (gid)"))
+        .stdout(predicate::str::contains("free: 231072-600100000"))
+        .stdout(predicate::str::contains(
+            "proposed: 231072:65536\nproposed: 296608:65536\n",
+        ));
+
+    free(&["--last-id", "231071"])
+        .failure()
+        .stderr(predicate::str::contains("no free range of 65536 IDs"));
+
+    free(&["--allocate", "web"])
+        .success()
+        .stdout(predicate::str::contains("allocated: web:231072:65536"));
+    assert_eq!(
+        fs::read_to_string(&subgid)?,
+        "lxc:100000:65536\nweb:231072:65536\n"
+    );
+    assert_eq!(
+        fs::read_to_string(temp_dir.path().join("subuid-"))?,
+        "lxc:100000:65536\n"
+    );
+    // The next allocation sees the first
+    free(&["--allocate", "db"])
+        .success()
+        .stdout(predicate::str::contains("allocated: db:296608:65536"));
+
+    // An owner spanning lines would delegate IDs of its own choosing
+    let before = fs::read_to_string(&subuid)?;
+    free(&["--allocate", "bob:0:1\nx"])
+        .failure()
+        .stderr(predicate::str::contains("invalid subordinate ID owner"));
+    free(&["--allocate", ""]).failure();
+    assert_eq!(fs::read_to_string(&subuid)?, before);
+
+    Ok(())
+}
+
+#[test]
+fn test_idmap_config() -> Result<(), Box<dyn std::error::Error>> {
+    let temp_dir = TempDir::new()?;
+    let subuid = temp_dir.path().join("subuid");
+    let subgid = temp_dir.path().join("subgid");
+    fs::write(&subuid, "root:100000:65536\nroot:1000:1\n")?;
+    fs::write(&subgid, "root:100000:65536\n")?;
+
+    let config = |extra: &[&str]| {
+        let mut cmd = Command::cargo_bin("rust-utils").unwrap();
+        cmd.args(["idmap", "config", "--subuid"])
+            .arg(&subuid)
+            .arg("--subgid")
+            .arg(&subgid)
+            .args(extra);
+        cmd.assert()
+    };
+    config(&["--map", "0:100000:65536"])
+        .success()
+        .stdout(predicate::str::starts_with(
+            "lxc.idmap = u 0 100000 65536\nlxc.idmap = g 0 100000 65536\n",
+        ));
+    config(&["--format", "raw-idmap", "--map", "0:100000:65536"])
+        .success()
+        .stdout(predicate::str::starts_with("both 100000-165535 0-65535\n"));
+
+    // Host IDs nobody delegated are refused
+    config(&["--map", "0:100000:65537"])
+        .failure()
+        .stderr(predicate::str::contains(
+            "host uids 165536-165536 of mapping 0:100000:65537 are not delegated to root",
+        ));
+    config(&["--uid-map", "1000:1000:1"])
+        .failure()
+        .stderr(predicate::str::contains("no gid mapping given"));
+
+    // lxc.idmap entries, given directly or read from a container config
+    config(&["--idmap", "u 0 100000 65536", "--idmap", "g 0 100000 65536"])
+        .success()
+        .stdout(predicate::str::starts_with(
+            "lxc.idmap = u 0 100000 65536\nlxc.idmap = g 0 100000 65536\n",
+        ));
+    let container = temp_dir.path().join("config");
+    fs::write(
+        &container,
+        "lxc.uts.name = web\nlxc.idmap = u 0 100000 65536\nlxc.idmap = u 65536 1000 1\nlxc.idmap = g 0 100000 65536\n",
+    )?;
+    config(&[
+        "--format",
+        "raw-idmap",
+        "--idmap-file",
+        container.to_str().unwrap(),
+    ])
+    .success()
+    .stdout(predicate::str::starts_with(
+        "uid 100000-165535 0-65535\nuid 1000 65536\ngid 100000-165535 0-65535\n",
+    ));
+    config(&["--idmap", "x 0 100000 65536"])
+        .failure()
+        .stderr(predicate::str::contains(
+            "invalid lxc.idmap 'x 0 100000 65536'",
+        ));
+
+    Ok(())
+}
+
+#[test]
+fn test_idmap_show() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("rust-utils")?;
+    cmd.args([
+        "idmap",
+        "show",
+        "--map",
+        "0:100000:65536",
+        "--uid-map",
+        "65536:1000:1",
+    ]);
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains(
+            "uid   B    65536      1000               1",
+        ))
+        .stdout(predicate::str::contains("  0-65536\n"))
+        .stdout(predicate::str::contains("|  100000-165535\n"));
+
+    let mut cmd = Command::cargo_bin("rust-utils")?;
+    cmd.args([
+        "idmap",
+        "show",
+        "--map",
+        "0:100000:10",
+        "--map",
+        "5:200000:10",
+    ]);
+    cmd.assert().failure();
+
+    Ok(())
+}
+
+#[test]
+fn test_output_format_json() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("rust-utils")?;
+    cmd.args([
+        "--output-format",
+        "json",
+        "idmap",
+        "show",
+        "--map",
+        "0:100000:65536",
+    ]);
+    let output = cmd.assert().success().get_output().stdout.clone();
+    let output = String::from_utf8(output)?;
+    let summary: serde_json::Value = serde_json::from_str(output.lines().last().unwrap())?;
+    assert_eq!(summary["command"], "idmap-show");
+    assert_eq!(summary["counts"]["mappings"], 2);
+    assert!(!output.contains("RESULT "));
+
+    Ok(())
+}
+
+#[test]
+fn test_with_caps() -> Result<(), Box<dyn std::error::Error>> {
+    let temp_dir = TempDir::new()?;
+    let rootfs = Rootfs::build(&temp_dir.path().join("rootfs"), 0)?;
+
+    // The command runs with only the capabilities remap needs, which root has
+    let mut cmd = Command::cargo_bin("rust-utils")?;
+    cmd.args(["--verbose", "with-caps", "--", "remap"])
+        .arg(rootfs.root())
+        .args(["--from-base", "0", "--to-base", "100000"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(
+            "Running remap with cap_chown,cap_dac_override",
+        ));
+    assert_eq!(owners(rootfs.root())?, rootfs.expected(100000));
+
+    let mut cmd = Command::cargo_bin("rust-utils")?;
+    cmd.args(["with-caps", "--", "remap", "profile", "web"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("elevate through pkexec"));
+
+    Ok(())
+}
+
+#[test]
+fn test_remap_preview_overlay() -> Result<(), Box<dyn std::error::Error>> {
+    let temp_dir = TempDir::new()?;
+    let rootfs = Rootfs::build(&temp_dir.path().join("rootfs"), 0)?;
+
+    let mut cmd = Command::cargo_bin("rust-utils")?;
+    let output = cmd
+        .arg("remap")
+        .arg(rootfs.root())
+        .args([
+            "--from-base",
+            "0",
+            "--to-base",
+            "100000",
+            "--preview-overlay",
+        ])
+        .write_stdin("\n")
+        .output()?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if stderr.contains("cannot mount") {
+        // Mounting needs CAP_SYS_ADMIN
+        return Ok(());
+    }
+    assert!(output.status.success(), "{stderr}");
+    assert!(stderr.contains("press Enter to discard it"));
+    assert!(String::from_utf8_lossy(&output.stdout).contains("RESULT status=ok changed="));
+
+    // The remap changed the overlay, which is gone, and not the tree
+    assert_eq!(owners(rootfs.root())?, rootfs.expected(0));
+
+    let mut cmd = Command::cargo_bin("rust-utils")?;
+    cmd.args(["--dry-run", "remap"])
+        .arg(rootfs.root())
+        .args([
+            "--from-base",
+            "0",
+            "--to-base",
+            "100000",
+            "--preview-overlay",
+        ])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("has no dry run"));
+
+    Ok(())
+}
+
+#[test]
+fn test_normalize() -> Result<(), Box<dyn std::error::Error>> {
+    let temp_dir = TempDir::new()?;
+    let tree = temp_dir.path().join("rootfs");
+    fs::create_dir(&tree)?;
+    File::create(tree.join("www"))?;
+    std::os::unix::fs::lchown(&tree, Some(100000), Some(200000))?;
+    std::os::unix::fs::lchown(tree.join("www"), Some(100033), Some(200033))?;
+
+    // UIDs and GIDs are shifted down from bases of their own
+    let mut cmd = Command::cargo_bin("rust-utils")?;
+    let output = cmd
+        .arg("normalize")
+        .arg(&tree)
+        .args(["--output-format", "json"])
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let summary: serde_json::Value = serde_json::from_slice(&output)?;
+    assert_eq!(summary["command"], "normalize");
+    assert_eq!(summary["counts"]["uid_base"], 100000);
+    assert_eq!(summary["counts"]["gid_base"], 200000);
+    assert_eq!(summary["counts"]["uids_outside"], 0);
+    let owners = owners(&tree)?;
+    assert_eq!(owners[Path::new("")], (0, 0));
+    assert_eq!(owners[Path::new("www")], (33, 33));
+
+    Ok(())
+}
+
+#[test]
+fn test_remap_homes() -> Result<(), Box<dyn std::error::Error>> {
+    let temp_dir = TempDir::new()?;
+    let homes = temp_dir.path().join("home");
+    for (user, id) in [("alice", 1000), ("bob", 1001)] {
+        fs::create_dir_all(homes.join(user))?;
+        File::create(homes.join(user).join(".profile"))?;
+        std::os::unix::fs::lchown(homes.join(user), Some(id), Some(id))?;
+    }
+
+    let mut cmd = Command::cargo_bin("rust-utils")?;
+    cmd.args(["--jobs", "2", "--state-dir"])
+        .arg(temp_dir.path().join("state"))
+        .arg("remap-homes")
+        .arg(&homes)
+        .args(["--map-users", "alice:100000", "bob:100001"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(
+            "RESULT status=ok changed=4 failed=0",
+        ));
+    let owners = owners(&homes)?;
+    assert_eq!(owners[Path::new("alice/.profile")], (100000, 100000));
+    assert_eq!(owners[Path::new("bob")], (100001, 100001));
+
+    // Two users cannot share an ID
+    let mut cmd = Command::cargo_bin("rust-utils")?;
+    cmd.arg("remap-homes")
+        .arg(&homes)
+        .args(["--map-users", "alice:5", "bob:5"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "alice and bob are both mapped to 5",
+        ));
+
+    Ok(())
+}
+
+#[test]
+fn test_remap_verify() -> Result<(), Box<dyn std::error::Error>> {
+    let temp_dir = TempDir::new()?;
+    let tree = temp_dir.path().join("tree");
+    fs::create_dir(&tree)?;
+    File::create(tree.join("file"))?;
+    std::os::unix::fs::lchown(&tree, Some(200000), Some(200000))?;
+    std::os::unix::fs::lchown(tree.join("file"), Some(100033), Some(200033))?;
+
+    let verify = || {
+        let mut cmd = Command::cargo_bin("rust-utils").unwrap();
+        cmd.args(["remap", "verify"]).arg(&tree).args([
+            "--from-base",
+            "100000",
+            "--to-base",
+            "200000",
+        ]);
+        cmd
+    };
+    // Left behind by the migration: the run completes, and fails the check
+    verify().assert().code(4).stdout(predicate::str::contains(
+        "RESULT status=ok changed=0 failed=1",
+    ));
+
+    std::os::unix::fs::lchown(tree.join("file"), Some(200033), None)?;
+    verify()
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("failed=0"));
+
+    Ok(())
+}
+
+#[test]
+fn test_journal_encrypt() -> Result<(), Box<dyn std::error::Error>> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let temp_dir = TempDir::new()?;
+    let tree = temp_dir.path().join("tree");
+    let journal = temp_dir.path().join("undo.journal");
+    fs::create_dir(&tree)?;
+    File::create(tree.join("file"))?;
+    let mut cmd = Command::cargo_bin("rust-utils")?;
+    cmd.args(["remap", "--undo-journal"])
+        .arg(&journal)
+        .arg(&tree)
+        .args(["--from-base", "0", "--to-base", "100000"])
+        .assert()
+        .success();
+
+    // Stands in for age, recording its arguments
+    let bin = temp_dir.path().join("bin");
+    fs::create_dir(&bin)?;
+    fs::write(
+        bin.join("age"),
+        "#!/bin/sh\necho \"$@\" > \"$(dirname \"$0\")/args\"\n\
+         while [ \"$1\" != --output ]; do shift; done\n\
+         { echo age-encryption.org/v1; cat \"$3\"; } > \"$2\"\n",
+    )?;
+    fs::set_permissions(bin.join("age"), fs::Permissions::from_mode(0o755))?;
+    let path = format!("{}:{}", bin.display(), std::env::var("PATH")?);
+
+    let mut cmd = Command::cargo_bin("rust-utils")?;
+    cmd.env("PATH", &path)
+        .args(["journal", "encrypt", "-r", "age1example"])
+        .arg(&journal)
+        .assert()
+        .success();
+    assert!(!journal.exists());
+    let encrypted = fs::read_to_string(temp_dir.path().join("undo.journal.age"))?;
+    assert!(encrypted.starts_with("age-encryption.org/v1\nrust-utils-undo-journal"));
+    assert!(fs::read_to_string(bin.join("args"))?.starts_with("--encrypt --recipient age1example"));
+
+    // Encrypted journals count towards retention, and are otherwise left alone
+    let mut cmd = Command::cargo_bin("rust-utils")?;
+    cmd.args(["journal", "compact", "--keep-last", "0"])
+        .arg(temp_dir.path())
+        .assert()
+        .success();
+    assert!(!temp_dir.path().join("undo.journal.age").exists());
+
+    Ok(())
+}
+
+#[test]
+fn test_profile_io() -> Result<(), Box<dyn std::error::Error>> {
+    let temp_dir = TempDir::new()?;
+    let rootfs = Rootfs::build(&temp_dir.path().join("rootfs"), 0)?;
+    let report = temp_dir.path().join("report.json");
+    let profile = temp_dir.path().join("remap.folded");
+
+    let mut cmd = Command::cargo_bin("rust-utils")?;
+    cmd.args(["--report", report.to_str().unwrap()])
+        .args(["--profile-io", profile.to_str().unwrap(), "remap"])
+        .arg(rootfs.root())
+        .args(["--from-base", "0", "--to-base", "100000"])
+        .assert()
+        .success();
+    assert_eq!(owners(rootfs.root())?, rootfs.expected(100000));
+
+    let json: serde_json::Value = serde_json::from_str(&fs::read_to_string(&report)?)?;
+    let entries = json["counts"]["entries"].as_u64().unwrap();
+    assert!(json["counts"]["statx_calls"].as_u64().unwrap() >= entries);
+    assert_eq!(json["counts"]["fchownat_calls"], json["counts"]["remapped"]);
+    assert!(json["counts"]["getdents_calls"].as_u64().unwrap() >= entries);
+    assert!(json["artifacts"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .any(|artifact| artifact["kind"] == "io-profile"));
+
+    // Every line is a stack of frames and a weight
+    let folded = fs::read_to_string(&profile)?;
+    for syscall in ["statx", "fchownat", "getdents"] {
+        assert!(
+            folded.contains(&format!("rust-utils;remap;{syscall};")),
+            "{folded}"
+        );
+    }
+    for line in folded.lines() {
+        let (stack, weight) = line.rsplit_once(' ').unwrap();
+        assert_eq!(stack.split(';').count(), 4, "{line}");
+        weight.parse::<u64>()?;
+    }
+    Ok(())
+}
+
+#[test]
+fn test_remap_interrupted() -> Result<(), Box<dyn std::error::Error>> {
+    use nix::sys::signal::{kill, Signal};
+    use nix::unistd::Pid;
+    use std::time::{Duration, Instant};
+
+    let temp_dir = TempDir::new()?;
+    let rootfs = Rootfs::build(&temp_dir.path().join("rootfs"), 0)?;
+    let report = temp_dir.path().join("report.json");
+    // A cgroup whose processes stop only when the test says so, which holds the run after
+    // its handlers are in place and before the walk
+    let cgroup = temp_dir.path().join("cgroup");
+    fs::create_dir(&cgroup)?;
+    fs::write(cgroup.join("cgroup.freeze"), "0")?;
+    fs::write(cgroup.join("cgroup.events"), "populated 1\nfrozen 0\n")?;
+    fs::write(cgroup.join("cgroup.procs"), "")?;
+
+    let child = std::process::Command::new(assert_cmd::cargo::cargo_bin("rust-utils"))
+        .args(["--report", report.to_str().unwrap(), "remap"])
+        .arg(rootfs.root())
+        .args(["--from-base", "0", "--to-base", "100000", "--freeze-cgroup"])
+        .arg(&cgroup)
+        .stdout(std::process::Stdio::piped())
+        .spawn()?;
+    let started = Instant::now();
+    while fs::read_to_string(cgroup.join("cgroup.freeze"))? != "1" {
+        assert!(
+            started.elapsed() < Duration::from_secs(10),
+            "cgroup never frozen"
+        );
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    kill(Pid::from_raw(child.id() as i32), Signal::SIGINT)?;
+    fs::write(cgroup.join("cgroup.events"), "populated 1\nfrozen 1\n")?;
+
+    let output = child.wait_with_output()?;
+    assert_eq!(output.status.code(), Some(130));
+    let stdout = String::from_utf8(output.stdout)?;
+    assert!(
+        stdout.contains("RESULT status=interrupted changed=0"),
+        "{stdout}"
+    );
+    // Stopped before the first entry, and the cgroup thawed
+    assert_eq!(owners(rootfs.root())?, rootfs.expected(0));
+    assert_eq!(fs::read_to_string(cgroup.join("cgroup.freeze"))?, "0");
+    let json: serde_json::Value = serde_json::from_str(&fs::read_to_string(&report)?)?;
+    assert_eq!(json["interrupted"], "SIGINT");
+    assert_eq!(json["success"], false);
+    assert_eq!(json["counts"]["entries"], 0);
+    Ok(())
+}
+
+#[test]
+fn test_remap_locked() -> Result<(), Box<dyn std::error::Error>> {
+    use std::time::{Duration, Instant};
+
+    let temp_dir = TempDir::new()?;
+    let rootfs = Rootfs::build(&temp_dir.path().join("rootfs"), 0)?;
+    let state = temp_dir.path().join("state");
+    // The first run waits for this cgroup to freeze while it holds the lock
+    let cgroup = temp_dir.path().join("cgroup");
+    fs::create_dir(&cgroup)?;
+    fs::write(cgroup.join("cgroup.freeze"), "0")?;
+    fs::write(cgroup.join("cgroup.events"), "populated 1\nfrozen 0\n")?;
+    fs::write(cgroup.join("cgroup.procs"), "")?;
+
+    let child = std::process::Command::new(assert_cmd::cargo::cargo_bin("rust-utils"))
+        .arg("--state-dir")
+        .arg(&state)
+        .arg("remap")
+        .arg(rootfs.root())
+        .args(["--from-base", "0", "--to-base", "100000", "--freeze-cgroup"])
+        .arg(&cgroup)
+        .stdout(std::process::Stdio::null())
+        .spawn()?;
+    let started = Instant::now();
+    while fs::read_to_string(cgroup.join("cgroup.freeze"))? != "1" {
+        assert!(
+            started.elapsed() < Duration::from_secs(10),
+            "cgroup never frozen"
+        );
+        std::thread::sleep(Duration::from_millis(10));
+    }
+
+    Command::cargo_bin("rust-utils")?
+        .arg("--state-dir")
+        .arg(&state)
+        .arg("remap")
+        .arg(rootfs.root())
+        .args(["--from-base", "0", "--to-base", "100000"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(format!(
+            "Tree in use: {} is being changed by process {}",
+            rootfs.root().canonicalize()?.display(),
+            child.id()
+        )));
+    // A dry run is not refused
+    Command::cargo_bin("rust-utils")?
+        .arg("--state-dir")
+        .arg(&state)
+        .args(["--dry-run", "remap"])
+        .arg(rootfs.root())
+        .args(["--from-base", "0", "--to-base", "100000"])
+        .assert()
+        .success();
+
+    fs::write(cgroup.join("cgroup.events"), "populated 1\nfrozen 1\n")?;
+    let output = child.wait_with_output()?;
+    assert!(output.status.success());
+    assert_eq!(owners(rootfs.root())?, rootfs.expected(100000));
+    Ok(())
 }